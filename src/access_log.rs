@@ -0,0 +1,169 @@
+//! Structured log of the visitors of every tunnel, for audits.
+//!
+//! Each line is a JSON record of one visitor: where it came from, which port
+//! and tunnel it reached, how many bytes it exchanged, for how long, and why
+//! its connection ended. Visitors that the server turned away are included,
+//! so the log answers who tried to reach a tunnel as well as who did.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::shared::CloseReason;
+
+/// Why the connection of a visitor ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// The visitor or the local service closed the connection.
+    Closed,
+
+    /// Forwarding the connection failed.
+    Error,
+
+    /// The tunnel used up its transfer quota.
+    QuotaExceeded,
+
+    /// Too many visitors of the tunnel were already waiting for the client.
+    QueueFull,
+
+    /// The client did not accept the connection in time.
+    AcceptTimeout,
+
+    /// The tunnel closed before the client accepted the connection.
+    TunnelClosed,
+
+    /// Address rules, country rules, or the policy turned the visitor away.
+    Denied,
+}
+
+impl From<CloseReason> for Reason {
+    fn from(reason: CloseReason) -> Self {
+        match reason {
+            CloseReason::QueueFull => Reason::QueueFull,
+            CloseReason::AcceptTimeout => Reason::AcceptTimeout,
+            CloseReason::TunnelClosed => Reason::TunnelClosed,
+        }
+    }
+}
+
+/// Record of one visitor in the access log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessEntry {
+    /// Unix timestamp in seconds when the connection ended.
+    pub time: u64,
+
+    /// Identifier of the connection, as used in the server's logs, unless
+    /// the visitor was turned away before it got one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+
+    /// Address of the visitor.
+    pub peer: SocketAddr,
+
+    /// ISO code of the country of the visitor, if the server looked it up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+
+    /// Public port that the visitor connected to.
+    pub port: u16,
+
+    /// User that owns the tunnel, if the client authenticated as one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+
+    /// Name of the tunnel, if the client gave one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel: Option<String>,
+
+    /// Bytes sent by the visitor.
+    pub bytes_in: u64,
+
+    /// Bytes sent to the visitor.
+    pub bytes_out: u64,
+
+    /// How long the connection was open, in milliseconds.
+    pub duration_ms: u64,
+
+    /// Why the connection ended.
+    pub reason: Reason,
+}
+
+impl AccessEntry {
+    /// Record of a visitor that exchanged no data, stamped with the current
+    /// time.
+    ///
+    /// ```
+    /// use bore_cli::access_log::{AccessEntry, Reason};
+    ///
+    /// let entry = AccessEntry::new("203.0.113.7:51234".parse().unwrap(), 8080, Reason::Denied);
+    /// let line = serde_json::to_string(&entry).unwrap();
+    /// assert!(line.contains(r#""peer":"203.0.113.7:51234""#));
+    /// assert!(line.contains(r#""reason":"denied""#));
+    /// assert!(!line.contains("user_id"));
+    /// assert!(!line.contains("country"));
+    /// ```
+    pub fn new(peer: SocketAddr, port: u16, reason: Reason) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        Self {
+            time,
+            id: None,
+            peer,
+            country: None,
+            port,
+            user_id: None,
+            tunnel: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            duration_ms: 0,
+            reason,
+        }
+    }
+}
+
+/// Destination of access log records, one JSON object per line.
+pub struct AccessLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    /// Append records to a file, or write them to standard output if the
+    /// path is `-`.
+    pub fn open(path: &Path) -> Result<Self> {
+        if path == Path::new("-") {
+            return Ok(Self::new(io::stdout()));
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("could not open access log {}", path.display()))?;
+        Ok(Self::new(file))
+    }
+
+    /// Write records to any destination.
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Write a record.
+    pub fn record(&self, entry: &AccessEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&line)?;
+        out.flush()?;
+        Ok(())
+    }
+}
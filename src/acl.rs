@@ -0,0 +1,144 @@
+//! Rules on which visitor addresses may reach a tunnel.
+//!
+//! Operators can restrict every tunnel of a server, and clients can further
+//! restrict their own tunnels, such as to an office network. Visitors that
+//! the rules do not permit are disconnected as soon as they are accepted,
+//! before the client hears of them.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Most rules that a client may ask for in one tunnel.
+pub const MAX_RULES: usize = 64;
+
+/// Longest text of a rule, which is an IPv6 address and its prefix length.
+pub const MAX_RULE_LENGTH: usize = "ffff:ffff:ffff:ffff:ffff:ffff:255.255.255.255/128".len();
+
+/// Block of IP addresses, such as `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether an address is in the block. IPv4 addresses mapped into IPv6,
+    /// as dual-stack servers see them, count as IPv4.
+    ///
+    /// ```
+    /// use bore_cli::acl::Cidr;
+    ///
+    /// let office: Cidr = "198.51.100.0/24".parse().unwrap();
+    /// assert!(office.contains("198.51.100.17".parse().unwrap()));
+    /// assert!(office.contains("::ffff:198.51.100.17".parse().unwrap()));
+    /// assert!(!office.contains("198.51.101.1".parse().unwrap()));
+    /// ```
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parse a block as `ADDR/PREFIX`, or a single address.
+    ///
+    /// ```
+    /// use bore_cli::acl::Cidr;
+    ///
+    /// assert!("10.0.0.0/8".parse::<Cidr>().is_ok());
+    /// assert!("2001:db8::/32".parse::<Cidr>().is_ok());
+    /// assert_eq!("192.0.2.1".parse::<Cidr>(), "192.0.2.1/32".parse());
+    /// assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    /// ```
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match input.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (input, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in {input:?}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length in {input:?}"))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Blocks of addresses that may, or may not, reach a tunnel.
+///
+/// ```
+/// use bore_cli::acl::AccessList;
+///
+/// let list = AccessList::new(
+///     vec!["10.0.0.0/8".parse().unwrap()],
+///     vec!["10.0.66.0/24".parse().unwrap()],
+/// );
+/// assert!(list.permits("10.1.2.3".parse().unwrap()));
+/// assert!(!list.permits("10.0.66.1".parse().unwrap()));
+/// assert!(!list.permits("203.0.113.7".parse().unwrap()));
+/// assert!(AccessList::default().permits("203.0.113.7".parse().unwrap()));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Permit only addresses in `allow`, unless it is empty, and never those
+    /// in `deny`.
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Whether the list permits every address.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether a visitor from an address may reach the tunnel.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip));
+        allowed && !self.deny.iter().any(|net| net.contains(ip))
+    }
+}
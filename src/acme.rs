@@ -0,0 +1,660 @@
+//! HTTPS for HTTP tunnels, with certificates from an ACME authority.
+//!
+//! With a TLS port next to the shared HTTP port, the server terminates TLS
+//! for HTTP tunnels and routes the decrypted requests like those on the HTTP
+//! port, so every tunnel gets an HTTPS URL. Certificates come from an ACME
+//! authority such as Let's Encrypt, with nothing to configure on the client:
+//!
+//! - With the `tls-alpn-01` challenge, each hostname gets a certificate of
+//!   its own when a tunnel first opens there, proven on the TLS port. With
+//!   `http-01`, the proof is served on the HTTP port, which must be port 80.
+//! - With the `dns-01` challenge, a single wildcard certificate covers every
+//!   tunnel. A hook program publishes the TXT record that proves control of
+//!   the domain, as DNS providers each have their own API.
+//!
+//! Certificates are kept in a cache directory across restarts, and renewed
+//! in the background a month before they expire. A renewed certificate is
+//! used from the next handshake on, without dropping any connection.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::time::sleep;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+
+use crate::{process, tls};
+
+/// Directory of Let's Encrypt, the default authority.
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// ALPN protocol of `tls-alpn-01` challenges, on which the authority expects
+/// the challenge certificate instead of the real one.
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Lifetime assumed for certificates, which is that of Let's Encrypt's.
+const LIFETIME: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// Time before a certificate expires that it is renewed.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Time between checks for certificates that are due for renewal.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Time between checks on an order or authorization that is in progress.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Checks on an order or authorization before giving up on it.
+const POLL_ATTEMPTS: u32 = 60;
+
+/// Name of the file in the cache directory with the account key.
+const ACCOUNT_KEY_FILE: &str = "account.der";
+
+/// How to prove control of a hostname to the authority.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Challenge {
+    /// A special certificate on the TLS port, for each hostname.
+    #[default]
+    TlsAlpn01,
+
+    /// A file on the HTTP port, which must be port 80, for each hostname.
+    Http01,
+
+    /// A TXT record published by a hook, for a wildcard certificate.
+    Dns01,
+}
+
+impl Challenge {
+    /// Name of the challenge in the ACME protocol.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Challenge::TlsAlpn01 => "tls-alpn-01",
+            Challenge::Http01 => "http-01",
+            Challenge::Dns01 => "dns-01",
+        }
+    }
+}
+
+/// Settings for getting certificates from an ACME authority.
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    /// URL of the authority's directory.
+    pub directory: String,
+
+    /// Email addresses that the authority may write to about certificates.
+    pub contact: Vec<String>,
+
+    /// Directory that keeps the account key and the certificates.
+    pub cache_dir: PathBuf,
+
+    /// How to prove control of hostnames.
+    pub challenge: Challenge,
+
+    /// Program that publishes and removes the TXT records of `dns-01`
+    /// challenges, told what to do through `BORE_ACME_ACTION` (`present` or
+    /// `cleanup`), `BORE_ACME_NAME`, and `BORE_ACME_VALUE`. It should only
+    /// exit once the record is visible to the authority.
+    pub dns_hook: Option<PathBuf>,
+}
+
+impl AcmeConfig {
+    /// Settings for Let's Encrypt, caching in a directory.
+    pub fn new(cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: LETS_ENCRYPT.into(),
+            contact: Vec::new(),
+            cache_dir: cache_dir.into(),
+            challenge: Challenge::default(),
+            dns_hook: None,
+        }
+    }
+}
+
+/// Certificates that the server presents on its TLS port, which change as
+/// they are issued and renewed.
+///
+/// ```
+/// use bore_cli::acme::CertStore;
+/// use std::time::SystemTime;
+/// use tokio_rustls::rustls::{Certificate, PrivateKey};
+///
+/// let cert = rcgen::generate_simple_self_signed(vec!["*.tunnel.example.com".into()]).unwrap();
+/// let chain = vec![Certificate(cert.serialize_der().unwrap())];
+/// let key = PrivateKey(cert.serialize_private_key_der());
+///
+/// let store = CertStore::default();
+/// store.insert("*.tunnel.example.com", chain, &key, SystemTime::now()).unwrap();
+/// assert!(store.get("myapp.tunnel.example.com").is_some());
+/// assert!(store.get("a.b.tunnel.example.com").is_none());
+/// assert!(store.get("tunnel.example.com").is_none());
+/// ```
+#[derive(Default)]
+pub struct CertStore {
+    certs: RwLock<HashMap<String, Issued>>,
+    alpn_challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    http_challenges: RwLock<HashMap<String, String>>,
+}
+
+/// Certificate in the store, with when to renew it.
+struct Issued {
+    key: Arc<CertifiedKey>,
+    renew_at: SystemTime,
+}
+
+impl CertStore {
+    /// Add or replace the certificate of a hostname, or of a wildcard such
+    /// as `*.tunnel.example.com`, which was issued at some time.
+    pub fn insert(
+        &self,
+        name: &str,
+        chain: Vec<Certificate>,
+        key: &PrivateKey,
+        issued: SystemTime,
+    ) -> Result<()> {
+        let issued = Issued {
+            key: certified_key(chain, key)?,
+            renew_at: issued + (LIFETIME - RENEW_BEFORE),
+        };
+        (self.certs.write().unwrap()).insert(name.to_ascii_lowercase(), issued);
+        Ok(())
+    }
+
+    /// Certificate for a hostname, or for a wildcard that covers it.
+    pub fn get(&self, hostname: &str) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap();
+        let hostname = hostname.to_ascii_lowercase();
+        let wildcard = hostname
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{parent}"));
+        (certs.get(&hostname))
+            .or_else(|| certs.get(&wildcard?))
+            .map(|issued| Arc::clone(&issued.key))
+    }
+
+    /// Key authorization of an `http-01` challenge token, if one is pending.
+    pub fn http_challenge(&self, token: &str) -> Option<String> {
+        self.http_challenges.read().unwrap().get(token).cloned()
+    }
+
+    /// Whether the certificate of a name is missing or due for renewal.
+    fn due(&self, name: &str) -> bool {
+        (self.certs.read().unwrap().get(name))
+            .is_none_or(|issued| issued.renew_at <= SystemTime::now())
+    }
+
+    /// Names of the certificates that are due for renewal.
+    fn due_names(&self) -> Vec<String> {
+        let now = SystemTime::now();
+        (self.certs.read().unwrap().iter())
+            .filter(|(_, issued)| issued.renew_at <= now)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let name = hello.server_name()?.to_ascii_lowercase();
+        let challenge =
+            (hello.alpn()).is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+        match challenge {
+            true => self.alpn_challenges.read().unwrap().get(&name).cloned(),
+            false => self.get(&name),
+        }
+    }
+}
+
+/// Client of an ACME authority that keeps the certificates of a domain's
+/// hostnames in a [`CertStore`].
+pub struct Acme {
+    config: AcmeConfig,
+    domain: String,
+    store: Arc<CertStore>,
+    client: reqwest::Client,
+    /// Account with the authority, once registered. Orders are placed one at
+    /// a time while holding it.
+    account: tokio::sync::Mutex<Option<Account>>,
+    /// Names with an order in progress.
+    ordering: Mutex<HashSet<String>>,
+}
+
+impl Acme {
+    /// Get certificates for hostnames under a domain, starting with those
+    /// cached from earlier runs.
+    pub fn new(config: AcmeConfig, domain: &str) -> Result<Self> {
+        let dir = &config.cache_dir;
+        fs::create_dir_all(dir).with_context(|| format!("could not create {}", dir.display()))?;
+        let store = Arc::new(CertStore::default());
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(stem) = (path.extension())
+                .filter(|extension| *extension == "crt")
+                .and(path.file_stem())
+                .and_then(|stem| stem.to_str())
+            else {
+                continue;
+            };
+            let name = stem.replace('_', "*");
+            let loaded = tls::load_certs(&path).and_then(|chain| {
+                let key = tls::load_key(&path.with_extension("key"))?;
+                let issued = fs::metadata(&path)?.modified()?;
+                store.insert(&name, chain, &key, issued)
+            });
+            match loaded {
+                Ok(()) => info!(name, "loaded cached certificate"),
+                Err(err) => warn!(%err, name, "ignoring cached certificate"),
+            }
+        }
+        Ok(Self {
+            config,
+            domain: domain.trim_end_matches('.').to_ascii_lowercase(),
+            store,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .expect("failed to create HTTP client"),
+            account: tokio::sync::Mutex::new(None),
+            ordering: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Certificates of the domain's hostnames.
+    pub fn store(&self) -> &Arc<CertStore> {
+        &self.store
+    }
+
+    /// TLS acceptor that presents the certificates of the store, and answers
+    /// `tls-alpn-01` challenges.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        let store: Arc<dyn ResolvesServerCert> = self.store.clone();
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(store);
+        config.alpn_protocols = vec![b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    /// Make sure that there is a current certificate for a hostname, ordering
+    /// one if needed. With `dns-01`, this is the domain's wildcard.
+    pub async fn ensure(&self, hostname: &str) {
+        let name = match self.config.challenge {
+            Challenge::Dns01 => format!("*.{}", self.domain),
+            _ => hostname.to_ascii_lowercase(),
+        };
+        if !self.store.due(&name) || !self.ordering.lock().unwrap().insert(name.clone()) {
+            return;
+        }
+        info!(
+            name,
+            challenge = self.config.challenge.as_str(),
+            "ordering certificate"
+        );
+        match self.issue(&name).await {
+            Ok(()) => info!(name, "certificate issued"),
+            Err(err) => warn!(err = format!("{err:#}"), name, "could not get certificate"),
+        }
+        self.ordering.lock().unwrap().remove(&name);
+    }
+
+    /// Renew certificates that are due, as long as their hostname is still in
+    /// use, until the task is aborted.
+    pub async fn run(&self, in_use: impl Fn(&str) -> bool) {
+        loop {
+            if self.config.challenge == Challenge::Dns01 {
+                self.ensure(&self.domain).await;
+            }
+            for name in self.store.due_names() {
+                if !name.starts_with("*.") && in_use(&name) {
+                    self.ensure(&name).await;
+                }
+            }
+            sleep(CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Order a certificate for a name and add it to the store.
+    async fn issue(&self, name: &str) -> Result<()> {
+        let mut account = self.account.lock().await;
+        let account = match &mut *account {
+            Some(account) => account,
+            None => account.insert(self.register().await?),
+        };
+        let identifiers = json!({ "identifiers": [{ "type": "dns", "value": name }] });
+        let new_order = account.directory.new_order.clone();
+        let response = account
+            .post(&self.client, &new_order, Some(&identifiers))
+            .await?;
+        let order_url = location(&response)?;
+        let order: Value = response.json().await?;
+        for authorization in order["authorizations"].as_array().into_iter().flatten() {
+            let url = authorization
+                .as_str()
+                .context("invalid authorization URL")?;
+            self.authorize(account, url).await?;
+        }
+
+        let mut params = CertificateParams::new(vec![name.to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        let cert = rcgen::Certificate::from_params(params)?;
+        let csr = json!({ "csr": BASE64.encode(cert.serialize_request_der()?) });
+        let finalize = order["finalize"]
+            .as_str()
+            .context("order has no finalize URL")?;
+        account.post(&self.client, finalize, Some(&csr)).await?;
+        let order = account.poll(&self.client, &order_url).await?;
+        if order["status"] != "valid" {
+            bail!("order ended up {}", order["status"]);
+        }
+        let url = order["certificate"]
+            .as_str()
+            .context("order has no certificate")?;
+        let chain = account.post(&self.client, url, None).await?.text().await?;
+
+        let stem = self.config.cache_dir.join(name.replace('*', "_"));
+        write_private(
+            &stem.with_extension("key"),
+            cert.serialize_private_key_pem(),
+        )?;
+        write_private(&stem.with_extension("crt"), &chain)?;
+        let key = PrivateKey(cert.serialize_private_key_der());
+        let chain = rustls_pemfile::certs(&mut chain.as_bytes())?;
+        let chain = chain.into_iter().map(Certificate).collect();
+        self.store.insert(name, chain, &key, SystemTime::now())
+    }
+
+    /// Complete the challenge of an authorization, if it is not yet valid.
+    async fn authorize(&self, account: &mut Account, url: &str) -> Result<()> {
+        let authorization: Value = account.post(&self.client, url, None).await?.json().await?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+        let identifier = (authorization["identifier"]["value"].as_str())
+            .context("authorization has no identifier")?
+            .to_string();
+        let kind = self.config.challenge.as_str();
+        let challenge = (authorization["challenges"].as_array().into_iter().flatten())
+            .find(|challenge| challenge["type"] == kind)
+            .with_context(|| format!("authority does not offer {kind} for {identifier}"))?;
+        let token = challenge["token"]
+            .as_str()
+            .context("challenge has no token")?;
+        let challenge_url = challenge["url"].as_str().context("challenge has no URL")?;
+        let key_authorization = format!("{token}.{}", account.thumbprint);
+        let dns = (
+            format!("_acme-challenge.{identifier}"),
+            BASE64.encode(Sha256::digest(key_authorization.as_bytes())),
+        );
+
+        match self.config.challenge {
+            Challenge::TlsAlpn01 => {
+                let cert = challenge_cert(&identifier, &key_authorization)?;
+                (self.store.alpn_challenges.write().unwrap()).insert(identifier.clone(), cert);
+            }
+            Challenge::Http01 => {
+                (self.store.http_challenges.write().unwrap())
+                    .insert(token.to_string(), key_authorization);
+            }
+            Challenge::Dns01 => self.dns_hook("present", &dns).await?,
+        }
+        let result = async {
+            account
+                .post(&self.client, challenge_url, Some(&json!({})))
+                .await?;
+            account.poll(&self.client, url).await
+        }
+        .await;
+        match self.config.challenge {
+            Challenge::TlsAlpn01 => {
+                self.store
+                    .alpn_challenges
+                    .write()
+                    .unwrap()
+                    .remove(&identifier);
+            }
+            Challenge::Http01 => {
+                self.store.http_challenges.write().unwrap().remove(token);
+            }
+            Challenge::Dns01 => {
+                if let Err(err) = self.dns_hook("cleanup", &dns).await {
+                    warn!(%err, "could not remove challenge TXT record");
+                }
+            }
+        }
+        let authorization = result?;
+        if authorization["status"] != "valid" {
+            let detail = (authorization["challenges"].as_array().into_iter().flatten())
+                .find_map(|challenge| challenge["error"]["detail"].as_str())
+                .unwrap_or("no details");
+            bail!("could not prove control of {identifier}: {detail}");
+        }
+        Ok(())
+    }
+
+    /// Run the DNS hook to publish or remove a TXT record.
+    async fn dns_hook(&self, action: &str, (name, value): &(String, String)) -> Result<()> {
+        let hook = (self.config.dns_hook.as_deref()).context("dns-01 needs a DNS hook")?;
+        let envs = [
+            ("BORE_ACME_ACTION", action.to_string()),
+            ("BORE_ACME_NAME", name.clone()),
+            ("BORE_ACME_VALUE", value.clone()),
+        ];
+        let status = process::run_hook(hook, &envs).await?;
+        if !status.success() {
+            bail!("DNS hook failed to {action} the TXT record with {status}");
+        }
+        Ok(())
+    }
+
+    /// Create an account with the authority, with the cached key or a new one.
+    async fn register(&self) -> Result<Account> {
+        let directory: Directory = (self.client.get(&self.config.directory).send().await)
+            .and_then(|response| response.error_for_status())
+            .context("could not reach ACME directory")?
+            .json()
+            .await?;
+        let path = self.config.cache_dir.join(ACCOUNT_KEY_FILE);
+        let pkcs8 = match fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(_) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(
+                    &ECDSA_P256_SHA256_FIXED_SIGNING,
+                    &SystemRandom::new(),
+                )
+                .map_err(|_| anyhow!("could not generate account key"))?;
+                write_private(&path, pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &pkcs8,
+            &SystemRandom::new(),
+        )
+        .map_err(|_| anyhow!("invalid account key in {}", path.display()))?;
+        let (jwk, thumbprint) = jwk(key.public_key().as_ref());
+        let mut account = Account {
+            key,
+            jwk,
+            thumbprint,
+            kid: None,
+            nonce: None,
+            directory,
+        };
+        let payload = json!({
+            "termsOfServiceAgreed": true,
+            "contact": self.config.contact.iter().map(|email| format!("mailto:{email}")).collect::<Vec<_>>(),
+        });
+        let new_account = account.directory.new_account.clone();
+        let response = (account
+            .post(&self.client, &new_account, Some(&payload))
+            .await)
+            .context("could not register ACME account")?;
+        account.kid = Some(location(&response)?);
+        Ok(account)
+    }
+}
+
+/// URLs of the authority's resources.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// Account with an authority, which signs requests.
+struct Account {
+    key: EcdsaKeyPair,
+    jwk: Value,
+    thumbprint: String,
+    kid: Option<String>,
+    nonce: Option<String>,
+    directory: Directory,
+}
+
+impl Account {
+    /// Send a signed request, or a signed GET if there is no payload,
+    /// retrying once with a fresh nonce if the authority rejects the nonce.
+    async fn post(
+        &mut self,
+        client: &reqwest::Client,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<reqwest::Response> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce(client).await?,
+            };
+            let response = client
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(self.sign(url, &nonce, payload)?)
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let problem: Value = response.json().await.unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            let detail = problem["detail"].as_str().unwrap_or("unknown error");
+            bail!("ACME request to {url} failed: {detail}");
+        }
+    }
+
+    /// Fetch a resource until it is no longer pending or processing.
+    async fn poll(&mut self, client: &reqwest::Client, url: &str) -> Result<Value> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource: Value = self.post(client, url, None).await?.json().await?;
+            match resource["status"].as_str() {
+                Some("pending" | "processing") => sleep(POLL_INTERVAL).await,
+                _ => return Ok(resource),
+            }
+        }
+        bail!("timed out waiting for {url}")
+    }
+
+    async fn new_nonce(&self, client: &reqwest::Client) -> Result<String> {
+        let response = client.head(&self.directory.new_nonce).send().await?;
+        replay_nonce(&response).context("authority sent no nonce")
+    }
+
+    /// Body of a request as a JSON Web Signature.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<String> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = BASE64.encode(protected.to_string());
+        let payload = payload.map_or(String::new(), |payload| BASE64.encode(payload.to_string()));
+        let signature = (self.key)
+            .sign(
+                &SystemRandom::new(),
+                format!("{protected}.{payload}").as_bytes(),
+            )
+            .map_err(|_| anyhow!("could not sign ACME request"))?;
+        let body = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": BASE64.encode(signature.as_ref()),
+        });
+        Ok(body.to_string())
+    }
+}
+
+/// JSON Web Key of a P-256 public key, along with its thumbprint.
+fn jwk(public_key: &[u8]) -> (Value, String) {
+    // The key is uncompressed: a tag byte and then both coordinates.
+    let (x, y) = public_key[1..].split_at(32);
+    let (x, y) = (BASE64.encode(x), BASE64.encode(y));
+    let thumbprint = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+    let thumbprint = BASE64.encode(Sha256::digest(thumbprint.as_bytes()));
+    (
+        json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+        thumbprint,
+    )
+}
+
+/// Self-signed certificate that answers a `tls-alpn-01` challenge.
+fn challenge_cert(name: &str, key_authorization: &str) -> Result<Arc<CertifiedKey>> {
+    let mut params = CertificateParams::new(vec![name.to_string()]);
+    let digest = Sha256::digest(key_authorization.as_bytes());
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(&digest)];
+    let cert = rcgen::Certificate::from_params(params)?;
+    let chain = vec![Certificate(cert.serialize_der()?)];
+    certified_key(chain, &PrivateKey(cert.serialize_private_key_der()))
+}
+
+fn certified_key(chain: Vec<Certificate>, key: &PrivateKey) -> Result<Arc<CertifiedKey>> {
+    let key = sign::any_supported_type(key).map_err(|_| anyhow!("unsupported private key"))?;
+    Ok(Arc::new(CertifiedKey::new(chain, key)))
+}
+
+fn location(response: &reqwest::Response) -> Result<String> {
+    (response.headers().get("Location"))
+        .and_then(|location| location.to_str().ok())
+        .map(String::from)
+        .context("authority sent no Location")
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    (response.headers().get("Replay-Nonce"))
+        .and_then(|nonce| nonce.to_str().ok())
+        .map(String::from)
+}
+
+/// Write a file that only the user can read.
+fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("could not write {}", path.display()))?;
+    file.write_all(contents.as_ref())?;
+    Ok(())
+}
@@ -0,0 +1,376 @@
+//! Authenticated HTTP API for operating a server.
+//!
+//! Operators of a shared server can see who has tunnels open and close the
+//! ones that misbehave, without restarting the server:
+//!
+//! ```text
+//! GET    /tunnels         ->  [{"port": ..., "client_addr": ..., ...}]
+//! DELETE /tunnels/<port>  ->  204 No Content
+//! GET    /config          ->  {"min_port": ..., "max_port": ..., ...}
+//! POST   /actions         {"select": {...}, "action": ...}  ->  {"ports": [...]}
+//! GET    /samples         ->  [{"port": ..., "every": ..., "bytes": ...}]
+//! PUT    /samples/<port>  {"every": 10, "bytes": 64}  ->  204 No Content
+//! DELETE /samples/<port>  ->  204 No Content
+//! ```
+//!
+//! Actions apply to every tunnel matching a selector at once, which helps
+//! during incidents with a single customer. Selectors match on the port, the
+//! user, the tunnel name, or a group that operators assign:
+//!
+//! ```text
+//! {"select": {"user_id": "acme"}, "action": "set_group", "group": "incident-7"}
+//! {"select": {"group": "incident-7"}, "action": "rate_limit", "bytes_per_second": 65536}
+//! {"select": {"group": "incident-7"}, "action": "pause"}
+//! ```
+//!
+//! Paused tunnels stay open, but leave new visitors waiting until they are
+//! resumed. Connections that are already forwarded keep going.
+//!
+//! Payload sampling, as with `--sample`, can be turned on and off for a port
+//! while diagnosing a tunnel, and applies to connections that arrive after.
+//!
+//! Every request must carry the admin token as `Authorization: Bearer <token>`.
+
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use hyper::header::AUTHORIZATION;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::daemon::{error_response, json_response, read_body};
+use crate::sampling::{SampleSpec, DEFAULT_SAMPLE_BYTES};
+use crate::server::Server;
+
+/// Description of a tunnel open on the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenTunnel {
+    /// Port that the tunnel listens on.
+    pub port: u16,
+
+    /// Address of the client that opened the tunnel.
+    pub client_addr: SocketAddr,
+
+    /// User that the client authenticated as, if the backend names one.
+    pub user_id: Option<String>,
+
+    /// Name that the client gave the tunnel, if any.
+    pub name: Option<String>,
+
+    /// Seconds that the tunnel has been open.
+    pub uptime_secs: u64,
+
+    /// Number of connections that have been forwarded.
+    pub connections: u64,
+
+    /// Bytes sent by visitors to the client.
+    pub bytes_in: u64,
+
+    /// Bytes sent by the client to visitors.
+    pub bytes_out: u64,
+
+    /// Bytes per second in both directions over the last minute.
+    pub throughput_1m: f64,
+
+    /// Bytes per second in both directions over the last five minutes.
+    pub throughput_5m: f64,
+
+    /// Milliseconds that 95% of recent connections stayed open for at most.
+    pub p95_duration_ms: Option<u64>,
+
+    /// Group that operators assigned the tunnel to, if any.
+    pub group: Option<String>,
+
+    /// Whether the tunnel is paused.
+    pub paused: bool,
+
+    /// Limit on the traffic of the tunnel, if any.
+    pub bytes_per_second: Option<u64>,
+}
+
+/// Which tunnels a bulk action applies to. Tunnels must match every field
+/// that is given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Selector {
+    /// Ports of the tunnels.
+    pub ports: Option<Vec<u16>>,
+
+    /// User that the clients authenticated as.
+    pub user_id: Option<String>,
+
+    /// Name of the tunnels.
+    pub name: Option<String>,
+
+    /// Group that the tunnels were assigned to.
+    pub group: Option<String>,
+}
+
+impl Selector {
+    /// Whether the selector leaves every field out, and would match all tunnels.
+    pub fn is_empty(&self) -> bool {
+        self.ports.is_none()
+            && self.user_id.is_none()
+            && self.name.is_none()
+            && self.group.is_none()
+    }
+
+    /// Whether a tunnel matches the selector.
+    ///
+    /// ```
+    /// use bore_cli::admin::Selector;
+    ///
+    /// let selector = Selector {
+    ///     user_id: Some("acme".into()),
+    ///     ..Default::default()
+    /// };
+    /// assert!(selector.matches(20000, Some("acme"), None, Some("incident-7")));
+    /// assert!(!selector.matches(20000, Some("globex"), None, None));
+    /// assert!(!selector.matches(20000, None, None, None));
+    /// ```
+    pub fn matches(
+        &self,
+        port: u16,
+        user_id: Option<&str>,
+        name: Option<&str>,
+        group: Option<&str>,
+    ) -> bool {
+        let field = |wanted: &Option<String>, value: Option<&str>| match wanted {
+            Some(wanted) => value == Some(wanted.as_str()),
+            None => true,
+        };
+        self.ports
+            .as_ref()
+            .is_none_or(|ports| ports.contains(&port))
+            && field(&self.user_id, user_id)
+            && field(&self.name, name)
+            && field(&self.group, group)
+    }
+}
+
+/// Action to take on every tunnel matching a selector.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BulkAction {
+    /// Stop accepting new visitors.
+    Pause,
+
+    /// Accept new visitors again.
+    Resume,
+
+    /// Close the tunnels and their connections.
+    Close,
+
+    /// Assign the tunnels to a group, or remove them from their group.
+    SetGroup {
+        /// Group to assign, or none to remove the tunnels from their group.
+        group: Option<String>,
+    },
+
+    /// Limit the traffic of each tunnel, or remove its limit.
+    RateLimit {
+        /// Bytes per second in both directions together, or none to remove
+        /// the limit.
+        bytes_per_second: Option<u64>,
+    },
+}
+
+/// Bulk action requested through the admin API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkRequest {
+    /// Which tunnels to act on.
+    pub select: Selector,
+
+    /// What to do with them.
+    #[serde(flatten)]
+    pub action: BulkAction,
+}
+
+/// Result of a bulk action.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkResult {
+    /// Ports of the tunnels that the action applied to.
+    pub ports: Vec<u16>,
+}
+
+/// Sampling of a port's connections, requested through the admin API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SampleRequest {
+    /// Sample one in this many connections.
+    pub every: u64,
+
+    /// Number of bytes captured in each direction.
+    pub bytes: usize,
+}
+
+impl Default for SampleRequest {
+    fn default() -> Self {
+        Self {
+            every: 1,
+            bytes: DEFAULT_SAMPLE_BYTES,
+        }
+    }
+}
+
+/// Settings that the server is currently running with. Secrets are left out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerSummary {
+    /// Smallest port that tunnels can listen on.
+    pub min_port: u16,
+
+    /// Largest port that tunnels can listen on.
+    pub max_port: u16,
+
+    /// Address of the control port.
+    pub bind_addr: IpAddr,
+
+    /// Address that tunnels listen on.
+    pub bind_tunnels: IpAddr,
+
+    /// How clients authenticate: `none`, `secret`, or `api_key`.
+    pub auth: String,
+
+    /// File that the settings are reloaded from, if any.
+    pub config_file: Option<PathBuf>,
+
+    /// Whether the control port requires TLS.
+    pub tls: bool,
+
+    /// Whether the server runs with the safe defaults of `--hardened`.
+    pub hardened: bool,
+
+    /// Whether clients may connect over WebSocket.
+    pub websocket: bool,
+
+    /// Most visitors of each tunnel that may wait for the client at once.
+    pub max_pending: usize,
+
+    /// Milliseconds that a visitor may wait for the client.
+    pub pending_timeout_ms: u64,
+
+    /// Milliseconds between heartbeats on control connections.
+    pub heartbeat_interval_ms: u64,
+
+    /// Milliseconds that a tunnel may carry no traffic before it is closed,
+    /// if limited.
+    #[serde(default)]
+    pub idle_timeout_ms: Option<u64>,
+}
+
+/// Serve the admin API on the given address until an error occurs.
+pub(crate) async fn serve(addr: SocketAddr, token: &str, server: Arc<Server>) -> Result<()> {
+    // Comparing digests does not reveal how much of a guess was right.
+    let token: [u8; 32] = Sha256::digest(token).into();
+    let make_service = make_service_fn(move |_| {
+        let server = Arc::clone(&server);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let server = Arc::clone(&server);
+                async move { Ok::<_, Infallible>(handle(&server, &token, req).await) }
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(&addr)?.serve(make_service);
+    info!(%addr, "admin api listening");
+    server.await?;
+    Ok(())
+}
+
+async fn handle(server: &Server, token: &[u8; 32], req: Request<Body>) -> Response<Body> {
+    let presented = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if presented.map(|presented| Sha256::digest(presented.trim())) != Some((*token).into()) {
+        warn!(path = req.uri().path(), "unauthorized admin request");
+        return error_response(StatusCode::UNAUTHORIZED, "invalid admin token");
+    }
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    match (req.method(), path.as_str()) {
+        (&Method::GET, "/tunnels") => json_response(StatusCode::OK, &server.open_tunnels()),
+        (&Method::GET, "/config") => json_response(StatusCode::OK, &server.summary()),
+        (&Method::DELETE, path) if path.starts_with("/tunnels/") => {
+            match path["/tunnels/".len()..].parse() {
+                Ok(port) if server.close_tunnel(port) => {
+                    info!(port, "tunnel closed by admin");
+                    Response::builder()
+                        .status(StatusCode::NO_CONTENT)
+                        .body(Body::empty())
+                        .unwrap()
+                }
+                _ => error_response(StatusCode::NOT_FOUND, "no tunnel on this port"),
+            }
+        }
+        (&Method::POST, "/actions") => {
+            let body = match read_body(req).await {
+                Ok(body) => body,
+                Err(response) => return response,
+            };
+            let request: BulkRequest = match serde_json::from_slice(&body) {
+                Ok(request) => request,
+                Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+            };
+            if request.select.is_empty() {
+                let message = "select tunnels by at least one of ports, user_id, name, or group";
+                return error_response(StatusCode::BAD_REQUEST, message);
+            }
+            let ports = server.apply(&request.select, &request.action);
+            info!(?ports, action = ?request.action, "applied bulk action");
+            json_response(StatusCode::OK, &BulkResult { ports })
+        }
+        (&Method::GET, "/samples") => json_response(StatusCode::OK, &server.sampler().rules()),
+        (&Method::PUT, path) if path.starts_with("/samples/") => {
+            let Ok(port) = path["/samples/".len()..].parse() else {
+                return error_response(StatusCode::NOT_FOUND, "not found");
+            };
+            let body = match read_body(req).await {
+                Ok(body) => body,
+                Err(response) => return response,
+            };
+            let request: SampleRequest = match serde_json::from_slice(&body) {
+                Ok(request) => request,
+                Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+            };
+            let spec = SampleSpec {
+                port,
+                every: request.every,
+                bytes: request.bytes,
+            };
+            if let Err(err) = spec.check() {
+                return error_response(StatusCode::BAD_REQUEST, err);
+            }
+            server.sampler().enable(spec);
+            info!(
+                port,
+                every = spec.every,
+                bytes = spec.bytes,
+                "sampling enabled by admin"
+            );
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap()
+        }
+        (&Method::DELETE, path) if path.starts_with("/samples/") => {
+            match path["/samples/".len()..].parse() {
+                Ok(port) if server.sampler().disable(port) => {
+                    info!(port, "sampling disabled by admin");
+                    Response::builder()
+                        .status(StatusCode::NO_CONTENT)
+                        .body(Body::empty())
+                        .unwrap()
+                }
+                _ => error_response(StatusCode::NOT_FOUND, "port is not sampled"),
+            }
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
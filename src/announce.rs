@@ -0,0 +1,74 @@
+//! Announcements of proxied connections to the local service.
+//!
+//! The local service only sees connections from the client, so it cannot tell
+//! who is on the other end. Services that want to know, but do not speak the
+//! PROXY protocol, can ask the client to announce each connection as one line
+//! of JSON, either at the start of the proxied stream or on a separate port.
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+/// Where the client announces each connection to the local service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Announce {
+    /// Send the announcement as the first line of the proxied stream.
+    Inline,
+
+    /// Send the announcement in its own connection to this local port.
+    Port(u16),
+}
+
+impl FromStr for Announce {
+    type Err = String;
+
+    /// Parse `inline` or a port number.
+    ///
+    /// ```
+    /// use bore_cli::announce::Announce;
+    ///
+    /// assert_eq!("inline".parse(), Ok(Announce::Inline));
+    /// assert_eq!("9000".parse(), Ok(Announce::Port(9000)));
+    /// assert!("header".parse::<Announce>().is_err());
+    /// ```
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if input == "inline" {
+            return Ok(Announce::Inline);
+        }
+        input
+            .parse()
+            .map(Announce::Port)
+            .map_err(|_| "expected `inline` or a port number".into())
+    }
+}
+
+/// Details of a proxied connection, as sent to the local service.
+#[derive(Debug, Clone, Serialize)]
+pub struct Announcement {
+    /// Identifier of the connection, which also appears in the client's logs.
+    pub id: Uuid,
+
+    /// Address of the visitor, as seen by the server.
+    pub peer: SocketAddr,
+
+    /// Port that the visitor connected to on the server.
+    pub remote_port: u16,
+
+    /// Name of the tunnel, if it has one.
+    pub tunnel: Option<String>,
+}
+
+impl Announcement {
+    /// Write the announcement as a single line of JSON.
+    pub async fn write_to(&self, stream: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        stream.write_all(&line).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+}
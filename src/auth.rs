@@ -1,27 +1,177 @@
 //! Auth implementation for bore client and server.
 
-use anyhow::{bail, ensure, Result};
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use dashmap::DashMap;
+use futures_util::future::{self, BoxFuture, FutureExt};
 use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{info, warn};
 use uuid::Uuid;
-use serde::{Deserialize, Serialize};
 
-use crate::shared::{ClientMessage, Delimited, ServerMessage};
+use crate::delegation::{SubKeyClaims, SubKeyIssuer};
+use crate::identity::ServerIdentity;
+use crate::metrics::ServerMetrics;
+use crate::shared::{
+    AuthError, AuthErrorCode, ClientMessage, Delimited, ServerBusy, ServerMessage,
+};
+use crate::transfer::TransferPeriod;
+
+/// Backend that decides which clients may use the server, such as one that
+/// checks credentials against LDAP or an internal gRPC service.
+///
+/// The server sends each client a random challenge, and passes it to the
+/// provider together with the client's answer. Identity proofs, sub-keys, and
+/// session tokens are handled by the server before the provider is asked.
+///
+/// ```
+/// use bore_cli::auth::{AuthProvider, Principal};
+/// use bore_cli::shared::{AuthError, AuthErrorCode};
+/// use futures_util::future::{self, BoxFuture, FutureExt};
+/// use uuid::Uuid;
+///
+/// /// Lets in anyone who knows the password of the day.
+/// struct Password(String);
+///
+/// impl AuthProvider for Password {
+///     fn method(&self) -> &str {
+///         "password"
+///     }
+///
+///     fn authenticate<'a>(
+///         &'a self,
+///         _challenge: &'a Uuid,
+///         answer: &'a str,
+///     ) -> BoxFuture<'a, Result<Principal, AuthError>> {
+///         let result = match answer == self.0 {
+///             true => Ok(Principal {
+///                 user_id: Some("guest".into()),
+///                 ..Default::default()
+///             }),
+///             false => Err(AuthError::new(AuthErrorCode::Failed, "wrong password")),
+///         };
+///         future::ready(result).boxed()
+///     }
+/// }
+/// ```
+pub trait AuthProvider: Send + Sync {
+    /// Name of the authentication method, as reported by the admin API.
+    fn method(&self) -> &str;
+
+    /// Check the answer of a client to a challenge, returning who it
+    /// authenticated as and the limits that apply to it.
+    ///
+    /// Failing with [`AuthErrorCode::BackendUnavailable`] means that the
+    /// provider could not decide, and the credentials of open tunnels are not
+    /// tried instead.
+    fn authenticate<'a>(
+        &'a self,
+        challenge: &'a Uuid,
+        answer: &'a str,
+    ) -> BoxFuture<'a, Result<Principal, AuthError>>;
+
+    /// Error for clients that did not answer the challenge at all.
+    fn missing(&self) -> AuthError {
+        AuthError::new(
+            AuthErrorCode::MethodNotSupported,
+            format!("server requires {} authentication", self.method()),
+        )
+    }
+}
+
+/// As the server, send a challenge to the client and check its answer with a
+/// provider, returning who the client authenticated as.
+///
+/// Answers that the provider rejects are also checked with `previous`
+/// providers, such as those still used by open tunnels. The server proves
+/// `identity` to clients that ask for it, and accepts sub-keys minted by
+/// `sub_keys` in place of the usual credential.
+pub async fn server_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut Delimited<T>,
+    provider: &dyn AuthProvider,
+    previous: &[&dyn AuthProvider],
+    identity: Option<&ServerIdentity>,
+    sub_keys: Option<&SubKeyIssuer>,
+) -> Result<Principal> {
+    let challenge = Uuid::new_v4();
+    stream.send(ServerMessage::Challenge(challenge)).await?;
+    match ServerIdentity::recv(identity, stream).await? {
+        Some(ClientMessage::Authenticate(answer)) => {
+            if let Some(claims) = SubKeyIssuer::check(sub_keys, &answer)? {
+                return Ok(Principal::sub_key(claims));
+            }
+            let err = match provider.authenticate(&challenge, &answer).await {
+                Ok(principal) => return Ok(principal),
+                Err(err) => err,
+            };
+            if err.code != AuthErrorCode::BackendUnavailable {
+                for provider in previous {
+                    if let Ok(principal) = provider.authenticate(&challenge, &answer).await {
+                        return Ok(Principal::replaced(principal.user_id, principal.quota));
+                    }
+                }
+            }
+            Err(err.into())
+        }
+        Some(ClientMessage::SessionToken(token)) => Ok(Principal::session(token)),
+        message => {
+            Err(anyhow::Error::from(provider.missing()).context(MissingCredentials(message)))
+        }
+    }
+}
+
+/// Context of a failed server handshake in which the client sent no
+/// credentials, with the message that it sent instead.
+///
+/// The error also downcasts to the [`AuthError`] of the provider, for servers
+/// that require credentials from everyone.
+#[derive(Debug)]
+pub struct MissingCredentials(pub Option<ClientMessage>);
+
+impl std::fmt::Display for MissingCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("client sent no credentials")
+    }
+}
 
 /// Wrapper around a MAC used for authenticating clients that have a secret.
-pub struct Authenticator(Hmac<Sha256>);
+pub struct Authenticator {
+    mac: Hmac<Sha256>,
+    identity: Option<Arc<ServerIdentity>>,
+    sub_keys: Option<Arc<SubKeyIssuer>>,
+}
 
 impl Authenticator {
     /// Generate an authenticator from a secret.
     pub fn new(secret: &str) -> Self {
         let hashed_secret = Sha256::new().chain_update(secret).finalize();
-        Self(Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"))
+        Self {
+            mac: Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"),
+            identity: None,
+            sub_keys: None,
+        }
+    }
+
+    /// Prove this identity to clients that ask for it during the server handshake.
+    pub fn set_identity(&mut self, identity: Arc<ServerIdentity>) {
+        self.identity = Some(identity);
+    }
+
+    /// Accept sub-keys minted by this issuer in place of the usual credential.
+    pub fn set_sub_keys(&mut self, issuer: Arc<SubKeyIssuer>) {
+        self.sub_keys = Some(issuer);
     }
 
     /// Generate a reply message for a challenge.
     pub fn answer(&self, challenge: &Uuid) -> String {
-        let mut hmac = self.0.clone();
+        let mut hmac = self.mac.clone();
         hmac.update(challenge.as_bytes());
         hex::encode(hmac.finalize().into_bytes())
     }
@@ -40,7 +190,7 @@ impl Authenticator {
     /// ```
     pub fn validate(&self, challenge: &Uuid, tag: &str) -> bool {
         if let Ok(tag) = hex::decode(tag) {
-            let mut hmac = self.0.clone();
+            let mut hmac = self.mac.clone();
             hmac.update(challenge.as_bytes());
             hmac.verify_slice(&tag).is_ok()
         } else {
@@ -49,19 +199,31 @@ impl Authenticator {
     }
 
     /// As the server, send a challenge to the client and validate their response.
+    ///
+    /// Returns who the client authenticated as.
     pub async fn server_handshake<T: AsyncRead + AsyncWrite + Unpin>(
         &self,
         stream: &mut Delimited<T>,
-    ) -> Result<()> {
-        let challenge = Uuid::new_v4();
-        stream.send(ServerMessage::Challenge(challenge)).await?;
-        match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(tag)) => {
-                ensure!(self.validate(&challenge, &tag), "invalid secret");
-                Ok(())
-            }
-            _ => bail!("server requires secret, but no secret was provided"),
-        }
+    ) -> Result<Principal> {
+        self.server_handshake_with(stream, &[]).await
+    }
+
+    /// Like [`Authenticator::server_handshake`], but also accept the secrets
+    /// of previous authenticators, such as those still used by open tunnels.
+    pub async fn server_handshake_with<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut Delimited<T>,
+        previous: &[&Authenticator],
+    ) -> Result<Principal> {
+        let previous: Vec<&dyn AuthProvider> = previous.iter().map(|auth| *auth as _).collect();
+        server_handshake(
+            stream,
+            self,
+            &previous,
+            self.identity.as_deref(),
+            self.sub_keys.as_deref(),
+        )
+        .await
     }
 
     /// As the client, answer a challenge to attempt to authenticate with the server.
@@ -71,7 +233,10 @@ impl Authenticator {
     ) -> Result<()> {
         let challenge = match stream.recv_timeout().await? {
             Some(ServerMessage::Challenge(challenge)) => challenge,
-            _ => bail!("expected authentication challenge, but no secret was required"),
+            Some(ServerMessage::Busy(millis)) => return Err(ServerBusy::from_millis(millis).into()),
+            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+            Some(ServerMessage::ErrorExt(err)) => bail!("server error: {err}"),
+            _ => bail!("expected authentication challenge, but the server does not require one"),
         };
         let tag = self.answer(&challenge);
         stream.send(ClientMessage::Authenticate(tag)).await?;
@@ -79,10 +244,383 @@ impl Authenticator {
     }
 }
 
+impl AuthProvider for Authenticator {
+    fn method(&self) -> &str {
+        "secret"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        challenge: &'a Uuid,
+        answer: &'a str,
+    ) -> BoxFuture<'a, Result<Principal, AuthError>> {
+        let result = match self.validate(challenge, answer) {
+            true => Ok(Principal::default()),
+            false => Err(AuthError::new(
+                AuthErrorCode::InvalidSecret,
+                "invalid secret",
+            )),
+        };
+        future::ready(result).boxed()
+    }
+
+    fn missing(&self) -> AuthError {
+        AuthError::new(
+            AuthErrorCode::MethodNotSupported,
+            "server requires secret, but no secret was provided",
+        )
+    }
+}
+
+/// Short name of a secret that tells it apart in logs without revealing it.
+///
+/// ```
+/// use bore_cli::auth::secret_fingerprint;
+///
+/// assert_eq!(secret_fingerprint("old").len(), 8);
+/// assert_ne!(secret_fingerprint("old"), secret_fingerprint("new"));
+/// ```
+pub fn secret_fingerprint(secret: &str) -> String {
+    // Domain-separated, so that it shares nothing with the key of the MAC.
+    let digest = Sha256::new()
+        .chain_update("bore secret fingerprint")
+        .chain_update(secret)
+        .finalize();
+    hex::encode(&digest[..4])
+}
+
+/// Secrets that the server accepts at once, each with a label for logs, so
+/// that clients can move to a new secret while the old one still works.
+///
+/// ```
+/// use bore_cli::auth::{AuthProvider, Authenticator, SecretSet};
+/// use uuid::Uuid;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let mut secrets = SecretSet::default();
+/// secrets.add("2024", "old");
+/// secrets.add("2025", "new");
+///
+/// let challenge = Uuid::new_v4();
+/// let answer = Authenticator::new("old").answer(&challenge);
+/// let principal = secrets.authenticate(&challenge, &answer).await.unwrap();
+/// assert_eq!(principal.secret_label.as_deref(), Some("2024"));
+/// # }
+/// ```
+#[derive(Default)]
+pub struct SecretSet {
+    secrets: Vec<(String, Authenticator)>,
+}
+
+impl SecretSet {
+    /// Accept a secret, logging clients that use it with this label.
+    pub fn add(&mut self, label: &str, secret: &str) {
+        self.secrets
+            .push((label.to_string(), Authenticator::new(secret)));
+    }
+
+    /// Whether no secrets were added.
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+}
+
+impl AuthProvider for SecretSet {
+    fn method(&self) -> &str {
+        "secret"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        challenge: &'a Uuid,
+        answer: &'a str,
+    ) -> BoxFuture<'a, Result<Principal, AuthError>> {
+        let label = self
+            .secrets
+            .iter()
+            .find(|(_, auth)| auth.validate(challenge, answer))
+            .map(|(label, _)| label.clone());
+        let result = match label {
+            Some(label) => Ok(Principal {
+                secret_label: Some(label),
+                ..Default::default()
+            }),
+            None => Err(AuthError::new(
+                AuthErrorCode::InvalidSecret,
+                "invalid secret",
+            )),
+        };
+        future::ready(result).boxed()
+    }
+
+    fn missing(&self) -> AuthError {
+        AuthError::new(
+            AuthErrorCode::MethodNotSupported,
+            "server requires secret, but no secret was provided",
+        )
+    }
+}
+
+/// Who a client authenticated as, according to the server.
+#[derive(Debug, Clone, Default)]
+pub struct Principal {
+    /// User that the validation backend associated with the API key.
+    pub user_id: Option<String>,
+
+    /// Claims of the sub-key that the client used, if any.
+    pub sub_key: Option<SubKeyClaims>,
+
+    /// Whether the client used a credential that the server has since
+    /// replaced, which is only good for data connections of open tunnels.
+    pub replaced: bool,
+
+    /// Limits that the validation backend set for the user.
+    pub quota: Quota,
+
+    /// Session token that the client presented instead of credentials. The
+    /// server has yet to check it against the connection being accepted.
+    pub session_token: Option<String>,
+
+    /// Label of the secret that the client answered with.
+    pub secret_label: Option<String>,
+
+    /// Digest of the API key that the client used, which tells keys apart
+    /// without revealing them.
+    pub api_key_digest: Option<String>,
+
+    /// Tier of service that the validation backend put the user in, which
+    /// the server can restrict ports by.
+    pub tier: Option<String>,
+
+    /// Whether the client sent no credentials, to a server that lets such
+    /// clients open tunnels on random high ports.
+    pub anonymous: bool,
+}
+
+/// Limits on the tunnels of one user, set by the validation backend when it
+/// confirms an API key. Limits that are left out do not apply.
+///
+/// ```
+/// use bore_cli::auth::Quota;
+/// use bore_cli::transfer::TransferPeriod;
+///
+/// let quota: Quota = serde_json::from_str(r#"{"max_tunnels": 2, "min_port": 20000}"#).unwrap();
+/// assert_eq!(quota.port_range(1024..=65535), 20000..=65535);
+/// assert_eq!(quota.max_bytes_per_second, None);
+/// assert_eq!(quota.transfer_period, TransferPeriod::Total);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quota {
+    /// Most tunnels that the user may have open at once.
+    pub max_tunnels: Option<u32>,
+
+    /// Smallest port that the user's tunnels may listen on.
+    pub min_port: Option<u16>,
+
+    /// Largest port that the user's tunnels may listen on.
+    pub max_port: Option<u16>,
+
+    /// Most bytes per second through each of the user's tunnels.
+    pub max_bytes_per_second: Option<u64>,
+
+    /// Most bytes that each of the user's tunnels may transfer, counted in
+    /// both directions over `transfer_period`.
+    pub max_transfer_bytes: Option<u64>,
+
+    /// Period over which `max_transfer_bytes` applies.
+    pub transfer_period: TransferPeriod,
+}
+
+impl Quota {
+    /// Narrow a range of ports to those that the user may use.
+    pub fn port_range(&self, range: RangeInclusive<u16>) -> RangeInclusive<u16> {
+        let start = self
+            .min_port
+            .map_or(*range.start(), |min| min.max(*range.start()));
+        let end = self
+            .max_port
+            .map_or(*range.end(), |max| max.min(*range.end()));
+        start..=end
+    }
+}
+
+impl Principal {
+    fn sub_key(claims: SubKeyClaims) -> Self {
+        Self {
+            sub_key: Some(claims),
+            ..Default::default()
+        }
+    }
+
+    fn session(token: String) -> Self {
+        Self {
+            session_token: Some(token),
+            ..Default::default()
+        }
+    }
+
+    fn replaced(user_id: Option<String>, quota: Quota) -> Self {
+        Self {
+            user_id,
+            replaced: true,
+            quota,
+            ..Default::default()
+        }
+    }
+}
+
+/// Default time that the backend's acceptance of an API key is reused.
+pub const VALIDATION_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Default time that the backend's rejection of an API key is reused.
+pub const VALIDATION_NEGATIVE_TTL: Duration = Duration::from_secs(10);
+
+/// Most answers from the backend kept at once, beyond which expired ones are
+/// cleared out.
+const MAX_CACHED_VALIDATIONS: usize = 10_000;
+
+/// Default number of failed requests in a row after which the backend is
+/// considered degraded.
+pub const VALIDATION_FAILURE_THRESHOLD: u32 = 3;
+
+/// Default longest wait before asking a degraded backend again.
+pub const VALIDATION_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Wait before asking a backend again once it is degraded, doubled after each
+/// failure.
+const VALIDATION_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How the API key authenticator copes with outages of its backend.
+///
+/// After a few failed requests in a row, the backend is considered degraded
+/// and is only asked again after a backoff, so that a reconnect storm does not
+/// pile onto it. Meanwhile, keys that it accepted recently can still be let
+/// in for a grace period past the expiry of their cached validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutagePolicy {
+    /// Time past its expiry that a cached acceptance of a key still lets the
+    /// client in while the backend is unavailable. Zero fails closed.
+    pub grace: Duration,
+
+    /// Failed requests in a row after which the backend is considered
+    /// degraded. Zero keeps asking it on every connection.
+    pub failure_threshold: u32,
+
+    /// Longest wait before asking a degraded backend again.
+    pub max_backoff: Duration,
+}
+
+impl Default for OutagePolicy {
+    fn default() -> Self {
+        Self {
+            grace: Duration::ZERO,
+            failure_threshold: VALIDATION_FAILURE_THRESHOLD,
+            max_backoff: VALIDATION_MAX_BACKOFF,
+        }
+    }
+}
+
+/// How requests to the API key validation backend are made, for backends
+/// that sit behind mutual TLS or a gateway that wants a service token.
+///
+/// ```
+/// use bore_cli::auth::ValidationRequestOptions;
+///
+/// let options = ValidationRequestOptions {
+///     headers: vec![("X-Service-Auth".into(), "s3cret".into())],
+///     ..Default::default()
+/// };
+/// assert!(options.client().is_ok());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ValidationRequestOptions {
+    /// Headers added to every request, by name.
+    pub headers: Vec<(String, String)>,
+
+    /// PEM file with the certificate that the server presents to the backend.
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the certificate, unless it is in the
+    /// certificate's file.
+    pub client_key: Option<PathBuf>,
+
+    /// PEM file of CA certificates to trust for the backend, on top of the
+    /// usual public roots.
+    pub ca: Option<PathBuf>,
+}
+
+impl ValidationRequestOptions {
+    /// Build an HTTP client that makes requests this way.
+    pub fn client(&self) -> Result<reqwest::Client> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name {name:?}"))?;
+            let mut value = reqwest::header::HeaderValue::from_str(value)
+                .with_context(|| format!("invalid value for header {name}"))?;
+            value.set_sensitive(true);
+            headers.append(name, value);
+        }
+        let mut builder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .default_headers(headers);
+        if let Some(cert) = &self.client_cert {
+            let mut pem = read_pem(cert)?;
+            if let Some(key) = &self.client_key {
+                pem.extend(read_pem(key)?);
+            }
+            let identity = reqwest::Identity::from_pem(&pem)
+                .context("invalid client certificate or key for the validation backend")?;
+            builder = builder.identity(identity);
+        }
+        if let Some(ca) = &self.ca {
+            for cert in reqwest::Certificate::from_pem_bundle(&read_pem(ca)?)
+                .with_context(|| format!("invalid CA certificate in {}", ca.display()))?
+            {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        builder.build().context("could not create HTTP client")
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("could not read {}", path.display()))
+}
+
+/// Failed requests to a backend in a row, and when it may be asked again.
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// URL of a validation backend, with the health of the backend.
+struct Endpoint {
+    url: String,
+    breaker: Mutex<Breaker>,
+}
+
 /// API Key Authenticator that validates against NativeBridge backend
+///
+/// With several validation URLs, each key is checked with the first backend
+/// that is not degraded, failing over to the next when a request fails.
 pub struct ApiKeyAuthenticator {
-    validation_url: String,
+    endpoints: Vec<Endpoint>,
     client: reqwest::Client,
+    identity: Option<Arc<ServerIdentity>>,
+    sub_keys: Option<Arc<SubKeyIssuer>>,
+
+    /// Recent answers from the backend, by hash of the API key, with when
+    /// they expire.
+    cache: DashMap<[u8; 32], (Instant, ValidationResponse)>,
+    cache_ttl: Duration,
+    negative_ttl: Duration,
+
+    outage: OutagePolicy,
+    metrics: Option<Arc<ServerMetrics>>,
 }
 
 #[derive(Serialize)]
@@ -90,32 +628,195 @@ struct ValidationRequest {
     api_key: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 struct ValidationResponse {
     valid: bool,
     #[serde(default)]
     user_id: Option<String>,
     #[serde(default)]
-    error: Option<String>,
+    limits: Quota,
+    #[serde(default)]
+    tier: Option<String>,
 }
 
 impl ApiKeyAuthenticator {
     /// Create a new API key authenticator with the validation URL
     pub fn new(validation_url: String) -> Self {
+        Self::with_urls(vec![validation_url])
+    }
+
+    /// Create an API key authenticator that asks the first healthy backend of
+    /// several, in order.
+    pub fn with_urls(validation_urls: Vec<String>) -> Self {
+        assert!(!validation_urls.is_empty(), "must provide a validation URL");
+        let endpoints = (validation_urls.into_iter())
+            .map(|url| Endpoint {
+                url,
+                breaker: Mutex::new(Breaker::default()),
+            })
+            .collect();
         Self {
-            validation_url,
-            client: reqwest::Client::builder()
-                .timeout(std::time::Duration::from_secs(5))
-                .build()
+            endpoints,
+            client: ValidationRequestOptions::default()
+                .client()
                 .expect("failed to create HTTP client"),
+            identity: None,
+            sub_keys: None,
+            cache: DashMap::new(),
+            cache_ttl: VALIDATION_CACHE_TTL,
+            negative_ttl: VALIDATION_NEGATIVE_TTL,
+            outage: OutagePolicy::default(),
+            metrics: None,
+        }
+    }
+
+    /// Prove this identity to clients that ask for it during the server handshake.
+    pub fn set_identity(&mut self, identity: Arc<ServerIdentity>) {
+        self.identity = Some(identity);
+    }
+
+    /// Accept sub-keys minted by this issuer in place of the usual credential.
+    pub fn set_sub_keys(&mut self, issuer: Arc<SubKeyIssuer>) {
+        self.sub_keys = Some(issuer);
+    }
+
+    /// Set how long the backend's acceptance and rejection of a key are
+    /// reused before asking it again. Zero disables caching.
+    pub fn set_cache_ttl(&mut self, ttl: Duration, negative_ttl: Duration) {
+        self.cache_ttl = ttl;
+        self.negative_ttl = negative_ttl;
+    }
+
+    /// Make requests to the backend with this client, such as one built from
+    /// [`ValidationRequestOptions`].
+    pub fn set_client(&mut self, client: reqwest::Client) {
+        self.client = client;
+    }
+
+    /// Set how outages of the backend are handled.
+    pub fn set_outage_policy(&mut self, policy: OutagePolicy) {
+        self.outage = policy;
+    }
+
+    /// Report the health of the backend in these metrics.
+    pub fn set_metrics(&mut self, metrics: Arc<ServerMetrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Validate an API key, reusing a recent answer from the backend.
+    async fn validate_api_key(&self, api_key: &str) -> Result<ValidationResponse> {
+        let key: [u8; 32] = Sha256::digest(api_key).into();
+        let cached = self.cache.get(&key).map(|entry| entry.clone());
+        if let Some((expires, validation)) = &cached {
+            if Instant::now() < *expires {
+                return Ok(validation.clone());
+            }
+        }
+        let validation = match self.ask_backend(api_key).await {
+            Ok(validation) => validation,
+            Err(err) => {
+                // Keep letting in keys that the backend accepted recently.
+                match cached {
+                    Some((expires, validation))
+                        if validation.valid && Instant::now() < expires + self.outage.grace =>
+                    {
+                        warn!(%err, user_id = ?validation.user_id, "validation backend is unavailable, reusing an expired acceptance");
+                        if let Some(metrics) = &self.metrics {
+                            metrics.add_validation_grace();
+                        }
+                        return Ok(validation);
+                    }
+                    _ => return Err(err),
+                }
+            }
+        };
+        let ttl = match validation.valid {
+            true => self.cache_ttl,
+            false => self.negative_ttl,
+        };
+        if self.cache.len() >= MAX_CACHED_VALIDATIONS {
+            let now = Instant::now();
+            let grace = self.outage.grace;
+            self.cache.retain(|_, (expires, _)| *expires + grace > now);
+        }
+        if !ttl.is_zero() && self.cache.len() < MAX_CACHED_VALIDATIONS {
+            self.cache
+                .insert(key, (Instant::now() + ttl, validation.clone()));
+        }
+        Ok(validation)
+    }
+
+    /// Ask the backends about an API key in order, skipping those that are
+    /// degraded and not due to be asked again yet.
+    async fn ask_backend(&self, api_key: &str) -> Result<ValidationResponse> {
+        let mut last_err = None;
+        for endpoint in &self.endpoints {
+            if let Some(retry_at) = endpoint.breaker.lock().unwrap().retry_at {
+                let now = Instant::now();
+                if now < retry_at {
+                    last_err = Some(anyhow!(
+                        "validation backend {} is degraded, asking it again in {:?}",
+                        endpoint.url,
+                        retry_at - now
+                    ));
+                    continue;
+                }
+            }
+            match self.request_validation(&endpoint.url, api_key).await {
+                Ok(validation) => {
+                    self.backend_answered(endpoint);
+                    return Ok(validation);
+                }
+                Err(err) => {
+                    self.backend_failed(endpoint, &err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("authenticator has a validation URL"))
+    }
+
+    /// Mark a backend as healthy after it answered.
+    fn backend_answered(&self, endpoint: &Endpoint) {
+        let threshold = self.outage.failure_threshold;
+        let mut breaker = endpoint.breaker.lock().unwrap();
+        if threshold > 0 && breaker.failures >= threshold {
+            info!(url = %endpoint.url, "API key validation backend recovered");
+            if let Some(metrics) = &self.metrics {
+                metrics.set_validation_degraded(&endpoint.url, false);
+            }
         }
+        *breaker = Breaker::default();
     }
 
-    /// Validate an API key against the backend
-    async fn validate_api_key(&self, api_key: &str) -> Result<bool> {
+    /// Count a failed request to a backend, backing off from it once it has
+    /// failed too many times in a row.
+    fn backend_failed(&self, endpoint: &Endpoint, err: &anyhow::Error) {
+        let threshold = self.outage.failure_threshold;
+        let mut breaker = endpoint.breaker.lock().unwrap();
+        breaker.failures += 1;
+        if let Some(metrics) = &self.metrics {
+            metrics.add_validation_failure();
+        }
+        if threshold > 0 && breaker.failures >= threshold {
+            let doublings = (breaker.failures - threshold).min(16);
+            let backoff =
+                (VALIDATION_INITIAL_BACKOFF * 2u32.pow(doublings)).min(self.outage.max_backoff);
+            breaker.retry_at = Some(Instant::now() + backoff);
+            if breaker.failures == threshold {
+                warn!(%err, url = %endpoint.url, failures = threshold, "API key validation backend is degraded, backing off");
+                if let Some(metrics) = &self.metrics {
+                    metrics.set_validation_degraded(&endpoint.url, true);
+                }
+            }
+        }
+    }
+
+    /// Validate an API key against a backend
+    async fn request_validation(&self, url: &str, api_key: &str) -> Result<ValidationResponse> {
         let response = self
             .client
-            .post(&self.validation_url)
+            .post(url)
             .header("Authorization", format!("Bearer {}", api_key))
             .json(&ValidationRequest {
                 api_key: api_key.to_string(),
@@ -125,31 +826,45 @@ impl ApiKeyAuthenticator {
 
         if response.status().is_success() {
             let validation: ValidationResponse = response.json().await?;
-            Ok(validation.valid)
+            Ok(validation)
+        } else if response.status().is_server_error() {
+            bail!("validation backend returned {}", response.status())
         } else {
-            Ok(false)
+            Ok(ValidationResponse {
+                valid: false,
+                user_id: None,
+                limits: Quota::default(),
+                tier: None,
+            })
         }
     }
 
-    /// Server-side handshake: receive API key and validate it
+    /// Server-side handshake: receive API key and validate it, returning who
+    /// the client authenticated as
     pub async fn server_handshake<T: AsyncRead + AsyncWrite + Unpin>(
         &self,
         stream: &mut Delimited<T>,
-    ) -> Result<()> {
-        let challenge = Uuid::new_v4();
-        stream.send(ServerMessage::Challenge(challenge)).await?;
-
-        match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(api_key)) => {
-                // Validate API key with backend
-                let is_valid = self.validate_api_key(&api_key).await
-                    .unwrap_or(false);
+    ) -> Result<Principal> {
+        self.server_handshake_with(stream, &[]).await
+    }
 
-                ensure!(is_valid, "invalid API key");
-                Ok(())
-            }
-            _ => bail!("server requires API key authentication"),
-        }
+    /// Like [`ApiKeyAuthenticator::server_handshake`], but fall back to
+    /// previous backends for keys that this one rejects, such as those of
+    /// open tunnels.
+    pub async fn server_handshake_with<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut Delimited<T>,
+        previous: &[&ApiKeyAuthenticator],
+    ) -> Result<Principal> {
+        let previous: Vec<&dyn AuthProvider> = previous.iter().map(|auth| *auth as _).collect();
+        server_handshake(
+            stream,
+            self,
+            &previous,
+            self.identity.as_deref(),
+            self.sub_keys.as_deref(),
+        )
+        .await
     }
 
     /// Client-side handshake: send API key for validation
@@ -160,10 +875,58 @@ impl ApiKeyAuthenticator {
         match stream.recv_timeout().await? {
             Some(ServerMessage::Challenge(_)) => {
                 // Send API key instead of HMAC
-                stream.send(ClientMessage::Authenticate(api_key.to_string())).await?;
+                stream
+                    .send(ClientMessage::Authenticate(api_key.to_string()))
+                    .await?;
                 Ok(())
             }
-            _ => bail!("expected authentication challenge"),
+            Some(ServerMessage::Busy(millis)) => Err(ServerBusy::from_millis(millis).into()),
+            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+            Some(ServerMessage::ErrorExt(err)) => bail!("server error: {err}"),
+            _ => bail!("expected authentication challenge, but the server does not require one"),
         }
     }
 }
+
+impl AuthProvider for ApiKeyAuthenticator {
+    fn method(&self) -> &str {
+        "api_key"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        _challenge: &'a Uuid,
+        api_key: &'a str,
+    ) -> BoxFuture<'a, Result<Principal, AuthError>> {
+        async move {
+            match self.validate_api_key(api_key).await {
+                Ok(validation) if validation.valid => Ok(Principal {
+                    user_id: validation.user_id,
+                    quota: validation.limits,
+                    api_key_digest: Some(hex::encode(Sha256::digest(api_key))),
+                    tier: validation.tier,
+                    ..Default::default()
+                }),
+                Ok(_) => Err(AuthError::new(
+                    AuthErrorCode::InvalidApiKey,
+                    "invalid API key",
+                )),
+                Err(err) => {
+                    warn!(%err, "could not reach API key validation backend");
+                    Err(AuthError::new(
+                        AuthErrorCode::BackendUnavailable,
+                        "API key validation backend is unavailable",
+                    ))
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn missing(&self) -> AuthError {
+        AuthError::new(
+            AuthErrorCode::MethodNotSupported,
+            "server requires API key authentication",
+        )
+    }
+}
@@ -0,0 +1,169 @@
+//! In-memory connections between a client and a server in the same process.
+//!
+//! A server that is given a [`Broker`] accepts control connections from it
+//! instead of the control port, and its tunnels listen on the broker instead
+//! of public ports. Clients reach the server by setting
+//! [`ClientOptions::broker`](crate::client::ClientOptions::broker), and
+//! visitors connect to tunnels with [`Broker::visit`]. Only the client's
+//! connections to its local service use the network, which makes for fast
+//! integration tests and lets applications embed bore as a connection broker.
+//!
+//! Connections over a broker are never wrapped in TLS or WebSocket, since
+//! they do not leave the process.
+
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use dashmap::{mapref::entry::Entry, DashMap};
+use tokio::io::{duplex, DuplexStream};
+use tokio::sync::mpsc;
+
+/// Capacity of each direction of an in-memory connection, in bytes.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Address reported for the far end of in-memory connections.
+pub(crate) const MEMORY_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+/// Handle to an in-memory network between clients, a server, and visitors.
+///
+/// Clones refer to the same broker.
+///
+/// ```
+/// use bore_cli::broker::Broker;
+/// use bore_cli::server::Server;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let broker = Broker::new();
+/// let mut server = Server::new(1024..=65535, None, None);
+/// server.set_broker(broker.clone());
+/// tokio::spawn(server.listen());
+///
+/// // Nothing listens on this port yet.
+/// assert!(broker.visit(8080).is_err());
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Broker {
+    inner: Arc<BrokerInner>,
+}
+
+struct BrokerInner {
+    /// Where clients send their end of new control connections.
+    control: mpsc::UnboundedSender<DuplexStream>,
+
+    /// Control connections for the server, until it starts serving them.
+    incoming: Mutex<Option<mpsc::UnboundedReceiver<DuplexStream>>>,
+
+    /// Open tunnels, by port, and where to send their visitors.
+    tunnels: DashMap<u16, mpsc::UnboundedSender<DuplexStream>>,
+}
+
+impl Broker {
+    /// Create a broker, which has no server until one is given it.
+    pub fn new() -> Self {
+        let (control, incoming) = mpsc::unbounded_channel();
+        let inner = BrokerInner {
+            control,
+            incoming: Mutex::new(Some(incoming)),
+            tunnels: DashMap::new(),
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Connect to a tunnel as a visitor.
+    pub fn visit(&self, port: u16) -> io::Result<DuplexStream> {
+        let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, "no tunnel on port");
+        let tunnel = self.inner.tunnels.get(&port).ok_or_else(refused)?;
+        let (visitor, server) = duplex(BUFFER_SIZE);
+        tunnel.send(server).map_err(|_| refused())?;
+        Ok(visitor)
+    }
+
+    /// Open a new connection to the server's control port.
+    pub(crate) fn dial(&self) -> io::Result<DuplexStream> {
+        let (client, server) = duplex(BUFFER_SIZE);
+        self.inner.control.send(server).map_err(|_| {
+            io::Error::new(io::ErrorKind::ConnectionRefused, "broker has no server")
+        })?;
+        Ok(client)
+    }
+
+    /// Take the control connections, for the one server that serves them.
+    pub(crate) fn serve(&self) -> Result<mpsc::UnboundedReceiver<DuplexStream>> {
+        match self.inner.incoming.lock().unwrap().take() {
+            Some(incoming) => Ok(incoming),
+            None => bail!("broker is already served by another server"),
+        }
+    }
+
+    /// Listen for visitors on a port, unless a tunnel already has it.
+    pub(crate) fn bind(&self, port: u16) -> Option<MemoryListener> {
+        let (visitors, incoming) = mpsc::unbounded_channel();
+        match self.inner.tunnels.entry(port) {
+            Entry::Occupied(_) => return None,
+            Entry::Vacant(entry) => entry.insert(visitors.clone()),
+        };
+        Some(MemoryListener {
+            broker: self.clone(),
+            port,
+            visitors,
+            incoming,
+        })
+    }
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Broker")
+            .field("tunnels", &self.inner.tunnels.len())
+            .finish()
+    }
+}
+
+/// Tunnel listening on a broker, which stops listening when dropped.
+pub(crate) struct MemoryListener {
+    broker: Broker,
+    port: u16,
+    visitors: mpsc::UnboundedSender<DuplexStream>,
+    incoming: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+impl MemoryListener {
+    /// Port that the tunnel listens on.
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for a new visitor. This is cancel safe.
+    pub(crate) async fn accept(&mut self) -> io::Result<DuplexStream> {
+        // The sender is kept alive by this listener, so this never ends.
+        self.incoming
+            .recv()
+            .await
+            .ok_or_else(|| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.broker
+            .inner
+            .tunnels
+            .remove_if(&self.port, |_, visitors| {
+                visitors.same_channel(&self.visitors)
+            });
+    }
+}
@@ -0,0 +1,692 @@
+//! Capturing the raw traffic of a tunnel's connections to a file.
+//!
+//! Some problems only show in the bytes themselves, such as an app that
+//! sends a malformed frame or a service that closes a connection early. The
+//! client can write what passes to and from the local service on each
+//! connection to a file, in one of two formats:
+//!
+//! - `pcapng`, which Wireshark and tcpdump open. Each connection shows as a
+//!   TCP stream between the client and the local service, with a made-up
+//!   handshake and close around the bytes that were captured.
+//! - `flow`, a line of JSON for each event on a connection:
+//!
+//! ```text
+//! {"event":"open","connection":"...","time_us":...,"visitor":"203.0.113.7:51234","local":"127.0.0.1:50312","service":"127.0.0.1:8000"}
+//! {"event":"data","connection":"...","time_us":...,"direction":"inbound","offset":0,"length":78,"data":"R0VUIC8g..."}
+//! {"event":"close","connection":"...","time_us":...,"inbound_bytes":78,"outbound_bytes":1024}
+//! ```
+//!
+//! Inbound bytes go from visitors to the local service, and outbound bytes
+//! come back. The capture can keep only the heads of HTTP/1 messages, or only
+//! the first bytes of each direction of a connection. Bytes that are left out
+//! still count towards the offsets of what follows, so gaps are visible, and
+//! show as missing segments in Wireshark.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::inspect::{base64_bytes, Heads};
+
+/// Most payload in one captured packet, which keeps it within the largest
+/// IP packet.
+const MAX_SEGMENT: usize = 65000;
+
+/// Link type of packets that start with their IP header.
+const LINKTYPE_RAW: u16 = 101;
+
+/// Flags of TCP segments.
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// Format of a capture file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaptureFormat {
+    /// Packets in the pcap-ng format.
+    #[default]
+    Pcapng,
+
+    /// Lines of JSON.
+    Flow,
+}
+
+impl FromStr for CaptureFormat {
+    type Err = String;
+
+    /// Parse `pcapng` or `flow`.
+    ///
+    /// ```
+    /// use bore_cli::capture::CaptureFormat;
+    ///
+    /// assert_eq!("pcapng".parse(), Ok(CaptureFormat::Pcapng));
+    /// assert_eq!("flow".parse(), Ok(CaptureFormat::Flow));
+    /// assert!("har".parse::<CaptureFormat>().is_err());
+    /// ```
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "pcapng" => Ok(CaptureFormat::Pcapng),
+            "flow" => Ok(CaptureFormat::Flow),
+            _ => Err("expected `pcapng` or `flow`".into()),
+        }
+    }
+}
+
+impl CaptureFormat {
+    /// Format of a file from its extension: pcap-ng for `.pcapng` and
+    /// `.pcap`, and lines of JSON for anything else.
+    ///
+    /// ```
+    /// use std::path::Path;
+    /// use bore_cli::capture::CaptureFormat;
+    ///
+    /// assert_eq!(CaptureFormat::for_path(Path::new("app.pcapng")), CaptureFormat::Pcapng);
+    /// assert_eq!(CaptureFormat::for_path(Path::new("app.jsonl")), CaptureFormat::Flow);
+    /// ```
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("pcapng" | "pcap") => CaptureFormat::Pcapng,
+            _ => CaptureFormat::Flow,
+        }
+    }
+}
+
+/// What to capture, and how.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureOptions {
+    /// Format of the file.
+    pub format: CaptureFormat,
+
+    /// Keep only the heads of HTTP/1 messages, leaving out their bodies.
+    /// Nothing is kept of connections that do not carry HTTP/1.
+    pub headers_only: bool,
+
+    /// Most bytes to keep of each direction of a connection.
+    pub max_bytes: Option<u64>,
+}
+
+/// Event on a connection, as a line of a flow capture.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum FlowRecord {
+    /// The connection to the local service was opened.
+    Open {
+        /// ID of the connection, shared by all of its records.
+        connection: Uuid,
+        /// Microseconds since the Unix epoch.
+        time_us: u64,
+        /// Address of the visitor, if the server forwarded it.
+        visitor: Option<SocketAddr>,
+        /// Address of the client's end of the connection.
+        local: SocketAddr,
+        /// Address of the local service.
+        service: SocketAddr,
+    },
+
+    /// Bytes passed through the connection.
+    Data {
+        /// ID of the connection.
+        connection: Uuid,
+        /// Microseconds since the Unix epoch.
+        time_us: u64,
+        /// Which way the bytes went.
+        direction: Direction,
+        /// Position of the bytes in their direction of the connection.
+        offset: u64,
+        /// Number of bytes that passed.
+        length: u64,
+        /// Bytes that were kept of them, as base64.
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+
+    /// The connection was closed.
+    Close {
+        /// ID of the connection.
+        connection: Uuid,
+        /// Microseconds since the Unix epoch.
+        time_us: u64,
+        /// Bytes that went to the local service in total.
+        inbound_bytes: u64,
+        /// Bytes that came from the local service in total.
+        outbound_bytes: u64,
+    },
+}
+
+/// Direction of bytes on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// From the visitor to the local service.
+    Inbound,
+
+    /// From the local service to the visitor.
+    Outbound,
+}
+
+/// Most writes that can wait for the file before captured traffic is
+/// dropped.
+const QUEUE_LENGTH: usize = 4096;
+
+/// File that the traffic of connections is captured to, shared by all of
+/// them.
+///
+/// ```
+/// use std::sync::Arc;
+/// use bore_cli::capture::{Capture, CaptureFormat, CaptureOptions, Captured, Direction, FlowRecord};
+/// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let path = std::env::temp_dir().join(format!("bore-capture-{}.jsonl", uuid::Uuid::new_v4()));
+/// let options = CaptureOptions {
+///     format: CaptureFormat::Flow,
+///     max_bytes: Some(4),
+///     ..Default::default()
+/// };
+/// let capture = Arc::new(Capture::create(&path, options).unwrap());
+///
+/// let (near, mut service) = tokio::io::duplex(64);
+/// let flow = capture.open(None, "127.0.0.1:50312".parse().unwrap(), "127.0.0.1:8000".parse().unwrap());
+/// let mut conn = Captured::new(near, Some(flow));
+/// conn.write_all(b"hello").await.unwrap();
+/// service.write_all(b"hi").await.unwrap();
+/// let mut reply = [0; 2];
+/// conn.read_exact(&mut reply).await.unwrap();
+/// drop(conn);
+/// capture.flush().await;
+///
+/// let lines = std::fs::read_to_string(&path).unwrap();
+/// let records: Vec<FlowRecord> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+/// assert_eq!(records.len(), 4);
+/// let FlowRecord::Data { direction, length, data, .. } = &records[1] else { panic!() };
+/// assert_eq!((*direction, *length, data.as_slice()), (Direction::Inbound, 5, &b"hell"[..]));
+/// let FlowRecord::Close { inbound_bytes, outbound_bytes, .. } = records[3] else { panic!() };
+/// assert_eq!((inbound_bytes, outbound_bytes), (5, 2));
+/// # std::fs::remove_file(path).unwrap();
+/// # }
+/// ```
+pub struct Capture {
+    /// Queue of the thread that writes to the file.
+    queue: mpsc::Sender<Job>,
+    options: CaptureOptions,
+
+    /// Whether captured traffic was dropped because the queue was full,
+    /// which is only logged once.
+    dropped: AtomicBool,
+}
+
+/// Work for the thread that writes to a capture file.
+enum Job {
+    Write(Vec<u8>),
+    Flush(oneshot::Sender<()>),
+}
+
+impl Capture {
+    /// Create a capture file, replacing any file at the path.
+    pub fn create(path: &Path, options: CaptureOptions) -> Result<Self> {
+        let mut file = File::create(path)
+            .with_context(|| format!("could not create capture file {}", path.display()))?;
+        if options.format == CaptureFormat::Pcapng {
+            let mut header = section_header();
+            header.extend(interface_description());
+            file.write_all(&header)?;
+        }
+        let (queue, jobs) = mpsc::channel(QUEUE_LENGTH);
+        std::thread::Builder::new()
+            .name("bore-capture".into())
+            .spawn(move || write_jobs(BufWriter::new(file), jobs))?;
+        Ok(Self {
+            queue,
+            options,
+            dropped: AtomicBool::new(false),
+        })
+    }
+
+    /// Wait until everything captured so far is written to the file.
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.queue.send(Job::Flush(done)).await.is_ok() {
+            flushed.await.ok();
+        }
+    }
+
+    /// Start capturing a connection from the client at `local` to the local
+    /// service at `service`, on behalf of a visitor.
+    pub fn open(
+        self: &Arc<Self>,
+        visitor: Option<SocketAddr>,
+        local: SocketAddr,
+        service: SocketAddr,
+    ) -> Flow {
+        let (local, service) = same_family(local, service);
+        let mut flow = Flow {
+            capture: Arc::clone(self),
+            id: Uuid::new_v4(),
+            local,
+            service,
+            inbound: Side::default(),
+            outbound: Side::default(),
+            heads: self.options.headers_only.then(Heads::new),
+            closed: false,
+        };
+        match self.options.format {
+            CaptureFormat::Pcapng => {
+                let mut packets = flow.packet(Direction::Inbound, SYN, 0, &[]);
+                flow.inbound.syn = 1;
+                packets.extend(flow.packet(Direction::Outbound, SYN | ACK, 0, &[]));
+                flow.outbound.syn = 1;
+                packets.extend(flow.packet(Direction::Inbound, ACK, 0, &[]));
+                self.write(&packets);
+            }
+            CaptureFormat::Flow => self.record(&FlowRecord::Open {
+                connection: flow.id,
+                time_us: now(),
+                visitor,
+                local,
+                service,
+            }),
+        }
+        flow
+    }
+
+    fn record(&self, record: &FlowRecord) {
+        let mut line = serde_json::to_vec(record).expect("records serialize");
+        line.push(b'\n');
+        self.write(&line);
+    }
+
+    /// Queue bytes to be written, without waiting for the file, or drop
+    /// them if the writer has fallen too far behind.
+    fn write(&self, bytes: &[u8]) {
+        let full = matches!(
+            self.queue.try_send(Job::Write(bytes.to_vec())),
+            Err(mpsc::error::TrySendError::Full(_))
+        );
+        if full && !self.dropped.swap(true, Ordering::Relaxed) {
+            warn!("capture file cannot keep up, dropping captured traffic");
+        }
+    }
+}
+
+/// Write the bytes from a queue to a capture file until the queue closes,
+/// flushing whenever it runs dry.
+fn write_jobs(mut file: BufWriter<File>, mut jobs: mpsc::Receiver<Job>) {
+    let mut failed = false;
+    while let Some(job) = jobs.blocking_recv() {
+        let result = match job {
+            Job::Write(_) if failed => Ok(()),
+            Job::Write(bytes) => file.write_all(&bytes),
+            Job::Flush(done) => {
+                let result = file.flush();
+                done.send(()).ok();
+                result
+            }
+        };
+        let result = result.and_then(|()| match jobs.is_empty() {
+            true => file.flush(),
+            false => Ok(()),
+        });
+        if let Err(err) = result {
+            if !std::mem::replace(&mut failed, true) {
+                warn!(%err, "could not write to capture file, capturing stopped");
+            }
+        }
+    }
+    file.flush().ok();
+}
+
+/// Capture of one connection.
+pub struct Flow {
+    capture: Arc<Capture>,
+    id: Uuid,
+    local: SocketAddr,
+    service: SocketAddr,
+    inbound: Side,
+    outbound: Side,
+
+    /// Splitter of HTTP messages, if only their heads are kept.
+    heads: Option<Heads>,
+    closed: bool,
+}
+
+/// One direction of a captured connection.
+#[derive(Default)]
+struct Side {
+    /// Bytes that passed.
+    offset: u64,
+
+    /// Bytes that were kept.
+    kept: u64,
+
+    /// Sequence numbers taken up by SYN and FIN flags.
+    syn: u32,
+    fin: u32,
+}
+
+impl Side {
+    /// Sequence number of the next byte, counted from an initial sequence
+    /// number of zero.
+    fn seq(&self) -> u32 {
+        (self.offset as u32).wrapping_add(self.syn + self.fin)
+    }
+}
+
+impl Flow {
+    /// Capture bytes that passed in one direction.
+    pub fn data(&mut self, direction: Direction, bytes: &[u8]) {
+        let kept = match (&mut self.heads, direction) {
+            (Some(heads), Direction::Inbound) => heads.requests(bytes),
+            (Some(heads), Direction::Outbound) => heads.responses(bytes),
+            (None, _) => bytes.to_vec(),
+        };
+        let CaptureOptions {
+            format, max_bytes, ..
+        } = self.capture.options;
+        let side = self.side(direction);
+        let room = match max_bytes {
+            Some(max) => max.saturating_sub(side.kept).min(kept.len() as u64) as usize,
+            None => kept.len(),
+        };
+        let kept = &kept[..room];
+        let offset = side.offset;
+        if !kept.is_empty() {
+            match format {
+                CaptureFormat::Pcapng => {
+                    let packets: Vec<u8> = (kept.chunks(MAX_SEGMENT).enumerate())
+                        .flat_map(|(i, segment)| {
+                            let at = (i * MAX_SEGMENT) as u32;
+                            self.packet(direction, PSH | ACK, at, segment)
+                        })
+                        .collect();
+                    self.capture.write(&packets);
+                }
+                CaptureFormat::Flow => self.capture.record(&FlowRecord::Data {
+                    connection: self.id,
+                    time_us: now(),
+                    direction,
+                    offset,
+                    length: bytes.len() as u64,
+                    data: kept.to_vec(),
+                }),
+            }
+        }
+        let side = self.side(direction);
+        side.offset += bytes.len() as u64;
+        side.kept += kept.len() as u64;
+    }
+
+    /// Capture the close of the connection, once.
+    pub fn close(&mut self) {
+        if std::mem::replace(&mut self.closed, true) {
+            return;
+        }
+        match self.capture.options.format {
+            CaptureFormat::Pcapng => {
+                let mut packets = self.packet(Direction::Inbound, FIN | ACK, 0, &[]);
+                self.inbound.fin = 1;
+                packets.extend(self.packet(Direction::Outbound, FIN | ACK, 0, &[]));
+                self.outbound.fin = 1;
+                packets.extend(self.packet(Direction::Inbound, ACK, 0, &[]));
+                self.capture.write(&packets);
+            }
+            CaptureFormat::Flow => self.capture.record(&FlowRecord::Close {
+                connection: self.id,
+                time_us: now(),
+                inbound_bytes: self.inbound.offset,
+                outbound_bytes: self.outbound.offset,
+            }),
+        }
+    }
+
+    fn side(&mut self, direction: Direction) -> &mut Side {
+        match direction {
+            Direction::Inbound => &mut self.inbound,
+            Direction::Outbound => &mut self.outbound,
+        }
+    }
+
+    /// Block with a packet in one direction, carrying payload from `at`
+    /// bytes past the direction's offset, and acknowledging everything from
+    /// the other side if it has the ACK flag.
+    fn packet(&self, direction: Direction, flags: u8, at: u32, payload: &[u8]) -> Vec<u8> {
+        let (from, to, side, other) = match direction {
+            Direction::Inbound => (self.local, self.service, &self.inbound, &self.outbound),
+            Direction::Outbound => (self.service, self.local, &self.outbound, &self.inbound),
+        };
+        let seq = side.seq().wrapping_add(at);
+        let ack = if flags & ACK != 0 { other.seq() } else { 0 };
+        let segment = tcp_segment(from, to, seq, ack, flags, payload);
+        enhanced_packet(&ip_packet(from.ip(), to.ip(), &segment))
+    }
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Connection to a local service whose bytes are captured, if it has a
+/// flow. Bytes pass through unchanged.
+pub struct Captured<S> {
+    inner: S,
+    flow: Option<Flow>,
+}
+
+impl<S> Captured<S> {
+    /// Capture the bytes of a connection to a flow, if any.
+    pub fn new(inner: S, flow: Option<Flow>) -> Self {
+        Self { inner, flow }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Captured<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(flow) = &mut this.flow {
+            let read = &buf.filled()[before..];
+            if !read.is_empty() {
+                flow.data(Direction::Outbound, read);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Captured<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if let Some(flow) = &mut this.flow {
+            flow.data(Direction::Inbound, &buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Microseconds since the Unix epoch.
+fn now() -> u64 {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH);
+    since.unwrap_or_default().as_micros() as u64
+}
+
+/// Addresses of both ends in one family, mapping IPv4 into IPv6 if they
+/// differ.
+fn same_family(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    if a.is_ipv4() == b.is_ipv4() {
+        (a, b)
+    } else {
+        (v6(a), v6(b))
+    }
+}
+
+/// Block that starts a pcap-ng section.
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend(0x1A2B3C4D_u32.to_le_bytes()); // Byte-order magic.
+    body.extend(1_u16.to_le_bytes()); // Major version.
+    body.extend(0_u16.to_le_bytes()); // Minor version.
+    body.extend((-1_i64).to_le_bytes()); // Unknown section length.
+    block(0x0A0D0D0A, &body)
+}
+
+/// Block that describes the one interface that packets are captured on.
+fn interface_description() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend(LINKTYPE_RAW.to_le_bytes());
+    body.extend(0_u16.to_le_bytes()); // Reserved.
+    body.extend(0_u32.to_le_bytes()); // No limit on packet length.
+    block(1, &body)
+}
+
+/// Block with a packet, timestamped now in microseconds.
+fn enhanced_packet(packet: &[u8]) -> Vec<u8> {
+    let time = now();
+    let mut body = Vec::new();
+    body.extend(0_u32.to_le_bytes()); // Interface.
+    body.extend(((time >> 32) as u32).to_le_bytes());
+    body.extend((time as u32).to_le_bytes());
+    body.extend((packet.len() as u32).to_le_bytes()); // Captured length.
+    body.extend((packet.len() as u32).to_le_bytes()); // Original length.
+    body.extend_from_slice(packet);
+    block(6, &body)
+}
+
+/// Block of a type, with its body padded to 32 bits.
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let padding = (4 - body.len() % 4) % 4;
+    let length = (12 + body.len() + padding) as u32;
+    let mut block = Vec::with_capacity(length as usize);
+    block.extend(kind.to_le_bytes());
+    block.extend(length.to_le_bytes());
+    block.extend_from_slice(body);
+    block.resize(block.len() + padding, 0);
+    block.extend(length.to_le_bytes());
+    block
+}
+
+/// TCP header and payload, with its checksum.
+fn tcp_segment(
+    from: SocketAddr,
+    to: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(20 + payload.len());
+    segment.extend(from.port().to_be_bytes());
+    segment.extend(to.port().to_be_bytes());
+    segment.extend(seq.to_be_bytes());
+    segment.extend(ack.to_be_bytes());
+    segment.push(5 << 4); // Header length in 32-bit words.
+    segment.push(flags);
+    segment.extend(u16::MAX.to_be_bytes()); // Window.
+    segment.extend([0; 2]); // Checksum.
+    segment.extend([0; 2]); // Urgent pointer.
+    segment.extend_from_slice(payload);
+
+    let mut pseudo = Vec::new();
+    match (from.ip(), to.ip()) {
+        (IpAddr::V4(from), IpAddr::V4(to)) => {
+            pseudo.extend(from.octets());
+            pseudo.extend(to.octets());
+            pseudo.extend([0, 6]);
+            pseudo.extend((segment.len() as u16).to_be_bytes());
+        }
+        (from, to) => {
+            pseudo.extend(ipv6(from).octets());
+            pseudo.extend(ipv6(to).octets());
+            pseudo.extend((segment.len() as u32).to_be_bytes());
+            pseudo.extend([0, 0, 0, 6]);
+        }
+    }
+    pseudo.extend_from_slice(&segment);
+    let sum = checksum(&pseudo);
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    segment
+}
+
+/// IP header around a TCP segment.
+fn ip_packet(from: IpAddr, to: IpAddr, segment: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(40 + segment.len());
+    match (from, to) {
+        (IpAddr::V4(from), IpAddr::V4(to)) => {
+            packet.extend([0x45, 0]); // Version, header length and TOS.
+            packet.extend(((20 + segment.len()) as u16).to_be_bytes());
+            packet.extend([0, 0, 0x40, 0]); // Identification, and don't fragment.
+            packet.extend([64, 6]); // TTL and protocol.
+            packet.extend([0; 2]); // Checksum.
+            packet.extend(from.octets());
+            packet.extend(to.octets());
+            let sum = checksum(&packet);
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        }
+        (from, to) => {
+            packet.extend([0x60, 0, 0, 0]); // Version, class and flow label.
+            packet.extend((segment.len() as u16).to_be_bytes());
+            packet.extend([6, 64]); // Next header and hop limit.
+            packet.extend(ipv6(from).octets());
+            packet.extend(ipv6(to).octets());
+        }
+    }
+    packet.extend_from_slice(segment);
+    packet
+}
+
+fn ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Internet checksum: the ones' complement of the ones' complement sum of
+/// 16-bit words.
+fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum: u32 = (bytes.chunks(2))
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -1,14 +1,71 @@
 //! Client implementation for the `bore` service.
 
-use std::sync::Arc;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use anyhow::{bail, Context, Result};
-use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+use anyhow::{bail, ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures_util::future::try_join_all;
+use serde::{Deserialize, Deserializer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpSocket, TcpStream, UdpSocket};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsConnector;
 use tracing::{error, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
-use crate::auth::{Authenticator, ApiKeyAuthenticator};
-use crate::shared::{ClientMessage, Delimited, ServerMessage, CONTROL_PORT, NETWORK_TIMEOUT};
+use crate::acl::Cidr;
+use crate::announce::{Announce, Announcement};
+use crate::auth::{ApiKeyAuthenticator, Authenticator};
+use crate::broker::Broker;
+use crate::capture::{Capture, Captured};
+use crate::encryption::Encrypted;
+use crate::heartbeat;
+use crate::http::{self, RequestHead};
+use crate::identity::{IdentityExchange, KnownServers};
+use crate::inspect::{Inspected, Inspector};
+use crate::integrity::{ChecksumLedger, Checksummed};
+use crate::multiplex::MuxClient;
+use crate::proxy_protocol::ProxyProtocol;
+use crate::ratelimit::{Bandwidth, Limited};
+use crate::rewrite::{Rewrite, Rewritten};
+use crate::shared::{
+    host_port, unbracket, AuthError, ClientHello, ClientMessage, ConnectionInfo, Delimited,
+    ErrorCode, LocalTimeout, Observation, ObserveRequest, ServerBusy, ServerError, ServerHello,
+    ServerMessage, ServerUnreachable, SubKeyRequest, TunnelClosed, TunnelRejected, CONTROL_PORT,
+    NETWORK_TIMEOUT, PROTOCOL_VERSION,
+};
+use crate::stats::{Metered, TunnelStats};
+use crate::striping;
+use crate::tls::{self, ControlStream};
+use crate::udp;
+use crate::websocket;
+
+/// Number of times to retry connecting when the server reports it is busy.
+const MAX_BUSY_RETRIES: u32 = 5;
+
+/// Capacity of the pipe that requests go through when they are rewritten.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Delay before the first attempt to reconnect, doubled after each failure.
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Most alternative ports to try from a fallback range.
+const MAX_FALLBACK_ATTEMPTS: usize = 20;
+
+/// Longest delay between attempts to reconnect.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How long the control connection may go without a message from the server
+/// before it is considered dead, beyond the server's heartbeat interval.
+/// Servers that do not report their interval send plain heartbeats several
+/// times a second, while adaptive heartbeats may be up to
+/// [`heartbeat::MAX_INTERVAL`] apart.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Authentication mode for the client
 enum ClientAuthMode {
@@ -17,10 +74,17 @@ enum ClientAuthMode {
     ApiKey(String), // Stores the API key string
 }
 
+/// How the client verifies the identity key of the server.
+enum IdentityCheck {
+    None,
+    Pinned(String),
+    KnownServers(KnownServers),
+}
+
 /// State structure for the client.
 pub struct Client {
     /// Control connection to the server.
-    conn: Option<Delimited<TcpStream>>,
+    conn: Option<Delimited<ControlStream>>,
 
     /// Destination address of the server.
     to: String,
@@ -36,6 +100,370 @@ pub struct Client {
 
     /// Authentication mode.
     auth: ClientAuthMode,
+
+    /// Verification of the server's identity key.
+    identity: IdentityCheck,
+
+    /// TLS configuration for the control port, if enabled.
+    tls: Option<TlsConnector>,
+
+    /// Whether connections to the control port are wrapped in WebSocket.
+    websocket: bool,
+
+    /// Connection that data connections are multiplexed over, if negotiated.
+    /// It is replaced when the client reconnects.
+    mux: Mutex<Option<MuxClient>>,
+
+    /// Token that data connections present instead of credentials, if the
+    /// server issued one. It is replaced when the client reconnects.
+    session_token: Mutex<Option<String>>,
+
+    /// Whether the server challenges data connections although the client
+    /// has no credentials.
+    challenged: bool,
+
+    /// Timeout for connecting to the local service.
+    local_connect_timeout: Duration,
+
+    /// How long to wait for a message from the server before the control
+    /// connection is considered dead.
+    heartbeat_timeout: Duration,
+
+    /// Checksums of proxied streams awaiting comparison, if negotiated.
+    checksums: Option<Arc<ChecksumLedger>>,
+
+    /// Number of data connections that each proxied stream is striped across.
+    stripes: u8,
+
+    /// Whether data connections are encrypted.
+    encryption: bool,
+
+    /// Whether the tunnel forwards UDP datagrams instead of TCP connections.
+    udp: bool,
+
+    /// Hostname that an HTTP tunnel is reached at, if it is one.
+    hostname: Option<String>,
+
+    /// URLs that an HTTP tunnel is reached at.
+    urls: Vec<String>,
+
+    /// Address, as `host:port`, that a TLS tunnel is reached at.
+    tls_addr: Option<String>,
+
+    /// Messages from the server that arrived before the client listens.
+    early: Vec<ServerMessage>,
+
+    /// Whether the server answers visits announced as probes.
+    probes: bool,
+
+    /// Running totals of the traffic through the tunnel.
+    stats: Arc<TunnelStats>,
+
+    /// Limit on the traffic from the local service to visitors, if any.
+    upload: Option<Arc<Bandwidth>>,
+
+    /// Limit on the traffic from visitors to the local service, if any.
+    download: Option<Arc<Bandwidth>>,
+
+    /// Options that the tunnel was opened with, to reopen it after a disconnect.
+    options: ClientOptions,
+}
+
+/// Options for connecting a client to the server.
+#[derive(Clone, Default)]
+pub struct ClientOptions {
+    /// Port on the remote server to select, or 0 for any available port.
+    pub port: u16,
+
+    /// Optional secret for authentication.
+    pub secret: Option<String>,
+
+    /// Optional API key for authentication, used instead of the secret.
+    pub api_key: Option<String>,
+
+    /// Ask the server to compress control frames.
+    pub compression: bool,
+
+    /// Compare checksums of each proxied stream with the server's at close.
+    pub checksums: bool,
+
+    /// Number of data connections to stripe each proxied stream across, for
+    /// throughput on long, high-bandwidth paths. Values below 2 disable this.
+    pub stripes: u8,
+
+    /// Refuse to connect unless the server challenges the client to authenticate.
+    pub require_auth: bool,
+
+    /// Hex-encoded public key that the server must prove it holds.
+    pub server_key: Option<String>,
+
+    /// File of known server identities, trusted on first use.
+    pub known_servers: Option<PathBuf>,
+
+    /// Connect to the control port over TLS.
+    pub tls: bool,
+
+    /// PEM file of CA certificates to trust for TLS, instead of public roots.
+    pub tls_ca: Option<PathBuf>,
+
+    /// Encrypt data connections to the server, even without TLS.
+    pub encryption: bool,
+
+    /// Forward UDP datagrams instead of TCP connections.
+    pub udp: bool,
+
+    /// Name of the tunnel, which labels its logs on the server.
+    pub name: Option<String>,
+
+    /// Wrap connections to the control port in WebSocket, for networks that
+    /// only allow HTTP traffic out.
+    pub websocket: bool,
+
+    /// Multiplex data connections over one connection opened at startup,
+    /// instead of opening a new connection for each.
+    pub multiplex: bool,
+
+    /// What to do when the requested remote port is not available.
+    pub port_fallback: PortFallback,
+
+    /// Session to open the tunnel over, shared with other tunnels, instead of
+    /// connecting and authenticating on its own. This implies multiplexing.
+    pub session: Option<Session>,
+
+    /// Reach a server in the same process through this broker, instead of
+    /// over the network. TLS and WebSocket are not used.
+    pub broker: Option<Broker>,
+
+    /// Answer pings from the server, which lets it detect a dead tunnel
+    /// quickly on flaky networks.
+    pub adaptive_heartbeat: bool,
+
+    /// Announce each proxied connection to the local service, with the
+    /// visitor's address.
+    pub announce: Option<Announce>,
+
+    /// Start each connection to the local service with a PROXY protocol
+    /// header of this version, carrying the visitor's address.
+    pub proxy_protocol: Option<ProxyProtocol>,
+
+    /// Ask the server for the address of each visitor, to log it and keep
+    /// the latest in the tunnel's statistics.
+    pub visitor_info: bool,
+
+    /// Only let visitors from these blocks of addresses reach the tunnel,
+    /// unless empty.
+    pub allow_ips: Vec<Cidr>,
+
+    /// Never let visitors from these blocks of addresses reach the tunnel.
+    pub deny_ips: Vec<Cidr>,
+
+    /// Class of addresses on the server that the tunnel should listen on,
+    /// instead of its default ones.
+    pub listener_class: Option<String>,
+
+    /// Serve HTTP through the server's shared HTTP port, where requests are
+    /// routed to the tunnel by hostname.
+    pub http: bool,
+
+    /// Subdomain to ask for as an HTTP or TLS tunnel, instead of the
+    /// tunnel's name or a random one.
+    pub subdomain: Option<String>,
+
+    /// Have TLS connections routed by server name through the server's
+    /// shared TLS port, still encrypted, for the local service to terminate.
+    pub tls_passthrough: bool,
+
+    /// What to do with the `Host` header of requests through an HTTP tunnel
+    /// before they reach the local service.
+    pub host_header: HostHeader,
+
+    /// Credentials, as `user:password`, that visitors of an HTTP tunnel must
+    /// give with basic authentication before the server lets them through.
+    pub basic_auth: Option<String>,
+
+    /// Emails, and domains starting with `@`, that visitors of an HTTP
+    /// tunnel must log in as with the server's OIDC provider, unless empty.
+    pub oidc_allow: Vec<String>,
+
+    /// Ask for a tunnel whose public endpoint can be checked with
+    /// [`Client::check_reachability`].
+    pub check_reachability: bool,
+
+    /// How long the control connection may go without hearing from the
+    /// server before it is considered dead. The server is asked to send
+    /// heartbeats often enough. By default, this follows the server's
+    /// heartbeat interval.
+    pub heartbeat_timeout: Option<Duration>,
+
+    /// Reopen the tunnel on the same remote port when the control connection
+    /// drops, with exponential backoff between attempts.
+    pub reconnect: bool,
+
+    /// Number of failed attempts in a row to reconnect before giving up, or
+    /// `None` to keep trying.
+    pub max_retries: Option<u32>,
+
+    /// Most bytes per second to send from the local service to visitors,
+    /// across all connections of the tunnel.
+    pub max_upload_rate: Option<u64>,
+
+    /// Most bytes per second to receive from visitors for the local service,
+    /// across all connections of the tunnel.
+    pub max_download_rate: Option<u64>,
+
+    /// Open data connections with a session token from the server, instead
+    /// of authenticating each of them, which saves a round trip and a call to
+    /// the API key backend for every visitor.
+    pub session_tokens: bool,
+
+    /// Record the HTTP requests and responses through the tunnel with this
+    /// inspector, to look at them and replay requests later.
+    pub inspector: Option<Arc<Inspector>>,
+
+    /// Capture the raw bytes of connections to the local service to this
+    /// file.
+    pub capture: Option<Arc<Capture>>,
+
+    /// Largest TCP segment to send or receive on data connections, for
+    /// networks that drop full-sized packets and stall large transfers.
+    /// Multiplexed data connections are not clamped.
+    pub max_segment_size: Option<u16>,
+}
+
+/// What a client does when the remote port that it asks for is taken.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PortFallback {
+    /// Give up, which is the default.
+    #[default]
+    Fail,
+
+    /// Let the server pick the free port nearest to the requested one.
+    Nearest,
+
+    /// Accept any port that the server assigns.
+    Any,
+
+    /// Try other ports in this range.
+    Range(RangeInclusive<u16>),
+}
+
+impl FromStr for PortFallback {
+    type Err = String;
+
+    /// Parse `fail`, `nearest`, `any`, or a range such as `range:9000-9100`.
+    ///
+    /// ```
+    /// use bore_cli::client::PortFallback;
+    ///
+    /// assert_eq!("nearest".parse(), Ok(PortFallback::Nearest));
+    /// assert_eq!("any".parse(), Ok(PortFallback::Any));
+    /// assert_eq!("range:9000-9100".parse(), Ok(PortFallback::Range(9000..=9100)));
+    /// assert!("range:9100-9000".parse::<PortFallback>().is_err());
+    /// assert!("retry".parse::<PortFallback>().is_err());
+    /// ```
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "fail" => return Ok(PortFallback::Fail),
+            "nearest" => return Ok(PortFallback::Nearest),
+            "any" => return Ok(PortFallback::Any),
+            _ => {}
+        }
+        let range = input
+            .strip_prefix("range:")
+            .and_then(|range| range.split_once('-'))
+            .and_then(|(min, max)| Some(min.parse::<u16>().ok()?..=max.parse().ok()?))
+            .ok_or("expected `fail`, `nearest`, `any`, or `range:MIN-MAX`")?;
+        if range.is_empty() {
+            return Err("port range is empty".into());
+        }
+        Ok(PortFallback::Range(range))
+    }
+}
+
+/// What an HTTP tunnel does with the `Host` header of requests, for local
+/// services that only answer to certain hostnames.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HostHeader {
+    /// Pass on the hostname that the visitor asked for, which is the default.
+    #[default]
+    Preserve,
+
+    /// Replace it with the local host and port, as if the request were made
+    /// locally. The original goes in `X-Forwarded-Host`.
+    Rewrite,
+
+    /// Replace it with a value. The original goes in `X-Forwarded-Host`.
+    Custom(String),
+}
+
+impl FromStr for HostHeader {
+    type Err = String;
+
+    /// Parse `preserve`, `rewrite`, or a value such as `custom:app.local`.
+    ///
+    /// ```
+    /// use bore_cli::client::HostHeader;
+    ///
+    /// assert_eq!("preserve".parse(), Ok(HostHeader::Preserve));
+    /// assert_eq!("rewrite".parse(), Ok(HostHeader::Rewrite));
+    /// assert_eq!("custom:app.local".parse(), Ok(HostHeader::Custom("app.local".into())));
+    /// assert!("custom:".parse::<HostHeader>().is_err());
+    /// assert!("custom:a\r\nb".parse::<HostHeader>().is_err());
+    /// ```
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "preserve" => return Ok(HostHeader::Preserve),
+            "rewrite" => return Ok(HostHeader::Rewrite),
+            _ => {}
+        }
+        let value = (input.strip_prefix("custom:"))
+            .ok_or("expected `preserve`, `rewrite`, or `custom:VALUE`")?;
+        if value.is_empty() || value.bytes().any(|b| b.is_ascii_control()) {
+            return Err("invalid Host header value".into());
+        }
+        Ok(HostHeader::Custom(value.to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for HostHeader {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl ClientOptions {
+    /// Whether these options need the versioned protocol handshake.
+    fn needs_extensions(&self) -> bool {
+        self.compression
+            || self.checksums
+            || self.stripes > 1
+            || self.encryption
+            || self.udp
+            || self.name.is_some()
+            || self.multiplex
+            || self.adaptive_heartbeat
+            || self.announce.is_some()
+            || self.proxy_protocol.is_some()
+            || self.visitor_info
+            || !self.allow_ips.is_empty()
+            || !self.deny_ips.is_empty()
+            || self.listener_class.is_some()
+            || self.http
+            || self.tls_passthrough
+            || self.basic_auth.is_some()
+            || !self.oidc_allow.is_empty()
+            || self.heartbeat_timeout.is_some()
+            || self.check_reachability
+            || self.session_tokens
+            || self.nearest_port()
+    }
+
+    /// Whether the server should pick the nearest free port if the requested
+    /// one is taken.
+    fn nearest_port(&self) -> bool {
+        self.port != 0 && self.port_fallback == PortFallback::Nearest
+    }
 }
 
 impl Client {
@@ -48,42 +476,154 @@ impl Client {
         secret: Option<&str>,
         api_key: Option<String>,
     ) -> Result<Self> {
-        let mut stream = Delimited::new(connect_with_timeout(to, CONTROL_PORT).await?);
-
-        // Determine authentication mode
-        let auth = if let Some(key) = api_key.clone() {
-            ClientAuthMode::ApiKey(key)
-        } else if let Some(secret) = secret {
-            ClientAuthMode::Secret(Authenticator::new(secret))
-        } else {
-            ClientAuthMode::None
+        let options = ClientOptions {
+            port,
+            secret: secret.map(String::from),
+            api_key,
+            ..Default::default()
         };
+        Self::with_options(local_host, local_port, to, options).await
+    }
 
-        // Perform authentication handshake
-        match &auth {
-            ClientAuthMode::Secret(authenticator) => {
-                authenticator.client_handshake(&mut stream).await?;
-            }
-            ClientAuthMode::ApiKey(key) => {
-                ApiKeyAuthenticator::client_handshake(key, &mut stream).await?;
-            }
-            ClientAuthMode::None => {
-                // No authentication required
-            }
-        }
+    /// Create a new client with additional connection options.
+    pub async fn with_options(
+        local_host: &str,
+        local_port: u16,
+        to: &str,
+        options: ClientOptions,
+    ) -> Result<Self> {
+        let (local_host, to) = (unbracket(local_host), unbracket(to));
+        let (auth, identity) = credentials(&options)?;
+        let tls = tls_connector(&options)?;
 
-        stream.send(ClientMessage::Hello(port)).await?;
-        let remote_port = match stream.recv_timeout().await? {
-            Some(ServerMessage::Hello(remote_port)) => remote_port,
-            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
-            Some(ServerMessage::Challenge(_)) => {
-                bail!("server requires authentication, but no client secret or API key was provided");
+        let (stream, hello) =
+            match open_patiently(to, &auth, &identity, tls.as_ref(), &options).await {
+                Err(err) if options.port != 0 && port_unavailable(&err) => {
+                    open_fallback(to, &auth, &identity, tls.as_ref(), &options, err).await?
+                }
+                Ok((stream, hello)) if options.nearest_port() && hello.port != options.port => {
+                    warn!(
+                        requested = options.port,
+                        remote_port = hello.port,
+                        "requested port is unavailable, using the nearest free port"
+                    );
+                    (stream, hello)
+                }
+                result => result?,
+            };
+        let remote_port = hello.port;
+        ensure!(
+            hello.encryption || !options.encryption,
+            "server does not support encrypted data connections"
+        );
+        ensure!(
+            hello.udp || !options.udp,
+            "server does not support UDP tunnels"
+        );
+        ensure!(
+            hello.connection_info || options.announce.is_none(),
+            "server does not forward visitor addresses, which announcements need"
+        );
+        ensure!(
+            hello.access_list || (options.allow_ips.is_empty() && options.deny_ips.is_empty()),
+            "server does not support rules on visitor addresses"
+        );
+        ensure!(
+            hello.connection_info || options.proxy_protocol.is_none(),
+            "server does not forward visitor addresses, which the PROXY protocol needs"
+        );
+        ensure!(
+            !(options.udp && options.announce == Some(Announce::Inline)),
+            "UDP tunnels can only announce connections on a separate port"
+        );
+        ensure!(
+            !(options.udp && options.inspector.is_some()),
+            "UDP tunnels cannot be inspected"
+        );
+        ensure!(
+            !(options.udp && options.capture.is_some()),
+            "UDP tunnels cannot be captured"
+        );
+        ensure!(
+            !(options.udp && options.proxy_protocol.is_some()),
+            "UDP tunnels cannot send PROXY protocol headers"
+        );
+        ensure!(
+            !(options.proxy_protocol.is_some() && options.announce == Some(Announce::Inline)),
+            "PROXY protocol headers and inline announcements cannot be combined"
+        );
+        ensure!(
+            options.http || options.host_header == HostHeader::Preserve,
+            "only HTTP tunnels can change the Host header"
+        );
+        ensure!(
+            options.http || options.basic_auth.is_none(),
+            "only HTTP tunnels can require basic authentication"
+        );
+        ensure!(
+            options.http || options.oidc_allow.is_empty(),
+            "only HTTP tunnels can require visitors to log in"
+        );
+        if options.checksums && !hello.checksums {
+            warn!("server does not support stream checksums");
+        }
+        if options.stripes > 1 && hello.stripes < options.stripes {
+            warn!(
+                stripes = hello.stripes,
+                "server limited the number of stripes"
+            );
+        }
+        if options.multiplex && !hello.multiplex {
+            warn!("server does not support multiplexing, opening a connection for each");
+        }
+        let mux = match hello.multiplex {
+            true => {
+                let tls = tls.as_ref();
+                Some(
+                    open_mux(
+                        to,
+                        &auth,
+                        &identity,
+                        tls,
+                        options.websocket,
+                        options.broker.as_ref(),
+                    )
+                    .await?,
+                )
             }
-            Some(_) => bail!("unexpected initial non-hello message"),
-            None => bail!("unexpected EOF"),
+            false => None,
+        };
+        let heartbeat_timeout = match (options.heartbeat_timeout, hello.heartbeat_interval_ms) {
+            (Some(timeout), _) => timeout,
+            (None, Some(ms)) => Duration::from_millis(ms) + HEARTBEAT_TIMEOUT,
+            (None, None) if hello.adaptive_heartbeat => heartbeat::MAX_INTERVAL + HEARTBEAT_TIMEOUT,
+            (None, None) => HEARTBEAT_TIMEOUT,
         };
         info!(remote_port, "connected to server");
-        info!("listening at {to}:{remote_port}");
+        if options.http && hello.hostname.is_none() {
+            bail!("server does not route HTTP tunnels");
+        }
+        if options.tls_passthrough && hello.hostname.is_none() {
+            bail!("server does not route TLS tunnels");
+        }
+        // Visitors must not get through unchecked if the server ignored the
+        // credentials.
+        if options.basic_auth.is_some() && !hello.basic_auth {
+            bail!("server does not protect tunnels with basic authentication");
+        }
+        if !options.oidc_allow.is_empty() && !hello.oidc {
+            bail!("server does not log visitors in");
+        }
+        if let Some(addr) = &hello.tls_addr {
+            info!("serving TLS at {addr}");
+        }
+        let urls: Vec<String> = [hello.http_url, hello.https_url]
+            .into_iter()
+            .flatten()
+            .collect();
+        for url in &urls {
+            info!("serving HTTP at {url}");
+        }
 
         Ok(Client {
             conn: Some(stream),
@@ -92,6 +632,31 @@ impl Client {
             local_port,
             remote_port,
             auth,
+            identity,
+            tls,
+            websocket: options.websocket,
+            mux: Mutex::new(mux),
+            session_token: Mutex::new(hello.session_token),
+            challenged: hello.challenged,
+            local_connect_timeout: NETWORK_TIMEOUT,
+            heartbeat_timeout,
+            checksums: hello.checksums.then(Default::default),
+            stripes: hello.stripes.max(1),
+            encryption: hello.encryption,
+            udp: hello.udp,
+            hostname: hello.hostname,
+            urls,
+            tls_addr: hello.tls_addr,
+            early: Vec::new(),
+            probes: hello.probes,
+            stats: Default::default(),
+            upload: options
+                .max_upload_rate
+                .map(|rate| Arc::new(Bandwidth::new(rate))),
+            download: options
+                .max_download_rate
+                .map(|rate| Arc::new(Bandwidth::new(rate))),
+            options,
         })
     }
 
@@ -100,65 +665,1056 @@ impl Client {
         self.remote_port
     }
 
+    /// Returns the hostname that an HTTP tunnel is reached at on the server's
+    /// shared HTTP port.
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+
+    /// Returns the URLs that an HTTP tunnel is reached at, over HTTP and, if
+    /// the server terminates TLS for it, over HTTPS.
+    pub fn urls(&self) -> &[String] {
+        &self.urls
+    }
+
+    /// Running totals of the traffic through the tunnel, which keep updating
+    /// while it is open.
+    pub fn stats(&self) -> Arc<TunnelStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Set the timeout for connecting to the local service.
+    ///
+    /// This is separate from the network timeout used for the server, since
+    /// loopback connections usually fail fast while hosts reached over a VPN
+    /// may need longer.
+    pub fn set_local_connect_timeout(&mut self, timeout: Duration) {
+        self.local_connect_timeout = timeout;
+    }
+
+    /// Set how long the control connection may go without hearing from the
+    /// server before it is considered dead, and reopened if reconnecting is
+    /// enabled. This catches half-open connections that never report an error.
+    pub fn set_heartbeat_timeout(&mut self, timeout: Duration) {
+        self.heartbeat_timeout = timeout;
+    }
+
+    /// Check that the public endpoint can be reached through the network.
+    ///
+    /// This visits the tunnel the same way a visitor would, at its URL for
+    /// HTTP and TLS tunnels, which catches a firewall on the server that
+    /// blocks the public port. The visit is announced on the control
+    /// connection first, so that the server answers it itself instead of
+    /// forwarding it to the local service. This must be called before
+    /// [`Client::listen`], on a tunnel opened with
+    /// [`ClientOptions::check_reachability`].
+    pub async fn check_reachability(&mut self) -> Result<()> {
+        ensure!(self.probes, "server does not support reachability checks");
+        let conn = self.conn.as_mut().context("tunnel is already listening")?;
+        let token = Uuid::new_v4();
+        conn.send(ClientMessage::Probe(token)).await?;
+        // Visitors may arrive before the server confirms the probe, and are
+        // handled once the client listens.
+        loop {
+            match conn.recv_timeout().await? {
+                Some(ServerMessage::Probe(confirmed)) if confirmed == token => break,
+                Some(ServerMessage::Heartbeat) => (),
+                Some(ServerMessage::Ping(seq)) => conn.send(ClientMessage::Pong(seq)).await?,
+                Some(message) => self.early.push(message),
+                None => bail!("server closed the connection"),
+            }
+        }
+        if let Some(broker) = &self.options.broker {
+            return probe(broker.visit(self.remote_port)?, &[], token).await;
+        }
+        let (host, port, request) = match (self.urls.first(), &self.tls_addr) {
+            (Some(url), _) => {
+                let authority = url.split_once("://").map_or(url.as_str(), |(_, rest)| rest);
+                let authority = authority.split('/').next().unwrap_or_default();
+                let (host, port) = match authority.rsplit_once(':') {
+                    Some((host, port)) => (host, port.parse()?),
+                    None => (authority, 80),
+                };
+                // Tunnels behind basic authentication only let the probe
+                // through with the credentials.
+                let authorization = (self.options.basic_auth.as_deref())
+                    .map(|credentials| {
+                        format!("Authorization: Basic {}\r\n", BASE64.encode(credentials))
+                    })
+                    .unwrap_or_default();
+                let request = format!(
+                    "GET / HTTP/1.1\r\nHost: {authority}\r\n{authorization}Connection: close\r\n\r\n"
+                );
+                (host, port, request.into_bytes())
+            }
+            (None, Some(addr)) => {
+                let (host, port) = addr.rsplit_once(':').context("invalid TLS address")?;
+                (host, port.parse()?, tls::client_hello(host)?)
+            }
+            (None, None) => (self.to.as_str(), self.remote_port, Vec::new()),
+        };
+        let stream = connect_with_timeout(host, port, NETWORK_TIMEOUT).await?;
+        probe(stream, &request, token).await
+    }
+
     /// Start the client, listening for new connections.
+    ///
+    /// If reconnecting is enabled, this only returns once the tunnel cannot be
+    /// reopened after the control connection drops.
     pub async fn listen(mut self) -> Result<()> {
         let mut conn = self.conn.take().unwrap();
+        let early = std::mem::take(&mut self.early);
         let this = Arc::new(self);
+        for message in early {
+            this.handle_message(&mut conn, message).await?;
+        }
         loop {
-            match conn.recv().await? {
-                Some(ServerMessage::Hello(_)) => warn!("unexpected hello"),
-                Some(ServerMessage::Challenge(_)) => warn!("unexpected challenge"),
-                Some(ServerMessage::Heartbeat) => (),
-                Some(ServerMessage::Connection(id)) => {
-                    let this = Arc::clone(&this);
-                    tokio::spawn(
-                        async move {
-                            info!("new connection");
-                            match this.handle_connection(id).await {
-                                Ok(_) => info!("connection exited"),
-                                Err(err) => warn!(%err, "connection exited with error"),
-                            }
-                        }
-                        .instrument(info_span!("proxy", %id)),
-                    );
+            let result = this.serve(&mut conn).await;
+            let closed = matches!(&result, Err(err) if err.is::<TunnelClosed>());
+            if !this.options.reconnect || closed {
+                return result;
+            }
+            match &result {
+                Ok(()) => warn!("server closed the connection"),
+                Err(err) => warn!(%err, "lost connection to server"),
+            }
+            this.stats.set_error(match result {
+                Ok(()) => "server closed the connection".to_string(),
+                Err(err) => format!("lost connection to server: {err:#}"),
+            });
+            conn = this.reconnect().await?;
+        }
+    }
+
+    /// Reopen the tunnel on the same remote port, backing off exponentially
+    /// between attempts.
+    async fn reconnect(&self) -> Result<Delimited<ControlStream>> {
+        let mut options = self.options.clone();
+        options.port = self.remote_port;
+        // Keep the subdomain, even if the server picked it at random.
+        options.subdomain = (self.hostname.as_deref())
+            .and_then(|hostname| hostname.split('.').next())
+            .map(String::from);
+        let mut failures = 0;
+        loop {
+            let delay = RECONNECT_BASE_DELAY
+                .saturating_mul(1 << failures.min(16))
+                .min(RECONNECT_MAX_DELAY);
+            // Jitter spreads out clients that were disconnected together.
+            let delay = delay.mul_f64(0.5 + fastrand::f64() / 2.0);
+            info!(?delay, "reconnecting to server");
+            sleep(delay).await;
+            match self.reopen(&options).await {
+                Ok(conn) => {
+                    info!(remote_port = self.remote_port, "reconnected to server");
+                    return Ok(conn);
+                }
+                Err(err) if err.downcast_ref::<AuthError>().is_some() => return Err(err),
+                Err(err) => {
+                    failures += 1;
+                    warn!(%err, failures, "could not reconnect to server");
+                    self.stats
+                        .set_error(format!("could not reconnect: {err:#}"));
+                    if options.max_retries.is_some_and(|max| failures >= max) {
+                        return Err(err.context("giving up on reconnecting to the server"));
+                    }
                 }
-                Some(ServerMessage::Error(err)) => error!(%err, "server error"),
+            }
+        }
+    }
+
+    /// Open the tunnel again with the settings that it was negotiated with.
+    async fn reopen(&self, options: &ClientOptions) -> Result<Delimited<ControlStream>> {
+        let (auth, identity, tls) = (&self.auth, &self.identity, self.tls.as_ref());
+        let (conn, hello) = open_tunnel(&self.to, auth, identity, tls, options).await?;
+        let multiplex = self.mux.lock().unwrap().is_some();
+        ensure!(
+            hello.port == self.remote_port
+                && hello.stripes.max(1) == self.stripes
+                && hello.encryption == self.encryption
+                && hello.udp == self.udp
+                && hello.checksums == self.checksums.is_some()
+                && hello.multiplex == multiplex
+                && hello.challenged == self.challenged
+                && hello.hostname == self.hostname,
+            "server changed the settings of the tunnel"
+        );
+        *self.session_token.lock().unwrap() = hello.session_token;
+        if multiplex {
+            let broker = options.broker.as_ref();
+            let mux = open_mux(&self.to, auth, identity, tls, options.websocket, broker).await?;
+            *self.mux.lock().unwrap() = Some(mux);
+        }
+        Ok(conn)
+    }
+
+    /// Handle messages on the control connection until it closes.
+    async fn serve(self: &Arc<Self>, conn: &mut Delimited<ControlStream>) -> Result<()> {
+        loop {
+            let Ok(message) = timeout(self.heartbeat_timeout, conn.recv()).await else {
+                bail!(
+                    "no heartbeat from the server in {:?}, connection is dead",
+                    self.heartbeat_timeout
+                );
+            };
+            match message? {
+                Some(message) => self.handle_message(conn, message).await?,
                 None => return Ok(()),
             }
         }
     }
 
-    async fn handle_connection(&self, id: Uuid) -> Result<()> {
-        let mut remote_conn =
-            Delimited::new(connect_with_timeout(&self.to[..], CONTROL_PORT).await?);
+    /// Act on a message from the server on the control connection.
+    async fn handle_message(
+        self: &Arc<Self>,
+        conn: &mut Delimited<ControlStream>,
+        message: ServerMessage,
+    ) -> Result<()> {
+        match message {
+            ServerMessage::Hello(_) | ServerMessage::HelloExt(_) => {
+                warn!("unexpected hello")
+            }
+            ServerMessage::Challenge(_) => warn!("unexpected challenge"),
+            ServerMessage::Identity(_) => warn!("unexpected identity"),
+            ServerMessage::Busy(_) => warn!("unexpected busy"),
+            ServerMessage::AuthFailed(_) => warn!("unexpected auth failure"),
+            ServerMessage::Delegated(_) => warn!("unexpected sub-key"),
+            ServerMessage::Observation(_) => warn!("unexpected observation"),
+            ServerMessage::Heartbeat => (),
+            ServerMessage::Ping(seq) => conn.send(ClientMessage::Pong(seq)).await?,
+            ServerMessage::Checksum(checksum) => match &self.checksums {
+                Some(ledger) => ledger.record_remote(checksum),
+                None => warn!("unexpected checksum"),
+            },
+            ServerMessage::Probe(_) => warn!("unexpected probe"),
+            ServerMessage::Connection(id) => self.spawn_connection(id, None),
+            ServerMessage::ConnectionExt(info) => self.spawn_connection(info.id, Some(info)),
+            ServerMessage::Error(message) => self.server_error(message.into())?,
+            ServerMessage::ErrorExt(err) => self.server_error(err)?,
+        }
+        Ok(())
+    }
+
+    /// Record an error that the server reported on the control connection,
+    /// failing if the server closed the tunnel for good.
+    fn server_error(&self, err: ServerError) -> Result<()> {
+        error!(code = ?err.code, message = %err.message, "server error");
+        self.stats.set_error(format!("server error: {err}"));
+        ensure!(!err.code.closes_tunnel(), TunnelClosed(err));
+        Ok(())
+    }
+
+    /// Proxy a new connection in the background.
+    fn spawn_connection(self: &Arc<Self>, id: Uuid, visitor: Option<ConnectionInfo>) {
+        let this = Arc::clone(self);
+        tokio::spawn(
+            async move {
+                let peer = visitor.as_ref().map(|visitor| visitor.peer);
+                match &visitor {
+                    Some(visitor) => {
+                        let port = visitor.port.unwrap_or(this.remote_port);
+                        info!(peer = %visitor.peer, port, "new connection");
+                        this.stats.set_visitor(visitor.peer);
+                    }
+                    None => info!("new connection"),
+                }
+                this.stats.add_connection();
+                match this.handle_connection(id, peer).await {
+                    Ok(_) => info!("connection exited"),
+                    Err(err) => {
+                        let reason = match err.is::<LocalTimeout>() {
+                            true => "local_timeout",
+                            false => "error",
+                        };
+                        warn!(%err, reason, "connection exited with error");
+                        this.stats.set_error(format!("{err:#}"));
+                    }
+                }
+            }
+            .instrument(info_span!("proxy", %id)),
+        );
+    }
+
+    async fn handle_connection(&self, id: Uuid, peer: Option<SocketAddr>) -> Result<()> {
+        let stripes = (0..self.stripes).map(|index| self.accept_stripe(id, index));
+        let data = try_join_all(stripes).await?;
+        let announcement = peer.map(|peer| Announcement {
+            id,
+            peer,
+            remote_port: self.remote_port,
+            tunnel: self.options.name.clone(),
+        });
+        if let (Some(Announce::Port(port)), Some(announcement)) =
+            (self.options.announce, &announcement)
+        {
+            if let Err(err) = self.announce_on(port, announcement).await {
+                warn!(%err, port, "could not announce connection");
+            }
+        }
+        if self.udp {
+            let socket = self.connect_udp().await?;
+            let data = data.into_iter().next().expect("at least one stripe");
+            let data = Metered::remote(data, Arc::clone(&self.stats));
+            let data = Limited::directional(data, self.download.clone(), self.upload.clone());
+            udp::relay(&socket, data).await?;
+            return Ok(());
+        }
+        // Local services that never accept, such as those behind a dropped
+        // VPN, fail on their own deadline rather than the network timeout.
+        let local = TcpStream::connect((unbracket(&self.local_host), self.local_port));
+        let Ok(local_conn) = timeout(self.local_connect_timeout, local).await else {
+            return Err(LocalTimeout(self.local_connect_timeout).into());
+        };
+        let mut local_conn = local_conn
+            .with_context(|| {
+                format!(
+                    "could not connect to {}",
+                    host_port(&self.local_host, self.local_port)
+                )
+            })
+            .context("local service unreachable")?;
+        let flow = match &self.options.capture {
+            Some(capture) => {
+                Some(capture.open(peer, local_conn.local_addr()?, local_conn.peer_addr()?))
+            }
+            None => None,
+        };
+        if let Some(version) = self.options.proxy_protocol {
+            let header = version.header(peer, self.remote_port);
+            local_conn.write_all(&header).await?;
+        }
+        if let (Some(Announce::Inline), Some(announcement)) = (self.options.announce, &announcement)
+        {
+            announcement.write_to(&mut local_conn).await?;
+        }
+        let recorder = (self.options.inspector.as_ref())
+            .map(|inspector| inspector.recorder(&self.local_host, self.local_port));
+        let local_conn = Inspected::new(local_conn, recorder);
+        let local_conn = Captured::new(local_conn, flow);
+        let local_conn = Metered::local(local_conn, Arc::clone(&self.stats));
+        let local_conn =
+            Limited::directional(local_conn, self.upload.clone(), self.download.clone());
+        let mut local = Checksummed::new(local_conn, self.checksums.is_some());
+        let result = match self.host_rewrite() {
+            Some(rewrite) => {
+                // Requests are rewritten on their way through a pipe to the
+                // local service.
+                let (mut near, far) = tokio::io::duplex(PIPE_SIZE);
+                let mut far = Rewritten::new(far, rewrite);
+                let (spliced, copied) = tokio::join!(
+                    striping::splice(&mut near, data),
+                    tokio::io::copy_bidirectional(&mut far, &mut local),
+                );
+                spliced.and(copied.map(drop))
+            }
+            None => striping::splice(&mut local, data).await,
+        };
+        if let (Some(ledger), Some(sent), Some(received)) =
+            (&self.checksums, local.read_digest(), local.written_digest())
+        {
+            ledger.record_local(id, sent, received);
+        }
+        result?;
+        Ok(())
+    }
+
+    /// Change to the head of each request for the local service, if its
+    /// `Host` header is replaced.
+    fn host_rewrite(&self) -> Option<Rewrite> {
+        let host = match &self.options.host_header {
+            HostHeader::Preserve => return None,
+            HostHeader::Rewrite => host_port(&self.local_host, self.local_port),
+            HostHeader::Custom(value) => value.clone(),
+        };
+        Some(Box::new(move |request: &mut RequestHead| {
+            if let Some(original) = request.header("host") {
+                if request.header("x-forwarded-host").is_none() {
+                    request.set_header("X-Forwarded-Host", &original);
+                }
+            }
+            request.set_header("Host", &host);
+        }))
+    }
+
+    /// Send an announcement in its own connection to a local port.
+    async fn announce_on(&self, port: u16, announcement: &Announcement) -> Result<()> {
+        let mut conn =
+            connect_with_timeout(&self.local_host, port, self.local_connect_timeout).await?;
+        announcement.write_to(&mut conn).await?;
+        conn.shutdown().await?;
+        Ok(())
+    }
+
+    /// Open a UDP socket that exchanges datagrams with the local service.
+    async fn connect_udp(&self) -> Result<UdpSocket> {
+        let addr = lookup_host((self.local_host.as_str(), self.local_port))
+            .await?
+            .next()
+            .with_context(|| format!("could not resolve {}", self.local_host))?;
+        let socket = match addr.is_ipv4() {
+            true => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+            false => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+        };
+        socket.connect(addr).await?;
+        Ok(socket)
+    }
+
+    /// Open a data connection to the server for one stripe of a connection.
+    async fn accept_stripe(&self, id: Uuid, index: u8) -> Result<Encrypted<ControlStream>> {
+        let mux = self.mux.lock().unwrap().clone();
+        let token = self.session_token.lock().unwrap().clone();
+        let mut challenged = false;
+        let mut remote_conn = match (&self.options.session, mux) {
+            (Some(session), _) => Delimited::new(session.open().await?),
+            (None, Some(mux)) => Delimited::new(mux.open().await?),
+            (None, None) => {
+                let (tls, broker) = (self.tls.as_ref(), self.options.broker.as_ref());
+                let segment_size = self.options.max_segment_size;
+                let mut conn =
+                    connect_control(&self.to, tls, self.websocket, broker, segment_size).await?;
+
+                // Perform authentication for each new connection, unless the
+                // server issued a token to present without waiting.
+                match token {
+                    Some(token) => {
+                        conn.send(ClientMessage::SessionToken(token)).await?;
+                        challenged = true;
+                    }
+                    None => {
+                        handshake(&mut conn, &self.auth, &self.identity, &self.to).await?;
+                        challenged = self.challenged;
+                    }
+                }
+                conn
+            }
+        };
+
+        let accept = match self.stripes {
+            1 => ClientMessage::Accept(id),
+            _ => ClientMessage::AcceptStripe(id, index),
+        };
+        remote_conn.send(accept).await?;
+        if challenged {
+            // The server challenges every connection, even those with a token
+            // or without credentials.
+            match remote_conn.recv_timeout().await? {
+                Some(ServerMessage::Challenge(_)) => {}
+                Some(ServerMessage::Busy(millis)) => {
+                    return Err(ServerBusy::from_millis(millis).into())
+                }
+                _ => bail!("expected authentication challenge before the data connection"),
+            }
+        }
+        let parts = remote_conn.into_parts();
+        if self.encryption {
+            Encrypted::initiate(parts, id).await
+        } else {
+            Ok(Encrypted::plain(parts))
+        }
+    }
+}
+
+/// Determine how to authenticate and how to check the server's identity.
+fn credentials(options: &ClientOptions) -> Result<(ClientAuthMode, IdentityCheck)> {
+    // Determine authentication mode
+    let auth = if let Some(key) = options.api_key.clone() {
+        ClientAuthMode::ApiKey(key)
+    } else if let Some(secret) = &options.secret {
+        ClientAuthMode::Secret(Authenticator::new(secret))
+    } else {
+        ClientAuthMode::None
+    };
+    if options.require_auth && matches!(auth, ClientAuthMode::None) {
+        bail!("authentication is required, but no client secret or API key was provided");
+    }
+    let identity = match (&options.server_key, &options.known_servers) {
+        (Some(key), _) => IdentityCheck::Pinned(key.to_lowercase()),
+        (None, Some(path)) => IdentityCheck::KnownServers(KnownServers::new(path.clone())),
+        (None, None) => IdentityCheck::None,
+    };
+    Ok((auth, identity))
+}
 
-        // Perform authentication for each new connection
-        match &self.auth {
-            ClientAuthMode::Secret(auth) => {
-                auth.client_handshake(&mut remote_conn).await?;
+/// Build the TLS configuration for the control port, if enabled.
+fn tls_connector(options: &ClientOptions) -> Result<Option<TlsConnector>> {
+    if !options.tls {
+        return Ok(None);
+    }
+    Ok(Some(tls::connector(options.tls_ca.as_deref())?))
+}
+
+/// Check that the server accepts the credentials of the options.
+///
+/// Servers only tell whether they accept credentials in their answer to a
+/// request, so this asks for a tunnel on any port and closes it right away.
+pub async fn verify_credentials(to: &str, options: &ClientOptions) -> Result<()> {
+    let (auth, identity) = credentials(options)?;
+    let tls = tls_connector(options)?;
+    let broker = options.broker.as_ref();
+    let mut stream = connect_control(to, tls.as_ref(), options.websocket, broker, None).await?;
+    handshake(&mut stream, &auth, &identity, to).await?;
+    stream.send(ClientMessage::Hello(0)).await?;
+    match recv_response(&mut stream).await? {
+        Some(ServerMessage::Hello(_)) => Ok(()),
+        // Errors from opening the tunnel, such as a full port range, come
+        // after the credentials were accepted.
+        Some(ServerMessage::Error(message)) => {
+            warn!(%message, "server accepted the credentials but refused a tunnel");
+            Ok(())
+        }
+        Some(ServerMessage::ErrorExt(err)) => {
+            warn!(code = ?err.code, message = %err.message, "server accepted the credentials but refused a tunnel");
+            Ok(())
+        }
+        Some(ServerMessage::AuthFailed(err)) => Err(auth_failed(err)),
+        Some(ServerMessage::Busy(millis)) => Err(ServerBusy::from_millis(millis).into()),
+        Some(_) => bail!("unexpected response to tunnel request"),
+        None => bail!("unexpected EOF"),
+    }
+}
+
+/// Ask the server to mint a scoped, time-limited sub-key for sharing access.
+///
+/// Others can pass the sub-key as an API key to open tunnels on the allowed
+/// ports until it expires, without knowing the original credentials.
+pub async fn create_sub_key(
+    to: &str,
+    options: &ClientOptions,
+    request: SubKeyRequest,
+) -> Result<String> {
+    let (auth, identity) = credentials(options)?;
+    let tls = tls_connector(options)?;
+    ensure!(
+        !matches!(auth, ClientAuthMode::None),
+        "creating a sub-key requires a client secret or API key"
+    );
+    let broker = options.broker.as_ref();
+    let mut stream = connect_control(to, tls.as_ref(), options.websocket, broker, None).await?;
+    handshake(&mut stream, &auth, &identity, to).await?;
+    stream.send(ClientMessage::Delegate(request)).await?;
+    match recv_response(&mut stream).await? {
+        Some(ServerMessage::Delegated(key)) => Ok(key),
+        Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+        Some(ServerMessage::ErrorExt(err)) => bail!("server error: {err}"),
+        Some(ServerMessage::AuthFailed(err)) => Err(auth_failed(err)),
+        Some(ServerMessage::Busy(millis)) => Err(ServerBusy::from_millis(millis).into()),
+        Some(_) => bail!("unexpected response to sub-key request"),
+        None => bail!("unexpected EOF"),
+    }
+}
+
+/// Read-only view of a tunnel on the server, opened with [`observe`].
+pub struct Observer {
+    conn: Delimited<ControlStream>,
+}
+
+impl Observer {
+    /// Wait for the next event on the tunnel, or return `None` once the
+    /// tunnel closes.
+    pub async fn next(&mut self) -> Result<Option<Observation>> {
+        loop {
+            let Ok(message) = timeout(HEARTBEAT_TIMEOUT, self.conn.recv()).await else {
+                bail!("no heartbeat from the server in {HEARTBEAT_TIMEOUT:?}, connection is dead");
+            };
+            match message? {
+                Some(ServerMessage::Observation(event)) => return Ok(Some(event)),
+                Some(ServerMessage::Heartbeat) => (),
+                Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+                Some(ServerMessage::ErrorExt(err)) => bail!("server error: {err}"),
+                Some(ServerMessage::AuthFailed(err)) => return Err(auth_failed(err)),
+                Some(ServerMessage::Busy(millis)) => {
+                    return Err(ServerBusy::from_millis(millis).into())
+                }
+                Some(_) => bail!("unexpected message while observing"),
+                None => return Ok(None),
             }
+        }
+    }
+}
+
+/// Attach to an existing tunnel on the server as a read-only observer.
+///
+/// This needs a client secret or API key, or a sub-key with the observe scope
+/// that covers the tunnel's port. Observers receive the metadata of each
+/// connection, and payload samples if requested, but cannot carry traffic.
+pub async fn observe(
+    to: &str,
+    options: &ClientOptions,
+    request: ObserveRequest,
+) -> Result<Observer> {
+    let (auth, identity) = credentials(options)?;
+    let tls = tls_connector(options)?;
+    ensure!(
+        !matches!(auth, ClientAuthMode::None),
+        "observing a tunnel requires a client secret or API key"
+    );
+    let broker = options.broker.as_ref();
+    let mut conn = connect_control(to, tls.as_ref(), options.websocket, broker, None).await?;
+    handshake(&mut conn, &auth, &identity, to).await?;
+    conn.send(ClientMessage::Observe(request)).await?;
+    match conn.recv_timeout().await? {
+        Some(ServerMessage::Heartbeat) => Ok(Observer { conn }),
+        Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+        Some(ServerMessage::ErrorExt(err)) => bail!("server error: {err}"),
+        Some(ServerMessage::AuthFailed(err)) => Err(auth_failed(err)),
+        Some(ServerMessage::Busy(millis)) => Err(ServerBusy::from_millis(millis).into()),
+        Some(_) => bail!("unexpected response to observe request"),
+        None => bail!("unexpected EOF"),
+    }
+}
+
+/// Open a tunnel, retrying after a delay while the server is busy.
+async fn open_patiently(
+    to: &str,
+    auth: &ClientAuthMode,
+    identity: &IdentityCheck,
+    tls: Option<&TlsConnector>,
+    options: &ClientOptions,
+) -> Result<(Delimited<ControlStream>, ServerHello)> {
+    let mut attempts = 0;
+    loop {
+        match open_tunnel(to, auth, identity, tls, options).await {
+            Ok(tunnel) => return Ok(tunnel),
+            Err(err) => match err.downcast_ref::<ServerBusy>() {
+                Some(busy) if attempts < MAX_BUSY_RETRIES => {
+                    attempts += 1;
+                    let delay = busy.max_delay.mul_f64(fastrand::f64());
+                    warn!(?delay, "server is busy, retrying after a random delay");
+                    sleep(delay).await;
+                }
+                _ => return Err(err),
+            },
+        }
+    }
+}
+
+/// Open a tunnel on another port after the requested one was unavailable,
+/// according to the fallback policy.
+async fn open_fallback(
+    to: &str,
+    auth: &ClientAuthMode,
+    identity: &IdentityCheck,
+    tls: Option<&TlsConnector>,
+    options: &ClientOptions,
+    err: anyhow::Error,
+) -> Result<(Delimited<ControlStream>, ServerHello)> {
+    let ports = match &options.port_fallback {
+        PortFallback::Fail => return Err(err),
+        // Servers that cannot pick the nearest port assign any port instead.
+        PortFallback::Nearest | PortFallback::Any => vec![0],
+        PortFallback::Range(range) => {
+            let mut ports: Vec<u16> = range.clone().filter(|&p| p != options.port).collect();
+            fastrand::shuffle(&mut ports);
+            ports.truncate(MAX_FALLBACK_ATTEMPTS);
+            ports
+        }
+    };
+    let requested = options.port;
+    warn!(%err, requested, "requested port is unavailable, falling back");
+    for port in ports {
+        let options = ClientOptions {
+            port,
+            ..options.clone()
+        };
+        match open_patiently(to, auth, identity, tls, &options).await {
+            Ok((stream, hello)) => {
+                warn!(
+                    requested,
+                    remote_port = hello.port,
+                    "using a fallback port instead of the requested one"
+                );
+                return Ok((stream, hello));
+            }
+            Err(err) if port_unavailable(&err) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(err.context("no fallback port was available"))
+}
+
+/// Whether the server rejected a tunnel because its port cannot be used.
+fn port_unavailable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<TunnelRejected>().is_some_and(|err| {
+        matches!(
+            err.0.code,
+            ErrorCode::PortUnavailable | ErrorCode::PortOutOfRange
+        )
+    })
+}
+
+/// Connect to the server and request a tunnel, returning the control
+/// connection and the negotiated tunnel.
+async fn open_tunnel(
+    to: &str,
+    auth: &ClientAuthMode,
+    identity: &IdentityCheck,
+    tls: Option<&TlsConnector>,
+    options: &ClientOptions,
+) -> Result<(Delimited<ControlStream>, ServerHello)> {
+    let mut stream = match &options.session {
+        Some(session) => Delimited::new(session.open().await?),
+        None => {
+            let broker = options.broker.as_ref();
+            let mut stream = connect_control(to, tls, options.websocket, broker, None).await?;
+            handshake(&mut stream, auth, identity, to).await?;
+            stream
+        }
+    };
+
+    if options.needs_extensions() {
+        let hello = ClientHello {
+            port: options.port,
+            version: PROTOCOL_VERSION,
+            compression: options.compression,
+            checksums: options.checksums,
+            stripes: options.stripes,
+            encryption: options.encryption,
+            udp: options.udp,
+            name: options.name.clone(),
+            // Tunnels in a session are multiplexed over it already.
+            multiplex: options.multiplex && options.session.is_none(),
+            adaptive_heartbeat: options.adaptive_heartbeat,
+            connection_info: options.announce.is_some()
+                || options.proxy_protocol.is_some()
+                || options.visitor_info,
+            heartbeat_timeout_ms: options
+                .heartbeat_timeout
+                .map(|timeout| timeout.as_millis() as u64),
+            // Tokens are sent before the server could prove its identity.
+            session_token: options.session_tokens
+                && !matches!(auth, ClientAuthMode::None)
+                && matches!(identity, IdentityCheck::None),
+            nearest_port: options.nearest_port(),
+            allow_ips: options.allow_ips.clone(),
+            deny_ips: options.deny_ips.clone(),
+            listener_class: options.listener_class.clone(),
+            http: options.http,
+            subdomain: options.subdomain.clone(),
+            tls_passthrough: options.tls_passthrough,
+            basic_auth: (options.basic_auth.as_deref())
+                .map(|credentials| hex::encode(http::basic_auth_digest(credentials))),
+            oidc_allow: options.oidc_allow.clone(),
+        };
+        stream.send(ClientMessage::HelloExt(hello)).await?;
+    } else {
+        stream.send(ClientMessage::Hello(options.port)).await?;
+    }
+    // Servers that check credentials challenge clients without any too, but
+    // some let them open tunnels anyway.
+    let mut challenged = false;
+    let mut hello = loop {
+        match recv_response(&mut stream).await? {
+            Some(ServerMessage::Hello(port)) => {
+                break ServerHello {
+                    port,
+                    ..Default::default()
+                }
+            }
+            Some(ServerMessage::HelloExt(hello)) => {
+                stream.set_compression(hello.compression);
+                break hello;
+            }
+            Some(ServerMessage::Error(message)) => {
+                return Err(TunnelRejected(message.into()).into())
+            }
+            Some(ServerMessage::ErrorExt(err)) => return Err(TunnelRejected(err).into()),
+            Some(ServerMessage::AuthFailed(_)) | None if challenged => {
+                bail!(
+                    "server requires authentication, but no client secret or API key was provided"
+                );
+            }
+            Some(ServerMessage::AuthFailed(err)) => return Err(auth_failed(err)),
+            Some(ServerMessage::Challenge(_))
+                if matches!(auth, ClientAuthMode::None) && !challenged =>
+            {
+                challenged = true
+            }
+            Some(ServerMessage::Busy(millis)) => return Err(ServerBusy::from_millis(millis).into()),
+            Some(_) => bail!("unexpected initial non-hello message"),
+            None => bail!("unexpected EOF"),
+        }
+    };
+    hello.challenged |= challenged;
+    Ok((stream, hello))
+}
+
+/// Authenticated connection to the server that several tunnels share, with
+/// their control and data connections multiplexed over it.
+///
+/// Tunnels are opened over a session by setting [`ClientOptions::session`].
+/// If the connection drops, it is reopened the next time that a tunnel needs
+/// it, so tunnels that reconnect all end up on the new connection.
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<SessionInner>,
+}
+
+struct SessionInner {
+    to: String,
+    auth: ClientAuthMode,
+    identity: IdentityCheck,
+    tls: Option<TlsConnector>,
+    websocket: bool,
+    broker: Option<Broker>,
+
+    /// Current connection, with a count of how many times it was reopened.
+    mux: tokio::sync::Mutex<(u64, MuxClient)>,
+}
+
+impl Session {
+    /// Connect and authenticate to the server, using the connection settings
+    /// and credentials of `options`.
+    pub async fn connect(to: &str, options: &ClientOptions) -> Result<Self> {
+        let (auth, identity) = credentials(options)?;
+        let tls = tls_connector(options)?;
+        let broker = options.broker.as_ref();
+        let mux = open_mux(
+            to,
+            &auth,
+            &identity,
+            tls.as_ref(),
+            options.websocket,
+            broker,
+        )
+        .await?;
+        info!("opened shared session to server");
+        let inner = SessionInner {
+            to: to.to_string(),
+            auth,
+            identity,
+            tls,
+            websocket: options.websocket,
+            broker: options.broker.clone(),
+            mux: tokio::sync::Mutex::new((0, mux)),
+        };
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Open a new stream, to be used like a new authenticated connection to
+    /// the control port, reconnecting first if the session has dropped.
+    async fn open(&self) -> Result<ControlStream> {
+        let (generation, mux) = self.inner.mux.lock().await.clone();
+        match mux.open().await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => warn!(%err, "shared session dropped, reconnecting"),
+        }
+        let mut current = self.inner.mux.lock().await;
+        if current.0 == generation {
+            let inner = &self.inner;
+            let tls = inner.tls.as_ref();
+            let mux = open_mux(
+                &inner.to,
+                &inner.auth,
+                &inner.identity,
+                tls,
+                inner.websocket,
+                inner.broker.as_ref(),
+            )
+            .await?;
+            *current = (generation + 1, mux);
+        }
+        current.1.open().await
+    }
+}
+
+/// Open the authenticated connection that data connections are multiplexed over.
+async fn open_mux(
+    to: &str,
+    auth: &ClientAuthMode,
+    identity: &IdentityCheck,
+    tls: Option<&TlsConnector>,
+    websocket: bool,
+    broker: Option<&Broker>,
+) -> Result<MuxClient> {
+    let mut stream = connect_control(to, tls, websocket, broker, None).await?;
+    handshake(&mut stream, auth, identity, to).await?;
+    stream.send(ClientMessage::Multiplex).await?;
+    MuxClient::connect(stream.into_parts()).await
+}
+
+/// Next response from the server while connecting, with the reason for a
+/// failed authentication in place of the error that comes before it for
+/// older clients.
+async fn recv_response(stream: &mut Delimited<ControlStream>) -> Result<Option<ServerMessage>> {
+    let message = stream.recv_timeout().await?;
+    if let Some(ServerMessage::Error(_)) = message {
+        // The server closes the connection after an error, so this is quick.
+        if let Ok(Ok(Some(ServerMessage::AuthFailed(err)))) =
+            timeout(NETWORK_TIMEOUT, stream.recv()).await
+        {
+            return Ok(Some(ServerMessage::AuthFailed(err)));
+        }
+    }
+    Ok(message)
+}
+
+/// Explain an authentication failure, keeping the server's reason as the cause.
+fn auth_failed(err: AuthError) -> anyhow::Error {
+    let hint = err.hint();
+    anyhow::Error::new(err).context(hint)
+}
+
+/// Verify the server's identity if requested, then authenticate with it.
+async fn handshake(
+    stream: &mut Delimited<ControlStream>,
+    auth: &ClientAuthMode,
+    identity: &IdentityCheck,
+    to: &str,
+) -> Result<()> {
+    if matches!(identity, IdentityCheck::None) {
+        match auth {
+            ClientAuthMode::Secret(authenticator) => authenticator.client_handshake(stream).await?,
             ClientAuthMode::ApiKey(key) => {
-                ApiKeyAuthenticator::client_handshake(key, &mut remote_conn).await?;
+                ApiKeyAuthenticator::client_handshake(key, stream).await?
             }
             ClientAuthMode::None => {
                 // No authentication required
             }
         }
+        return Ok(());
+    }
 
-        remote_conn.send(ClientMessage::Accept(id)).await?;
-        let mut local_conn = connect_with_timeout(&self.local_host, self.local_port).await?;
-        let mut parts = remote_conn.into_parts();
-        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-        local_conn.write_all(&parts.read_buf).await?; // mostly of the cases, this will be empty
-        tokio::io::copy_bidirectional(&mut local_conn, &mut parts.io).await?;
-        Ok(())
+    // Servers that require authentication send their challenge immediately, so
+    // it may arrive before the identity proof. Credentials are only sent after
+    // the server has proven its identity.
+    let exchange = IdentityExchange::new()?;
+    stream
+        .send(ClientMessage::Identify(exchange.request().clone()))
+        .await?;
+    let mut challenge = None;
+    let proof = loop {
+        match recv_response(stream).await? {
+            Some(ServerMessage::Challenge(id)) => challenge = Some(id),
+            Some(ServerMessage::Identity(proof)) => break proof,
+            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
+            Some(ServerMessage::ErrorExt(err)) => bail!("server error: {err}"),
+            Some(ServerMessage::AuthFailed(err)) => return Err(auth_failed(err)),
+            Some(ServerMessage::Busy(millis)) => return Err(ServerBusy::from_millis(millis).into()),
+            _ => bail!("server did not prove its identity"),
+        }
+    };
+    let session = exchange.verify(&proof)?;
+    match identity {
+        IdentityCheck::Pinned(key) => ensure!(
+            *key == proof.public_key,
+            "server identity {} does not match the pinned key, refusing to connect",
+            proof.public_key
+        ),
+        IdentityCheck::KnownServers(known) => known.verify(to, &proof.public_key)?,
+        IdentityCheck::None => unreachable!(),
+    }
+
+    let tag = match (auth, challenge) {
+        (ClientAuthMode::Secret(authenticator), Some(challenge)) => {
+            authenticator.answer(&challenge)
+        }
+        (ClientAuthMode::ApiKey(key), Some(_)) => key.clone(),
+        (ClientAuthMode::None, None) => return Ok(()),
+        (ClientAuthMode::None, Some(_)) => {
+            bail!("server requires authentication, but no client secret or API key was provided")
+        }
+        (_, None) => {
+            bail!("expected authentication challenge, but the server does not require one")
+        }
+    };
+    stream
+        .send(ClientMessage::Authenticate(session.seal(&tag)))
+        .await?;
+    Ok(())
+}
+
+/// Connect to the control port of the server, over TLS and WebSocket if
+/// enabled, or through the broker if there is one. A segment size clamps the
+/// size of TCP segments in both directions.
+async fn connect_control(
+    to: &str,
+    tls: Option<&TlsConnector>,
+    websocket: bool,
+    broker: Option<&Broker>,
+    segment_size: Option<u16>,
+) -> Result<Delimited<ControlStream>> {
+    if let Some(broker) = broker {
+        let stream = broker.dial().context(ServerUnreachable)?;
+        return Ok(Delimited::new(ControlStream::Memory(stream)));
+    }
+    let to = unbracket(to);
+    let stream = match segment_size {
+        Some(size) => connect_clamped(to, CONTROL_PORT, size).await,
+        None => connect_with_timeout(to, CONTROL_PORT, NETWORK_TIMEOUT).await,
+    }
+    .context(ServerUnreachable)?;
+    let mut stream = match tls {
+        Some(connector) => tls::connect(connector, to, stream).await?,
+        None => ControlStream::Plain(stream),
+    };
+    if websocket {
+        stream = websocket::connect(stream, to, CONTROL_PORT).await?;
+    }
+    Ok(Delimited::new(stream))
+}
+
+/// Visit the tunnel with the start of a request, and check that the server
+/// answers with the token of the probe.
+async fn probe(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    request: &[u8],
+    token: Uuid,
+) -> Result<()> {
+    let mut answer = Vec::new();
+    timeout(NETWORK_TIMEOUT, async {
+        stream.write_all(request).await?;
+        stream.read_to_end(&mut answer).await
+    })
+    .await
+    .context("timed out waiting for the server to answer the probe")??;
+    ensure!(
+        String::from_utf8_lossy(&answer).contains(&token.to_string()),
+        "the public endpoint is not answered by the server"
+    );
+    Ok(())
+}
+
+async fn connect_with_timeout(to: &str, port: u16, duration: Duration) -> Result<TcpStream> {
+    match timeout(duration, TcpStream::connect((unbracket(to), port))).await {
+        Ok(res) => res,
+        Err(err) => Err(err.into()),
     }
+    .with_context(|| format!("could not connect to {}", host_port(to, port)))
 }
 
-async fn connect_with_timeout(to: &str, port: u16) -> Result<TcpStream> {
-    match timeout(NETWORK_TIMEOUT, TcpStream::connect((to, port))).await {
+/// Connect with a maximum segment size, which is also advertised to the
+/// server so that it sends segments no larger either. This gets around paths
+/// that silently drop full-sized packets.
+pub(crate) async fn connect_clamped(to: &str, port: u16, segment_size: u16) -> Result<TcpStream> {
+    let connect = async {
+        let addr = lookup_host((unbracket(to), port))
+            .await?
+            .next()
+            .with_context(|| format!("could not resolve {to}"))?;
+        let socket = match addr.is_ipv4() {
+            true => TcpSocket::new_v4()?,
+            false => TcpSocket::new_v6()?,
+        };
+        clamp_segment_size(&socket, segment_size)?;
+        Ok::<_, anyhow::Error>(socket.connect(addr).await?)
+    };
+    match timeout(NETWORK_TIMEOUT, connect).await {
         Ok(res) => res,
         Err(err) => Err(err.into()),
     }
-    .with_context(|| format!("could not connect to {to}:{port}"))
+    .with_context(|| format!("could not connect to {}", host_port(to, port)))
+}
+
+#[cfg(unix)]
+fn clamp_segment_size(socket: &TcpSocket, segment_size: u16) -> Result<()> {
+    socket2::SockRef::from(socket)
+        .set_mss(segment_size.into())
+        .context("could not set the maximum segment size")
+}
+
+#[cfg(not(unix))]
+fn clamp_segment_size(_socket: &TcpSocket, _segment_size: u16) -> Result<()> {
+    bail!("clamping the segment size is only supported on Unix")
 }
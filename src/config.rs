@@ -0,0 +1,527 @@
+//! Configuration files for clients and servers.
+//!
+//! A development environment often exposes the same handful of services every
+//! day. Instead of a long `bore local` command line, `bore up` reads them
+//! from a TOML file along with the server and credentials, and `bore down`
+//! closes them all again:
+//!
+//! ```toml
+//! server = "bore.example.com"
+//! secret = "my_secret_string"
+//!
+//! [[tunnels]]
+//! name = "web"
+//! local_port = 3000
+//! remote_port = 8080
+//! labels = { team = "frontend" }
+//!
+//! [[tunnels]]
+//! name = "dns"
+//! local_port = 5353
+//! protocol = "udp"
+//! api_key = "key_for_this_tunnel_only"
+//! ```
+//!
+//! Servers read their port range, credentials, and bind addresses from a
+//! file with `bore server --config`, and reload it on SIGHUP:
+//!
+//! ```toml
+//! port_ranges = ["20000-21000", "30000-31000"]
+//! exclude_ports = ["20500-20599"]
+//! secret = "my_secret_string"
+//! bind_addr = "0.0.0.0"
+//!
+//! # Accepted as well while clients move to the secret above.
+//! [secrets]
+//! "2024" = "my_old_secret_string"
+//!
+//! # Clients that the API key backend puts in the free tier.
+//! [credential_ports."tier:free"]
+//! min_port = 20000
+//! max_port = 29999
+//! ```
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::auth::secret_fingerprint;
+use crate::client::{ClientOptions, HostHeader};
+use crate::http::{check_basic_auth, check_subdomain};
+use crate::oidc;
+use crate::ports::{parse_port_range, PortSet};
+use crate::shared::check_tunnel_name;
+
+/// Client configuration, as read from a file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Address of the remote server.
+    pub server: String,
+
+    /// Secret for authentication.
+    #[serde(default)]
+    pub secret: Option<String>,
+
+    /// API key for authentication, instead of a secret.
+    #[serde(default)]
+    pub api_key: Option<String>,
+
+    /// Whether to connect to the server's control port over TLS.
+    #[serde(default)]
+    pub tls: bool,
+
+    /// PEM file of CA certificates to trust for TLS.
+    #[serde(default)]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Public key that the server must prove it holds.
+    #[serde(default)]
+    pub server_key: Option<String>,
+
+    /// Tunnels to open.
+    pub tunnels: Vec<TunnelConfig>,
+}
+
+/// Definition of one tunnel in a [`ClientConfig`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelConfig {
+    /// Name of the tunnel, which labels its logs and status.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Local host to expose.
+    #[serde(default = "default_local_host")]
+    pub local_host: String,
+
+    /// Local port to expose.
+    pub local_port: u16,
+
+    /// Port on the remote server to select, or any port if not given.
+    #[serde(default)]
+    pub remote_port: u16,
+
+    /// Protocol that the tunnel forwards.
+    #[serde(default)]
+    pub protocol: Protocol,
+
+    /// Subdomain to ask for as an HTTP or TLS tunnel, instead of its name or
+    /// a random one.
+    #[serde(default)]
+    pub subdomain: Option<String>,
+
+    /// What an HTTP tunnel does with the `Host` header of requests, such as
+    /// `rewrite` or `custom:app.local`.
+    #[serde(default)]
+    pub host_header: HostHeader,
+
+    /// Credentials, as `user:password`, that visitors of an HTTP tunnel must
+    /// give with basic authentication.
+    #[serde(default)]
+    pub basic_auth: Option<String>,
+
+    /// Emails, and domains starting with `@`, that visitors of an HTTP
+    /// tunnel must log in as with the server's OIDC provider.
+    #[serde(default)]
+    pub oidc_allow: Vec<String>,
+
+    /// Labels to show in the status of the tunnel, such as the team or
+    /// service that it belongs to.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+
+    /// Secret for this tunnel, instead of the shared credentials.
+    #[serde(default)]
+    pub secret: Option<String>,
+
+    /// API key for this tunnel, instead of the shared credentials.
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+/// Protocol that a tunnel forwards.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// TCP connections.
+    #[default]
+    Tcp,
+
+    /// UDP datagrams.
+    Udp,
+
+    /// HTTP requests, routed by hostname on the server's shared HTTP port.
+    Http,
+
+    /// TLS that the local service terminates, routed by server name on the
+    /// server's shared TLS port.
+    Tls,
+}
+
+fn default_local_host() -> String {
+    "localhost".into()
+}
+
+impl ClientConfig {
+    /// Read and check a configuration file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+        let config = Self::parse(&contents)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Parse and check a configuration.
+    ///
+    /// ```
+    /// use bore_cli::config::{ClientConfig, Protocol};
+    ///
+    /// let config = ClientConfig::parse(r#"
+    ///     server = "bore.example.com"
+    ///     tunnels = [{ local_port = 3000 }, { local_port = 53, protocol = "udp" }]
+    /// "#).unwrap();
+    /// assert_eq!(config.tunnels[1].protocol, Protocol::Udp);
+    ///
+    /// assert!(ClientConfig::parse(r#"server = "x"
+    ///     tunnels = []"#).is_err());
+    /// ```
+    pub fn parse(contents: &str) -> Result<Self> {
+        let config: Self = toml::from_str(contents)?;
+        ensure!(!config.tunnels.is_empty(), "no tunnels are defined");
+        let mut names = HashSet::new();
+        for name in config.tunnels.iter().filter_map(|t| t.name.as_deref()) {
+            check_tunnel_name(name)?;
+            ensure!(names.insert(name), "tunnel {name:?} is defined twice");
+        }
+        for subdomain in config.tunnels.iter().filter_map(|t| t.subdomain.as_deref()) {
+            check_subdomain(subdomain)?;
+        }
+        for credentials in config
+            .tunnels
+            .iter()
+            .filter_map(|t| t.basic_auth.as_deref())
+        {
+            check_basic_auth(credentials)?;
+        }
+        for rule in config.tunnels.iter().flat_map(|t| &t.oidc_allow) {
+            oidc::check_rule(rule)?;
+        }
+        Ok(config)
+    }
+
+    /// Options for connecting to the server, shared by all tunnels.
+    pub fn options(&self) -> ClientOptions {
+        ClientOptions {
+            secret: self.secret.clone(),
+            api_key: self.api_key.clone(),
+            tls: self.tls,
+            tls_ca: self.tls_ca.clone(),
+            server_key: self.server_key.clone(),
+            reconnect: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Server configuration, as read from a file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Minimum accepted TCP port number.
+    pub min_port: u16,
+
+    /// Maximum accepted TCP port number.
+    pub max_port: u16,
+
+    /// Ranges of accepted TCP ports as `MIN-MAX`, instead of `min_port` and
+    /// `max_port`.
+    pub port_ranges: Vec<String>,
+
+    /// Ports and ranges of ports that are never accepted.
+    pub exclude_ports: Vec<String>,
+
+    /// Secret for authentication.
+    pub secret: Option<String>,
+
+    /// More secrets that are accepted, by their label in logs, such as while
+    /// rotating to a new secret.
+    pub secrets: BTreeMap<String, String>,
+
+    /// URL to validate API keys against, instead of a secret.
+    pub api_validation_url: Option<String>,
+
+    /// More URLs to validate API keys against, tried in order when those
+    /// before them are unavailable.
+    pub api_validation_fallback_urls: Vec<String>,
+
+    /// IP address to bind the control port to.
+    pub bind_addr: IpAddr,
+
+    /// IP addresses where tunnels listen, which default to `bind_addr`.
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind_tunnels: Vec<IpAddr>,
+
+    /// IP addresses where the tunnels of clients that ask for a class of
+    /// listener listen instead, by class, such as `internal`.
+    pub listener_classes: BTreeMap<String, Vec<IpAddr>>,
+
+    /// Ports that clients may use, by the credential they authenticated
+    /// with: `secret:<label>`, `user:<user_id>`, or `tier:<tier>`.
+    pub credential_ports: BTreeMap<String, PortRange>,
+}
+
+/// Range of ports in a configuration file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortRange {
+    /// Smallest port in the range.
+    pub min_port: u16,
+
+    /// Largest port in the range.
+    pub max_port: u16,
+}
+
+impl PortRange {
+    /// Ports in the range.
+    pub fn range(&self) -> RangeInclusive<u16> {
+        self.min_port..=self.max_port
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            min_port: 1024,
+            max_port: 65535,
+            port_ranges: Vec::new(),
+            exclude_ports: Vec::new(),
+            secret: None,
+            secrets: BTreeMap::new(),
+            api_validation_url: None,
+            api_validation_fallback_urls: Vec::new(),
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            bind_tunnels: Vec::new(),
+            listener_classes: BTreeMap::new(),
+            credential_ports: BTreeMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Read and check a configuration file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+        let config = Self::parse(&contents)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Parse and check a configuration.
+    ///
+    /// ```
+    /// use bore_cli::config::ServerConfig;
+    ///
+    /// let config = ServerConfig::parse("min_port = 9000\nsecret = \"s3cret\"").unwrap();
+    /// assert_eq!(config.port_range(), 9000..=65535);
+    /// assert_eq!(config.bind_tunnels(), [config.bind_addr]);
+    ///
+    /// assert!(ServerConfig::parse("min_port = 9000\nmax_port = 8000").is_err());
+    /// ```
+    pub fn parse(contents: &str) -> Result<Self> {
+        let config: Self = toml::from_str(contents)?;
+        ensure!(!config.port_range().is_empty(), "port range is empty");
+        for range in config.port_ranges.iter().chain(&config.exclude_ports) {
+            parse_port_range(range)?;
+        }
+        ensure!(!config.ports().is_empty(), "all ports are excluded");
+        for (class, addrs) in &config.listener_classes {
+            check_tunnel_name(class)
+                .with_context(|| format!("invalid listener class {class:?}"))?;
+            ensure!(
+                !addrs.is_empty(),
+                "listener class {class:?} has no addresses"
+            );
+        }
+        for (credential, range) in &config.credential_ports {
+            let known = ["secret:", "user:", "tier:"];
+            ensure!(
+                known.iter().any(|prefix| credential.starts_with(prefix)),
+                "credential {credential:?} must start with secret:, user:, or tier:"
+            );
+            ensure!(
+                !range.range().is_empty(),
+                "port range of {credential:?} is empty"
+            );
+        }
+        Ok(config)
+    }
+
+    /// Range of TCP ports that can be forwarded.
+    pub fn port_range(&self) -> RangeInclusive<u16> {
+        self.min_port..=self.max_port
+    }
+
+    /// TCP ports that can be forwarded: those of `port_ranges`, or else of
+    /// `min_port` and `max_port`, except those of `exclude_ports`. Ranges that
+    /// do not parse are left out.
+    ///
+    /// ```
+    /// use bore_cli::config::ServerConfig;
+    ///
+    /// let config = ServerConfig::parse(
+    ///     "port_ranges = [\"20000-21000\", \"30000-31000\"]\nexclude_ports = [\"20022\"]",
+    /// )
+    /// .unwrap();
+    /// let ports = config.ports();
+    /// assert!(ports.contains(20021) && ports.contains(30000));
+    /// assert!(!ports.contains(20022) && !ports.contains(25000));
+    ///
+    /// assert!(ServerConfig::parse("port_ranges = [\"20000-\"]").is_err());
+    /// ```
+    pub fn ports(&self) -> PortSet {
+        let parse = |ranges: &[String]| -> Vec<RangeInclusive<u16>> {
+            (ranges.iter())
+                .filter_map(|range| parse_port_range(range).ok())
+                .collect()
+        };
+        let ranges = match self.port_ranges.is_empty() {
+            true => vec![self.port_range()],
+            false => parse(&self.port_ranges),
+        };
+        PortSet::new(ranges, parse(&self.exclude_ports))
+    }
+
+    /// IP addresses where tunnels listen.
+    ///
+    /// ```
+    /// use bore_cli::config::ServerConfig;
+    ///
+    /// let config = ServerConfig::parse(
+    ///     "bind_tunnels = [\"203.0.113.7\", \"2001:db8::7\"]\n\
+    ///      [listener_classes]\ninternal = [\"10.0.0.7\"]",
+    /// )
+    /// .unwrap();
+    /// assert_eq!(config.bind_tunnels().len(), 2);
+    /// assert_eq!(config.listener_classes["internal"][0].to_string(), "10.0.0.7");
+    ///
+    /// let config = ServerConfig::parse("bind_tunnels = \"10.0.0.7\"").unwrap();
+    /// assert_eq!(config.bind_tunnels()[0].to_string(), "10.0.0.7");
+    /// ```
+    pub fn bind_tunnels(&self) -> Vec<IpAddr> {
+        match self.bind_tunnels.is_empty() {
+            true => vec![self.bind_addr],
+            false => self.bind_tunnels.clone(),
+        }
+    }
+
+    /// All accepted secrets with their labels, where `secret` is labeled by
+    /// its fingerprint.
+    ///
+    /// ```
+    /// use bore_cli::config::ServerConfig;
+    ///
+    /// let config = ServerConfig::parse("secret = \"new\"\nsecrets = { old = \"s3cret\" }").unwrap();
+    /// let labels: Vec<_> = config.secrets().into_iter().map(|(label, _)| label).collect();
+    /// assert_eq!(labels.len(), 2);
+    /// assert_eq!(labels[1], "old");
+    /// ```
+    pub fn secrets(&self) -> Vec<(String, &str)> {
+        let unlabeled = self
+            .secret
+            .as_deref()
+            .map(|secret| (secret_fingerprint(secret), secret));
+        let labeled = self
+            .secrets
+            .iter()
+            .map(|(label, secret)| (label.clone(), secret.as_str()));
+        unlabeled.into_iter().chain(labeled).collect()
+    }
+
+    /// Ports that clients may use, by credential.
+    ///
+    /// ```
+    /// use bore_cli::config::ServerConfig;
+    ///
+    /// let config = ServerConfig::parse(
+    ///     "[credential_ports.\"tier:free\"]\nmin_port = 20000\nmax_port = 29999",
+    /// )
+    /// .unwrap();
+    /// assert_eq!(config.credential_ports()["tier:free"], 20000..=29999);
+    ///
+    /// assert!(ServerConfig::parse("[credential_ports.free]\nmin_port = 1\nmax_port = 2").is_err());
+    /// ```
+    pub fn credential_ports(&self) -> BTreeMap<String, RangeInclusive<u16>> {
+        (self.credential_ports.iter())
+            .map(|(credential, range)| (credential.clone(), range.range()))
+            .collect()
+    }
+
+    /// URLs to validate API keys against, in the order they are tried.
+    ///
+    /// ```
+    /// use bore_cli::config::ServerConfig;
+    ///
+    /// let config = ServerConfig::parse(
+    ///     "api_validation_url = \"https://a/validate\"\n\
+    ///      api_validation_fallback_urls = [\"https://b/validate\"]",
+    /// )
+    /// .unwrap();
+    /// assert_eq!(config.validation_urls(), ["https://a/validate", "https://b/validate"]);
+    /// ```
+    pub fn validation_urls(&self) -> Vec<String> {
+        let primary = self.api_validation_url.iter();
+        primary
+            .chain(&self.api_validation_fallback_urls)
+            .cloned()
+            .collect()
+    }
+}
+
+impl TunnelConfig {
+    /// Options for opening this tunnel, on top of the shared ones.
+    ///
+    /// A tunnel with its own credentials connects on its own, instead of over
+    /// a session shared with the other tunnels.
+    pub fn options(&self, shared: &ClientOptions) -> ClientOptions {
+        let mut options = ClientOptions {
+            port: self.remote_port,
+            name: self.name.clone(),
+            udp: self.protocol == Protocol::Udp,
+            http: self.protocol == Protocol::Http,
+            tls_passthrough: self.protocol == Protocol::Tls,
+            subdomain: self.subdomain.clone(),
+            host_header: self.host_header.clone(),
+            basic_auth: self.basic_auth.clone(),
+            oidc_allow: self.oidc_allow.clone(),
+            ..shared.clone()
+        };
+        if self.secret.is_some() || self.api_key.is_some() {
+            options.secret = self.secret.clone();
+            options.api_key = self.api_key.clone();
+            options.session = None;
+        }
+        options
+    }
+}
+
+/// Deserialize a list of IP addresses, or a single one.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpAddr>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(IpAddr),
+        Many(Vec<IpAddr>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
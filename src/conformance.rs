@@ -0,0 +1,301 @@
+//! Protocol conformance checks that can run against any server.
+//!
+//! Forks and alternative implementations of the server can run these checks
+//! to confirm that unmodified clients will work with them. Each check opens
+//! its own connections to the control port and covers one part of the
+//! protocol, including how the server handles clients that misbehave.
+//!
+//! ```no_run
+//! use bore_cli::conformance::Target;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let report = Target::new("localhost").run().await;
+//! print!("{report}");
+//! assert!(report.passed());
+//! # }
+//! ```
+//!
+//! This module is only built with the `conformance` feature.
+
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use crate::auth::Authenticator;
+use crate::shared::{
+    ClientHello, ClientMessage, Delimited, ServerMessage, CONTROL_PORT, MAX_FRAME_LENGTH,
+    NETWORK_TIMEOUT, PROTOCOL_VERSION,
+};
+
+/// Server to check, and the credentials to check it with.
+#[derive(Debug, Clone)]
+pub struct Target {
+    host: String,
+    port: u16,
+    secret: Option<String>,
+}
+
+/// Outcome of one check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The server behaved as the protocol requires.
+    Passed,
+
+    /// The server did not, for this reason.
+    Failed(String),
+
+    /// The check does not apply to this server.
+    Skipped(&'static str),
+}
+
+/// Results of all checks against a server.
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// Name and outcome of each check, in the order they ran.
+    pub checks: Vec<(&'static str, Outcome)>,
+}
+
+impl Report {
+    /// Whether no check failed.
+    pub fn passed(&self) -> bool {
+        !self
+            .checks
+            .iter()
+            .any(|(_, outcome)| matches!(outcome, Outcome::Failed(_)))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, outcome) in &self.checks {
+            match outcome {
+                Outcome::Passed => writeln!(f, "pass  {name}")?,
+                Outcome::Failed(reason) => writeln!(f, "FAIL  {name}: {reason}")?,
+                Outcome::Skipped(reason) => writeln!(f, "skip  {name}: {reason}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Target {
+    /// Check the server on a host, at the usual control port.
+    pub fn new(host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            port: CONTROL_PORT,
+            secret: None,
+        }
+    }
+
+    /// Connect to another control port.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Authenticate with a secret, which also enables the checks of
+    /// authentication failures.
+    pub fn with_secret(mut self, secret: &str) -> Self {
+        self.secret = Some(secret.to_string());
+        self
+    }
+
+    /// Run every check, one after another.
+    pub async fn run(&self) -> Report {
+        let mut checks = Vec::new();
+        checks.push(("original hello", outcome(self.original_hello()).await));
+        checks.push(("extended hello", outcome(self.extended_hello()).await));
+        checks.push(("newer client version", outcome(self.newer_version()).await));
+        checks.push(("bad secret", self.bad_secret().await));
+        checks.push(("oversized frame", outcome(self.oversized_frame()).await));
+        checks.push(("malformed message", outcome(self.malformed_message()).await));
+        checks.push((
+            "unknown connection",
+            outcome(self.unknown_connection()).await,
+        ));
+        checks.push(("duplicate accept", outcome(self.duplicate_accept()).await));
+        Report { checks }
+    }
+
+    /// Connect to the control port and authenticate, if there is a secret.
+    async fn connect(&self) -> Result<Delimited<TcpStream>> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("could not connect to {}:{}", self.host, self.port))?;
+        let mut stream = Delimited::new(stream);
+        if let Some(secret) = &self.secret {
+            Authenticator::new(secret)
+                .client_handshake(&mut stream)
+                .await?;
+        }
+        Ok(stream)
+    }
+
+    /// Open a tunnel with a hello, returning the control connection and port.
+    async fn open(&self, message: ClientMessage) -> Result<(Delimited<TcpStream>, u16)> {
+        let mut stream = self.connect().await?;
+        stream.send(message).await?;
+        let port = match stream.recv_timeout().await? {
+            Some(ServerMessage::Hello(port)) => port,
+            Some(ServerMessage::HelloExt(hello)) => hello.port,
+            other => bail!("expected a hello, got {other:?}"),
+        };
+        ensure!(port != 0, "server assigned port 0");
+        Ok((stream, port))
+    }
+
+    async fn original_hello(&self) -> Result<()> {
+        let (_stream, port) = self.open(ClientMessage::Hello(0)).await?;
+        TcpStream::connect((self.host.as_str(), port))
+            .await
+            .with_context(|| format!("tunnel on port {port} is not reachable"))?;
+        Ok(())
+    }
+
+    async fn extended_hello(&self) -> Result<()> {
+        let mut stream = self.connect().await?;
+        let hello = ClientHello {
+            version: PROTOCOL_VERSION,
+            ..Default::default()
+        };
+        stream.send(ClientMessage::HelloExt(hello)).await?;
+        match stream.recv_timeout().await? {
+            Some(ServerMessage::HelloExt(reply)) => {
+                ensure!(
+                    reply.version <= PROTOCOL_VERSION,
+                    "server answered with version {}, above the client's",
+                    reply.version
+                );
+                ensure!(!reply.compression, "server enabled compression unasked");
+                Ok(())
+            }
+            other => bail!("expected an extended hello, got {other:?}"),
+        }
+    }
+
+    async fn newer_version(&self) -> Result<()> {
+        let mut stream = self.connect().await?;
+        let hello = ClientHello {
+            version: u32::MAX,
+            ..Default::default()
+        };
+        stream.send(ClientMessage::HelloExt(hello)).await?;
+        match stream.recv_timeout().await? {
+            Some(ServerMessage::HelloExt(reply)) if reply.version < u32::MAX => Ok(()),
+            Some(ServerMessage::HelloExt(_)) => bail!("server claimed to speak a future version"),
+            other => bail!("expected an extended hello, got {other:?}"),
+        }
+    }
+
+    async fn bad_secret(&self) -> Outcome {
+        let Some(secret) = &self.secret else {
+            return Outcome::Skipped("no secret was given");
+        };
+        let check = async {
+            let mut stream =
+                Delimited::new(TcpStream::connect((self.host.as_str(), self.port)).await?);
+            let wrong = format!("not {secret}");
+            Authenticator::new(&wrong)
+                .client_handshake(&mut stream)
+                .await?;
+            // The server may reject the secret before the client says anything more.
+            let _ = stream.send(ClientMessage::Hello(0)).await;
+            match timeout(NETWORK_TIMEOUT, stream.recv::<ServerMessage>()).await {
+                Ok(Ok(Some(ServerMessage::Hello(_) | ServerMessage::HelloExt(_)))) => {
+                    bail!("server opened a tunnel for a wrong secret")
+                }
+                Ok(_) => Ok(()),
+                Err(_) => bail!("server neither rejected the client nor closed the connection"),
+            }
+        };
+        outcome(check).await
+    }
+
+    async fn oversized_frame(&self) -> Result<()> {
+        let mut stream = self.connect().await?.into_parts().io;
+        let frame = vec![b'x'; 64 * MAX_FRAME_LENGTH];
+        // The server may close the connection before all of it is written.
+        let _ = stream.write_all(&frame).await;
+        // It may also say why first, in an error that the client can read.
+        let mut stream = Delimited::new(stream);
+        let deadline = NETWORK_TIMEOUT + Duration::from_secs(1);
+        let mut explained = false;
+        loop {
+            match timeout(deadline, stream.recv::<ServerMessage>()).await {
+                Ok(Ok(Some(ServerMessage::Error(_)))) if !explained => explained = true,
+                Ok(Ok(None) | Err(_)) => return Ok(()),
+                Ok(Ok(Some(other))) => {
+                    bail!("server sent {other:?} instead of closing the connection")
+                }
+                Err(_) => bail!("server did not close the connection"),
+            }
+        }
+    }
+
+    async fn malformed_message(&self) -> Result<()> {
+        let mut stream = self.connect().await?.into_parts().io;
+        stream.write_all(b"{\"Hello\": \"nope\"}\0").await?;
+        expect_closed(stream).await
+    }
+
+    async fn unknown_connection(&self) -> Result<()> {
+        let mut stream = self.connect().await?;
+        stream.send(ClientMessage::Accept(Uuid::new_v4())).await?;
+        expect_closed(stream.into_parts().io).await
+    }
+
+    async fn duplicate_accept(&self) -> Result<()> {
+        let (mut control, port) = self.open(ClientMessage::Hello(0)).await?;
+        let mut visitor = TcpStream::connect((self.host.as_str(), port)).await?;
+        let id = loop {
+            match control.recv_timeout().await? {
+                Some(ServerMessage::Heartbeat) => continue,
+                Some(ServerMessage::Connection(id)) => break id,
+                other => bail!("expected a connection, got {other:?}"),
+            }
+        };
+        let mut first = self.connect().await?;
+        first.send(ClientMessage::Accept(id)).await?;
+        let mut first = first.into_parts().io;
+        visitor.write_all(b"conformance").await?;
+        let mut buf = [0; 11];
+        timeout(NETWORK_TIMEOUT, first.read_exact(&mut buf))
+            .await
+            .context("first data connection was not forwarded")??;
+        ensure!(&buf == b"conformance", "forwarded data was altered");
+
+        let mut second = self.connect().await?;
+        second.send(ClientMessage::Accept(id)).await?;
+        expect_closed(second.into_parts().io).await
+    }
+}
+
+/// Run a check, turning its error into the reason that it failed.
+async fn outcome(check: impl Future<Output = Result<()>>) -> Outcome {
+    match check.await {
+        Ok(()) => Outcome::Passed,
+        Err(err) => Outcome::Failed(format!("{err:#}")),
+    }
+}
+
+/// Check that the server closes a connection without sending anything more.
+async fn expect_closed(mut stream: TcpStream) -> Result<()> {
+    let mut buf = [0; 1024];
+    let deadline = NETWORK_TIMEOUT + Duration::from_secs(1);
+    match timeout(deadline, stream.read(&mut buf)).await {
+        Ok(Ok(0) | Err(_)) => Ok(()),
+        Ok(Ok(n)) => bail!(
+            "server sent {:?} instead of closing the connection",
+            String::from_utf8_lossy(&buf[..n])
+        ),
+        Err(_) => bail!("server did not close the connection"),
+    }
+}
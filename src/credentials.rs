@@ -0,0 +1,159 @@
+//! API keys stored by `bore login`, for client commands to pick up.
+//!
+//! Keys are kept per server in a TOML file, at
+//! `~/.config/bore/credentials` unless `$XDG_CONFIG_HOME` says otherwise, and
+//! readable only by the user. With the `keyring` feature, a key can instead
+//! live in the OS keyring, and the file only records that it is there:
+//!
+//! ```toml
+//! [servers."bore.example.com"]
+//! api_key = "..."
+//!
+//! [servers."tunnels.acme.dev"]
+//! keyring = true
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Name of the service that keys are stored under in the OS keyring.
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "bore";
+
+/// Path of the credentials file of the current user.
+pub fn default_path() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir).join("bore").join("credentials"));
+    }
+    #[cfg(windows)]
+    let dir = PathBuf::from(std::env::var_os("APPDATA").context("%APPDATA% is not set")?);
+    #[cfg(not(windows))]
+    let dir = PathBuf::from(std::env::var_os("HOME").context("$HOME is not set")?).join(".config");
+    Ok(dir.join("bore").join("credentials"))
+}
+
+/// Stored credentials for one server.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    /// The API key, unless it is in the keyring.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+
+    /// Whether the API key is in the OS keyring.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    keyring: bool,
+}
+
+/// Contents of a credentials file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    servers: BTreeMap<String, Entry>,
+}
+
+/// Credentials file, with the API keys of each server.
+#[derive(Debug)]
+pub struct Credentials {
+    path: PathBuf,
+    file: File,
+}
+
+impl Credentials {
+    /// Read the credentials file at a path, which is empty if it does not
+    /// exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)
+                .with_context(|| format!("invalid credentials file {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => File::default(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not read {}", path.display()))
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// API key stored for a server, if any.
+    pub fn api_key(&self, server: &str) -> Result<Option<String>> {
+        let Some(entry) = self.file.servers.get(server) else {
+            return Ok(None);
+        };
+        if entry.keyring {
+            return keyring_get(server).map(Some);
+        }
+        Ok(entry.api_key.clone())
+    }
+
+    /// Store the API key for a server, in the OS keyring or in the file.
+    /// The change is kept in memory until [`Credentials::save`].
+    pub fn store(&mut self, server: &str, api_key: &str, keyring: bool) -> Result<()> {
+        let entry = match keyring {
+            true => {
+                keyring_set(server, api_key)?;
+                Entry {
+                    api_key: None,
+                    keyring: true,
+                }
+            }
+            false => Entry {
+                api_key: Some(api_key.into()),
+                keyring: false,
+            },
+        };
+        self.file.servers.insert(server.into(), entry);
+        Ok(())
+    }
+
+    /// Write the credentials back to their file, readable only by the user.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("could not create {}", dir.display()))?;
+        }
+        let text = toml::to_string(&self.file)?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&self.path)
+            .with_context(|| format!("could not write {}", self.path.display()))?;
+        std::io::Write::write_all(&mut file, text.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_get(server: &str) -> Result<String> {
+    keyring::Entry::new(KEYRING_SERVICE, server)
+        .and_then(|entry| entry.get_password())
+        .with_context(|| format!("could not read the API key for {server} from the keyring"))
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_set(server: &str, api_key: &str) -> Result<()> {
+    keyring::Entry::new(KEYRING_SERVICE, server)
+        .and_then(|entry| entry.set_password(api_key))
+        .with_context(|| format!("could not store the API key for {server} in the keyring"))
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_get(server: &str) -> Result<String> {
+    anyhow::bail!(
+        "the API key for {server} is in the keyring, but bore was built without keyring support"
+    )
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_set(_server: &str, _api_key: &str) -> Result<()> {
+    anyhow::bail!("bore was built without keyring support")
+}
@@ -0,0 +1,524 @@
+//! Local HTTP API for opening tunnels on demand from other programs.
+//!
+//! A long-running `bore daemon` holds the credentials for a server, and local
+//! programs such as test frameworks ask it to expose a port they just bound:
+//!
+//! ```text
+//! POST   /tunnels       {"local_port": 8000}  ->  {"id": ..., "remote_port": ...}
+//! GET    /tunnels                             ->  [{"id": ..., ...}]
+//! DELETE /tunnels/<id>                        ->  204 No Content
+//! POST   /shutdown                            ->  202 Accepted
+//! ```
+//!
+//! Tunnels that close on their own, such as when the server goes away, stay
+//! listed as closed with their last error until they are deleted.
+//!
+//! Web pages can reach loopback addresses too, so every request must carry
+//! the daemon's token as `Authorization: Bearer <token>`. The daemon writes
+//! it to a file that only the user can read, from which local programs and
+//! `bore status` pick it up. Requests must also name a loopback host, which
+//! rules out DNS rebinding, and send JSON with `POST` and `DELETE`.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fs;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use dashmap::DashMap;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::client::{Client, ClientOptions};
+use crate::config::TunnelConfig;
+use crate::credentials;
+use crate::shared::host_port;
+use crate::stats::TunnelStats;
+
+/// Maximum size of a request body accepted by the API.
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+
+/// Whether a tunnel managed by the daemon is still open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelState {
+    /// The tunnel is forwarding connections.
+    Open,
+
+    /// The tunnel has closed, and is kept to report its last error.
+    Closed,
+}
+
+/// Description of a tunnel managed by the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    /// Identifier used to close the tunnel.
+    pub id: Uuid,
+
+    /// Name of the tunnel, if it has one.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Labels of the tunnel, from its configuration.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+
+    /// Local host that is forwarded.
+    pub local_host: String,
+
+    /// Local port that is forwarded.
+    pub local_port: u16,
+
+    /// Port that is publicly available on the remote.
+    pub remote_port: u16,
+
+    /// Public address of the tunnel, as `host:port`.
+    pub remote: String,
+
+    /// Whether the tunnel is still open.
+    pub state: TunnelState,
+
+    /// Seconds that the tunnel has been, or was, open.
+    pub uptime_secs: u64,
+
+    /// Number of connections that have been forwarded.
+    pub connections: u64,
+
+    /// Bytes sent by visitors to the local service.
+    pub bytes_in: u64,
+
+    /// Bytes sent by the local service to visitors.
+    pub bytes_out: u64,
+
+    /// Most recent error of the tunnel or one of its connections.
+    pub last_error: Option<String>,
+
+    /// Address of the most recent visitor, if the server forwards them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_visitor: Option<SocketAddr>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExposeRequest {
+    local_port: u16,
+    #[serde(default = "default_local_host")]
+    local_host: String,
+    #[serde(default)]
+    port: u16,
+}
+
+fn default_local_host() -> String {
+    "localhost".into()
+}
+
+struct DaemonTunnel {
+    info: TunnelInfo,
+    stats: Arc<TunnelStats>,
+
+    /// How long the tunnel was open, once it has closed.
+    closed_after: Arc<OnceLock<Duration>>,
+    task: JoinHandle<()>,
+}
+
+impl DaemonTunnel {
+    /// Describe the tunnel with up-to-date statistics.
+    fn info(&self) -> TunnelInfo {
+        let (state, uptime) = match self.closed_after.get() {
+            Some(uptime) => (TunnelState::Closed, *uptime),
+            None => (TunnelState::Open, self.stats.uptime()),
+        };
+        TunnelInfo {
+            state,
+            uptime_secs: uptime.as_secs(),
+            connections: self.stats.connections(),
+            bytes_in: self.stats.inbound(),
+            bytes_out: self.stats.outbound(),
+            last_error: self.stats.last_error(),
+            last_visitor: self.stats.last_visitor(),
+            ..self.info.clone()
+        }
+    }
+}
+
+/// A client daemon that opens tunnels when asked through its local API.
+pub struct Daemon {
+    /// Destination address of the server.
+    to: String,
+
+    /// Options shared by every tunnel, with the port chosen per request.
+    options: ClientOptions,
+
+    /// Tunnels that are currently open.
+    tunnels: Arc<DashMap<Uuid, DaemonTunnel>>,
+
+    /// Notified when the daemon is asked to shut down.
+    shutdown: Arc<Notify>,
+
+    /// Bearer token that requests to the local API must carry.
+    token: String,
+}
+impl Daemon {
+    /// Create a daemon for the given server, with a new random token.
+    pub fn new(to: &str, options: ClientOptions) -> Self {
+        let mut token = [0; 32];
+        getrandom::getrandom(&mut token).expect("failed to generate daemon token");
+        Daemon {
+            to: to.to_string(),
+            options,
+            tunnels: Arc::new(DashMap::new()),
+            shutdown: Arc::new(Notify::new()),
+            token: hex::encode(token),
+        }
+    }
+
+    /// Token that requests to the local API must carry.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Write the token to a file that only the user can read.
+    pub fn save_token(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("could not create {}", dir.display()))?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("could not write {}", path.display()))?;
+        file.write_all(self.token.as_bytes())?;
+        Ok(())
+    }
+
+    /// Open a new tunnel to a local port, returning its public endpoint.
+    pub async fn expose(&self, local_host: &str, local_port: u16, port: u16) -> Result<TunnelInfo> {
+        let options = ClientOptions {
+            port,
+            ..self.options.clone()
+        };
+        self.expose_with_options(local_host, local_port, options)
+            .await
+    }
+
+    /// Open a new tunnel to a local port with its own options, such as a
+    /// name or protocol, instead of the daemon's.
+    pub async fn expose_with_options(
+        &self,
+        local_host: &str,
+        local_port: u16,
+        options: ClientOptions,
+    ) -> Result<TunnelInfo> {
+        self.open(local_host, local_port, options, BTreeMap::new())
+            .await
+    }
+
+    /// Open a tunnel defined in a configuration file, with the shared options
+    /// of the file.
+    pub async fn expose_tunnel(
+        &self,
+        tunnel: &TunnelConfig,
+        shared: &ClientOptions,
+    ) -> Result<TunnelInfo> {
+        let options = tunnel.options(shared);
+        let labels = tunnel.labels.clone();
+        self.open(&tunnel.local_host, tunnel.local_port, options, labels)
+            .await
+    }
+
+    async fn open(
+        &self,
+        local_host: &str,
+        local_port: u16,
+        options: ClientOptions,
+        labels: BTreeMap<String, String>,
+    ) -> Result<TunnelInfo> {
+        let name = options.name.clone();
+        let client = Client::with_options(local_host, local_port, &self.to, options).await?;
+        let id = Uuid::new_v4();
+        let info = TunnelInfo {
+            id,
+            name,
+            labels,
+            local_host: local_host.to_string(),
+            local_port,
+            remote_port: client.remote_port(),
+            remote: host_port(&self.to, client.remote_port()),
+            state: TunnelState::Open,
+            uptime_secs: 0,
+            connections: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            last_error: None,
+            last_visitor: None,
+        };
+
+        let stats = client.stats();
+        let closed_after = Arc::new(OnceLock::new());
+        let task = tokio::spawn({
+            let stats = Arc::clone(&stats);
+            let closed_after = Arc::clone(&closed_after);
+            async move {
+                match client.listen().await {
+                    Ok(()) => stats.set_error("server closed the connection"),
+                    Err(err) => {
+                        warn!(%err, "tunnel exited with error");
+                        stats.set_error(format!("{err:#}"));
+                    }
+                }
+                closed_after.set(stats.uptime()).ok();
+            }
+            .instrument(info_span!("tunnel", %id))
+        });
+        self.tunnels.insert(
+            id,
+            DaemonTunnel {
+                info: info.clone(),
+                stats,
+                closed_after,
+                task,
+            },
+        );
+        info!(%id, remote = %info.remote, "exposed local port {local_port}");
+        Ok(info)
+    }
+
+    /// Close a tunnel, returning whether it existed.
+    pub fn close(&self, id: &Uuid) -> bool {
+        match self.tunnels.remove(id) {
+            Some((_, tunnel)) => {
+                tunnel.task.abort();
+                info!(%id, "closed tunnel");
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close every tunnel and stop serving the local API.
+    pub fn shutdown(&self) {
+        let ids: Vec<Uuid> = self.tunnels.iter().map(|entry| *entry.key()).collect();
+        for id in &ids {
+            self.close(id);
+        }
+        self.shutdown.notify_one();
+    }
+
+    /// List the tunnels, with their current statistics.
+    pub fn tunnels(&self) -> Vec<TunnelInfo> {
+        let mut tunnels: Vec<_> = self.tunnels.iter().map(|entry| entry.info()).collect();
+        tunnels.sort_by_key(|info| std::cmp::Reverse(info.uptime_secs));
+        tunnels
+    }
+
+    /// Serve the local API on the given address until an error occurs, or
+    /// the daemon is shut down.
+    pub async fn listen(self, addr: SocketAddr) -> Result<()> {
+        let shutdown = Arc::clone(&self.shutdown);
+        let this = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let this = Arc::clone(&this);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let this = Arc::clone(&this);
+                    async move { Ok::<_, Infallible>(this.handle(req).await) }
+                }))
+            }
+        });
+        let server = hyper::Server::try_bind(&addr)?
+            .serve(make_service)
+            .with_graceful_shutdown(async move { shutdown.notified().await });
+        info!(%addr, "daemon api listening");
+        server.await?;
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if !loopback_host(&req) {
+            return error_response(StatusCode::FORBIDDEN, "host is not a loopback address");
+        }
+        let authorization = req.headers().get("Authorization");
+        let token = authorization.and_then(|value| value.to_str().ok()?.strip_prefix("Bearer "));
+        if !token.is_some_and(|token| equal_secret(token, &self.token)) {
+            return error_response(StatusCode::UNAUTHORIZED, "missing or invalid token");
+        }
+        if !json_content(&req) {
+            return error_response(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "expected application/json",
+            );
+        }
+        let path = req.uri().path().trim_end_matches('/').to_string();
+        match (req.method(), path.as_str()) {
+            (&Method::GET, "/tunnels") => json_response(StatusCode::OK, &self.tunnels()),
+            (&Method::POST, "/tunnels") => {
+                let body = match read_body(req).await {
+                    Ok(body) => body,
+                    Err(response) => return response,
+                };
+                let request: ExposeRequest = match serde_json::from_slice(&body) {
+                    Ok(request) => request,
+                    Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+                };
+                match self
+                    .expose(&request.local_host, request.local_port, request.port)
+                    .await
+                {
+                    Ok(info) => json_response(StatusCode::CREATED, &info),
+                    Err(err) => error_response(StatusCode::BAD_GATEWAY, err),
+                }
+            }
+            (&Method::DELETE, path) if path.starts_with("/tunnels/") => {
+                match path["/tunnels/".len()..].parse() {
+                    Ok(id) if self.close(&id) => Response::builder()
+                        .status(StatusCode::NO_CONTENT)
+                        .body(Body::empty())
+                        .unwrap(),
+                    _ => error_response(StatusCode::NOT_FOUND, "no such tunnel"),
+                }
+            }
+            (&Method::POST, "/shutdown") => {
+                info!("shutting down on request");
+                self.shutdown();
+                Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+/// Path of the token file of a daemon serving its API on an address, next
+/// to the credentials of the current user.
+pub fn token_path(addr: SocketAddr) -> Result<PathBuf> {
+    let path = credentials::default_path()?;
+    Ok(path.with_file_name(format!("daemon-{}.token", addr.port())))
+}
+
+/// Read the token of a daemon from its file.
+pub fn read_token(path: &Path) -> Result<String> {
+    let token = fs::read_to_string(path)
+        .with_context(|| format!("could not read the daemon token from {}", path.display()))?;
+    Ok(token.trim().to_string())
+}
+
+/// Ask a running daemon for its tunnels.
+pub async fn status(addr: SocketAddr, token: &str) -> Result<Vec<TunnelInfo>> {
+    let url = format!("http://{addr}/tunnels");
+    let response = reqwest::Client::new()
+        .get(&url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .with_context(|| format!("could not reach the daemon at {addr}, is it running?"))?;
+    ensure!(
+        response.status().is_success(),
+        "daemon returned {}",
+        response.status()
+    );
+    Ok(response.json().await?)
+}
+
+/// Ask a running daemon to close its tunnels and exit.
+pub async fn shutdown(addr: SocketAddr, token: &str) -> Result<()> {
+    let url = format!("http://{addr}/shutdown");
+    let response = reqwest::Client::new()
+        .post(&url)
+        .bearer_auth(token)
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .with_context(|| format!("could not reach the daemon at {addr}, is it running?"))?;
+    ensure!(
+        response.status().is_success(),
+        "daemon returned {}",
+        response.status()
+    );
+    Ok(())
+}
+
+/// Name or address in the `Host` header of a request, without brackets, and
+/// its port, if any.
+pub(crate) fn request_host(req: &Request<Body>) -> Option<(&str, Option<u16>)> {
+    let host = req.headers().get("Host")?.to_str().ok()?;
+    let (host, port) = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => {
+            (name, Some(port.parse().ok()?))
+        }
+        _ => (host, None),
+    };
+    Some((host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+/// Whether a request names a loopback address or `localhost` as its host,
+/// which a page that rebinds its own domain to a loopback address cannot.
+pub(crate) fn loopback_host(req: &Request<Body>) -> bool {
+    request_host(req).is_some_and(|(host, _)| {
+        host.eq_ignore_ascii_case("localhost")
+            || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    })
+}
+
+/// Whether a request that changes something sends JSON, which pages can
+/// only send after a CORS preflight that they do not pass.
+pub(crate) fn json_content(req: &Request<Body>) -> bool {
+    if !matches!(*req.method(), Method::POST | Method::DELETE) {
+        return true;
+    }
+    let content_type = (req.headers().get("Content-Type"))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim);
+    content_type.is_some_and(|value| value.eq_ignore_ascii_case("application/json"))
+}
+
+/// Compare secrets in time that does not depend on where they differ.
+fn equal_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+pub(crate) async fn read_body(req: Request<Body>) -> Result<Vec<u8>, Response<Body>> {
+    let mut body = req.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+        let chunk = chunk.map_err(|err| error_response(StatusCode::BAD_REQUEST, err))?;
+        if bytes.len() + chunk.len() > MAX_BODY_LENGTH {
+            return Err(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request body too large",
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+pub(crate) fn error_response(status: StatusCode, err: impl std::fmt::Display) -> Response<Body> {
+    json_response(status, &serde_json::json!({ "error": err.to_string() }))
+}
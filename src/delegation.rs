@@ -0,0 +1,151 @@
+//! Delegated sub-keys, for sharing scoped access to a server.
+//!
+//! An authenticated client can ask the server to mint a sub-key that expires
+//! after a while and may only open tunnels on a range of ports. Sub-keys are
+//! signed with the server's identity key, so the server checks them without
+//! keeping any state, and teammates use them in place of an API key.
+//!
+//! Sub-keys with the observe scope cannot open tunnels, but can watch the
+//! tunnels that are open on their ports, for monitoring by people who should
+//! not be able to carry traffic.
+
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::identity::ServerIdentity;
+use crate::shared::{AuthError, AuthErrorCode, Scope, SubKeyRequest};
+
+/// Prefix that distinguishes sub-keys from other credentials.
+pub const SUB_KEY_PREFIX: &str = "bore_sub_";
+
+/// Longest lifetime that a sub-key can be minted with.
+pub const MAX_SUB_KEY_TTL: Duration = Duration::from_secs(30 * 86400);
+
+/// Prefix of the payloads that sub-key signatures cover, which sets them
+/// apart from other signatures of the identity key.
+const SIGNATURE_CONTEXT: &[u8] = b"bore sub-key v1\0";
+
+/// Permissions carried by a sub-key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubKeyClaims {
+    /// Unique identifier of the sub-key, for logging.
+    pub id: Uuid,
+
+    /// Unix timestamp in seconds after which the sub-key is invalid.
+    pub expires: u64,
+
+    /// Lowest remote port that the sub-key may open.
+    pub min_port: u16,
+
+    /// Highest remote port that the sub-key may open.
+    pub max_port: u16,
+
+    /// What the sub-key allows on those ports. Sub-keys minted before scopes
+    /// existed may only open tunnels.
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+impl SubKeyClaims {
+    /// Range of remote ports that the sub-key may open.
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.min_port..=self.max_port
+    }
+
+    /// Time left until the sub-key expires.
+    pub fn remaining(&self) -> Duration {
+        let expires = UNIX_EPOCH + Duration::from_secs(self.expires);
+        expires
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Mints and checks sub-keys on behalf of the server.
+pub struct SubKeyIssuer {
+    identity: Arc<ServerIdentity>,
+}
+
+impl SubKeyIssuer {
+    /// Create an issuer that signs sub-keys with the server's identity.
+    pub fn new(identity: Arc<ServerIdentity>) -> Self {
+        Self { identity }
+    }
+
+    /// Mint a new sub-key, returning it along with its claims.
+    pub fn mint(&self, request: &SubKeyRequest) -> Result<(String, SubKeyClaims)> {
+        ensure!(
+            request.min_port <= request.max_port,
+            "sub-key port range is empty"
+        );
+        let ttl = Duration::from_secs(request.ttl_secs).min(MAX_SUB_KEY_TTL);
+        let expires = (SystemTime::now() + ttl).duration_since(UNIX_EPOCH)?;
+        let claims = SubKeyClaims {
+            id: Uuid::new_v4(),
+            expires: expires.as_secs(),
+            min_port: request.min_port,
+            max_port: request.max_port,
+            scope: request.scope,
+        };
+        let payload = BASE64.encode(serde_json::to_vec(&claims)?);
+        let signature = BASE64.encode(self.identity.sign(SIGNATURE_CONTEXT, payload.as_bytes()));
+        Ok((format!("{SUB_KEY_PREFIX}{payload}.{signature}"), claims))
+    }
+
+    /// Check the signature and expiry of a sub-key, returning its claims.
+    pub fn verify(&self, key: &str) -> Result<SubKeyClaims, AuthError> {
+        let invalid = || AuthError::new(AuthErrorCode::InvalidApiKey, "invalid sub-key");
+        let (payload, signature) = key
+            .strip_prefix(SUB_KEY_PREFIX)
+            .and_then(|key| key.split_once('.'))
+            .ok_or_else(invalid)?;
+        let signature: [u8; 64] = BASE64
+            .decode(signature)
+            .ok()
+            .and_then(|signature| signature.try_into().ok())
+            .ok_or_else(invalid)?;
+        if !self
+            .identity
+            .verify(SIGNATURE_CONTEXT, payload.as_bytes(), &signature)
+        {
+            return Err(invalid());
+        }
+        let claims: SubKeyClaims = BASE64
+            .decode(payload)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or_else(invalid)?;
+        if claims.remaining().is_zero() {
+            return Err(AuthError::new(
+                AuthErrorCode::InvalidApiKey,
+                "sub-key has expired",
+            ));
+        }
+        Ok(claims)
+    }
+
+    /// As the server, check a credential that may be a sub-key.
+    ///
+    /// Returns the claims of a valid sub-key, or `None` for other credentials.
+    pub fn check(
+        issuer: Option<&Self>,
+        credential: &str,
+    ) -> Result<Option<SubKeyClaims>, AuthError> {
+        if !credential.starts_with(SUB_KEY_PREFIX) {
+            return Ok(None);
+        }
+        match issuer {
+            Some(issuer) => issuer.verify(credential).map(Some),
+            None => Err(AuthError::new(
+                AuthErrorCode::MethodNotSupported,
+                "server does not accept sub-keys",
+            )),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Diagnosis of the network path to a server, for `bore doctor`.
+//!
+//! Some networks silently drop packets above a certain size, often because a
+//! VPN or tunnel lowers the MTU without path MTU discovery working. Small
+//! messages then get through while large transfers stall, which looks like a
+//! hung tunnel. The doctor opens a tunnel to a local echo service and sends
+//! payloads of increasing size through it to find out, and retries with a
+//! clamped segment size to check whether that works around the problem.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::client::{connect_clamped, Client, ClientOptions};
+use crate::shared::{host_port, unbracket};
+
+/// Segment size to retry with when large transfers stall, which fits inside
+/// the MTU of common VPNs and tunnels.
+pub const FALLBACK_SEGMENT_SIZE: u16 = 1200;
+
+/// How long a payload may take to come back through the tunnel.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of the payload that fits in a single packet on any path.
+const SMALL_PROBE: usize = 512;
+
+/// Size of the payload that needs many full-sized packets.
+const LARGE_PROBE: usize = 256 * 1024;
+
+/// Result of one check.
+#[derive(Debug, Clone)]
+pub struct Check {
+    /// What was checked.
+    pub name: String,
+
+    /// How long the check took if it passed, or why it failed.
+    pub result: Result<Duration, String>,
+}
+
+/// Results of diagnosing the path to a server.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnosis {
+    /// Each check, in the order they ran.
+    pub checks: Vec<Check>,
+
+    /// Segment size that got large transfers through after they stalled, to
+    /// pass as `--max-segment-size`.
+    pub suggested_segment_size: Option<u16>,
+}
+
+impl Diagnosis {
+    /// Whether every check passed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+}
+
+/// Run the checks against a server, connecting with these options.
+pub async fn diagnose(to: &str, mut options: ClientOptions) -> Diagnosis {
+    options.reconnect = false;
+    let mut diagnosis = Diagnosis::default();
+    let large_passed = probe_path(to, options.clone(), &mut diagnosis).await;
+    if large_passed == Some(false) && options.max_segment_size.is_none() {
+        options.max_segment_size = Some(FALLBACK_SEGMENT_SIZE);
+        if probe_path(to, options, &mut diagnosis).await == Some(true) {
+            diagnosis.suggested_segment_size = Some(FALLBACK_SEGMENT_SIZE);
+        }
+    }
+    diagnosis
+}
+
+/// Open a tunnel and send a small and a large payload through it, returning
+/// whether the large one came back, or `None` if it was not sent.
+async fn probe_path(to: &str, options: ClientOptions, diagnosis: &mut Diagnosis) -> Option<bool> {
+    let segment_size = options.max_segment_size;
+    let suffix = match segment_size {
+        Some(size) => format!(" with segments of {size} bytes"),
+        None => String::new(),
+    };
+    let start = Instant::now();
+    let opened = open_echo_tunnel(to, options).await;
+    let (port, tasks) = match record(diagnosis, format!("open tunnel{suffix}"), start, opened) {
+        Some(opened) => opened,
+        None => return None,
+    };
+    let mut large_passed = None;
+    for (name, size) in [("small", SMALL_PROBE), ("large", LARGE_PROBE)] {
+        let start = Instant::now();
+        let sent = echo(to, port, size, segment_size).await;
+        let passed = record(diagnosis, format!("{name} transfer{suffix}"), start, sent).is_some();
+        if size == LARGE_PROBE {
+            large_passed = Some(passed);
+        } else if !passed {
+            break;
+        }
+    }
+    for task in tasks {
+        task.abort();
+    }
+    large_passed
+}
+
+/// Add the outcome of a check to the diagnosis, returning its value if it passed.
+fn record<T>(
+    diagnosis: &mut Diagnosis,
+    name: String,
+    start: Instant,
+    result: Result<T>,
+) -> Option<T> {
+    let (result, value) = match result {
+        Ok(value) => (Ok(start.elapsed()), Some(value)),
+        Err(err) => (Err(format!("{err:#}")), None),
+    };
+    diagnosis.checks.push(Check { name, result });
+    value
+}
+
+/// Open a tunnel to a local service that echoes everything back, returning
+/// the remote port and the tasks serving the tunnel.
+async fn open_echo_tunnel(
+    to: &str,
+    options: ClientOptions,
+) -> Result<(u16, [tokio::task::JoinHandle<()>; 2])> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let echo = tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    let client = match Client::with_options("127.0.0.1", local_port, to, options).await {
+        Ok(client) => client,
+        Err(err) => {
+            echo.abort();
+            return Err(err);
+        }
+    };
+    let port = client.remote_port();
+    let tunnel = tokio::spawn(async move {
+        let _ = client.listen().await;
+    });
+    Ok((port, [echo, tunnel]))
+}
+
+/// Send a payload through the tunnel and check that it comes back intact.
+/// The payload crosses the same network twice, as a visitor and through the
+/// client, so a segment size clamps both.
+async fn echo(to: &str, port: u16, size: usize, segment_size: Option<u16>) -> Result<()> {
+    let payload: Vec<u8> = (0..size).map(|_| fastrand::u8(..)).collect();
+    let roundtrip = async {
+        let stream = match segment_size {
+            Some(size) => connect_clamped(to, port, size).await?,
+            None => TcpStream::connect((unbracket(to), port))
+                .await
+                .with_context(|| format!("could not connect to {}", host_port(to, port)))?,
+        };
+        let (mut reader, mut writer) = stream.into_split();
+        let sent = payload.clone();
+        let send = tokio::spawn(async move { writer.write_all(&sent).await });
+        let mut received = vec![0; size];
+        reader.read_exact(&mut received).await?;
+        send.await??;
+        ensure!(received == payload, "payload came back altered");
+        Ok(())
+    };
+    timeout(PROBE_TIMEOUT, roundtrip)
+        .await
+        .with_context(|| format!("{size} bytes did not come back within {PROBE_TIMEOUT:?}"))?
+}
@@ -0,0 +1,238 @@
+//! Encryption of data connections between the client and server.
+//!
+//! Tunnels that negotiate encryption run a Noise handshake at the start of
+//! every data connection, then carry the proxied bytes in authenticated,
+//! length-prefixed frames. The handshake is anonymous, so it protects against
+//! eavesdroppers on the path but not against an active attacker; use TLS on the
+//! control port with a trusted certificate to rule out the latter.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use anyhow::{bail, Context as _, Result};
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::timeout;
+use tokio_util::codec::{AnyDelimiterCodec, FramedParts};
+use uuid::Uuid;
+
+use crate::shared::NETWORK_TIMEOUT;
+
+/// Noise protocol used for the handshake and transport.
+const NOISE_PATTERN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest number of plaintext bytes carried by a single frame.
+const MAX_PLAINTEXT: usize = 16 * 1024;
+
+/// Length of the authentication tag added to each frame.
+const TAG_LENGTH: usize = 16;
+
+/// Data connection that is encrypted, or passes bytes through unchanged.
+pub struct Encrypted<S> {
+    inner: S,
+    transport: Option<TransportState>,
+
+    /// Bytes read from the inner stream that have not been processed yet.
+    received: Vec<u8>,
+
+    /// Decrypted bytes waiting to be read, starting at `consumed`.
+    plaintext: Vec<u8>,
+    consumed: usize,
+
+    /// Encrypted frame waiting to be written, starting at `flushed`.
+    frame: Vec<u8>,
+    flushed: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Encrypted<S> {
+    /// Pass a data connection through without encryption.
+    pub fn plain(parts: FramedParts<S, AnyDelimiterCodec>) -> Self {
+        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
+        Self::new(parts.io, parts.read_buf.to_vec())
+    }
+
+    /// Start the handshake as the client, which opened the data connection.
+    pub async fn initiate(parts: FramedParts<S, AnyDelimiterCodec>, id: Uuid) -> Result<Self> {
+        let handshake = builder(&id)?.build_initiator()?;
+        Self::handshake(parts, handshake, true).await
+    }
+
+    /// Answer the handshake as the server.
+    pub async fn respond(parts: FramedParts<S, AnyDelimiterCodec>, id: Uuid) -> Result<Self> {
+        let handshake = builder(&id)?.build_responder()?;
+        Self::handshake(parts, handshake, false).await
+    }
+
+    fn new(inner: S, received: Vec<u8>) -> Self {
+        Self {
+            inner,
+            transport: None,
+            received,
+            plaintext: Vec::new(),
+            consumed: 0,
+            frame: Vec::new(),
+            flushed: 0,
+        }
+    }
+
+    async fn handshake(
+        parts: FramedParts<S, AnyDelimiterCodec>,
+        mut handshake: HandshakeState,
+        initiator: bool,
+    ) -> Result<Self> {
+        let mut stream = Self::plain(parts);
+        let mut buf = vec![0; u16::MAX as usize];
+        let exchange = async {
+            // The NN pattern has two messages: one from each side.
+            if initiator {
+                let len = handshake.write_message(&[], &mut buf)?;
+                stream.send_frame(&buf[..len]).await?;
+            }
+            let Some(message) = std::future::poll_fn(|cx| stream.poll_frame(cx)).await? else {
+                bail!("data connection closed during encryption handshake");
+            };
+            handshake.read_message(&message, &mut buf)?;
+            if !initiator {
+                let len = handshake.write_message(&[], &mut buf)?;
+                stream.send_frame(&buf[..len]).await?;
+            }
+            Ok(())
+        };
+        timeout(NETWORK_TIMEOUT, exchange)
+            .await
+            .context("timed out during encryption handshake")??;
+        stream.transport = Some(handshake.into_transport_mode()?);
+        Ok(stream)
+    }
+
+    async fn send_frame(&mut self, message: &[u8]) -> io::Result<()> {
+        self.inner
+            .write_all(&(message.len() as u16).to_be_bytes())
+            .await?;
+        self.inner.write_all(message).await
+    }
+
+    /// Read the next length-prefixed frame, or `None` at the end of the stream.
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<Vec<u8>>>> {
+        loop {
+            if self.received.len() >= 2 {
+                let len = u16::from_be_bytes([self.received[0], self.received[1]]) as usize;
+                if self.received.len() >= 2 + len {
+                    let frame = self.received[2..2 + len].to_vec();
+                    self.received.drain(..2 + len);
+                    return Poll::Ready(Ok(Some(frame)));
+                }
+            }
+            let mut chunk = [0; 8192];
+            let mut buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            if buf.filled().is_empty() {
+                if self.received.is_empty() {
+                    return Poll::Ready(Ok(None));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.received.extend_from_slice(buf.filled());
+        }
+    }
+
+    /// Write out the pending encrypted frame.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.flushed < self.frame.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.frame[self.flushed..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.flushed += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Noise handshake builder, bound to the connection that is being proxied.
+fn builder(id: &Uuid) -> Result<Builder<'_>> {
+    Ok(Builder::new(NOISE_PATTERN.parse()?).prologue(id.as_bytes()))
+}
+
+fn invalid_data(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Encrypted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.transport.is_none() {
+            if this.received.is_empty() {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+            let n = this.received.len().min(buf.remaining());
+            buf.put_slice(&this.received[..n]);
+            this.received.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        while this.consumed == this.plaintext.len() {
+            let Some(frame) = ready!(this.poll_frame(cx))? else {
+                return Poll::Ready(Ok(()));
+            };
+            let transport = this.transport.as_mut().unwrap();
+            this.plaintext.resize(frame.len(), 0);
+            let len = transport
+                .read_message(&frame, &mut this.plaintext)
+                .map_err(invalid_data)?;
+            this.plaintext.truncate(len);
+            this.consumed = 0;
+        }
+        let n = (this.plaintext.len() - this.consumed).min(buf.remaining());
+        buf.put_slice(&this.plaintext[this.consumed..this.consumed + n]);
+        this.consumed += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Encrypted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.transport.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_PLAINTEXT);
+        let transport = this.transport.as_mut().unwrap();
+        this.frame.resize(2 + n + TAG_LENGTH, 0);
+        let len = transport
+            .write_message(&buf[..n], &mut this.frame[2..])
+            .map_err(invalid_data)?;
+        this.frame.truncate(2 + len);
+        this.frame[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        this.flushed = 0;
+        // The frame is written out by later calls if the stream is not ready.
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,58 @@
+//! Exit codes of the `bore` command.
+//!
+//! Scripts and apps that wrap the client can tell common failures apart by
+//! the exit code, instead of matching on error messages. These values are
+//! stable across releases.
+
+use crate::shared::{AuthError, LocalUnreachable, ServerUnreachable, TunnelClosed, TunnelRejected};
+
+/// The command finished, or the tunnel was closed on purpose.
+pub const SUCCESS: u8 = 0;
+
+/// A failure that has no more specific code.
+pub const FAILURE: u8 = 1;
+
+/// The command line was invalid.
+pub const USAGE: u8 = 2;
+
+/// The server rejected the client's credentials.
+pub const AUTH_FAILED: u8 = 3;
+
+/// The server's control port could not be reached.
+pub const SERVER_UNREACHABLE: u8 = 4;
+
+/// The server refused to open the tunnel, such as when the port is taken.
+pub const PORT_REJECTED: u8 = 5;
+
+/// The local service never became available.
+pub const LOCAL_UNREACHABLE: u8 = 6;
+
+/// The server closed the tunnel for good, such as after it sat idle.
+pub const TUNNEL_CLOSED: u8 = 7;
+
+/// Pick the exit code for an error that ended the command.
+///
+/// ```
+/// use anyhow::{anyhow, Context};
+/// use bore_cli::exit;
+/// use bore_cli::shared::ServerUnreachable;
+///
+/// let err = Err::<(), _>(anyhow!("connection refused")).context(ServerUnreachable);
+/// assert_eq!(exit::for_error(&err.unwrap_err()), exit::SERVER_UNREACHABLE);
+/// assert_eq!(exit::for_error(&anyhow!("something else")), exit::FAILURE);
+/// ```
+pub fn for_error(err: &anyhow::Error) -> u8 {
+    if err.downcast_ref::<AuthError>().is_some() {
+        AUTH_FAILED
+    } else if err.downcast_ref::<ServerUnreachable>().is_some() {
+        SERVER_UNREACHABLE
+    } else if err.downcast_ref::<TunnelRejected>().is_some() {
+        PORT_REJECTED
+    } else if err.downcast_ref::<LocalUnreachable>().is_some() {
+        LOCAL_UNREACHABLE
+    } else if err.downcast_ref::<TunnelClosed>().is_some() {
+        TUNNEL_CLOSED
+    } else {
+        FAILURE
+    }
+}
@@ -0,0 +1,190 @@
+//! Framing of the messages in a stream of HTTP/1 requests or responses.
+//!
+//! A connection can carry many messages one after another, so anything that
+//! reads more than the first has to follow the framing of each body to find
+//! the head of the next. Rewriting request heads and inspecting exchanges
+//! both step through bodies this way.
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::http::RequestHead;
+
+/// Longest line of a chunked body, other than its data.
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Part of a stream of messages that is being read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    /// The head of a message.
+    Head,
+
+    /// A body with this many bytes left.
+    Body(u64),
+
+    /// The line with the size of the next chunk of a chunked body.
+    ChunkSize,
+
+    /// A chunk with this many bytes left, including its line ending.
+    ChunkData(u64),
+
+    /// The trailers after the last chunk.
+    Trailers,
+
+    /// A body that lasts until the connection closes.
+    UntilClose,
+
+    /// Anything after a change of protocol.
+    Raw,
+}
+
+/// Bytes at the start of the input that a step through a body covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Step {
+    /// Number of bytes that the step covers.
+    pub length: usize,
+
+    /// Number of those bytes, from their start, that are data of the body.
+    pub data: usize,
+}
+
+impl Framing {
+    /// What follows the head of a request.
+    ///
+    /// Requests whose end is ambiguous are errors, as a request could hide
+    /// in the body of another and skip what is done to every head.
+    pub(crate) fn request(head: &RequestHead) -> Result<Self> {
+        if head.method.eq_ignore_ascii_case("CONNECT") || head.header("upgrade").is_some() {
+            return Ok(Self::Raw);
+        }
+        Self::body(&head.headers, Self::Head, true)
+    }
+
+    /// What follows the head of a response with a status and headers, to a
+    /// `HEAD` request or not.
+    pub(crate) fn response(
+        status: u16,
+        headers: &[(String, String)],
+        head_request: bool,
+    ) -> Result<Self> {
+        if status == 101 {
+            return Ok(Self::Raw);
+        }
+        if head_request || status < 200 || status == 204 || status == 304 {
+            return Ok(Self::Head);
+        }
+        Self::body(headers, Self::UntilClose, false)
+    }
+
+    /// How a body is framed by the headers of its message, or as `otherwise`
+    /// without either header.
+    fn body(headers: &[(String, String)], otherwise: Self, request: bool) -> Result<Self> {
+        let codings: Vec<_> = values(headers, "transfer-encoding").collect();
+        let lengths: Vec<_> = values(headers, "content-length").collect();
+        if let Some(last) = codings.last() {
+            let chunked = last.eq_ignore_ascii_case("chunked");
+            if request {
+                ensure!(
+                    chunked,
+                    "transfer encoding of request does not end in chunked"
+                );
+                ensure!(
+                    lengths.is_empty(),
+                    "request has a transfer encoding and a length"
+                );
+            }
+            // Without chunks, the body only ends with the connection.
+            return Ok(match chunked {
+                true => Self::ChunkSize,
+                false => Self::UntilClose,
+            });
+        }
+        match lengths[..] {
+            [] => Ok(otherwise),
+            [length] => {
+                ensure!(
+                    !length.is_empty() && length.bytes().all(|b| b.is_ascii_digit()),
+                    "invalid content length"
+                );
+                match length.parse().context("invalid content length")? {
+                    0 => Ok(Self::Head),
+                    length => Ok(Self::Body(length)),
+                }
+            }
+            _ => bail!("more than one content length"),
+        }
+    }
+
+    /// Take the next step through a body, if the input holds all of it, and
+    /// move on to what follows. Once the body ends, the framing is
+    /// [`Framing::Head`] again.
+    ///
+    /// Heads, bodies until close, and changes of protocol take no steps.
+    pub(crate) fn step(&mut self, input: &[u8]) -> Result<Option<Step>> {
+        match *self {
+            Self::Body(remaining) | Self::ChunkData(remaining) => {
+                let n = remaining.min(input.len() as u64);
+                if n == 0 {
+                    return Ok(None);
+                }
+                // Chunks end with a line ending that is not data.
+                let data = match self {
+                    Self::Body(_) => n,
+                    _ => n.min(remaining.saturating_sub(2)),
+                };
+                *self = match (*self, remaining - n) {
+                    (Self::Body(_), 0) => Self::Head,
+                    (Self::Body(_), left) => Self::Body(left),
+                    (_, 0) => Self::ChunkSize,
+                    (_, left) => Self::ChunkData(left),
+                };
+                Ok(Some(Step {
+                    length: n as usize,
+                    data: data as usize,
+                }))
+            }
+            Self::ChunkSize | Self::Trailers => {
+                let Some(end) = input.windows(2).position(|w| w == b"\r\n") else {
+                    ensure!(input.len() <= MAX_LINE_LENGTH, "chunk line is too long");
+                    return Ok(None);
+                };
+                let line = &input[..end];
+                *self = match *self {
+                    Self::ChunkSize => match chunk_size(line)? {
+                        0 => Self::Trailers,
+                        size => Self::ChunkData(size + 2),
+                    },
+                    _ if line.is_empty() => Self::Head,
+                    _ => Self::Trailers,
+                };
+                Ok(Some(Step {
+                    length: end + 2,
+                    data: 0,
+                }))
+            }
+            Self::Head | Self::UntilClose | Self::Raw => Ok(None),
+        }
+    }
+}
+
+/// Values of a header in a list, over all of its lines.
+fn values<'a>(headers: &'a [(String, String)], name: &'a str) -> impl Iterator<Item = &'a str> {
+    (headers.iter())
+        .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// Size of a chunk from its line, which may have extensions after a `;`.
+fn chunk_size(line: &[u8]) -> Result<u64> {
+    let line = std::str::from_utf8(line).context("invalid chunk size")?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+    ensure!(
+        !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit()),
+        "invalid chunk size"
+    );
+    u64::from_str_radix(size, 16)
+        .ok()
+        .filter(|size| *size < u64::MAX - 2)
+        .context("invalid chunk size")
+}
@@ -0,0 +1,97 @@
+//! Countries of visitors, from a MaxMind GeoLite2 database.
+//!
+//! Operators can keep visitors from some countries out of every tunnel, or
+//! only let visitors from some countries in. Countries are looked up in a
+//! GeoLite2 Country or City database, which MaxMind offers for free, and are
+//! also logged and counted in metrics. Lookups need the `geoip` feature.
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{ensure, Result};
+
+/// Database of the countries that IP addresses are in.
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    /// Read a database in the MaxMind format.
+    #[cfg(feature = "geoip")]
+    pub fn open(path: &Path) -> Result<Self> {
+        use anyhow::Context;
+
+        let reader = maxminddb::Reader::open_readfile(path)
+            .with_context(|| format!("could not read GeoIP database {}", path.display()))?;
+        Ok(Self { reader })
+    }
+
+    /// Read a database in the MaxMind format.
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(_path: &Path) -> Result<Self> {
+        anyhow::bail!("bore was built without GeoIP support")
+    }
+
+    /// ISO code of the country that an address is in, if known.
+    #[cfg(feature = "geoip")]
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: maxminddb::geoip2::Country = self.reader.lookup(ip.to_canonical()).ok()?;
+        Some(record.country?.iso_code?.to_string())
+    }
+
+    /// ISO code of the country that an address is in, if known.
+    #[cfg(not(feature = "geoip"))]
+    pub fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+}
+
+/// Countries whose visitors may, or may not, reach tunnels.
+///
+/// ```
+/// use bore_cli::geoip::CountryRules;
+///
+/// let rules = CountryRules::new(vec!["de".into(), "FR".into()], vec![]).unwrap();
+/// assert!(rules.permits(Some("DE")));
+/// assert!(!rules.permits(Some("US")));
+/// assert!(!rules.permits(None));
+///
+/// let rules = CountryRules::new(vec![], vec!["US".into()]).unwrap();
+/// assert!(!rules.permits(Some("US")));
+/// assert!(rules.permits(None));
+///
+/// assert!(CountryRules::new(vec!["Germany".into()], vec![]).is_err());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CountryRules {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl CountryRules {
+    /// Permit only visitors from countries in `allow`, unless it is empty,
+    /// and never those from countries in `deny`, by ISO code. Visitors whose
+    /// country is unknown are only permitted if `allow` is empty. Fails
+    /// unless every code is two letters.
+    pub fn new(allow: Vec<String>, deny: Vec<String>) -> Result<Self> {
+        for code in allow.iter().chain(&deny) {
+            ensure!(
+                code.len() == 2 && code.bytes().all(|b| b.is_ascii_alphabetic()),
+                "{code:?} is not a two-letter ISO country code"
+            );
+        }
+        let upper = |codes: Vec<String>| codes.iter().map(|code| code.to_uppercase()).collect();
+        Ok(Self {
+            allow: upper(allow),
+            deny: upper(deny),
+        })
+    }
+
+    /// Whether a visitor from a country may reach tunnels.
+    pub fn permits(&self, country: Option<&str>) -> bool {
+        let listed =
+            |codes: &[String]| country.is_some_and(|country| codes.iter().any(|c| c == country));
+        (self.allow.is_empty() || listed(&self.allow)) && !listed(&self.deny)
+    }
+}
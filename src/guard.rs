@@ -0,0 +1,193 @@
+//! Defenses of the control port against clients that guess credentials.
+//!
+//! Servers open to the internet see a steady stream of connections trying
+//! secrets and API keys. The guard limits how fast each IP address may
+//! connect, and like fail2ban, bans addresses for a while after repeated
+//! failed authentications. Connections from banned addresses are closed
+//! before the server spends any work on TLS or authentication.
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tracing::{info, warn};
+
+use crate::ratelimit::TokenBucket;
+
+/// Failed authentications after which an address is banned, unless set.
+pub const DEFAULT_BAN_AFTER: u32 = 5;
+
+/// Time that an address stays banned, unless set.
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// Time over which failed authentications of an address are counted.
+const FAILURE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Interval at which expired bans are lifted and idle addresses forgotten.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether a connection from an address may proceed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The connection may proceed.
+    Admit,
+
+    /// The address is banned for this much longer.
+    Banned(Duration),
+
+    /// The address connects faster than its rate limit.
+    RateLimited,
+}
+
+/// Failed authentications of one address.
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+/// Per-address rate limits and bans on the control port.
+///
+/// ```
+/// use std::time::Duration;
+/// use bore_cli::guard::{SourceGuard, Verdict};
+///
+/// let mut guard = SourceGuard::default();
+/// guard.set_auto_ban(2, Duration::from_secs(60));
+/// let addr = "203.0.113.7".parse().unwrap();
+///
+/// guard.record_failure(addr);
+/// assert_eq!(guard.admit(addr), Verdict::Admit);
+/// guard.record_failure(addr);
+/// assert!(matches!(guard.admit(addr), Verdict::Banned(_)));
+/// ```
+#[derive(Default)]
+pub struct SourceGuard {
+    /// Connections per second and burst allowed from each address.
+    rate: Option<(f64, u32)>,
+
+    /// Failed authentications that ban an address, and for how long.
+    ban: Option<(u32, Duration)>,
+
+    buckets: DashMap<IpAddr, TokenBucket>,
+    failures: DashMap<IpAddr, Failures>,
+
+    /// Banned addresses, with when their ban ends.
+    bans: DashMap<IpAddr, Instant>,
+}
+
+impl SourceGuard {
+    /// Limit each address to this many new connections per second, allowing
+    /// bursts up to `burst`.
+    pub fn set_rate(&mut self, per_second: f64, burst: u32) {
+        self.rate = Some((per_second, burst.max(1)));
+    }
+
+    /// Ban addresses for `duration` once they fail to authenticate
+    /// `max_failures` times within a few minutes.
+    pub fn set_auto_ban(&mut self, max_failures: u32, duration: Duration) {
+        self.ban = Some((max_failures.max(1), duration));
+    }
+
+    /// Failed authentications that ban an address, and for how long, if
+    /// addresses are banned at all.
+    pub fn auto_ban(&self) -> Option<(u32, Duration)> {
+        self.ban
+    }
+
+    /// Decide whether a new connection from an address may proceed.
+    pub fn admit(&self, addr: IpAddr) -> Verdict {
+        let now = Instant::now();
+        let ban = self.bans.get(&addr).map(|until| *until);
+        if let Some(until) = ban {
+            match until > now {
+                true => return Verdict::Banned(until - now),
+                false => self.lift(addr, now),
+            }
+        }
+        if let Some((per_second, burst)) = self.rate {
+            let bucket = self
+                .buckets
+                .entry(addr)
+                .or_insert_with(|| TokenBucket::new(per_second, burst as f64));
+            if !bucket.try_acquire(1.0) {
+                return Verdict::RateLimited;
+            }
+        }
+        Verdict::Admit
+    }
+
+    /// Count a failed authentication from an address, banning it once it
+    /// has failed too often.
+    pub fn record_failure(&self, addr: IpAddr) {
+        let Some((max_failures, duration)) = self.ban else {
+            return;
+        };
+        let now = Instant::now();
+        let mut failures = self.failures.entry(addr).or_insert(Failures {
+            count: 0,
+            since: now,
+        });
+        if now.duration_since(failures.since) > FAILURE_WINDOW {
+            *failures = Failures {
+                count: 0,
+                since: now,
+            };
+        }
+        failures.count += 1;
+        if failures.count >= max_failures {
+            let count = failures.count;
+            drop(failures);
+            self.failures.remove(&addr);
+            self.bans.insert(addr, now + duration);
+            warn!(%addr, failures = count, ?duration, "banned address after failed authentications");
+        }
+    }
+
+    /// Banned addresses, with the time left on each ban.
+    pub fn bans(&self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        self.bans
+            .iter()
+            .filter(|ban| *ban.value() > now)
+            .map(|ban| (*ban.key(), *ban.value() - now))
+            .collect()
+    }
+
+    /// Ban an address for this much longer, such as one that was banned
+    /// before the server restarted.
+    pub fn restore_ban(&self, addr: IpAddr, remaining: Duration) {
+        self.bans.insert(addr, Instant::now() + remaining);
+    }
+
+    /// Lift expired bans, and forget addresses that have nothing left to
+    /// limit, so that the guard does not grow with every address seen.
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        let expired: Vec<IpAddr> = self
+            .bans
+            .iter()
+            .filter(|ban| *ban.value() <= now)
+            .map(|ban| *ban.key())
+            .collect();
+        for addr in expired {
+            self.lift(addr, now);
+        }
+        self.failures
+            .retain(|_, failures| now.duration_since(failures.since) <= FAILURE_WINDOW);
+        if let Some((_, burst)) = self.rate {
+            self.buckets
+                .retain(|_, bucket| bucket.available() < burst as f64);
+        }
+    }
+
+    /// Lift the ban of an address if it has ended.
+    fn lift(&self, addr: IpAddr, now: Instant) {
+        if self
+            .bans
+            .remove_if(&addr, |_, until| *until <= now)
+            .is_some()
+        {
+            info!(%addr, "lifted ban");
+        }
+    }
+}
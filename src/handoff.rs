@@ -0,0 +1,130 @@
+//! Upgrades without downtime, by handing listening sockets to a new server.
+//!
+//! On SIGUSR2, a server with handoff enabled saves its state and starts its
+//! own binary again with the same arguments, passing on the listening sockets
+//! of the control port and of every open tunnel. The new server accepts
+//! control connections from then on, while the old one closes its tunnels.
+//! Their clients reconnect to the new server, which gives each of them back
+//! the listening socket of its port, so visitors that arrive in the meantime
+//! wait in its backlog instead of being refused. The old server exits once
+//! the connections that it was still forwarding have finished.
+//!
+//! Control connections are not handed over, so every client reconnects, and
+//! forwarded connections that outlast [`DRAIN_TIMEOUT`], such as WebSockets,
+//! are cut when the old server exits.
+
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::process::{Child, Command};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+/// Variable that tells a new server which descriptors it inherited, such as
+/// `control=3,8080=5`.
+pub const ENV_VAR: &str = "BORE_HANDOFF_FDS";
+
+/// Time that a new server has to start before the old one keeps serving.
+pub const STARTUP_TIME: Duration = Duration::from_secs(2);
+
+/// Time that the old server waits for forwarded connections to finish.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Time that inherited listeners of tunnels wait for their clients to
+/// reconnect, before they are closed.
+pub const CLAIM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Listening sockets inherited from a previous server.
+#[derive(Debug, Default)]
+pub struct Inherited {
+    /// Listener of the control port.
+    pub control: Option<TcpListener>,
+
+    /// Listeners of the tunnels that were open, by port.
+    pub tunnels: HashMap<u16, TcpListener>,
+}
+
+/// Descriptors of inherited listening sockets, as listed in [`ENV_VAR`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Descriptors {
+    /// Descriptor of the control port.
+    pub control: Option<i32>,
+
+    /// Descriptors of tunnel ports.
+    pub tunnels: Vec<(u16, i32)>,
+}
+
+/// Read the descriptors listed in [`ENV_VAR`].
+///
+/// ```
+/// use bore_cli::handoff::parse;
+///
+/// let fds = parse("control=3,8080=5").unwrap();
+/// assert_eq!((fds.control, fds.tunnels), (Some(3), vec![(8080, 5)]));
+/// assert_eq!(parse("").unwrap(), Default::default());
+/// assert!(parse("8080").is_err());
+/// ```
+pub fn parse(value: &str) -> Result<Descriptors> {
+    let mut control = None;
+    let mut tunnels = Vec::new();
+    for entry in value.split(',').filter(|entry| !entry.is_empty()) {
+        let (name, fd) = entry
+            .split_once('=')
+            .with_context(|| format!("invalid entry {entry:?} in {ENV_VAR}"))?;
+        let fd = fd
+            .parse()
+            .with_context(|| format!("invalid descriptor {fd:?} in {ENV_VAR}"))?;
+        match name {
+            "control" => control = Some(fd),
+            port => {
+                let port = port
+                    .parse()
+                    .with_context(|| format!("invalid port {port:?} in {ENV_VAR}"))?;
+                tunnels.push((port, fd));
+            }
+        }
+    }
+    Ok(Descriptors { control, tunnels })
+}
+
+/// Function that starts a command with the given descriptors left open in
+/// the new process.
+///
+/// Descriptors are close-on-exec, so that no other process inherits them.
+/// Clearing that flag in the new process alone takes `unsafe` code between
+/// fork and exec, which the binary provides.
+pub type Spawner = fn(Command, &[i32]) -> std::io::Result<Child>;
+
+/// Start the binary of this process again with the same arguments, passing
+/// on duplicates of the listeners of the control port and tunnels.
+#[cfg(unix)]
+pub(crate) fn spawn_successor(
+    spawn: Spawner,
+    control: &tokio::net::TcpListener,
+    tunnels: &[(u16, socket2::Socket)],
+) -> Result<Child> {
+    use std::os::fd::AsRawFd;
+
+    let control = socket2::SockRef::from(control).try_clone()?;
+    let mut fds = vec![control.as_raw_fd()];
+    let mut entries = vec![format!("control={}", control.as_raw_fd())];
+    for (port, socket) in tunnels {
+        fds.push(socket.as_raw_fd());
+        entries.push(format!("{port}={}", socket.as_raw_fd()));
+    }
+    let program = std::env::current_exe().context("could not find the server binary")?;
+    let mut command = Command::new(&program);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(ENV_VAR, entries.join(","));
+    spawn(command, &fds).with_context(|| format!("could not start {}", program.display()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn spawn_successor(
+    _spawn: Spawner,
+    _control: &tokio::net::TcpListener,
+    _tunnels: &[(u16, socket2::Socket)],
+) -> Result<Child> {
+    anyhow::bail!("handing over to a new server is only supported on Unix")
+}
@@ -0,0 +1,208 @@
+//! Heartbeats that adapt to the quality of the path to the client.
+//!
+//! Plain heartbeats only fail once the operating system gives up on the TCP
+//! connection, which can take many minutes on a mobile network that silently
+//! drops packets. Clients that negotiate it answer each ping instead, so the
+//! server can time round trips like TCP does, estimating the round-trip time
+//! and its variance. Pings that go unanswered for too long count as lost.
+//!
+//! The interval between pings shrinks when round trips become erratic or
+//! pings are lost, so that a dead tunnel is noticed within seconds, and grows
+//! again while the path is stable, so that good links carry little traffic.
+
+use std::time::{Duration, Instant};
+
+/// Interval between pings on a new tunnel.
+pub const INITIAL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Shortest interval between pings, used on the worst paths.
+pub const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest interval between pings, used on stable paths.
+pub const MAX_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait for an answer before the round-trip time is known.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Bounds on how long to wait for an answer once it is known.
+const MIN_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of pings in a row that may be lost before the client is dead.
+pub const MAX_MISSED: u32 = 3;
+
+/// Weight of the latest ping in the moving average of the loss rate.
+const LOSS_WEIGHT: f64 = 0.2;
+
+/// What to do next to keep the heartbeat going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beat {
+    /// Nothing is due yet.
+    Idle,
+
+    /// Send a ping with this sequence number.
+    Ping(u64),
+
+    /// Too many pings were lost, so the client should be considered gone.
+    Dead,
+}
+
+/// Schedule of pings to one client, adapted to the answers that come back.
+///
+/// ```
+/// use std::time::{Duration, Instant};
+/// use bore_cli::heartbeat::{Beat, Heartbeat, MAX_MISSED};
+///
+/// let start = Instant::now();
+/// let mut heartbeat = Heartbeat::new(start);
+/// let Beat::Ping(seq) = heartbeat.poll(start) else { panic!() };
+/// heartbeat.ack(seq, start + Duration::from_millis(50));
+/// assert_eq!(heartbeat.poll(start + Duration::from_millis(100)), Beat::Idle);
+///
+/// // A client that stops answering is dead after a few lost pings.
+/// let mut now = start + heartbeat.interval();
+/// let mut pings = 0;
+/// while heartbeat.poll(now) != Beat::Dead {
+///     pings += 1;
+///     now += Duration::from_secs(1);
+/// }
+/// assert!(pings >= MAX_MISSED);
+/// assert!(heartbeat.interval() < Duration::from_secs(5));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    interval: Duration,
+
+    /// Longest that the interval may grow to.
+    max_interval: Duration,
+
+    /// Smoothed round-trip time and its variance, once measured.
+    srtt: Option<Duration>,
+    rttvar: Duration,
+
+    /// Moving average of the fraction of pings that were lost.
+    loss: f64,
+
+    /// Sequence number of the last ping.
+    seq: u64,
+
+    /// Ping waiting for an answer, with the time it was sent.
+    outstanding: Option<(u64, Instant)>,
+
+    /// When the next ping is due.
+    next_ping: Instant,
+
+    /// Number of pings lost since the last answer.
+    missed: u32,
+}
+
+impl Heartbeat {
+    /// Start a heartbeat whose first ping is due now.
+    pub fn new(now: Instant) -> Self {
+        Self {
+            interval: INITIAL_INTERVAL,
+            max_interval: MAX_INTERVAL,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            loss: 0.0,
+            seq: 0,
+            outstanding: None,
+            next_ping: now,
+            missed: 0,
+        }
+    }
+
+    /// Keep the interval between pings below `max`, for clients that expect
+    /// to hear from the server within some time.
+    pub fn with_max_interval(mut self, max: Duration) -> Self {
+        self.max_interval = max.clamp(MIN_INTERVAL, MAX_INTERVAL);
+        self.interval = self.interval.min(self.max_interval);
+        self
+    }
+
+    /// Current interval between pings.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Smoothed round-trip time to the client, once measured.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// How long to wait for an answer before counting a ping as lost.
+    pub fn timeout(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt + 4 * self.rttvar).clamp(MIN_TIMEOUT, MAX_TIMEOUT),
+            None => INITIAL_TIMEOUT,
+        }
+    }
+
+    /// Decide what to do at this time.
+    pub fn poll(&mut self, now: Instant) -> Beat {
+        if let Some((_, sent)) = self.outstanding {
+            if now < sent + self.timeout() {
+                return Beat::Idle;
+            }
+            self.outstanding = None;
+            self.missed += 1;
+            self.adapt(true);
+            if self.missed >= MAX_MISSED {
+                return Beat::Dead;
+            }
+            // Probe again right away rather than waiting a whole interval.
+            self.next_ping = now;
+        }
+        if now < self.next_ping {
+            return Beat::Idle;
+        }
+        self.seq += 1;
+        self.outstanding = Some((self.seq, now));
+        self.next_ping = now + self.interval;
+        Beat::Ping(self.seq)
+    }
+
+    /// Record an answer from the client.
+    ///
+    /// Answers to pings that were already counted as lost still show that
+    /// the client is alive, but are not used to time round trips.
+    pub fn ack(&mut self, seq: u64, now: Instant) {
+        if seq == 0 || seq > self.seq {
+            return;
+        }
+        self.missed = 0;
+        let Some((expected, sent)) = self.outstanding else {
+            return;
+        };
+        if seq != expected {
+            return;
+        }
+        self.outstanding = None;
+        let rtt = now.saturating_duration_since(sent);
+        match self.srtt {
+            Some(srtt) => {
+                let error = srtt.abs_diff(rtt);
+                self.rttvar = (3 * self.rttvar + error) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+        }
+        self.adapt(false);
+    }
+
+    /// Update the loss rate with the outcome of a ping, and the interval with
+    /// the state of the path.
+    fn adapt(&mut self, lost: bool) {
+        let outcome = if lost { 1.0 } else { 0.0 };
+        self.loss = (1.0 - LOSS_WEIGHT) * self.loss + LOSS_WEIGHT * outcome;
+        let srtt = self.srtt.unwrap_or(INITIAL_TIMEOUT);
+        if lost || self.loss > 0.1 || self.rttvar > srtt / 2 {
+            self.interval = (self.interval / 2).max(MIN_INTERVAL);
+        } else if self.loss < 0.01 && self.rttvar < srtt / 8 {
+            self.interval = (self.interval + self.interval / 4).min(self.max_interval);
+        }
+    }
+}
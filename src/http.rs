@@ -0,0 +1,970 @@
+//! Routing of HTTP requests on a shared port to tunnels by hostname.
+//!
+//! A server can listen on one public port, such as 80, for tunnels that are
+//! opened with `--proto http`. Each such tunnel is given a subdomain of the
+//! server's domain: the one the client asks for, or else the tunnel's name,
+//! or else a random one. The server reads the head of every request on the
+//! shared port and hands the connection to the tunnel named by its `Host`
+//! header, so many tunnels can serve HTTP on the same port, like
+//! `myapp.tunnel.example.com` and `api.tunnel.example.com`.
+//!
+//! Connections stay with the tunnel of their first request, which browsers
+//! guarantee by only reusing connections for the same host. Every request
+//! on them is told where it came from with `X-Forwarded-For`,
+//! `X-Forwarded-Proto`, and `Forwarded` headers, as the local service only
+//! sees connections from the client.
+//!
+//! A tunnel can ask the server to keep visitors out unless they give a user
+//! and password with HTTP basic authentication, which the server checks on
+//! the first request of each connection before handing it over. It can also
+//! ask for visitors to log in with the server's OpenID Connect provider, with
+//! [`crate::oidc`].
+//!
+//! The server can also terminate TLS for these tunnels on a second shared
+//! port, with certificates from [`crate::acme`]. Tunnels opened with
+//! `--proto tls` get a subdomain the same way, but terminate TLS themselves:
+//! the server routes their connections on a shared TLS port by the server
+//! name of the ClientHello, from [`crate::sni`], without decrypting them.
+//!
+//! Visitors of a hostname whose tunnel is offline, busy, or missing get an
+//! error page, which the server can render from its own HTML template.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures_util::future;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+use crate::acme::{Acme, ACME_TLS_ALPN};
+use crate::framing::Framing;
+use crate::oidc::{self, Oidc};
+use crate::rewrite::Rewritten;
+use crate::shared::canonical_addr;
+use crate::sni;
+use crate::websocket::Prefixed;
+
+/// Longest head of a request that is read to find its host.
+pub const MAX_HEAD_LENGTH: usize = 16 * 1024;
+
+/// Time for a visitor to send the head of its request.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections that may wait for a tunnel to pick them up, beyond which
+/// visitors are told that the tunnel is busy.
+const ROUTE_BACKLOG: usize = 64;
+
+/// Length of random subdomains.
+const RANDOM_SUBDOMAIN_LENGTH: usize = 8;
+
+/// Challenge sent to visitors of tunnels that require basic authentication.
+const BASIC_AUTH_CHALLENGE: &str = "Basic realm=\"bore\", charset=\"UTF-8\"";
+
+/// Time that the hostnames of closed HTTP tunnels are remembered, to tell
+/// their visitors that the tunnel is offline rather than missing.
+const OFFLINE_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Path under which `http-01` challenges are served.
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Connection of a visitor to a shared port, in plaintext or after TLS.
+pub(crate) trait HttpStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> HttpStream for T {}
+
+/// Connection of a visitor routed to a tunnel, with the bytes of the request
+/// that were read to route it still to be read.
+pub(crate) type Routed = (Box<dyn HttpStream>, SocketAddr);
+
+/// Hostnames of the HTTP and TLS tunnels on a server.
+///
+/// ```
+/// use bore_cli::http::{Access, HttpRouter, RouteKind};
+///
+/// let router = HttpRouter::new("Tunnel.Example.com");
+/// let route = router.register(Some("myapp"), None, RouteKind::Http, Access::default()).unwrap();
+/// assert_eq!(route.hostname(), "myapp.tunnel.example.com");
+/// assert!(router.register(Some("myapp"), None, RouteKind::Tls, Access::default()).is_err());
+/// assert!(router.register(Some("not_a_label"), None, RouteKind::Http, Access::default()).is_err());
+///
+/// let random = router.register(None, None, RouteKind::Http, Access::default()).unwrap();
+/// assert!(random.hostname().ends_with(".tunnel.example.com"));
+/// drop(route);
+/// assert!(router.register(Some("myapp"), None, RouteKind::Http, Access::default()).is_ok());
+/// ```
+#[derive(Debug)]
+pub struct HttpRouter {
+    domain: String,
+    routes: DashMap<String, Backend>,
+
+    /// Subdomains of HTTP tunnels that closed, with their tunnel's name and
+    /// when it closed.
+    offline: DashMap<String, (Option<String>, Instant)>,
+
+    /// HTML template of error pages, if any.
+    error_page: Option<String>,
+}
+
+/// Tunnel that a hostname is routed to.
+#[derive(Debug, Clone)]
+struct Backend {
+    kind: RouteKind,
+    name: Option<String>,
+    sender: mpsc::Sender<Routed>,
+    access: Arc<Access>,
+}
+
+/// What visitors of an HTTP tunnel must prove before they reach it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Access {
+    /// Digest of the credentials that visitors must give with basic
+    /// authentication, from [`basic_auth_digest`], if any.
+    pub basic_auth: Option<[u8; 32]>,
+
+    /// Emails and domains that visitors must log in as with OIDC, from
+    /// [`oidc::check_rule`], unless empty.
+    pub oidc: Vec<String>,
+}
+
+/// How the connections of a tunnel's hostname reach it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteKind {
+    /// HTTP requests, by their `Host` header, in plaintext or after the
+    /// server terminates TLS.
+    Http,
+
+    /// TLS connections, still encrypted, by the server name of their
+    /// ClientHello.
+    Tls,
+}
+
+impl std::fmt::Display for RouteKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteKind::Http => f.write_str("HTTP"),
+            RouteKind::Tls => f.write_str("TLS"),
+        }
+    }
+}
+
+/// What a shared port does with the connections of visitors. A port that
+/// does neither serves plain HTTP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct PortMode {
+    /// Terminate TLS with certificates from the ACME client.
+    pub terminate: bool,
+
+    /// Route TLS connections for TLS tunnels without decrypting them.
+    pub passthrough: bool,
+}
+
+/// Error when a tunnel cannot get the subdomain that it asked for.
+#[derive(Debug)]
+pub enum RouteError {
+    /// The subdomain is not a valid DNS label.
+    Invalid(String),
+
+    /// Another tunnel has the subdomain.
+    Taken(String),
+}
+
+impl std::fmt::Display for RouteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteError::Invalid(reason) => f.write_str(reason),
+            RouteError::Taken(subdomain) => write!(f, "subdomain {subdomain:?} is already in use"),
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// Subdomain registered for a tunnel, which receives the connections for
+/// its hostname until it is dropped.
+pub struct Route<'a> {
+    router: &'a HttpRouter,
+    kind: RouteKind,
+    name: Option<String>,
+    subdomain: String,
+    hostname: String,
+    receiver: mpsc::Receiver<Routed>,
+}
+
+impl HttpRouter {
+    /// Route hostnames under a domain, such as `tunnel.example.com`.
+    pub fn new(domain: &str) -> Self {
+        Self {
+            domain: domain.trim_end_matches('.').to_ascii_lowercase(),
+            routes: DashMap::new(),
+            offline: DashMap::new(),
+            error_page: None,
+        }
+    }
+
+    /// Render error pages for visitors from an HTML template, instead of
+    /// plain text. The template may refer to `{{status}}`, `{{reason}}`,
+    /// `{{hostname}}`, `{{name}}`, and `{{message}}`.
+    ///
+    /// ```
+    /// use bore_cli::http::HttpRouter;
+    ///
+    /// let mut router = HttpRouter::new("tunnel.example.com");
+    /// router.set_error_page("<h1>{{status}} {{reason}}</h1><p>{{name}}: {{message}}</p>".into());
+    /// let page = router.error_page(502, "Bad Gateway", "a.tunnel.example.com", Some("<a>"), "offline");
+    /// assert_eq!(page.unwrap(), "<h1>502 Bad Gateway</h1><p>&lt;a&gt;: offline</p>");
+    /// ```
+    pub fn set_error_page(&mut self, template: String) {
+        self.error_page = Some(template);
+    }
+
+    /// Error page for visitors of a hostname, if there is a template.
+    pub fn error_page(
+        &self,
+        status: u16,
+        reason: &str,
+        hostname: &str,
+        name: Option<&str>,
+        message: &str,
+    ) -> Option<String> {
+        let template = self.error_page.as_ref()?;
+        let page = template
+            .replace("{{status}}", &status.to_string())
+            .replace("{{reason}}", &escape_html(reason))
+            .replace("{{hostname}}", &escape_html(hostname))
+            .replace("{{name}}", &escape_html(name.unwrap_or(hostname)))
+            .replace("{{message}}", &escape_html(message));
+        Some(page)
+    }
+
+    /// Domain that tunnels get subdomains of.
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Give a tunnel with a name a subdomain, or a random one if it asks for
+    /// none. An HTTP tunnel may restrict who can visit it.
+    pub fn register(
+        &self,
+        subdomain: Option<&str>,
+        name: Option<&str>,
+        kind: RouteKind,
+        access: Access,
+    ) -> Result<Route<'_>, RouteError> {
+        let subdomain = match subdomain {
+            Some(subdomain) => {
+                let subdomain = subdomain.to_ascii_lowercase();
+                check_subdomain(&subdomain).map_err(|err| RouteError::Invalid(err.to_string()))?;
+                subdomain
+            }
+            None => loop {
+                let subdomain = random_subdomain();
+                if !self.routes.contains_key(&subdomain) {
+                    break subdomain;
+                }
+            },
+        };
+        let (sender, receiver) = mpsc::channel(ROUTE_BACKLOG);
+        match self.routes.entry(subdomain.clone()) {
+            Entry::Occupied(_) => return Err(RouteError::Taken(subdomain)),
+            Entry::Vacant(entry) => entry.insert(Backend {
+                kind,
+                name: name.map(str::to_string),
+                sender,
+                access: Arc::new(access),
+            }),
+        };
+        self.offline.remove(&subdomain);
+        Ok(Route {
+            router: self,
+            kind,
+            name: name.map(str::to_string),
+            hostname: format!("{subdomain}.{}", self.domain),
+            subdomain,
+            receiver,
+        })
+    }
+
+    /// Whether an HTTP tunnel has a hostname.
+    pub fn serves(&self, hostname: &str) -> bool {
+        self.backend(hostname, RouteKind::Http).is_some()
+    }
+
+    /// Tunnel of a kind that a host names, if any.
+    fn backend(&self, host: &str, kind: RouteKind) -> Option<Backend> {
+        let subdomain = self.subdomain_of(host)?;
+        let backend = self.routes.get(&subdomain)?;
+        (backend.kind == kind).then(|| backend.clone())
+    }
+
+    /// Subdomain of the domain that a `Host` header names, if any.
+    ///
+    /// ```
+    /// use bore_cli::http::HttpRouter;
+    ///
+    /// let router = HttpRouter::new("tunnel.example.com");
+    /// assert_eq!(router.subdomain_of("MyApp.tunnel.example.com:8080"), Some("myapp".into()));
+    /// assert_eq!(router.subdomain_of("tunnel.example.com"), None);
+    /// assert_eq!(router.subdomain_of("a.b.tunnel.example.com"), None);
+    /// assert_eq!(router.subdomain_of("myapp.example.org"), None);
+    /// ```
+    pub fn subdomain_of(&self, host: &str) -> Option<String> {
+        let host = bare_host(host);
+        let subdomain = host.strip_suffix(&self.domain)?.strip_suffix('.')?;
+        (!subdomain.is_empty() && !subdomain.contains('.')).then(|| subdomain.to_string())
+    }
+
+    /// Whether a `Host` header names the domain itself.
+    fn is_domain(&self, host: &str) -> bool {
+        bare_host(host) == self.domain
+    }
+
+    /// Read the head of a request and hand the connection to the tunnel that
+    /// its host names, or answer it with an error. Requests for `http-01`
+    /// challenges are answered from the ACME client, if there is one, and
+    /// OIDC logins finish at the domain itself.
+    async fn route(
+        &self,
+        mut stream: Box<dyn HttpStream>,
+        peer: SocketAddr,
+        proto: &'static str,
+        acme: Option<&Acme>,
+        oidc: Option<&Oidc>,
+    ) -> Result<()> {
+        let head = match timeout(HEAD_TIMEOUT, read_head(&mut stream)).await {
+            Ok(Ok(head)) => head,
+            Ok(Err(err)) => {
+                respond(&mut stream, 400, "Bad Request", "malformed request\n").await?;
+                return Err(err);
+            }
+            Err(_) => bail!("timed out reading request"),
+        };
+        let request = RequestHead::parse(&head)?.context("incomplete request")?;
+        if let Err(err) = Framing::request(&request) {
+            debug!(%peer, %err, "rejecting request");
+            respond(&mut stream, 400, "Bad Request", "malformed request\n").await?;
+            return Ok(());
+        }
+        let challenge = (request.path.strip_prefix(ACME_CHALLENGE_PATH))
+            .and_then(|token| acme?.store().http_challenge(token));
+        if let Some(key_authorization) = challenge {
+            respond(&mut stream, 200, "OK", &key_authorization).await?;
+            return Ok(());
+        }
+        let Some(host) = request.header("host") else {
+            respond(&mut stream, 400, "Bad Request", "missing Host header\n").await?;
+            return Ok(());
+        };
+        if let Some(oidc) = oidc.filter(|_| self.is_domain(&host)) {
+            let path = request.path.split('?').next();
+            if path == Some(oidc::LOGIN_PATH) {
+                let reply = oidc.login(&request).await;
+                return Ok(respond_reply(&mut stream, &reply).await?);
+            }
+            if path == Some(oidc::CALLBACK_PATH) {
+                let reply = oidc.callback(&request).await;
+                return Ok(respond_reply(&mut stream, &reply).await?);
+            }
+        }
+        let Some(backend) = self.backend(&host, RouteKind::Http) else {
+            let offline = (self.subdomain_of(&host))
+                .and_then(|subdomain| self.offline.get(&subdomain).map(|entry| entry.0.clone()));
+            let (status, reason, message, name) = match offline {
+                Some(name) => {
+                    debug!(%peer, host, "tunnel for host is offline");
+                    let message = format!("the tunnel at {host} is offline");
+                    (502, "Bad Gateway", message, name)
+                }
+                None => {
+                    debug!(%peer, host, "no tunnel for host");
+                    let message = format!("no tunnel is open at {host}");
+                    (404, "Not Found", message, None)
+                }
+            };
+            self.respond_error(
+                &mut stream,
+                status,
+                reason,
+                &host,
+                name.as_deref(),
+                &message,
+            )
+            .await?;
+            return Ok(());
+        };
+        let access = Arc::clone(&backend.access);
+        let basic_auth = access.basic_auth;
+        if basic_auth.is_some() && authorization_digest(&request) != basic_auth {
+            debug!(%peer, host, "visitor did not authenticate");
+            let headers = [("WWW-Authenticate", BASIC_AUTH_CHALLENGE)];
+            let body = "authentication required\n";
+            respond_with(&mut stream, 401, "Unauthorized", &headers, body).await?;
+            return Ok(());
+        }
+        if !access.oidc.is_empty() {
+            // Tunnels that ask for OIDC are refused by servers without it.
+            let reply = match oidc {
+                Some(oidc) => oidc.gate(&request, proto, &host, &access.oidc).await,
+                None => Some(oidc::Reply {
+                    status: 403,
+                    reason: "Forbidden",
+                    headers: vec![],
+                    body: "login is unavailable\n".into(),
+                }),
+            };
+            if let Some(reply) = reply {
+                debug!(%peer, host, status = reply.status, "visitor is not logged in");
+                return Ok(respond_reply(&mut stream, &reply).await?);
+            }
+        }
+        let stream = Rewritten::new(
+            Prefixed::new(stream, head),
+            Box::new(move |request| {
+                // The local service does not need to see the tunnel's password
+                // or the visitor's session.
+                if basic_auth.is_some() && authorization_digest(request) == basic_auth {
+                    request.remove_header("authorization");
+                }
+                if !access.oidc.is_empty() {
+                    oidc::strip_cookie(request);
+                }
+                add_forwarded(request, peer, proto);
+            }),
+        );
+        let name = backend.name.as_deref();
+        match backend.sender.try_send((Box::new(stream), peer)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full((mut stream, _))) => {
+                warn!(%peer, host, "too many connections waiting for tunnel");
+                let message = format!("the tunnel at {host} is busy");
+                (self.respond_error(
+                    &mut stream,
+                    503,
+                    "Service Unavailable",
+                    &host,
+                    name,
+                    &message,
+                ))
+                .await?;
+            }
+            Err(mpsc::error::TrySendError::Closed((mut stream, _))) => {
+                let message = format!("the tunnel at {host} is closing");
+                (self.respond_error(&mut stream, 502, "Bad Gateway", &host, name, &message))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell a visitor that a hostname's tunnel cannot take the request, with
+    /// the error page if there is one.
+    async fn respond_error(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        status: u16,
+        reason: &str,
+        host: &str,
+        name: Option<&str>,
+        message: &str,
+    ) -> io::Result<()> {
+        match self.error_page(status, reason, host, name, message) {
+            Some(page) => {
+                let content_type = "text/html; charset=utf-8";
+                write_response(stream, status, reason, content_type, &[], &page).await
+            }
+            None => respond(stream, status, reason, &format!("{message}\n")).await,
+        }
+    }
+
+    /// Read the ClientHello of a TLS connection and hand the connection to
+    /// the TLS tunnel that its server name names. Otherwise, return it with
+    /// the bytes that were read, for the server to terminate TLS itself.
+    async fn pass_through(
+        &self,
+        mut stream: Box<dyn HttpStream>,
+        peer: SocketAddr,
+    ) -> Result<Option<Prefixed<Box<dyn HttpStream>>>> {
+        let (hello, bytes) = timeout(HEAD_TIMEOUT, read_hello(&mut stream))
+            .await
+            .context("timed out reading ClientHello")??;
+        let backend =
+            (hello.server_name.as_deref()).and_then(|name| self.backend(name, RouteKind::Tls));
+        let Some(backend) = backend else {
+            return Ok(Some(Prefixed::new(stream, bytes)));
+        };
+        let name = hello.server_name.unwrap_or_default();
+        match (backend.sender).try_send((Box::new(Prefixed::new(stream, bytes)), peer)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!(%peer, name, "too many connections waiting for tunnel");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+        Ok(None)
+    }
+}
+
+impl Route<'_> {
+    /// Hostname that the tunnel is reached at.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// How connections reach the tunnel.
+    pub fn kind(&self) -> RouteKind {
+        self.kind
+    }
+
+    /// Wait for the next connection to the hostname.
+    pub(crate) async fn accept(&mut self) -> Option<Routed> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Route<'_> {
+    fn drop(&mut self) {
+        self.router.routes.remove(&self.subdomain);
+        if self.kind == RouteKind::Http {
+            let offline = &self.router.offline;
+            offline.retain(|_, (_, since)| since.elapsed() < OFFLINE_MEMORY);
+            offline.insert(self.subdomain.clone(), (self.name.take(), Instant::now()));
+        }
+    }
+}
+
+/// Request line and headers of an HTTP request.
+///
+/// ```
+/// use bore_cli::http::RequestHead;
+///
+/// let head = b"GET /index.html HTTP/1.1\r\nHost: myapp.example.com\r\n\r\n";
+/// let request = RequestHead::parse(head).unwrap().unwrap();
+/// assert_eq!((request.method.as_str(), request.path.as_str()), ("GET", "/index.html"));
+/// assert_eq!(request.header("HOST").as_deref(), Some("myapp.example.com"));
+///
+/// assert!(RequestHead::parse(b"GET / HTTP/1.1\r\nHost: a").unwrap().is_none());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    /// Method of the request, such as `GET`.
+    pub method: String,
+
+    /// Path and query of the request.
+    pub path: String,
+
+    /// Minor version of HTTP/1.
+    pub version: u8,
+
+    /// Headers in the order they were sent.
+    pub headers: Vec<(String, String)>,
+
+    /// Length of the head in bytes, up to and including its blank line.
+    pub length: usize,
+}
+
+impl RequestHead {
+    /// Parse the head at the start of a request, or `None` if it is still
+    /// incomplete.
+    pub fn parse(bytes: &[u8]) -> Result<Option<Self>> {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        let length = match request.parse(bytes).context("malformed HTTP request")? {
+            httparse::Status::Complete(length) => length,
+            httparse::Status::Partial => return Ok(None),
+        };
+        Ok(Some(Self {
+            method: request.method.unwrap_or_default().to_string(),
+            path: request.path.unwrap_or_default().to_string(),
+            version: request.version.unwrap_or(1),
+            headers: (request.headers.iter())
+                .map(|header| {
+                    let value = String::from_utf8_lossy(header.value).into_owned();
+                    (header.name.to_string(), value)
+                })
+                .collect(),
+            length,
+        }))
+    }
+
+    /// Value of the first header with a name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<String> {
+        (self.headers.iter())
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().to_string())
+    }
+
+    /// Replace any headers with a name by one with a value.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.remove_header(name);
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Remove any headers with a name, ignoring case.
+    pub fn remove_header(&mut self, name: &str) {
+        (self.headers).retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+    }
+
+    /// Add a value to the end of a header that holds a list, joining the
+    /// values of any headers with that name into one.
+    ///
+    /// ```
+    /// use bore_cli::http::RequestHead;
+    ///
+    /// let head = b"GET / HTTP/1.1\r\nVia: 1.0 a\r\nVia: 1.1 b\r\n\r\n";
+    /// let mut request = RequestHead::parse(head).unwrap().unwrap();
+    /// request.append_header("via", "1.1 c");
+    /// assert_eq!(request.to_bytes(), b"GET / HTTP/1.1\r\nVia: 1.0 a, 1.1 b, 1.1 c\r\n\r\n");
+    /// ```
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        let mut values = Vec::new();
+        let mut first = None;
+        self.headers.retain(|(header, existing)| {
+            if !header.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            first.get_or_insert_with(|| header.clone());
+            values.push(existing.trim().to_string());
+            false
+        });
+        values.push(value.to_string());
+        let name = first.unwrap_or_else(|| name.to_string());
+        self.headers.push((name, values.join(", ")));
+    }
+
+    /// Head of the request as it is sent, up to and including its blank line.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.{}\r\n", self.method, self.path, self.version);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+/// Tell the local service where a request came from: the address of the
+/// visitor, the scheme that it used, and the host that it asked for. Values
+/// from proxies in front of the server are kept, with the visitor last.
+///
+/// ```
+/// use bore_cli::http::{add_forwarded, RequestHead};
+///
+/// let head = b"GET / HTTP/1.1\r\nHost: myapp.example.com\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n";
+/// let mut request = RequestHead::parse(head).unwrap().unwrap();
+/// add_forwarded(&mut request, "[2001:db8::7]:51234".parse().unwrap(), "https");
+/// assert_eq!(request.header("x-forwarded-for").as_deref(), Some("10.0.0.1, 2001:db8::7"));
+/// assert_eq!(request.header("x-forwarded-proto").as_deref(), Some("https"));
+/// assert_eq!(
+///     request.header("forwarded").as_deref(),
+///     Some(r#"for="[2001:db8::7]";proto=https;host="myapp.example.com""#),
+/// );
+/// ```
+pub fn add_forwarded(request: &mut RequestHead, peer: SocketAddr, proto: &str) {
+    let ip = peer.ip();
+    request.append_header("X-Forwarded-For", &ip.to_string());
+    request.set_header("X-Forwarded-Proto", proto);
+    let mut forwarded = match ip {
+        IpAddr::V4(ip) => format!("for={ip};proto={proto}"),
+        IpAddr::V6(ip) => format!("for=\"[{ip}]\";proto={proto}"),
+    };
+    if let Some(host) = request.header("host") {
+        let host = host.replace(['"', '\\'], "");
+        forwarded.push_str(&format!(";host=\"{host}\""));
+    }
+    request.append_header("Forwarded", &forwarded);
+}
+
+/// Check credentials for basic authentication, which are a user and a
+/// password separated by a colon.
+///
+/// ```
+/// use bore_cli::http::check_basic_auth;
+///
+/// assert!(check_basic_auth("demo:s3cret:with:colons").is_ok());
+/// assert!(check_basic_auth(":s3cret").is_err());
+/// assert!(check_basic_auth("demo").is_err());
+/// ```
+pub fn check_basic_auth(credentials: &str) -> Result<()> {
+    let (user, password) = credentials
+        .split_once(':')
+        .context("credentials must be given as user:password")?;
+    ensure!(!user.is_empty(), "user must not be empty");
+    ensure!(!password.is_empty(), "password must not be empty");
+    ensure!(
+        !credentials.chars().any(char::is_control),
+        "credentials must not contain control characters"
+    );
+    Ok(())
+}
+
+/// Digest of credentials for basic authentication, `user:password`, which a
+/// client sends to the server instead of the password itself.
+pub fn basic_auth_digest(credentials: &str) -> [u8; 32] {
+    Sha256::digest(credentials).into()
+}
+
+/// Digest of the credentials that a request gives with basic authentication.
+fn authorization_digest(request: &RequestHead) -> Option<[u8; 32]> {
+    let authorization = request.header("authorization")?;
+    let (scheme, encoded) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = BASE64.decode(encoded.trim()).ok()?;
+    Some(Sha256::digest(credentials).into())
+}
+
+/// Check that a subdomain is a single DNS label.
+///
+/// ```
+/// use bore_cli::http::check_subdomain;
+///
+/// assert!(check_subdomain("my-app2").is_ok());
+/// assert!(check_subdomain("-app").is_err());
+/// assert!(check_subdomain("my.app").is_err());
+/// ```
+pub fn check_subdomain(subdomain: &str) -> Result<()> {
+    ensure!(
+        (1..=63).contains(&subdomain.len()),
+        "subdomain must be between 1 and 63 bytes"
+    );
+    ensure!(
+        (subdomain.bytes()).all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-'),
+        "subdomain may only contain lowercase letters, digits, and '-'"
+    );
+    ensure!(
+        !subdomain.starts_with('-') && !subdomain.ends_with('-'),
+        "subdomain may not start or end with '-'"
+    );
+    Ok(())
+}
+
+/// Accept connections on a shared port and route them to tunnels, until the
+/// task is aborted. With an ACME client, a plain HTTP port answers its
+/// `http-01` challenges, and a port that terminates TLS uses its
+/// certificates. With an OIDC gate, visitors of tunnels that ask for it must
+/// log in.
+pub(crate) async fn serve(
+    listeners: Vec<TcpListener>,
+    router: Arc<HttpRouter>,
+    acme: Option<Arc<Acme>>,
+    oidc: Option<Arc<Oidc>>,
+    mode: PortMode,
+) {
+    let acceptor = (acme.as_ref())
+        .filter(|_| mode.terminate)
+        .map(|acme| acme.acceptor());
+    let accept = |listener: TcpListener| {
+        let router = Arc::clone(&router);
+        let acme = acme.clone();
+        let oidc = oidc.clone();
+        let acceptor = acceptor.clone();
+        async move {
+            if let Ok(addr) = listener.local_addr() {
+                let tunnels = match (mode.terminate, mode.passthrough) {
+                    (false, false) => "HTTP",
+                    (true, false) => "HTTPS",
+                    (false, true) => "TLS",
+                    (true, true) => "HTTPS and TLS",
+                };
+                info!(%addr, domain = router.domain(), "routing {tunnels} tunnels");
+            }
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!(%err, "could not accept HTTP connection");
+                        continue;
+                    }
+                };
+                let router = Arc::clone(&router);
+                let acme = acme.clone();
+                let oidc = oidc.clone();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let peer = canonical_addr(peer);
+                    let routed = async {
+                        let mut stream: Box<dyn HttpStream> = Box::new(stream);
+                        if mode.passthrough {
+                            match router.pass_through(stream, peer).await? {
+                                Some(rest) => stream = Box::new(rest),
+                                None => return Ok(()),
+                            }
+                        }
+                        match &acceptor {
+                            Some(acceptor) => match terminate(acceptor, stream).await? {
+                                Some(decrypted) => stream = decrypted,
+                                None => return Ok(()),
+                            },
+                            None if mode.passthrough => bail!("no tunnel for server name"),
+                            None => {}
+                        }
+                        let acme = acme.as_deref().filter(|_| acceptor.is_none());
+                        let proto = if acceptor.is_some() { "https" } else { "http" };
+                        router
+                            .route(stream, peer, proto, acme, oidc.as_deref())
+                            .await
+                    };
+                    if let Err(err) = routed.await {
+                        debug!(%err, %peer, "could not route HTTP connection");
+                    }
+                });
+            }
+        }
+    };
+    future::join_all(listeners.into_iter().map(accept)).await;
+}
+
+/// Complete the TLS handshake of a visitor, or `None` if it was the ACME
+/// authority checking a `tls-alpn-01` challenge, which needs nothing more.
+async fn terminate(
+    acceptor: &TlsAcceptor,
+    stream: Box<dyn HttpStream>,
+) -> Result<Option<Box<dyn HttpStream>>> {
+    let stream = timeout(HEAD_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("timed out waiting for TLS handshake")?
+        .context("TLS handshake failed")?;
+    if stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN) {
+        return Ok(None);
+    }
+    Ok(Some(Box::new(stream)))
+}
+
+/// Read from a stream until it holds the whole head of a request, returning
+/// all bytes that were read.
+async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        ensure!(
+            n > 0,
+            "connection closed before the end of the request head"
+        );
+        head.extend_from_slice(&buf[..n]);
+        if RequestHead::parse(&head)?.is_some() {
+            return Ok(head);
+        }
+        ensure!(head.len() <= MAX_HEAD_LENGTH, "request head is too long");
+    }
+}
+
+/// Read from a stream until it holds a whole ClientHello, returning it along
+/// with all bytes that were read.
+async fn read_hello(stream: &mut (impl AsyncRead + Unpin)) -> Result<(sni::Hello, Vec<u8>)> {
+    let mut bytes = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        ensure!(n > 0, "connection closed before the end of the ClientHello");
+        bytes.extend_from_slice(&buf[..n]);
+        if let Some(hello) = sni::Hello::parse(&bytes)? {
+            return Ok((hello, bytes));
+        }
+        // Leave room for the headers of the records that hold it.
+        ensure!(
+            bytes.len() <= sni::MAX_HELLO_LENGTH + 1024,
+            "ClientHello is too long"
+        );
+    }
+}
+
+/// Answer a request with a short plain text response and close.
+async fn respond(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    reason: &str,
+    body: &str,
+) -> io::Result<()> {
+    respond_with(stream, status, reason, &[], body).await
+}
+
+/// Answer a request with a short plain text response and more headers, and
+/// close.
+async fn respond_with(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    reason: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> io::Result<()> {
+    let content_type = "text/plain; charset=utf-8";
+    write_response(stream, status, reason, content_type, headers, body).await
+}
+
+/// Answer a request with a response and close.
+async fn write_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    reason: &str,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> io::Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: {content_type}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answer a request with a reply of the OIDC gate, and close.
+async fn respond_reply(
+    stream: &mut (impl AsyncWrite + Unpin),
+    reply: &oidc::Reply,
+) -> io::Result<()> {
+    let headers: Vec<_> = (reply.headers.iter())
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect();
+    respond_with(stream, reply.status, reply.reason, &headers, &reply.body).await
+}
+
+/// Escape text for HTML.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Hostname of a `Host` header, without its port or a trailing dot.
+fn bare_host(host: &str) -> String {
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn random_subdomain() -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+    (0..RANDOM_SUBDOMAIN_LENGTH)
+        .map(|_| ALPHABET[fastrand::usize(..ALPHABET.len())] as char)
+        .collect()
+}
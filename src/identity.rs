@@ -0,0 +1,361 @@
+//! Long-term server identity keys, for detecting server impersonation.
+//!
+//! A client that checks the server's identity sends a random nonce and an
+//! ephemeral X25519 key share during the handshake. The server answers with
+//! its own key share and signs both, along with the nonce. Clients either pin
+//! the expected public key or record it the first time they connect (trust
+//! on first use), and refuse to continue if a server later presents a
+//! different key.
+//!
+//! The shared secret of the two key shares keys the session, and the client
+//! seals its credentials with it. Someone in the middle can relay the
+//! signature, but not learn the secret, so they cannot read the credentials.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::rand::SystemRandom;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::shared::{bounded_string, ClientMessage, Delimited, ServerMessage};
+
+/// Prefix of the handshakes that identity keys sign, so that no other
+/// signature made with the same key passes for one.
+const PROOF_CONTEXT: &[u8] = b"bore identity proof v1\0";
+
+/// Prefix of the hash that derives a session key from a shared secret.
+const SESSION_KEY_CONTEXT: &[u8] = b"bore session key v1\0";
+
+/// Request from the client for the server to prove its identity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdentityRequest {
+    /// Random nonce chosen by the client.
+    pub nonce: Uuid,
+
+    /// Hex-encoded ephemeral X25519 public key of the client.
+    #[serde(deserialize_with = "bounded_string")]
+    pub key_share: String,
+}
+
+/// Proof that the server holds the private key for a public identity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdentityProof {
+    /// Hex-encoded ed25519 public key of the server.
+    #[serde(deserialize_with = "bounded_string")]
+    pub public_key: String,
+
+    /// Hex-encoded ephemeral X25519 public key of the server.
+    #[serde(deserialize_with = "bounded_string")]
+    pub key_share: String,
+
+    /// Hex-encoded signature of the handshake.
+    #[serde(deserialize_with = "bounded_string")]
+    pub signature: String,
+}
+
+/// Client's side of an identity check, which holds its ephemeral key until
+/// the server answers.
+///
+/// ```
+/// use bore_cli::identity::{IdentityExchange, ServerIdentity};
+///
+/// let identity = ServerIdentity::generate().unwrap();
+/// let check = IdentityExchange::new().unwrap();
+/// let (proof, server_key) = identity.prove(check.request()).unwrap();
+/// assert_eq!(proof.public_key, identity.public_key());
+///
+/// let client_key = check.verify(&proof).unwrap();
+/// let sealed = client_key.seal("api key");
+/// assert_eq!(server_key.open(&sealed).unwrap(), "api key");
+///
+/// // A proof for another handshake does not pass.
+/// let (other, _) = identity.prove(IdentityExchange::new().unwrap().request()).unwrap();
+/// assert!(IdentityExchange::new().unwrap().verify(&other).is_err());
+/// ```
+pub struct IdentityExchange {
+    private: EphemeralPrivateKey,
+    request: IdentityRequest,
+}
+
+impl IdentityExchange {
+    /// Start an identity check with a new nonce and key share.
+    pub fn new() -> Result<Self> {
+        let private = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .ok()
+            .context("failed to generate key share")?;
+        let public = private
+            .compute_public_key()
+            .ok()
+            .context("invalid key share")?;
+        let request = IdentityRequest {
+            nonce: Uuid::new_v4(),
+            key_share: hex::encode(public.as_ref()),
+        };
+        Ok(Self { private, request })
+    }
+
+    /// Request to send to the server.
+    pub fn request(&self) -> &IdentityRequest {
+        &self.request
+    }
+
+    /// Check that the proof is a valid signature of this handshake, and
+    /// derive the session key from it.
+    pub fn verify(self, proof: &IdentityProof) -> Result<SessionKey> {
+        let key: [u8; 32] = decode_hex(&proof.public_key).context("malformed server public key")?;
+        let signature: [u8; 64] =
+            decode_hex(&proof.signature).context("malformed server signature")?;
+        let server_share: [u8; 32] =
+            decode_hex(&proof.key_share).context("malformed server key share")?;
+        let transcript = transcript(&self.request, &server_share, &key)?;
+        VerifyingKey::from_bytes(&key)
+            .context("invalid server public key")?
+            .verify(
+                &[PROOF_CONTEXT, &transcript].concat(),
+                &Signature::from_bytes(&signature),
+            )
+            .context("server failed to prove its identity")?;
+        SessionKey::agree(self.private, &server_share, &transcript)
+    }
+}
+
+/// Key of a session, shared by the client and server after an identity
+/// check, which seals one message from the client.
+pub struct SessionKey(LessSafeKey);
+
+impl SessionKey {
+    fn agree(private: EphemeralPrivateKey, peer_share: &[u8], transcript: &[u8]) -> Result<Self> {
+        let peer = UnparsedPublicKey::new(&X25519, peer_share);
+        let key = agreement::agree_ephemeral(private, &peer, |shared| {
+            Sha256::new()
+                .chain_update(SESSION_KEY_CONTEXT)
+                .chain_update(shared)
+                .chain_update(transcript)
+                .finalize()
+        })
+        .ok()
+        .context("invalid key share")?;
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key).expect("key has the right length");
+        Ok(Self(LessSafeKey::new(key)))
+    }
+
+    /// Encrypt a message, as base64. Each key only seals one message, so
+    /// its nonce is fixed.
+    pub fn seal(&self, message: &str) -> String {
+        let mut sealed = message.as_bytes().to_vec();
+        (self.0)
+            .seal_in_place_append_tag(zero_nonce(), Aad::empty(), &mut sealed)
+            .expect("message fits in a frame");
+        BASE64.encode(sealed)
+    }
+
+    /// Decrypt a message sealed with the same key.
+    pub fn open(&self, sealed: &str) -> Result<String> {
+        let mut sealed = BASE64.decode(sealed).context("malformed sealed message")?;
+        let message = (self.0)
+            .open_in_place(zero_nonce(), Aad::empty(), &mut sealed)
+            .ok()
+            .context("could not open sealed message")?;
+        String::from_utf8(message.to_vec()).context("sealed message is not UTF-8")
+    }
+}
+
+fn zero_nonce() -> Nonce {
+    Nonce::assume_unique_for_key([0; aead::NONCE_LEN])
+}
+
+fn decode_hex<const N: usize>(input: &str) -> Option<[u8; N]> {
+    hex::decode(input).ok()?.try_into().ok()
+}
+
+/// What the server signs: the client's nonce and key share, then the
+/// server's key share and public key.
+fn transcript(
+    request: &IdentityRequest,
+    server_share: &[u8],
+    public_key: &[u8],
+) -> Result<Vec<u8>> {
+    let client_share: [u8; 32] =
+        decode_hex(&request.key_share).context("malformed client key share")?;
+    Ok([
+        request.nonce.as_bytes().as_slice(),
+        &client_share,
+        server_share,
+        public_key,
+    ]
+    .concat())
+}
+
+/// Private identity key held by the server.
+pub struct ServerIdentity(SigningKey);
+
+impl ServerIdentity {
+    /// Generate a new random identity.
+    pub fn generate() -> Result<Self> {
+        let mut seed = [0u8; 32];
+        getrandom::getrandom(&mut seed).context("failed to generate identity key")?;
+        Ok(Self(SigningKey::from_bytes(&seed)))
+    }
+
+    /// Load an identity from a file, generating and saving a new one if missing.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("could not read identity key {}", path.display()))?;
+            let seed: [u8; 32] = hex::decode(contents.trim())
+                .ok()
+                .and_then(|seed| seed.try_into().ok())
+                .context("identity key file must contain 32 hex-encoded bytes")?;
+            return Ok(Self(SigningKey::from_bytes(&seed)));
+        }
+
+        let identity = Self::generate()?;
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("could not create identity key {}", path.display()))?;
+        writeln!(file, "{}", hex::encode(identity.0.to_bytes()))?;
+        Ok(identity)
+    }
+
+    /// Returns the hex-encoded public key, which clients can pin.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.0.verifying_key().as_bytes())
+    }
+
+    /// Answer an identity request from the client with a key share of our
+    /// own, signing the handshake, and derive the session key.
+    pub fn prove(&self, request: &IdentityRequest) -> Result<(IdentityProof, SessionKey)> {
+        let private = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .ok()
+            .context("failed to generate key share")?;
+        let share = private
+            .compute_public_key()
+            .ok()
+            .context("invalid key share")?;
+        let public_key = self.0.verifying_key().to_bytes();
+        let transcript = transcript(request, share.as_ref(), &public_key)?;
+        let signature = self.0.sign(&[PROOF_CONTEXT, &transcript].concat());
+        let client_share = hex::decode(&request.key_share)?;
+        let proof = IdentityProof {
+            public_key: self.public_key(),
+            key_share: hex::encode(share.as_ref()),
+            signature: hex::encode(signature.to_bytes()),
+        };
+        Ok((
+            proof,
+            SessionKey::agree(private, &client_share, &transcript)?,
+        ))
+    }
+
+    /// Sign a message with the identity key, after a prefix that names what
+    /// the signature is for. Prefixes must differ between purposes, and from
+    /// that of identity proofs, which they cannot start with.
+    pub fn sign(&self, context: &[u8], message: &[u8]) -> [u8; 64] {
+        assert!(!context.starts_with(PROOF_CONTEXT) && !PROOF_CONTEXT.starts_with(context));
+        self.0.sign(&[context, message].concat()).to_bytes()
+    }
+
+    /// Check a signature made with [`ServerIdentity::sign`].
+    pub fn verify(&self, context: &[u8], message: &[u8], signature: &[u8; 64]) -> bool {
+        self.0
+            .verify(
+                &[context, message].concat(),
+                &Signature::from_bytes(signature),
+            )
+            .is_ok()
+    }
+
+    /// As the server, receive the next client message, answering any identity
+    /// requests that arrive before it. Credentials sent after an identity
+    /// request are sealed with its session key, and are opened here.
+    pub async fn recv<T: AsyncRead + AsyncWrite + Unpin>(
+        identity: Option<&Self>,
+        stream: &mut Delimited<T>,
+    ) -> Result<Option<ClientMessage>> {
+        let mut session = None;
+        loop {
+            match (stream.recv_timeout().await?, &session) {
+                (Some(ClientMessage::Identify(request)), _) => match identity {
+                    Some(identity) => {
+                        let (proof, key) = identity.prove(&request)?;
+                        stream.send(ServerMessage::Identity(proof)).await?;
+                        session = Some(key);
+                    }
+                    None => bail!("client requested an identity, but none is configured"),
+                },
+                (Some(ClientMessage::Authenticate(sealed)), Some(key)) => {
+                    return Ok(Some(ClientMessage::Authenticate(key.open(&sealed)?)));
+                }
+                (message, _) => return Ok(message),
+            }
+        }
+    }
+}
+
+/// File of server identities that the client has seen before.
+///
+/// Each line holds a server address and its hex-encoded public key, separated
+/// by whitespace, similar to an SSH `known_hosts` file.
+pub struct KnownServers {
+    path: PathBuf,
+}
+
+impl KnownServers {
+    /// Use the known servers file at this path, which need not exist yet.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Look up the recorded public key of a server.
+    pub fn get(&self, server: &str) -> Result<Option<String>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(contents.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            (fields.next() == Some(server)).then(|| fields.next().unwrap_or_default().to_string())
+        }))
+    }
+
+    /// Check a server's public key, recording it if the server is new.
+    pub fn verify(&self, server: &str, public_key: &str) -> Result<()> {
+        match self.get(server)? {
+            Some(known) => ensure!(
+                known == public_key,
+                "identity of server {server} has changed (expected {known}, got {public_key}), \
+                 refusing to connect"
+            ),
+            None => {
+                warn!(%server, %public_key, "trusting new server identity");
+                if let Some(parent) = self.path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .with_context(|| format!("could not write {}", self.path.display()))?;
+                writeln!(file, "{server} {public_key}")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,841 @@
+//! Inspecting the HTTP traffic of a tunnel on the client.
+//!
+//! While debugging a webhook or an app behind a tunnel, it helps to see what
+//! visitors actually sent and what the local service answered. The client
+//! reads the requests and responses that pass to and from the local service,
+//! keeps the most recent ones in memory, and serves them on a local address:
+//!
+//! ```text
+//! GET    /                          ->  page that lists the requests
+//! GET    /api/requests              ->  [{"id": ..., "request": {...}, "response": {...}}]
+//! GET    /api/requests/<id>         ->  {"id": ..., "request": {...}, "response": {...}}
+//! POST   /api/requests/<id>/replay  ->  {"id": ..., "replay_of": ..., ...}
+//! DELETE /api/requests              ->  204 No Content
+//! ```
+//!
+//! Replaying a request sends it to the local service again, as the service
+//! received it the first time, and records the new exchange. `bore replay`
+//! does the same from the command line, for the most recent request or the
+//! one with an ID.
+//!
+//! Bodies are kept up to [`MAX_BODY_LENGTH`] bytes, as base64 in the API.
+//! Connections that do not carry HTTP/1 are passed through untouched, and so
+//! is the rest of a connection once it switches protocols.
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Context as _, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::daemon::{error_response, json_content, json_response, request_host};
+use crate::framing::Framing;
+use crate::http::{RequestHead, MAX_HEAD_LENGTH};
+use crate::shared::NETWORK_TIMEOUT;
+
+/// Most exchanges that are kept, dropping the oldest first.
+pub const MAX_EXCHANGES: usize = 100;
+
+/// Most bytes of each body that are kept.
+pub const MAX_BODY_LENGTH: usize = 256 * 1024;
+
+/// Time that the local service has to answer a replayed request.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Page of the inspector, which lists the requests through the API.
+const PAGE: &str = include_str!("inspect.html");
+
+/// Request and response that passed through the tunnel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exchange {
+    /// Identifier of the exchange, to look it up or replay it.
+    pub id: Uuid,
+
+    /// Unix timestamp in seconds when the request reached the local service.
+    pub time: u64,
+
+    /// Local host of the tunnel that the request went to.
+    pub local_host: String,
+
+    /// Local port of the tunnel that the request went to.
+    pub local_port: u16,
+
+    /// Request as the local service received it.
+    pub request: CapturedRequest,
+
+    /// Response of the local service, unless it has not answered yet.
+    pub response: Option<CapturedResponse>,
+
+    /// Milliseconds from the end of the request to the end of the response.
+    pub duration_ms: Option<u64>,
+
+    /// Exchange that this one replayed, if it is a replay.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay_of: Option<Uuid>,
+}
+
+/// Request that passed through the tunnel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    /// Method of the request, such as `POST`.
+    pub method: String,
+
+    /// Path and query of the request.
+    pub path: String,
+
+    /// Headers in the order they were sent.
+    pub headers: Vec<(String, String)>,
+
+    /// Start of the body, without any chunked framing.
+    #[serde(with = "base64_bytes")]
+    pub body: Vec<u8>,
+
+    /// Length of the whole body in bytes.
+    pub body_length: u64,
+}
+
+/// Response that the local service sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedResponse {
+    /// Status code, such as 200.
+    pub status: u16,
+
+    /// Reason phrase after the status code.
+    pub reason: String,
+
+    /// Headers in the order they were sent.
+    pub headers: Vec<(String, String)>,
+
+    /// Start of the body, without any chunked framing.
+    #[serde(with = "base64_bytes")]
+    pub body: Vec<u8>,
+
+    /// Length of the whole body in bytes.
+    pub body_length: u64,
+}
+
+impl CapturedRequest {
+    /// Whether the body was too long to keep in full.
+    pub fn truncated(&self) -> bool {
+        (self.body.len() as u64) < self.body_length
+    }
+}
+
+/// Recent exchanges through the tunnels of a client.
+///
+/// ```
+/// use std::sync::Arc;
+/// use bore_cli::inspect::{Inspected, Inspector};
+/// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let inspector = Arc::new(Inspector::new());
+/// let (near, mut service) = tokio::io::duplex(1024);
+/// let recorder = inspector.recorder("localhost", 8000);
+/// let mut conn = Inspected::new(near, Some(recorder));
+///
+/// conn.write_all(b"POST /hook HTTP/1.1\r\nContent-Length: 4\r\n\r\nping").await.unwrap();
+/// service.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 4\r\n\r\npong").await.unwrap();
+/// drop(service);
+/// conn.read_to_end(&mut Vec::new()).await.unwrap();
+///
+/// let exchange = &inspector.exchanges()[0];
+/// assert_eq!((exchange.request.method.as_str(), exchange.request.path.as_str()), ("POST", "/hook"));
+/// assert_eq!(exchange.request.body, b"ping");
+/// let response = exchange.response.as_ref().unwrap();
+/// assert_eq!((response.status, response.body.as_slice()), (201, &b"pong"[..]));
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct Inspector {
+    /// Exchanges, oldest first.
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl Inspector {
+    /// Create an inspector with no exchanges.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Recorder for a new connection to a local service.
+    pub fn recorder(self: &Arc<Self>, local_host: &str, local_port: u16) -> Recorder {
+        Recorder {
+            inspector: Arc::clone(self),
+            local_host: local_host.to_string(),
+            local_port,
+            requests: Reader::new(),
+            responses: Reader::new(),
+            waiting: VecDeque::new(),
+            replay: None,
+            done: false,
+        }
+    }
+
+    /// Exchanges that are kept, newest first.
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        let exchanges = self.exchanges.lock().unwrap();
+        exchanges.iter().rev().cloned().collect()
+    }
+
+    /// Exchange with an identifier, if it is still kept.
+    pub fn get(&self, id: &Uuid) -> Option<Exchange> {
+        let exchanges = self.exchanges.lock().unwrap();
+        exchanges
+            .iter()
+            .find(|exchange| exchange.id == *id)
+            .cloned()
+    }
+
+    /// Forget all exchanges.
+    pub fn clear(&self) {
+        self.exchanges.lock().unwrap().clear();
+    }
+
+    /// Send the request of an exchange to its local service again, and
+    /// return the new exchange once the service has answered.
+    pub async fn replay(self: &Arc<Self>, exchange: &Exchange) -> Result<Exchange> {
+        let request = &exchange.request;
+        ensure!(
+            !request.truncated(),
+            "the body of the request was too long to keep"
+        );
+        let mut head = RequestHead {
+            method: request.method.clone(),
+            path: request.path.clone(),
+            version: 1,
+            headers: request.headers.clone(),
+            length: 0,
+        };
+        // The body was kept without its framing.
+        head.remove_header("Transfer-Encoding");
+        if !request.body.is_empty() || head.header("content-length").is_some() {
+            head.set_header("Content-Length", &request.body.len().to_string());
+        }
+        head.set_header("Connection", "close");
+
+        let (host, port) = (exchange.local_host.as_str(), exchange.local_port);
+        let conn = timeout(NETWORK_TIMEOUT, TcpStream::connect((host, port)))
+            .await
+            .context("timed out connecting to the local service")?
+            .context("local service unreachable")?;
+        let id = Uuid::new_v4();
+        let mut recorder = self.recorder(host, port);
+        recorder.replay = Some((id, exchange.id));
+        let mut conn = Inspected::new(conn, Some(recorder));
+        let exchanged = async {
+            conn.write_all(&head.to_bytes()).await?;
+            conn.write_all(&request.body).await?;
+            tokio::io::copy(&mut conn, &mut tokio::io::sink()).await
+        };
+        timeout(REPLAY_TIMEOUT, exchanged)
+            .await
+            .context("timed out waiting for the local service")??;
+        drop(conn);
+        match self.get(&id) {
+            Some(replayed) if replayed.response.is_some() => Ok(replayed),
+            _ => bail!("local service closed the connection without a response"),
+        }
+    }
+
+    /// Keep a new exchange, dropping the oldest if there are too many.
+    fn add(&self, exchange: Exchange) {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        if exchanges.len() >= MAX_EXCHANGES {
+            exchanges.pop_front();
+        }
+        exchanges.push_back(exchange);
+    }
+
+    /// Attach the response to the request of an exchange.
+    fn respond(&self, id: Uuid, response: CapturedResponse, duration: Duration) {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        if let Some(exchange) = exchanges
+            .iter_mut()
+            .rev()
+            .find(|exchange| exchange.id == id)
+        {
+            exchange.response = Some(response);
+            exchange.duration_ms = Some(duration.as_millis() as u64);
+        }
+    }
+}
+
+/// Reader of the requests and responses on one connection to a local service,
+/// which records them with its inspector.
+pub struct Recorder {
+    inspector: Arc<Inspector>,
+    local_host: String,
+    local_port: u16,
+    requests: Reader<RequestHead>,
+    responses: Reader<ResponseHead>,
+
+    /// Requests that wait for their response, oldest first, with whether
+    /// they are `HEAD` requests and when they ended.
+    waiting: VecDeque<(Uuid, bool, Instant)>,
+
+    /// Identifier to give the first request, and the exchange it replays.
+    replay: Option<(Uuid, Uuid)>,
+
+    /// Whether the connection stopped being recorded.
+    done: bool,
+}
+
+impl Recorder {
+    /// Read bytes on their way to the local service.
+    fn requests(&mut self, bytes: &[u8]) {
+        if !self.done {
+            let result = self.read_requests(bytes);
+            self.stop_on_error(result);
+        }
+    }
+
+    /// Read bytes on their way from the local service.
+    fn responses(&mut self, bytes: &[u8]) {
+        if !self.done {
+            let result = self.read_responses(bytes);
+            self.stop_on_error(result);
+        }
+    }
+
+    /// Read the end of the responses, which may end a body that lasts until
+    /// the connection closes.
+    fn finish(&mut self) {
+        if self.done {
+            return;
+        }
+        self.done = true;
+        if let Some(message) = self.responses.finish() {
+            self.record_response(message);
+        }
+    }
+
+    fn stop_on_error(&mut self, result: Result<()>) {
+        if let Err(err) = result {
+            debug!(%err, "stopped inspecting connection");
+            self.done = true;
+        }
+    }
+
+    fn read_requests(&mut self, bytes: &[u8]) -> Result<()> {
+        self.requests.input.extend_from_slice(bytes);
+        while let Some(message) = self.requests.next(parse_request, Framing::request)? {
+            let (id, replay_of) = match self.replay.take() {
+                Some((id, replay_of)) => (id, Some(replay_of)),
+                None => (Uuid::new_v4(), None),
+            };
+            let head = message.head.method.eq_ignore_ascii_case("HEAD");
+            self.waiting.push_back((id, head, Instant::now()));
+            let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            self.inspector.add(Exchange {
+                id,
+                time,
+                local_host: self.local_host.clone(),
+                local_port: self.local_port,
+                request: CapturedRequest {
+                    method: message.head.method,
+                    path: message.head.path,
+                    headers: message.head.headers,
+                    body: message.body,
+                    body_length: message.body_length,
+                },
+                response: None,
+                duration_ms: None,
+                replay_of,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_responses(&mut self, bytes: &[u8]) -> Result<()> {
+        self.responses.input.extend_from_slice(bytes);
+        loop {
+            let head_request = self.waiting.front().is_some_and(|(_, head, _)| *head);
+            let framing = |response: &ResponseHead| response_framing(response, head_request);
+            let Some(message) = self.responses.next(parse_response, framing)? else {
+                return Ok(());
+            };
+            ensure!(self.record_response(message), "response without a request");
+        }
+    }
+
+    /// Attach a response to the oldest request that waits for one, unless
+    /// it is an interim response, returning whether it found its request.
+    fn record_response(&mut self, message: Message<ResponseHead>) -> bool {
+        let status = message.head.status;
+        // Interim responses, such as 100 Continue, precede the real one.
+        if (100..200).contains(&status) && status != 101 {
+            return true;
+        }
+        let Some((id, _, since)) = self.waiting.pop_front() else {
+            return false;
+        };
+        let response = CapturedResponse {
+            status,
+            reason: message.head.reason,
+            headers: message.head.headers,
+            body: message.body,
+            body_length: message.body_length,
+        };
+        self.inspector.respond(id, response, since.elapsed());
+        true
+    }
+}
+
+/// Connection to a local service whose HTTP traffic is recorded, if it has a
+/// recorder. Bytes pass through unchanged.
+pub struct Inspected<S> {
+    inner: S,
+    recorder: Option<Recorder>,
+}
+
+impl<S> Inspected<S> {
+    /// Record the traffic of a connection with a recorder, if any.
+    pub fn new(inner: S, recorder: Option<Recorder>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<S> Drop for Inspected<S> {
+    fn drop(&mut self) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.finish();
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Inspected<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(recorder) = &mut this.recorder {
+            match &buf.filled()[before..] {
+                [] => recorder.finish(),
+                read => recorder.responses(read),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Inspected<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if let Some(recorder) = &mut this.recorder {
+            recorder.requests(&buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Splitter of the HTTP messages on a connection, which picks out their
+/// heads and leaves out their bodies.
+pub(crate) struct Heads {
+    requests: Reader<RequestHead>,
+    responses: Reader<ResponseHead>,
+
+    /// Whether each request that waits for its response is a `HEAD`
+    /// request, oldest first.
+    waiting: VecDeque<bool>,
+
+    /// Whether the connection turned out not to carry HTTP/1.
+    failed: bool,
+}
+
+impl Heads {
+    pub(crate) fn new() -> Self {
+        Self {
+            requests: Reader::heads_only(),
+            responses: Reader::heads_only(),
+            waiting: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    /// Heads among bytes on their way to the local service.
+    pub(crate) fn requests(&mut self, bytes: &[u8]) -> Vec<u8> {
+        self.requests.input.extend_from_slice(bytes);
+        while !self.failed {
+            match self.requests.next(parse_request, Framing::request) {
+                Ok(Some(message)) => {
+                    let head = message.head.method.eq_ignore_ascii_case("HEAD");
+                    self.waiting.push_back(head);
+                }
+                Ok(None) => break,
+                Err(_) => self.failed = true,
+            }
+        }
+        self.requests
+            .heads
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Heads among bytes on their way from the local service.
+    pub(crate) fn responses(&mut self, bytes: &[u8]) -> Vec<u8> {
+        self.responses.input.extend_from_slice(bytes);
+        while !self.failed {
+            let head_request = self.waiting.front().copied().unwrap_or_default();
+            let framing = |response: &ResponseHead| response_framing(response, head_request);
+            match self.responses.next(parse_response, framing) {
+                Ok(Some(message)) => {
+                    let status = message.head.status;
+                    if !(100..200).contains(&status) || status == 101 {
+                        self.waiting.pop_front();
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => self.failed = true,
+            }
+        }
+        self.responses
+            .heads
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+/// Head of a response from the local service.
+struct ResponseHead {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+}
+
+/// Message read in full, with as much of its body as is kept.
+struct Message<H> {
+    head: H,
+    body: Vec<u8>,
+    body_length: u64,
+}
+
+/// Reader of the messages in one direction of a connection.
+struct Reader<H> {
+    state: Framing,
+
+    /// Bytes that are not yet read.
+    input: Vec<u8>,
+
+    /// Head of the message whose body is being read.
+    head: Option<H>,
+    body: Vec<u8>,
+    body_length: u64,
+
+    /// Bytes of the heads that were read, if only heads are kept.
+    heads: Option<Vec<u8>>,
+}
+
+impl<H> Reader<H> {
+    fn new() -> Self {
+        Self {
+            state: Framing::Head,
+            input: Vec::new(),
+            head: None,
+            body: Vec::new(),
+            body_length: 0,
+            heads: None,
+        }
+    }
+
+    /// Reader that keeps the bytes of heads, but no bodies.
+    fn heads_only() -> Self {
+        Self {
+            heads: Some(Vec::new()),
+            ..Self::new()
+        }
+    }
+
+    /// Next message that the input completes, if any, with heads read by
+    /// `parse` and bodies framed as `framing` decides from their head.
+    fn next(
+        &mut self,
+        parse: impl Fn(&[u8]) -> Result<Option<(H, usize)>>,
+        framing: impl FnOnce(&H) -> Result<Framing>,
+    ) -> Result<Option<Message<H>>> {
+        let mut framing = Some(framing);
+        loop {
+            match self.state {
+                Framing::Head => {
+                    let Some((head, length)) = parse(&self.input)? else {
+                        ensure!(
+                            self.input.len() <= MAX_HEAD_LENGTH,
+                            "message head is too long"
+                        );
+                        return Ok(None);
+                    };
+                    let Some(framing) = framing.take() else {
+                        return Ok(None);
+                    };
+                    if let Some(heads) = &mut self.heads {
+                        heads.extend_from_slice(&self.input[..length]);
+                    }
+                    self.input.drain(..length);
+                    self.state = framing(&head)?;
+                    self.head = Some(head);
+                    self.body.clear();
+                    self.body_length = 0;
+                    if matches!(self.state, Framing::Head | Framing::Raw) {
+                        return Ok(self.complete());
+                    }
+                }
+                Framing::UntilClose => {
+                    self.keep(self.input.len());
+                    self.input.clear();
+                    return Ok(None);
+                }
+                Framing::Raw => {
+                    self.input.clear();
+                    return Ok(None);
+                }
+                _ => {
+                    let Some(step) = self.state.step(&self.input)? else {
+                        return Ok(None);
+                    };
+                    self.keep(step.data);
+                    self.input.drain(..step.length);
+                    if self.state == Framing::Head {
+                        return Ok(self.complete());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Message whose body lasted until the connection closed, if any.
+    fn finish(&mut self) -> Option<Message<H>> {
+        match self.state {
+            Framing::UntilClose => self.complete(),
+            _ => None,
+        }
+    }
+
+    /// Count the first `n` bytes of the input as body, keeping what fits.
+    fn keep(&mut self, n: usize) {
+        let room = match self.heads {
+            Some(_) => 0,
+            None => MAX_BODY_LENGTH.saturating_sub(self.body.len()),
+        };
+        self.body.extend_from_slice(&self.input[..n.min(room)]);
+        self.body_length += n as u64;
+    }
+
+    /// End the message that is being read.
+    fn complete(&mut self) -> Option<Message<H>> {
+        if self.state != Framing::Raw {
+            self.state = Framing::Head;
+        }
+        Some(Message {
+            head: self.head.take()?,
+            body: std::mem::take(&mut self.body),
+            body_length: self.body_length,
+        })
+    }
+}
+
+fn parse_request(bytes: &[u8]) -> Result<Option<(RequestHead, usize)>> {
+    Ok(RequestHead::parse(bytes)?.map(|head| {
+        let length = head.length;
+        (head, length)
+    }))
+}
+
+fn parse_response(bytes: &[u8]) -> Result<Option<(ResponseHead, usize)>> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let length = match response.parse(bytes).context("malformed HTTP response")? {
+        httparse::Status::Complete(length) => length,
+        httparse::Status::Partial => return Ok(None),
+    };
+    let head = ResponseHead {
+        status: response.code.unwrap_or_default(),
+        reason: response.reason.unwrap_or_default().to_string(),
+        headers: (response.headers.iter())
+            .map(|header| {
+                let value = String::from_utf8_lossy(header.value).into_owned();
+                (header.name.to_string(), value)
+            })
+            .collect(),
+    };
+    Ok(Some((head, length)))
+}
+
+/// What follows the head of a response, to a `HEAD` request or not.
+fn response_framing(head: &ResponseHead, head_request: bool) -> Result<Framing> {
+    Framing::response(head.status, &head.headers, head_request)
+}
+
+/// Serve the inspector on an address until an error occurs.
+pub async fn serve(addr: SocketAddr, inspector: Arc<Inspector>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let inspector = Arc::clone(&inspector);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let inspector = Arc::clone(&inspector);
+                async move { Ok::<_, Infallible>(handle(&inspector, addr, req).await) }
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(&addr)?.serve(make_service);
+    info!("inspecting traffic at http://{addr}");
+    server.await?;
+    Ok(())
+}
+
+async fn handle(
+    inspector: &Arc<Inspector>,
+    addr: SocketAddr,
+    req: Request<Body>,
+) -> Response<Body> {
+    if !bound_host(&req, addr) {
+        return error_response(StatusCode::FORBIDDEN, "host is not the inspector's address");
+    }
+    if !json_content(&req) {
+        return error_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected application/json",
+        );
+    }
+    let path = req.uri().path().trim_end_matches('/');
+    let id = path.strip_prefix("/api/requests/");
+    let (id, replay) = match id.and_then(|id| id.strip_suffix("/replay")) {
+        Some(id) => (Some(id), true),
+        None => (id, false),
+    };
+    let exchange = id
+        .and_then(|id| id.parse().ok())
+        .and_then(|id| inspector.get(&id));
+    match (req.method(), path) {
+        (&Method::GET, "") => Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(PAGE))
+            .unwrap(),
+        (&Method::GET, "/api/requests") => json_response(StatusCode::OK, &inspector.exchanges()),
+        (&Method::DELETE, "/api/requests") => {
+            inspector.clear();
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap()
+        }
+        (_, _) if id.is_some() && exchange.is_none() => {
+            error_response(StatusCode::NOT_FOUND, "no such request")
+        }
+        (&Method::GET, _) if id.is_some() && !replay => json_response(StatusCode::OK, &exchange),
+        (&Method::POST, _) if id.is_some() && replay => {
+            let exchange = exchange.expect("request was found");
+            if exchange.request.truncated() {
+                let message = "the body of the request was too long to keep";
+                return error_response(StatusCode::CONFLICT, message);
+            }
+            match inspector.replay(&exchange).await {
+                Ok(replayed) => json_response(StatusCode::OK, &replayed),
+                Err(err) => error_response(StatusCode::BAD_GATEWAY, format!("{err:#}")),
+            }
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Whether a request names the address that the inspector listens on as its
+/// host, which a page that rebinds its own domain to that address cannot.
+fn bound_host(req: &Request<Body>, addr: SocketAddr) -> bool {
+    let Some((host, port)) = request_host(req) else {
+        return false;
+    };
+    if port.unwrap_or(80) != addr.port() {
+        return false;
+    }
+    let ip = addr.ip();
+    match host.parse::<IpAddr>() {
+        Ok(host) => host == ip || ip.is_unspecified(),
+        Err(_) => {
+            host.eq_ignore_ascii_case("localhost") && (ip.is_loopback() || ip.is_unspecified())
+        }
+    }
+}
+
+/// Ask the inspector of a running client to replay a request, or its most
+/// recent one, returning the new exchange.
+pub async fn replay(addr: SocketAddr, id: Option<Uuid>) -> Result<Exchange> {
+    let unreachable = || format!("could not reach the inspector at {addr}, is it running?");
+    let id = match id {
+        Some(id) => id,
+        None => {
+            let url = format!("http://{addr}/api/requests");
+            let response = reqwest::get(&url).await.with_context(unreachable)?;
+            let exchanges: Vec<Exchange> = api_result(response).await?;
+            let latest = exchanges
+                .first()
+                .context("no requests have been recorded")?;
+            latest.id
+        }
+    };
+    let url = format!("http://{addr}/api/requests/{id}/replay");
+    let request = reqwest::Client::new()
+        .post(&url)
+        .header("Content-Type", "application/json");
+    let response = (request.send()).await.with_context(unreachable)?;
+    api_result(response).await
+}
+
+/// Value in a successful response from the inspector, or its error.
+async fn api_result<T: DeserializeOwned>(response: reqwest::Response) -> Result<T> {
+    if response.status().is_success() {
+        return Ok(response.json().await?);
+    }
+    let status = response.status();
+    let body: serde_json::Value = response.json().await.unwrap_or_default();
+    match body["error"].as_str() {
+        Some(error) => bail!("{error}"),
+        None => bail!("inspector returned {status}"),
+    }
+}
+
+/// Bytes as a base64 string.
+pub(crate) mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64.decode(encoded).map_err(D::Error::custom)
+    }
+}
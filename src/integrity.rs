@@ -0,0 +1,212 @@
+//! Integrity checksums over proxied data streams.
+//!
+//! When enabled, the client and server each hash the bytes they send and
+//! receive on every data connection. At close the server reports its hashes
+//! over the control connection, and the client logs any difference, which
+//! points to corruption by a middlebox between them.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::shared::bounded_string;
+
+/// How long to wait for the other side's checksums of a stream.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Summary of the bytes that passed through a stream in one direction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamDigest {
+    /// Number of bytes.
+    pub bytes: u64,
+
+    /// Hex-encoded SHA-256 hash of the bytes.
+    #[serde(deserialize_with = "bounded_string")]
+    pub sha256: String,
+}
+
+/// Checksums of a data connection as seen by the server, sent at close.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamChecksum {
+    /// Connection that the checksums belong to.
+    pub id: Uuid,
+
+    /// Bytes the server sent to the client.
+    pub sent: StreamDigest,
+
+    /// Bytes the server received from the client.
+    pub received: StreamDigest,
+}
+
+#[derive(Default)]
+struct Hasher {
+    bytes: u64,
+    hash: Sha256,
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.bytes += data.len() as u64;
+        self.hash.update(data);
+    }
+
+    fn digest(&self) -> StreamDigest {
+        StreamDigest {
+            bytes: self.bytes,
+            sha256: hex::encode(self.hash.clone().finalize()),
+        }
+    }
+}
+
+/// Stream wrapper that hashes the bytes read from and written to it.
+pub struct Checksummed<S> {
+    inner: S,
+    read: Option<Hasher>,
+    written: Option<Hasher>,
+}
+
+impl<S> Checksummed<S> {
+    /// Wrap a stream, hashing its traffic only if `enabled` is set.
+    pub fn new(inner: S, enabled: bool) -> Self {
+        Self {
+            inner,
+            read: enabled.then(Hasher::default),
+            written: enabled.then(Hasher::default),
+        }
+    }
+
+    /// Get a reference to the wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Digest of the bytes read so far, if hashing is enabled.
+    pub fn read_digest(&self) -> Option<StreamDigest> {
+        self.read.as_ref().map(Hasher::digest)
+    }
+
+    /// Digest of the bytes written so far, if hashing is enabled.
+    pub fn written_digest(&self) -> Option<StreamDigest> {
+        self.written.as_ref().map(Hasher::digest)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Checksummed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(hasher)) = (&poll, &mut this.read) {
+            hasher.update(&buf.filled()[start..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Checksummed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(hasher)) = (&poll, &mut this.written) {
+            hasher.update(&buf[..*n]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+enum Pending {
+    /// Checksums computed by the client, as (sent, received).
+    Local(StreamDigest, StreamDigest),
+
+    /// Checksums reported by the server.
+    Remote(StreamChecksum),
+}
+
+/// Client-side record of stream checksums waiting for the other side's.
+#[derive(Default)]
+pub struct ChecksumLedger {
+    pending: DashMap<Uuid, Pending>,
+}
+
+impl ChecksumLedger {
+    /// Record the client's checksums for a connection that has closed.
+    pub fn record_local(self: &Arc<Self>, id: Uuid, sent: StreamDigest, received: StreamDigest) {
+        match self.pending.remove(&id) {
+            Some((_, Pending::Remote(remote))) => compare(id, &sent, &received, &remote),
+            _ => self.wait(id, Pending::Local(sent, received)),
+        }
+    }
+
+    /// Record the checksums reported by the server for a connection.
+    pub fn record_remote(self: &Arc<Self>, remote: StreamChecksum) {
+        let id = remote.id;
+        match self.pending.remove(&id) {
+            Some((_, Pending::Local(sent, received))) => compare(id, &sent, &received, &remote),
+            _ => self.wait(id, Pending::Remote(remote)),
+        }
+    }
+
+    fn wait(self: &Arc<Self>, id: Uuid, side: Pending) {
+        self.pending.insert(id, side);
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            tokio::time::sleep(PENDING_TIMEOUT).await;
+            if this.pending.remove(&id).is_some() {
+                debug!(%id, "no checksums from the other side of the connection");
+            }
+        });
+    }
+}
+
+/// Check the client's view of a connection against the server's.
+fn compare(id: Uuid, sent: &StreamDigest, received: &StreamDigest, remote: &StreamChecksum) {
+    let mut intact = true;
+    if *sent != remote.received {
+        intact = false;
+        warn!(
+            %id,
+            sent_bytes = sent.bytes,
+            received_bytes = remote.received.bytes,
+            "checksum mismatch on data sent to the server"
+        );
+    }
+    if *received != remote.sent {
+        intact = false;
+        warn!(
+            %id,
+            sent_bytes = remote.sent.bytes,
+            received_bytes = received.bytes,
+            "checksum mismatch on data received from the server"
+        );
+    }
+    if intact {
+        debug!(%id, "stream checksums match");
+    }
+}
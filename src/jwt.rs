@@ -0,0 +1,189 @@
+//! Authentication with JSON Web Tokens, verified against a published key set.
+//!
+//! Identity providers that issue signed JWTs publish their public keys as a
+//! JWKS document. The server fetches it once and verifies the signature,
+//! issuer, audience, and expiry of each token locally, so that handshakes do
+//! not wait on a validation backend. Clients present the token in place of an
+//! API key, and its claims set the user and limits like the backend would:
+//!
+//! ```json
+//! { "sub": "acme", "exp": 1767225600, "min_port": 20000, "max_port": 20999 }
+//! ```
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use futures_util::future::{BoxFuture, FutureExt};
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk, JwkSet};
+use jsonwebtoken::{DecodingKey, Validation};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::auth::{AuthProvider, Principal, Quota};
+use crate::shared::{AuthError, AuthErrorCode};
+
+/// Time after which the key set is fetched again, to pick up rotated keys.
+const JWKS_MAX_AGE: Duration = Duration::from_secs(600);
+
+/// Least time between fetches of the key set for tokens signed by unknown
+/// keys, so that such tokens cannot be used to flood the identity provider.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(30);
+
+/// Claims of a token that the server uses, besides those it validates.
+#[derive(Deserialize)]
+struct Claims {
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(flatten)]
+    limits: Quota,
+}
+
+/// Authenticator that verifies JWTs with the keys of a JWKS document.
+pub struct JwtAuthenticator {
+    jwks_url: String,
+    client: reqwest::Client,
+    issuer: Option<String>,
+    audience: Option<String>,
+
+    /// Keys from the last fetch of the key set, with when they were fetched.
+    keys: Mutex<Option<(Instant, JwkSet)>>,
+}
+
+impl JwtAuthenticator {
+    /// Create an authenticator with the URL of the key set.
+    pub fn new(jwks_url: String) -> Self {
+        Self {
+            jwks_url,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .expect("failed to create HTTP client"),
+            issuer: None,
+            audience: None,
+            keys: Mutex::new(None),
+        }
+    }
+
+    /// Only accept tokens with this `iss` claim.
+    pub fn set_issuer(&mut self, issuer: String) {
+        self.issuer = Some(issuer);
+    }
+
+    /// Only accept tokens with this value in their `aud` claim.
+    pub fn set_audience(&mut self, audience: String) {
+        self.audience = Some(audience);
+    }
+
+    /// Verify a token, returning its claims.
+    async fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        let invalid = |reason: String| AuthError::new(AuthErrorCode::InvalidApiKey, reason);
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|err| invalid(format!("invalid token: {err}")))?;
+        let jwk = self
+            .key(header.kid.as_deref())
+            .await?
+            .ok_or_else(|| invalid("token is signed by an unknown key".into()))?;
+        let key = match &jwk.algorithm {
+            // Keys shared with the server would let anyone holding the key
+            // set sign tokens.
+            AlgorithmParameters::OctetKey(_) => None,
+            _ => DecodingKey::from_jwk(&jwk).ok(),
+        }
+        .ok_or_else(|| invalid("token is signed by an unsupported key".into()))?;
+
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            validation.set_required_spec_claims(&["exp", "iss"]);
+        }
+        match &self.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                validation.required_spec_claims.insert("aud".into());
+            }
+            None => validation.validate_aud = false,
+        }
+        let data = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|err| invalid(format!("invalid token: {err}")))?;
+        Ok(data.claims)
+    }
+
+    /// Find the key with this ID, or the only key if the token names none,
+    /// fetching the key set when it is missing, old, or lacks the key.
+    async fn key(&self, kid: Option<&str>) -> Result<Option<Jwk>, AuthError> {
+        let find = |keys: &JwkSet| match kid {
+            Some(kid) => keys.find(kid).cloned(),
+            None if keys.keys.len() == 1 => keys.keys.first().cloned(),
+            None => None,
+        };
+        let mut cached = self.keys.lock().await;
+        let stale = match &*cached {
+            Some((fetched, keys)) => {
+                fetched.elapsed() > JWKS_MAX_AGE
+                    || (find(keys).is_none() && fetched.elapsed() > JWKS_MIN_REFRESH)
+            }
+            None => true,
+        };
+        if stale {
+            match self.fetch().await {
+                Ok(keys) => *cached = Some((Instant::now(), keys)),
+                Err(err) => match &mut *cached {
+                    Some((fetched, _)) => {
+                        warn!(%err, "could not refresh JWKS, keeping the previous keys");
+                        *fetched = Instant::now();
+                    }
+                    None => {
+                        warn!(%err, "could not fetch JWKS");
+                        return Err(AuthError::new(
+                            AuthErrorCode::BackendUnavailable,
+                            "token signing keys are unavailable",
+                        ));
+                    }
+                },
+            }
+        }
+        Ok(cached.as_ref().and_then(|(_, keys)| find(keys)))
+    }
+
+    /// Fetch the key set from its URL.
+    async fn fetch(&self) -> Result<JwkSet> {
+        let response = self.client.get(&self.jwks_url).send().await?;
+        if !response.status().is_success() {
+            bail!("JWKS endpoint returned {}", response.status());
+        }
+        let keys: JwkSet = response.json().await?;
+        info!(keys = keys.keys.len(), "fetched JWKS");
+        Ok(keys)
+    }
+}
+
+impl AuthProvider for JwtAuthenticator {
+    fn method(&self) -> &str {
+        "jwt"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        _challenge: &'a Uuid,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<Principal, AuthError>> {
+        async move {
+            let claims = self.verify(token).await?;
+            Ok(Principal {
+                user_id: claims.sub,
+                quota: claims.limits,
+                ..Default::default()
+            })
+        }
+        .boxed()
+    }
+
+    fn missing(&self) -> AuthError {
+        AuthError::new(
+            AuthErrorCode::MethodNotSupported,
+            "server requires a token, passed as the API key",
+        )
+    }
+}
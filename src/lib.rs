@@ -15,7 +15,58 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+pub mod access_log;
+pub mod acl;
+pub mod acme;
+pub mod admin;
+pub mod announce;
 pub mod auth;
+pub mod broker;
+pub mod capture;
 pub mod client;
+pub mod config;
+#[cfg(feature = "conformance")]
+pub mod conformance;
+pub mod credentials;
+pub mod daemon;
+pub mod delegation;
+pub mod doctor;
+pub mod encryption;
+pub mod exit;
+pub mod framing;
+pub mod geoip;
+pub mod guard;
+pub mod handoff;
+pub mod heartbeat;
+pub mod http;
+pub mod identity;
+pub mod inspect;
+pub mod integrity;
+pub mod jwt;
+pub mod logging;
+pub mod messages;
+pub mod metrics;
+pub mod multiplex;
+pub mod oidc;
+pub mod policy;
+pub mod ports;
+pub mod process;
+pub mod proxy_protocol;
+pub mod ratelimit;
+pub mod reservation;
+pub mod rewrite;
+pub mod sampling;
 pub mod server;
+pub mod service;
 pub mod shared;
+pub mod sni;
+pub mod state;
+pub mod stats;
+pub mod striping;
+pub mod tls;
+pub mod transcript;
+pub mod transfer;
+pub mod udp;
+pub mod units;
+pub mod usage;
+pub mod websocket;
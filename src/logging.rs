@@ -0,0 +1,113 @@
+//! Log file output with size and time based rotation.
+
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// A log file that rotates itself once it grows too large or too old.
+///
+/// Rotated files are renamed with a numeric suffix, so `bore.log` becomes
+/// `bore.log.1`, the previous `bore.log.1` becomes `bore.log.2`, and so on.
+/// Files beyond the configured count are deleted.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    created: SystemTime,
+    max_size: u64,
+    max_age: Option<Duration>,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    /// Open a log file for appending, creating it if necessary.
+    pub fn open(path: impl AsRef<Path>, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            path,
+            file,
+            size: metadata.len(),
+            created: created(&metadata),
+            max_size,
+            max_age: None,
+            max_files,
+        })
+    }
+
+    /// Also rotate the file once it is this old, counting from when it was
+    /// created, even by an earlier process. The age cannot be zero.
+    pub fn set_max_age(&mut self, max_age: Duration) -> io::Result<()> {
+        if max_age.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "maximum age of log files cannot be zero",
+            ));
+        }
+        self.max_age = Some(max_age);
+        Ok(())
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        if self.size > 0 && self.size + incoming as u64 > self.max_size {
+            return true;
+        }
+        match self.max_age {
+            Some(max_age) => self.created.elapsed().unwrap_or_default() >= max_age,
+            None => false,
+        }
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            // A new file, rather than a truncated one, starts a new age.
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.created = SystemTime::now();
+        Ok(())
+    }
+}
+
+/// When a log file was created, or last written to on platforms that do not
+/// record creation times.
+fn created(metadata: &Metadata) -> SystemTime {
+    (metadata.created().or_else(|_| metadata.modified())).unwrap_or_else(|_| SystemTime::now())
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -1,47 +1,379 @@
-use std::net::IpAddr;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use std::{future, iter};
 
-use anyhow::Result;
-use bore_cli::{client::Client, server::Server};
-use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use anyhow::{bail, ensure, Context, Result};
+use bore_cli::{
+    access_log::AccessLog,
+    acl::{AccessList, Cidr},
+    acme::{self, Acme, AcmeConfig},
+    announce::Announce,
+    auth::{self, OutagePolicy, ValidationRequestOptions},
+    capture::{Capture, CaptureFormat, CaptureOptions},
+    client::{self, Client, ClientOptions, HostHeader, PortFallback, Session},
+    config::{ClientConfig, ServerConfig},
+    credentials::{self, Credentials},
+    daemon::{self, Daemon, TunnelInfo, TunnelState},
+    doctor, exit,
+    geoip::{CountryRules, GeoIp},
+    guard,
+    identity::ServerIdentity,
+    inspect::{self, Exchange, Inspector},
+    jwt::JwtAuthenticator,
+    logging::RotatingFile,
+    messages::{Catalog, Message, MessageId},
+    oidc::{self, OidcConfig},
+    policy::Policy,
+    ports::{self, HashedPorts, LeastRecentlyUsed, PortAllocator, RandomPorts, SequentialPorts},
+    process,
+    proxy_protocol::ProxyProtocol,
+    sampling::SampleSpec,
+    server::Server,
+    service,
+    shared::{
+        check_tunnel_name, host_port, LocalUnreachable, Observation, ObserveRequest, Scope,
+        SubKeyRequest, MAX_FRAME_LENGTH,
+    },
+    state::StateFile,
+    striping::MAX_STRIPES,
+    tls,
+    transcript::{self, Transcript},
+    units::{format_duration, format_size, parse_duration, parse_rate, parse_size},
+    usage::UsageReporter,
+};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use futures_util::future::try_join_all;
+use tokio::{signal, time};
+use tracing::{info, warn};
+use uuid::Uuid;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 struct Args {
     #[clap(subcommand)]
     command: Command,
+
+    /// Write logs to this file instead of the terminal.
+    #[clap(long, global = true, help_heading = "Logging", value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it exceeds this size.
+    #[clap(long, global = true, help_heading = "Logging", value_name = "SIZE", default_value = "10MiB", value_parser = parse_size)]
+    log_max_size: u64,
+
+    /// Rotate the log file once it is this old, regardless of size.
+    #[clap(long, global = true, help_heading = "Logging", value_name = "DURATION", value_parser = parse_duration)]
+    log_max_age: Option<Duration>,
+
+    /// Number of rotated log files to keep.
+    #[clap(long, global = true, help_heading = "Logging", default_value_t = 5)]
+    log_max_files: usize,
+
+    /// Format of messages for people, which apps that wrap the command can
+    /// set to `json` to get stable message IDs.
+    #[clap(long, global = true, help_heading = "Output", value_enum, default_value_t = MessageFormat::Text, env = "BORE_MESSAGE_FORMAT")]
+    message_format: MessageFormat,
+
+    /// TOML file of translated message templates, by message ID.
+    #[clap(
+        long,
+        global = true,
+        help_heading = "Output",
+        value_name = "PATH",
+        env = "BORE_MESSAGES"
+    )]
+    messages: Option<PathBuf>,
 }
 
+// Parsed once at startup, so the size of the server's options does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Command {
     /// Starts a local proxy to the remote server.
     Local {
-        /// The local port to expose.
-        #[clap(env = "BORE_LOCAL_PORT")]
-        local_port: u16,
+        /// The local port to expose, or `auto` to detect the port that the
+        /// `--exec` command listens on.
+        ///
+        /// Several ports can be given, optionally as `LOCAL:REMOTE` to select
+        /// the remote port, to open a tunnel for each over one connection.
+        #[clap(env = "BORE_LOCAL_PORT", value_name = "LOCAL_PORT", required = true, value_parser = parse_port_spec)]
+        local_ports: Vec<PortSpec>,
 
         /// The local host to expose.
         #[clap(short, long, value_name = "HOST", default_value = "localhost")]
         local_host: String,
 
-        /// Address of the remote server to expose local ports to.
-        #[clap(short, long, env = "BORE_SERVER")]
-        to: String,
-
-        /// Optional port on the remote server to select.
+        /// Optional port on the remote server to select, for a single tunnel.
         #[clap(short, long, default_value_t = 0)]
         port: u16,
 
-        /// Optional secret for authentication.
-        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
-        secret: Option<String>,
+        /// Forward UDP datagrams instead of TCP connections.
+        #[clap(long)]
+        udp: bool,
+
+        /// Name of the tunnel, which labels its logs on the server.
+        #[clap(long, env = "BORE_TUNNEL_NAME", value_parser = parse_tunnel_name)]
+        name: Option<String>,
+
+        /// Class of addresses on the server to listen on, such as `internal`,
+        /// instead of its default ones.
+        #[clap(long, value_name = "CLASS", env = "BORE_LISTENER_CLASS")]
+        listener_class: Option<String>,
+
+        /// Protocol of the local service. HTTP and TLS tunnels are also
+        /// reached at a hostname on the server's shared HTTP or TLS port.
+        #[clap(long, value_enum, default_value_t = Proto::Tcp, conflicts_with = "udp")]
+        proto: Proto,
+
+        /// Subdomain to ask for as an HTTP or TLS tunnel, instead of the
+        /// tunnel's name or a random one.
+        #[clap(long, env = "BORE_SUBDOMAIN", value_parser = parse_subdomain)]
+        subdomain: Option<String>,
+
+        /// What to do with the Host header of requests through an HTTP
+        /// tunnel: `preserve` it, `rewrite` it to the local host and port, or
+        /// replace it with `custom:VALUE`.
+        #[clap(
+            long,
+            value_name = "POLICY",
+            env = "BORE_HOST_HEADER",
+            default_value = "preserve"
+        )]
+        host_header: HostHeader,
+
+        /// Require visitors of an HTTP tunnel to log in with these
+        /// credentials, which the server checks with basic authentication.
+        #[clap(long, value_name = "USER:PASSWORD", env = "BORE_BASIC_AUTH", hide_env_values = true, value_parser = parse_basic_auth)]
+        basic_auth: Option<String>,
+
+        /// Require visitors of an HTTP tunnel to log in with the server's
+        /// OIDC provider as this email, or with any email at a domain given
+        /// as `@example.com`.
+        #[clap(long, value_name = "EMAIL", env = "BORE_OIDC_ALLOW", value_delimiter = ',', value_parser = parse_oidc_rule)]
+        oidc_allow: Vec<String>,
+
+        #[clap(flatten)]
+        connect: ConnectArgs,
+
+        /// Dial the public endpoint after connecting to verify it is reachable.
+        #[clap(long, conflicts_with = "udp")]
+        check_reachability: bool,
+
+        /// Timeout for connecting to the local service.
+        #[clap(long, value_name = "DURATION", default_value = "3s", value_parser = parse_duration)]
+        local_connect_timeout: Duration,
+
+        /// Announce each connection to the local service as a line of JSON
+        /// with the visitor's address, either at the start of the connection
+        /// (`inline`) or on a separate local port.
+        #[clap(long, value_name = "inline|PORT", env = "BORE_ANNOUNCE")]
+        announce: Option<Announce>,
+
+        /// Start each connection to the local service with a PROXY protocol
+        /// header carrying the visitor's address, for services such as nginx
+        /// or HAProxy that accept it.
+        #[clap(long, value_name = "v1|v2", env = "BORE_PROXY_PROTOCOL")]
+        proxy_protocol: Option<ProxyProtocol>,
+
+        /// Record the HTTP requests and responses through the tunnel, and
+        /// serve a page to inspect and replay them at this address.
+        #[clap(
+            long,
+            value_name = "ADDR",
+            env = "BORE_INSPECT",
+            conflicts_with = "udp"
+        )]
+        inspect: Option<SocketAddr>,
+
+        /// Capture the raw bytes of every connection to the local service
+        /// to this file, for Wireshark or as lines of JSON.
+        #[clap(
+            long,
+            value_name = "PATH",
+            env = "BORE_CAPTURE",
+            conflicts_with = "udp"
+        )]
+        capture: Option<PathBuf>,
+
+        /// Format of the capture file, which is otherwise `pcapng` for paths
+        /// ending in `.pcapng` or `.pcap`, and `flow` for anything else.
+        #[clap(
+            long,
+            value_name = "pcapng|flow",
+            env = "BORE_CAPTURE_FORMAT",
+            requires = "capture"
+        )]
+        capture_format: Option<CaptureFormat>,
+
+        /// Capture only the heads of HTTP requests and responses.
+        #[clap(long, env = "BORE_CAPTURE_HEADERS_ONLY", requires = "capture")]
+        capture_headers_only: bool,
+
+        /// Capture at most this many bytes of each direction of a connection.
+        #[clap(
+            long,
+            value_name = "SIZE",
+            env = "BORE_CAPTURE_LIMIT",
+            value_parser = parse_size,
+            requires = "capture"
+        )]
+        capture_limit: Option<u64>,
+
+        /// Close the tunnel as soon as the process with this ID exits.
+        #[clap(long, value_name = "PID")]
+        bind_lifetime_to_pid: Option<u32>,
+
+        /// Run a shell command once connected, closing the tunnel when it exits.
+        ///
+        /// With a local port of `auto`, the command is started before
+        /// connecting instead, and the tunnel exposes the first port it listens on.
+        #[clap(long, value_name = "COMMAND")]
+        exec: Option<String>,
+    },
+
+    /// Runs a client daemon that exposes local ports on request.
+    Daemon {
+        #[clap(flatten)]
+        connect: ConnectArgs,
+
+        /// Address where the local API will listen.
+        #[clap(long, default_value = "127.0.0.1:7836", env = "BORE_DAEMON_ADDR")]
+        api_addr: SocketAddr,
+    },
+
+    /// Shows the tunnels of a running client daemon.
+    Status {
+        /// Address of the daemon's local API.
+        #[clap(long, default_value = "127.0.0.1:7836", env = "BORE_DAEMON_ADDR")]
+        api_addr: SocketAddr,
+
+        /// Print the tunnels as JSON instead of text.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Opens all the tunnels defined in a configuration file.
+    #[clap(visible_alias = "start")]
+    Up {
+        /// TOML file with the server, credentials, and tunnels.
+        #[clap(
+            short,
+            long,
+            value_name = "PATH",
+            env = "BORE_CONFIG",
+            default_value = "bore.toml"
+        )]
+        config: PathBuf,
+
+        /// Address to serve the status of the tunnels on, for `bore status`
+        /// and `bore down`.
+        #[clap(long, default_value = "127.0.0.1:7836", env = "BORE_DAEMON_ADDR")]
+        api_addr: SocketAddr,
+
+        /// Keep the tunnels open in the background once they are up.
+        #[clap(short, long)]
+        detach: bool,
+    },
+
+    /// Closes all the tunnels opened by `bore up`.
+    Down {
+        /// Address of the local API of `bore up`.
+        #[clap(long, default_value = "127.0.0.1:7836", env = "BORE_DAEMON_ADDR")]
+        api_addr: SocketAddr,
+    },
+
+    /// Sends a request recorded by `bore local --inspect` to the local
+    /// service again.
+    Replay {
+        /// ID of the request, as shown by the inspector, instead of the most
+        /// recent one.
+        #[clap(value_name = "REQUEST_ID")]
+        id: Option<Uuid>,
+
+        /// Address of the inspector of `bore local`.
+        #[clap(
+            long,
+            value_name = "ADDR",
+            default_value = "127.0.0.1:4040",
+            env = "BORE_INSPECT"
+        )]
+        inspect: SocketAddr,
+
+        /// Print the replayed request and its response as JSON instead of text.
+        #[clap(long)]
+        json: bool,
+    },
 
-        /// Optional API key for authentication (alternative to secret).
-        #[clap(long, env = "BORE_API_KEY", hide_env_values = true)]
-        api_key: Option<String>,
+    /// Verifies an API key with the server and stores it for later commands.
+    Login {
+        #[clap(flatten)]
+        connect: ConnectArgs,
+
+        /// Store the API key in the OS keyring instead of the credentials
+        /// file.
+        #[clap(long)]
+        keyring: bool,
+    },
+
+    /// Creates a scoped, time-limited sub-key that others can use as an API key.
+    Delegate {
+        #[clap(flatten)]
+        connect: ConnectArgs,
+
+        /// How long the sub-key stays valid.
+        #[clap(long, value_name = "DURATION", default_value = "1d", value_parser = parse_duration)]
+        ttl: Duration,
+
+        /// Lowest remote port that the sub-key may open.
+        #[clap(long, default_value_t = 0)]
+        min_port: u16,
+
+        /// Highest remote port that the sub-key may open.
+        #[clap(long, default_value_t = 65535)]
+        max_port: u16,
+
+        /// Only allow watching tunnels on the ports with `bore observe`,
+        /// instead of opening them.
+        #[clap(long)]
+        observe: bool,
+    },
+
+    /// Watches the connections of an open tunnel on the server, without
+    /// carrying any traffic.
+    Observe {
+        #[clap(flatten)]
+        connect: ConnectArgs,
+
+        /// Public port of the tunnel to watch.
+        port: u16,
+
+        /// Also print payload samples of connections that the server samples.
+        #[clap(long)]
+        samples: bool,
+
+        /// Print events as JSON lines instead of text.
+        #[clap(long)]
+        json: bool,
     },
 
     /// Runs the remote proxy server.
     Server {
+        /// TOML file with the port range, credentials, and bind addresses,
+        /// reloaded on SIGHUP, instead of the options for them.
+        #[clap(
+            short,
+            long,
+            value_name = "PATH",
+            env = "BORE_SERVER_CONFIG",
+            conflicts_with_all = ["min_port", "max_port", "port_range", "exclude_ports", "secret", "secret_file", "api_validation_url", "bind_addr", "bind_tunnels", "listener_class"],
+        )]
+        config: Option<PathBuf>,
+
         /// Minimum accepted TCP port number.
         #[clap(long, default_value_t = 1024, env = "BORE_MIN_PORT")]
         min_port: u16,
@@ -50,22 +382,932 @@ enum Command {
         #[clap(long, default_value_t = 65535, env = "BORE_MAX_PORT")]
         max_port: u16,
 
-        /// Optional secret for authentication.
+        /// Range of accepted TCP ports as `MIN-MAX`, repeated for several
+        /// disjoint ranges, instead of the minimum and maximum port.
+        #[clap(long, value_name = "RANGE", env = "BORE_PORT_RANGE", value_delimiter = ',', value_parser = parse_port_range)]
+        port_range: Vec<String>,
+
+        /// Ports or ranges of ports that are never accepted, comma-separated.
+        #[clap(long, value_name = "PORTS", env = "BORE_EXCLUDE_PORTS", value_delimiter = ',', value_parser = parse_port_range)]
+        exclude_ports: Vec<String>,
+
+        /// How ports are chosen for tunnels that do not ask for one.
+        #[clap(long, value_enum, default_value_t = PortAllocation::Random, env = "BORE_PORT_ALLOCATION")]
+        port_allocation: PortAllocation,
+
+        /// Optional secret for authentication, repeated to accept several
+        /// while rotating them.
         #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
-        secret: Option<String>,
+        secret: Vec<String>,
+
+        /// Read a secret from a file, such as a mounted Kubernetes secret.
+        #[clap(long, value_name = "PATH", env = "BORE_SECRET_FILE", value_parser = read_credential_file)]
+        secret_file: Vec<String>,
 
-        /// Optional API validation URL for API key authentication.
-        #[clap(long, env = "BORE_API_VALIDATION_URL")]
-        api_validation_url: Option<String>,
+        /// Optional API validation URL for API key authentication. May be
+        /// repeated or comma-separated, to fail over to the next URL when one
+        /// is unavailable.
+        #[clap(long, env = "BORE_API_VALIDATION_URL", value_delimiter = ',')]
+        api_validation_url: Vec<String>,
+
+        /// URL of a JWKS document with the keys that sign the JWTs clients
+        /// present as API keys, which are verified without a backend.
+        #[clap(long, value_name = "URL", env = "BORE_JWT_JWKS_URL", conflicts_with_all = ["secret", "secret_file", "api_validation_url"])]
+        jwt_jwks_url: Option<String>,
+
+        /// Only accept JWTs issued by this issuer.
+        #[clap(long, env = "BORE_JWT_ISSUER", requires = "jwt_jwks_url")]
+        jwt_issuer: Option<String>,
+
+        /// Only accept JWTs meant for this audience.
+        #[clap(long, env = "BORE_JWT_AUDIENCE", requires = "jwt_jwks_url")]
+        jwt_audience: Option<String>,
+
+        /// Time to reuse the backend's acceptance of an API key, or 0s to ask
+        /// it on every connection.
+        #[clap(long, value_name = "DURATION", default_value = "60s", env = "BORE_VALIDATION_CACHE_TTL", value_parser = parse_duration)]
+        validation_cache_ttl: Duration,
+
+        /// Time to reuse the backend's rejection of an API key.
+        #[clap(long, value_name = "DURATION", default_value = "10s", env = "BORE_VALIDATION_NEGATIVE_TTL", value_parser = parse_duration)]
+        validation_negative_ttl: Duration,
+
+        /// Time past the cache TTL to keep letting in API keys that the
+        /// backend accepted, while the backend is unavailable.
+        #[clap(long, value_name = "DURATION", default_value = "0s", env = "BORE_VALIDATION_OUTAGE_GRACE", value_parser = parse_duration)]
+        validation_outage_grace: Duration,
+
+        /// Failed requests in a row after which the validation backend is
+        /// backed off from, or 0 to ask it on every connection.
+        #[clap(long, value_name = "COUNT", default_value_t = auth::VALIDATION_FAILURE_THRESHOLD, env = "BORE_VALIDATION_FAILURE_THRESHOLD")]
+        validation_failure_threshold: u32,
+
+        /// Longest wait before asking a degraded validation backend again.
+        #[clap(long, value_name = "DURATION", default_value = "30s", env = "BORE_VALIDATION_MAX_BACKOFF", value_parser = parse_duration)]
+        validation_max_backoff: Duration,
+
+        /// Header to add to requests to the validation backend, as
+        /// `NAME:VALUE`, such as a service token. May be repeated.
+        #[clap(long, value_name = "HEADER", env = "BORE_VALIDATION_HEADER", hide_env_values = true, value_parser = parse_header)]
+        validation_header: Vec<(String, String)>,
+
+        /// PEM file with a client certificate to present to the validation
+        /// backend, for mutual TLS.
+        #[clap(long, value_name = "PATH", env = "BORE_VALIDATION_CLIENT_CERT")]
+        validation_client_cert: Option<PathBuf>,
+
+        /// PEM file with the private key of --validation-client-cert, unless
+        /// it is in the same file.
+        #[clap(
+            long,
+            value_name = "PATH",
+            env = "BORE_VALIDATION_CLIENT_KEY",
+            requires = "validation_client_cert"
+        )]
+        validation_client_key: Option<PathBuf>,
+
+        /// PEM file of CA certificates to trust for the validation backend.
+        #[clap(long, value_name = "PATH", env = "BORE_VALIDATION_CA")]
+        validation_ca: Option<PathBuf>,
 
         /// IP address to bind to, clients must reach this.
         #[clap(long, default_value = "0.0.0.0")]
         bind_addr: IpAddr,
 
-        /// IP address where tunnels will listen on, defaults to --bind-addr.
-        #[clap(long)]
-        bind_tunnels: Option<IpAddr>,
+        /// IP addresses where tunnels will listen on, defaults to --bind-addr.
+        #[clap(long, value_delimiter = ',')]
+        bind_tunnels: Vec<IpAddr>,
+
+        /// Addresses where the tunnels of clients that ask for a class of
+        /// listener listen instead, as `CLASS=IP`, repeated for each address.
+        #[clap(long, value_name = "CLASS=IP", env = "BORE_LISTENER_CLASS", value_delimiter = ',', value_parser = parse_listener_class)]
+        listener_class: Vec<(String, IpAddr)>,
+
+        /// Maximum rate of new control connections per second, for pacing reconnect storms.
+        #[clap(long, value_name = "RATE")]
+        max_handshake_rate: Option<f64>,
+
+        /// Number of control connections accepted in a burst above the handshake rate.
+        #[clap(long, value_name = "COUNT", default_value_t = 100)]
+        handshake_burst: u32,
+
+        /// Maximum rate of new control connections per second from each IP
+        /// address, closing those beyond it.
+        #[clap(long, value_name = "RATE")]
+        max_handshake_rate_per_ip: Option<f64>,
+
+        /// Number of control connections accepted from each IP address in a
+        /// burst above its handshake rate.
+        #[clap(long, value_name = "COUNT", default_value_t = 10)]
+        handshake_burst_per_ip: u32,
+
+        /// Ban IP addresses after this many failed authentications within
+        /// 10 minutes [default: 5 with --ban-duration].
+        #[clap(long, value_name = "COUNT", env = "BORE_BAN_AFTER")]
+        ban_after: Option<u32>,
+
+        /// How long banned IP addresses stay banned [default: 15m with
+        /// --ban-after].
+        #[clap(long, value_name = "DURATION", env = "BORE_BAN_DURATION", value_parser = parse_duration)]
+        ban_duration: Option<Duration>,
+
+        /// Time that new control connections have to finish their handshake,
+        /// through TLS, authentication, and their first message.
+        #[clap(long, value_name = "DURATION", default_value = "10s", value_parser = parse_duration)]
+        handshake_timeout: Duration,
+
+        /// Most control connections in their handshake at once, closing
+        /// further ones until some finish.
+        #[clap(long, value_name = "COUNT", default_value_t = 1024)]
+        max_handshakes: usize,
+
+        /// Hold the port of a closed tunnel for this long, for the same
+        /// client to get back when it reconnects.
+        #[clap(long, value_name = "DURATION", env = "BORE_PORT_RESERVATION", value_parser = parse_duration)]
+        port_reservation: Option<Duration>,
+
+        /// Keep the port of a closed tunnel listening for this long, so that
+        /// visitors wait for its client to reconnect instead of being refused.
+        #[clap(long, value_name = "DURATION", env = "BORE_RECONNECT_GRACE", value_parser = parse_duration)]
+        reconnect_grace: Option<Duration>,
+
+        /// File holding the server's identity key, created if it does not exist.
+        #[clap(long, value_name = "PATH", env = "BORE_IDENTITY_KEY")]
+        identity_key: Option<PathBuf>,
+
+        /// Let authenticated clients mint sub-keys, signed with the identity key.
+        #[clap(long, requires = "identity_key")]
+        allow_sub_keys: bool,
+
+        /// Give clients only a generic reason when their credentials are rejected.
+        #[clap(long, env = "BORE_REDACT_AUTH_ERRORS")]
+        redact_auth_errors: bool,
+
+        /// Log a hex dump of the first bytes of sampled connections on a tunnel.
+        #[clap(long, value_name = "PORT[:EVERY[:BYTES]]")]
+        sample: Vec<SampleSpec>,
+
+        /// Script deciding whether to accept, reject, or throttle each public connection.
+        #[clap(long, value_name = "PATH", env = "BORE_POLICY")]
+        policy: Option<PathBuf>,
+
+        /// PEM file with the TLS certificate chain for the control port.
+        #[clap(long, value_name = "PATH", env = "BORE_TLS_CERT", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// PEM file with the private key for the TLS certificate.
+        #[clap(long, value_name = "PATH", env = "BORE_TLS_KEY", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// Refuse to run without TLS, and tell plaintext clients to enable it.
+        #[clap(long, env = "BORE_REQUIRE_TLS", requires = "tls_cert")]
+        require_tls: bool,
+
+        /// Use safe defaults for a public relay: require authentication and
+        /// TLS, limit handshakes, and keep tunnels off privileged ports.
+        #[clap(long, env = "BORE_HARDENED", requires = "tls_cert")]
+        hardened: bool,
+
+        /// Also accept clients that connect to the control port over WebSocket.
+        #[clap(long, env = "BORE_WEBSOCKET")]
+        websocket: bool,
+
+        /// Script to run whenever a tunnel is opened, with its details in BORE_* variables.
+        #[clap(long, value_name = "PATH", env = "BORE_ON_TUNNEL_OPEN")]
+        on_tunnel_open: Option<PathBuf>,
+
+        /// Append a hash-chained record of every forwarded connection to this file.
+        #[clap(long, value_name = "PATH", env = "BORE_TRANSCRIPT")]
+        transcript: Option<PathBuf>,
+
+        /// Append a JSON record of every visitor to this file, or print them
+        /// if it is `-`.
+        #[clap(long, value_name = "PATH", env = "BORE_ACCESS_LOG")]
+        access_log: Option<PathBuf>,
+
+        /// Periodically post the traffic of each API key to this URL, such
+        /// as for billing.
+        #[clap(long, value_name = "URL", env = "BORE_USAGE_REPORT_URL")]
+        usage_report_url: Option<String>,
+
+        /// Time between usage reports.
+        #[clap(long, value_name = "DURATION", default_value = "60s", env = "BORE_USAGE_REPORT_INTERVAL", value_parser = parse_duration)]
+        usage_report_interval: Duration,
+
+        /// File where abuse countermeasures and port reservations are kept
+        /// across restarts.
+        #[clap(long, value_name = "PATH", env = "BORE_STATE_FILE")]
+        state_file: Option<PathBuf>,
+
+        /// Interval between heartbeats to clients, shorter for NAT devices
+        /// that drop idle connections quickly.
+        #[clap(long, value_name = "DURATION", default_value = "500ms", env = "BORE_HEARTBEAT_INTERVAL", value_parser = parse_duration)]
+        heartbeat_interval: Duration,
+
+        /// Most visitors of each tunnel that may wait for the client to accept them.
+        #[clap(
+            long,
+            value_name = "COUNT",
+            default_value_t = 128,
+            env = "BORE_MAX_PENDING"
+        )]
+        max_pending: usize,
+
+        /// Time that a visitor may wait for the client before it is disconnected.
+        #[clap(long, value_name = "DURATION", default_value = "10s", env = "BORE_PENDING_TIMEOUT", value_parser = parse_duration)]
+        pending_timeout: Duration,
+
+        /// Close tunnels that carry no traffic for this long, such as `30m`,
+        /// freeing the ports of forgotten tunnels.
+        #[clap(long, value_name = "DURATION", env = "BORE_IDLE_TIMEOUT", value_parser = parse_duration)]
+        idle_timeout: Option<Duration>,
+
+        /// Let clients without a secret or API key open tunnels on random
+        /// ports from this one up, while specific ports still require them.
+        #[clap(long, value_name = "PORT", env = "BORE_ANONYMOUS_MIN_PORT")]
+        anonymous_min_port: Option<u16>,
+
+        /// Close tunnels of clients that did not authenticate after this
+        /// long, when the server checks no secret or API key.
+        #[clap(long, value_name = "DURATION", env = "BORE_ANONYMOUS_TUNNEL_TTL", value_parser = parse_duration)]
+        anonymous_tunnel_ttl: Option<Duration>,
+
+        /// Most tunnels that clients that did not authenticate may have open
+        /// at once from each address.
+        #[clap(long, value_name = "COUNT", env = "BORE_MAX_ANONYMOUS_TUNNELS_PER_IP")]
+        max_anonymous_tunnels_per_ip: Option<u32>,
+
+        /// Longest frame that clients may send on the control port, closing
+        /// connections that send longer ones.
+        #[clap(long, value_name = "SIZE", default_value_t = MAX_FRAME_LENGTH as u64, value_parser = parse_size)]
+        max_frame_length: u64,
+
+        /// Only let visitors from these blocks of addresses reach any tunnel,
+        /// such as `10.0.0.0/8`.
+        #[clap(
+            long,
+            value_name = "CIDR",
+            value_delimiter = ',',
+            env = "BORE_ALLOW_IPS"
+        )]
+        allow_ips: Vec<Cidr>,
+
+        /// Never let visitors from these blocks of addresses reach any tunnel.
+        #[clap(
+            long,
+            value_name = "CIDR",
+            value_delimiter = ',',
+            env = "BORE_DENY_IPS"
+        )]
+        deny_ips: Vec<Cidr>,
+
+        /// MaxMind GeoLite2 database to look up the country of each visitor
+        /// in, for logs and metrics. Needs the `geoip` feature.
+        #[clap(long, value_name = "PATH", env = "BORE_GEOIP_DB")]
+        geoip_db: Option<PathBuf>,
+
+        /// Only let visitors from these countries reach any tunnel, by ISO
+        /// code, such as `DE,FR`.
+        #[clap(
+            long,
+            value_name = "CODE",
+            value_delimiter = ',',
+            env = "BORE_ALLOW_COUNTRIES",
+            requires = "geoip_db"
+        )]
+        allow_countries: Vec<String>,
+
+        /// Never let visitors from these countries reach any tunnel.
+        #[clap(
+            long,
+            value_name = "CODE",
+            value_delimiter = ',',
+            env = "BORE_DENY_COUNTRIES",
+            requires = "geoip_db"
+        )]
+        deny_countries: Vec<String>,
+
+        /// Address to serve Prometheus metrics on, at /metrics.
+        #[clap(long, value_name = "ADDR", env = "BORE_METRICS_ADDR")]
+        metrics_addr: Option<SocketAddr>,
+
+        /// Shared port for HTTP tunnels, which routes requests by their Host
+        /// header to tunnels at subdomains of --http-domain.
+        #[clap(
+            long,
+            value_name = "PORT",
+            env = "BORE_HTTP_PORT",
+            requires = "http_domain"
+        )]
+        http_port: Option<u16>,
+
+        /// Domain that HTTP tunnels get subdomains of, whose wildcard DNS
+        /// record points at the server.
+        #[clap(
+            long,
+            value_name = "DOMAIN",
+            env = "BORE_HTTP_DOMAIN",
+            requires = "http_port"
+        )]
+        http_domain: Option<String>,
+
+        /// Shared port that terminates TLS for HTTP tunnels, with certificates
+        /// from an ACME authority such as Let's Encrypt.
+        #[clap(
+            long,
+            value_name = "PORT",
+            env = "BORE_HTTPS_PORT",
+            requires = "http_port"
+        )]
+        https_port: Option<u16>,
+
+        /// Email address that the ACME authority may write to about expiring
+        /// certificates.
+        #[clap(
+            long,
+            value_name = "EMAIL",
+            env = "BORE_ACME_EMAIL",
+            value_delimiter = ',',
+            requires = "https_port"
+        )]
+        acme_email: Vec<String>,
+
+        /// Directory URL of the ACME authority.
+        #[clap(
+            long,
+            value_name = "URL",
+            env = "BORE_ACME_DIRECTORY",
+            default_value = acme::LETS_ENCRYPT
+        )]
+        acme_directory: String,
+
+        /// Directory that keeps the ACME account key and certificates.
+        #[clap(
+            long,
+            value_name = "DIR",
+            env = "BORE_ACME_CACHE",
+            default_value = "bore-acme"
+        )]
+        acme_cache: PathBuf,
+
+        /// How to prove control of hostnames to the ACME authority.
+        #[clap(
+            long,
+            value_enum,
+            env = "BORE_ACME_CHALLENGE",
+            default_value_t = AcmeChallenge::TlsAlpn01
+        )]
+        acme_challenge: AcmeChallenge,
+
+        /// Program that publishes the TXT records of dns-01 challenges, for a
+        /// wildcard certificate.
+        #[clap(long, value_name = "PATH", env = "BORE_ACME_DNS_HOOK")]
+        acme_dns_hook: Option<PathBuf>,
+
+        /// Shared port that routes TLS connections, without decrypting them,
+        /// to tunnels opened with `--proto tls` by their server name. This
+        /// may be the same port as --https-port.
+        #[clap(
+            long,
+            value_name = "PORT",
+            env = "BORE_TLS_PORT",
+            requires = "http_port"
+        )]
+        tls_port: Option<u16>,
+
+        /// HTML template of the page that visitors of HTTP tunnels get when
+        /// their tunnel is offline, busy, or missing, which may refer to
+        /// {{status}}, {{reason}}, {{hostname}}, {{name}}, and {{message}}.
+        #[clap(
+            long,
+            value_name = "PATH",
+            env = "BORE_HTTP_ERROR_PAGE",
+            requires = "http_port"
+        )]
+        http_error_page: Option<PathBuf>,
+
+        /// URL of an OpenID Connect provider that visitors of HTTP tunnels
+        /// opened with --oidc-allow log in with. The provider must accept
+        /// redirects to /_bore/oidc/callback on --http-domain itself.
+        #[clap(
+            long,
+            value_name = "URL",
+            env = "BORE_OIDC_ISSUER",
+            requires_all = ["http_port", "oidc_client_id"]
+        )]
+        oidc_issuer: Option<String>,
+
+        /// Client ID of the server with the OIDC provider.
+        #[clap(
+            long,
+            value_name = "ID",
+            env = "BORE_OIDC_CLIENT_ID",
+            requires = "oidc_issuer"
+        )]
+        oidc_client_id: Option<String>,
+
+        /// Client secret of the server with the OIDC provider.
+        #[clap(
+            long,
+            value_name = "SECRET",
+            env = "BORE_OIDC_CLIENT_SECRET",
+            hide_env_values = true,
+            requires = "oidc_issuer"
+        )]
+        oidc_client_secret: Option<String>,
+
+        /// Address to serve the admin API on, for listing and closing tunnels.
+        #[clap(
+            long,
+            value_name = "ADDR",
+            env = "BORE_ADMIN_ADDR",
+            requires = "admin_token"
+        )]
+        admin_addr: Option<SocketAddr>,
+
+        /// Token that requests to the admin API must bear.
+        #[clap(
+            long,
+            value_name = "TOKEN",
+            env = "BORE_ADMIN_TOKEN",
+            hide_env_values = true
+        )]
+        admin_token: Option<String>,
+
+        /// Hand listening sockets to a new server binary on SIGUSR2, for
+        /// upgrades without downtime. Unix only.
+        #[clap(long, env = "BORE_HANDOFF")]
+        handoff: bool,
+    },
+
+    /// Checks the network path to a server by sending traffic through a
+    /// tunnel, and suggests workarounds for stalls.
+    Doctor {
+        #[clap(flatten)]
+        connect: ConnectArgs,
     },
+
+    /// Checks that a server transcript has not been tampered with.
+    VerifyTranscript {
+        /// Transcript file written by the server.
+        path: PathBuf,
+    },
+
+    /// Manages bore commands that run in the background as a system service.
+    Service {
+        #[clap(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ServiceAction {
+    /// Installs a bore command to start automatically and restart on failure.
+    ///
+    /// For example: bore service install --name web -- local 8000 --to example.com
+    Install {
+        /// Name that identifies the service.
+        #[clap(long, default_value = "bore")]
+        name: String,
+
+        /// Arguments of the bore command to run, after `--`.
+        #[clap(last = true, required = true)]
+        args: Vec<String>,
+    },
+
+    /// Stops and removes an installed service.
+    Uninstall {
+        /// Name that identifies the service.
+        #[clap(long, default_value = "bore")]
+        name: String,
+    },
+
+    /// Runs an installed service, as invoked by the service manager.
+    #[clap(hide = true)]
+    Run {
+        /// Name that identifies the service.
+        #[clap(long, default_value = "bore")]
+        name: String,
+
+        /// Arguments of the bore command to run, after `--`.
+        #[clap(last = true, required = true)]
+        args: Vec<String>,
+    },
+}
+
+/// Options for connecting to the server, shared by client commands.
+#[derive(clap::Args, Debug)]
+struct ConnectArgs {
+    /// Address of the remote server to expose local ports to.
+    #[clap(short, long, env = "BORE_SERVER")]
+    to: String,
+
+    /// Optional secret for authentication.
+    #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+    secret: Option<String>,
+
+    /// Read the secret from a file, such as a mounted Kubernetes secret.
+    #[clap(long, value_name = "PATH", env = "BORE_SECRET_FILE", value_parser = read_credential_file, conflicts_with = "secret")]
+    secret_file: Option<String>,
+
+    /// Optional API key for authentication (alternative to secret).
+    #[clap(long, env = "BORE_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Read the API key from a file, such as a mounted Kubernetes secret.
+    #[clap(long, value_name = "PATH", env = "BORE_API_KEY_FILE", value_parser = read_credential_file, conflicts_with = "api_key")]
+    api_key_file: Option<String>,
+
+    /// Ask the server to compress control frames.
+    #[clap(long)]
+    compress_control: bool,
+
+    /// Compare checksums of each proxied stream with the server, logging mismatches.
+    #[clap(long)]
+    verify_checksums: bool,
+
+    /// Stripe each proxied connection across this many data connections.
+    #[clap(long, value_name = "COUNT", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=MAX_STRIPES as i64))]
+    stripes: u8,
+
+    /// Refuse to connect to servers that do not require authentication.
+    #[clap(long, env = "BORE_REQUIRE_AUTH")]
+    require_auth: bool,
+
+    /// Public key that the server must prove it holds, as printed by the server.
+    #[clap(long, value_name = "KEY", env = "BORE_SERVER_KEY")]
+    server_key: Option<String>,
+
+    /// File of known server identities, trusted the first time a server is seen.
+    #[clap(long, value_name = "PATH", env = "BORE_KNOWN_SERVERS")]
+    known_servers: Option<PathBuf>,
+
+    /// Encrypt data connections to the server, even when TLS is not used.
+    #[clap(long, env = "BORE_ENCRYPT_DATA")]
+    encrypt_data: bool,
+
+    /// Connect to the server's control port over TLS.
+    #[clap(long, env = "BORE_TLS")]
+    tls: bool,
+
+    /// PEM file of CA certificates to trust for TLS, instead of the public roots.
+    #[clap(long, value_name = "PATH", env = "BORE_TLS_CA", requires = "tls")]
+    tls_ca: Option<PathBuf>,
+
+    /// Carry all data connections over one connection to the server, opened
+    /// at startup, instead of opening a new connection for each.
+    #[clap(long, env = "BORE_MULTIPLEX")]
+    multiplex: bool,
+
+    /// Answer pings from the server, so it can adapt its heartbeat to the
+    /// network and notice a dead tunnel within seconds.
+    #[clap(long, env = "BORE_ADAPTIVE_HEARTBEAT")]
+    adaptive_heartbeat: bool,
+
+    /// Consider the connection to the server dead after this long without
+    /// hearing from it, and ask the server to send heartbeats often enough.
+    #[clap(long, value_name = "DURATION", env = "BORE_HEARTBEAT_TIMEOUT", value_parser = parse_duration)]
+    heartbeat_timeout: Option<Duration>,
+
+    /// What to do when the requested remote port is taken: `fail`, let the
+    /// server pick the `nearest` free port, accept `any` port, or try others
+    /// in a `range:MIN-MAX`.
+    #[clap(
+        long,
+        value_name = "POLICY",
+        env = "BORE_PORT_FALLBACK",
+        default_value = "fail"
+    )]
+    port_fallback: PortFallback,
+
+    /// Give up after this many failed attempts in a row to reconnect when the
+    /// connection to the server drops. By default, keeps trying forever.
+    #[clap(long, value_name = "COUNT", env = "BORE_MAX_RETRIES")]
+    max_retries: Option<u32>,
+
+    /// Most traffic per second to send from the local service, such as
+    /// `1MiB/s`, so that a tunnel cannot saturate a home uplink.
+    #[clap(long, value_name = "RATE", env = "BORE_MAX_UPLOAD_RATE", value_parser = parse_rate)]
+    max_upload_rate: Option<u64>,
+
+    /// Most traffic per second to receive for the local service, such as `10MBps`.
+    #[clap(long, value_name = "RATE", env = "BORE_MAX_DOWNLOAD_RATE", value_parser = parse_rate)]
+    max_download_rate: Option<u64>,
+
+    /// Open data connections with a session token from the server, instead
+    /// of authenticating each of them.
+    #[clap(long, env = "BORE_SESSION_TOKENS")]
+    session_tokens: bool,
+
+    /// Largest TCP segment to send or receive on data connections, for
+    /// networks that drop full-sized packets. `bore doctor` finds out if
+    /// this is needed.
+    #[clap(long, value_name = "BYTES", env = "BORE_MAX_SEGMENT_SIZE", conflicts_with = "multiplex", value_parser = clap::value_parser!(u16).range(536..))]
+    max_segment_size: Option<u16>,
+
+    /// How to carry connections to the server's control port.
+    #[clap(long, value_enum, env = "BORE_TRANSPORT", default_value_t = Transport::Tcp)]
+    transport: Transport,
+
+    /// Ask the server for the address of each visitor, to log it and show
+    /// the latest in `bore status`.
+    #[clap(long, env = "BORE_LOG_VISITORS")]
+    log_visitors: bool,
+
+    /// Only let visitors from these blocks of addresses reach the tunnel,
+    /// such as `198.51.100.0/24,2001:db8::/32`.
+    #[clap(
+        long,
+        value_name = "CIDR",
+        value_delimiter = ',',
+        env = "BORE_ALLOW_IPS"
+    )]
+    allow_ips: Vec<Cidr>,
+
+    /// Never let visitors from these blocks of addresses reach the tunnel.
+    #[clap(
+        long,
+        value_name = "CIDR",
+        value_delimiter = ',',
+        env = "BORE_DENY_IPS"
+    )]
+    deny_ips: Vec<Cidr>,
+}
+
+/// Format of messages for people.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum MessageFormat {
+    /// Plain text.
+    #[default]
+    Text,
+
+    /// A line of JSON per message, with its ID, arguments, and text.
+    Json,
+}
+
+/// How messages for people are printed.
+#[derive(Default)]
+struct Output {
+    format: MessageFormat,
+    catalog: Catalog,
+}
+
+/// Output of the command, set once the options are read.
+static OUTPUT: OnceLock<Output> = OnceLock::new();
+
+impl Output {
+    fn format(&self, message: &Message) -> String {
+        let text = self.catalog.render(message);
+        match self.format {
+            MessageFormat::Text => text,
+            MessageFormat::Json => {
+                serde_json::json!({ "id": message.id, "args": message.args, "text": text })
+                    .to_string()
+            }
+        }
+    }
+}
+
+/// Print a message for people to standard output.
+fn say(message: Message) {
+    println!("{}", OUTPUT.get_or_init(Output::default).format(&message));
+}
+
+/// Strategy for choosing the ports of tunnels.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum PortAllocation {
+    /// Any free port, at random.
+    Random,
+
+    /// The next free port after the last one handed out.
+    Sequential,
+
+    /// Free ports that were never used or were released longest ago.
+    Lru,
+
+    /// The same port for a client every time, when it is free.
+    Hash,
+}
+
+impl PortAllocation {
+    fn allocator(self) -> Arc<dyn PortAllocator> {
+        match self {
+            PortAllocation::Random => Arc::new(RandomPorts),
+            PortAllocation::Sequential => Arc::new(SequentialPorts::default()),
+            PortAllocation::Lru => Arc::new(LeastRecentlyUsed::default()),
+            PortAllocation::Hash => Arc::new(HashedPorts),
+        }
+    }
+}
+
+/// How to prove control of hostnames to an ACME authority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum AcmeChallenge {
+    /// A certificate for each hostname, proven on the HTTPS port.
+    #[value(name = "tls-alpn-01")]
+    TlsAlpn01,
+
+    /// A certificate for each hostname, proven on the HTTP port, which must
+    /// be port 80.
+    #[value(name = "http-01")]
+    Http01,
+
+    /// A wildcard certificate, proven with a TXT record by --acme-dns-hook.
+    #[value(name = "dns-01")]
+    Dns01,
+}
+
+impl AcmeChallenge {
+    fn challenge(self) -> acme::Challenge {
+        match self {
+            AcmeChallenge::TlsAlpn01 => acme::Challenge::TlsAlpn01,
+            AcmeChallenge::Http01 => acme::Challenge::Http01,
+            AcmeChallenge::Dns01 => acme::Challenge::Dns01,
+        }
+    }
+}
+
+/// Protocol of the service behind a tunnel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Proto {
+    /// Any TCP service.
+    Tcp,
+
+    /// HTTP, routed by hostname on the server's shared HTTP port.
+    Http,
+
+    /// TLS that the local service terminates, routed by server name on the
+    /// server's shared TLS port without being decrypted.
+    Tls,
+}
+
+/// Transport for connections to the control port.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Transport {
+    /// Plain TCP connections.
+    Tcp,
+
+    /// WebSocket, for networks that only allow HTTP traffic out.
+    Websocket,
+}
+
+impl ConnectArgs {
+    /// Split into the server address and client options for a remote port.
+    fn into_options(self, port: u16) -> (String, ClientOptions) {
+        let secret = self.secret.or(self.secret_file);
+        let api_key = match (self.api_key.or(self.api_key_file), &secret) {
+            (None, None) => stored_api_key(&self.to),
+            (api_key, _) => api_key,
+        };
+        let options = ClientOptions {
+            port,
+            secret,
+            api_key,
+            compression: self.compress_control,
+            checksums: self.verify_checksums,
+            stripes: self.stripes,
+            require_auth: self.require_auth,
+            server_key: self.server_key,
+            known_servers: self.known_servers,
+            tls: self.tls,
+            tls_ca: self.tls_ca,
+            encryption: self.encrypt_data,
+            udp: false,
+            name: None,
+            announce: None,
+            proxy_protocol: None,
+            visitor_info: self.log_visitors,
+            allow_ips: self.allow_ips,
+            deny_ips: self.deny_ips,
+            listener_class: None,
+            http: false,
+            subdomain: None,
+            tls_passthrough: false,
+            host_header: HostHeader::Preserve,
+            basic_auth: None,
+            oidc_allow: Vec::new(),
+            check_reachability: false,
+            heartbeat_timeout: self.heartbeat_timeout,
+            websocket: matches!(self.transport, Transport::Websocket),
+            multiplex: self.multiplex,
+            session: None,
+            broker: None,
+            port_fallback: self.port_fallback,
+            adaptive_heartbeat: self.adaptive_heartbeat,
+            reconnect: true,
+            max_retries: self.max_retries,
+            max_upload_rate: self.max_upload_rate,
+            max_download_rate: self.max_download_rate,
+            session_tokens: self.session_tokens,
+            inspector: None,
+            capture: None,
+            max_segment_size: self.max_segment_size,
+        };
+        (self.to, options)
+    }
+}
+
+/// API key stored by `bore login` for a server, if any.
+fn stored_api_key(server: &str) -> Option<String> {
+    let lookup = || Credentials::load(&credentials::default_path()?)?.api_key(server);
+    lookup().unwrap_or_else(|err| {
+        warn!("ignoring stored credentials: {err:#}");
+        None
+    })
+}
+
+/// Local port given to `bore local`.
+#[derive(Clone, Copy, Debug)]
+enum LocalPort {
+    /// Detect the port that the `--exec` command listens on.
+    Auto,
+
+    /// Expose this port.
+    Port(u16),
+}
+
+/// Tunnel given to `bore local`, as `LOCAL[:REMOTE]`.
+#[derive(Clone, Copy, Debug)]
+struct PortSpec {
+    local: LocalPort,
+    remote: Option<u16>,
+}
+
+fn parse_local_port(input: &str) -> Result<LocalPort, String> {
+    if input == "auto" {
+        return Ok(LocalPort::Auto);
+    }
+    input
+        .parse()
+        .map(LocalPort::Port)
+        .map_err(|_| "expected a port number or `auto`".into())
+}
+
+fn parse_port_spec(input: &str) -> Result<PortSpec, String> {
+    let (local, remote) = match input.split_once(':') {
+        Some((local, remote)) => {
+            let remote = remote
+                .parse()
+                .map_err(|_| format!("invalid remote port in {input:?}"))?;
+            (local, Some(remote))
+        }
+        None => (input, None),
+    };
+    let local = parse_local_port(local)?;
+    Ok(PortSpec { local, remote })
+}
+
+fn parse_tunnel_name(input: &str) -> Result<String, String> {
+    check_tunnel_name(input).map_err(|err| err.to_string())?;
+    Ok(input.to_string())
+}
+
+fn parse_port_range(input: &str) -> Result<String, String> {
+    ports::parse_port_range(input).map_err(|err| err.to_string())?;
+    Ok(input.to_string())
+}
+
+fn parse_subdomain(input: &str) -> Result<String, String> {
+    let subdomain = input.to_ascii_lowercase();
+    bore_cli::http::check_subdomain(&subdomain).map_err(|err| err.to_string())?;
+    Ok(subdomain)
+}
+
+fn parse_basic_auth(input: &str) -> Result<String, String> {
+    bore_cli::http::check_basic_auth(input).map_err(|err| err.to_string())?;
+    Ok(input.to_string())
+}
+
+fn parse_oidc_rule(input: &str) -> Result<String, String> {
+    oidc::check_rule(input).map_err(|err| err.to_string())?;
+    Ok(input.to_string())
+}
+
+fn parse_listener_class(input: &str) -> Result<(String, IpAddr), String> {
+    let (class, addr) = (input.split_once('='))
+        .ok_or_else(|| "expected a class and address as `CLASS=IP`".to_string())?;
+    check_tunnel_name(class).map_err(|err| err.to_string())?;
+    let addr = addr.parse().map_err(|err| format!("{addr:?}: {err}"))?;
+    Ok((class.to_string(), addr))
+}
+
+fn parse_header(input: &str) -> Result<(String, String), String> {
+    match input.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err("expected a header as `NAME:VALUE`".into()),
+    }
+}
+
+/// Read a secret or API key from a file, without its trailing newline.
+fn read_credential_file(path: &str) -> Result<String, String> {
+    let contents = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    let credential = contents.trim_end_matches(['\r', '\n']);
+    if credential.is_empty() {
+        return Err(format!("{path} is empty"));
+    }
+    Ok(credential.to_string())
 }
 
 #[tokio::main]
@@ -73,40 +1315,929 @@ async fn run(command: Command) -> Result<()> {
     match command {
         Command::Local {
             local_host,
-            local_port,
-            to,
+            local_ports,
             port,
-            secret,
-            api_key,
+            udp,
+            name,
+            listener_class,
+            proto,
+            subdomain,
+            host_header,
+            basic_auth,
+            oidc_allow,
+            connect,
+            check_reachability,
+            local_connect_timeout,
+            announce,
+            proxy_protocol,
+            inspect,
+            capture,
+            capture_format,
+            capture_headers_only,
+            capture_limit,
+            bind_lifetime_to_pid,
+            exec,
+        } => {
+            if local_ports.len() > 1 {
+                if port != 0 {
+                    Args::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            "--port only applies to a single tunnel, use LOCAL:REMOTE instead",
+                        )
+                        .exit();
+                }
+                if subdomain.is_some() {
+                    Args::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            "--subdomain only applies to a single tunnel",
+                        )
+                        .exit();
+                }
+                if local_ports
+                    .iter()
+                    .any(|spec| matches!(spec.local, LocalPort::Auto))
+                {
+                    Args::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            "a local port of `auto` only works for a single tunnel",
+                        )
+                        .exit();
+                }
+            }
+            let mut child = None;
+            let mut tunnels = Vec::new();
+            for spec in &local_ports {
+                let local_port = match spec.local {
+                    LocalPort::Port(port) => port,
+                    LocalPort::Auto => {
+                        if udp {
+                            Args::command()
+                                .error(
+                                    ErrorKind::ArgumentConflict,
+                                    "a local port of `auto` only detects TCP ports",
+                                )
+                                .exit();
+                        }
+                        let Some(command) = &exec else {
+                            Args::command()
+                                .error(
+                                    ErrorKind::MissingRequiredArgument,
+                                    "a local port of `auto` requires --exec",
+                                )
+                                .exit();
+                        };
+                        let spawned = child.insert(process::spawn_shell(command, &[])?);
+                        let pid = spawned.id().context("command exited immediately")?;
+                        let port = tokio::select! {
+                            port = process::detect_port(pid) => port?,
+                            status = spawned.wait() => {
+                                let reason = format!("command exited before listening on a port ({})", status?);
+                                return Err(LocalUnreachable(reason).into());
+                            }
+                        };
+                        info!(port, "detected local port");
+                        port
+                    }
+                };
+                tunnels.push((local_port, spec.remote.unwrap_or(port)));
+            }
+            let (to, mut options) = connect.into_options(0);
+            options.udp = udp;
+            options.name = name;
+            options.listener_class = listener_class;
+            options.http = proto == Proto::Http;
+            options.tls_passthrough = proto == Proto::Tls;
+            options.subdomain = subdomain;
+            options.host_header = host_header;
+            options.basic_auth = basic_auth;
+            options.oidc_allow = oidc_allow;
+            options.check_reachability = check_reachability;
+            options.announce = announce;
+            options.proxy_protocol = proxy_protocol;
+            if let Some(addr) = inspect {
+                let inspector = Arc::new(Inspector::new());
+                options.inspector = Some(Arc::clone(&inspector));
+                tokio::spawn(async move {
+                    if let Err(err) = inspect::serve(addr, inspector).await {
+                        warn!(%err, "inspector exited with error");
+                    }
+                });
+            }
+            if let Some(path) = capture {
+                let capture_options = CaptureOptions {
+                    format: capture_format.unwrap_or_else(|| CaptureFormat::for_path(&path)),
+                    headers_only: capture_headers_only,
+                    max_bytes: capture_limit,
+                };
+                options.capture = Some(Arc::new(Capture::create(&path, capture_options)?));
+            }
+            if tunnels.len() > 1 {
+                // All tunnels share one authenticated connection to the server.
+                options.session = Some(Session::connect(&to, &options).await?);
+            }
+            let mut clients = Vec::new();
+            for (local_port, remote_port) in tunnels {
+                let options = ClientOptions {
+                    port: remote_port,
+                    ..options.clone()
+                };
+                let mut client =
+                    Client::with_options(&local_host, local_port, &to, options).await?;
+                client.set_local_connect_timeout(local_connect_timeout);
+                let addr = host_port(&to, client.remote_port());
+                say(Message::new(MessageId::ListeningAt).arg("addr", &addr));
+                if check_reachability {
+                    match client.check_reachability().await {
+                        Ok(()) => say(Message::new(MessageId::EndpointReachable).arg("addr", addr)),
+                        Err(err) => say(Message::new(MessageId::EndpointUnreachable)
+                            .arg("addr", addr)
+                            .arg("error", format!("{err:#}"))),
+                    }
+                }
+                clients.push(client);
+            }
+            let remote_port = clients[0].remote_port();
+            if child.is_none() {
+                if let Some(command) = &exec {
+                    let envs = [
+                        ("BORE_REMOTE_PORT", remote_port.to_string()),
+                        ("BORE_REMOTE_ADDR", host_port(&to, remote_port)),
+                    ];
+                    child = Some(process::spawn_shell(command, &envs)?);
+                }
+            }
+            let watch_pid = async {
+                match bind_lifetime_to_pid {
+                    Some(pid) => process::wait_for_exit(pid).await,
+                    None => future::pending().await,
+                }
+            };
+            let watch_child = async {
+                match child.as_mut() {
+                    Some(child) => child.wait().await,
+                    None => future::pending().await,
+                }
+            };
+            let result = tokio::select! {
+                result = try_join_all(clients.into_iter().map(Client::listen)) => result.map(drop),
+                _ = watch_pid => {
+                    info!("watched process exited, closing tunnel");
+                    Ok(())
+                }
+                status = watch_child => status
+                    .map(|status| info!(?status, "command exited, closing tunnel"))
+                    .map_err(Into::into),
+                _ = signal::ctrl_c() => {
+                    info!("interrupted, closing tunnel");
+                    Ok(())
+                }
+            };
+            if let Some(capture) = &options.capture {
+                capture.flush().await;
+            }
+            result?;
+        }
+        Command::Daemon { connect, api_addr } => {
+            let (to, options) = connect.into_options(0);
+            let daemon = Daemon::new(&to, options);
+            daemon.save_token(&daemon::token_path(api_addr)?)?;
+            daemon.listen(api_addr).await?;
+        }
+        Command::Status { api_addr, json } => {
+            let tunnels = daemon::status(api_addr, &daemon_token(api_addr)?).await?;
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&tunnels)?),
+                false => print_status(&tunnels),
+            }
+        }
+        Command::Up {
+            config: config_path,
+            api_addr,
+            detach,
+        } => {
+            let config = ClientConfig::load(&config_path)?;
+            if detach {
+                let addr = api_addr.to_string();
+                let args = ["up", "--api-addr", &addr, "--config"].map(OsStr::new);
+                let child =
+                    process::spawn_detached(&[&args[..], &[config_path.as_os_str()]].concat())?;
+                let tunnels = wait_until_up(api_addr, child).await?;
+                print_status(&tunnels);
+                print_summary(&tunnels, config.tunnels.len());
+                return Ok(());
+            }
+            let mut options = config.options();
+            if config.tunnels.len() > 1 {
+                options.session = Some(Session::connect(&config.server, &options).await?);
+            }
+            let daemon = Daemon::new(&config.server, options.clone());
+            daemon.save_token(&daemon::token_path(api_addr)?)?;
+            let mut last_err = None;
+            for tunnel in &config.tunnels {
+                let result = daemon.expose_tunnel(tunnel, &options).await;
+                if let Err(err) = result {
+                    let label = match &tunnel.name {
+                        Some(name) => name.clone(),
+                        None => host_port(&tunnel.local_host, tunnel.local_port),
+                    };
+                    say(Message::new(MessageId::TunnelFailedToOpen)
+                        .arg("tunnel", label)
+                        .arg("error", format!("{err:#}")));
+                    last_err = Some(err);
+                }
+            }
+            let tunnels = daemon.tunnels();
+            if let (true, Some(err)) = (tunnels.is_empty(), last_err) {
+                return Err(err.context("none of the tunnels could be opened"));
+            }
+            print_status(&tunnels);
+            print_summary(&tunnels, config.tunnels.len());
+            tokio::select! {
+                result = daemon.listen(api_addr) => result?,
+                _ = signal::ctrl_c() => info!("interrupted, closing tunnels"),
+            }
+        }
+        Command::Replay { id, inspect, json } => {
+            let exchange = inspect::replay(inspect, id).await?;
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&exchange)?),
+                false => print_replay(&exchange),
+            }
+        }
+        Command::Down { api_addr } => {
+            let token = daemon_token(api_addr)?;
+            let tunnels = daemon::status(api_addr, &token).await?;
+            daemon::shutdown(api_addr, &token).await?;
+            say(Message::new(MessageId::TunnelsDown).arg("count", tunnels.len()));
+        }
+        Command::Login {
+            mut connect,
+            keyring,
+        } => {
+            let api_key = match connect.api_key.take().or(connect.api_key_file.take()) {
+                Some(api_key) => api_key,
+                None => rpassword::prompt_password(format!("API key for {}: ", connect.to))?,
+            };
+            let api_key = api_key.trim().to_string();
+            ensure!(!api_key.is_empty(), "no API key given");
+            connect.api_key = Some(api_key.clone());
+            let (to, options) = connect.into_options(0);
+            client::verify_credentials(&to, &options).await?;
+
+            let path = credentials::default_path()?;
+            let mut stored = Credentials::load(&path)?;
+            stored.store(&to, &api_key, keyring)?;
+            stored.save()?;
+            let location = match keyring {
+                true => "the keyring".to_string(),
+                false => path.display().to_string(),
+            };
+            say(Message::new(MessageId::LoggedIn)
+                .arg("server", to)
+                .arg("location", location));
+        }
+        Command::Delegate {
+            connect,
+            ttl,
+            min_port,
+            max_port,
+            observe,
+        } => {
+            let (to, options) = connect.into_options(0);
+            let request = SubKeyRequest {
+                ttl_secs: ttl.as_secs(),
+                min_port,
+                max_port,
+                scope: match observe {
+                    true => Scope::Observe,
+                    false => Scope::Tunnel,
+                },
+            };
+            let key = client::create_sub_key(&to, &options, request).await?;
+            say(Message::new(MessageId::SubKeyCreated).arg("key", key));
+        }
+        Command::Observe {
+            connect,
+            port,
+            samples,
+            json,
         } => {
-            let client = Client::new(&local_host, local_port, &to, port, secret.as_deref(), api_key).await?;
-            client.listen().await?;
+            let (to, options) = connect.into_options(0);
+            let request = ObserveRequest { port, samples };
+            let mut observer = client::observe(&to, &options, request).await?;
+            while let Some(event) = observer.next().await? {
+                match json {
+                    true => println!("{}", serde_json::to_string(&event)?),
+                    false => print_observation(&event),
+                }
+            }
+            info!(port, "tunnel closed");
         }
         Command::Server {
+            config: config_file,
             min_port,
             max_port,
+            port_range,
+            exclude_ports,
+            port_allocation,
             secret,
+            secret_file,
             api_validation_url,
             bind_addr,
             bind_tunnels,
+            listener_class,
+            max_handshake_rate,
+            handshake_burst,
+            max_handshake_rate_per_ip,
+            handshake_burst_per_ip,
+            ban_after,
+            ban_duration,
+            handshake_timeout,
+            max_handshakes,
+            port_reservation,
+            reconnect_grace,
+            identity_key,
+            allow_sub_keys,
+            redact_auth_errors,
+            sample,
+            policy,
+            tls_cert,
+            tls_key,
+            require_tls,
+            hardened,
+            websocket,
+            on_tunnel_open,
+            transcript,
+            access_log,
+            usage_report_url,
+            usage_report_interval,
+            state_file,
+            heartbeat_interval,
+            max_pending,
+            pending_timeout,
+            idle_timeout,
+            anonymous_min_port,
+            anonymous_tunnel_ttl,
+            max_anonymous_tunnels_per_ip,
+            max_frame_length,
+            allow_ips,
+            deny_ips,
+            geoip_db,
+            allow_countries,
+            deny_countries,
+            metrics_addr,
+            http_port,
+            http_domain,
+            https_port,
+            acme_email,
+            acme_directory,
+            acme_cache,
+            acme_challenge,
+            acme_dns_hook,
+            tls_port,
+            http_error_page,
+            oidc_issuer,
+            oidc_client_id,
+            oidc_client_secret,
+            admin_addr,
+            admin_token,
+            handoff,
+            jwt_jwks_url,
+            jwt_issuer,
+            jwt_audience,
+            validation_cache_ttl,
+            validation_negative_ttl,
+            validation_outage_grace,
+            validation_failure_threshold,
+            validation_max_backoff,
+            validation_header,
+            validation_client_cert,
+            validation_client_key,
+            validation_ca,
         } => {
-            let port_range = min_port..=max_port;
-            if port_range.is_empty() {
+            let mut validation_urls = api_validation_url.into_iter();
+            let config = match &config_file {
+                Some(path) => ServerConfig::load(path)?,
+                None => ServerConfig {
+                    min_port,
+                    max_port,
+                    port_ranges: port_range,
+                    exclude_ports,
+                    secret: None,
+                    secrets: secret
+                        .into_iter()
+                        .chain(secret_file)
+                        .map(|secret| (auth::secret_fingerprint(&secret), secret))
+                        .collect(),
+                    api_validation_url: validation_urls.next(),
+                    api_validation_fallback_urls: validation_urls.collect(),
+                    bind_addr,
+                    bind_tunnels,
+                    listener_classes: listener_class.into_iter().fold(
+                        BTreeMap::new(),
+                        |mut classes, (class, addr)| {
+                            classes.entry(class).or_insert_with(Vec::new).push(addr);
+                            classes
+                        },
+                    ),
+                    credential_ports: Default::default(),
+                },
+            };
+            if config.port_range().is_empty() {
                 Args::command()
                     .error(ErrorKind::InvalidValue, "port range is empty")
                     .exit();
             }
-            let mut server = Server::new(port_range, secret.as_deref(), api_validation_url);
-            server.set_bind_addr(bind_addr);
-            server.set_bind_tunnels(bind_tunnels.unwrap_or(bind_addr));
+            if config.ports().is_empty() {
+                Args::command()
+                    .error(ErrorKind::InvalidValue, "all ports are excluded")
+                    .exit();
+            }
+            let mut server = Server::with_config(&config);
+            if let Some(path) = config_file {
+                server.set_config_file(path);
+            }
+            server.set_port_allocator(port_allocation.allocator());
+            server.set_validation_cache_ttl(validation_cache_ttl, validation_negative_ttl);
+            server.set_validation_outage_policy(OutagePolicy {
+                grace: validation_outage_grace,
+                failure_threshold: validation_failure_threshold,
+                max_backoff: validation_max_backoff,
+            });
+            let request_options = ValidationRequestOptions {
+                headers: validation_header,
+                client_cert: validation_client_cert,
+                client_key: validation_client_key,
+                ca: validation_ca,
+            };
+            if !request_options.headers.is_empty()
+                || request_options.client_cert.is_some()
+                || request_options.ca.is_some()
+            {
+                server.set_validation_client(request_options.client()?);
+            }
+            if let Some(url) = jwt_jwks_url {
+                let mut auth = JwtAuthenticator::new(url);
+                if let Some(issuer) = jwt_issuer {
+                    auth.set_issuer(issuer);
+                }
+                if let Some(audience) = jwt_audience {
+                    auth.set_audience(audience);
+                }
+                server.set_auth_provider(Arc::new(auth));
+            }
+            server.set_redact_auth_errors(redact_auth_errors);
+            server.set_heartbeat_interval(heartbeat_interval);
+            server.set_max_pending(max_pending);
+            server.set_max_frame_length(max_frame_length as usize);
+            server.set_pending_timeout(pending_timeout);
+            if let Some(timeout) = idle_timeout {
+                server.set_idle_timeout(timeout);
+            }
+            if let Some(port) = anonymous_min_port {
+                server.set_anonymous_min_port(port);
+            }
+            if let Some(ttl) = anonymous_tunnel_ttl {
+                server.set_anonymous_tunnel_ttl(ttl);
+            }
+            if let Some(max) = max_anonymous_tunnels_per_ip {
+                server.set_max_anonymous_tunnels(max);
+            }
+            if let Some(path) = policy {
+                server.set_policy(Policy::load(&path)?);
+            }
+            server.set_access_list(AccessList::new(allow_ips, deny_ips));
+            if let Some(path) = geoip_db {
+                let rules = CountryRules::new(allow_countries, deny_countries)?;
+                server.set_country_rules(GeoIp::open(&path)?, rules);
+            }
+            if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+                server.set_tls(tls::acceptor(&cert, &key)?);
+            }
+            server.set_require_tls(require_tls);
+            if websocket {
+                server.enable_websocket();
+            }
+            if let Some(program) = on_tunnel_open {
+                server.set_on_tunnel_open(program);
+            }
+            if let Some(path) = transcript {
+                server.set_transcript(Transcript::open(&path)?);
+            }
+            if let Some(path) = access_log {
+                server.set_access_log(AccessLog::open(&path)?);
+            }
+            if let Some(url) = usage_report_url {
+                let mut reporter = UsageReporter::new(url);
+                reporter.set_interval(usage_report_interval);
+                server.set_usage_reporter(reporter);
+            }
+            if let Some(path) = state_file {
+                server.set_state_file(StateFile::new(&path));
+            }
+            for spec in sample {
+                server.sampler().enable(spec);
+            }
+            if let Some(rate) = max_handshake_rate {
+                server.set_handshake_rate(rate, handshake_burst);
+            }
+            if let Some(rate) = max_handshake_rate_per_ip {
+                server.set_handshake_rate_per_ip(rate, handshake_burst_per_ip);
+            }
+            if ban_after.is_some() || ban_duration.is_some() {
+                server.set_auto_ban(
+                    ban_after.unwrap_or(guard::DEFAULT_BAN_AFTER),
+                    ban_duration.unwrap_or(guard::DEFAULT_BAN_DURATION),
+                );
+            }
+            server.set_handshake_timeout(handshake_timeout);
+            server.set_max_handshakes(max_handshakes);
+            if let Some(grace) = port_reservation {
+                server.set_port_reservation(grace);
+            }
+            if let Some(grace) = reconnect_grace {
+                server.set_reconnect_grace(grace);
+            }
+            if hardened {
+                server.enable_hardening();
+            }
+            if let Some(path) = identity_key {
+                let identity = ServerIdentity::load_or_generate(&path)?;
+                info!(key = %identity.public_key(), "loaded server identity");
+                server.set_identity(identity);
+                if allow_sub_keys {
+                    server.enable_sub_keys();
+                }
+            }
+            if let Some(addr) = metrics_addr {
+                server.set_metrics_addr(addr);
+            }
+            if let (Some(port), Some(domain)) = (http_port, &http_domain) {
+                server.enable_http(port, domain);
+                if let Some(path) = &http_error_page {
+                    let template = std::fs::read_to_string(path)
+                        .with_context(|| format!("could not read {}", path.display()))?;
+                    server.set_http_error_page(template);
+                }
+                if let Some(https_port) = https_port {
+                    if acme_challenge == AcmeChallenge::Dns01 && acme_dns_hook.is_none() {
+                        Args::command()
+                            .error(
+                                ErrorKind::MissingRequiredArgument,
+                                "--acme-challenge dns-01 requires --acme-dns-hook",
+                            )
+                            .exit();
+                    }
+                    if acme_challenge == AcmeChallenge::Http01 && port != 80 {
+                        warn!("http-01 challenges are only checked on port 80");
+                    }
+                    let config = AcmeConfig {
+                        directory: acme_directory,
+                        contact: acme_email,
+                        cache_dir: acme_cache,
+                        challenge: acme_challenge.challenge(),
+                        dns_hook: acme_dns_hook,
+                    };
+                    server.enable_https(https_port, Acme::new(config, domain)?);
+                }
+                if let Some(tls_port) = tls_port {
+                    if tls_port == port {
+                        Args::command()
+                            .error(
+                                ErrorKind::ArgumentConflict,
+                                "--tls-port must differ from --http-port",
+                            )
+                            .exit();
+                    }
+                    server.enable_tls_passthrough(tls_port);
+                }
+                if let (Some(issuer), Some(client_id)) = (oidc_issuer, oidc_client_id) {
+                    server.enable_oidc(OidcConfig {
+                        issuer,
+                        client_id,
+                        client_secret: oidc_client_secret,
+                    });
+                }
+            }
+            if let (Some(addr), Some(token)) = (admin_addr, admin_token) {
+                server.set_admin(addr, token);
+            }
+            if handoff {
+                server.enable_handoff(spawn_successor);
+            }
+            #[cfg(unix)]
+            server.adopt_listeners(inherited_listeners()?);
             server.listen().await?;
         }
+        Command::Doctor { connect } => {
+            let (to, options) = connect.into_options(0);
+            let diagnosis = doctor::diagnose(&to, options).await;
+            for check in &diagnosis.checks {
+                let message = match &check.result {
+                    Ok(duration) => Message::new(MessageId::DoctorCheckPassed)
+                        .arg("millis", duration.as_millis()),
+                    Err(error) => Message::new(MessageId::DoctorCheckFailed).arg("error", error),
+                };
+                say(message.arg("check", &check.name));
+            }
+            if let Some(size) = diagnosis.suggested_segment_size {
+                say(Message::new(MessageId::DoctorSuggestSegmentSize).arg("size", size));
+            }
+            ensure!(diagnosis.passed(), "some checks failed");
+        }
+        Command::VerifyTranscript { path } => match transcript::verify(&path)? {
+            Some(last) => say(Message::new(MessageId::TranscriptIntact)
+                .arg("records", last.seq + 1)
+                .arg("hash", last.hash)),
+            None => say(Message::new(MessageId::TranscriptEmpty)),
+        },
+        Command::Service { .. } => unreachable!("services are managed outside the runtime"),
+    }
+
+    Ok(())
+}
+
+/// Print the tunnels of a daemon, one per line.
+fn print_replay(exchange: &Exchange) {
+    let request = &exchange.request;
+    let mut message = Message::new(MessageId::RequestReplayed)
+        .arg("id", exchange.id)
+        .arg("method", &request.method)
+        .arg("path", &request.path);
+    if let (Some(response), Some(ms)) = (&exchange.response, exchange.duration_ms) {
+        message = message
+            .arg("status", response.status)
+            .arg("reason", &response.reason)
+            .arg("millis", ms);
+    }
+    say(message);
+}
+
+fn print_status(tunnels: &[TunnelInfo]) {
+    if tunnels.is_empty() {
+        say(Message::new(MessageId::NoTunnels));
+        return;
+    }
+    for tunnel in tunnels {
+        let state = match tunnel.state {
+            TunnelState::Open => "open",
+            TunnelState::Closed => "closed",
+        };
+        let mut label = match &tunnel.name {
+            Some(name) => format!("{}  {name}", tunnel.id),
+            None => tunnel.id.to_string(),
+        };
+        if !tunnel.labels.is_empty() {
+            let labels: Vec<_> = tunnel
+                .labels
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect();
+            label += &format!(" [{}]", labels.join(","));
+        }
+        say(Message::new(MessageId::TunnelStatus)
+            .arg("tunnel", label)
+            .arg("local", host_port(&tunnel.local_host, tunnel.local_port))
+            .arg("remote", &tunnel.remote)
+            .arg("state", state)
+            .arg(
+                "uptime",
+                format_duration(Duration::from_secs(tunnel.uptime_secs)),
+            )
+            .arg("connections", tunnel.connections)
+            .arg("bytes_in", format_size(tunnel.bytes_in))
+            .arg("bytes_out", format_size(tunnel.bytes_out)));
+        if let Some(err) = &tunnel.last_error {
+            say(Message::new(MessageId::TunnelLastError).arg("error", err));
+        }
+        if let Some(addr) = tunnel.last_visitor {
+            say(Message::new(MessageId::TunnelLastVisitor).arg("addr", addr));
+        }
+    }
+}
+
+/// Wait for `bore up` running in the background to open its tunnels and
+/// serve its API, returning the tunnels.
+async fn wait_until_up(
+    api_addr: SocketAddr,
+    mut child: tokio::process::Child,
+) -> Result<Vec<TunnelInfo>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            bail!("tunnels stopped in the background ({status}), run without --detach to see why");
+        }
+        // The token is only written once the tunnels are being opened.
+        if let Ok(token) = daemon_token(api_addr) {
+            if let Ok(tunnels) = daemon::status(api_addr, &token).await {
+                return Ok(tunnels);
+            }
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Token of the daemon serving its API on an address.
+fn daemon_token(api_addr: SocketAddr) -> Result<String> {
+    daemon::read_token(&daemon::token_path(api_addr)?)
+}
+
+/// Print how many of the tunnels in a configuration file are open.
+fn print_summary(tunnels: &[TunnelInfo], total: usize) {
+    let open = tunnels
+        .iter()
+        .filter(|tunnel| tunnel.state == TunnelState::Open)
+        .count();
+    say(Message::new(MessageId::TunnelsUp)
+        .arg("open", open)
+        .arg("total", total));
+}
+
+fn print_observation(event: &Observation) {
+    let message = match event {
+        Observation::Connection { id, peer } => Message::new(MessageId::ObservedConnection)
+            .arg("id", id)
+            .arg("peer", peer),
+        Observation::Sample {
+            id,
+            inbound,
+            outbound,
+        } => Message::new(MessageId::ObservedSample)
+            .arg("id", id)
+            .arg("inbound", inbound)
+            .arg("outbound", outbound),
+        Observation::Dropped { id, reason } => Message::new(MessageId::ObservedDrop)
+            .arg("id", id)
+            .arg("reason", reason.as_str()),
+        Observation::Closed { id, duration_ms } => {
+            Message::new(MessageId::ObservedClose).arg("id", id).arg(
+                "duration",
+                format_duration(Duration::from_millis(*duration_ms)),
+            )
+        }
+    };
+    say(message);
+}
+
+/// Listening sockets handed over by a previous server, if this one was
+/// started by it.
+#[cfg(unix)]
+fn inherited_listeners() -> Result<bore_cli::handoff::Inherited> {
+    use bore_cli::handoff::{self, Inherited};
+    use std::os::fd::FromRawFd;
+
+    // Servers that this one hands over to later get their own list, so the
+    // variable can stay in the environment.
+    let Some(value) = std::env::var_os(handoff::ENV_VAR) else {
+        return Ok(Inherited::default());
+    };
+    let fds = handoff::parse(&value.to_string_lossy())?;
+    let adopt = |fd| -> Result<std::net::TcpListener> {
+        // SAFETY: The previous server passed these descriptors of listening
+        // sockets to this process, and nothing else here owns them.
+        let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+        socket.set_cloexec(true)?;
+        Ok(socket.into())
+    };
+    let inherited = Inherited {
+        control: fds.control.map(adopt).transpose()?,
+        tunnels: fds
+            .tunnels
+            .into_iter()
+            .map(|(port, fd)| Ok((port, adopt(fd)?)))
+            .collect::<Result<_>>()?,
+    };
+    info!(
+        tunnels = inherited.tunnels.len(),
+        "took over listening sockets from the previous server"
+    );
+    Ok(inherited)
+}
+
+/// Start a new server that inherits the given descriptors of listening
+/// sockets, which stay close-on-exec in this process.
+fn spawn_successor(
+    mut command: std::process::Command,
+    fds: &[i32],
+) -> std::io::Result<std::process::Child> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::BorrowedFd;
+        use std::os::unix::process::CommandExt;
+
+        let fds = fds.to_vec();
+        // SAFETY: Only `fcntl` runs between fork and exec, which is safe to
+        // call there, on descriptors that the caller keeps open.
+        unsafe {
+            command.pre_exec(move || {
+                for &fd in &fds {
+                    socket2::SockRef::from(&BorrowedFd::borrow_raw(fd)).set_cloexec(false)?;
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = fds;
+    command.spawn()
+}
+
+/// Parse the bore command that a service runs.
+fn parse_service_command(args: &[String]) -> Result<Command> {
+    let argv = iter::once("bore").chain(args.iter().map(String::as_str));
+    let command = Args::try_parse_from(argv)?.command;
+    ensure!(
+        !matches!(command, Command::Service { .. }),
+        "a service cannot run another service command"
+    );
+    Ok(command)
+}
+
+#[cfg(windows)]
+static SERVICE: Mutex<Option<(String, Command)>> = Mutex::new(None);
+
+#[cfg(windows)]
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+#[cfg(windows)]
+fn service_main(_arguments: Vec<std::ffi::OsString>) {
+    let (name, command) = SERVICE
+        .lock()
+        .unwrap()
+        .take()
+        .expect("service command is set");
+    if let Err(err) = service::serve(&name, || run(command)) {
+        tracing::error!("service exited with error: {err:#}");
     }
+}
 
+fn run_service(action: ServiceAction) -> Result<()> {
+    match action {
+        ServiceAction::Install { name, args } => {
+            parse_service_command(&args)?;
+            service::install(&name, &args)?;
+            info!(%name, "installed service");
+        }
+        ServiceAction::Uninstall { name } => {
+            service::uninstall(&name)?;
+            info!(%name, "uninstalled service");
+        }
+        ServiceAction::Run { name, args } => {
+            let command = parse_service_command(&args)?;
+            #[cfg(windows)]
+            {
+                *SERVICE.lock().unwrap() = Some((name.clone(), command));
+                service::dispatch(&name, ffi_service_main)?;
+            }
+            #[cfg(not(windows))]
+            {
+                info!(%name, "running service");
+                run(command)?;
+            }
+        }
+    }
     Ok(())
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    run(Args::parse().command)
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match start(args) {
+        Ok(()) => ExitCode::from(exit::SUCCESS),
+        Err(err) => {
+            let message = Message::new(MessageId::for_error(&err)).arg("error", format!("{err:#}"));
+            eprintln!("{}", OUTPUT.get_or_init(Output::default).format(&message));
+            ExitCode::from(exit::for_error(&err))
+        }
+    }
+}
+
+fn start(args: Args) -> Result<()> {
+    let catalog = match &args.messages {
+        Some(path) => Catalog::load(path)?,
+        None => Catalog::default(),
+    };
+    let _ = OUTPUT.set(Output {
+        format: args.message_format,
+        catalog,
+    });
+    match &args.log_file {
+        Some(path) => {
+            let mut file = RotatingFile::open(path, args.log_max_size, args.log_max_files)
+                .with_context(|| format!("could not open log file {}", path.display()))?;
+            if let Some(max_age) = args.log_max_age {
+                file.set_max_age(max_age).context("invalid --log-max-age")?;
+            }
+            tracing_subscriber::fmt()
+                .with_writer(Mutex::new(file))
+                .with_ansi(false)
+                .init();
+        }
+        None => tracing_subscriber::fmt::init(),
+    }
+    match args.command {
+        Command::Service { action } => run_service(action),
+        command => run(command),
+    }
 }
@@ -0,0 +1,253 @@
+//! Catalog of the user-facing messages of the `bore` command.
+//!
+//! Every message that the command prints for people, as opposed to its logs,
+//! has a stable ID. Apps that wrap the command can ask for messages as JSON
+//! and map the IDs to their own translated strings, instead of matching on
+//! English text. The text itself can be replaced with a catalog file of
+//! templates keyed by ID:
+//!
+//! ```toml
+//! no_tunnels = "keine Tunnel"
+//! tunnel_failed_to_open = "{tunnel}  konnte nicht geöffnet werden: {error}"
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::exit;
+
+/// Stable identifier of a user-facing message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageId {
+    /// The command failed, for a reason without a more specific ID.
+    Error,
+
+    /// The server rejected the client's credentials.
+    ErrorAuthFailed,
+
+    /// The server's control port could not be reached.
+    ErrorServerUnreachable,
+
+    /// The server refused to open the tunnel.
+    ErrorPortRejected,
+
+    /// The local service never became available.
+    ErrorLocalUnreachable,
+
+    /// The server closed the tunnel for good.
+    ErrorTunnelClosed,
+
+    /// A tunnel was opened at a public address.
+    ListeningAt,
+
+    /// The public endpoint of a tunnel could be reached.
+    EndpointReachable,
+
+    /// The public endpoint of a tunnel could not be reached.
+    EndpointUnreachable,
+
+    /// One tunnel of a configuration file could not be opened.
+    TunnelFailedToOpen,
+
+    /// A daemon has no tunnels.
+    NoTunnels,
+
+    /// One tunnel in the status of a daemon.
+    TunnelStatus,
+
+    /// The most recent error of a tunnel in the status of a daemon.
+    TunnelLastError,
+
+    /// The most recent visitor of a tunnel in the status of a daemon.
+    TunnelLastVisitor,
+
+    /// A sub-key was created.
+    SubKeyCreated,
+
+    /// Credentials were verified and stored by `bore login`.
+    LoggedIn,
+
+    /// A transcript was verified.
+    TranscriptIntact,
+
+    /// A transcript has no records to verify.
+    TranscriptEmpty,
+
+    /// An observed tunnel received a connection.
+    ObservedConnection,
+
+    /// Payload sample of a connection on an observed tunnel.
+    ObservedSample,
+
+    /// A visitor of an observed tunnel was dropped before being accepted.
+    ObservedDrop,
+
+    /// A connection on an observed tunnel closed.
+    ObservedClose,
+
+    /// Tunnels of a configuration file were opened.
+    TunnelsUp,
+
+    /// Tunnels of a configuration file were closed.
+    TunnelsDown,
+
+    /// A recorded request was sent to the local service again.
+    RequestReplayed,
+
+    /// A check of `bore doctor` passed.
+    DoctorCheckPassed,
+
+    /// A check of `bore doctor` failed.
+    DoctorCheckFailed,
+
+    /// Large transfers only got through with a clamped segment size.
+    DoctorSuggestSegmentSize,
+}
+
+impl MessageId {
+    /// English template of the message, with arguments in braces.
+    pub fn template(self) -> &'static str {
+        match self {
+            MessageId::Error
+            | MessageId::ErrorAuthFailed
+            | MessageId::ErrorServerUnreachable
+            | MessageId::ErrorPortRejected
+            | MessageId::ErrorLocalUnreachable
+            | MessageId::ErrorTunnelClosed => "Error: {error}",
+            MessageId::ListeningAt => "listening at {addr}",
+            MessageId::EndpointReachable => "public endpoint {addr} is reachable",
+            MessageId::EndpointUnreachable => {
+                "public endpoint {addr} is unreachable, is the port blocked by a firewall? {error}"
+            }
+            MessageId::TunnelFailedToOpen => "{tunnel}  failed to open: {error}",
+            MessageId::NoTunnels => "no tunnels",
+            MessageId::TunnelStatus => {
+                "{tunnel}  {local} -> {remote}  {state}  up {uptime}  {connections} connections  in {bytes_in}  out {bytes_out}"
+            }
+            MessageId::TunnelLastError => "    last error: {error}",
+            MessageId::TunnelLastVisitor => "    last visitor: {addr}",
+            MessageId::SubKeyCreated => "{key}",
+            MessageId::LoggedIn => "logged in to {server}, API key stored in {location}",
+            MessageId::TranscriptIntact => {
+                "transcript is intact, {records} records, latest hash {hash}"
+            }
+            MessageId::TranscriptEmpty => "transcript is empty",
+            MessageId::ObservedConnection => "{id}  connected from {peer}",
+            MessageId::ObservedSample => "{id}  sample in {inbound}  out {outbound}",
+            MessageId::ObservedDrop => "{id}  dropped before accepted: {reason}",
+            MessageId::ObservedClose => "{id}  closed after {duration}",
+            MessageId::TunnelsUp => "{open} of {total} tunnels are up",
+            MessageId::TunnelsDown => "closed {count} tunnels",
+            MessageId::RequestReplayed => {
+                "{id}  {method} {path} -> {status} {reason} in {millis} ms"
+            }
+            MessageId::DoctorCheckPassed => "ok    {check}  ({millis} ms)",
+            MessageId::DoctorCheckFailed => "FAIL  {check}: {error}",
+            MessageId::DoctorSuggestSegmentSize => {
+                "large packets are dropped on the way to the server, try --max-segment-size {size}"
+            }
+        }
+    }
+
+    /// ID of the message for an error that ended the command, following
+    /// its exit code.
+    pub fn for_error(err: &anyhow::Error) -> Self {
+        match exit::for_error(err) {
+            exit::AUTH_FAILED => MessageId::ErrorAuthFailed,
+            exit::SERVER_UNREACHABLE => MessageId::ErrorServerUnreachable,
+            exit::PORT_REJECTED => MessageId::ErrorPortRejected,
+            exit::LOCAL_UNREACHABLE => MessageId::ErrorLocalUnreachable,
+            exit::TUNNEL_CLOSED => MessageId::ErrorTunnelClosed,
+            _ => MessageId::Error,
+        }
+    }
+}
+
+/// User-facing message, with the values of its arguments.
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    /// Which message this is.
+    pub id: MessageId,
+
+    /// Values of the arguments, by name.
+    pub args: BTreeMap<&'static str, String>,
+}
+
+impl Message {
+    /// Create a message without arguments.
+    pub fn new(id: MessageId) -> Self {
+        Self {
+            id,
+            args: BTreeMap::new(),
+        }
+    }
+
+    /// Add an argument to the message.
+    pub fn arg(mut self, name: &'static str, value: impl Display) -> Self {
+        self.args.insert(name, value.to_string());
+        self
+    }
+}
+
+/// Templates for messages, which default to English.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    templates: HashMap<MessageId, String>,
+}
+
+impl Catalog {
+    /// Read a catalog file of templates by message ID. Messages that it does
+    /// not mention keep their English text.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("could not read message catalog {}", path.display()))?;
+        let templates = toml::from_str(&contents)
+            .with_context(|| format!("invalid message catalog {}", path.display()))?;
+        Ok(Self { templates })
+    }
+
+    /// Fill in the template of a message with its arguments.
+    ///
+    /// ```
+    /// use bore_cli::messages::{Catalog, Message, MessageId};
+    ///
+    /// let message = Message::new(MessageId::TranscriptIntact)
+    ///     .arg("records", 3)
+    ///     .arg("hash", "ab12");
+    /// assert_eq!(
+    ///     Catalog::default().render(&message),
+    ///     "transcript is intact, 3 records, latest hash ab12",
+    /// );
+    /// ```
+    pub fn render(&self, message: &Message) -> String {
+        let template = match self.templates.get(&message.id) {
+            Some(template) => template.as_str(),
+            None => message.id.template(),
+        };
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            text.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let name = after.find('}').map(|end| &after[..end]);
+            match name.and_then(|name| Some((name, message.args.get(name)?))) {
+                Some((name, value)) => {
+                    text.push_str(value);
+                    rest = &after[name.len() + 1..];
+                }
+                None => {
+                    text.push('{');
+                    rest = after;
+                }
+            }
+        }
+        text.push_str(rest);
+        text
+    }
+}
@@ -0,0 +1,427 @@
+//! Metrics of the server, served in the Prometheus text format.
+//!
+//! Visitors of a tunnel wait in a queue until the client opens a data
+//! connection for them. A slow or overloaded client shows up here first, as a
+//! growing queue and longer waits, well before visitors start being dropped.
+//!
+//! Each open tunnel also reports its recent throughput and how long its
+//! connections last, labeled by port, for capacity planning and to spot
+//! tunnels whose connections hang. Tunnels of clients that authenticated as
+//! a user are labeled with it too, and the bytes of each user's tunnels are
+//! counted, to attribute traffic to users.
+//!
+//! Data connections from clients that match no waiting visitor are counted
+//! too, by whether the visitor already timed out, was already accepted, or
+//! is unknown. Many accepts of timed out visitors mean that clients are too
+//! slow for the pending timeout of the server.
+//!
+//! With a GeoIP database, visitors are also counted by country, and by
+//! whether the country rules of the server let them in.
+//!
+//! The health of the API key validation backend is reported too, so that an
+//! outage shows up as an alert rather than as clients failing to connect.
+
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use dashmap::DashMap;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tracing::info;
+
+use crate::shared::CloseReason;
+use crate::stats::TunnelStats;
+
+/// Windows that the throughput of tunnels is reported over, by label.
+const THROUGHPUT_WINDOWS: [(&str, Duration); 2] = [
+    ("1m", Duration::from_secs(60)),
+    ("5m", Duration::from_secs(300)),
+];
+
+/// Counters of the server, shared by its tunnels.
+///
+/// ```
+/// use std::time::Duration;
+/// use bore_cli::metrics::{ServerMetrics, UnmatchedAccept};
+/// use bore_cli::shared::CloseReason;
+///
+/// let metrics = ServerMetrics::default();
+/// metrics.enqueue();
+/// metrics.enqueue();
+/// metrics.accept(Duration::from_millis(20));
+/// metrics.drop_pending(CloseReason::AcceptTimeout);
+/// assert_eq!(metrics.pending(), 0);
+/// assert!(metrics.render().contains("bore_pending_connections_total{outcome=\"accepted\"} 1"));
+///
+/// metrics.add_unmatched_accept(UnmatchedAccept::Expired);
+/// assert!(metrics.render().contains("bore_unmatched_accepts_total{reason=\"expired\"} 1"));
+///
+/// metrics.add_visitor(Some("DE"), true);
+/// assert!(metrics.render().contains("bore_visitors_total{country=\"DE\",outcome=\"admitted\"} 1"));
+/// ```
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    pending: AtomicU64,
+    accepted: AtomicU64,
+    queue_full: AtomicU64,
+    accept_timeout: AtomicU64,
+    tunnel_closed: AtomicU64,
+    wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+    /// Open tunnels by port, with the user that opened them.
+    tunnels: DashMap<u16, (Option<String>, Arc<TunnelStats>)>,
+
+    /// Bytes in and out of the closed tunnels of each user.
+    users: DashMap<String, (u64, u64)>,
+
+    /// Visitors by country, and whether they were admitted.
+    countries: DashMap<(String, bool), u64>,
+
+    /// Whether each API key validation backend is degraded, by URL.
+    validation_degraded: DashMap<String, bool>,
+    validation_failures: AtomicU64,
+    validation_grace: AtomicU64,
+
+    /// Data connections that matched no waiting visitor, by why.
+    unmatched_expired: AtomicU64,
+    unmatched_duplicate: AtomicU64,
+    unmatched_unknown: AtomicU64,
+}
+
+/// Why a data connection from a client matched no waiting visitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmatchedAccept {
+    /// The visitor timed out before the client accepted it.
+    Expired,
+
+    /// The client already accepted the visitor, or this stripe of it.
+    Duplicate,
+
+    /// The server does not know the visitor, or forgot it long ago.
+    Unknown,
+}
+
+impl UnmatchedAccept {
+    /// Name of the reason, as used in logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnmatchedAccept::Expired => "expired",
+            UnmatchedAccept::Duplicate => "duplicate",
+            UnmatchedAccept::Unknown => "unknown",
+        }
+    }
+}
+
+impl ServerMetrics {
+    /// Number of visitors currently waiting for their client.
+    pub fn pending(&self) -> u64 {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Count a visitor that starts waiting for its client.
+    pub fn enqueue(&self) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a waiting visitor that the client accepted after `wait`.
+    pub fn accept(&self, wait: Duration) {
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        let micros = wait.as_micros() as u64;
+        self.wait_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_wait_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Count a visitor that was disconnected before being accepted. Visitors
+    /// turned away by a full queue never started waiting.
+    pub fn drop_pending(&self, reason: CloseReason) {
+        match reason {
+            CloseReason::QueueFull => &self.queue_full,
+            CloseReason::AcceptTimeout => {
+                self.pending.fetch_sub(1, Ordering::Relaxed);
+                &self.accept_timeout
+            }
+            CloseReason::TunnelClosed => {
+                self.pending.fetch_sub(1, Ordering::Relaxed);
+                &self.tunnel_closed
+            }
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a data connection from a client that matched no waiting
+    /// visitor.
+    pub fn add_unmatched_accept(&self, reason: UnmatchedAccept) {
+        match reason {
+            UnmatchedAccept::Expired => &self.unmatched_expired,
+            UnmatchedAccept::Duplicate => &self.unmatched_duplicate,
+            UnmatchedAccept::Unknown => &self.unmatched_unknown,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a visitor from a country, if known, and whether the country
+    /// rules admitted it.
+    pub fn add_visitor(&self, country: Option<&str>, admitted: bool) {
+        let country = country.unwrap_or("unknown").to_string();
+        *self.countries.entry((country, admitted)).or_insert(0) += 1;
+    }
+
+    /// Mark an API key validation backend as degraded, or as recovered.
+    pub fn set_validation_degraded(&self, url: &str, degraded: bool) {
+        self.validation_degraded.insert(url.to_string(), degraded);
+    }
+
+    /// Count a failed request to the API key validation backend.
+    pub fn add_validation_failure(&self) {
+        self.validation_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a client let in from an expired validation while the backend
+    /// was unavailable.
+    pub fn add_validation_grace(&self) {
+        self.validation_grace.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Report the traffic of a tunnel that a user, if known, opened on a
+    /// port.
+    pub fn add_tunnel(&self, port: u16, user_id: Option<&str>, stats: Arc<TunnelStats>) {
+        self.tunnels
+            .insert(port, (user_id.map(String::from), stats));
+    }
+
+    /// Stop reporting a tunnel that closed, unless another took its port,
+    /// keeping its bytes in the totals of its user.
+    pub fn remove_tunnel(&self, port: u16, stats: &Arc<TunnelStats>) {
+        let removed =
+            (self.tunnels).remove_if(&port, |_, (_, current)| Arc::ptr_eq(current, stats));
+        if let Some((_, (Some(user_id), stats))) = removed {
+            let mut totals = self.users.entry(user_id).or_insert((0, 0));
+            totals.0 += stats.inbound();
+            totals.1 += stats.outbound();
+        }
+    }
+
+    /// Format the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let seconds = |micros: u64| micros as f64 / 1e6;
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP bore_pending_connections Visitors waiting for their client to accept them.\n\
+             # TYPE bore_pending_connections gauge\n\
+             bore_pending_connections {}",
+            self.pending(),
+        );
+        let _ = writeln!(
+            out,
+            "# HELP bore_pending_connections_total Visitors that waited for their client, by outcome.\n\
+             # TYPE bore_pending_connections_total counter"
+        );
+        for (outcome, counter) in [
+            ("accepted", &self.accepted),
+            (CloseReason::QueueFull.as_str(), &self.queue_full),
+            (CloseReason::AcceptTimeout.as_str(), &self.accept_timeout),
+            (CloseReason::TunnelClosed.as_str(), &self.tunnel_closed),
+        ] {
+            let _ = writeln!(
+                out,
+                "bore_pending_connections_total{{outcome=\"{outcome}\"}} {}",
+                load(counter)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP bore_unmatched_accepts_total Data connections from clients that matched no waiting visitor, by reason.\n\
+             # TYPE bore_unmatched_accepts_total counter"
+        );
+        for (reason, counter) in [
+            (UnmatchedAccept::Expired, &self.unmatched_expired),
+            (UnmatchedAccept::Duplicate, &self.unmatched_duplicate),
+            (UnmatchedAccept::Unknown, &self.unmatched_unknown),
+        ] {
+            let _ = writeln!(
+                out,
+                "bore_unmatched_accepts_total{{reason=\"{}\"}} {}",
+                reason.as_str(),
+                load(counter)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP bore_pending_wait_seconds Time that accepted visitors waited for their client.\n\
+             # TYPE bore_pending_wait_seconds summary\n\
+             bore_pending_wait_seconds_sum {}\n\
+             bore_pending_wait_seconds_count {}\n\
+             # HELP bore_pending_wait_seconds_max Longest time that an accepted visitor waited.\n\
+             # TYPE bore_pending_wait_seconds_max gauge\n\
+             bore_pending_wait_seconds_max {}",
+            seconds(load(&self.wait_micros)),
+            load(&self.accepted),
+            seconds(load(&self.max_wait_micros)),
+        );
+        let mut backends: Vec<_> = (self.validation_degraded.iter())
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        if !backends.is_empty() {
+            backends.sort_unstable();
+            let _ = writeln!(
+                out,
+                "# HELP bore_validation_backend_degraded Whether each API key validation backend is failing and being backed off from.\n\
+                 # TYPE bore_validation_backend_degraded gauge"
+            );
+        }
+        for (url, degraded) in backends {
+            let _ = writeln!(
+                out,
+                "bore_validation_backend_degraded{{url=\"{url}\"}} {}",
+                u8::from(degraded)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP bore_validation_backend_failures_total Failed requests to API key validation backends.\n\
+             # TYPE bore_validation_backend_failures_total counter\n\
+             bore_validation_backend_failures_total {}\n\
+             # HELP bore_validation_grace_admissions_total Clients let in from an expired validation while the backend was unavailable.\n\
+             # TYPE bore_validation_grace_admissions_total counter\n\
+             bore_validation_grace_admissions_total {}",
+            load(&self.validation_failures),
+            load(&self.validation_grace),
+        );
+        let mut tunnels: Vec<_> = self
+            .tunnels
+            .iter()
+            .map(|entry| {
+                let (user_id, stats) = entry.value();
+                (*entry.key(), user_id.clone(), Arc::clone(stats))
+            })
+            .collect();
+        tunnels.sort_unstable_by_key(|(port, _, _)| *port);
+        let labels = |port: &u16, user_id: &Option<String>| match user_id {
+            Some(user_id) => format!("port=\"{port}\",user=\"{}\"", escape(user_id)),
+            None => format!("port=\"{port}\""),
+        };
+        let _ = writeln!(
+            out,
+            "# HELP bore_tunnel_throughput_bytes_per_second Recent traffic through each tunnel.\n\
+             # TYPE bore_tunnel_throughput_bytes_per_second gauge"
+        );
+        for (port, user_id, stats) in &tunnels {
+            let labels = labels(port, user_id);
+            for (window, duration) in THROUGHPUT_WINDOWS {
+                let _ = writeln!(
+                    out,
+                    "bore_tunnel_throughput_bytes_per_second{{{labels},window=\"{window}\"}} {}",
+                    stats.throughput(duration),
+                );
+            }
+        }
+        let _ = writeln!(
+            out,
+            "# HELP bore_tunnel_connection_duration_seconds Time that recent connections of each tunnel stayed open.\n\
+             # TYPE bore_tunnel_connection_duration_seconds summary"
+        );
+        for (port, user_id, stats) in &tunnels {
+            if let Some(p95) = stats.p95_duration() {
+                let _ = writeln!(
+                    out,
+                    "bore_tunnel_connection_duration_seconds{{{},quantile=\"0.95\"}} {}",
+                    labels(port, user_id),
+                    p95.as_secs_f64(),
+                );
+            }
+        }
+        let mut users: Vec<_> = (self.users.iter())
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        for (_, user_id, stats) in &tunnels {
+            let Some(user_id) = user_id else { continue };
+            let totals = match users.iter_mut().find(|(user, _)| user == user_id) {
+                Some((_, totals)) => totals,
+                None => {
+                    users.push((user_id.clone(), (0, 0)));
+                    &mut users.last_mut().unwrap().1
+                }
+            };
+            totals.0 += stats.inbound();
+            totals.1 += stats.outbound();
+        }
+        if !users.is_empty() {
+            users.sort_unstable();
+            let _ = writeln!(
+                out,
+                "# HELP bore_user_bytes_total Bytes through the tunnels of each user, by direction.\n\
+                 # TYPE bore_user_bytes_total counter"
+            );
+        }
+        for (user_id, (inbound, outbound)) in users {
+            let user_id = escape(&user_id);
+            let _ = writeln!(
+                out,
+                "bore_user_bytes_total{{user=\"{user_id}\",direction=\"in\"}} {inbound}\n\
+                 bore_user_bytes_total{{user=\"{user_id}\",direction=\"out\"}} {outbound}",
+            );
+        }
+        let mut countries: Vec<_> = (self.countries.iter())
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        if !countries.is_empty() {
+            countries.sort_unstable();
+            let _ = writeln!(
+                out,
+                "# HELP bore_visitors_total Visitors of tunnels by country, and whether they were admitted.\n\
+                 # TYPE bore_visitors_total counter"
+            );
+        }
+        for ((country, admitted), count) in countries {
+            let outcome = if admitted { "admitted" } else { "rejected" };
+            let _ = writeln!(
+                out,
+                "bore_visitors_total{{country=\"{country}\",outcome=\"{outcome}\"}} {count}",
+            );
+        }
+        out
+    }
+}
+
+/// Escape a label value of the Prometheus text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve the metrics at `/metrics` on the given address until an error occurs.
+pub async fn serve(addr: SocketAddr, metrics: Arc<ServerMetrics>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = Arc::clone(&metrics);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let metrics = Arc::clone(&metrics);
+                async move {
+                    let response = match (req.method(), req.uri().path()) {
+                        (&Method::GET, "/metrics") => Response::builder()
+                            .header("Content-Type", "text/plain; version=0.0.4")
+                            .body(Body::from(metrics.render())),
+                        _ => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("not found")),
+                    };
+                    Ok::<_, Infallible>(response.unwrap())
+                }
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(&addr)?.serve(make_service);
+    info!(%addr, "metrics listening");
+    server.await?;
+    Ok(())
+}
@@ -0,0 +1,255 @@
+//! Multiplexing of data connections over one connection to the control port.
+//!
+//! Without multiplexing, each stripe of each proxied connection is a new
+//! connection to the control port, with its own TCP, TLS, and authentication
+//! handshakes before the first byte is forwarded. Clients that negotiate
+//! multiplexing instead open a single authenticated connection at startup and
+//! speak HTTP/2 over it, with one stream in place of each data connection.
+//! Streams begin with the usual accept message, so everything layered on top
+//! of data connections works the same over them.
+//!
+//! The client opens streams, so it is the HTTP/2 client, and the server
+//! answers each request immediately. The request and response bodies then
+//! carry the two directions of the stream, with HTTP/2 flow control keeping a
+//! slow stream from holding up the others.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use anyhow::{Context as _, Result};
+use h2::{client, server, Reason, RecvStream, SendStream};
+use hyper::body::{Buf, Bytes};
+use hyper::{Request, Response};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::timeout;
+use tokio_util::codec::{AnyDelimiterCodec, FramedParts};
+use tracing::{warn, Instrument};
+
+use crate::encryption::Encrypted;
+use crate::shared::NETWORK_TIMEOUT;
+use crate::tls::ControlStream;
+
+/// Receive window of each stream, which bounds how much a stream that is
+/// not being read can buffer.
+const STREAM_WINDOW: u32 = 1 << 20;
+
+/// Receive window of the connection, shared by all of its streams.
+const CONNECTION_WINDOW: u32 = 16 << 20;
+
+/// Connection to the control port that is carried as a multiplexed stream.
+pub struct MuxStream {
+    send: SendStream<Bytes>,
+    recv: Recv,
+
+    /// Data received but not read yet.
+    buf: Bytes,
+
+    /// Whether the sending half has been closed.
+    ended: bool,
+
+    /// Address of the other end of the underlying connection.
+    peer: SocketAddr,
+}
+
+/// Receiving half of a stream, which the client gets once the server answers.
+enum Recv {
+    Pending(client::ResponseFuture),
+    Open(RecvStream),
+}
+
+impl MuxStream {
+    /// Address of the other end of the underlying connection.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Wait for the stream to be reset by the peer, and explain why.
+    fn poll_reset(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        match ready!(self.send.poll_reset(cx)) {
+            Ok(Reason::NO_ERROR | Reason::CANCEL | Reason::STREAM_CLOSED) => {
+                Poll::Ready(io::ErrorKind::BrokenPipe.into())
+            }
+            Ok(reason) => Poll::Ready(into_io(reason.into())),
+            Err(err) => Poll::Ready(into_io(err)),
+        }
+    }
+}
+
+/// Client side of a multiplexed connection, which opens streams.
+#[derive(Clone)]
+pub struct MuxClient {
+    requests: client::SendRequest<Bytes>,
+    peer: SocketAddr,
+}
+
+impl MuxClient {
+    /// Start multiplexing over an authenticated connection to the control port.
+    pub async fn connect(parts: FramedParts<ControlStream, AnyDelimiterCodec>) -> Result<Self> {
+        let peer = parts.io.peer_addr()?;
+        let handshake = client::Builder::new()
+            .initial_window_size(STREAM_WINDOW)
+            .initial_connection_window_size(CONNECTION_WINDOW)
+            .handshake(Encrypted::plain(parts));
+        let (requests, connection) = timeout(NETWORK_TIMEOUT, handshake)
+            .await
+            .context("timed out starting multiplexed connection")??;
+        tokio::spawn(
+            async move {
+                if let Err(err) = connection.await {
+                    warn!(%err, "multiplexed connection failed");
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(Self { requests, peer })
+    }
+
+    /// Open a new stream, to be used like a new connection to the control port.
+    pub async fn open(&self) -> Result<ControlStream> {
+        let mut requests = self.requests.clone().ready().await?;
+        let (response, send) = requests.send_request(Request::new(()), false)?;
+        Ok(ControlStream::Mux(Box::new(MuxStream {
+            send,
+            recv: Recv::Pending(response),
+            buf: Bytes::new(),
+            ended: false,
+            peer: self.peer,
+        })))
+    }
+}
+
+/// Server side of a multiplexed connection, which accepts streams.
+pub struct MuxServer {
+    connection: server::Connection<Encrypted<ControlStream>, Bytes>,
+    peer: SocketAddr,
+}
+
+impl MuxServer {
+    /// Start multiplexing over an authenticated connection from a client.
+    pub async fn accept(parts: FramedParts<ControlStream, AnyDelimiterCodec>) -> Result<Self> {
+        let peer = parts.io.peer_addr()?;
+        let handshake = server::Builder::new()
+            .initial_window_size(STREAM_WINDOW)
+            .initial_connection_window_size(CONNECTION_WINDOW)
+            .handshake(Encrypted::plain(parts));
+        let connection = timeout(NETWORK_TIMEOUT, handshake)
+            .await
+            .context("timed out starting multiplexed connection")??;
+        Ok(Self { connection, peer })
+    }
+
+    /// Wait for the client to open a stream, or return `None` once the
+    /// connection is closed. Streams only make progress while this is being
+    /// polled. This is cancel safe.
+    pub async fn next(&mut self) -> Option<Result<ControlStream>> {
+        let (request, mut respond) = match self.connection.accept().await? {
+            Ok(stream) => stream,
+            Err(err) => return Some(Err(err.into())),
+        };
+        let send = match respond.send_response(Response::new(()), false) {
+            Ok(send) => send,
+            Err(err) => return Some(Err(err.into())),
+        };
+        Some(Ok(ControlStream::Mux(Box::new(MuxStream {
+            send,
+            recv: Recv::Open(request.into_body()),
+            buf: Bytes::new(),
+            ended: false,
+            peer: self.peer,
+        }))))
+    }
+}
+
+fn into_io(err: h2::Error) -> io::Error {
+    match err.is_io() {
+        true => err.into_io().expect("error is an I/O error"),
+        false => io::Error::other(err),
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.buf.is_empty() {
+            let recv = match &mut this.recv {
+                Recv::Open(recv) => recv,
+                Recv::Pending(response) => {
+                    let response = ready!(Pin::new(response).poll(cx)).map_err(into_io)?;
+                    this.recv = Recv::Open(response.into_body());
+                    continue;
+                }
+            };
+            match ready!(recv.poll_data(cx)) {
+                Some(Ok(data)) => this.buf = data,
+                Some(Err(err)) if err.reason() == Some(Reason::NO_ERROR) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Some(Err(err)) => return Poll::Ready(Err(into_io(err))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = this.buf.len().min(buf.remaining());
+        buf.put_slice(&this.buf[..n]);
+        this.buf.advance(n);
+        if let Recv::Open(recv) = &mut this.recv {
+            // The window is only reopened for data that was read, so that a
+            // stream that is not read stops its sender instead of buffering.
+            let _ = recv.flow_control().release_capacity(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        this.send.reserve_capacity(buf.len());
+        while this.send.capacity() == 0 {
+            match ready!(this.send.poll_capacity(cx)) {
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => return this.poll_reset(cx).map(Err),
+            }
+        }
+        let n = this.send.capacity().min(buf.len());
+        match this
+            .send
+            .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+        {
+            Ok(()) => Poll::Ready(Ok(n)),
+            Err(_) => this.poll_reset(cx).map(Err),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Data is handed to the connection task as soon as it is written.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.ended {
+            return Poll::Ready(Ok(()));
+        }
+        match this.send.send_data(Bytes::new(), true) {
+            Ok(()) => {
+                this.ended = true;
+                Poll::Ready(Ok(()))
+            }
+            Err(_) => this.poll_reset(cx).map(Err),
+        }
+    }
+}
@@ -0,0 +1,640 @@
+//! Logging visitors of HTTP tunnels in with an OpenID Connect provider.
+//!
+//! A tunnel can ask the server to only let through visitors who log in with
+//! the server's OIDC provider as one of a list of emails, or with any email
+//! at one of a list of domains, like `@example.com`. Visitors without a
+//! session are redirected to the provider, which sends them back to a
+//! callback on the server's own domain, as providers only redirect to URLs
+//! that are registered in advance. There the server exchanges the code for
+//! an ID token, and sends visitors on to the tunnel's hostname with a
+//! short-lived grant, which it turns into a session cookie for that host.
+//!
+//! Each login is bound to the browser that started it by a random nonce in
+//! a cookie, which is set on the tunnel's hostname and, on the way to the
+//! provider, on the server's domain. The callback and the grant are only
+//! accepted with that cookie, so a visitor cannot be made to finish a login
+//! that someone else started. The provider also puts a digest of the nonce
+//! in the ID token, which must match.
+//!
+//! The ID token comes straight from the provider's token endpoint, so its
+//! issuer, audience, and expiry are checked but not its signature, as
+//! OpenID Connect allows. Logins, grants, and sessions are signed with a key
+//! that the server makes up when it starts, so they do not outlive it.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{DecodingKey, Validation};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::http::RequestHead;
+
+/// Path on the server's domain that the provider redirects visitors to.
+pub const CALLBACK_PATH: &str = "/_bore/oidc/callback";
+
+/// Path on the server's domain that sends visitors on to the provider.
+pub const LOGIN_PATH: &str = "/_bore/oidc/login";
+
+/// Path on a tunnel's hostname that turns a grant into a session.
+pub const SESSION_PATH: &str = "/_bore/oidc/session";
+
+/// Name of the session cookie, which is never passed on to tunnels.
+pub const COOKIE_NAME: &str = "bore_oidc";
+
+/// Name of the cookie with the nonce of a login in progress, which is never
+/// passed on to tunnels either.
+pub const LOGIN_COOKIE_NAME: &str = "bore_oidc_login";
+
+/// Most emails and domains that a tunnel may let through.
+pub const MAX_RULES: usize = 64;
+
+/// Time that visitors have to log in with the provider.
+const LOGIN_TTL: Duration = Duration::from_secs(600);
+
+/// Time that a grant can be turned into a session.
+const GRANT_TTL: Duration = Duration::from_secs(60);
+
+/// Time that a session lasts before visitors must log in again.
+const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longest email address.
+pub const MAX_EMAIL_LENGTH: usize = 254;
+
+/// Provider that visitors log in with.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// URL of the provider, under which it publishes its discovery document.
+    pub issuer: String,
+
+    /// ID of the server as a client of the provider.
+    pub client_id: String,
+
+    /// Secret of the server as a client of the provider, if it has one.
+    pub client_secret: Option<String>,
+}
+
+/// Gate that logs visitors of HTTP tunnels in with an OIDC provider.
+pub struct Oidc {
+    config: OidcConfig,
+
+    /// URL of [`CALLBACK_PATH`] on the server's domain.
+    redirect_url: String,
+    client: reqwest::Client,
+    key: Vec<u8>,
+
+    /// Endpoints from the provider's discovery document, once fetched.
+    endpoints: Mutex<Option<Endpoints>>,
+}
+
+/// Endpoints of a provider, from its discovery document.
+#[derive(Debug, Clone, Deserialize)]
+struct Endpoints {
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// Login in progress, carried through the provider as its state.
+#[derive(Serialize, Deserialize)]
+struct Login {
+    proto: String,
+    host: String,
+    path: String,
+
+    /// Digest of the nonce in the browser's login cookie.
+    nonce: String,
+    exp: u64,
+}
+
+/// Proof that a visitor logged in, to be turned into a session at a host.
+#[derive(Serialize, Deserialize)]
+struct Grant {
+    host: String,
+    email: String,
+    path: String,
+
+    /// Digest of the nonce of the login.
+    nonce: String,
+    exp: u64,
+}
+
+/// Session of a visitor at a host.
+#[derive(Serialize, Deserialize)]
+struct Session {
+    host: String,
+    email: String,
+    exp: u64,
+}
+
+/// Claims of an ID token that the gate uses, besides those it validates.
+#[derive(Deserialize)]
+struct Claims {
+    email: Option<String>,
+    email_verified: Option<bool>,
+    nonce: Option<String>,
+}
+
+/// Response that a visitor gets instead of reaching a tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    /// Status code of the response.
+    pub status: u16,
+
+    /// Reason phrase of the status.
+    pub reason: &'static str,
+
+    /// Headers besides those of every response.
+    pub headers: Vec<(&'static str, String)>,
+
+    /// Plain text body.
+    pub body: String,
+}
+
+impl Oidc {
+    /// Log visitors in with a provider that redirects them back to a URL,
+    /// which must be [`CALLBACK_PATH`] on the server's domain.
+    pub fn new(config: OidcConfig, redirect_url: String) -> Self {
+        Self {
+            config,
+            redirect_url,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .expect("failed to create HTTP client"),
+            key: [Uuid::new_v4().into_bytes(), Uuid::new_v4().into_bytes()].concat(),
+            endpoints: Mutex::new(None),
+        }
+    }
+
+    /// Check the first request of a visitor to a tunnel that lets through
+    /// visitors by rules, returning the response that the visitor gets
+    /// instead, or `None` if it may pass.
+    pub async fn gate(
+        &self,
+        request: &RequestHead,
+        proto: &str,
+        host: &str,
+        rules: &[String],
+    ) -> Option<Reply> {
+        let host = host.to_ascii_lowercase();
+        if let Some(query) = request.path.strip_prefix(SESSION_PATH) {
+            return Some(self.start_session(request, query, proto, &host));
+        }
+        let session = (cookie(request, COOKIE_NAME))
+            .and_then(|value| self.open::<Session>("session", &value))
+            .filter(|session| session.host == host);
+        if let Some(session) = session {
+            if allows(rules, &session.email) {
+                return None;
+            }
+            let body = format!("{} may not visit this tunnel\n", session.email);
+            return Some(reply(403, "Forbidden", vec![], body));
+        }
+        let mut nonce = [0; 16];
+        getrandom::getrandom(&mut nonce).expect("failed to generate login nonce");
+        let nonce = hex::encode(nonce);
+        let login = Login {
+            proto: proto.to_string(),
+            host,
+            path: request.path.clone(),
+            nonce: digest(&nonce),
+            exp: expiry(LOGIN_TTL),
+        };
+        let url = Url::parse_with_params(
+            &self.login_url(),
+            [
+                ("state", self.seal("login", &login)),
+                ("nonce", nonce.clone()),
+            ],
+        )
+        .expect("login URL is valid");
+        let cookie = login_cookie(&nonce, proto);
+        Some(redirect(url.into(), vec![("Set-Cookie", cookie)]))
+    }
+
+    /// Start a login at [`LOGIN_PATH`], binding it to the browser on the
+    /// server's domain too, and send the visitor on to the provider.
+    pub async fn login(&self, request: &RequestHead) -> Reply {
+        let params = query(&request.path);
+        let param = |name: &str| {
+            (params.iter())
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let login =
+            param("state").and_then(|state| Some((state, self.open::<Login>("login", state)?)));
+        let nonce = param("nonce").filter(|nonce| {
+            login
+                .as_ref()
+                .is_some_and(|(_, login)| digest(nonce) == login.nonce)
+        });
+        let (Some((state, login)), Some(nonce)) = (login, nonce) else {
+            let body = "invalid or expired login\n".to_string();
+            return reply(400, "Bad Request", vec![], body);
+        };
+        match self.authorization_url(state, &login.nonce).await {
+            Ok(url) => {
+                let proto = self
+                    .redirect_url
+                    .split_once("://")
+                    .map_or("http", |(proto, _)| proto);
+                redirect(url, vec![("Set-Cookie", login_cookie(nonce, proto))])
+            }
+            Err(err) => {
+                warn!(err = format!("{err:#}"), "could not reach OIDC provider");
+                let body = "identity provider is unavailable\n".to_string();
+                reply(502, "Bad Gateway", vec![], body)
+            }
+        }
+    }
+
+    /// Finish a login at [`CALLBACK_PATH`], sending the visitor on to the
+    /// tunnel they came from.
+    pub async fn callback(&self, request: &RequestHead) -> Reply {
+        let params = query(&request.path);
+        let param = |name: &str| {
+            (params.iter())
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        if let Some(error) = param("error") {
+            return reply(403, "Forbidden", vec![], format!("login failed: {error}\n"));
+        }
+        let login = param("state").and_then(|state| self.open::<Login>("login", state));
+        let (Some(login), Some(code)) = (login, param("code")) else {
+            let body = "invalid or expired login\n".to_string();
+            return reply(400, "Bad Request", vec![], body);
+        };
+        if !started_here(request, &login.nonce) {
+            let body = "login was started in another browser\n".to_string();
+            return reply(400, "Bad Request", vec![], body);
+        }
+        let email = match self.exchange(code, &login.nonce).await {
+            Ok(email) => email,
+            Err(err) => {
+                warn!(err = format!("{err:#}"), "could not finish OIDC login");
+                let body = "could not log in with the identity provider\n".to_string();
+                return reply(403, "Forbidden", vec![], body);
+            }
+        };
+        info!(email, host = login.host, "visitor logged in");
+        let grant = Grant {
+            host: login.host.clone(),
+            email,
+            path: login.path,
+            nonce: login.nonce,
+            exp: expiry(GRANT_TTL),
+        };
+        let token = self.seal("grant", &grant);
+        let url = format!(
+            "{}://{}{SESSION_PATH}?token={token}",
+            login.proto, login.host
+        );
+        redirect(url, vec![("Set-Cookie", finished_login())])
+    }
+
+    /// Turn a grant into a session cookie, and send the visitor back to the
+    /// page they first asked for.
+    fn start_session(
+        &self,
+        request: &RequestHead,
+        query_string: &str,
+        proto: &str,
+        host: &str,
+    ) -> Reply {
+        let params = query(query_string);
+        let grant = (params.iter())
+            .find(|(key, _)| key == "token")
+            .and_then(|(_, token)| self.open::<Grant>("grant", token))
+            .filter(|grant| grant.host == host);
+        let Some(grant) = grant else {
+            let body = "invalid or expired login\n".to_string();
+            return reply(400, "Bad Request", vec![], body);
+        };
+        if !started_here(request, &grant.nonce) {
+            let body = "login was started in another browser\n".to_string();
+            return reply(400, "Bad Request", vec![], body);
+        }
+        let session = Session {
+            host: grant.host,
+            email: grant.email,
+            exp: expiry(SESSION_TTL),
+        };
+        let mut cookie = format!(
+            "{COOKIE_NAME}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            self.seal("session", &session),
+            SESSION_TTL.as_secs()
+        );
+        if proto == "https" {
+            cookie.push_str("; Secure");
+        }
+        // Paths like `//example.org` would leave the tunnel.
+        let path = match grant.path.starts_with('/') && !grant.path.starts_with("//") {
+            true => grant.path,
+            false => "/".to_string(),
+        };
+        let finished = ("Set-Cookie", finished_login());
+        redirect(path, vec![("Set-Cookie", cookie), finished])
+    }
+
+    /// URL of [`LOGIN_PATH`] on the server's domain.
+    fn login_url(&self) -> String {
+        let base = self.redirect_url.strip_suffix(CALLBACK_PATH);
+        format!("{}{LOGIN_PATH}", base.unwrap_or(&self.redirect_url))
+    }
+
+    /// URL of the provider that starts a login with a state and a nonce.
+    async fn authorization_url(&self, state: &str, nonce: &str) -> Result<String> {
+        let endpoints = self.endpoints().await?;
+        let url = Url::parse_with_params(
+            &endpoints.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.redirect_url),
+                ("scope", "openid email"),
+                ("state", state),
+                ("nonce", nonce),
+            ],
+        )?;
+        Ok(url.into())
+    }
+
+    /// Exchange an authorization code for the email of the visitor, from an
+    /// ID token with the nonce of their login.
+    async fn exchange(&self, code: &str, nonce: &str) -> Result<String> {
+        let endpoints = self.endpoints().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_url),
+            ("client_id", &self.config.client_id),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let response = (self.client.post(&endpoints.token_endpoint))
+            .form(&form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("token endpoint returned {}", response.status());
+        }
+        #[derive(Deserialize)]
+        struct Tokens {
+            id_token: String,
+        }
+        let tokens: Tokens = response.json().await?;
+
+        let header = jsonwebtoken::decode_header(&tokens.id_token)?;
+        let mut validation = Validation::new(header.alg);
+        validation.insecure_disable_signature_validation();
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        let key = DecodingKey::from_secret(&[]);
+        let claims = jsonwebtoken::decode::<Claims>(&tokens.id_token, &key, &validation)
+            .context("invalid ID token")?
+            .claims;
+        ensure!(
+            claims.nonce.as_deref() == Some(nonce),
+            "ID token is for another login"
+        );
+        let email = claims.email.context("provider did not share an email")?;
+        ensure!(claims.email_verified == Some(true), "email is not verified");
+        Ok(email.to_ascii_lowercase())
+    }
+
+    /// Endpoints of the provider, fetching its discovery document the first
+    /// time.
+    async fn endpoints(&self) -> Result<Endpoints> {
+        let mut endpoints = self.endpoints.lock().await;
+        if let Some(endpoints) = &*endpoints {
+            return Ok(endpoints.clone());
+        }
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+        let response = self.client.get(&url).send().await?;
+        if !response.status().is_success() {
+            bail!("discovery document returned {}", response.status());
+        }
+        let fetched: Endpoints = response.json().await?;
+        *endpoints = Some(fetched.clone());
+        Ok(fetched)
+    }
+
+    /// Sign a value for a purpose, as `payload.signature`.
+    fn seal(&self, purpose: &str, value: &impl Serialize) -> String {
+        let payload = BASE64_URL.encode(serde_json::to_vec(value).expect("serializable"));
+        let signature = BASE64_URL.encode(self.mac(purpose, &payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// Value that was signed for a purpose and has not expired, if any.
+    fn open<T: DeserializeOwned + Expiring>(&self, purpose: &str, sealed: &str) -> Option<T> {
+        let (payload, signature) = sealed.split_once('.')?;
+        let signature = BASE64_URL.decode(signature).ok()?;
+        self.mac(purpose, payload).verify_slice(&signature).ok()?;
+        let value: T = serde_json::from_slice(&BASE64_URL.decode(payload).ok()?).ok()?;
+        (value.exp() > expiry(Duration::ZERO)).then_some(value)
+    }
+
+    fn mac(&self, purpose: &str, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC can take key of any size");
+        mac.update(purpose.as_bytes());
+        mac.update(b".");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+/// Signed values that expire.
+trait Expiring {
+    /// Time that the value expires, in seconds since the Unix epoch.
+    fn exp(&self) -> u64;
+}
+
+impl Expiring for Login {
+    fn exp(&self) -> u64 {
+        self.exp
+    }
+}
+
+impl Expiring for Grant {
+    fn exp(&self) -> u64 {
+        self.exp
+    }
+}
+
+impl Expiring for Session {
+    fn exp(&self) -> u64 {
+        self.exp
+    }
+}
+
+/// Check a rule of visitors that a tunnel lets through: an email, or a
+/// domain of emails starting with `@`.
+///
+/// ```
+/// use bore_cli::oidc::check_rule;
+///
+/// assert!(check_rule("alice@example.com").is_ok());
+/// assert!(check_rule("@example.com").is_ok());
+/// assert!(check_rule("example.com").is_err());
+/// assert!(check_rule("alice@").is_err());
+/// ```
+pub fn check_rule(rule: &str) -> Result<()> {
+    ensure!(rule.len() <= MAX_EMAIL_LENGTH, "email is too long");
+    let (user, domain) = rule
+        .split_once('@')
+        .context("expected an email or a domain starting with @")?;
+    ensure!(!domain.is_empty(), "domain must not be empty");
+    ensure!(
+        !(user.contains('@') || domain.contains('@')),
+        "expected a single @"
+    );
+    ensure!(
+        !rule.chars().any(|c| c.is_whitespace() || c.is_control()),
+        "email must not contain whitespace"
+    );
+    Ok(())
+}
+
+/// Whether rules from [`check_rule`] let through a visitor with an email.
+///
+/// ```
+/// use bore_cli::oidc::allows;
+///
+/// let rules = ["alice@example.com".to_string(), "@corp.example".to_string()];
+/// assert!(allows(&rules, "Alice@Example.com"));
+/// assert!(allows(&rules, "bob@corp.example"));
+/// assert!(!allows(&rules, "bob@example.com"));
+/// assert!(!allows(&rules, "mallory@evilcorp.example"));
+/// ```
+pub fn allows(rules: &[String], email: &str) -> bool {
+    let email = email.to_ascii_lowercase();
+    let domain = email.rsplit_once('@').map(|(_, domain)| domain);
+    rules.iter().any(|rule| {
+        let rule = rule.to_ascii_lowercase();
+        match rule.strip_prefix('@') {
+            Some(allowed) => domain == Some(allowed),
+            None => rule == email,
+        }
+    })
+}
+
+/// Remove the session cookie from a request, so that tunnels never see it.
+///
+/// ```
+/// use bore_cli::http::RequestHead;
+/// use bore_cli::oidc::strip_cookie;
+///
+/// let head = b"GET / HTTP/1.1\r\nCookie: a=1; bore_oidc=x; b=2\r\nCookie: bore_oidc=y\r\n\r\n";
+/// let mut request = RequestHead::parse(head).unwrap().unwrap();
+/// strip_cookie(&mut request);
+/// assert_eq!(request.headers, [("Cookie".to_string(), "a=1; b=2".to_string())]);
+/// ```
+pub fn strip_cookie(request: &mut RequestHead) {
+    request.headers.retain_mut(|(name, value)| {
+        if !name.eq_ignore_ascii_case("cookie") {
+            return true;
+        }
+        let kept: Vec<&str> = (value.split(';'))
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty() && !is_session_cookie(pair))
+            .collect();
+        *value = kept.join("; ");
+        !value.is_empty()
+    });
+}
+
+fn is_session_cookie(pair: &str) -> bool {
+    let name = pair.split_once('=').map(|(name, _)| name.trim());
+    name == Some(COOKIE_NAME) || name == Some(LOGIN_COOKIE_NAME)
+}
+
+/// Path of the login cookie, under which are all paths that read it.
+const LOGIN_COOKIE_PATH: &str = "/_bore/oidc";
+
+/// Cookie with the nonce of a login that a browser starts.
+fn login_cookie(nonce: &str, proto: &str) -> String {
+    let mut cookie = format!(
+        "{LOGIN_COOKIE_NAME}={nonce}; Path={LOGIN_COOKIE_PATH}; Max-Age={}; HttpOnly; SameSite=Lax",
+        LOGIN_TTL.as_secs()
+    );
+    if proto == "https" {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+/// Cookie that removes the login cookie once a login is finished.
+fn finished_login() -> String {
+    format!("{LOGIN_COOKIE_NAME}=; Path={LOGIN_COOKIE_PATH}; Max-Age=0")
+}
+
+/// Whether a request comes from the browser that started a login, by the
+/// digest of its nonce.
+fn started_here(request: &RequestHead, nonce: &str) -> bool {
+    cookie(request, LOGIN_COOKIE_NAME).is_some_and(|value| digest(&value) == nonce)
+}
+
+/// Digest of a login nonce, as hex.
+fn digest(nonce: &str) -> String {
+    hex::encode(Sha256::digest(nonce.as_bytes()))
+}
+
+/// Value of a cookie that a request sends, if any.
+fn cookie(request: &RequestHead, name: &str) -> Option<String> {
+    (request.headers.iter())
+        .filter(|(header, _)| header.eq_ignore_ascii_case("cookie"))
+        .flat_map(|(_, value)| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Decoded parameters of the query of a path.
+fn query(path: &str) -> Vec<(String, String)> {
+    let query = path.split_once('?').map_or("", |(_, query)| query);
+    Url::parse(&format!("http://localhost/?{query}"))
+        .map(|url| url.query_pairs().into_owned().collect())
+        .unwrap_or_default()
+}
+
+/// Seconds since the Unix epoch after a time from now.
+fn expiry(ttl: Duration) -> u64 {
+    (SystemTime::now() + ttl)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn redirect(location: String, mut headers: Vec<(&'static str, String)>) -> Reply {
+    headers.push(("Location", location));
+    reply(302, "Found", headers, "redirecting\n".into())
+}
+
+fn reply(
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+) -> Reply {
+    Reply {
+        status,
+        reason,
+        headers,
+        body,
+    }
+}
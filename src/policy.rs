@@ -0,0 +1,127 @@
+//! Scriptable admission policy for incoming public connections.
+//!
+//! Operators can write a short [Rhai](https://rhai.rs) script that is evaluated
+//! for every connection arriving on a tunnel, without recompiling the server.
+//! The script sees these variables:
+//!
+//! - `peer_ip`: address of the visitor, as a string
+//! - `port`: public port of the tunnel
+//! - `connections`: number of connections the server is currently forwarding
+//! - `hour`, `weekday`: current time in UTC, with Monday as weekday 0
+//!
+//! It evaluates to `"accept"` or `true` to let the connection through,
+//! `"reject"` or `false` to close it, or `"throttle"` or a number of
+//! milliseconds to slow down accepting further connections on the tunnel.
+//!
+//! ```text
+//! if peer_ip.starts_with("10.") { "accept" }
+//! else if connections > 1000 { 250 }
+//! else if hour < 6 { "reject" }
+//! else { "accept" }
+//! ```
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rhai::{Dynamic, Engine, Scope, AST};
+
+/// Delay applied by a bare `"throttle"` decision.
+const DEFAULT_THROTTLE: Duration = Duration::from_millis(500);
+
+/// Longest delay a policy can impose, so tunnels keep sending heartbeats.
+const MAX_THROTTLE: Duration = Duration::from_secs(2);
+
+/// Maximum number of operations a policy may run for a single connection.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// Facts about an incoming connection, passed to the policy.
+#[derive(Debug, Clone)]
+pub struct Admission {
+    /// Address of the visitor.
+    pub peer_ip: IpAddr,
+
+    /// Public port of the tunnel the visitor connected to.
+    pub port: u16,
+
+    /// Number of connections the server is currently forwarding.
+    pub connections: usize,
+}
+
+/// Outcome of evaluating the policy for a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Forward the connection as usual.
+    Accept,
+
+    /// Close the connection immediately.
+    Reject,
+
+    /// Forward the connection, then wait before accepting more on the tunnel.
+    Throttle(Duration),
+}
+
+/// A compiled admission policy script.
+pub struct Policy {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Policy {
+    /// Compile a policy from the source of a script.
+    pub fn compile(script: &str) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine
+            .compile(script)
+            .map_err(|err| anyhow!("invalid policy script: {err}"))?;
+        Ok(Self { engine, ast })
+    }
+
+    /// Read and compile a policy script from a file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let script = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read policy {}", path.display()))?;
+        Self::compile(&script)
+    }
+
+    /// Decide what to do with an incoming connection.
+    pub fn evaluate(&self, admission: &Admission) -> Result<Decision> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let mut scope = Scope::new();
+        scope.push_constant("peer_ip", admission.peer_ip.to_string());
+        scope.push_constant("port", admission.port as i64);
+        scope.push_constant("connections", admission.connections as i64);
+        scope.push_constant("hour", now / 3600 % 24);
+        // The Unix epoch fell on a Thursday.
+        scope.push_constant("weekday", (now / 86400 + 3) % 7);
+
+        let value: Dynamic = self
+            .engine
+            .eval_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|err| anyhow!("policy failed: {err}"))?;
+        to_decision(value)
+    }
+}
+
+fn to_decision(value: Dynamic) -> Result<Decision> {
+    if let Ok(accept) = value.as_bool() {
+        return Ok(if accept {
+            Decision::Accept
+        } else {
+            Decision::Reject
+        });
+    }
+    if let Ok(millis) = value.as_int() {
+        let delay = Duration::from_millis(millis.max(0) as u64);
+        return Ok(Decision::Throttle(delay.min(MAX_THROTTLE)));
+    }
+    match value.into_string().as_deref() {
+        Ok("accept") => Ok(Decision::Accept),
+        Ok("reject") => Ok(Decision::Reject),
+        Ok("throttle") => Ok(Decision::Throttle(DEFAULT_THROTTLE)),
+        Ok(other) => bail!("policy returned unknown decision {other:?}"),
+        Err(kind) => bail!("policy returned a value of type {kind}"),
+    }
+}
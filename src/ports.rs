@@ -0,0 +1,297 @@
+//! Sets of ports that tunnels may listen on.
+//!
+//! A server usually hands out one contiguous range of ports, but hosts that
+//! run other services need gaps in it. A [`PortSet`] is made of any number of
+//! ranges, minus any number of excluded ports, and picks random ports evenly
+//! across what is left.
+//!
+//! Which free port a tunnel gets is up to a [`PortAllocator`]. Besides the
+//! default of [`RandomPorts`], the server can hand out ports in order with
+//! [`SequentialPorts`], avoid ports that tunnels just closed with
+//! [`LeastRecentlyUsed`], or give each client the same port every time with
+//! [`HashedPorts`].
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::{ensure, Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Ports that tunnels may listen on, as disjoint ranges in ascending order.
+///
+/// ```
+/// use bore_cli::ports::PortSet;
+///
+/// let ports = PortSet::new(vec![20000..=21000, 30000..=31000], vec![20500..=20599]);
+/// assert!(ports.contains(20000) && ports.contains(30500));
+/// assert!(!ports.contains(20500) && !ports.contains(25000));
+/// assert_eq!(ports.len(), 1902);
+///
+/// let narrowed = ports.within(&(20550..=30000));
+/// assert_eq!(narrowed.len(), 402);
+/// assert!(narrowed.contains(narrowed.random().unwrap()));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortSet {
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl PortSet {
+    /// Set of the ports in any of the ranges, except those that are excluded.
+    pub fn new(ranges: Vec<RangeInclusive<u16>>, excluded: Vec<RangeInclusive<u16>>) -> Self {
+        let mut ranges: Vec<_> = ranges
+            .into_iter()
+            .filter(|range| !range.is_empty())
+            .collect();
+        ranges.sort_unstable_by_key(|range| *range.start());
+        let mut merged: Vec<RangeInclusive<u16>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if u32::from(*range.start()) <= u32::from(*last.end()) + 1 => {
+                    *last = *last.start()..=(*last.end()).max(*range.end());
+                }
+                _ => merged.push(range),
+            }
+        }
+        let mut set = Self { ranges: merged };
+        for excluded in excluded.iter().filter(|range| !range.is_empty()) {
+            set.ranges = (set.ranges.iter())
+                .flat_map(|range| {
+                    let below = (*excluded.start() > *range.start())
+                        .then(|| *range.start()..=(*excluded.start() - 1).min(*range.end()));
+                    let above = (*excluded.end() < *range.end())
+                        .then(|| (*excluded.end() + 1).max(*range.start())..=*range.end());
+                    [below, above]
+                })
+                .flatten()
+                .filter(|range| !range.is_empty())
+                .collect();
+        }
+        set
+    }
+
+    /// Whether a port is in the set.
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|range| range.contains(&port))
+    }
+
+    /// Number of ports in the set.
+    pub fn len(&self) -> usize {
+        (self.ranges.iter())
+            .map(|range| usize::from(*range.end() - *range.start()) + 1)
+            .sum()
+    }
+
+    /// Whether the set has no ports.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Smallest range that holds every port of the set, if it has any.
+    pub fn bounds(&self) -> Option<RangeInclusive<u16>> {
+        let first = self.ranges.first()?;
+        let last = self.ranges.last()?;
+        Some(*first.start()..=*last.end())
+    }
+
+    /// Ranges of the set, in ascending order.
+    pub fn ranges(&self) -> &[RangeInclusive<u16>] {
+        &self.ranges
+    }
+
+    /// Ports of the set that are also in a range.
+    pub fn within(&self, range: &RangeInclusive<u16>) -> Self {
+        let ranges = (self.ranges.iter())
+            .map(|own| (*own.start()).max(*range.start())..=(*own.end()).min(*range.end()))
+            .filter(|range| !range.is_empty())
+            .collect();
+        Self { ranges }
+    }
+
+    /// Port chosen uniformly at random from the set, if it has any.
+    pub fn random(&self) -> Option<u16> {
+        match self.is_empty() {
+            true => None,
+            false => self.nth(fastrand::usize(..self.len())),
+        }
+    }
+
+    /// Port at a position of the set in ascending order, if it has that many.
+    pub fn nth(&self, mut index: usize) -> Option<u16> {
+        for range in &self.ranges {
+            let size = usize::from(*range.end() - *range.start()) + 1;
+            if index < size {
+                return Some(*range.start() + index as u16);
+            }
+            index -= size;
+        }
+        None
+    }
+
+    /// Ports of the set in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.ranges.iter().flat_map(|range| range.clone())
+    }
+
+    /// Ports of the set in ascending order from one, wrapping around to the
+    /// smallest.
+    pub fn iter_from(&self, start: u16) -> impl Iterator<Item = u16> + '_ {
+        (self.iter().skip_while(move |port| *port < start))
+            .chain(self.iter().take_while(move |port| *port < start))
+    }
+}
+
+impl From<RangeInclusive<u16>> for PortSet {
+    fn from(range: RangeInclusive<u16>) -> Self {
+        Self::new(vec![range], Vec::new())
+    }
+}
+
+/// Parse a port, or a range of ports as `MIN-MAX`.
+///
+/// ```
+/// use bore_cli::ports::parse_port_range;
+///
+/// assert_eq!(parse_port_range("20000-21000").unwrap(), 20000..=21000);
+/// assert_eq!(parse_port_range("8080").unwrap(), 8080..=8080);
+/// assert!(parse_port_range("21000-20000").is_err());
+/// ```
+pub fn parse_port_range(input: &str) -> Result<RangeInclusive<u16>> {
+    let (min, max) = input.split_once('-').unwrap_or((input, input));
+    let parse =
+        |port: &str| (port.trim().parse::<u16>()).with_context(|| format!("invalid port {port:?}"));
+    let range = parse(min)?..=parse(max)?;
+    ensure!(!range.is_empty(), "port range {input:?} is empty");
+    Ok(range)
+}
+
+/// Strategy for choosing the ports of tunnels that do not ask for one.
+///
+/// The server tries the candidates in order until it can listen on one, and
+/// reports which ports tunnels end up on and when they close. Ports that
+/// clients ask for are reported as well.
+pub trait PortAllocator: Send + Sync {
+    /// Up to `count` ports of a set to try for a new tunnel, in order of
+    /// preference. The client is named by its credential, or else by its IP
+    /// address, along with the name of the tunnel if it has one.
+    fn candidates(&self, ports: &PortSet, client: &str, count: usize) -> Vec<u16>;
+
+    /// A tunnel is now listening on a port.
+    fn allocated(&self, _port: u16) {}
+
+    /// The tunnel on a port has closed.
+    fn released(&self, _port: u16) {}
+}
+
+/// Ports chosen uniformly at random, which is the default.
+#[derive(Debug, Default)]
+pub struct RandomPorts;
+
+impl PortAllocator for RandomPorts {
+    fn candidates(&self, ports: &PortSet, _client: &str, count: usize) -> Vec<u16> {
+        std::iter::from_fn(|| ports.random()).take(count).collect()
+    }
+}
+
+/// Ports in ascending order, starting after the last one allocated and
+/// wrapping around at the end of the set.
+///
+/// ```
+/// use bore_cli::ports::{PortAllocator, PortSet, SequentialPorts};
+///
+/// let ports = PortSet::new(vec![20000..=20002], Vec::new());
+/// let allocator = SequentialPorts::default();
+/// assert_eq!(allocator.candidates(&ports, "", 2), [20000, 20001]);
+/// allocator.allocated(20001);
+/// assert_eq!(allocator.candidates(&ports, "", 3), [20002, 20000, 20001]);
+/// ```
+#[derive(Debug, Default)]
+pub struct SequentialPorts {
+    next: Mutex<u16>,
+}
+
+impl PortAllocator for SequentialPorts {
+    fn candidates(&self, ports: &PortSet, _client: &str, count: usize) -> Vec<u16> {
+        let next = *self.next.lock().unwrap();
+        ports.iter_from(next).take(count).collect()
+    }
+
+    fn allocated(&self, port: u16) {
+        *self.next.lock().unwrap() = port.wrapping_add(1);
+    }
+}
+
+/// Ports that no tunnel has used yet, in random order, and then those that
+/// were released longest ago, so that a port a tunnel just closed is not soon
+/// handed to someone else.
+///
+/// ```
+/// use bore_cli::ports::{LeastRecentlyUsed, PortAllocator, PortSet};
+///
+/// let ports = PortSet::new(vec![20000..=20001], Vec::new());
+/// let allocator = LeastRecentlyUsed::default();
+/// allocator.allocated(20000);
+/// allocator.released(20000);
+/// assert_eq!(allocator.candidates(&ports, "", 100).last(), Some(&20000));
+/// ```
+#[derive(Debug, Default)]
+pub struct LeastRecentlyUsed {
+    released: Mutex<HashMap<u16, Instant>>,
+}
+
+impl PortAllocator for LeastRecentlyUsed {
+    fn candidates(&self, ports: &PortSet, _client: &str, count: usize) -> Vec<u16> {
+        let released = self.released.lock().unwrap();
+        let mut candidates: Vec<u16> = std::iter::from_fn(|| ports.random())
+            .take(count)
+            .filter(|port| !released.contains_key(port))
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        fastrand::shuffle(&mut candidates);
+        let mut used: Vec<_> = (released.iter())
+            .filter(|(port, _)| ports.contains(**port))
+            .map(|(port, at)| (*at, *port))
+            .collect();
+        used.sort_unstable();
+        candidates.extend(used.into_iter().map(|(_, port)| port));
+        candidates.truncate(count);
+        candidates
+    }
+
+    fn allocated(&self, port: u16) {
+        self.released.lock().unwrap().remove(&port);
+    }
+
+    fn released(&self, port: u16) {
+        self.released.lock().unwrap().insert(port, Instant::now());
+    }
+}
+
+/// Ports in ascending order from one picked by a hash of the client, so that
+/// a client that reconnects gets the same port as long as it is free.
+///
+/// ```
+/// use bore_cli::ports::{HashedPorts, PortAllocator, PortSet};
+///
+/// let ports = PortSet::new(vec![20000..=29999], Vec::new());
+/// let first = HashedPorts.candidates(&ports, "user:acme/web", 3);
+/// assert_eq!(first, HashedPorts.candidates(&ports, "user:acme/web", 3));
+/// assert_eq!(first[1], first[0] + 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct HashedPorts;
+
+impl PortAllocator for HashedPorts {
+    fn candidates(&self, ports: &PortSet, client: &str, count: usize) -> Vec<u16> {
+        let digest = Sha256::digest(client.as_bytes());
+        let hash = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        let start = match ports.len() {
+            0 => return Vec::new(),
+            len => ports.nth((hash % len as u64) as usize).unwrap(),
+        };
+        ports.iter_from(start).take(count).collect()
+    }
+}
@@ -0,0 +1,254 @@
+//! Helpers for tying tunnels to the lifetime of local processes, and for
+//! running hook scripts on events.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::process::{Child, Command};
+use tokio::time::{interval, timeout};
+
+/// How often to check whether a watched process is still running.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a hook script may run before it is killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to check which ports a spawned command is listening on.
+const DETECT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Spawn a shell command, such as the development server behind a tunnel.
+pub fn spawn_shell(command: &str, envs: &[(&str, String)]) -> Result<Child> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    cmd.envs(envs.iter().map(|(key, value)| (key, value)))
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("could not run command {command:?}"))
+}
+
+/// Run this executable again in the background with other arguments, without
+/// its output, to keep running after this process exits.
+pub fn spawn_detached(args: &[&OsStr]) -> Result<Child> {
+    let program = std::env::current_exe().context("could not find the bore executable")?;
+    Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("could not start bore in the background")
+}
+
+/// Run a hook script with event details in its environment, waiting for it to exit.
+pub async fn run_hook(program: &Path, envs: &[(&str, String)]) -> Result<ExitStatus> {
+    let mut child = Command::new(program)
+        .envs(envs.iter().map(|(key, value)| (key, value)))
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("could not run hook {}", program.display()))?;
+    timeout(HOOK_TIMEOUT, child.wait())
+        .await
+        .with_context(|| format!("hook {} timed out", program.display()))?
+        .context("could not wait for hook")
+}
+
+/// Returns whether a process with this ID is currently running.
+pub fn is_running(pid: u32) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        std::path::Path::new(&format!("/proc/{pid}")).exists()
+    }
+    #[cfg(all(unix, not(target_os = "linux")))]
+    {
+        std::process::Command::new("kill")
+            .args(["-0", &pid.to_string()])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false)
+    }
+    #[cfg(windows)]
+    {
+        std::process::Command::new("tasklist")
+            .args(["/NH", "/FI", &format!("PID eq {pid}")])
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+            .unwrap_or(false)
+    }
+}
+
+/// Wait until the process with this ID has exited.
+pub async fn wait_for_exit(pid: u32) {
+    let mut ticker = interval(POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if !is_running(pid) {
+            return;
+        }
+    }
+}
+
+/// Wait until a process or one of its descendants listens on a TCP port.
+///
+/// If several ports are open by the time they are first seen, the lowest one
+/// is returned, since development servers usually open their main port first
+/// and helpers like live reload on higher ones.
+pub async fn detect_port(pid: u32) -> Result<u16> {
+    let mut ticker = interval(DETECT_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Some(&port) = listening_ports(pid)?.first() {
+            return Ok(port);
+        }
+    }
+}
+
+/// Ports that a process and its descendants are listening on, in order.
+#[cfg(target_os = "linux")]
+pub fn listening_ports(pid: u32) -> Result<Vec<u16>> {
+    use std::collections::HashSet;
+    use std::fs;
+
+    let mut parents = Vec::new();
+    for entry in fs::read_dir("/proc")?.flatten() {
+        let Some(child) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        // The command name is in parentheses and may contain spaces, so the
+        // parent ID is found after the last closing parenthesis.
+        let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        let parent = stat
+            .rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+            .and_then(|parent| parent.parse().ok());
+        if let Some(parent) = parent {
+            parents.push((child, parent));
+        }
+    }
+
+    let mut inodes = HashSet::new();
+    for pid in descendants(pid, &parents) {
+        let Ok(fds) = fs::read_dir(format!("/proc/{pid}/fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            let inode = target
+                .to_str()
+                .and_then(|target| target.strip_prefix("socket:["))
+                .and_then(|target| target.strip_suffix(']'));
+            if let Some(inode) = inode {
+                inodes.insert(inode.to_string());
+            }
+        }
+    }
+
+    let mut ports = Vec::new();
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(contents) = fs::read_to_string(table) else {
+            continue;
+        };
+        for line in contents.lines().skip(1) {
+            // Fields are: slot, local address, remote address, state, queues,
+            // timer, retransmits, uid, timeout, and inode. State 0A is LISTEN.
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() <= 9 || fields[3] != "0A" || !inodes.contains(fields[9]) {
+                continue;
+            }
+            let port = fields[1]
+                .rsplit_once(':')
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            ports.extend(port);
+        }
+    }
+    ports.sort_unstable();
+    ports.dedup();
+    Ok(ports)
+}
+
+/// Ports that a process and its descendants are listening on, in order.
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn listening_ports(pid: u32) -> Result<Vec<u16>> {
+    use std::process::Command;
+
+    let output = Command::new("ps")
+        .args(["-A", "-o", "pid=,ppid="])
+        .output()
+        .context("could not run ps")?;
+    let parents: Vec<(u32, u32)> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().map(|field| field.parse().ok());
+            Some((fields.next()??, fields.next()??))
+        })
+        .collect();
+    let pids: Vec<String> = descendants(pid, &parents)
+        .iter()
+        .map(u32::to_string)
+        .collect();
+
+    // lsof exits with an error when nothing matches, so only its output counts.
+    let output = Command::new("lsof")
+        .args([
+            "-nP",
+            "-a",
+            "-iTCP",
+            "-sTCP:LISTEN",
+            "-Fn",
+            "-p",
+            &pids.join(","),
+        ])
+        .output()
+        .context("could not run lsof")?;
+    let mut ports: Vec<u16> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.strip_prefix('n'))
+        .filter_map(|name| name.rsplit_once(':')?.1.parse().ok())
+        .collect();
+    ports.sort_unstable();
+    ports.dedup();
+    Ok(ports)
+}
+
+/// Ports that a process and its descendants are listening on, in order.
+#[cfg(not(unix))]
+pub fn listening_ports(_pid: u32) -> Result<Vec<u16>> {
+    anyhow::bail!("detecting the local port is only supported on Linux and macOS")
+}
+
+/// A process and all of its descendants, given (child, parent) pairs.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn descendants(root: u32, parents: &[(u32, u32)]) -> Vec<u32> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for &(child, parent) in parents {
+        children.entry(parent).or_default().push(child);
+    }
+    let mut tree = vec![root];
+    let mut next = 0;
+    while next < tree.len() {
+        if let Some(kids) = children.get(&tree[next]) {
+            tree.extend(kids.iter().copied().filter(|kid| *kid != root));
+        }
+        next += 1;
+    }
+    tree
+}
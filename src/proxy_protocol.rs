@@ -0,0 +1,108 @@
+//! Headers of the PROXY protocol, for local services behind a tunnel.
+//!
+//! Every connection that the client makes to the local service comes from
+//! the client itself, so the service loses the address of the visitor. Web
+//! servers and load balancers such as nginx and HAProxy can read it from a
+//! [PROXY protocol] header at the start of the connection instead, which the
+//! client writes with the address that the server forwarded.
+//!
+//! [PROXY protocol]: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// Signature that starts every header of version 2.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version of the PROXY protocol to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// The human-readable version 1.
+    V1,
+
+    /// The binary version 2.
+    V2,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    /// Parse `v1` or `v2`.
+    ///
+    /// ```
+    /// use bore_cli::proxy_protocol::ProxyProtocol;
+    ///
+    /// assert_eq!("v1".parse(), Ok(ProxyProtocol::V1));
+    /// assert_eq!("v2".parse(), Ok(ProxyProtocol::V2));
+    /// assert!("v3".parse::<ProxyProtocol>().is_err());
+    /// ```
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "v1" => Ok(ProxyProtocol::V1),
+            "v2" => Ok(ProxyProtocol::V2),
+            _ => Err("expected `v1` or `v2`".into()),
+        }
+    }
+}
+
+impl ProxyProtocol {
+    /// Header for a TCP connection from a visitor to a port of the server,
+    /// or one that says nothing about its source if the visitor is unknown.
+    ///
+    /// The server does not know the public address that visitors connect
+    /// to, so the destination is the unspecified address with that port.
+    ///
+    /// ```
+    /// use bore_cli::proxy_protocol::ProxyProtocol;
+    ///
+    /// let peer = "203.0.113.7:51234".parse().unwrap();
+    /// assert_eq!(
+    ///     ProxyProtocol::V1.header(Some(peer), 8080),
+    ///     b"PROXY TCP4 203.0.113.7 0.0.0.0 51234 8080\r\n",
+    /// );
+    /// assert_eq!(ProxyProtocol::V1.header(None, 8080), b"PROXY UNKNOWN\r\n");
+    ///
+    /// let header = ProxyProtocol::V2.header(Some(peer), 8080);
+    /// assert_eq!(header.len(), 16 + 12);
+    /// assert_eq!(&header[16..20], &[203, 0, 113, 7]);
+    /// ```
+    pub fn header(self, peer: Option<SocketAddr>, port: u16) -> Vec<u8> {
+        // Dual-stack servers see IPv4 visitors at mapped IPv6 addresses.
+        let peer = peer.map(|peer| SocketAddr::new(peer.ip().to_canonical(), peer.port()));
+        match (self, peer) {
+            (ProxyProtocol::V1, None) => b"PROXY UNKNOWN\r\n".to_vec(),
+            (ProxyProtocol::V1, Some(peer)) => {
+                let (family, dest) = match peer.ip() {
+                    IpAddr::V4(_) => ("TCP4", IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                    IpAddr::V6(_) => ("TCP6", IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+                };
+                let (ip, source_port) = (peer.ip(), peer.port());
+                format!("PROXY {family} {ip} {dest} {source_port} {port}\r\n").into_bytes()
+            }
+            (ProxyProtocol::V2, None) => {
+                // A LOCAL command, with no address family.
+                let mut header = SIGNATURE.to_vec();
+                header.extend([0x20, 0x00, 0, 0]);
+                header
+            }
+            (ProxyProtocol::V2, Some(peer)) => {
+                let (family, addresses) = match peer.ip() {
+                    IpAddr::V4(ip) => {
+                        (0x11, [ip.octets(), Ipv4Addr::UNSPECIFIED.octets()].concat())
+                    }
+                    IpAddr::V6(ip) => {
+                        (0x21, [ip.octets(), Ipv6Addr::UNSPECIFIED.octets()].concat())
+                    }
+                };
+                let length = (addresses.len() + 4) as u16;
+                let mut header = SIGNATURE.to_vec();
+                header.extend([0x21, family]);
+                header.extend(length.to_be_bytes());
+                header.extend(addresses);
+                header.extend(peer.port().to_be_bytes());
+                header.extend(port.to_be_bytes());
+                header
+            }
+        }
+    }
+}
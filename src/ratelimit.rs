@@ -0,0 +1,253 @@
+//! Token bucket rate limiting, used to pace work that arrives in bursts.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+/// A token bucket that refills continuously at a fixed rate.
+///
+/// ```
+/// use bore_cli::ratelimit::TokenBucket;
+///
+/// let bucket = TokenBucket::new(1.0, 2.0);
+/// assert!(bucket.try_acquire(1.0));
+/// assert!(bucket.try_acquire(1.0));
+/// assert!(!bucket.try_acquire(1.0));
+/// ```
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Create a full bucket holding `capacity` tokens, refilled at `rate` tokens per second.
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        assert!(rate > 0.0, "token bucket rate must be positive");
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Take tokens from the bucket, returning whether enough were available.
+    pub fn try_acquire(&self, tokens: f64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (available, last) = &mut *state;
+        let now = Instant::now();
+        *available =
+            (*available + now.duration_since(*last).as_secs_f64() * self.rate).min(self.capacity);
+        *last = now;
+        if *available >= tokens {
+            *available -= tokens;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take tokens from the bucket whether or not they are available, going
+    /// into debt that later refills pay off first.
+    pub fn consume(&self, tokens: f64) {
+        let mut state = self.state.lock().unwrap();
+        let (available, last) = &mut *state;
+        let now = Instant::now();
+        *available =
+            (*available + now.duration_since(*last).as_secs_f64() * self.rate).min(self.capacity);
+        *last = now;
+        *available -= tokens;
+    }
+
+    /// Time until the bucket holds `tokens` tokens.
+    pub fn wait_time(&self, tokens: f64) -> Duration {
+        let missing = tokens - self.available();
+        Duration::from_secs_f64(missing.max(0.0) / self.rate)
+    }
+
+    /// Number of tokens currently available.
+    pub fn available(&self) -> f64 {
+        let state = self.state.lock().unwrap();
+        let (available, last) = *state;
+        (available + last.elapsed().as_secs_f64() * self.rate).min(self.capacity)
+    }
+
+    /// Restore the tokens that were available some time ago, such as before
+    /// a restart, refilling the bucket for the time that has passed since.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use bore_cli::ratelimit::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(1.0, 10.0);
+    /// bucket.restore(0.0, Duration::from_secs(2));
+    /// assert!(bucket.try_acquire(2.0));
+    /// assert!(!bucket.try_acquire(1.0));
+    /// ```
+    pub fn restore(&self, available: f64, elapsed: Duration) {
+        let refilled = available.max(0.0) + elapsed.as_secs_f64() * self.rate;
+        *self.state.lock().unwrap() = (refilled.min(self.capacity), Instant::now());
+    }
+
+    /// Time it takes for an empty bucket to fill up completely.
+    pub fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.capacity / self.rate)
+    }
+}
+
+/// Limit on the bytes per second of a set of streams, which can be changed
+/// while they run.
+///
+/// ```
+/// use bore_cli::ratelimit::Bandwidth;
+///
+/// let bandwidth = Bandwidth::default();
+/// bandwidth.set_limit(Some(64 * 1024));
+/// assert_eq!(bandwidth.limit(), Some(64 * 1024));
+/// bandwidth.set_limit(None);
+/// assert_eq!(bandwidth.limit(), None);
+/// ```
+#[derive(Default)]
+pub struct Bandwidth {
+    bucket: RwLock<Option<(u64, Arc<TokenBucket>)>>,
+}
+
+impl Bandwidth {
+    /// Create a limit of `bytes_per_second`.
+    pub fn new(bytes_per_second: u64) -> Self {
+        let bandwidth = Self::default();
+        bandwidth.set_limit(Some(bytes_per_second));
+        bandwidth
+    }
+
+    /// Current limit in bytes per second, if any.
+    pub fn limit(&self) -> Option<u64> {
+        self.bucket
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|(limit, _)| *limit)
+    }
+
+    /// Change the limit, or remove it. Bursts of up to a second of traffic
+    /// are allowed.
+    pub fn set_limit(&self, bytes_per_second: Option<u64>) {
+        let bucket = bytes_per_second.filter(|&limit| limit > 0).map(|limit| {
+            let rate = limit as f64;
+            (limit, Arc::new(TokenBucket::new(rate, rate)))
+        });
+        *self.bucket.write().unwrap() = bucket;
+    }
+
+    fn bucket(&self) -> Option<Arc<TokenBucket>> {
+        let bucket = self.bucket.read().unwrap();
+        bucket.as_ref().map(|(_, bucket)| Arc::clone(bucket))
+    }
+}
+
+/// Stream wrapper that holds the bytes read and written through it to
+/// shared [`Bandwidth`] limits.
+pub struct Limited<S> {
+    inner: S,
+    read_limit: Option<Arc<Bandwidth>>,
+    write_limit: Option<Arc<Bandwidth>>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Limited<S> {
+    /// Limit a stream, counting both directions against the same limit.
+    pub fn new(inner: S, bandwidth: Arc<Bandwidth>) -> Self {
+        Self::directional(inner, Some(Arc::clone(&bandwidth)), Some(bandwidth))
+    }
+
+    /// Limit what is read from and written to a stream separately, if at all.
+    pub fn directional(
+        inner: S,
+        read_limit: Option<Arc<Bandwidth>>,
+        write_limit: Option<Arc<Bandwidth>>,
+    ) -> Self {
+        Self {
+            inner,
+            read_limit,
+            write_limit,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Get a reference to the wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+/// Wait until the bucket has tokens, returning how many bytes may pass.
+fn poll_budget(
+    bucket: &TokenBucket,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+        let available = bucket.available();
+        if available >= 1.0 {
+            return Poll::Ready(available as usize);
+        }
+        *delay = Some(Box::pin(sleep(bucket.wait_time(1.0))));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Limited<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(bucket) = this.read_limit.as_ref().and_then(|limit| limit.bucket()) else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        let budget = ready!(poll_budget(&bucket, &mut this.read_delay, cx));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(budget.min(buf.remaining())));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        buf.advance(n);
+        bucket.consume(n as f64);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Limited<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(bucket) = this.write_limit.as_ref().and_then(|limit| limit.bucket()) else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        let budget = ready!(poll_budget(&bucket, &mut this.write_delay, cx));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..budget.min(buf.len())]))?;
+        bucket.consume(n as f64);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,173 @@
+//! Ports held for clients that are about to reconnect.
+//!
+//! When the connection of a client drops, its tunnel closes and the port goes
+//! back to the pool, where another client may get it before the first one
+//! reconnects. Webhooks pointed at the old address then break, or reach
+//! someone else. With reservations, the server holds the port of a closed
+//! tunnel for a grace period, and gives it back to the same client when it
+//! reconnects, whether or not it asks for the port.
+//!
+//! Clients are told apart by an HMAC of the credential that they used and
+//! the name of their tunnel, so reservations keep no credentials around.
+
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::debug;
+use uuid::Uuid;
+
+/// Client that a port is reserved for, as an HMAC of its credential and
+/// tunnel name.
+pub type Owner = [u8; 32];
+
+/// Reservation of a port, until it is reclaimed or expires.
+struct Reservation {
+    owner: Owner,
+    until: Instant,
+}
+
+/// Ports of closed tunnels, held for their clients for a grace period.
+///
+/// ```
+/// use std::time::Duration;
+/// use bore_cli::reservation::Reservations;
+///
+/// let reservations = Reservations::new(Duration::from_secs(60));
+/// let alice = reservations.owner("secret:alice", Some("web"));
+/// let bob = reservations.owner("secret:bob", Some("web"));
+///
+/// drop(reservations.hold(8080, alice));
+/// assert_eq!(reservations.reserved_for(&alice), Some(8080));
+/// assert!(reservations.is_held_for_other(8080, &bob));
+/// assert!(!reservations.is_held_for_other(8080, &alice));
+/// ```
+pub struct Reservations {
+    /// Key of the HMAC that owners are derived with.
+    key: RwLock<Vec<u8>>,
+    grace: Duration,
+
+    /// Owners of the ports of open tunnels.
+    open: DashMap<u16, Owner>,
+
+    /// Ports of closed tunnels.
+    ports: DashMap<u16, Reservation>,
+}
+
+impl Reservations {
+    /// Hold ports of closed tunnels for `grace`.
+    pub fn new(grace: Duration) -> Self {
+        let key = [Uuid::new_v4().into_bytes(), Uuid::new_v4().into_bytes()].concat();
+        Self {
+            key: RwLock::new(key),
+            grace,
+            open: DashMap::new(),
+            ports: DashMap::new(),
+        }
+    }
+
+    /// Owner of the tunnel that a client opens with a credential, which
+    /// describes who the server authenticated it as, and a tunnel name.
+    pub fn owner(&self, credential: &str, name: Option<&str>) -> Owner {
+        let key = self.key.read().unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC can take key of any size");
+        mac.update(&(credential.len() as u64).to_be_bytes());
+        mac.update(credential.as_bytes());
+        if let Some(name) = name {
+            mac.update(name.as_bytes());
+        }
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Port reserved for an owner, if any.
+    pub fn reserved_for(&self, owner: &Owner) -> Option<u16> {
+        let now = Instant::now();
+        self.ports
+            .iter()
+            .find(|entry| entry.owner == *owner && entry.until > now)
+            .map(|entry| *entry.key())
+    }
+
+    /// Whether a port is reserved for someone other than an owner.
+    pub fn is_held_for_other(&self, port: u16, owner: &Owner) -> bool {
+        let now = Instant::now();
+        self.ports
+            .get(&port)
+            .is_some_and(|entry| entry.owner != *owner && entry.until > now)
+    }
+
+    /// Claim a port for the tunnel of an owner, which reserves it for the
+    /// grace period once the returned hold is dropped.
+    pub fn hold(&self, port: u16, owner: Owner) -> Hold<'_> {
+        self.ports.remove(&port);
+        self.open.insert(port, owner);
+        Hold {
+            reservations: self,
+            port,
+            owner,
+        }
+    }
+
+    /// Forget reservations that have expired.
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.ports.retain(|_, entry| entry.until > now);
+    }
+
+    /// Key that owners are derived with, to carry them across restarts.
+    pub fn key(&self) -> Vec<u8> {
+        self.key.read().unwrap().clone()
+    }
+
+    /// Reserved ports, with their owners and the time left on each. Ports of
+    /// open tunnels are included with the full grace period, as they would
+    /// be reserved if the server stopped now.
+    pub fn snapshot(&self) -> Vec<(u16, Owner, Duration)> {
+        let now = Instant::now();
+        let open = self
+            .open
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value(), self.grace));
+        let closed = self
+            .ports
+            .iter()
+            .filter(|entry| entry.until > now)
+            .map(|entry| (*entry.key(), entry.owner, entry.until - now));
+        open.chain(closed).collect()
+    }
+
+    /// Take over the key and reservations saved before a restart, before
+    /// any tunnel is opened.
+    pub fn restore(&self, key: Vec<u8>, reserved: Vec<(u16, Owner, Duration)>) {
+        *self.key.write().unwrap() = key;
+        let now = Instant::now();
+        for (port, owner, remaining) in reserved {
+            let until = now + remaining;
+            self.ports.insert(port, Reservation { owner, until });
+        }
+    }
+}
+
+/// Port of an open tunnel, which is reserved for its owner when dropped.
+pub struct Hold<'a> {
+    reservations: &'a Reservations,
+    port: u16,
+    owner: Owner,
+}
+
+impl Drop for Hold<'_> {
+    fn drop(&mut self) {
+        self.reservations.open.remove(&self.port);
+        let until = Instant::now() + self.reservations.grace;
+        debug!(port = self.port, grace = ?self.reservations.grace, "reserved port");
+        self.reservations.ports.insert(
+            self.port,
+            Reservation {
+                owner: self.owner,
+                until,
+            },
+        );
+    }
+}
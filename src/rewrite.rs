@@ -0,0 +1,185 @@
+//! Rewriting the head of every HTTP request on a connection.
+//!
+//! A connection can carry many requests one after another, so changing only
+//! the first would leave the rest as the visitor sent them. [`Rewritten`]
+//! follows the framing of each request's body to find the head of the next,
+//! and hands every head to a function that may change it before it is read.
+//!
+//! Once a connection switches protocols, such as to a WebSocket, the rest of
+//! it is passed through untouched. A malformed request, or one whose end is
+//! ambiguous, ends the requests that are read, and is answered with a
+//! `400 Bad Request` once the responses to those before it are through.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::debug;
+
+use crate::framing::Framing;
+use crate::http::{RequestHead, MAX_HEAD_LENGTH};
+
+/// Size of reads from the underlying stream.
+const READ_SIZE: usize = 8192;
+
+/// Response to a request that is not passed on.
+const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain; charset=utf-8\r\n\
+    Content-Length: 18\r\nConnection: close\r\n\r\nmalformed request\n";
+
+/// Change to the head of a request.
+pub type Rewrite = Box<dyn FnMut(&mut RequestHead) + Send + Sync>;
+
+/// Stream of HTTP requests whose heads are rewritten as they are read.
+/// Writes pass through unchanged.
+///
+/// ```
+/// use bore_cli::rewrite::Rewritten;
+/// use tokio::io::AsyncReadExt;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let requests: &[u8] = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nGET /bGET /c HTTP/1.1\r\n\r\n";
+/// let mut stream = Rewritten::new(
+///     requests,
+///     Box::new(|head| head.set_header("X-Seen", "yes")),
+/// );
+/// let mut rewritten = String::new();
+/// stream.read_to_string(&mut rewritten).await.unwrap();
+/// assert_eq!(
+///     rewritten,
+///     "POST /a HTTP/1.1\r\nContent-Length: 5\r\nX-Seen: yes\r\n\r\nGET /b\
+///      GET /c HTTP/1.1\r\nX-Seen: yes\r\n\r\n",
+/// );
+/// # }
+/// ```
+pub struct Rewritten<S> {
+    inner: S,
+    rewrite: Rewrite,
+    state: Framing,
+    /// Bytes read from the stream that are not yet processed.
+    input: Vec<u8>,
+    /// Processed bytes that are ready to be read.
+    output: Vec<u8>,
+    eof: bool,
+    /// Response to a rejected request that is left to write on shutdown.
+    rejection: &'static [u8],
+}
+
+impl<S> Rewritten<S> {
+    /// Rewrite the heads of the requests on a stream.
+    pub fn new(inner: S, rewrite: Rewrite) -> Self {
+        Self {
+            inner,
+            rewrite,
+            state: Framing::Head,
+            input: Vec::new(),
+            output: Vec::new(),
+            eof: false,
+            rejection: &[],
+        }
+    }
+
+    /// Move as much of the input as can be processed to the output.
+    fn process(&mut self) -> io::Result<()> {
+        loop {
+            match self.state {
+                Framing::Head => {
+                    let head = RequestHead::parse(&self.input).map_err(invalid)?;
+                    let Some(mut head) = head else {
+                        if self.input.len() > MAX_HEAD_LENGTH {
+                            return Err(invalid("request head is too long"));
+                        }
+                        return Ok(());
+                    };
+                    self.input.drain(..head.length);
+                    self.state = Framing::request(&head).map_err(invalid)?;
+                    (self.rewrite)(&mut head);
+                    self.output.extend(head.to_bytes());
+                }
+                // No heads follow a body that lasts until the connection closes.
+                Framing::UntilClose | Framing::Raw => {
+                    self.output.append(&mut self.input);
+                    return Ok(());
+                }
+                _ => {
+                    let Some(step) = self.state.step(&self.input).map_err(invalid)? else {
+                        return Ok(());
+                    };
+                    self.output.extend(self.input.drain(..step.length));
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewritten<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.output.is_empty() {
+                let n = this.output.len().min(buf.remaining());
+                buf.put_slice(&this.output[..n]);
+                this.output.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            if matches!(this.state, Framing::UntilClose | Framing::Raw) && this.input.is_empty() {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+            let mut chunk = [0; READ_SIZE];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                // Whatever is left of an incomplete request is passed on as is.
+                this.eof = true;
+                this.output.append(&mut this.input);
+                continue;
+            }
+            this.input.extend_from_slice(read.filled());
+            if let Err(err) = this.process() {
+                debug!(%err, "rejecting request");
+                this.input.clear();
+                this.eof = true;
+                this.rejection = BAD_REQUEST;
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewritten<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while !this.rejection.is_empty() {
+            let n = ready!(Pin::new(&mut this.inner).poll_write(cx, this.rejection))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.rejection = &this.rejection[n..];
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn invalid(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{err:#}"))
+}
@@ -0,0 +1,225 @@
+//! Sampling of connection payloads, for diagnosing protocol mismatches.
+//!
+//! When sampling is enabled on a tunnel, one in every N connections has the
+//! first bytes in each direction captured and written to the access log as a
+//! hex dump, without recording the rest of the traffic.
+
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Number of bytes captured in each direction when none is given.
+pub const DEFAULT_SAMPLE_BYTES: usize = 64;
+
+/// Most bytes that can be captured in each direction, as each sample is
+/// kept in memory and logged on a single line.
+pub const MAX_SAMPLE_BYTES: usize = 4096;
+
+/// Sampling settings for one tunnel, written as `PORT[:EVERY[:BYTES]]`.
+///
+/// ```
+/// use bore_cli::sampling::SampleSpec;
+///
+/// let spec: SampleSpec = "8080:10:32".parse().unwrap();
+/// assert_eq!((spec.port, spec.every, spec.bytes), (8080, 10, 32));
+/// assert_eq!("8080".parse::<SampleSpec>().unwrap().every, 1);
+/// assert!("8080:0".parse::<SampleSpec>().is_err());
+/// assert!("8080:1:1GiB".parse::<SampleSpec>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SampleSpec {
+    /// Public port of the tunnel to sample.
+    pub port: u16,
+
+    /// Sample one in this many connections.
+    pub every: u64,
+
+    /// Number of bytes captured in each direction.
+    pub bytes: usize,
+}
+
+impl FromStr for SampleSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let port = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| format!("invalid port in sample spec {s:?}"))?;
+        let every = match parts.next() {
+            Some(every) => every
+                .parse()
+                .map_err(|_| format!("invalid rate in sample spec {s:?}"))?,
+            None => 1,
+        };
+        let bytes = match parts.next() {
+            Some(bytes) => crate::units::parse_size(bytes)? as usize,
+            None => DEFAULT_SAMPLE_BYTES,
+        };
+        if parts.next().is_some() {
+            return Err(format!("expected PORT[:EVERY[:BYTES]], found {s:?}"));
+        }
+        let spec = Self { port, every, bytes };
+        spec.check()?;
+        Ok(spec)
+    }
+}
+
+impl SampleSpec {
+    /// Check that the rate and size of samples are within bounds.
+    pub fn check(&self) -> Result<(), String> {
+        if self.every == 0 {
+            return Err("sample rate must be at least 1".into());
+        }
+        if self.bytes > MAX_SAMPLE_BYTES {
+            return Err(format!(
+                "samples can capture at most {MAX_SAMPLE_BYTES} bytes in each direction"
+            ));
+        }
+        Ok(())
+    }
+}
+
+struct SampleRule {
+    every: u64,
+    bytes: usize,
+    seen: AtomicU64,
+}
+
+/// Decides which connections have their payloads sampled, per tunnel port.
+///
+/// Rules can be changed while the server is running.
+///
+/// ```
+/// use bore_cli::sampling::{SampleSpec, Sampler};
+///
+/// let sampler = Sampler::default();
+/// sampler.enable("8080:2:16".parse().unwrap());
+/// assert_eq!(sampler.sample(8080), Some(16));
+/// assert_eq!(sampler.sample(8080), None);
+/// assert_eq!(sampler.sample(8080), Some(16));
+/// assert_eq!(sampler.sample(9090), None);
+/// ```
+#[derive(Default)]
+pub struct Sampler {
+    rules: DashMap<u16, SampleRule>,
+}
+
+impl Sampler {
+    /// Start sampling connections on a tunnel, replacing any previous rule.
+    pub fn enable(&self, spec: SampleSpec) {
+        let rule = SampleRule {
+            every: spec.every.max(1),
+            bytes: spec.bytes.min(MAX_SAMPLE_BYTES),
+            seen: AtomicU64::new(0),
+        };
+        self.rules.insert(spec.port, rule);
+    }
+
+    /// Stop sampling connections on a tunnel, returning whether it was sampled.
+    pub fn disable(&self, port: u16) -> bool {
+        self.rules.remove(&port).is_some()
+    }
+
+    /// Rules of the tunnels that are sampled, by port.
+    pub fn rules(&self) -> Vec<SampleSpec> {
+        let mut rules: Vec<_> = (self.rules.iter())
+            .map(|rule| SampleSpec {
+                port: *rule.key(),
+                every: rule.every,
+                bytes: rule.bytes,
+            })
+            .collect();
+        rules.sort_by_key(|rule| rule.port);
+        rules
+    }
+
+    /// Decide whether to sample a new connection, returning how many bytes to capture.
+    pub fn sample(&self, port: u16) -> Option<usize> {
+        let rule = self.rules.get(&port)?;
+        let seen = rule.seen.fetch_add(1, Ordering::Relaxed);
+        (seen % rule.every == 0).then_some(rule.bytes)
+    }
+}
+
+/// Stream wrapper that records the first bytes read from and written to it.
+pub struct Tap<S> {
+    inner: S,
+    limit: usize,
+    read: Vec<u8>,
+    written: Vec<u8>,
+}
+
+impl<S> Tap<S> {
+    /// Wrap a stream, capturing up to `limit` bytes in each direction.
+    pub fn new(inner: S, limit: usize) -> Self {
+        Self {
+            inner,
+            limit,
+            read: Vec::new(),
+            written: Vec::new(),
+        }
+    }
+
+    /// Hex dump of the bytes read from the stream so far.
+    pub fn read_hex(&self) -> String {
+        hex::encode(&self.read)
+    }
+
+    /// Hex dump of the bytes written to the stream so far.
+    pub fn written_hex(&self) -> String {
+        hex::encode(&self.written)
+    }
+}
+
+fn capture(buf: &mut Vec<u8>, limit: usize, data: &[u8]) {
+    let n = limit.saturating_sub(buf.len()).min(data.len());
+    buf.extend_from_slice(&data[..n]);
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tap<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            capture(&mut this.read, this.limit, &buf.filled()[start..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tap<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            capture(&mut this.written, this.limit, &buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
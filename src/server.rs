@@ -1,116 +1,2193 @@
 //! Server implementation for the `bore` service.
 
-use std::net::{IpAddr, Ipv4Addr};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock, Weak};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{io, ops::RangeInclusive, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Context, Result};
 use dashmap::DashMap;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{sleep, timeout};
-use tracing::{info, info_span, warn, Instrument};
+use futures_util::future;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{broadcast, mpsc, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, timeout, timeout_at};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 
-use crate::auth::{Authenticator, ApiKeyAuthenticator};
-use crate::shared::{ClientMessage, Delimited, ServerMessage, CONTROL_PORT};
+use crate::access_log::{AccessEntry, AccessLog, Reason};
+use crate::acl::{self, AccessList};
+use crate::acme::Acme;
+use crate::admin::{self, BulkAction, OpenTunnel, Selector, ServerSummary};
+use crate::auth::{
+    self, secret_fingerprint, ApiKeyAuthenticator, AuthProvider, MissingCredentials, OutagePolicy,
+    Principal, SecretSet, VALIDATION_CACHE_TTL, VALIDATION_NEGATIVE_TTL,
+};
+use crate::broker::{Broker, MemoryListener, MEMORY_ADDR};
+use crate::config::ServerConfig;
+use crate::delegation::SubKeyIssuer;
+use crate::encryption::Encrypted;
+use crate::geoip::{CountryRules, GeoIp};
+use crate::guard::{self, SourceGuard, Verdict};
+use crate::handoff::{self, Inherited};
+use crate::heartbeat::{Beat, Heartbeat, MAX_INTERVAL, MIN_INTERVAL};
+use crate::http::{
+    self, check_subdomain, Access, HttpRouter, PortMode, Route, RouteError, RouteKind,
+};
+use crate::identity::ServerIdentity;
+use crate::integrity::{Checksummed, StreamChecksum};
+use crate::metrics::{self, ServerMetrics, UnmatchedAccept};
+use crate::multiplex::MuxServer;
+use crate::oidc::{self, Oidc, OidcConfig};
+use crate::policy::{Admission, Decision, Policy};
+use crate::ports::{PortAllocator, PortSet, RandomPorts};
+use crate::process;
+use crate::ratelimit::{Bandwidth, Limited, TokenBucket};
+use crate::reservation::{Owner, Reservations};
+use crate::sampling::{Sampler, Tap};
+use crate::shared::{
+    canonical_addr, check_tunnel_name, AuthError, AuthErrorCode, ClientHello, ClientMessage,
+    CloseReason, ConnectionInfo, Delimited, ErrorCode, FrameTooLong, Observation, ObserveRequest,
+    Scope, ServerError, ServerHello, ServerMessage, CONTROL_PORT, MAX_FRAME_LENGTH,
+    NETWORK_TIMEOUT, PROTOCOL_VERSION,
+};
+use crate::state::{
+    self, SavedBan, SavedReservation, SavedTransfer, ServerState, StateFile, SAVE_INTERVAL,
+};
+use crate::stats::{Metered, TunnelStats};
+use crate::striping::{self, MAX_STRIPES};
+use crate::tls::{self, ControlStream};
+use crate::transcript::{Transcript, TranscriptEntry};
+use crate::transfer::{TransferLedger, Usage};
+use crate::udp::{Relay, Session};
+use crate::usage::UsageReporter;
+use crate::websocket;
+
+/// Default interval between heartbeats on the control connection.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Shortest interval between heartbeats, however impatient the client is.
+const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Default number of visitors of each tunnel that may wait for the client.
+const MAX_PENDING: usize = 128;
+
+/// Default time that a visitor may wait for the client to accept it.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time that a new connection has to finish its handshake, through
+/// TLS, authentication, and its first message.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default number of connections that may be in their handshake at once.
+const MAX_HANDSHAKES: usize = 1024;
+
+/// Time that a hardened server gives a new connection to finish its
+/// handshake, through TLS, authentication, and its first message.
+const HARDENED_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Rate of new control connections per second that a hardened server
+/// accepts, unless set otherwise, and the burst above it.
+const HARDENED_HANDSHAKE_RATE: (f64, u32) = (20.0, 50);
+
+/// Ports below this need root on most systems, and belong to well-known
+/// services that tunnels of a hardened server may not impersonate.
+const PRIVILEGED_PORTS: u16 = 1024;
+
+/// Number of events buffered for each observer before it starts missing them.
+const OBSERVER_BUFFER: usize = 256;
+
+/// Most bytes of each direction of a sample that observers receive, so that
+/// events fit in a frame.
+const MAX_OBSERVED_SAMPLE: usize = 128;
 
 /// Authentication mode for the server
 enum AuthMode {
     None,
-    Secret(Authenticator),
+    Secret(SecretSet),
     ApiKey(ApiKeyAuthenticator),
+    Custom(Arc<dyn AuthProvider>),
 }
 
-/// State structure for the server.
-pub struct Server {
-    /// Range of TCP ports that can be forwarded.
-    port_range: RangeInclusive<u16>,
+impl AuthMode {
+    fn new(secrets: &[(String, &str)], validation_urls: Vec<String>) -> Self {
+        if !validation_urls.is_empty() {
+            return AuthMode::ApiKey(ApiKeyAuthenticator::with_urls(validation_urls));
+        }
+        let mut set = SecretSet::default();
+        for (label, secret) in secrets {
+            set.add(label, secret);
+        }
+        match set.is_empty() {
+            true => AuthMode::None,
+            false => AuthMode::Secret(set),
+        }
+    }
+
+    fn provider(&self) -> Option<&dyn AuthProvider> {
+        match self {
+            AuthMode::None => None,
+            AuthMode::Secret(auth) => Some(auth),
+            AuthMode::ApiKey(auth) => Some(auth),
+            AuthMode::Custom(provider) => Some(&**provider),
+        }
+    }
+
+    fn set_cache_ttl(&mut self, ttl: Duration, negative_ttl: Duration) {
+        if let AuthMode::ApiKey(auth) = self {
+            auth.set_cache_ttl(ttl, negative_ttl);
+        }
+    }
+
+    fn set_client(&mut self, client: &reqwest::Client) {
+        if let AuthMode::ApiKey(auth) = self {
+            auth.set_client(client.clone());
+        }
+    }
+
+    fn set_outage_policy(&mut self, policy: OutagePolicy, metrics: &Arc<ServerMetrics>) {
+        if let AuthMode::ApiKey(auth) = self {
+            auth.set_outage_policy(policy);
+            auth.set_metrics(Arc::clone(metrics));
+        }
+    }
+}
+
+/// Handshake of a new connection to the control port, which holds one of
+/// the server's permits for handshakes until it is dropped.
+struct Handshake {
+    /// When the connection must have finished its handshake.
+    deadline: tokio::time::Instant,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Handshake {
+    /// Run a step of the handshake, failing if the deadline passes first.
+    async fn within<T>(&self, step: impl Future<Output = Result<T>>) -> Result<T> {
+        timeout_at(self.deadline, step)
+            .await
+            .context("timed out waiting for handshake")?
+    }
+}
+
+/// Tell the client that it sent a frame longer than the maximum, if that is
+/// why a step failed, before the connection closes.
+async fn report_long_frame<T>(
+    stream: &mut Delimited<ControlStream>,
+    result: Result<T>,
+) -> Result<T> {
+    if let Some(err) = result
+        .as_ref()
+        .err()
+        .and_then(|err| err.downcast_ref::<FrameTooLong>())
+    {
+        stream
+            .send(ServerMessage::Error(err.to_string()))
+            .await
+            .ok();
+    }
+    result
+}
+
+/// Whether a failed handshake counts toward banning the client's address,
+/// which outages of the validation backend do not.
+fn counts_as_failure(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<AuthError>()
+        .is_some_and(|err| !matches!(err.code, AuthErrorCode::BackendUnavailable))
+}
+
+/// URL of a hostname on a port, which is left out if it is the default.
+fn url(scheme: &str, hostname: &str, port: u16, default: u16) -> String {
+    match port == default {
+        true => format!("{scheme}://{hostname}"),
+        false => format!("{scheme}://{hostname}:{port}"),
+    }
+}
+
+/// Wait for the next connection routed to an HTTP tunnel, or forever if the
+/// tunnel is not one. This is cancel safe.
+/// Answer a visit of the client to its own tunnel with the token that it
+/// announced, in HTTP on the shared HTTP port.
+async fn answer_probe(visitor: Visitor, token: Uuid) {
+    let (mut stream, http): (Box<dyn VisitorStream>, _) = match visitor {
+        Visitor::Tcp(stream) => (Box::new(stream), false),
+        Visitor::Memory(stream, _) => (Box::new(stream), false),
+        Visitor::Routed(stream, _, _) => (Box::new(stream), true),
+        Visitor::Udp(_) => return,
+    };
+    let answer = match http {
+        true => {
+            format!("HTTP/1.1 204 No Content\r\nBore-Probe: {token}\r\nConnection: close\r\n\r\n")
+        }
+        false => format!("{token}\n"),
+    };
+    let _ = timeout(NETWORK_TIMEOUT, async {
+        stream.write_all(answer.as_bytes()).await?;
+        stream.shutdown().await
+    })
+    .await;
+}
+
+async fn accept_routed(route: &mut Option<Route<'_>>) -> Option<http::Routed> {
+    match route {
+        Some(route) => route.accept().await,
+        None => future::pending().await,
+    }
+}
+
+/// Listen for TCP connections on an address. An unspecified IPv6 address
+/// takes IPv4 connections as well, unless `v6_only` is set because another
+/// socket listens on IPv4.
+fn bind_tcp(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Who a client authenticated as, to tell its tunnels apart from those of
+/// other clients.
+fn credential(principal: &Principal) -> String {
+    if let Some(claims) = &principal.sub_key {
+        format!("sub-key:{}", claims.id)
+    } else if let Some(user_id) = &principal.user_id {
+        format!("user:{user_id}")
+    } else if let Some(digest) = &principal.api_key_digest {
+        format!("api-key:{digest}")
+    } else if let Some(label) = &principal.secret_label {
+        format!("secret:{label}")
+    } else {
+        String::new()
+    }
+}
+
+/// Proxy a connection until it finishes, or until its tunnel has used up its
+/// transfer quota.
+async fn cut_off(
+    exhausted: &CancellationToken,
+    splice: impl Future<Output = io::Result<()>>,
+) -> io::Result<()> {
+    tokio::select! {
+        result = splice => result,
+        _ = exhausted.cancelled() => Ok(()),
+    }
+}
+
+/// Write a visitor to the access log, if there is one.
+fn log_access(log: Option<&AccessLog>, entry: AccessEntry) {
+    if let Some(log) = log {
+        if let Err(err) = log.record(&entry) {
+            warn!(%err, "could not write to access log");
+        }
+    }
+}
+
+/// Authentication providers of retired settings.
+fn retired_providers(retired: &[Arc<Settings>]) -> Vec<&dyn AuthProvider> {
+    retired
+        .iter()
+        .filter_map(|settings| settings.auth.provider())
+        .collect()
+}
+
+/// Settings that are replaced when the configuration is reloaded. Each
+/// handshake uses the settings current at its start, so open tunnels are
+/// unaffected.
+struct Settings {
+    /// TCP ports that can be forwarded.
+    ports: PortSet,
 
     /// Authentication mode.
     auth: AuthMode,
 
+    /// IP addresses where tunnels will listen on.
+    bind_tunnels: Vec<IpAddr>,
+
+    /// IP addresses where tunnels listen instead, by the class of listener
+    /// that clients ask for.
+    listener_classes: BTreeMap<String, Vec<IpAddr>>,
+
+    /// Ports that clients may use, by credential, as in
+    /// [`ServerConfig::credential_ports`].
+    credential_ports: BTreeMap<String, RangeInclusive<u16>>,
+}
+
+impl Settings {
+    /// Narrow a set of ports to those that every rule matching the
+    /// credentials of a client allows.
+    fn credential_ports(&self, principal: &Principal, ports: PortSet) -> PortSet {
+        let credentials = [
+            principal
+                .secret_label
+                .as_ref()
+                .map(|label| format!("secret:{label}")),
+            principal
+                .user_id
+                .as_ref()
+                .map(|user_id| format!("user:{user_id}")),
+            principal.tier.as_ref().map(|tier| format!("tier:{tier}")),
+        ];
+        (credentials.iter().flatten())
+            .filter_map(|credential| self.credential_ports.get(credential))
+            .fold(ports, |ports, allowed| ports.within(allowed))
+    }
+}
+
+/// Public side of a tunnel.
+enum Listener {
+    /// Sockets on the same port of each address that the tunnel listens on.
+    Tcp(Vec<TcpListener>),
+    Udp(Relay),
+    Memory(MemoryListener),
+}
+
+impl Listener {
+    /// Duplicate of the listening socket, to hand over to a new server.
+    /// Tunnels that listen on several addresses are not handed over.
+    fn duplicate(&self) -> Option<Socket> {
+        match self {
+            Listener::Tcp(listeners) => match listeners.as_slice() {
+                [listener] => SockRef::from(listener).try_clone().ok(),
+                _ => None,
+            },
+            Listener::Udp(_) | Listener::Memory(_) => None,
+        }
+    }
+
+    /// Duplicates of all listening sockets of a TCP tunnel, to keep them
+    /// open once it closes.
+    fn duplicate_all(&self) -> Vec<Socket> {
+        match self {
+            Listener::Tcp(listeners) => (listeners.iter())
+                .filter_map(|listener| SockRef::from(listener).try_clone().ok())
+                .collect(),
+            Listener::Udp(_) | Listener::Memory(_) => Vec::new(),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listeners) => listeners[0].local_addr(),
+            Listener::Udp(relay) => relay.local_addr(),
+            Listener::Memory(listener) => Ok(SocketAddr::new(MEMORY_ADDR.ip(), listener.port())),
+        }
+    }
+
+    /// Wait for a new visitor. This is cancel safe.
+    async fn accept(&mut self) -> io::Result<(Visitor, SocketAddr)> {
+        match self {
+            Listener::Tcp(listeners) => {
+                let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+                let (accepted, _, _) = future::select_all(accepts).await;
+                let (stream, addr) = accepted?;
+                Ok((Visitor::Tcp(stream), canonical_addr(addr)))
+            }
+            Listener::Udp(relay) => {
+                let (session, addr) = relay.accept().await?;
+                Ok((Visitor::Udp(session), addr))
+            }
+            Listener::Memory(listener) => {
+                let stream = listener.accept().await?;
+                Ok((Visitor::Memory(stream, listener.port()), MEMORY_ADDR))
+            }
+        }
+    }
+}
+
+/// Visitor of a tunnel, waiting to be forwarded.
+enum Visitor {
+    Tcp(TcpStream),
+    Udp(Session),
+    Memory(DuplexStream, u16),
+    Routed(Box<dyn http::HttpStream>, u16, SocketAddr),
+}
+
+/// Byte stream from a visitor, over the network or in memory.
+trait VisitorStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> VisitorStream for T {}
+
+/// Labels identifying the owner of a tunnel in logs.
+#[derive(Clone, Default)]
+struct TunnelLabels {
+    /// User that the client authenticated as.
+    user_id: Option<String>,
+
+    /// Name that the client gave the tunnel.
+    name: Option<String>,
+}
+
+impl TunnelLabels {
+    /// Attach the labels to a span, which must declare them as fields.
+    fn record(&self, span: &Span) {
+        if let Some(user_id) = &self.user_id {
+            span.record("user_id", user_id.as_str());
+        }
+        if let Some(name) = &self.name {
+            span.record("tunnel", name.as_str());
+        }
+    }
+
+    /// Access log record of a visitor of the tunnel.
+    fn access(&self, peer: SocketAddr, port: u16, reason: Reason) -> AccessEntry {
+        AccessEntry {
+            user_id: self.user_id.clone(),
+            tunnel: self.name.clone(),
+            ..AccessEntry::new(peer, port, reason)
+        }
+    }
+}
+
+/// Registration of an open tunnel that observers can attach to, which is
+/// removed when dropped.
+struct ObservedTunnel<'a> {
+    observers: &'a DashMap<u16, broadcast::Sender<Observation>>,
+    port: u16,
+    events: broadcast::Sender<Observation>,
+}
+
+impl<'a> ObservedTunnel<'a> {
+    fn new(observers: &'a DashMap<u16, broadcast::Sender<Observation>>, port: u16) -> Self {
+        let (events, _) = broadcast::channel(OBSERVER_BUFFER);
+        observers.insert(port, events.clone());
+        Self {
+            observers,
+            port,
+            events,
+        }
+    }
+}
+
+impl Drop for ObservedTunnel<'_> {
+    fn drop(&mut self) {
+        // Another tunnel may have taken over the port in the meantime.
+        self.observers
+            .remove_if(&self.port, |_, events| events.same_channel(&self.events));
+    }
+}
+
+/// Open tunnel, as the admin API sees it.
+struct TunnelEntry {
+    /// Address of the client that opened the tunnel.
+    client_addr: SocketAddr,
+
+    /// Labels of the tunnel.
+    labels: TunnelLabels,
+
+    /// Traffic through the tunnel.
+    stats: Arc<TunnelStats>,
+
+    /// Group that operators assigned the tunnel to.
+    group: Option<String>,
+
+    /// Switches that the admin API operates the tunnel with.
+    controls: Arc<TunnelControls>,
+
+    /// Listening socket of the tunnel, kept to hand over to a new server.
+    socket: Option<Socket>,
+}
+
+/// Switches that the admin API operates a tunnel with.
+#[derive(Default)]
+struct TunnelControls {
+    /// Signal to close the tunnel.
+    close: Notify,
+
+    /// Whether new visitors are left waiting.
+    paused: AtomicBool,
+
+    /// Limit on the traffic of the tunnel's connections.
+    bandwidth: Arc<Bandwidth>,
+
+    /// Cancelled when the tunnel has used up its transfer quota, to cut off
+    /// its connections.
+    exhausted: CancellationToken,
+}
+
+/// Registration of an open tunnel with the admin API, which is removed when
+/// dropped.
+struct RegisteredTunnel<'a> {
+    tunnels: &'a DashMap<u16, TunnelEntry>,
+    metrics: &'a ServerMetrics,
+    allocator: &'a dyn PortAllocator,
+    port: u16,
+    controls: Arc<TunnelControls>,
+    stats: Arc<TunnelStats>,
+}
+
+impl Drop for RegisteredTunnel<'_> {
+    fn drop(&mut self) {
+        self.tunnels.remove_if(&self.port, |_, entry| {
+            Arc::ptr_eq(&entry.controls, &self.controls)
+        });
+        self.metrics.remove_tunnel(self.port, &self.stats);
+        self.allocator.released(self.port);
+    }
+}
+
+/// Listening sockets of a closed tunnel, kept open for its client to
+/// reconnect.
+struct ParkedPort {
+    sockets: Vec<Socket>,
+
+    /// Client that may claim the port, if ports are reserved.
+    owner: Option<Owner>,
+    id: Uuid,
+}
+
+/// Listening sockets of an open tunnel, which are parked when it closes,
+/// unless it was handed over to a new server.
+struct ParkOnClose<'a> {
+    parked: &'a Arc<DashMap<u16, ParkedPort>>,
+    port: u16,
+    sockets: Vec<Socket>,
+    owner: Option<Owner>,
+    grace: Duration,
+    handed_off: CancellationToken,
+}
+
+impl Drop for ParkOnClose<'_> {
+    fn drop(&mut self) {
+        if self.sockets.is_empty() || self.handed_off.is_cancelled() {
+            return;
+        }
+        let (port, id) = (self.port, Uuid::new_v4());
+        let sockets = std::mem::take(&mut self.sockets);
+        (self.parked).insert(
+            port,
+            ParkedPort {
+                sockets,
+                owner: self.owner,
+                id,
+            },
+        );
+        info!(port, grace = ?self.grace, "holding port for the client to reconnect");
+        let (parked, grace) = (Arc::clone(self.parked), self.grace);
+        tokio::spawn(async move {
+            sleep(grace).await;
+            if parked
+                .remove_if(&port, |_, parked| parked.id == id)
+                .is_some()
+            {
+                info!(port, "closed port that no client reconnected to");
+            }
+        });
+    }
+}
+
+/// Claim on one of the tunnels that a user, or a client address, may have
+/// open, which is given back when dropped.
+struct TunnelSlot<'a> {
+    counts: &'a DashMap<String, u32>,
+    owner: String,
+}
+
+impl<'a> TunnelSlot<'a> {
+    /// Claim a slot, unless the owner already has `max` tunnels open.
+    fn claim(counts: &'a DashMap<String, u32>, owner: &str, max: u32) -> Option<Self> {
+        let mut count = counts.entry(owner.to_string()).or_insert(0);
+        if *count >= max {
+            drop(count);
+            counts.remove_if(owner, |_, count| *count == 0);
+            return None;
+        }
+        *count += 1;
+        Some(Self {
+            counts,
+            owner: owner.to_string(),
+        })
+    }
+}
+
+impl Drop for TunnelSlot<'_> {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.counts.get_mut(&self.owner) {
+            *count -= 1;
+        }
+        self.counts.remove_if(&self.owner, |_, count| *count == 0);
+    }
+}
+
+/// Session token of an open tunnel, which is revoked when dropped.
+struct SessionToken<'a> {
+    tokens: &'a DashMap<[u8; 32], u16>,
+    digest: [u8; 32],
+    value: String,
+}
+
+impl<'a> SessionToken<'a> {
+    /// Issue a token for data connections of the tunnel on a port.
+    fn issue(tokens: &'a DashMap<[u8; 32], u16>, port: u16) -> Self {
+        let value = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let digest = Sha256::digest(&value).into();
+        tokens.insert(digest, port);
+        Self {
+            tokens,
+            digest,
+            value,
+        }
+    }
+}
+
+impl Drop for SessionToken<'_> {
+    fn drop(&mut self) {
+        self.tokens.remove(&self.digest);
+    }
+}
+
+/// Incoming connection waiting for the client to accept it.
+struct PendingConnection {
+    /// Connection or UDP session from the visitor.
+    visitor: Visitor,
+
+    /// Port of the tunnel that the visitor connected to.
+    port: u16,
+
+    /// Where to report checksums of the proxied stream, if negotiated.
+    checksums: Option<mpsc::UnboundedSender<StreamChecksum>>,
+
+    /// Number of data connections that the proxied stream is striped across.
+    stripes: u8,
+
+    /// Whether the data connections are encrypted.
+    encrypted: bool,
+
+    /// Data connections from the client that have arrived, with their index.
+    arrived: Vec<(u8, Delimited<ControlStream>)>,
+
+    /// ISO code of the country of the visitor, if known.
+    country: Option<String>,
+
+    /// Labels of the tunnel that the connection arrived on.
+    labels: TunnelLabels,
+
+    /// When the visitor connected.
+    since: Instant,
+
+    /// Number of visitors waiting on the same tunnel, including this one.
+    queued: Arc<AtomicUsize>,
+
+    /// Traffic through the tunnel that the connection arrived on.
+    stats: Arc<TunnelStats>,
+
+    /// Limit on the traffic through the tunnel.
+    bandwidth: Arc<Bandwidth>,
+
+    /// Cancelled when the tunnel has used up its transfer quota.
+    exhausted: CancellationToken,
+}
+
+/// How a connection that no longer waits for the client was settled,
+/// remembered for a while to tell why a late data connection matches nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Settled {
+    Claimed,
+    Expired,
+}
+
+/// Visitors of a tunnel that wait for its client, which are dropped when the
+/// tunnel closes rather than when they time out.
+struct AbandonOnClose<'a> {
+    conns: &'a DashMap<Uuid, PendingConnection>,
+    metrics: &'a ServerMetrics,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Drop for AbandonOnClose<'_> {
+    fn drop(&mut self) {
+        if self.queued.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut abandoned = 0;
+        self.conns.retain(|_, pending| {
+            if !Arc::ptr_eq(&pending.queued, &self.queued) {
+                return true;
+            }
+            abandoned += 1;
+            self.metrics.drop_pending(CloseReason::TunnelClosed);
+            false
+        });
+        if abandoned > 0 {
+            let reason = CloseReason::TunnelClosed.as_str();
+            warn!(
+                abandoned,
+                reason, "dropped connections that the client never accepted"
+            );
+        }
+    }
+}
+
+/// State structure for the server.
+pub struct Server {
+    /// Port range, authentication, and other settings that can be reloaded.
+    settings: RwLock<Arc<Settings>>,
+
+    /// File that the settings are reloaded from on SIGHUP, if any.
+    config_file: Option<PathBuf>,
+
+    /// Settings from before a reload, which open tunnels may still use.
+    retired: Mutex<Vec<Weak<Settings>>>,
+
     /// Concurrent map of IDs to incoming connections.
-    conns: Arc<DashMap<Uuid, TcpStream>>,
+    conns: Arc<DashMap<Uuid, PendingConnection>>,
+
+    /// Connections that recently stopped waiting, and how.
+    settled: Arc<DashMap<Uuid, Settled>>,
 
     /// IP address where the control server will bind to.
     bind_addr: IpAddr,
 
-    /// IP address where tunnels will listen on.
-    bind_tunnels: IpAddr,
+    /// Identity key proven to clients that ask for it.
+    identity: Option<Arc<ServerIdentity>>,
+
+    /// Pacing of new control connections, to smooth out reconnect storms.
+    handshake_limiter: Option<TokenBucket>,
+
+    /// Rate limits and bans of the addresses that connect, if enabled.
+    guard: Option<SourceGuard>,
+
+    /// Ports of closed tunnels held for their clients, if enabled.
+    reservations: Option<Reservations>,
+
+    /// Time that the listening sockets of closed tunnels stay open for their
+    /// clients to reconnect, if enabled.
+    reconnect_grace: Option<Duration>,
+
+    /// Listening sockets of closed tunnels, kept open within the grace.
+    parked: Arc<DashMap<u16, ParkedPort>>,
+
+    /// Time that a new connection has to finish its handshake.
+    handshake_timeout: Duration,
+
+    /// Permits for connections in their handshake. New connections that
+    /// find none left are closed rather than waiting.
+    handshakes: Arc<Semaphore>,
+
+    /// Whether the server requires authentication and keeps tunnels off
+    /// privileged ports.
+    hardened: bool,
+
+    /// Whether to hide the reason for credential failures from clients.
+    redact_auth_errors: bool,
+
+    /// Rules for capturing payload samples of forwarded connections.
+    sampler: Arc<Sampler>,
+
+    /// Script deciding whether to admit incoming public connections.
+    policy: Option<Policy>,
+
+    /// Addresses of visitors that may reach any tunnel.
+    access_list: AccessList,
+
+    /// Database that visitors' countries are looked up in, and the countries
+    /// that may reach tunnels.
+    geoip: Option<(GeoIp, CountryRules)>,
+
+    /// Number of connections that are currently being forwarded.
+    active: AtomicUsize,
+
+    /// Issuer of sub-keys, if clients may delegate access.
+    sub_keys: Option<Arc<SubKeyIssuer>>,
+
+    /// How long answers of the API key backend are reused, when accepting
+    /// and when rejecting a key.
+    validation_cache_ttl: (Duration, Duration),
+
+    /// How outages of the API key backend are handled, kept for reloads.
+    validation_outage: OutagePolicy,
+
+    /// Client for requests to the API key backend, if not the default one.
+    validation_client: Option<reqwest::Client>,
+
+    /// Audit log of forwarded connections, if enabled.
+    transcript: Option<Transcript>,
+
+    /// Structured log of every visitor, if enabled.
+    access_log: Option<Arc<AccessLog>>,
+
+    /// Reporter of the traffic of each API key for billing, if enabled.
+    usage: Option<Arc<UsageReporter>>,
+
+    /// TLS configuration for the control port, if enabled.
+    tls: Option<TlsAcceptor>,
+
+    /// Whether the server refuses to run, or to serve clients, without TLS.
+    require_tls: bool,
+
+    /// Script to run whenever a tunnel is opened.
+    on_tunnel_open: Option<PathBuf>,
+
+    /// Whether clients may wrap connections to the control port in WebSocket.
+    websocket: bool,
+
+    /// Where to keep state across restarts, if anywhere.
+    state_file: Option<StateFile>,
+
+    /// Interval between heartbeats on control connections.
+    heartbeat_interval: Duration,
+
+    /// Events of each open tunnel, by port, for observers to subscribe to.
+    observers: DashMap<u16, broadcast::Sender<Observation>>,
+
+    /// Most visitors of each tunnel that may wait for the client at once.
+    max_pending: usize,
+
+    /// Time that a visitor may wait for the client to accept it.
+    pending_timeout: Duration,
+
+    /// Time that a tunnel may carry no traffic before it is closed, if any.
+    idle_timeout: Option<Duration>,
+
+    /// Longest frame accepted from clients on the control port.
+    max_frame_length: usize,
+
+    /// Counters exposed to monitoring.
+    metrics: Arc<ServerMetrics>,
+
+    /// In-memory broker to serve instead of the network, if any.
+    broker: Option<Broker>,
+
+    /// Open tunnels, by port, for the admin API.
+    tunnels: DashMap<u16, TunnelEntry>,
+
+    /// Number of open tunnels of each user with a quota.
+    user_tunnels: DashMap<String, u32>,
+
+    /// Number of open anonymous tunnels of each client address, if limited.
+    anonymous_tunnels: DashMap<String, u32>,
+
+    /// Most anonymous tunnels that one client address may have open at once.
+    max_anonymous_tunnels: Option<u32>,
+
+    /// Time that anonymous tunnels stay open, if limited.
+    anonymous_tunnel_ttl: Option<Duration>,
+
+    /// Lowest port that clients without credentials may get, if they may
+    /// open tunnels on a server that checks credentials.
+    anonymous_min_port: Option<u16>,
+
+    /// Strategy for choosing the ports of tunnels that do not ask for one.
+    allocator: Arc<dyn PortAllocator>,
+
+    /// Traffic of the tunnels with transfer quotas.
+    transfers: TransferLedger,
+
+    /// Address and token of the admin API, if enabled.
+    admin: Option<(SocketAddr, String)>,
+
+    /// Address of the metrics endpoint, if enabled.
+    metrics_addr: Option<SocketAddr>,
+
+    /// Shared port for HTTP tunnels and the router of its hostnames, if
+    /// enabled.
+    http: Option<(u16, Arc<HttpRouter>)>,
+
+    /// Shared TLS port for HTTP tunnels and the ACME client of its
+    /// certificates, if enabled.
+    https: Option<(u16, Arc<Acme>)>,
+
+    /// Shared port that routes TLS connections to TLS tunnels without
+    /// decrypting them, if enabled.
+    tls_passthrough: Option<u16>,
+
+    /// Provider that visitors of HTTP tunnels log in with, for tunnels that
+    /// ask for it, if enabled.
+    oidc: Option<OidcConfig>,
+
+    /// Digests of the session tokens of open tunnels, with their port.
+    session_tokens: DashMap<[u8; 32], u16>,
+
+    /// How to start a new server to hand the listening sockets over to on
+    /// SIGUSR2, if enabled.
+    handoff: Option<handoff::Spawner>,
+
+    /// Listening sockets inherited from a previous server, until they are
+    /// used.
+    inherited: Mutex<Inherited>,
+
+    /// Cancelled when handing over to a new server, to close all tunnels.
+    handed_off: Mutex<CancellationToken>,
 }
 
-impl Server {
-    /// Create a new server with a specified minimum port number.
-    pub fn new(port_range: RangeInclusive<u16>, secret: Option<&str>, api_validation_url: Option<String>) -> Self {
-        assert!(!port_range.is_empty(), "must provide at least one port");
+impl Server {
+    /// Create a new server with a specified minimum port number.
+    pub fn new(
+        port_range: RangeInclusive<u16>,
+        secret: Option<&str>,
+        api_validation_url: Option<String>,
+    ) -> Self {
+        assert!(!port_range.is_empty(), "must provide at least one port");
+
+        let secrets: Vec<_> = secret
+            .map(|secret| (secret_fingerprint(secret), secret))
+            .into_iter()
+            .collect();
+        let metrics = Arc::new(ServerMetrics::default());
+        let mut auth = AuthMode::new(&secrets, api_validation_url.into_iter().collect());
+        auth.set_outage_policy(OutagePolicy::default(), &metrics);
+        let settings = Settings {
+            ports: port_range.into(),
+            auth,
+            bind_tunnels: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            listener_classes: BTreeMap::new(),
+            credential_ports: BTreeMap::new(),
+        };
+
+        Server {
+            settings: RwLock::new(Arc::new(settings)),
+            config_file: None,
+            retired: Mutex::new(Vec::new()),
+            conns: Arc::new(DashMap::new()),
+            settled: Arc::new(DashMap::new()),
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            identity: None,
+            handshake_limiter: None,
+            guard: None,
+            reservations: None,
+            reconnect_grace: None,
+            parked: Arc::new(DashMap::new()),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            handshakes: Arc::new(Semaphore::new(MAX_HANDSHAKES)),
+            hardened: false,
+            redact_auth_errors: false,
+            sampler: Arc::new(Sampler::default()),
+            policy: None,
+            access_list: AccessList::default(),
+            geoip: None,
+            active: AtomicUsize::new(0),
+            sub_keys: None,
+            validation_cache_ttl: (VALIDATION_CACHE_TTL, VALIDATION_NEGATIVE_TTL),
+            validation_outage: OutagePolicy::default(),
+            validation_client: None,
+            transcript: None,
+            access_log: None,
+            usage: None,
+            tls: None,
+            require_tls: false,
+            on_tunnel_open: None,
+            websocket: false,
+            state_file: None,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            observers: DashMap::new(),
+            max_pending: MAX_PENDING,
+            pending_timeout: PENDING_TIMEOUT,
+            idle_timeout: None,
+            max_frame_length: MAX_FRAME_LENGTH,
+            metrics,
+            broker: None,
+            tunnels: DashMap::new(),
+            user_tunnels: DashMap::new(),
+            anonymous_tunnels: DashMap::new(),
+            max_anonymous_tunnels: None,
+            anonymous_tunnel_ttl: None,
+            anonymous_min_port: None,
+            allocator: Arc::new(RandomPorts),
+            transfers: TransferLedger::default(),
+            admin: None,
+            metrics_addr: None,
+            http: None,
+            https: None,
+            tls_passthrough: None,
+            oidc: None,
+            session_tokens: DashMap::new(),
+            handoff: None,
+            inherited: Mutex::new(Inherited::default()),
+            handed_off: Mutex::new(CancellationToken::new()),
+        }
+    }
+
+    /// Set the IP address where tunnels will listen on.
+    pub fn set_bind_addr(&mut self, bind_addr: IpAddr) {
+        self.bind_addr = bind_addr;
+    }
+
+    /// Set the IP address where the control server will bind to.
+    pub fn set_bind_tunnels(&mut self, bind_tunnels: IpAddr) {
+        self.settings_mut().bind_tunnels = vec![bind_tunnels];
+    }
+
+    /// Set several IP addresses where tunnels will listen on, each on the
+    /// same port, such as a public IPv4 and IPv6 address. UDP tunnels only
+    /// listen on the first.
+    pub fn set_tunnel_addrs(&mut self, addrs: Vec<IpAddr>) {
+        assert!(!addrs.is_empty(), "must provide at least one address");
+        self.settings_mut().bind_tunnels = addrs;
+    }
+
+    /// Let clients ask for their tunnels to listen on other addresses by
+    /// naming a class of listener, such as `internal` for addresses that are
+    /// only reachable from a private network.
+    pub fn set_listener_class(&mut self, class: &str, addrs: Vec<IpAddr>) {
+        assert!(!addrs.is_empty(), "must provide at least one address");
+        (self.settings_mut().listener_classes).insert(class.to_string(), addrs);
+    }
+
+    /// Create a server from the settings of a configuration file.
+    pub fn with_config(config: &ServerConfig) -> Self {
+        let mut server = Self::new(config.port_range(), None, None);
+        server.set_ports(config.ports());
+        let mut auth = AuthMode::new(&config.secrets(), config.validation_urls());
+        auth.set_outage_policy(server.validation_outage, &server.metrics);
+        server.settings_mut().auth = auth;
+        server.set_bind_addr(config.bind_addr);
+        server.set_tunnel_addrs(config.bind_tunnels());
+        server.settings_mut().listener_classes = config.listener_classes.clone();
+        server.settings_mut().credential_ports = config.credential_ports();
+        server
+    }
+
+    /// Choose the ports of tunnels that do not ask for one with this
+    /// strategy, instead of at random.
+    pub fn set_port_allocator(&mut self, allocator: Arc<dyn PortAllocator>) {
+        self.allocator = allocator;
+    }
+
+    /// Replace the ports that tunnels may listen on, such as with several
+    /// disjoint ranges or with some ports excluded.
+    pub fn set_ports(&mut self, ports: PortSet) {
+        assert!(!ports.is_empty(), "must provide at least one port");
+        self.settings_mut().ports = ports;
+    }
+
+    /// Only let clients with a credential use ports in this range, within the
+    /// server's own. The credential is `secret:<label>`, `user:<user_id>`, or
+    /// `tier:<tier>`, and clients matching several rules are held to all of
+    /// them.
+    pub fn set_credential_port_range(&mut self, credential: &str, range: RangeInclusive<u16>) {
+        (self.settings_mut().credential_ports).insert(credential.to_string(), range);
+    }
+
+    /// Set how long answers of the API key backend are reused before asking it
+    /// again, when it accepted and when it rejected a key. Zero disables
+    /// caching. Reloading the configuration clears the cache.
+    pub fn set_validation_cache_ttl(&mut self, ttl: Duration, negative_ttl: Duration) {
+        self.validation_cache_ttl = (ttl, negative_ttl);
+        self.settings_mut().auth.set_cache_ttl(ttl, negative_ttl);
+    }
+
+    /// Set how the server copes with outages of the API key backend: whether
+    /// keys that it accepted recently are still let in for a grace period, and
+    /// when it is backed off from. The health of the backend is reported in
+    /// the metrics.
+    pub fn set_validation_outage_policy(&mut self, policy: OutagePolicy) {
+        self.validation_outage = policy;
+        let metrics = Arc::clone(&self.metrics);
+        self.settings_mut().auth.set_outage_policy(policy, &metrics);
+    }
+
+    /// Make requests to the API key backend with this client, such as one
+    /// that adds a service token or presents a client certificate, built from
+    /// [`ValidationRequestOptions`](crate::auth::ValidationRequestOptions).
+    pub fn set_validation_client(&mut self, client: reqwest::Client) {
+        self.settings_mut().auth.set_client(&client);
+        self.validation_client = Some(client);
+    }
+
+    /// Check the credentials of clients with a custom provider, in place of
+    /// the secret or API key backend given to [`Server::new`].
+    ///
+    /// The provider is kept when the configuration file is reloaded.
+    pub fn set_auth_provider(&mut self, provider: Arc<dyn AuthProvider>) {
+        self.settings_mut().auth = AuthMode::Custom(provider);
+    }
+
+    /// Reload the settings from this configuration file whenever the server
+    /// receives SIGHUP, which is only supported on Unix.
+    ///
+    /// The port range, credentials, and tunnel bind address of the file apply
+    /// to new tunnels, while open tunnels are left alone and their clients
+    /// may keep authenticating data connections with their old credentials.
+    /// A file that fails to load is ignored, keeping the current settings.
+    pub fn set_config_file(&mut self, path: PathBuf) {
+        self.config_file = Some(path);
+    }
+
+    /// Set the identity key that clients can pin to detect impersonation.
+    pub fn set_identity(&mut self, identity: ServerIdentity) {
+        self.identity = Some(Arc::new(identity));
+    }
+
+    /// Let authenticated clients mint sub-keys, signed with the identity key.
+    ///
+    /// # Panics
+    ///
+    /// If no identity key has been set with [`Server::set_identity`].
+    pub fn enable_sub_keys(&mut self) {
+        let identity = self
+            .identity
+            .clone()
+            .expect("sub-keys require an identity key");
+        self.sub_keys = Some(Arc::new(SubKeyIssuer::new(identity)));
+    }
+
+    /// Limit the rate of new control connections, allowing bursts up to `burst`.
+    ///
+    /// Connections beyond the limit are told to retry after a random delay, so
+    /// that clients reconnecting after a server restart spread out over time.
+    pub fn set_handshake_rate(&mut self, per_second: f64, burst: u32) {
+        self.handshake_limiter = Some(TokenBucket::new(per_second, burst.max(1) as f64));
+    }
+
+    /// Limit the rate of new control connections from each IP address,
+    /// allowing bursts up to `burst`. Connections beyond the limit are closed.
+    pub fn set_handshake_rate_per_ip(&mut self, per_second: f64, burst: u32) {
+        self.guard
+            .get_or_insert_with(SourceGuard::default)
+            .set_rate(per_second, burst);
+    }
+
+    /// Ban IP addresses from the control port for `duration` after
+    /// `max_failures` failed authentications, like fail2ban does.
+    pub fn set_auto_ban(&mut self, max_failures: u32, duration: Duration) {
+        self.guard
+            .get_or_insert_with(SourceGuard::default)
+            .set_auto_ban(max_failures, duration);
+    }
+
+    /// Set the time that a new connection has to finish its handshake,
+    /// through TLS, authentication, and its first message, 10 seconds by
+    /// default. Connections that take longer are closed.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Set how many connections may be in their handshake at once, 1024 by
+    /// default, so that clients that connect and then stall cannot tie up the
+    /// server. Further connections are closed until handshakes finish.
+    pub fn set_max_handshakes(&mut self, max: usize) {
+        self.handshakes = Arc::new(Semaphore::new(max.max(1)));
+    }
+
+    /// Hold the port of a closed tunnel for `grace`, so that only the same
+    /// client may open a tunnel on it. A client that reconnects with the same
+    /// credential and tunnel name gets the port back, even without asking for
+    /// it, so that addresses handed out stay valid across network blips.
+    /// Clients without credentials must reconnect from the same address.
+    pub fn set_port_reservation(&mut self, grace: Duration) {
+        self.reservations = Some(Reservations::new(grace));
+    }
+
+    /// Keep the port of a closed tunnel listening for `grace`, so that
+    /// visitors who arrive while its client reconnects wait in the backlog,
+    /// and reach the client once it opens a tunnel on the port again.
+    pub fn set_reconnect_grace(&mut self, grace: Duration) {
+        self.reconnect_grace = Some(grace);
+    }
+
+    /// Switch to safe defaults for a relay open to the internet.
+    ///
+    /// The server refuses to start without authentication and TLS, or with
+    /// [`Server::set_anonymous_min_port`], limits the rate of new control
+    /// connections unless a rate was already set, gives handshakes a few
+    /// seconds to finish, bans addresses that fail to authenticate repeatedly
+    /// unless bans were already set up, and keeps tunnels off privileged
+    /// ports whatever the port range allows.
+    pub fn enable_hardening(&mut self) {
+        self.hardened = true;
+        self.require_tls = true;
+        self.handshake_timeout = self.handshake_timeout.min(HARDENED_HANDSHAKE_TIMEOUT);
+        if self.handshake_limiter.is_none() {
+            let (per_second, burst) = HARDENED_HANDSHAKE_RATE;
+            self.set_handshake_rate(per_second, burst);
+        }
+        let guard = self.guard.get_or_insert_with(SourceGuard::default);
+        if guard.auto_ban().is_none() {
+            guard.set_auto_ban(guard::DEFAULT_BAN_AFTER, guard::DEFAULT_BAN_DURATION);
+        }
+    }
+
+    /// Report only a generic reason to clients whose credentials are rejected.
+    ///
+    /// Detailed reasons help users fix their configuration, but also tell an
+    /// attacker which kind of credential the server expects.
+    pub fn set_redact_auth_errors(&mut self, redact: bool) {
+        self.redact_auth_errors = redact;
+    }
+
+    /// Handle for sampling connection payloads, which can be changed at runtime.
+    pub fn sampler(&self) -> Arc<Sampler> {
+        Arc::clone(&self.sampler)
+    }
+
+    /// Require TLS on all connections to the control port.
+    pub fn set_tls(&mut self, acceptor: TlsAcceptor) {
+        self.tls = Some(acceptor);
+    }
+
+    /// Guarantee that no credentials or traffic cross the network unencrypted.
+    ///
+    /// The server refuses to listen until TLS is set with [`Server::set_tls`],
+    /// and clients that connect to the control port in plaintext are told to
+    /// enable TLS, rather than failing the TLS handshake.
+    pub fn set_require_tls(&mut self, require: bool) {
+        self.require_tls = require;
+    }
+
+    /// Keep abuse countermeasures, such as the handshake rate limit and bans,
+    /// and port reservations in a state file, so that restarting the server
+    /// does not reset them.
+    pub fn set_state_file(&mut self, file: StateFile) {
+        self.state_file = Some(file);
+    }
+
+    /// Accept WebSocket upgrades on the control port, alongside the plain protocol.
+    ///
+    /// Clients of the plain protocol that wait for the server to speak first,
+    /// such as those that authenticate, are delayed slightly on each
+    /// connection while the server checks for a WebSocket handshake.
+    pub fn enable_websocket(&mut self) {
+        self.websocket = true;
+    }
+
+    /// Set the interval between heartbeats on control connections.
+    ///
+    /// Shorter intervals keep idle connections alive through NAT devices
+    /// that forget mappings quickly. Clients that declare a shorter liveness
+    /// threshold than this get heartbeats more often, and clients that answer
+    /// pings adapt the interval on their own.
+    pub fn set_heartbeat_interval(&mut self, interval: Duration) {
+        self.heartbeat_interval = interval.max(MIN_HEARTBEAT_INTERVAL);
+    }
+
+    /// Set how many visitors of each tunnel may wait for the client at once.
+    ///
+    /// Visitors beyond this are disconnected right away, instead of piling up
+    /// behind a client that cannot keep up.
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending.max(1);
+    }
+
+    /// Set the longest frame that clients may send on the control port.
+    ///
+    /// Clients that send a longer frame are disconnected, so that a peer
+    /// cannot make the server buffer unbounded input.
+    pub fn set_max_frame_length(&mut self, max_length: usize) {
+        self.max_frame_length = max_length;
+    }
+
+    /// Set how long a visitor may wait for the client to accept it.
+    pub fn set_pending_timeout(&mut self, timeout: Duration) {
+        self.pending_timeout = timeout;
+    }
+
+    /// Close tunnels of clients that did not authenticate, because the server
+    /// checks no credentials, once they have been open this long.
+    ///
+    /// This keeps a free tier from holding on to ports forever. Clients are
+    /// told not to reopen the tunnel.
+    pub fn set_anonymous_tunnel_ttl(&mut self, ttl: Duration) {
+        self.anonymous_tunnel_ttl = Some(ttl);
+    }
+
+    /// Let clients without credentials open tunnels on random ports from this
+    /// one up, while asking for a specific port, or getting a lower one, still
+    /// requires credentials.
+    ///
+    /// This runs a semi-public server, where anyone may get a tunnel, but the
+    /// ports that matter are kept for those with a secret or API key. Such
+    /// tunnels count as anonymous for the other limits on them.
+    pub fn set_anonymous_min_port(&mut self, port: u16) {
+        self.anonymous_min_port = Some(port);
+    }
+
+    /// Set how many tunnels of clients that did not authenticate may be open
+    /// at once from each address.
+    pub fn set_max_anonymous_tunnels(&mut self, per_ip: u32) {
+        self.max_anonymous_tunnels = Some(per_ip);
+    }
+
+    /// Close tunnels that carry no traffic for this long, telling their
+    /// clients not to reopen them.
+    ///
+    /// This frees the ports of tunnels that were left open and forgotten,
+    /// such as by development sessions on a shared server.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    /// Get a handle to the counters of the server, such as for serving them.
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Serve clients of an in-memory broker instead of the control port, with
+    /// tunnels that visitors reach through the broker instead of public ports.
+    pub fn set_broker(&mut self, broker: Broker) {
+        self.broker = Some(broker);
+    }
+
+    /// Serve the admin API on an address, for requests bearing the token.
+    pub fn set_admin(&mut self, addr: SocketAddr, token: String) {
+        self.admin = Some((addr, token));
+    }
+
+    /// Serve metrics in the Prometheus format on an address.
+    pub fn set_metrics_addr(&mut self, addr: SocketAddr) {
+        self.metrics_addr = Some(addr);
+    }
+
+    /// Route HTTP requests on a shared port, on the addresses of tunnels, to
+    /// HTTP tunnels by hostname, each at a subdomain of a domain.
+    pub fn enable_http(&mut self, port: u16, domain: &str) {
+        self.http = Some((port, Arc::new(HttpRouter::new(domain))));
+    }
+
+    /// Render the pages that visitors of HTTP tunnels get when their tunnel
+    /// is offline, busy, or missing from an HTML template, as described in
+    /// [`HttpRouter::set_error_page`]. HTTP routing must be enabled.
+    pub fn set_http_error_page(&mut self, template: String) {
+        let Some((_, router)) = &mut self.http else {
+            panic!("error pages need HTTP routing");
+        };
+        (Arc::get_mut(router))
+            .expect("router is not shared before listening")
+            .set_error_page(template);
+    }
+
+    /// Also terminate TLS for HTTP tunnels on a shared port, with
+    /// certificates from an ACME authority. HTTP routing must be enabled.
+    pub fn enable_https(&mut self, port: u16, acme: Acme) {
+        assert!(self.http.is_some(), "HTTPS needs HTTP routing");
+        self.https = Some((port, Arc::new(acme)));
+    }
+
+    /// Route TLS connections on a shared port to TLS tunnels by the server
+    /// name of their ClientHello, without decrypting them. This may be the
+    /// port that terminates TLS for HTTP tunnels. HTTP routing must be
+    /// enabled, for the domain of their hostnames.
+    pub fn enable_tls_passthrough(&mut self, port: u16) {
+        let Some((http_port, _)) = self.http else {
+            panic!("TLS passthrough needs HTTP routing");
+        };
+        assert_ne!(port, http_port, "TLS passthrough needs its own port");
+        self.tls_passthrough = Some(port);
+    }
+
+    /// Let HTTP tunnels require visitors to log in with an OpenID Connect
+    /// provider. The provider must accept redirects to the callback path of
+    /// [`crate::oidc`] on the domain of HTTP tunnels, over HTTPS if it is
+    /// enabled. HTTP routing must be enabled.
+    pub fn enable_oidc(&mut self, config: OidcConfig) {
+        assert!(self.http.is_some(), "OIDC needs HTTP routing");
+        self.oidc = Some(config);
+    }
+
+    /// Hand the listening sockets over to a new server on SIGUSR2, for
+    /// upgrades without downtime, which is only supported on Unix.
+    ///
+    /// The server starts its own binary again with the same arguments, and
+    /// once that has started, closes its tunnels for their clients to
+    /// reconnect to the new server. Tunnel ports stay open throughout. This
+    /// server then waits for forwarded connections to finish, up to a few
+    /// minutes, and [`Server::listen`] returns. With a state file, bans and
+    /// port reservations carry over too.
+    pub fn enable_handoff(&mut self, spawn: handoff::Spawner) {
+        self.handoff = Some(spawn);
+    }
+
+    /// Use the listening sockets that a previous server handed over, instead
+    /// of binding the control port and the ports of its tunnels anew.
+    pub fn adopt_listeners(&mut self, inherited: Inherited) {
+        self.inherited = Mutex::new(inherited);
+    }
+
+    /// Run a script whenever a tunnel is opened, with its details in the environment.
+    ///
+    /// The script receives `BORE_EVENT`, `BORE_PORT`, `BORE_BIND_ADDR`, and
+    /// `BORE_CLIENT_ADDR`, plus `BORE_SUB_KEY` for tunnels opened with a
+    /// sub-key, `BORE_USER_ID` when the API key backend names a user, and
+    /// `BORE_TUNNEL_NAME` for named tunnels. It runs in the background and
+    /// does not delay the tunnel.
+    pub fn set_on_tunnel_open(&mut self, program: PathBuf) {
+        self.on_tunnel_open = Some(program);
+    }
+
+    /// Record every forwarded connection into a hash-chained transcript.
+    pub fn set_transcript(&mut self, transcript: Transcript) {
+        self.transcript = Some(transcript);
+    }
+
+    /// Write a JSON record of every visitor of every tunnel to a log, with
+    /// its address, traffic, and why its connection ended.
+    pub fn set_access_log(&mut self, log: AccessLog) {
+        self.access_log = Some(Arc::new(log));
+    }
+
+    /// Periodically report the bytes and connections of the tunnels of each
+    /// API key, such as to a billing backend.
+    pub fn set_usage_reporter(&mut self, reporter: UsageReporter) {
+        self.usage = Some(Arc::new(reporter));
+    }
+
+    /// Evaluate an admission policy for every incoming public connection.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = Some(policy);
+    }
+
+    /// Only let visitors from addresses that a list permits reach tunnels.
+    /// Clients may restrict their own tunnels further.
+    pub fn set_access_list(&mut self, list: AccessList) {
+        self.access_list = list;
+    }
+
+    /// Look up the country of each visitor in a GeoIP database, to log and
+    /// count it, and only let visitors from countries that the rules permit
+    /// reach tunnels.
+    pub fn set_country_rules(&mut self, geoip: GeoIp, rules: CountryRules) {
+        self.geoip = Some((geoip, rules));
+    }
+
+    /// Start the server, listening for new connections.
+    pub async fn listen(self) -> Result<()> {
+        ensure!(
+            !self.require_tls || self.tls.is_some(),
+            "TLS is required, but no certificate was given"
+        );
+        ensure!(
+            !self.hardened || self.settings().auth.provider().is_some(),
+            "hardened servers require a secret or another way to authenticate"
+        );
+        ensure!(
+            !self.hardened || self.anonymous_min_port.is_none(),
+            "hardened servers cannot let clients without credentials open tunnels"
+        );
+        let this = Arc::new(self);
+        #[cfg(unix)]
+        if let Some(path) = this.config_file.clone() {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut hangups = signal(SignalKind::hangup())?;
+            let this = Arc::clone(&this);
+            tokio::spawn(async move {
+                while hangups.recv().await.is_some() {
+                    if let Err(err) = this.reload(&path) {
+                        warn!(%err, "could not reload configuration, keeping the current one");
+                    }
+                }
+            });
+        }
+        let mut endpoints = this.serve_endpoints();
+        if let Some(file) = &this.state_file {
+            this.restore(&file.load()?);
+            let this = Arc::clone(&this);
+            tokio::spawn(async move {
+                let mut ticker = interval(SAVE_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    if this.handed_off.lock().unwrap().is_cancelled() {
+                        // The new server keeps the state from now on.
+                        continue;
+                    }
+                    let file = this.state_file.as_ref().expect("state file is set");
+                    if let Err(err) = file.save(this.snapshot()) {
+                        warn!(%err, "could not save server state");
+                    }
+                }
+            });
+        }
+        if let Some(usage) = this.usage.clone() {
+            tokio::spawn(async move { usage.run().await });
+        }
+        if let Some(broker) = &this.broker {
+            let mut incoming = broker.serve()?;
+            info!("server listening in memory");
+            while let Some(stream) = incoming.recv().await {
+                let stream = Delimited::new(ControlStream::Memory(stream));
+                let Some(handshake) = this.start_handshake() else {
+                    debug!("too many handshakes in progress, refusing connection");
+                    continue;
+                };
+                let this = Arc::clone(&this);
+                tokio::spawn(
+                    async move { this.serve_control(stream, handshake).await }.instrument(
+                        info_span!(
+                            "control",
+                            addr = ?MEMORY_ADDR,
+                            user_id = field::Empty,
+                            secret = field::Empty,
+                            tunnel = field::Empty
+                        ),
+                    ),
+                );
+            }
+            return Ok(());
+        }
+        let inherited = this.inherited.lock().unwrap().control.take();
+        let listener = match inherited {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                info!(addr = ?listener.local_addr()?, "server listening on inherited socket");
+                TcpListener::from_std(listener)?
+            }
+            None => {
+                let listener = bind_tcp((this.bind_addr, CONTROL_PORT).into(), false)?;
+                info!(addr = ?this.bind_addr, "server listening");
+                listener
+            }
+        };
+        this.expire_inherited();
+        #[cfg(unix)]
+        let mut upgrades = match this.handoff {
+            Some(_) => Some(tokio::signal::unix::signal(
+                tokio::signal::unix::SignalKind::user_defined2(),
+            )?),
+            None => None,
+        };
+        if this.guard.is_some() || this.reservations.is_some() {
+            let this = Arc::clone(&this);
+            tokio::spawn(async move {
+                let mut ticker = interval(guard::SWEEP_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    if let Some(guard) = &this.guard {
+                        guard.sweep();
+                    }
+                    if let Some(reservations) = &this.reservations {
+                        reservations.sweep();
+                    }
+                }
+            });
+        }
+
+        loop {
+            #[cfg(unix)]
+            let upgrade = async {
+                match &mut upgrades {
+                    Some(upgrades) => upgrades.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let upgrade = std::future::pending::<Option<()>>();
+            let (stream, addr) = tokio::select! {
+                result = listener.accept() => result.map(|(stream, addr)| (stream, canonical_addr(addr)))?,
+                Some(()) = upgrade => {
+                    match this.hand_off(&listener, &mut endpoints).await {
+                        Ok(()) => return Ok(()),
+                        Err(err) => {
+                            warn!(%err, "could not hand over to a new server, serving on");
+                            continue;
+                        }
+                    }
+                }
+            };
+            if let Some(guard) = &this.guard {
+                let verdict = guard.admit(addr.ip());
+                if verdict != Verdict::Admit {
+                    debug!(?addr, ?verdict, "refused connection");
+                    continue;
+                }
+            }
+            let Some(handshake) = this.start_handshake() else {
+                debug!(
+                    ?addr,
+                    "too many handshakes in progress, refusing connection"
+                );
+                continue;
+            };
+            if let Some(limiter) = &this.handshake_limiter {
+                if !limiter.try_acquire(1.0) {
+                    let max_delay = limiter.refill_time().max(Duration::from_secs(1));
+                    debug!(?addr, "handshake rate exceeded, asking client to retry");
+                    let this = Arc::clone(&this);
+                    tokio::spawn(async move {
+                        let opened = handshake.within(this.open_control(stream)).await;
+                        let Ok(mut stream) = opened else {
+                            return;
+                        };
+                        let millis = max_delay.as_millis() as u64;
+                        stream.send(ServerMessage::Busy(millis)).await.ok();
+                    });
+                    continue;
+                }
+            }
+            let this = Arc::clone(&this);
+            tokio::spawn(
+                async move {
+                    info!("incoming connection");
+                    match handshake.within(this.open_control(stream)).await {
+                        Ok(stream) => this.serve_control(stream, handshake).await,
+                        Err(err) => warn!(%err, "rejected connection"),
+                    }
+                }
+                .instrument(info_span!(
+                    "control",
+                    ?addr,
+                    user_id = field::Empty,
+                    secret = field::Empty,
+                    tunnel = field::Empty
+                )),
+            );
+        }
+    }
+
+    /// Start the handshake of a new connection, unless too many are already
+    /// in progress.
+    fn start_handshake(&self) -> Option<Handshake> {
+        let permit = Arc::clone(&self.handshakes).try_acquire_owned().ok()?;
+        Some(Handshake {
+            deadline: tokio::time::Instant::now() + self.handshake_timeout,
+            _permit: permit,
+        })
+    }
+
+    /// Handle a connection to the control port until it closes.
+    async fn serve_control(&self, stream: Delimited<ControlStream>, handshake: Handshake) {
+        if let Err(err) = self.handle_connection(stream, handshake).await {
+            warn!(%err, "connection exited with error");
+        } else {
+            info!("connection exited");
+        }
+    }
+
+    /// Settings for a new handshake.
+    fn settings(&self) -> Arc<Settings> {
+        Arc::clone(&self.settings.read().unwrap())
+    }
+
+    /// Settings from before a reload that open tunnels still use.
+    fn retired_settings(&self) -> Vec<Arc<Settings>> {
+        let mut retired = self.retired.lock().unwrap();
+        retired.retain(|settings| settings.strong_count() > 0);
+        retired.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /// Change the settings before the server starts.
+    fn settings_mut(&mut self) -> &mut Settings {
+        let settings = self.settings.get_mut().unwrap();
+        Arc::get_mut(settings).expect("settings are not shared before the server starts")
+    }
+
+    /// Replace the settings with those of the configuration file.
+    fn reload(&self, path: &Path) -> Result<()> {
+        let config = ServerConfig::load(path)?;
+        if config.bind_addr != self.bind_addr {
+            warn!(bind_addr = %config.bind_addr, "changing bind_addr requires a restart");
+        }
+        let mut auth = match &self.settings().auth {
+            AuthMode::Custom(provider) => AuthMode::Custom(Arc::clone(provider)),
+            _ => AuthMode::new(&config.secrets(), config.validation_urls()),
+        };
+        ensure!(
+            !self.hardened || auth.provider().is_some(),
+            "hardened servers require a secret or another way to authenticate"
+        );
+        let (ttl, negative_ttl) = self.validation_cache_ttl;
+        auth.set_cache_ttl(ttl, negative_ttl);
+        auth.set_outage_policy(self.validation_outage, &self.metrics);
+        if let Some(client) = &self.validation_client {
+            auth.set_client(client);
+        }
+        let settings = Settings {
+            ports: config.ports(),
+            auth,
+            bind_tunnels: config.bind_tunnels(),
+            listener_classes: config.listener_classes.clone(),
+            credential_ports: config.credential_ports(),
+        };
+        let previous = std::mem::replace(&mut *self.settings.write().unwrap(), Arc::new(settings));
+        self.retired.lock().unwrap().push(Arc::downgrade(&previous));
+        info!(path = %path.display(), "reloaded configuration");
+        Ok(())
+    }
+
+    /// Tunnels that are currently open, oldest first.
+    pub(crate) fn open_tunnels(&self) -> Vec<OpenTunnel> {
+        let mut tunnels: Vec<_> = self
+            .tunnels
+            .iter()
+            .map(|entry| OpenTunnel {
+                port: *entry.key(),
+                client_addr: entry.client_addr,
+                user_id: entry.labels.user_id.clone(),
+                name: entry.labels.name.clone(),
+                uptime_secs: entry.stats.uptime().as_secs(),
+                connections: entry.stats.connections(),
+                bytes_in: entry.stats.inbound(),
+                bytes_out: entry.stats.outbound(),
+                throughput_1m: entry.stats.throughput(Duration::from_secs(60)),
+                throughput_5m: entry.stats.throughput(Duration::from_secs(300)),
+                p95_duration_ms: entry
+                    .stats
+                    .p95_duration()
+                    .map(|duration| duration.as_millis() as u64),
+                group: entry.group.clone(),
+                paused: entry.controls.paused.load(Ordering::Relaxed),
+                bytes_per_second: entry.controls.bandwidth.limit(),
+            })
+            .collect();
+        tunnels.sort_by_key(|tunnel| std::cmp::Reverse(tunnel.uptime_secs));
+        tunnels
+    }
+
+    /// Close the tunnel on a port, returning whether there was one.
+    pub(crate) fn close_tunnel(&self, port: u16) -> bool {
+        match self.tunnels.get(&port) {
+            Some(entry) => {
+                entry.controls.close.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take an action on every tunnel matching a selector, returning their ports.
+    pub(crate) fn apply(&self, selector: &Selector, action: &BulkAction) -> Vec<u16> {
+        let mut ports = Vec::new();
+        for mut entry in self.tunnels.iter_mut() {
+            let port = *entry.key();
+            let labels = &entry.labels;
+            if !selector.matches(
+                port,
+                labels.user_id.as_deref(),
+                labels.name.as_deref(),
+                entry.group.as_deref(),
+            ) {
+                continue;
+            }
+            let controls = &entry.controls;
+            match action {
+                BulkAction::Pause => controls.paused.store(true, Ordering::Relaxed),
+                BulkAction::Resume => controls.paused.store(false, Ordering::Relaxed),
+                BulkAction::Close => controls.close.notify_one(),
+                BulkAction::SetGroup { group } => entry.group = group.clone(),
+                BulkAction::RateLimit { bytes_per_second } => {
+                    controls.bandwidth.set_limit(*bytes_per_second)
+                }
+            }
+            ports.push(port);
+        }
+        ports.sort_unstable();
+        ports
+    }
+
+    /// Settings that the server is currently running with.
+    pub(crate) fn summary(&self) -> ServerSummary {
+        let settings = self.settings();
+        let auth = match settings.auth.provider() {
+            Some(provider) => provider.method(),
+            None => "none",
+        };
+        let bounds = settings.ports.bounds().unwrap_or(0..=0);
+        ServerSummary {
+            min_port: *bounds.start(),
+            max_port: *bounds.end(),
+            bind_addr: self.bind_addr,
+            bind_tunnels: settings.bind_tunnels[0],
+            auth: auth.into(),
+            config_file: self.config_file.clone(),
+            tls: self.tls.is_some(),
+            hardened: self.hardened,
+            websocket: self.websocket,
+            max_pending: self.max_pending,
+            pending_timeout_ms: self.pending_timeout.as_millis() as u64,
+            heartbeat_interval_ms: self.heartbeat_interval.as_millis() as u64,
+            idle_timeout_ms: self.idle_timeout.map(|timeout| timeout.as_millis() as u64),
+        }
+    }
+
+    /// Serve the admin API and metrics, as enabled.
+    fn serve_endpoints(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        let mut endpoints = Vec::new();
+        if let Some((addr, token)) = self.admin.clone() {
+            let this = Arc::clone(self);
+            endpoints.push(tokio::spawn(async move {
+                if let Err(err) = admin::serve(addr, &token, this).await {
+                    warn!(%err, "admin api exited with error");
+                }
+            }));
+        }
+        if let Some(addr) = self.metrics_addr {
+            let metrics = Arc::clone(&self.metrics);
+            endpoints.push(tokio::spawn(async move {
+                if let Err(err) = metrics::serve(addr, metrics).await {
+                    warn!(%err, "metrics server exited with error");
+                }
+            }));
+        }
+        if let Some((port, router)) = self.http.clone() {
+            let acme = self.https.as_ref().map(|(_, acme)| Arc::clone(acme));
+            let oidc = self.oidc.clone().map(|config| {
+                // Logins finish on the domain itself, over HTTPS if the
+                // server terminates TLS.
+                let base = match self.https {
+                    Some((port, _)) => url("https", router.domain(), port, 443),
+                    None => url("http", router.domain(), port, 80),
+                };
+                Arc::new(Oidc::new(config, format!("{base}{}", oidc::CALLBACK_PATH)))
+            });
+            let mut ports = BTreeMap::from([(port, PortMode::default())]);
+            if let Some((port, _)) = self.https {
+                ports.entry(port).or_default().terminate = true;
+            }
+            if let Some(port) = self.tls_passthrough {
+                ports.entry(port).or_default().passthrough = true;
+            }
+            let addrs = self.settings().bind_tunnels.clone();
+            let v6_only = addrs.iter().any(IpAddr::is_ipv4);
+            for (port, mode) in ports {
+                let listeners: io::Result<Vec<_>> = (addrs.iter())
+                    .map(|addr| bind_tcp(SocketAddr::new(*addr, port), v6_only))
+                    .collect();
+                match listeners {
+                    Ok(listeners) => {
+                        let serve = http::serve(
+                            listeners,
+                            Arc::clone(&router),
+                            acme.clone(),
+                            oidc.clone(),
+                            mode,
+                        );
+                        endpoints.push(tokio::spawn(serve));
+                    }
+                    Err(err) => warn!(%err, port, "could not listen for HTTP tunnels"),
+                }
+            }
+            if let Some(acme) = acme {
+                let oidc = oidc.is_some();
+                endpoints.push(tokio::spawn(async move {
+                    if oidc {
+                        acme.ensure(router.domain()).await;
+                    }
+                    let in_use = |hostname: &str| {
+                        router.serves(hostname) || (oidc && hostname == router.domain())
+                    };
+                    acme.run(in_use).await;
+                }));
+            }
+        }
+        endpoints
+    }
+
+    /// Close inherited listeners of tunnels whose clients do not reconnect
+    /// in time.
+    fn expire_inherited(self: &Arc<Self>) {
+        let unclaimed = self.inherited.lock().unwrap().tunnels.len();
+        if unclaimed == 0 {
+            return;
+        }
+        info!(
+            tunnels = unclaimed,
+            "waiting for clients of inherited tunnels to reconnect"
+        );
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            sleep(handoff::CLAIM_TIMEOUT).await;
+            let unclaimed = std::mem::take(&mut this.inherited.lock().unwrap().tunnels);
+            if !unclaimed.is_empty() {
+                let ports: Vec<_> = unclaimed.keys().collect();
+                info!(
+                    ?ports,
+                    "closed inherited tunnel ports that no client claimed"
+                );
+            }
+        });
+    }
 
-        // Determine authentication mode
-        let auth = if let Some(url) = api_validation_url {
-            AuthMode::ApiKey(ApiKeyAuthenticator::new(url))
-        } else if let Some(secret) = secret {
-            AuthMode::Secret(Authenticator::new(secret))
-        } else {
-            AuthMode::None
+    /// Hand the listening sockets over to a new server, close the tunnels for
+    /// their clients to reconnect to it, and wait for the connections that
+    /// are still being forwarded to finish.
+    ///
+    /// Tunnels close before the new server starts, as it shares the control
+    /// port and would not know the visitors that they still accepted.
+    async fn hand_off(
+        self: &Arc<Self>,
+        control: &TcpListener,
+        endpoints: &mut Vec<JoinHandle<()>>,
+    ) -> Result<()> {
+        let spawn = self.handoff.context("handing over is not enabled")?;
+        info!("handing over to a new server");
+        if let Some(file) = &self.state_file {
+            file.save(self.snapshot())?;
+        }
+        let tunnels: Vec<(u16, Socket)> = (self.tunnels.iter())
+            .filter_map(|entry| Some((*entry.key(), entry.socket.as_ref()?.try_clone().ok()?)))
+            .collect();
+        // The new server binds the addresses of the admin API and metrics.
+        for endpoint in endpoints.drain(..) {
+            endpoint.abort();
+            endpoint.await.ok();
+        }
+        self.handed_off.lock().unwrap().cancel();
+        let started = async {
+            let mut child = handoff::spawn_successor(spawn, control, &tunnels)?;
+            sleep(handoff::STARTUP_TIME).await;
+            if let Some(status) = child.try_wait()? {
+                bail!("new server exited with {status}");
+            }
+            Ok(child)
         };
-
-        Server {
-            port_range,
-            conns: Arc::new(DashMap::new()),
-            auth,
-            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-            bind_tunnels: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        let child = match started.await {
+            Ok(child) => child,
+            Err(err) => {
+                // Serve on, giving clients back their ports as they reconnect.
+                *self.handed_off.lock().unwrap() = CancellationToken::new();
+                let ports = tunnels
+                    .into_iter()
+                    .map(|(port, socket)| (port, socket.into()));
+                self.inherited.lock().unwrap().tunnels.extend(ports);
+                self.expire_inherited();
+                *endpoints = self.serve_endpoints();
+                return Err(err);
+            }
+        };
+        info!(
+            pid = child.id(),
+            tunnels = tunnels.len(),
+            "new server took over"
+        );
+        drop(tunnels);
+        let drained = timeout(handoff::DRAIN_TIMEOUT, async {
+            while self.active.load(Ordering::Relaxed) > 0 {
+                sleep(Duration::from_millis(100)).await;
+            }
+        });
+        if drained.await.is_err() {
+            let connections = self.active.load(Ordering::Relaxed);
+            warn!(
+                connections,
+                "closing connections that did not finish in time"
+            );
         }
+        Ok(())
     }
 
-    /// Set the IP address where tunnels will listen on.
-    pub fn set_bind_addr(&mut self, bind_addr: IpAddr) {
-        self.bind_addr = bind_addr;
+    /// Current state of the countermeasures, reservations, transfer quotas,
+    /// and usage reports that are kept across restarts.
+    fn snapshot(&self) -> ServerState {
+        let bans = self
+            .guard
+            .as_ref()
+            .map(SourceGuard::bans)
+            .unwrap_or_default();
+        let reserved = (self.reservations.as_ref())
+            .map(Reservations::snapshot)
+            .unwrap_or_default();
+        ServerState {
+            handshake_tokens: self.handshake_limiter.as_ref().map(TokenBucket::available),
+            bans: bans
+                .into_iter()
+                .map(|(addr, remaining)| SavedBan {
+                    addr,
+                    until: state::deadline(remaining),
+                })
+                .collect(),
+            reservation_key: self.reservations.as_ref().map(|r| hex::encode(r.key())),
+            reservations: reserved
+                .into_iter()
+                .map(|(port, owner, remaining)| SavedReservation {
+                    port,
+                    owner: hex::encode(owner),
+                    until: state::deadline(remaining),
+                })
+                .collect(),
+            usage_reports: (self.usage.as_ref())
+                .map(|usage| usage.pending())
+                .unwrap_or_default(),
+            transfers: (self.transfers.snapshot().into_iter())
+                .map(|(key, usage)| SavedTransfer {
+                    key,
+                    bytes: usage.bytes,
+                    month: usage.month,
+                })
+                .collect(),
+            ..Default::default()
+        }
     }
 
-    /// Set the IP address where the control server will bind to.
-    pub fn set_bind_tunnels(&mut self, bind_tunnels: IpAddr) {
-        self.bind_tunnels = bind_tunnels;
+    /// Pick up countermeasures, reservations, transfer quotas, and usage
+    /// reports from before a restart.
+    fn restore(&self, state: &ServerState) {
+        if let (Some(limiter), Some(tokens)) = (&self.handshake_limiter, state.handshake_tokens) {
+            limiter.restore(tokens, state.age());
+            debug!(tokens, "restored handshake rate limit");
+        }
+        if let Some(guard) = &self.guard {
+            let bans = state.bans.iter().filter_map(|ban| {
+                state::remaining(ban.until).map(|remaining| (ban.addr, remaining))
+            });
+            for (addr, remaining) in bans {
+                guard.restore_ban(addr, remaining);
+                debug!(%addr, ?remaining, "restored ban");
+            }
+        }
+        let key = state
+            .reservation_key
+            .as_deref()
+            .and_then(|key| hex::decode(key).ok());
+        if let (Some(reservations), Some(key)) = (&self.reservations, key) {
+            let reserved: Vec<_> = (state.reservations.iter())
+                .filter_map(|saved| {
+                    let owner = hex::decode(&saved.owner).ok()?.try_into().ok()?;
+                    Some((saved.port, owner, state::remaining(saved.until)?))
+                })
+                .collect();
+            info!(ports = reserved.len(), "restored port reservations");
+            reservations.restore(key, reserved);
+        }
+        let transfers = state.transfers.iter().map(|saved| {
+            let usage = Usage {
+                bytes: saved.bytes,
+                month: saved.month,
+            };
+            (saved.key.clone(), usage)
+        });
+        self.transfers.restore(transfers.collect());
+        if let Some(usage) = &self.usage {
+            usage.buffer(state.usage_reports.clone());
+        }
     }
 
-    /// Start the server, listening for new connections.
-    pub async fn listen(self) -> Result<()> {
-        let this = Arc::new(self);
-        let listener = TcpListener::bind((this.bind_addr, CONTROL_PORT)).await?;
-        info!(addr = ?this.bind_addr, "server listening");
-
-        loop {
-            let (stream, addr) = listener.accept().await?;
-            let this = Arc::clone(&this);
-            tokio::spawn(
-                async move {
-                    info!("incoming connection");
-                    if let Err(err) = this.handle_connection(stream).await {
-                        warn!(%err, "connection exited with error");
-                    } else {
-                        info!("connection exited");
-                    }
+    /// Wrap a new connection to the control port in TLS, and in WebSocket if
+    /// the client asks for it, as enabled.
+    async fn open_control(&self, stream: TcpStream) -> Result<Delimited<ControlStream>> {
+        let stream = match &self.tls {
+            Some(acceptor) => {
+                if self.require_tls && !tls::starts_handshake(&stream).await {
+                    let mut stream = Delimited::new(ControlStream::Plain(stream));
+                    let message = "server requires TLS, connect with --tls";
+                    // Plain messages, since the client has not said which
+                    // protocol version it speaks.
+                    let err = ServerError::new(ErrorCode::TlsRequired, message);
+                    stream.send(err.into_message(0)).await?;
+                    bail!("refused connection without TLS");
                 }
-                .instrument(info_span!("control", ?addr)),
-            );
+                tls::accept(acceptor, stream).await?
+            }
+            None => ControlStream::Plain(stream),
+        };
+        if !self.websocket {
+            return Ok(Delimited::new(stream));
         }
+        let (stream, read) = websocket::accept(stream).await?;
+        Ok(Delimited::with_read_buf(stream, &read))
     }
 
-    async fn create_listener(&self, port: u16) -> Result<TcpListener, &'static str> {
-        let try_bind = |port: u16| async move {
-            TcpListener::bind((self.bind_tunnels, port))
-                .await
-                .map_err(|err| match err.kind() {
+    async fn create_listener(
+        &self,
+        hello: &ClientHello,
+        ports: PortSet,
+        client: &str,
+        addrs: &[IpAddr],
+        owner: Option<Owner>,
+    ) -> Result<Listener, ServerError> {
+        let (port, udp) = (hello.port, hello.udp);
+        let reserved = match (&self.reservations, &owner) {
+            (Some(reservations), Some(owner)) => reservations.reserved_for(owner),
+            _ => None,
+        };
+        let try_bind = |candidate: u16| async move {
+            if let (Some(reservations), Some(owner)) = (&self.reservations, &owner) {
+                if reservations.is_held_for_other(candidate, owner) {
+                    let message = "port is reserved for another client";
+                    return Err(ServerError::new(ErrorCode::PortUnavailable, message));
+                }
+            }
+            // Parked ports only go to clients that come back for them, not to
+            // those that happen to get them at random.
+            let claims = !udp && (candidate == port || reserved == Some(candidate));
+            let parked = (self.parked).remove_if(&candidate, |_, parked| {
+                claims && parked.owner.is_none_or(|parked| owner == Some(parked))
+            });
+            if let Some((_, parked)) = parked {
+                info!(
+                    port = candidate,
+                    "took over port held for reconnecting client"
+                );
+                return (parked.sockets.into_iter())
+                    .map(|socket| {
+                        let listener = std::net::TcpListener::from(socket);
+                        listener.set_nonblocking(true)?;
+                        TcpListener::from_std(listener)
+                    })
+                    .collect::<io::Result<_>>()
+                    .map(Listener::Tcp)
+                    .map_err(|_| {
+                        ServerError::new(ErrorCode::PortUnavailable, "failed to bind to port")
+                    });
+            }
+            let port = candidate;
+            if let Some(broker) = &self.broker {
+                if udp {
+                    let message = "UDP tunnels are not supported in memory";
+                    return Err(ServerError::new(ErrorCode::Unsupported, message));
+                }
+                return broker.bind(port).map(Listener::Memory).ok_or_else(|| {
+                    ServerError::new(ErrorCode::PortUnavailable, "port already in use")
+                });
+            }
+            let inherited = match udp {
+                true => None,
+                false => self.inherited.lock().unwrap().tunnels.remove(&port),
+            };
+            if let Some(listener) = inherited {
+                info!(port, "took over inherited tunnel port");
+                return (listener.set_nonblocking(true))
+                    .and_then(|()| TcpListener::from_std(listener))
+                    .map(|listener| Listener::Tcp(vec![listener]))
+                    .map_err(|_| {
+                        ServerError::new(ErrorCode::PortUnavailable, "failed to bind to port")
+                    });
+            }
+            let result = if udp {
+                UdpSocket::bind((addrs[0], port))
+                    .await
+                    .map(|socket| Listener::Udp(Relay::new(socket)))
+            } else {
+                let v6_only = addrs.iter().any(IpAddr::is_ipv4);
+                (addrs.iter())
+                    .map(|addr| bind_tcp((*addr, port).into(), v6_only))
+                    .collect::<io::Result<_>>()
+                    .map(Listener::Tcp)
+            };
+            result.map_err(|err| {
+                let message = match err.kind() {
                     io::ErrorKind::AddrInUse => "port already in use",
                     io::ErrorKind::PermissionDenied => "permission denied",
                     _ => "failed to bind to port",
-                })
+                };
+                ServerError::new(ErrorCode::PortUnavailable, message)
+            })
         };
         if port > 0 {
             // Client requests a specific port number.
-            if !self.port_range.contains(&port) {
-                return Err("client port number not in allowed range");
+            if !ports.contains(port) {
+                let message = "client port number not in allowed range";
+                return Err(ServerError::new(ErrorCode::PortOutOfRange, message));
+            }
+            let err = match try_bind(port).await {
+                Err(err) if hello.nearest_port && err.code == ErrorCode::PortUnavailable => err,
+                result => return result,
+            };
+            // Try ports ever farther above and below the requested one, up to
+            // as many as for a random port below.
+            let nearby = (1..=u16::MAX)
+                .flat_map(|distance| [port.checked_add(distance), port.checked_sub(distance)])
+                .flatten()
+                .filter(|port| *port != 0 && ports.contains(*port))
+                .take(150);
+            for port in nearby {
+                if let Ok(listener) = try_bind(port).await {
+                    return Ok(listener);
+                }
             }
-            try_bind(port).await
+            Err(err)
         } else {
             // Client requests any available port in range.
             //
@@ -121,98 +2198,1156 @@ impl Server {
             //
             // Checking 150 times gives us 99.999% success at utilizing 85% of ports under these
             // conditions, when ε=0.15 and δ=0.00001.
-            for _ in 0..150 {
-                let port = fastrand::u16(self.port_range.clone());
+            if ports.is_empty() {
+                let message = "no ports are allowed for this client";
+                return Err(ServerError::new(ErrorCode::PortOutOfRange, message));
+            }
+            if let Some(port) = reserved.filter(|port| ports.contains(*port)) {
+                if let Ok(listener) = try_bind(port).await {
+                    info!(port, "reclaimed reserved port");
+                    return Ok(listener);
+                }
+            }
+            for port in self.allocator.candidates(&ports, client, 150) {
                 match try_bind(port).await {
                     Ok(listener) => return Ok(listener),
                     Err(_) => continue,
                 }
             }
-            Err("failed to find an available port")
+            let message = "failed to find an available port";
+            Err(ServerError::new(ErrorCode::NoPortAvailable, message))
         }
     }
 
-    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
-        let mut stream = Delimited::new(stream);
+    /// Describe an authentication error to the client, according to policy.
+    ///
+    /// Clients learn whether the server has failed them before they say
+    /// which protocol version they speak, so the reason follows an error
+    /// that clients from before [`ServerMessage::AuthFailed`] understand.
+    async fn send_auth_failure(
+        &self,
+        stream: &mut Delimited<ControlStream>,
+        err: &anyhow::Error,
+    ) -> Result<()> {
+        let mut err = match err.downcast_ref::<AuthError>() {
+            Some(err) => err.clone(),
+            None => AuthError::new(AuthErrorCode::Failed, err.to_string()),
+        };
+        if self.redact_auth_errors {
+            err = err.redacted();
+        }
+        stream.send(ServerMessage::Error(err.to_string())).await?;
+        stream.send(ServerMessage::AuthFailed(err)).await
+    }
 
-        // Perform authentication based on mode
-        match &self.auth {
-            AuthMode::Secret(auth) => {
-                if let Err(err) = auth.server_handshake(&mut stream).await {
-                    warn!(%err, "server handshake failed");
-                    stream.send(ServerMessage::Error(err.to_string())).await?;
-                    return Ok(());
+    /// Authenticate a new connection and receive its first message, or tell
+    /// the client why it failed and return `None`.
+    async fn authenticate(
+        &self,
+        settings: &Settings,
+        stream: &mut Delimited<ControlStream>,
+    ) -> Result<Option<(Principal, Option<ClientMessage>)>> {
+        let retired = self.retired_settings();
+        let principal = match settings.auth.provider() {
+            Some(provider) => match auth::server_handshake(
+                stream,
+                provider,
+                &retired_providers(&retired),
+                self.identity.as_deref(),
+                self.sub_keys.as_deref(),
+            )
+            .await
+            {
+                Ok(principal) => principal,
+                Err(err) if self.anonymous_min_port.is_some() && err.is::<MissingCredentials>() => {
+                    // The client goes on without credentials, for a tunnel on a
+                    // random high port.
+                    let Ok(MissingCredentials(message)) = err.downcast() else {
+                        unreachable!("checked above")
+                    };
+                    let principal = Principal {
+                        anonymous: true,
+                        ..Default::default()
+                    };
+                    return Ok(Some((principal, message)));
                 }
-            }
-            AuthMode::ApiKey(auth) => {
-                if let Err(err) = auth.server_handshake(&mut stream).await {
-                    warn!(%err, "API key authentication failed");
-                    stream.send(ServerMessage::Error(err.to_string())).await?;
-                    return Ok(());
+                Err(err) => {
+                    warn!(%err, method = provider.method(), "server handshake failed");
+                    if let (Some(guard), Ok(addr)) = (&self.guard, stream.get_ref().peer_addr()) {
+                        if counts_as_failure(&err) {
+                            guard.record_failure(addr.ip());
+                        }
+                    }
+                    self.send_auth_failure(stream, &err).await?;
+                    return Ok(None);
                 }
-            }
-            AuthMode::None => {
+            },
+            None => {
                 // No authentication required
+                Principal::default()
             }
+        };
+        if let Some(user_id) = &principal.user_id {
+            Span::current().record("user_id", user_id.as_str());
         }
+        if let Some(label) = &principal.secret_label {
+            Span::current().record("secret", label.as_str());
+        }
+        if let Some(claims) = &principal.sub_key {
+            info!(sub_key = %claims.id, "authenticated with sub-key");
+        }
+        let message = ServerIdentity::recv(self.identity.as_deref(), stream).await?;
+        Ok(Some((principal, message)))
+    }
 
-        match stream.recv_timeout().await? {
+    async fn handle_connection(
+        &self,
+        mut stream: Delimited<ControlStream>,
+        handshake: Handshake,
+    ) -> Result<()> {
+        stream.set_max_frame_length(self.max_frame_length);
+        let settings = self.settings();
+        let authenticated = handshake
+            .within(self.authenticate(&settings, &mut stream))
+            .await;
+        let authenticated = report_long_frame(&mut stream, authenticated).await;
+        let Some((principal, message)) = authenticated? else {
+            return Ok(());
+        };
+        drop(handshake);
+        let data = match message {
+            Some(ClientMessage::Accept(id) | ClientMessage::AcceptStripe(id, _)) => Some(id),
+            _ => None,
+        };
+        if let Some(token) = &principal.session_token {
+            // Tokens only accept connections to the tunnel they were issued for.
+            let digest: [u8; 32] = Sha256::digest(token).into();
+            let port = self.session_tokens.get(&digest).map(|port| *port);
+            let pending = data.and_then(|id| self.conns.get(&id).map(|pending| pending.port));
+            if port.is_none() || port != pending {
+                warn!("invalid session token");
+                return Ok(());
+            }
+        }
+        let data = data.is_some();
+        if principal.replaced && !data {
+            // Replaced credentials only serve tunnels opened before a reload.
+            warn!("client authenticated with replaced credentials");
+            let err = AuthError::new(AuthErrorCode::Failed, "credentials are no longer valid");
+            self.send_auth_failure(&mut stream, &err.into()).await?;
+            return Ok(());
+        }
+        match message {
             Some(ClientMessage::Authenticate(_)) => {
                 warn!("unexpected authenticate");
                 Ok(())
             }
+            Some(ClientMessage::Pong(_)) => {
+                warn!("unexpected pong");
+                Ok(())
+            }
+            Some(ClientMessage::Probe(_)) => {
+                warn!("unexpected probe");
+                Ok(())
+            }
+            Some(ClientMessage::SessionToken(_)) => {
+                warn!("unexpected session token");
+                Ok(())
+            }
+            Some(ClientMessage::Identify(_)) => unreachable!("identity requests are answered"),
             Some(ClientMessage::Hello(port)) => {
-                let listener = match self.create_listener(port).await {
-                    Ok(listener) => listener,
-                    Err(err) => {
-                        stream.send(ServerMessage::Error(err.into())).await?;
+                let hello = ClientHello {
+                    port,
+                    ..Default::default()
+                };
+                self.handle_tunnel(stream, hello, principal).await
+            }
+            Some(ClientMessage::HelloExt(hello)) => {
+                self.handle_tunnel(stream, hello, principal).await
+            }
+            Some(ClientMessage::Delegate(request)) => {
+                let reply = match (&self.sub_keys, &principal.sub_key, &settings.auth) {
+                    _ if principal.anonymous => Err(ServerError::new(
+                        ErrorCode::AuthRequired,
+                        "creating sub-keys requires authentication",
+                    )),
+                    (_, _, AuthMode::None) => Err(ServerError::new(
+                        ErrorCode::Unsupported,
+                        "server does not require authentication",
+                    )),
+                    (None, _, _) => Err(ServerError::new(
+                        ErrorCode::Unsupported,
+                        "server does not allow sub-keys",
+                    )),
+                    (_, Some(_), _) => Err(ServerError::new(
+                        ErrorCode::Forbidden,
+                        "sub-keys cannot create other sub-keys",
+                    )),
+                    (Some(issuer), None, _) => match issuer.mint(&request) {
+                        Ok((key, claims)) => {
+                            info!(sub_key = %claims.id, ports = ?claims.ports(), "minted sub-key");
+                            Ok(ServerMessage::Delegated(key))
+                        }
+                        Err(err) => {
+                            Err(ServerError::new(ErrorCode::InvalidRequest, err.to_string()))
+                        }
+                    },
+                };
+                // Delegation requests do not say which protocol version the
+                // client speaks, so errors are plain messages.
+                stream
+                    .send(reply.unwrap_or_else(|err| err.into_message(0)))
+                    .await?;
+                Ok(())
+            }
+            Some(ClientMessage::Accept(id)) => self.accept(id, 0, stream).await,
+            Some(ClientMessage::AcceptStripe(id, index)) => self.accept(id, index, stream).await,
+            Some(ClientMessage::Multiplex) => self.multiplex(stream, principal).await,
+            Some(ClientMessage::Observe(request)) => {
+                self.handle_observer(stream, request, principal).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Send events of an open tunnel to an observer, until either goes away.
+    ///
+    /// Observers must authenticate with full credentials, or with a sub-key
+    /// scoped to observing the tunnel's port. They only receive copies of
+    /// events, and any message that they send ends the connection.
+    async fn handle_observer(
+        &self,
+        mut stream: Delimited<ControlStream>,
+        request: ObserveRequest,
+        principal: Principal,
+    ) -> Result<()> {
+        let port = request.port;
+        let denied = match (&self.settings().auth, &principal.sub_key) {
+            (auth, _) if principal.anonymous || matches!(auth, AuthMode::None) => Some((
+                ErrorCode::AuthRequired,
+                "observing tunnels requires authentication",
+            )),
+            (_, Some(claims)) if claims.scope != Scope::Observe => Some((
+                ErrorCode::Forbidden,
+                "sub-key does not allow observing tunnels",
+            )),
+            (_, Some(claims)) if !claims.ports().contains(&port) => Some((
+                ErrorCode::Forbidden,
+                "sub-key does not allow observing this port",
+            )),
+            _ => None,
+        };
+        // Observe requests do not say which protocol version the client
+        // speaks, so errors are plain messages.
+        if let Some((code, reason)) = denied {
+            let err = ServerError::new(code, reason);
+            stream.send(err.into_message(0)).await?;
+            return Ok(());
+        }
+        let Some(mut events) = self.observers.get(&port).map(|events| events.subscribe()) else {
+            let err = ServerError::new(ErrorCode::TunnelNotFound, "no tunnel is open on this port");
+            stream.send(err.into_message(0)).await?;
+            return Ok(());
+        };
+        info!(port, "observer attached");
+        // The first heartbeat tells the observer that it is attached.
+        stream.send(ServerMessage::Heartbeat).await?;
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(Observation::Sample { .. }) if !request.samples => {}
+                    Ok(event) => stream.send(ServerMessage::Observation(event)).await?,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!(port, missed, "observer is falling behind");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        info!(port, "observed tunnel closed");
                         return Ok(());
                     }
+                },
+                message = stream.recv::<ClientMessage>() => {
+                    if message?.is_some() {
+                        warn!(port, "observers cannot send messages, detaching");
+                    }
+                    return Ok(());
+                }
+                _ = sleep(self.heartbeat_interval) => stream.send(ServerMessage::Heartbeat).await?,
+            }
+        }
+    }
+
+    /// Report an event on a tunnel to its observers, if it has any.
+    fn observe(&self, port: u16, event: Observation) {
+        if let Some(events) = self.observers.get(&port) {
+            // Nobody may be observing, which is not an error.
+            let _ = events.send(event);
+        }
+    }
+
+    /// Accept data connections and tunnels from the client as streams over
+    /// this connection, which share its authentication.
+    async fn multiplex(
+        &self,
+        stream: Delimited<ControlStream>,
+        principal: Principal,
+    ) -> Result<()> {
+        let mut mux = MuxServer::accept(stream.into_parts()).await?;
+        info!("multiplexing data connections");
+        let mut streams = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                stream = mux.next() => match stream {
+                    Some(stream) => {
+                        let principal = principal.clone();
+                        let span = info_span!("stream", tunnel = field::Empty);
+                        streams.push(self.accept_stream(stream?, principal).instrument(span));
+                    }
+                    None => return Ok(()),
+                },
+                Some(result) = streams.next() => {
+                    if let Err(err) = result {
+                        warn!(%err, "stream exited with error");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a stream of a multiplexed connection like a new data connection
+    /// or tunnel, whose client has already authenticated.
+    async fn accept_stream(&self, stream: ControlStream, principal: Principal) -> Result<()> {
+        let mut stream = Delimited::new(stream);
+        stream.set_max_frame_length(self.max_frame_length);
+        let message = stream.recv_timeout().await;
+        match report_long_frame(&mut stream, message).await? {
+            Some(ClientMessage::Accept(id)) => self.accept(id, 0, stream).await,
+            Some(ClientMessage::AcceptStripe(id, index)) => self.accept(id, index, stream).await,
+            Some(ClientMessage::Hello(port)) => {
+                let hello = ClientHello {
+                    port,
+                    ..Default::default()
+                };
+                self.handle_tunnel(stream, hello, principal).await
+            }
+            Some(ClientMessage::HelloExt(hello)) => {
+                self.handle_tunnel(stream, hello, principal).await
+            }
+            Some(_) => {
+                warn!("unexpected message on multiplexed stream");
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Attach a data connection from the client to a pending connection,
+    /// forwarding it once all of its stripes have arrived.
+    async fn accept(&self, id: Uuid, index: u8, stream: Delimited<ControlStream>) -> Result<()> {
+        let complete = match self.conns.get_mut(&id) {
+            Some(mut pending) => {
+                if index >= pending.stripes || pending.arrived.iter().any(|(i, _)| *i == index) {
+                    warn!(%id, index, "unexpected stripe");
+                    self.metrics
+                        .add_unmatched_accept(UnmatchedAccept::Duplicate);
+                    return Ok(());
+                }
+                pending.arrived.push((index, stream));
+                pending.arrived.len() == pending.stripes as usize
+            }
+            None => {
+                let reason = match self.settled.get(&id).map(|settled| *settled) {
+                    Some(Settled::Claimed) => UnmatchedAccept::Duplicate,
+                    Some(Settled::Expired) => UnmatchedAccept::Expired,
+                    None => UnmatchedAccept::Unknown,
                 };
-                let host = listener.local_addr()?.ip();
-                let port = listener.local_addr()?.port();
-                info!(?host, ?port, "new client");
-                stream.send(ServerMessage::Hello(port)).await?;
+                warn!(%id, reason = reason.as_str(), "missing connection");
+                self.metrics.add_unmatched_accept(reason);
+                return Ok(());
+            }
+        };
+        if !complete {
+            return Ok(());
+        }
+        let Some((_, pending)) = self.conns.remove(&id) else {
+            warn!(%id, "missing connection");
+            return Ok(());
+        };
+        pending.queued.fetch_sub(1, Ordering::Relaxed);
+        self.settled.insert(id, Settled::Claimed);
+        self.metrics.accept(pending.since.elapsed());
+        pending.labels.record(&Span::current());
+        info!(%id, stripes = pending.stripes, "forwarding connection");
+        pending.stats.add_connection();
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let result = self.forward(id, pending).await;
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        result
+    }
 
-                loop {
-                    if stream.send(ServerMessage::Heartbeat).await.is_err() {
-                        // Assume that the TCP connection has been dropped.
+    /// Proxy a visitor's connection over the data connections from the client.
+    async fn forward(&self, id: Uuid, mut pending: PendingConnection) -> Result<()> {
+        pending.arrived.sort_by_key(|(index, _)| *index);
+        let mut data = Vec::new();
+        for (_, stream) in pending.arrived {
+            let parts = stream.into_parts();
+            data.push(if pending.encrypted {
+                Encrypted::respond(parts, id).await?
+            } else {
+                Encrypted::plain(parts)
+            });
+        }
+        let (stream, port, peer): (Box<dyn VisitorStream>, _, _) = match pending.visitor {
+            Visitor::Tcp(stream) => {
+                let (port, peer) = (stream.local_addr()?.port(), stream.peer_addr()?);
+                let peer = canonical_addr(peer);
+                (Box::new(stream), port, peer)
+            }
+            Visitor::Memory(stream, port) => (Box::new(stream), port, MEMORY_ADDR),
+            Visitor::Routed(stream, port, peer) => (Box::new(stream), port, peer),
+            Visitor::Udp(session) => {
+                // UDP tunnels are never striped, and datagrams are not hashed.
+                let data = data.into_iter().next().expect("at least one stripe");
+                tokio::select! {
+                    result = session.relay(data) => result?,
+                    _ = pending.exhausted.cancelled() => {}
+                }
+                return Ok(());
+            }
+        };
+        let hashed = pending.checksums.is_some() || self.transcript.is_some();
+        let stats = Arc::clone(&pending.stats);
+        let stream = Limited::new(Metered::remote(stream, pending.stats), pending.bandwidth);
+        let mut visitor = Checksummed::new(stream, hashed);
+        let start = Instant::now();
+        let result = if let Some(bytes) = self.sampler.sample(port) {
+            let mut tap = Tap::new(&mut visitor, bytes);
+            let result = cut_off(&pending.exhausted, striping::splice(&mut tap, data)).await;
+            let (inbound, outbound) = (tap.read_hex(), tap.written_hex());
+            info!(
+                target: "bore::access",
+                %id,
+                port,
+                inbound,
+                outbound,
+                "sampled connection"
+            );
+            let truncate = |hex: &str| hex[..hex.len().min(2 * MAX_OBSERVED_SAMPLE)].to_string();
+            let sample = Observation::Sample {
+                id,
+                inbound: truncate(&inbound),
+                outbound: truncate(&outbound),
+            };
+            self.observe(port, sample);
+            result
+        } else {
+            cut_off(&pending.exhausted, striping::splice(&mut visitor, data)).await
+        };
+        stats.add_duration(start.elapsed());
+        let duration_ms = start.elapsed().as_millis() as u64;
+        let reason = match &result {
+            Err(_) => Reason::Error,
+            Ok(()) if pending.exhausted.is_cancelled() => Reason::QuotaExceeded,
+            Ok(()) => Reason::Closed,
+        };
+        let metered = visitor.get_ref().get_ref();
+        let entry = AccessEntry {
+            id: Some(id),
+            bytes_in: metered.read_bytes(),
+            bytes_out: metered.written_bytes(),
+            duration_ms,
+            country: pending.country.clone(),
+            ..pending.labels.access(peer, port, reason)
+        };
+        log_access(self.access_log.as_deref(), entry);
+        self.observe(port, Observation::Closed { id, duration_ms });
+        if let (Some(transcript), Some(inbound), Some(outbound)) = (
+            &self.transcript,
+            visitor.read_digest(),
+            visitor.written_digest(),
+        ) {
+            let entry = TranscriptEntry {
+                seq: 0,
+                time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                id,
+                port,
+                peer,
+                duration_ms,
+                inbound,
+                outbound,
+                prev: String::new(),
+                hash: String::new(),
+            };
+            if let Err(err) = transcript.record(entry) {
+                warn!(%err, %id, "could not record connection in transcript");
+            }
+        }
+        if let (Some(checksums), Some(sent), Some(received)) = (
+            pending.checksums,
+            visitor.read_digest(),
+            visitor.written_digest(),
+        ) {
+            // The tunnel may already be closed, in which case nobody is listening.
+            let _ = checksums.send(StreamChecksum { id, sent, received });
+        }
+        result?;
+        Ok(())
+    }
+
+    async fn handle_tunnel(
+        &self,
+        mut stream: Delimited<ControlStream>,
+        hello: ClientHello,
+        principal: Principal,
+    ) -> Result<()> {
+        // Clients without credentials are told apart by their address, so
+        // that strangers cannot take over their tunnels or reserved ports.
+        let client = match credential(&principal) {
+            credential if credential.is_empty() => stream.get_ref().peer_addr()?.ip().to_string(),
+            credential => credential,
+        };
+        let owner = (self.reservations.as_ref())
+            .map(|reservations| reservations.owner(&client, hello.name.as_deref()));
+        let client = match &hello.name {
+            Some(name) => format!("{client}/{name}"),
+            None => client,
+        };
+        let settings = self.settings();
+        let credential_ports = settings.credential_ports(&principal, settings.ports.clone());
+        let Principal {
+            user_id,
+            sub_key,
+            quota,
+            api_key_digest,
+            anonymous,
+            ..
+        } = principal;
+        if let Some(name) = &hello.name {
+            if let Err(err) = check_tunnel_name(name) {
+                let err = ServerError::new(ErrorCode::InvalidRequest, err.to_string());
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+        }
+        if hello.allow_ips.len() + hello.deny_ips.len() > acl::MAX_RULES {
+            let message = format!("at most {} address rules are allowed", acl::MAX_RULES);
+            let err = ServerError::new(ErrorCode::InvalidRequest, message);
+            stream.send(err.into_message(hello.version)).await?;
+            return Ok(());
+        }
+        let kind = match (hello.http, hello.tls_passthrough) {
+            (false, false) => None,
+            (true, false) => Some(RouteKind::Http),
+            (false, true) => Some(RouteKind::Tls),
+            (true, true) => {
+                let message = "a tunnel cannot be routed as both HTTP and TLS";
+                let err = ServerError::new(ErrorCode::InvalidRequest, message);
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+        };
+        if !hello.oidc_allow.is_empty() {
+            let err = if kind != Some(RouteKind::Http) {
+                let message = "only HTTP tunnels can require visitors to log in";
+                Some(ServerError::new(ErrorCode::InvalidRequest, message))
+            } else if self.oidc.is_none() {
+                let message = "server does not log visitors in";
+                Some(ServerError::new(ErrorCode::Unsupported, message))
+            } else if hello.oidc_allow.len() > oidc::MAX_RULES {
+                let message = format!("at most {} login rules are allowed", oidc::MAX_RULES);
+                Some(ServerError::new(ErrorCode::InvalidRequest, message))
+            } else {
+                (hello.oidc_allow.iter())
+                    .find_map(|rule| oidc::check_rule(rule).err())
+                    .map(|err| ServerError::new(ErrorCode::InvalidRequest, err.to_string()))
+            };
+            if let Some(err) = err {
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+        }
+        let basic_auth = match &hello.basic_auth {
+            None => None,
+            Some(_) if kind != Some(RouteKind::Http) => {
+                let message = "only HTTP tunnels can require basic authentication";
+                let err = ServerError::new(ErrorCode::InvalidRequest, message);
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+            Some(digest) => match hex::decode(digest).ok().and_then(|d| d.try_into().ok()) {
+                Some(digest) => Some(digest),
+                None => {
+                    let message = "invalid digest of basic authentication credentials";
+                    let err = ServerError::new(ErrorCode::InvalidRequest, message);
+                    stream.send(err.into_message(hello.version)).await?;
+                    return Ok(());
+                }
+            },
+        };
+        let access = Access {
+            basic_auth,
+            oidc: hello.oidc_allow.clone(),
+        };
+        // TLS tunnels share the hostnames of HTTP tunnels.
+        let router = (self.http.as_ref())
+            .filter(|_| kind != Some(RouteKind::Tls) || self.tls_passthrough.is_some());
+        let mut route = match (router, kind) {
+            (_, None) => None,
+            (None, Some(kind)) => {
+                let message = format!("server does not route {kind} tunnels");
+                let err = ServerError::new(ErrorCode::Unsupported, message);
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+            (Some(_), Some(kind)) if hello.udp => {
+                let message = format!("{kind} tunnels cannot forward UDP");
+                let err = ServerError::new(ErrorCode::InvalidRequest, message);
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+            (Some((_, router)), Some(kind)) => {
+                // Tunnels are named after their subdomain, unless their name
+                // is not a valid one.
+                let name = (hello.name.as_deref())
+                    .filter(|name| check_subdomain(&name.to_ascii_lowercase()).is_ok());
+                match router.register(
+                    hello.subdomain.as_deref().or(name),
+                    hello.name.as_deref(),
+                    kind,
+                    access,
+                ) {
+                    Ok(route) => {
+                        if let (Some((_, acme)), RouteKind::Http) = (&self.https, kind) {
+                            let (acme, hostname) = (Arc::clone(acme), route.hostname().to_string());
+                            tokio::spawn(async move { acme.ensure(&hostname).await });
+                        }
+                        Some(route)
+                    }
+                    Err(err) => {
+                        let code = match err {
+                            RouteError::Invalid(_) => ErrorCode::InvalidRequest,
+                            RouteError::Taken(_) => ErrorCode::HostnameTaken,
+                        };
+                        let err = ServerError::new(code, err.to_string());
+                        stream.send(err.into_message(hello.version)).await?;
                         return Ok(());
                     }
-                    const TIMEOUT: Duration = Duration::from_millis(500);
-                    if let Ok(result) = timeout(TIMEOUT, listener.accept()).await {
-                        let (stream2, addr) = result?;
-                        info!(?addr, ?port, "new connection");
+                }
+            }
+        };
+        let addrs = match &hello.listener_class {
+            Some(class) => match settings.listener_classes.get(class) {
+                Some(addrs) => addrs,
+                None => {
+                    let message = format!("unknown listener class {class:?}");
+                    let err = ServerError::new(ErrorCode::InvalidRequest, message);
+                    stream.send(err.into_message(hello.version)).await?;
+                    return Ok(());
+                }
+            },
+            None => &settings.bind_tunnels,
+        };
+        let access_list = AccessList::new(hello.allow_ips.clone(), hello.deny_ips.clone());
+        let labels = TunnelLabels {
+            user_id,
+            name: hello.name.clone(),
+        };
+        labels.record(&Span::current());
+        if sub_key
+            .as_ref()
+            .is_some_and(|claims| claims.scope != Scope::Tunnel)
+        {
+            let err = ServerError::new(
+                ErrorCode::Forbidden,
+                "sub-key only allows observing tunnels",
+            );
+            stream.send(err.into_message(hello.version)).await?;
+            return Ok(());
+        }
+
+        let ports = match &sub_key {
+            Some(claims) => credential_ports.within(&(claims.min_port..=claims.max_port)),
+            None => credential_ports,
+        };
+        let ports = ports.within(&quota.port_range(0..=u16::MAX));
+        let ports = match self.hardened {
+            true => ports.within(&(PRIVILEGED_PORTS..=u16::MAX)),
+            false => ports,
+        };
+        let ports = match self.anonymous_min_port.filter(|_| anonymous) {
+            Some(min_port) if hello.port != 0 => {
+                let message =
+                    format!("only random ports from {min_port} up are open without credentials");
+                let err = ServerError::new(ErrorCode::AuthRequired, message);
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+            Some(min_port) => ports.within(&(min_port..=u16::MAX)),
+            None => ports,
+        };
+        let _slot = match (&labels.user_id, quota.max_tunnels) {
+            (Some(user_id), Some(max)) => match TunnelSlot::claim(&self.user_tunnels, user_id, max)
+            {
+                Some(slot) => Some(slot),
+                None => {
+                    warn!(max, "user has too many tunnels open");
+                    let message = format!("tunnel quota exceeded, at most {max} may be open");
+                    let err = ServerError::new(ErrorCode::QuotaExceeded, message);
+                    stream.send(err.into_message(hello.version)).await?;
+                    return Ok(());
+                }
+            },
+            _ => None,
+        };
+        let anonymous = anonymous || matches!(settings.auth, AuthMode::None);
+        let client_ip = stream
+            .get_ref()
+            .peer_addr()?
+            .ip()
+            .to_canonical()
+            .to_string();
+        let _anonymous_slot = match self.max_anonymous_tunnels.filter(|_| anonymous) {
+            Some(max) => match TunnelSlot::claim(&self.anonymous_tunnels, &client_ip, max) {
+                Some(slot) => Some(slot),
+                None => {
+                    warn!(max, "address has too many anonymous tunnels open");
+                    let message = format!(
+                        "too many anonymous tunnels, at most {max} may be open per address"
+                    );
+                    let err = ServerError::new(ErrorCode::QuotaExceeded, message);
+                    stream.send(err.into_message(hello.version)).await?;
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        let expires = (self.anonymous_tunnel_ttl)
+            .filter(|_| anonymous)
+            .map(|ttl| Instant::now() + ttl);
+        let transfer = (labels.user_id.as_ref()).zip(quota.max_transfer_bytes);
+        let transfer_key =
+            transfer.map(|(user_id, _)| TransferLedger::key(user_id, hello.name.as_deref()));
+        if let (Some((_, max)), Some(key)) = (transfer, &transfer_key) {
+            if self.transfers.used(key, quota.transfer_period) >= max {
+                warn!(max, "tunnel has used up its transfer quota");
+                let err = ServerError::new(ErrorCode::QuotaExceeded, "transfer quota exceeded");
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+        }
+        let mut listener = match self
+            .create_listener(&hello, ports, &client, addrs, owner)
+            .await
+        {
+            Ok(listener) => listener,
+            Err(err) => {
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+        };
+        let host = listener.local_addr()?.ip();
+        let port = listener.local_addr()?.port();
+        self.allocator.allocated(port);
+        let _hold = (self.reservations.as_ref())
+            .zip(owner)
+            .map(|(reservations, owner)| reservations.hold(port, owner));
+        let observed = ObservedTunnel::new(&self.observers, port);
+        let stats = Arc::new(TunnelStats::default());
+        let controls = Arc::new(TunnelControls::default());
+        controls.bandwidth.set_limit(quota.max_bytes_per_second);
+        if let (Some(usage), Some(digest)) = (&self.usage, &api_key_digest) {
+            usage.track(digest, labels.user_id.as_deref(), Arc::clone(&stats));
+        }
+        let entry = TunnelEntry {
+            client_addr: stream.get_ref().peer_addr()?,
+            labels: labels.clone(),
+            stats: Arc::clone(&stats),
+            group: None,
+            controls: Arc::clone(&controls),
+            socket: self.handoff.and_then(|_| listener.duplicate()),
+        };
+        self.tunnels.insert(port, entry);
+        (self.metrics).add_tunnel(port, labels.user_id.as_deref(), Arc::clone(&stats));
+        let _registered = RegisteredTunnel {
+            tunnels: &self.tunnels,
+            metrics: &self.metrics,
+            allocator: self.allocator.as_ref(),
+            port,
+            controls: Arc::clone(&controls),
+            stats: Arc::clone(&stats),
+        };
+        let _park = self.reconnect_grace.map(|grace| ParkOnClose {
+            parked: &self.parked,
+            port,
+            sockets: listener.duplicate_all(),
+            owner,
+            grace,
+            handed_off: self.handed_off.lock().unwrap().clone(),
+        });
+        let stripes = match hello.udp {
+            true => 1,
+            false => hello.stripes.clamp(1, MAX_STRIPES),
+        };
+        let udp = hello.udp;
+        let checksums = hello.checksums && !udp;
+        let session_token = (hello.session_token && !matches!(settings.auth, AuthMode::None))
+            .then(|| SessionToken::issue(&self.session_tokens, port));
+        info!(
+            ?host,
+            ?port,
+            version = hello.version,
+            stripes,
+            udp,
+            hostname = route.as_ref().map(Route::hostname),
+            "new client"
+        );
+        // Beat at least three times within the client's liveness threshold,
+        // so that a single late heartbeat does not make it give up.
+        let client_limit = hello
+            .heartbeat_timeout_ms
+            .map(|ms| (Duration::from_millis(ms) / 3).max(MIN_HEARTBEAT_INTERVAL));
+        let mut heartbeat = hello.adaptive_heartbeat.then(|| {
+            let heartbeat = Heartbeat::new(Instant::now());
+            match client_limit {
+                Some(limit) => heartbeat.with_max_interval(limit),
+                None => heartbeat,
+            }
+        });
+        let heartbeat_interval = match (&heartbeat, client_limit) {
+            (Some(_), limit) => limit
+                .unwrap_or(MAX_INTERVAL)
+                .clamp(MIN_INTERVAL, MAX_INTERVAL),
+            (None, Some(limit)) => self.heartbeat_interval.min(limit),
+            (None, None) => self.heartbeat_interval,
+        };
+        if hello.version == 0 {
+            stream.send(ServerMessage::Hello(port)).await?;
+        } else {
+            let http_route = (route.as_ref()).filter(|route| route.kind() == RouteKind::Http);
+            let reply = ServerHello {
+                port,
+                version: PROTOCOL_VERSION.min(hello.version),
+                compression: hello.compression,
+                checksums,
+                stripes,
+                encryption: hello.encryption,
+                udp,
+                multiplex: hello.multiplex,
+                adaptive_heartbeat: hello.adaptive_heartbeat,
+                connection_info: hello.connection_info,
+                heartbeat_interval_ms: Some(heartbeat_interval.as_millis() as u64),
+                session_token: session_token.as_ref().map(|token| token.value.clone()),
+                access_list: true,
+                challenged: anonymous && settings.auth.provider().is_some(),
+                hostname: route.as_ref().map(|route| route.hostname().to_string()),
+                http_url: (http_route.zip(self.http.as_ref()))
+                    .map(|(route, (port, _))| url("http", route.hostname(), *port, 80)),
+                https_url: (http_route.zip(self.https.as_ref()))
+                    .map(|(route, (port, _))| url("https", route.hostname(), *port, 443)),
+                tls_addr: (route
+                    .as_ref()
+                    .filter(|route| route.kind() == RouteKind::Tls))
+                .zip(self.tls_passthrough)
+                .map(|(route, port)| format!("{}:{port}", route.hostname())),
+                basic_auth: basic_auth.is_some(),
+                oidc: !hello.oidc_allow.is_empty(),
+                probes: !udp,
+            };
+            stream.send(ServerMessage::HelloExt(reply)).await?;
+            stream.set_compression(hello.compression);
+        }
+        if let Some(program) = self.on_tunnel_open.clone() {
+            let mut envs = vec![
+                ("BORE_EVENT", "tunnel_open".to_string()),
+                ("BORE_PORT", port.to_string()),
+                ("BORE_BIND_ADDR", host.to_string()),
+                (
+                    "BORE_CLIENT_ADDR",
+                    stream.get_ref().peer_addr()?.to_string(),
+                ),
+            ];
+            if let Some(claims) = &sub_key {
+                envs.push(("BORE_SUB_KEY", claims.id.to_string()));
+            }
+            if let Some(user_id) = &labels.user_id {
+                envs.push(("BORE_USER_ID", user_id.clone()));
+            }
+            if let Some(name) = &labels.name {
+                envs.push(("BORE_TUNNEL_NAME", name.clone()));
+            }
+            tokio::spawn(
+                async move {
+                    match process::run_hook(&program, &envs).await {
+                        Ok(status) if status.success() => debug!("tunnel open hook succeeded"),
+                        Ok(status) => warn!(%status, "tunnel open hook failed"),
+                        Err(err) => warn!(%err, "could not run tunnel open hook"),
+                    }
+                }
+                .in_current_span(),
+            );
+        }
 
-                        let id = Uuid::new_v4();
-                        let conns = Arc::clone(&self.conns);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let _abandon = AbandonOnClose {
+            conns: &self.conns,
+            metrics: &self.metrics,
+            queued: Arc::clone(&queued),
+        };
+        let (checksum_tx, mut checksum_rx) = mpsc::unbounded_channel();
+        let checksum_tx = checksums.then_some(checksum_tx);
+        let mut next_heartbeat = Instant::now();
+        let handed_off = self.handed_off.lock().unwrap().clone();
+        let mut counted = 0;
+        let mut traffic = (0, 0);
+        let mut last_traffic = Instant::now();
+        // Visitors of a throttled tunnel wait until then, while the control
+        // connection carries on.
+        let mut resume = Instant::now();
+        // Visit of the client's own, announced until then, to check that the
+        // tunnel is reachable.
+        let probes = hello.version != 0 && !udp;
+        let client_ip = stream.get_ref().peer_addr()?.ip();
+        let mut probe: Option<(Uuid, Instant)> = None;
 
-                        conns.insert(id, stream2);
-                        tokio::spawn(async move {
-                            // Remove stale entries to avoid memory leaks.
-                            sleep(Duration::from_secs(10)).await;
-                            if conns.remove(&id).is_some() {
-                                warn!(%id, "removed stale connection");
-                            }
-                        });
-                        stream.send(ServerMessage::Connection(id)).await?;
+        loop {
+            while let Ok(checksum) = checksum_rx.try_recv() {
+                stream.send(ServerMessage::Checksum(checksum)).await?;
+            }
+            let sent = match &mut heartbeat {
+                Some(heartbeat) => match heartbeat.poll(Instant::now()) {
+                    Beat::Idle => true,
+                    Beat::Ping(seq) => stream.send(ServerMessage::Ping(seq)).await.is_ok(),
+                    Beat::Dead => {
+                        info!(?port, rtt = ?heartbeat.rtt(), "client stopped answering pings, closing tunnel");
+                        return Ok(());
                     }
+                },
+                None if Instant::now() < next_heartbeat => true,
+                None => {
+                    next_heartbeat = Instant::now() + heartbeat_interval;
+                    stream.send(ServerMessage::Heartbeat).await.is_ok()
                 }
+            };
+            if !sent {
+                // Assume that the TCP connection has been dropped.
+                return Ok(());
             }
-            Some(ClientMessage::Accept(id)) => {
-                info!(%id, "forwarding connection");
-                match self.conns.remove(&id) {
-                    Some((_, mut stream2)) => {
-                        let mut parts = stream.into_parts();
-                        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-                        stream2.write_all(&parts.read_buf).await?;
-                        tokio::io::copy_bidirectional(&mut parts.io, &mut stream2).await?;
+            if sub_key
+                .as_ref()
+                .is_some_and(|claims| claims.remaining().is_zero())
+            {
+                info!(?port, "sub-key expired, closing tunnel");
+                let err = ServerError::new(ErrorCode::Expired, "sub-key has expired");
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+            if expires.is_some_and(|expires| Instant::now() >= expires) {
+                info!(?port, "anonymous tunnel expired, closing it");
+                let err = ServerError::new(ErrorCode::Expired, "anonymous tunnel expired");
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+            if let (Some((_, max)), Some(key)) = (transfer, &transfer_key) {
+                let total = stats.inbound() + stats.outbound();
+                let used = (self.transfers).add(key, total - counted, quota.transfer_period);
+                counted = total;
+                if used >= max {
+                    info!(?port, used, max, "transfer quota exceeded, closing tunnel");
+                    controls.exhausted.cancel();
+                    let err = ServerError::new(ErrorCode::QuotaExceeded, "transfer quota exceeded");
+                    stream.send(err.into_message(hello.version)).await?;
+                    return Ok(());
+                }
+            }
+            let seen = (stats.inbound() + stats.outbound(), stats.connections());
+            if seen != traffic || queued.load(Ordering::Relaxed) > 0 {
+                (traffic, last_traffic) = (seen, Instant::now());
+            }
+            if let Some(idle) = self
+                .idle_timeout
+                .filter(|idle| last_traffic.elapsed() >= *idle)
+            {
+                info!(?port, ?idle, "tunnel is idle, closing it");
+                let message = "tunnel closed after sitting idle";
+                let err = ServerError::new(ErrorCode::IdleTimeout, message);
+                stream.send(err.into_message(hello.version)).await?;
+                return Ok(());
+            }
+            const TIMEOUT: Duration = Duration::from_millis(500);
+            let throttled = resume.saturating_duration_since(Instant::now());
+            let mut tick = TIMEOUT.min(heartbeat_interval);
+            if !throttled.is_zero() {
+                tick = tick.min(throttled);
+            }
+            let accepting = !controls.paused.load(Ordering::Relaxed) && throttled.is_zero();
+            let accepted = tokio::select! {
+                result = listener.accept(), if accepting => Some(result),
+                Some((stream, addr)) = accept_routed(&mut route), if accepting => {
+                    Some(Ok((Visitor::Routed(stream, port, addr), addr)))
+                }
+                message = stream.recv(), if heartbeat.is_some() || probes => {
+                    match message? {
+                        Some(ClientMessage::Pong(seq)) => {
+                            if let Some(heartbeat) = &mut heartbeat {
+                                heartbeat.ack(seq, Instant::now());
+                            }
+                        }
+                        Some(ClientMessage::Probe(token)) if probes => {
+                            probe = Some((token, Instant::now() + NETWORK_TIMEOUT));
+                            stream.send(ServerMessage::Probe(token)).await?;
+                        }
+                        Some(_) => warn!("unexpected message on control connection"),
+                        None => return Ok(()),
                     }
-                    None => warn!(%id, "missing connection"),
+                    None
+                }
+                _ = handed_off.cancelled() => {
+                    // The client reconnects to the new server.
+                    info!(?port, "closing tunnel for the new server to take over");
+                    return Ok(());
+                }
+                _ = controls.close.notified() => {
+                    info!(?port, "closing tunnel on admin request");
+                    let message = "tunnel closed by the server operator";
+                    let err = ServerError::new(ErrorCode::ClosedByOperator, message);
+                    stream.send(err.into_message(hello.version)).await?;
+                    return Ok(());
+                }
+                _ = sleep(tick) => None,
+            };
+            if let Some(result) = accepted {
+                let (visitor, addr) = result?;
+                // The client's own visit is answered here, so that it
+                // reaches neither the local service nor the logs.
+                if let Some((token, deadline)) = probe {
+                    if canonical_addr(addr).ip() == client_ip && Instant::now() < deadline {
+                        probe = None;
+                        debug!(?addr, ?port, "answering reachability probe");
+                        tokio::spawn(answer_probe(visitor, token));
+                        continue;
+                    }
+                }
+                let country = (self.geoip.as_ref()).and_then(|(geoip, _)| geoip.country(addr.ip()));
+                info!(?addr, ?port, country = country.as_deref(), "new connection");
+                let access = |reason| AccessEntry {
+                    country: country.clone(),
+                    ..labels.access(addr, port, reason)
+                };
+                let denied = || access(Reason::Denied);
+                if !self.access_list.permits(addr.ip()) || !access_list.permits(addr.ip()) {
+                    info!(?addr, ?port, "connection from disallowed address");
+                    log_access(self.access_log.as_deref(), denied());
+                    continue;
+                }
+                if let Some((_, rules)) = &self.geoip {
+                    let admitted = rules.permits(country.as_deref());
+                    self.metrics.add_visitor(country.as_deref(), admitted);
+                    if !admitted {
+                        info!(?addr, ?port, country, "connection from disallowed country");
+                        log_access(self.access_log.as_deref(), denied());
+                        continue;
+                    }
+                }
+
+                let mut throttle = None;
+                if let Some(policy) = &self.policy {
+                    let admission = Admission {
+                        peer_ip: addr.ip(),
+                        port,
+                        connections: self.active.load(Ordering::Relaxed),
+                    };
+                    match policy.evaluate(&admission) {
+                        Ok(Decision::Accept) => {}
+                        Ok(Decision::Reject) => {
+                            info!(?addr, ?port, "connection rejected by policy");
+                            log_access(self.access_log.as_deref(), denied());
+                            continue;
+                        }
+                        Ok(Decision::Throttle(delay)) => throttle = Some(delay),
+                        Err(err) => {
+                            warn!(%err, ?addr, ?port, "rejecting connection after policy error");
+                            log_access(self.access_log.as_deref(), denied());
+                            continue;
+                        }
+                    }
+                }
+
+                let id = Uuid::new_v4();
+                if queued.load(Ordering::Relaxed) >= self.max_pending {
+                    let reason = CloseReason::QueueFull;
+                    warn!(%id, ?addr, ?port, reason = reason.as_str(), "dropped connection");
+                    self.metrics.drop_pending(reason);
+                    let _ = observed.events.send(Observation::Dropped { id, reason });
+                    let entry = AccessEntry {
+                        id: Some(id),
+                        ..access(reason.into())
+                    };
+                    log_access(self.access_log.as_deref(), entry);
+                    continue;
+                }
+
+                let pending = PendingConnection {
+                    visitor,
+                    port,
+                    checksums: checksum_tx.clone(),
+                    stripes,
+                    encrypted: hello.encryption,
+                    arrived: Vec::new(),
+                    country,
+                    labels: labels.clone(),
+                    since: Instant::now(),
+                    queued: Arc::clone(&queued),
+                    stats: Arc::clone(&stats),
+                    bandwidth: Arc::clone(&controls.bandwidth),
+                    exhausted: controls.exhausted.clone(),
+                };
+                queued.fetch_add(1, Ordering::Relaxed);
+                self.metrics.enqueue();
+                self.conns.insert(id, pending);
+                let conns = Arc::clone(&self.conns);
+                let settled = Arc::clone(&self.settled);
+                let metrics = Arc::clone(&self.metrics);
+                let events = observed.events.clone();
+                let timeout = self.pending_timeout;
+                let access_log = self.access_log.clone();
+                tokio::spawn(async move {
+                    sleep(timeout).await;
+                    if let Some((_, pending)) = conns.remove(&id) {
+                        settled.insert(id, Settled::Expired);
+                        let reason = CloseReason::AcceptTimeout;
+                        let waited = pending.since.elapsed();
+                        warn!(%id, ?waited, reason = reason.as_str(), "dropped connection");
+                        pending.queued.fetch_sub(1, Ordering::Relaxed);
+                        metrics.drop_pending(reason);
+                        let _ = events.send(Observation::Dropped { id, reason });
+                        let entry = AccessEntry {
+                            id: Some(id),
+                            duration_ms: waited.as_millis() as u64,
+                            country: pending.country,
+                            ..pending.labels.access(addr, port, reason.into())
+                        };
+                        log_access(access_log.as_deref(), entry);
+                    }
+                    // Late data connections are told apart for as long again.
+                    sleep(timeout).await;
+                    settled.remove(&id);
+                });
+                let message = match hello.connection_info {
+                    true => ServerMessage::ConnectionExt(ConnectionInfo {
+                        id,
+                        peer: addr,
+                        port: Some(port),
+                    }),
+                    false => ServerMessage::Connection(id),
+                };
+                stream.send(message).await?;
+                let _ = observed
+                    .events
+                    .send(Observation::Connection { id, peer: addr });
+                if let Some(delay) = throttle {
+                    debug!(?delay, ?port, "throttling tunnel by policy");
+                    resume = Instant::now() + delay;
                 }
-                Ok(())
             }
-            None => Ok(()),
         }
     }
 }
@@ -0,0 +1,503 @@
+//! Running bore persistently as a Windows service or a launchd agent.
+//!
+//! `bore service install` registers a bore command with the platform's
+//! service manager, which starts it at login or boot and restarts it if it
+//! fails. Logs are written to files in the platform's usual location for
+//! services: `~/Library/Logs/bore` on macOS and `%ProgramData%\bore\logs` on
+//! Windows. Logging to the Windows Event Log or the macOS unified log is out of
+//! scope, so only the service manager records there that the service started,
+//! stopped, or failed.
+//!
+//! Service definitions are stored where other users can read them, so secrets
+//! and API keys in the command are moved to files that only the service's
+//! account can read, and the service reads them with `--secret-file` and
+//! `--api-key-file` instead.
+
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Directory holding the logs of installed services.
+#[cfg_attr(not(any(target_os = "macos", windows)), allow(dead_code))]
+fn log_dir() -> Result<PathBuf> {
+    #[cfg(windows)]
+    let dir = PathBuf::from(std::env::var_os("ProgramData").context("%ProgramData% is not set")?)
+        .join("bore")
+        .join("logs");
+    #[cfg(not(windows))]
+    let dir = PathBuf::from(std::env::var_os("HOME").context("$HOME is not set")?)
+        .join("Library")
+        .join("Logs")
+        .join("bore");
+    Ok(dir)
+}
+
+/// Directory holding the credentials of installed services.
+#[cfg_attr(not(any(target_os = "macos", windows)), allow(dead_code))]
+fn credential_dir() -> Result<PathBuf> {
+    #[cfg(windows)]
+    let dir = PathBuf::from(std::env::var_os("ProgramData").context("%ProgramData% is not set")?)
+        .join("bore")
+        .join("credentials");
+    #[cfg(not(windows))]
+    let dir = PathBuf::from(std::env::var_os("HOME").context("$HOME is not set")?)
+        .join("Library")
+        .join("Application Support")
+        .join("bore")
+        .join("credentials");
+    Ok(dir)
+}
+
+/// Options that give a credential on the command line, with the options that
+/// read it from a file instead, and the kind of credential.
+const CREDENTIAL_OPTIONS: [(&str, Option<&str>, &str, &str); 2] = [
+    ("--secret", Some("-s"), "--secret-file", "secret"),
+    ("--api-key", None, "--api-key-file", "api-key"),
+];
+
+/// How the service manager starts a bore command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceDefinition {
+    /// Name that identifies the service.
+    pub name: String,
+
+    /// Path of the bore executable.
+    pub program: PathBuf,
+
+    /// Arguments that the service manager passes to bore.
+    pub arguments: Vec<String>,
+
+    /// Directory holding the logs of the service.
+    pub log_dir: PathBuf,
+
+    /// Credentials taken out of the arguments, with the files that the
+    /// service reads them from instead.
+    pub credentials: Vec<(PathBuf, String)>,
+}
+
+impl ServiceDefinition {
+    /// Define a service that runs a bore command, given as its arguments,
+    /// and logs to a directory. Credentials in the arguments are moved to
+    /// files in `credential_dir`.
+    ///
+    /// ```
+    /// use bore_cli::service::ServiceDefinition;
+    ///
+    /// let args = ["local", "8000", "--secret", "hunter2"].map(String::from);
+    /// let service = ServiceDefinition::new(
+    ///     "web",
+    ///     "/usr/bin/bore".into(),
+    ///     "/logs".into(),
+    ///     "/credentials".into(),
+    ///     &args,
+    /// );
+    /// assert_eq!(service.arguments[0], "--log-file");
+    /// assert_eq!(service.arguments[2..7], ["service", "run", "--name", "web", "--"]);
+    /// assert_eq!(
+    ///     service.arguments[7..],
+    ///     ["local", "8000", "--secret-file", "/credentials/web.secret"],
+    /// );
+    /// assert_eq!(
+    ///     service.credentials,
+    ///     [("/credentials/web.secret".into(), "hunter2".into())],
+    /// );
+    /// ```
+    pub fn new(
+        name: &str,
+        program: PathBuf,
+        log_dir: PathBuf,
+        credential_dir: PathBuf,
+        args: &[String],
+    ) -> Self {
+        let mut arguments = vec![
+            "--log-file".to_string(),
+            log_dir.join(format!("{name}.log")).display().to_string(),
+            "service".into(),
+            "run".into(),
+            "--name".into(),
+            name.into(),
+            "--".into(),
+        ];
+        let mut credentials = Vec::new();
+        let mut counts = [0; CREDENTIAL_OPTIONS.len()];
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let option = CREDENTIAL_OPTIONS.iter().position(|&(long, short, _, _)| {
+                arg == long || Some(arg.as_str()) == short || arg.starts_with(&format!("{long}="))
+            });
+            let Some(index) = option else {
+                arguments.push(arg.clone());
+                continue;
+            };
+            let value = match arg.split_once('=') {
+                Some((_, value)) => value.to_string(),
+                None => match args.next() {
+                    Some(value) => value.clone(),
+                    // A missing value is for the command line parser to report.
+                    None => {
+                        arguments.push(arg.clone());
+                        continue;
+                    }
+                },
+            };
+            // Servers accept several secrets, each in its own file.
+            let (_, _, file, kind) = CREDENTIAL_OPTIONS[index];
+            counts[index] += 1;
+            let path = match counts[index] {
+                1 => credential_dir.join(format!("{name}.{kind}")),
+                count => credential_dir.join(format!("{name}.{kind}.{count}")),
+            };
+            arguments.push(file.into());
+            arguments.push(path.display().to_string());
+            credentials.push((path, value));
+        }
+        Self {
+            name: name.into(),
+            program,
+            arguments,
+            log_dir,
+            credentials,
+        }
+    }
+
+    /// File for output that bypasses the logger, such as panics, since the
+    /// log file is rotated.
+    pub fn stderr_path(&self) -> PathBuf {
+        self.log_dir.join(format!("{}.stderr.log", self.name))
+    }
+
+    /// Property list of the launchd agent.
+    pub fn plist(&self) -> String {
+        let mut arguments = String::new();
+        for arg in iter::once(self.program.display().to_string()).chain(self.arguments.clone()) {
+            arguments += &format!("    <string>{}</string>\n", escape(&arg));
+        }
+        // Restart the agent whenever it exits with an error, but not after a
+        // clean shutdown, waiting between attempts to avoid a tight loop.
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Label</key>
+  <string>{label}</string>
+  <key>ProgramArguments</key>
+  <array>
+{arguments}  </array>
+  <key>RunAtLoad</key>
+  <true/>
+  <key>KeepAlive</key>
+  <dict>
+    <key>SuccessfulExit</key>
+    <false/>
+  </dict>
+  <key>ThrottleInterval</key>
+  <integer>10</integer>
+  <key>StandardErrorPath</key>
+  <string>{stderr}</string>
+</dict>
+</plist>
+"#,
+            label = escape(&label(&self.name)),
+            stderr = escape(&self.stderr_path().display().to_string()),
+        )
+    }
+}
+
+/// Label of a launchd agent.
+fn label(name: &str) -> String {
+    format!("dev.bore.{name}")
+}
+
+/// Escape text for an XML document.
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Define a service for this executable, creating its log directory and
+/// saving its credentials.
+#[cfg_attr(not(any(target_os = "macos", windows)), allow(dead_code))]
+fn define(name: &str, args: &[String]) -> Result<ServiceDefinition> {
+    let dir = log_dir()?;
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("could not create log directory {}", dir.display()))?;
+    let service =
+        ServiceDefinition::new(name, std::env::current_exe()?, dir, credential_dir()?, args);
+    for (path, value) in &service.credentials {
+        save_credential(path, value)
+            .with_context(|| format!("could not save credential {}", path.display()))?;
+    }
+    Ok(service)
+}
+
+/// Write a credential to a file that only the current account can read.
+#[cfg_attr(not(any(target_os = "macos", windows)), allow(dead_code))]
+fn save_credential(path: &Path, value: &str) -> Result<()> {
+    use std::io::Write;
+
+    let dir = path.parent().context("credential file has no directory")?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    std::fs::create_dir_all(dir)?;
+    // A file left from an earlier install may be readable by others.
+    let _ = std::fs::remove_file(path);
+    let mut file = options.open(path)?;
+    // Services run as LocalSystem, and only it and administrators may read
+    // the file.
+    #[cfg(windows)]
+    {
+        let status = std::process::Command::new("icacls")
+            .arg(path)
+            .args([
+                "/inheritance:r",
+                "/grant:r",
+                "*S-1-5-18:F",
+                "*S-1-5-32-544:F",
+            ])
+            .status()
+            .context("could not run icacls")?;
+        anyhow::ensure!(status.success(), "icacls failed");
+    }
+    file.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+/// Delete the credential files of a service.
+#[cfg_attr(not(any(target_os = "macos", windows)), allow(dead_code))]
+fn remove_credentials(name: &str) -> Result<()> {
+    let Ok(entries) = std::fs::read_dir(credential_dir()?) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let credential = CREDENTIAL_OPTIONS.iter().any(|(_, _, _, kind)| {
+            match file_name.strip_prefix(&format!("{name}.{kind}")) {
+                Some("") => true,
+                Some(rest) => rest
+                    .strip_prefix('.')
+                    .is_some_and(|n| n.parse::<u32>().is_ok()),
+                None => false,
+            }
+        });
+        if credential {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Register a bore command as a service that starts automatically.
+pub fn install(name: &str, args: &[String]) -> Result<()> {
+    platform::install(name, args)
+}
+
+/// Stop and remove a service installed with [`install`].
+pub fn uninstall(name: &str) -> Result<()> {
+    platform::uninstall(name)
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use anyhow::{ensure, Context, Result};
+
+    fn plist_path(name: &str) -> Result<PathBuf> {
+        let home = std::env::var_os("HOME").context("$HOME is not set")?;
+        Ok(PathBuf::from(home)
+            .join("Library")
+            .join("LaunchAgents")
+            .join(format!("{}.plist", super::label(name))))
+    }
+
+    fn launchctl(args: &[&str]) -> Result<()> {
+        let status = Command::new("launchctl")
+            .args(args)
+            .status()
+            .context("could not run launchctl")?;
+        ensure!(status.success(), "launchctl {} failed", args.join(" "));
+        Ok(())
+    }
+
+    pub fn install(name: &str, args: &[String]) -> Result<()> {
+        let service = super::define(name, args)?;
+        let path = plist_path(name)?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, service.plist())
+            .with_context(|| format!("could not write {}", path.display()))?;
+        launchctl(&["load", "-w", &path.display().to_string()])
+    }
+
+    pub fn uninstall(name: &str) -> Result<()> {
+        let path = plist_path(name)?;
+        ensure!(path.exists(), "no service named {name:?} is installed");
+        launchctl(&["unload", "-w", &path.display().to_string()])?;
+        std::fs::remove_file(&path)?;
+        super::remove_credentials(name)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::OsString;
+    use std::time::Duration;
+
+    use anyhow::{Context, Result};
+    use windows_service::service::{
+        ServiceAccess, ServiceAction, ServiceActionType, ServiceErrorControl,
+        ServiceFailureActions, ServiceFailureResetPeriod, ServiceInfo, ServiceStartType,
+        ServiceState, ServiceType,
+    };
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    pub fn service_name(name: &str) -> String {
+        format!("bore-{name}")
+    }
+
+    pub fn install(name: &str, args: &[String]) -> Result<()> {
+        let service = super::define(name, args)?;
+        let access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
+        let manager = ServiceManager::local_computer(None::<&str>, access)?;
+        let info = ServiceInfo {
+            name: OsString::from(service_name(name)),
+            display_name: OsString::from(format!("bore ({name})")),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: service.program,
+            launch_arguments: service.arguments.iter().map(OsString::from).collect(),
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager
+            .create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+            .context("could not create service, is this an administrator prompt?")?;
+        service.set_description("Tunnel managed by bore")?;
+
+        let restart = ServiceAction {
+            action_type: ServiceActionType::Restart,
+            delay: Duration::from_secs(10),
+        };
+        service.update_failure_actions(ServiceFailureActions {
+            reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(86400)),
+            reboot_msg: None,
+            command: None,
+            actions: Some(vec![restart.clone(), restart.clone(), restart]),
+        })?;
+        service.set_failure_actions_on_non_crash_failures(true)?;
+        service.start::<&str>(&[])?;
+        Ok(())
+    }
+
+    pub fn uninstall(name: &str) -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE;
+        let service = manager
+            .open_service(service_name(name), access)
+            .with_context(|| format!("no service named {name:?} is installed"))?;
+        if service.query_status()?.current_state != ServiceState::Stopped {
+            service.stop()?;
+        }
+        service.delete()?;
+        super::remove_credentials(name)
+    }
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+mod platform {
+    use anyhow::{bail, Result};
+
+    pub fn install(_name: &str, _args: &[String]) -> Result<()> {
+        bail!("services are only supported on Windows and macOS, use a systemd unit instead")
+    }
+
+    pub fn uninstall(_name: &str) -> Result<()> {
+        bail!("services are only supported on Windows and macOS, use a systemd unit instead")
+    }
+}
+
+/// Run inside the Windows service control dispatcher, blocking until the service stops.
+///
+/// The dispatcher calls `service_main` on another thread, which should call
+/// [`serve`] with the work the service does.
+#[cfg(windows)]
+pub fn dispatch(name: &str, service_main: extern "system" fn(u32, *mut *mut u16)) -> Result<()> {
+    windows_service::service_dispatcher::start(platform::service_name(name), service_main)
+        .context("could not connect to the service control manager")
+}
+
+/// Report to the service control manager while running the service's work.
+///
+/// A stop request ends the process once it has been acknowledged. If the work
+/// fails, the service reports an error so that its failure actions restart it.
+#[cfg(windows)]
+pub fn serve(name: &str, work: impl FnOnce() -> Result<()>) -> Result<()> {
+    use std::sync::{Arc, OnceLock};
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{
+        self, ServiceControlHandlerResult, ServiceStatusHandle,
+    };
+
+    let status = |state: ServiceState, exit_code: ServiceExitCode| ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code,
+        checkpoint: 0,
+        wait_hint: std::time::Duration::default(),
+        process_id: None,
+    };
+
+    let handle = Arc::new(OnceLock::<ServiceStatusHandle>::new());
+    let stop_handle = Arc::clone(&handle);
+    let handler = move |control| match control {
+        ServiceControl::Stop => {
+            if let Some(handle) = stop_handle.get() {
+                let _ = handle
+                    .set_service_status(status(ServiceState::Stopped, ServiceExitCode::NO_ERROR));
+            }
+            tracing::info!("service stopped");
+            std::process::exit(0);
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let registered = service_control_handler::register(platform::service_name(name), handler)?;
+    let _ = handle.set(registered);
+    let handle = handle.get().expect("status handle was just set");
+    handle.set_service_status(status(ServiceState::Running, ServiceExitCode::NO_ERROR))?;
+
+    let result = work();
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::NO_ERROR,
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    handle.set_service_status(status(ServiceState::Stopped, exit_code))?;
+    result
+}
@@ -1,36 +1,539 @@
 //! Shared data structures, utilities, and protocol definitions.
 
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
 use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use futures_util::{SinkExt, StreamExt};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::de::{self, DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::time::timeout;
-use tokio_util::codec::{AnyDelimiterCodec, Framed, FramedParts};
+use tokio_util::codec::{AnyDelimiterCodec, AnyDelimiterCodecError, Framed, FramedParts};
 use tracing::trace;
 use uuid::Uuid;
 
+use crate::acl::{self, Cidr};
+use crate::identity::{IdentityProof, IdentityRequest};
+use crate::integrity::StreamChecksum;
+use crate::oidc;
+
 /// TCP port used for control connections with the server.
 pub const CONTROL_PORT: u16 = 7835;
 
-/// Maximum byte length for a JSON frame in the stream.
-pub const MAX_FRAME_LENGTH: usize = 256;
+/// Default maximum byte length for a JSON frame in the stream.
+///
+/// This fits the largest valid message, a [`ClientHello`] with the most
+/// address and login rules, each at its longest, quoted, and with every
+/// character of the emails escaped, along with other strings of up to
+/// [`MAX_STRING_LENGTH`] bytes.
+pub const MAX_FRAME_LENGTH: usize = acl::MAX_RULES * (acl::MAX_RULE_LENGTH + 3)
+    + oidc::MAX_RULES * (2 * oidc::MAX_EMAIL_LENGTH + 3)
+    + 8 * MAX_STRING_LENGTH;
 
 /// Timeout for network connections and initial protocol messages.
 pub const NETWORK_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// Version of the control protocol implemented by this crate.
+///
+/// Version 0 is the original protocol, where clients open a tunnel with
+/// [`ClientMessage::Hello`]. Later versions use [`ClientMessage::HelloExt`].
+/// Since version 2, servers report errors with [`ServerMessage::ErrorExt`].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Compressed frames smaller than this are sent uncompressed instead.
+const COMPRESSION_THRESHOLD: usize = 128;
+
+/// Maximum byte length of a control frame after decompression.
+const MAX_INFLATED_LENGTH: usize = 16 * 1024;
+
+/// Prefix marking a frame as deflate-compressed and base64-encoded.
+const COMPRESSED_PREFIX: u8 = b'~';
+
+/// Maximum byte length of a string field in a control message.
+pub const MAX_STRING_LENGTH: usize = 4096;
+
+/// Maximum byte length of a tunnel name.
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// Check that a tunnel name is short and plain enough to use as a label in
+/// logs and metrics.
+///
+/// ```
+/// use bore_cli::shared::check_tunnel_name;
+///
+/// assert!(check_tunnel_name("staging-api.v2").is_ok());
+/// assert!(check_tunnel_name("").is_err());
+/// assert!(check_tunnel_name("has spaces").is_err());
+/// ```
+pub fn check_tunnel_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty() && name.len() <= MAX_NAME_LENGTH,
+        "tunnel name must be between 1 and {MAX_NAME_LENGTH} bytes"
+    );
+    ensure!(
+        name.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.')),
+        "tunnel name may only contain letters, digits, '-', '_', and '.'"
+    );
+    Ok(())
+}
+
+/// Host name or IP address without the brackets around an IPv6 literal, so
+/// that hosts can be given as `[::1]` like in URLs.
+///
+/// ```
+/// use bore_cli::shared::unbracket;
+///
+/// assert_eq!(unbracket("[2001:db8::1]"), "2001:db8::1");
+/// assert_eq!(unbracket("::1"), "::1");
+/// assert_eq!(unbracket("example.com"), "example.com");
+/// ```
+pub fn unbracket(host: &str) -> &str {
+    (host.strip_prefix('['))
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Host and port as text, with brackets around an IPv6 literal.
+///
+/// ```
+/// use bore_cli::shared::host_port;
+///
+/// assert_eq!(host_port("::1", 8080), "[::1]:8080");
+/// assert_eq!(host_port("[::1]", 8080), "[::1]:8080");
+/// assert_eq!(host_port("localhost", 8080), "localhost:8080");
+/// ```
+pub fn host_port(host: &str, port: u16) -> String {
+    match unbracket(host) {
+        host if host.contains(':') => format!("[{host}]:{port}"),
+        host => format!("{host}:{port}"),
+    }
+}
+
+/// Address of a peer with an IPv4 address mapped into IPv6, as dual-stack
+/// sockets report them, turned back into IPv4.
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Deserialize a string field, rejecting it before allocation if it is too long.
+///
+/// Frames are already bounded in size, but compressed frames can inflate to
+/// many times their length, so string fields in messages use this as well.
+pub(crate) fn bounded_string<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<String, D::Error> {
+    struct BoundedString;
+
+    impl de::Visitor<'_> for BoundedString {
+        type Value = String;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "a string of at most {MAX_STRING_LENGTH} bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> std::result::Result<String, E> {
+            if value.len() > MAX_STRING_LENGTH {
+                return Err(E::invalid_length(value.len(), &self));
+            }
+            Ok(value.to_owned())
+        }
+    }
+
+    deserializer.deserialize_str(BoundedString)
+}
+
+/// Deserialize an optional string field with [`bounded_string`].
+pub(crate) fn bounded_optional_string<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    struct Bounded(#[serde(deserialize_with = "bounded_string")] String);
+
+    Ok(Option::<Bounded>::deserialize(deserializer)?.map(|bounded| bounded.0))
+}
+
+/// Initial message from clients that speak a versioned protocol.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientHello {
+    /// Port on the remote server to select, or 0 for any available port.
+    pub port: u16,
+
+    /// Protocol version spoken by the client.
+    pub version: u32,
+
+    /// Whether the client would like control frames to be compressed.
+    #[serde(default)]
+    pub compression: bool,
+
+    /// Whether the client would like checksums of each proxied stream.
+    #[serde(default)]
+    pub checksums: bool,
+
+    /// Number of data connections to stripe each proxied stream across.
+    #[serde(default)]
+    pub stripes: u8,
+
+    /// Whether the client would like data connections to be encrypted.
+    #[serde(default)]
+    pub encryption: bool,
+
+    /// Whether to forward UDP datagrams instead of TCP connections.
+    #[serde(default)]
+    pub udp: bool,
+
+    /// Name of the tunnel, used to label its logs on the server.
+    #[serde(default, deserialize_with = "bounded_optional_string")]
+    pub name: Option<String>,
+
+    /// Whether the client would like to multiplex data connections over one
+    /// connection.
+    #[serde(default)]
+    pub multiplex: bool,
+
+    /// Whether the client answers pings, so that heartbeats can adapt to the
+    /// path.
+    #[serde(default)]
+    pub adaptive_heartbeat: bool,
+
+    /// Whether the client would like details about the visitor of each new
+    /// connection.
+    #[serde(default)]
+    pub connection_info: bool,
+
+    /// How long, in milliseconds, the client waits to hear from the server
+    /// before it considers the connection dead.
+    #[serde(default)]
+    pub heartbeat_timeout_ms: Option<u64>,
+
+    /// Whether the client would like a session token to open data
+    /// connections with, instead of authenticating each of them.
+    #[serde(default)]
+    pub session_token: bool,
+
+    /// Whether the server may open the tunnel on the free port nearest to
+    /// the requested one, if that is taken.
+    #[serde(default)]
+    pub nearest_port: bool,
+
+    /// Only let visitors from these blocks of addresses reach the tunnel,
+    /// unless empty.
+    #[serde(default)]
+    pub allow_ips: Vec<Cidr>,
+
+    /// Never let visitors from these blocks of addresses reach the tunnel.
+    #[serde(default)]
+    pub deny_ips: Vec<Cidr>,
+
+    /// Class of addresses that the tunnel should listen on, such as
+    /// `internal`, instead of the server's default ones.
+    #[serde(default)]
+    pub listener_class: Option<String>,
+
+    /// Whether the tunnel serves HTTP, to be routed by hostname on the
+    /// server's shared HTTP port.
+    #[serde(default)]
+    pub http: bool,
+
+    /// Subdomain that an HTTP or TLS tunnel asks for, instead of its name or
+    /// a random one.
+    #[serde(default, deserialize_with = "bounded_optional_string")]
+    pub subdomain: Option<String>,
+
+    /// Whether the tunnel terminates TLS itself, to be routed by server name
+    /// on the server's shared TLS port.
+    #[serde(default)]
+    pub tls_passthrough: bool,
+
+    /// Hex-encoded SHA-256 digest of `user:password`, which visitors of an
+    /// HTTP tunnel must give with basic authentication.
+    #[serde(default, deserialize_with = "bounded_optional_string")]
+    pub basic_auth: Option<String>,
+
+    /// Emails, and domains starting with `@`, that visitors of an HTTP
+    /// tunnel must log in as with the server's OIDC provider, unless empty.
+    #[serde(default)]
+    pub oidc_allow: Vec<String>,
+}
+
+/// Response to a [`ClientHello`], describing the negotiated tunnel.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerHello {
+    /// Port that is publicly available on the remote.
+    pub port: u16,
+
+    /// Protocol version spoken by the server.
+    pub version: u32,
+
+    /// Whether control frames after this message are compressed.
+    #[serde(default)]
+    pub compression: bool,
+
+    /// Whether the server reports a checksum of each proxied stream at close.
+    #[serde(default)]
+    pub checksums: bool,
+
+    /// Number of data connections that each proxied stream is striped across.
+    #[serde(default)]
+    pub stripes: u8,
+
+    /// Whether data connections are encrypted.
+    #[serde(default)]
+    pub encryption: bool,
+
+    /// Whether the tunnel forwards UDP datagrams instead of TCP connections.
+    #[serde(default)]
+    pub udp: bool,
+
+    /// Whether the client may multiplex data connections over one connection.
+    #[serde(default)]
+    pub multiplex: bool,
+
+    /// Whether the server sends pings instead of heartbeats.
+    #[serde(default)]
+    pub adaptive_heartbeat: bool,
+
+    /// Whether new connections are announced with [`ServerMessage::ConnectionExt`].
+    #[serde(default)]
+    pub connection_info: bool,
+
+    /// Longest time, in milliseconds, between heartbeats or pings from the
+    /// server.
+    #[serde(default)]
+    pub heartbeat_interval_ms: Option<u64>,
+
+    /// Token that data connections of this tunnel may present instead of
+    /// credentials, until the tunnel closes.
+    #[serde(default, deserialize_with = "bounded_optional_string")]
+    pub session_token: Option<String>,
+
+    /// Whether the server applies the visitor address rules of the hello.
+    #[serde(default)]
+    pub access_list: bool,
+
+    /// Whether the client has no credentials, but the server challenges its
+    /// connections anyway, so that data connections must skip the challenge.
+    #[serde(default)]
+    pub challenged: bool,
+
+    /// Hostname that an HTTP tunnel is reached at on the shared HTTP port.
+    #[serde(default, deserialize_with = "bounded_optional_string")]
+    pub hostname: Option<String>,
+
+    /// URL of an HTTP tunnel on the shared HTTP port.
+    #[serde(default, deserialize_with = "bounded_optional_string")]
+    pub http_url: Option<String>,
+
+    /// URL of an HTTP tunnel on the shared TLS port, if the server terminates
+    /// TLS for it.
+    #[serde(default, deserialize_with = "bounded_optional_string")]
+    pub https_url: Option<String>,
+
+    /// Address, as `host:port`, of a TLS tunnel on the shared TLS port.
+    #[serde(default, deserialize_with = "bounded_optional_string")]
+    pub tls_addr: Option<String>,
+
+    /// Whether the server requires visitors of the tunnel to give the
+    /// credentials of the hello with basic authentication.
+    #[serde(default)]
+    pub basic_auth: bool,
+
+    /// Whether the server requires visitors of the tunnel to log in as one
+    /// of the emails or domains of the hello.
+    #[serde(default)]
+    pub oidc: bool,
+
+    /// Whether the server answers visits announced with
+    /// [`ClientMessage::Probe`] instead of forwarding them.
+    #[serde(default)]
+    pub probes: bool,
+}
+
+/// Details of a new connection from a visitor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    /// Identifier that the client accepts the connection with.
+    pub id: Uuid,
+
+    /// Address of the visitor.
+    pub peer: SocketAddr,
+
+    /// Port of the server that the visitor connected to, unless the server
+    /// predates it.
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+/// Why the server disconnected a visitor without forwarding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Too many visitors of the tunnel were already waiting for the client.
+    QueueFull,
+
+    /// The client did not accept the connection in time.
+    AcceptTimeout,
+
+    /// The tunnel closed before the client accepted the connection.
+    TunnelClosed,
+}
+
+impl CloseReason {
+    /// Name of the reason, as used in logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::QueueFull => "queue_full",
+            CloseReason::AcceptTimeout => "accept_timeout",
+            CloseReason::TunnelClosed => "tunnel_closed",
+        }
+    }
+}
+
+/// Request from an authenticated client to mint a sub-key for others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubKeyRequest {
+    /// Number of seconds until the sub-key expires.
+    pub ttl_secs: u64,
+
+    /// Lowest remote port that the sub-key may open.
+    pub min_port: u16,
+
+    /// Highest remote port that the sub-key may open.
+    pub max_port: u16,
+
+    /// What the sub-key allows its holder to do on those ports.
+    #[serde(default, skip_serializing_if = "Scope::is_default")]
+    pub scope: Scope,
+}
+
+/// What a sub-key allows its holder to do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Open tunnels.
+    #[default]
+    Tunnel,
+
+    /// Watch existing tunnels, without accepting or sending any traffic.
+    Observe,
+}
+
+impl Scope {
+    fn is_default(&self) -> bool {
+        *self == Scope::default()
+    }
+}
+
+/// Request to watch an existing tunnel as a read-only observer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObserveRequest {
+    /// Public port of the tunnel to watch.
+    pub port: u16,
+
+    /// Whether to receive payload samples of connections that the server
+    /// samples on the tunnel.
+    #[serde(default)]
+    pub samples: bool,
+}
+
+/// Event on a tunnel, as reported to observers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Observation {
+    /// A visitor connected and was handed to the client.
+    Connection {
+        /// Identifier of the connection.
+        id: Uuid,
+
+        /// Address of the visitor.
+        peer: SocketAddr,
+    },
+
+    /// The first bytes in each direction of a sampled connection, hex-encoded.
+    Sample {
+        /// Identifier of the connection.
+        id: Uuid,
+
+        /// Bytes received from the visitor.
+        #[serde(deserialize_with = "bounded_string")]
+        inbound: String,
+
+        /// Bytes sent to the visitor.
+        #[serde(deserialize_with = "bounded_string")]
+        outbound: String,
+    },
+
+    /// A visitor was disconnected before the client accepted it.
+    Dropped {
+        /// Identifier of the connection.
+        id: Uuid,
+
+        /// Why the visitor was disconnected.
+        reason: CloseReason,
+    },
+
+    /// A forwarded connection closed.
+    Closed {
+        /// Identifier of the connection.
+        id: Uuid,
+
+        /// How long the connection was forwarded for, in milliseconds.
+        duration_ms: u64,
+    },
+}
+
 /// A message from the client on the control connection.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
     /// Response to an authentication challenge from the server.
-    Authenticate(String),
+    Authenticate(#[serde(deserialize_with = "bounded_string")] String),
 
     /// Initial client message specifying a port to forward.
     Hello(u16),
 
+    /// Initial client message for versioned protocols, with extra options.
+    HelloExt(ClientHello),
+
     /// Accepts an incoming TCP connection, using this stream as a proxy.
     Accept(Uuid),
+
+    /// Accepts an incoming TCP connection, using this stream as one of the
+    /// stripes at the given index, on tunnels that negotiated striping.
+    AcceptStripe(Uuid, u8),
+
+    /// Asks the server to prove its identity by signing a random nonce and
+    /// key shares, which then key the session.
+    Identify(IdentityRequest),
+
+    /// Asks the server to mint a scoped, time-limited sub-key.
+    Delegate(SubKeyRequest),
+
+    /// Turns this connection into streams that are each used like a new data
+    /// connection, on tunnels that negotiated multiplexing.
+    Multiplex,
+
+    /// Answer to a ping from the server, with its sequence number.
+    Pong(u64),
+
+    /// Attaches to an existing tunnel as a read-only observer.
+    Observe(ObserveRequest),
+
+    /// Presents a session token instead of credentials, right after
+    /// connecting and without waiting for the challenge. Only an accept of a
+    /// connection to the tunnel that the token was issued for may follow.
+    SessionToken(#[serde(deserialize_with = "bounded_string")] String),
+
+    /// Announces that the client is about to visit its own tunnel from the
+    /// address of the control connection, to check that the public endpoint
+    /// is reachable. The server answers that visit with the token instead of
+    /// forwarding it, on tunnels that negotiated probes.
+    Probe(Uuid),
 }
 
 /// A message from the server on the control connection.
@@ -42,33 +545,442 @@ pub enum ServerMessage {
     /// Response to a client's initial message, with actual public port.
     Hello(u16),
 
+    /// Response to a client's versioned initial message.
+    HelloExt(ServerHello),
+
     /// No-op used to test if the client is still reachable.
     Heartbeat,
 
     /// Asks the client to accept a forwarded TCP connection.
     Connection(Uuid),
 
+    /// Asks the client to accept a forwarded connection, with details about
+    /// the visitor, on tunnels that negotiated them.
+    ConnectionExt(ConnectionInfo),
+
     /// Indicates a server error that terminates the connection.
-    Error(String),
+    Error(#[serde(deserialize_with = "bounded_string")] String),
+
+    /// Indicates a server error that terminates the connection, with a
+    /// machine-readable code, for clients of protocol version 2 and later.
+    ErrorExt(ServerError),
+
+    /// Response to an identity request from the client.
+    Identity(IdentityProof),
+
+    /// Indicates the server is overloaded, and the client should reconnect
+    /// after a random delay of up to this many milliseconds.
+    Busy(u64),
+
+    /// Indicates that authentication failed, terminating the connection.
+    ///
+    /// Follows an [`ServerMessage::Error`] with the same message, which is
+    /// all that older clients read.
+    AuthFailed(AuthError),
+
+    /// Response to a delegation request, with the new sub-key.
+    Delegated(#[serde(deserialize_with = "bounded_string")] String),
+
+    /// Checksums of a proxied stream that has closed, if negotiated.
+    Checksum(StreamChecksum),
+
+    /// Heartbeat that the client answers, on tunnels that negotiated adaptive
+    /// heartbeats.
+    Ping(u64),
+
+    /// Event on a tunnel, sent to its observers.
+    Observation(Observation),
+
+    /// Confirms that the next visit from the client's address is answered
+    /// with the token of its [`ClientMessage::Probe`].
+    Probe(Uuid),
+}
+
+/// Reason that the server refused a request or closed a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The requested port is taken, or the server may not bind to it.
+    PortUnavailable,
+
+    /// The requested port is outside of the range allowed for the client.
+    PortOutOfRange,
+
+    /// No port in the range allowed for the client was free.
+    NoPortAvailable,
+
+    /// The client has as many tunnels open as its quota allows, or the
+    /// tunnel has used up its transfer quota.
+    QuotaExceeded,
+
+    /// The request needs credentials, but the server does not check any.
+    AuthRequired,
+
+    /// The server only accepts connections over TLS.
+    TlsRequired,
+
+    /// The credentials do not allow the request.
+    Forbidden,
+
+    /// The credentials have expired, or the tunnel was only allowed to stay
+    /// open for a limited time.
+    Expired,
+
+    /// The request was malformed, such as an invalid tunnel name.
+    InvalidRequest,
+
+    /// The server does not support the request.
+    Unsupported,
+
+    /// No tunnel is open on the requested port.
+    TunnelNotFound,
+
+    /// The server operator closed the tunnel.
+    ClosedByOperator,
+
+    /// The tunnel carried no traffic for longer than the server allows.
+    IdleTimeout,
+
+    /// Another tunnel has the subdomain that an HTTP tunnel asked for.
+    HostnameTaken,
+
+    /// Any other error, including codes from newer servers.
+    #[serde(other)]
+    Other,
+}
+
+/// Error reported by the server, with a code for clients to act on and a
+/// message for humans.
+///
+/// ```
+/// use bore_cli::shared::{ErrorCode, ServerError};
+///
+/// let err = ServerError::new(ErrorCode::PortUnavailable, "port already in use");
+/// assert_eq!(err.to_string(), "port already in use");
+/// let legacy = ServerError::from("port already in use".to_string());
+/// assert_eq!(legacy.code, ErrorCode::PortUnavailable);
+/// assert_eq!(ServerError::from("out of cheese".to_string()).code, ErrorCode::Other);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerError {
+    /// Machine-readable reason for the error.
+    pub code: ErrorCode,
+
+    /// Human-readable description from the server.
+    #[serde(deserialize_with = "bounded_string")]
+    pub message: String,
 }
 
+impl ServerError {
+    /// Construct a server error with a code and message.
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Message that reports this error to a client speaking a protocol
+    /// version, which only carries the code since version 2.
+    pub fn into_message(self, version: u32) -> ServerMessage {
+        match version {
+            0 | 1 => ServerMessage::Error(self.message),
+            _ => ServerMessage::ErrorExt(self),
+        }
+    }
+}
+
+impl From<String> for ServerError {
+    /// Error from a server that only sends a message, such as to clients
+    /// that open tunnels with [`ClientMessage::Hello`]. Messages about ports
+    /// and about closing tunnels for good are recognized, and anything else
+    /// has no specific code.
+    fn from(message: String) -> Self {
+        let code = match message.as_str() {
+            "port already in use" | "permission denied" | "failed to bind to port" => {
+                ErrorCode::PortUnavailable
+            }
+            "client port number not in allowed range" | "no ports are allowed for this client" => {
+                ErrorCode::PortOutOfRange
+            }
+            "failed to find an available port" => ErrorCode::NoPortAvailable,
+            "transfer quota exceeded" => ErrorCode::QuotaExceeded,
+            "tunnel closed after sitting idle" => ErrorCode::IdleTimeout,
+            "sub-key has expired" | "anonymous tunnel expired" => ErrorCode::Expired,
+            _ => ErrorCode::Other,
+        };
+        Self::new(code, message)
+    }
+}
+
+impl ErrorCode {
+    /// Whether the server closed a tunnel with this error for good, so that
+    /// the client should not reopen it.
+    ///
+    /// ```
+    /// use bore_cli::shared::ErrorCode;
+    ///
+    /// assert!(ErrorCode::IdleTimeout.closes_tunnel());
+    /// assert!(!ErrorCode::PortUnavailable.closes_tunnel());
+    /// ```
+    pub fn closes_tunnel(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::Expired | ErrorCode::IdleTimeout | ErrorCode::QuotaExceeded
+        )
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Reason that the server rejected a client's authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthErrorCode {
+    /// The client answered the challenge with the wrong secret.
+    InvalidSecret,
+
+    /// The API key was rejected by the validation backend.
+    InvalidApiKey,
+
+    /// The server could not reach its validation backend.
+    BackendUnavailable,
+
+    /// The client did not use the authentication method the server requires.
+    MethodNotSupported,
+
+    /// Authentication failed, with details withheld by the server.
+    Failed,
+}
+
+/// Authentication failure, as reported by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthError {
+    /// Machine-readable reason for the failure.
+    pub code: AuthErrorCode,
+
+    /// Human-readable description from the server.
+    #[serde(deserialize_with = "bounded_string")]
+    pub message: String,
+}
+
+impl AuthError {
+    /// Construct an authentication error with a code and message.
+    pub fn new(code: AuthErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Hide the reason for credential failures, so they cannot be probed.
+    pub fn redacted(&self) -> Self {
+        match self.code {
+            AuthErrorCode::BackendUnavailable | AuthErrorCode::MethodNotSupported => self.clone(),
+            _ => Self::new(AuthErrorCode::Failed, "authentication failed"),
+        }
+    }
+
+    /// Suggestion for the user on how to fix the failure.
+    pub fn hint(&self) -> &'static str {
+        match self.code {
+            AuthErrorCode::InvalidSecret => "the server rejected the secret, check --secret",
+            AuthErrorCode::InvalidApiKey => "the server rejected the API key, check --api-key",
+            AuthErrorCode::BackendUnavailable => {
+                "the server could not validate credentials, try again later"
+            }
+            AuthErrorCode::MethodNotSupported => {
+                "the server requires a different authentication method"
+            }
+            AuthErrorCode::Failed => "authentication failed, check your credentials",
+        }
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Error returned when the server asks the client to back off and retry.
+#[derive(Debug)]
+pub struct ServerBusy {
+    /// Upper bound for the random delay before retrying.
+    pub max_delay: Duration,
+}
+
+impl std::fmt::Display for ServerBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server is busy, retry within {:?}", self.max_delay)
+    }
+}
+
+impl std::error::Error for ServerBusy {}
+
+impl ServerBusy {
+    /// Construct the error from the delay in a [`ServerMessage::Busy`].
+    pub fn from_millis(millis: u64) -> Self {
+        Self {
+            max_delay: Duration::from_millis(millis),
+        }
+    }
+}
+
+/// Error returned when the server's control port cannot be reached.
+#[derive(Debug)]
+pub struct ServerUnreachable;
+
+impl fmt::Display for ServerUnreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server is unreachable")
+    }
+}
+
+impl std::error::Error for ServerUnreachable {}
+
+/// Error returned when the server refuses to open a tunnel, such as when the
+/// requested port is taken.
+#[derive(Debug)]
+pub struct TunnelRejected(pub ServerError);
+
+impl fmt::Display for TunnelRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server error: {}", self.0)
+    }
+}
+
+impl std::error::Error for TunnelRejected {}
+
+/// Error returned when the server closes an open tunnel for good, such as
+/// after it carried no traffic for too long.
+#[derive(Debug)]
+pub struct TunnelClosed(pub ServerError);
+
+impl fmt::Display for TunnelClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server closed the tunnel: {}", self.0)
+    }
+}
+
+impl std::error::Error for TunnelClosed {}
+
+/// Error returned when the local service never became available.
+#[derive(Debug)]
+pub struct LocalUnreachable(pub String);
+
+impl fmt::Display for LocalUnreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "local service unreachable: {}", self.0)
+    }
+}
+
+impl std::error::Error for LocalUnreachable {}
+
+/// Error returned when the local service does not accept a connection
+/// before the local connect timeout.
+#[derive(Debug)]
+pub struct LocalTimeout(pub Duration);
+
+impl fmt::Display for LocalTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "local service did not accept the connection within {:?}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for LocalTimeout {}
+
+/// Error for a frame from the peer that is longer than the maximum length,
+/// which is dropped instead of being buffered.
+#[derive(Debug)]
+pub struct FrameTooLong(pub usize);
+
+impl fmt::Display for FrameTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame exceeds the maximum length of {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for FrameTooLong {}
+
 /// Transport stream with JSON frames delimited by null characters.
-pub struct Delimited<U>(Framed<U, AnyDelimiterCodec>);
+pub struct Delimited<U> {
+    inner: Framed<U, AnyDelimiterCodec>,
+    compression: bool,
+}
 
 impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     /// Construct a new delimited stream.
     pub fn new(stream: U) -> Self {
         let codec = AnyDelimiterCodec::new_with_max_length(vec![0], vec![0], MAX_FRAME_LENGTH);
-        Self(Framed::new(stream, codec))
+        Self {
+            inner: Framed::new(stream, codec),
+            compression: false,
+        }
+    }
+
+    /// Limit the length of frames from the peer, [`MAX_FRAME_LENGTH`] by
+    /// default. Longer frames fail with [`FrameTooLong`].
+    ///
+    /// ```
+    /// use bore_cli::shared::{ClientMessage, Delimited, FrameTooLong};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let (client, server) = tokio::io::duplex(1024);
+    /// let (mut client, mut server) = (Delimited::new(client), Delimited::new(server));
+    /// server.set_max_frame_length(16);
+    ///
+    /// client.send(ClientMessage::Authenticate("a".repeat(32))).await.unwrap();
+    /// let err = server.recv::<ClientMessage>().await.unwrap_err();
+    /// assert!(err.is::<FrameTooLong>());
+    /// # }
+    /// ```
+    pub fn set_max_frame_length(&mut self, max_length: usize) {
+        *self.inner.codec_mut() =
+            AnyDelimiterCodec::new_with_max_length(vec![0], vec![0], max_length);
+    }
+
+    /// Construct a delimited stream from which some bytes were already read.
+    pub fn with_read_buf(stream: U, read: &[u8]) -> Self {
+        let mut delimited = Self::new(stream);
+        delimited.inner.read_buffer_mut().extend_from_slice(read);
+        delimited
+    }
+
+    /// Enable or disable compression of frames, once negotiated with the peer.
+    ///
+    /// Compressed frames are deflated and base64-encoded so that they remain
+    /// free of null bytes. Small frames are still sent as plain JSON.
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
     }
 
     /// Read the next null-delimited JSON instruction from a stream.
     pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
         trace!("waiting to receive json message");
-        if let Some(next_message) = self.0.next().await {
-            let byte_message = next_message.context("frame error, invalid byte length")?;
-            let serialized_obj =
-                serde_json::from_slice(&byte_message).context("unable to parse message")?;
+        if let Some(next_message) = self.inner.next().await {
+            let byte_message = match next_message {
+                Err(AnyDelimiterCodecError::MaxChunkLengthExceeded) => {
+                    return Err(FrameTooLong(self.inner.codec().max_length()).into());
+                }
+                message => message.context("frame error, invalid byte length")?,
+            };
+            let serialized_obj = match byte_message.first() {
+                Some(&COMPRESSED_PREFIX) if self.compression => {
+                    serde_json::from_slice(&inflate(&byte_message[1..])?)
+                }
+                _ => serde_json::from_slice(&byte_message),
+            }
+            .context("unable to parse message")?;
             Ok(serialized_obj)
         } else {
             Ok(None)
@@ -88,12 +1000,42 @@ impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     /// Send a null-terminated JSON instruction on a stream.
     pub async fn send<T: Serialize>(&mut self, msg: T) -> Result<()> {
         trace!("sending json message");
-        self.0.send(serde_json::to_string(&msg)?).await?;
+        let mut frame = serde_json::to_string(&msg)?;
+        if self.compression && frame.len() >= COMPRESSION_THRESHOLD {
+            frame = deflate(&frame)?;
+        }
+        self.inner.send(frame).await?;
         Ok(())
     }
 
+    /// Get a reference to the inner transport.
+    pub fn get_ref(&self) -> &U {
+        self.inner.get_ref()
+    }
+
     /// Consume this object, returning current buffers and the inner transport.
     pub fn into_parts(self) -> FramedParts<U, AnyDelimiterCodec> {
-        self.0.into_parts()
+        self.inner.into_parts()
+    }
+}
+
+fn deflate(frame: &str) -> Result<String> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(frame.as_bytes())?;
+    let compressed = encoder.finish()?;
+    let mut encoded = String::with_capacity(1 + compressed.len() * 4 / 3 + 4);
+    encoded.push(COMPRESSED_PREFIX as char);
+    BASE64.encode_string(compressed, &mut encoded);
+    Ok(encoded)
+}
+
+fn inflate(encoded: &[u8]) -> Result<Vec<u8>> {
+    let compressed = BASE64.decode(encoded).context("invalid compressed frame")?;
+    let mut decoder = DeflateDecoder::new(&compressed[..]).take(MAX_INFLATED_LENGTH as u64 + 1);
+    let mut frame = Vec::new();
+    decoder.read_to_end(&mut frame)?;
+    if frame.len() > MAX_INFLATED_LENGTH {
+        bail!("compressed frame exceeds maximum length");
     }
+    Ok(frame)
 }
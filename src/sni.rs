@@ -0,0 +1,168 @@
+//! Server names of TLS connections, read from their ClientHello.
+//!
+//! A server can route TLS connections on a shared port to tunnels without
+//! terminating TLS itself, by the server name indication (SNI) that clients
+//! send in the clear at the start of the handshake. The tunnel's local
+//! service then completes the handshake with its own certificate.
+//!
+//! Only the first message of the handshake is read, and it is passed on to
+//! the tunnel untouched along with the rest of the connection.
+
+use anyhow::{ensure, Result};
+
+/// Longest ClientHello that is read to find its server name, which is far
+/// more than browsers send.
+pub const MAX_HELLO_LENGTH: usize = 16 * 1024;
+
+/// Content type of TLS records that carry handshake messages.
+const HANDSHAKE: u8 = 22;
+
+/// Type of the ClientHello handshake message.
+const CLIENT_HELLO: u8 = 1;
+
+/// Type of the server name extension.
+const SERVER_NAME: u16 = 0;
+
+/// Type of DNS hostnames in the server name extension.
+const HOST_NAME: u8 = 0;
+
+/// Length of the header of a TLS record.
+const RECORD_HEADER_LENGTH: usize = 5;
+
+/// First handshake message of a TLS connection.
+///
+/// ```
+/// use std::sync::Arc;
+/// use bore_cli::sni::Hello;
+/// use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
+///
+/// let config = ClientConfig::builder()
+///     .with_safe_defaults()
+///     .with_root_certificates(RootCertStore::empty())
+///     .with_no_client_auth();
+/// let name = "myapp.tunnel.example.com".try_into().unwrap();
+/// let mut conn = ClientConnection::new(Arc::new(config), name).unwrap();
+/// let mut bytes = Vec::new();
+/// conn.write_tls(&mut bytes).unwrap();
+///
+/// let hello = Hello::parse(&bytes).unwrap().unwrap();
+/// assert_eq!(hello.server_name.as_deref(), Some("myapp.tunnel.example.com"));
+/// assert_eq!(hello.length, bytes.len());
+///
+/// assert!(Hello::parse(&bytes[..bytes.len() - 1]).unwrap().is_none());
+/// assert!(Hello::parse(b"GET / HTTP/1.1\r\n").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    /// Hostname that the client asked for, if it sent one.
+    pub server_name: Option<String>,
+
+    /// Length in bytes of the records that hold the message.
+    pub length: usize,
+}
+
+impl Hello {
+    /// Parse the ClientHello at the start of a connection, or `None` if it is
+    /// still incomplete.
+    pub fn parse(bytes: &[u8]) -> Result<Option<Self>> {
+        // The message may be split across several records.
+        let mut message = Vec::new();
+        let mut offset = 0;
+        loop {
+            let Some(header) = bytes.get(offset..offset + RECORD_HEADER_LENGTH) else {
+                ensure!(
+                    bytes.get(offset).is_none_or(|&kind| kind == HANDSHAKE),
+                    "not a TLS handshake"
+                );
+                return Ok(None);
+            };
+            ensure!(header[0] == HANDSHAKE, "not a TLS handshake");
+            let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+            let start = offset + RECORD_HEADER_LENGTH;
+            let Some(fragment) = bytes.get(start..start + length) else {
+                return Ok(None);
+            };
+            message.extend_from_slice(fragment);
+            offset = start + length;
+
+            if let [kind, a, b, c, body @ ..] = &message[..] {
+                ensure!(
+                    *kind == CLIENT_HELLO,
+                    "handshake does not start with a ClientHello"
+                );
+                let length = u32::from_be_bytes([0, *a, *b, *c]) as usize;
+                ensure!(length <= MAX_HELLO_LENGTH, "ClientHello is too long");
+                if let Some(body) = body.get(..length) {
+                    return Ok(Some(Self {
+                        server_name: server_name(body)?,
+                        length: offset,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+/// Server name in the body of a ClientHello, if it has one.
+fn server_name(body: &[u8]) -> Result<Option<String>> {
+    let mut hello = Reader(body);
+    hello.take(2 + 32)?; // Version and random.
+    hello.vec8()?; // Session ID.
+    hello.vec16()?; // Cipher suites.
+    hello.vec8()?; // Compression methods.
+    if hello.0.is_empty() {
+        return Ok(None);
+    }
+    let mut extensions = Reader(hello.vec16()?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = Reader(extensions.vec16()?);
+        if kind != SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader(data.vec16()?);
+        while !names.0.is_empty() {
+            let kind = names.u8()?;
+            let name = names.vec16()?;
+            if kind == HOST_NAME {
+                ensure!(name.is_ascii(), "server name is not ASCII");
+                let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+                return Ok(Some(name));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Cursor over the fields of a handshake message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        ensure!(self.0.len() >= n, "truncated ClientHello");
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Bytes prefixed with a one-byte length.
+    fn vec8(&mut self) -> Result<&'a [u8]> {
+        let length = self.u8()? as usize;
+        self.take(length)
+    }
+
+    /// Bytes prefixed with a two-byte length.
+    fn vec16(&mut self) -> Result<&'a [u8]> {
+        let length = self.u16()? as usize;
+        self.take(length)
+    }
+}
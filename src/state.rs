@@ -0,0 +1,179 @@
+//! Server state that survives restarts.
+//!
+//! Abuse countermeasures are only useful if they last. A server that forgets
+//! them on restart gives an attacker a clean slate whenever it is upgraded or
+//! crashes, so the server periodically saves them to a state file and restores
+//! them at startup. Port reservations are kept the same way, so that clients
+//! get their ports back when they reconnect after a restart, and so is the
+//! traffic counted against transfer quotas and the usage reports that have yet
+//! to be delivered. Everything in the file is optional, so state written by
+//! other versions of the server can still be read.
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::usage::UsageReport;
+
+/// How often the server saves its state.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Snapshot of the server's state.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerState {
+    /// Unix timestamp in seconds when the snapshot was taken.
+    #[serde(default)]
+    pub saved_at: u64,
+
+    /// Tokens left in the limiter for new control connections.
+    #[serde(default)]
+    pub handshake_tokens: Option<f64>,
+
+    /// Addresses banned from the control port.
+    #[serde(default)]
+    pub bans: Vec<SavedBan>,
+
+    /// Hex-encoded key that the owners of reserved ports are derived with.
+    #[serde(default)]
+    pub reservation_key: Option<String>,
+
+    /// Ports of open and recently closed tunnels, held for their clients.
+    #[serde(default)]
+    pub reservations: Vec<SavedReservation>,
+
+    /// Traffic of tunnels with transfer quotas.
+    #[serde(default)]
+    pub transfers: Vec<SavedTransfer>,
+
+    /// Usage reports that were not delivered yet.
+    #[serde(default)]
+    pub usage_reports: Vec<UsageReport>,
+}
+
+/// Ban of an address, as saved in the state file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedBan {
+    /// The banned address.
+    pub addr: IpAddr,
+
+    /// Unix timestamp in seconds when the ban ends.
+    pub until: u64,
+}
+
+/// Reservation of a port, as saved in the state file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedReservation {
+    /// The reserved port.
+    pub port: u16,
+
+    /// Hex-encoded owner of the port.
+    pub owner: String,
+
+    /// Unix timestamp in seconds when the reservation ends.
+    pub until: u64,
+}
+
+/// Traffic of a tunnel with a transfer quota, as saved in the state file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedTransfer {
+    /// User and tunnel name that the traffic is counted under.
+    pub key: String,
+
+    /// Bytes transferred in both directions.
+    pub bytes: u64,
+
+    /// Month in which counting started.
+    pub month: u32,
+}
+
+/// Unix timestamp in seconds of the time that is `remaining` from now.
+///
+/// ```
+/// use std::time::Duration;
+/// use bore_cli::state::{deadline, remaining};
+///
+/// let until = deadline(Duration::from_secs(60));
+/// assert!(remaining(until).unwrap() > Duration::from_secs(58));
+/// assert_eq!(remaining(until - 120), None);
+/// ```
+pub fn deadline(remaining: Duration) -> u64 {
+    (SystemTime::now() + remaining)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Time left until a Unix timestamp in seconds, unless it has passed.
+pub fn remaining(until: u64) -> Option<Duration> {
+    (UNIX_EPOCH + Duration::from_secs(until))
+        .duration_since(SystemTime::now())
+        .ok()
+        .filter(|left| !left.is_zero())
+}
+
+impl ServerState {
+    /// Time that has passed since the snapshot was taken.
+    pub fn age(&self) -> Duration {
+        let saved_at = UNIX_EPOCH + Duration::from_secs(self.saved_at);
+        SystemTime::now()
+            .duration_since(saved_at)
+            .unwrap_or_default()
+    }
+}
+
+/// File where the server keeps its state.
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    /// Use the state file at this path, which need not exist yet.
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Read the last saved state, or the empty state if there is none yet.
+    pub fn load(&self) -> Result<ServerState> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(ServerState::default())
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not read {}", self.path.display()))
+            }
+        };
+        serde_json::from_str(&contents)
+            .with_context(|| format!("invalid state file {}", self.path.display()))
+    }
+
+    /// Save a snapshot of the state, stamped with the current time.
+    ///
+    /// The snapshot is written to a temporary file that then replaces the old
+    /// one, so a crash while saving leaves the previous snapshot intact. The
+    /// file is readable only by the user, as it holds the key of reserved
+    /// ports.
+    pub fn save(&self, mut state: ServerState) -> Result<()> {
+        state.saved_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&temp)
+            .and_then(|mut file| {
+                std::io::Write::write_all(&mut file, &serde_json::to_vec_pretty(&state)?)
+            })
+            .with_context(|| format!("could not write {}", self.path.display()))?;
+        fs::rename(&temp, &self.path)
+            .with_context(|| format!("could not replace {}", self.path.display()))
+    }
+}
@@ -0,0 +1,277 @@
+//! Running totals of the traffic through a tunnel.
+//!
+//! Besides totals since the tunnel opened, the bytes of recent traffic are
+//! counted in buckets of a few seconds, which give the throughput over the
+//! last minutes, and the durations of recent connections are kept to find
+//! slow ones.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Seconds of traffic counted in each bucket.
+const BUCKET_SECS: u64 = 5;
+
+/// Number of buckets, covering five minutes and the bucket being filled.
+const BUCKETS: u64 = 61;
+
+/// Number of recent connections whose durations are kept.
+const DURATION_SAMPLES: usize = 1000;
+
+/// Bytes counted during one interval of a few seconds.
+#[derive(Debug, Default)]
+struct Bucket {
+    /// Which interval since the tunnel opened the bytes belong to.
+    epoch: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// Counters for one tunnel, shared by its connections and whoever reports on it.
+#[derive(Debug)]
+pub struct TunnelStats {
+    started: Instant,
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+    connections: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    last_visitor: Mutex<Option<SocketAddr>>,
+    recent: [Bucket; BUCKETS as usize],
+    durations: Mutex<VecDeque<Duration>>,
+}
+
+impl Default for TunnelStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            inbound: AtomicU64::new(0),
+            outbound: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+            last_visitor: Mutex::new(None),
+            recent: std::array::from_fn(|_| Bucket::default()),
+            durations: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl TunnelStats {
+    /// Time since the tunnel was opened.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Bytes sent by visitors to the local service.
+    pub fn inbound(&self) -> u64 {
+        self.inbound.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent by the local service to visitors.
+    pub fn outbound(&self) -> u64 {
+        self.outbound.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections that have been forwarded.
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Most recent error of the tunnel or one of its connections.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    /// Address of the most recent visitor, if the server forwards them.
+    pub fn last_visitor(&self) -> Option<SocketAddr> {
+        *self.last_visitor.lock().unwrap()
+    }
+
+    /// Remember the address of a new visitor.
+    pub fn set_visitor(&self, addr: SocketAddr) {
+        *self.last_visitor.lock().unwrap() = Some(addr);
+    }
+
+    /// Count a new forwarded connection.
+    pub fn add_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Remember an error, replacing the previous one.
+    pub fn set_error(&self, err: impl ToString) {
+        *self.last_error.lock().unwrap() = Some(err.to_string());
+    }
+
+    /// Average bytes per second in both directions over a recent window of
+    /// up to five minutes, or since the tunnel opened if it is younger.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use bore_cli::stats::TunnelStats;
+    ///
+    /// let stats = TunnelStats::default();
+    /// assert_eq!(stats.throughput(Duration::from_secs(60)), 0.0);
+    /// ```
+    pub fn throughput(&self, window: Duration) -> f64 {
+        let elapsed = self.started.elapsed();
+        let epoch = elapsed.as_secs() / BUCKET_SECS;
+        let count = (window.as_secs() / BUCKET_SECS).clamp(1, BUCKETS - 1);
+        let oldest = (epoch + 1).saturating_sub(count);
+        let bytes: u64 = self
+            .recent
+            .iter()
+            .filter(|bucket| (oldest..=epoch).contains(&bucket.epoch.load(Ordering::Relaxed)))
+            .map(|bucket| bucket.bytes.load(Ordering::Relaxed))
+            .sum();
+        let span = elapsed.as_secs_f64() - (oldest * BUCKET_SECS) as f64;
+        match span > 0.0 {
+            true => bytes as f64 / span,
+            false => 0.0,
+        }
+    }
+
+    /// Record how long a forwarded connection was open.
+    pub fn add_duration(&self, duration: Duration) {
+        let mut durations = self.durations.lock().unwrap();
+        if durations.len() == DURATION_SAMPLES {
+            durations.pop_front();
+        }
+        durations.push_back(duration);
+    }
+
+    /// Duration that 95% of recent connections stayed below, if any closed.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use bore_cli::stats::TunnelStats;
+    ///
+    /// let stats = TunnelStats::default();
+    /// for millis in 1..=100 {
+    ///     stats.add_duration(Duration::from_millis(millis));
+    /// }
+    /// assert_eq!(stats.p95_duration(), Some(Duration::from_millis(95)));
+    /// ```
+    pub fn p95_duration(&self) -> Option<Duration> {
+        let mut durations: Vec<_> = self.durations.lock().unwrap().iter().copied().collect();
+        durations.sort_unstable();
+        let rank = (durations.len() * 95).div_ceil(100);
+        durations.get(rank.checked_sub(1)?).copied()
+    }
+
+    /// Count bytes of recent traffic.
+    fn add_recent(&self, bytes: u64) {
+        let epoch = self.started.elapsed().as_secs() / BUCKET_SECS;
+        let bucket = &self.recent[(epoch % BUCKETS) as usize];
+        // The first connection to reach a new interval clears what the
+        // bucket counted five minutes ago.
+        if bucket.epoch.swap(epoch, Ordering::Relaxed) != epoch {
+            bucket.bytes.store(0, Ordering::Relaxed);
+        }
+        bucket.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Stream wrapper that adds the bytes passing through it to a tunnel's totals.
+pub struct Metered<S> {
+    inner: S,
+    stats: Arc<TunnelStats>,
+
+    /// Whether the stream faces visitors, so that reads are inbound.
+    remote: bool,
+
+    /// Bytes read from and written to this stream alone.
+    read: u64,
+    written: u64,
+}
+
+impl<S> Metered<S> {
+    /// Meter a stream to the local service, whose reads are outbound.
+    pub fn local(inner: S, stats: Arc<TunnelStats>) -> Self {
+        Self {
+            inner,
+            stats,
+            remote: false,
+            read: 0,
+            written: 0,
+        }
+    }
+
+    /// Meter a stream that carries traffic from visitors, whose reads are inbound.
+    pub fn remote(inner: S, stats: Arc<TunnelStats>) -> Self {
+        Self {
+            inner,
+            stats,
+            remote: true,
+            read: 0,
+            written: 0,
+        }
+    }
+
+    /// Bytes read from this stream so far.
+    pub fn read_bytes(&self) -> u64 {
+        self.read
+    }
+
+    /// Bytes written to this stream so far.
+    pub fn written_bytes(&self) -> u64 {
+        self.written
+    }
+
+    fn add(&mut self, read: bool, bytes: u64) {
+        match read {
+            true => self.read += bytes,
+            false => self.written += bytes,
+        }
+        let counter = match read == self.remote {
+            true => &self.stats.inbound,
+            false => &self.stats.outbound,
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+        self.stats.add_recent(bytes);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let n = (buf.filled().len() - start) as u64;
+            this.add(true, n);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.add(false, n as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,96 @@
+//! Striping of one proxied stream across several data connections.
+//!
+//! A single TCP connection over a long, high-bandwidth path is often limited
+//! by its congestion window rather than by the link. When a tunnel negotiates
+//! striping, each visitor connection is carried by several data connections
+//! between the client and server. Data is cut into chunks that are sent round
+//! robin across the stripes, each with a small header, and the other side
+//! reads them back in the same order.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest number of data connections that a tunnel may stripe across.
+pub const MAX_STRIPES: u8 = 8;
+
+/// Largest payload carried by a single chunk.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Length of the chunk header: a sequence number and a payload length.
+const HEADER_SIZE: usize = 12;
+
+/// Copy data both ways between a local stream and the data connections of a
+/// proxied stream.
+///
+/// With a single data connection, bytes are copied as they are. With more,
+/// they are striped in chunks across all the data connections.
+pub async fn splice<L, S>(local: &mut L, data: Vec<S>) -> io::Result<()>
+where
+    L: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if data.len() == 1 {
+        let mut data = data.into_iter().next().unwrap();
+        tokio::io::copy_bidirectional(local, &mut data).await?;
+        return Ok(());
+    }
+
+    let stripes = data.len() as u64;
+    let mut readers = Vec::new();
+    let mut writers = Vec::new();
+    for stream in data {
+        let (reader, writer) = tokio::io::split(stream);
+        readers.push(reader);
+        writers.push(writer);
+    }
+    let (mut local_reader, mut local_writer) = tokio::io::split(local);
+
+    let send = async {
+        let mut buf = vec![0; HEADER_SIZE + CHUNK_SIZE];
+        let mut seq = 0u64;
+        loop {
+            let n = local_reader.read(&mut buf[HEADER_SIZE..]).await?;
+            if n == 0 {
+                break;
+            }
+            buf[..8].copy_from_slice(&seq.to_be_bytes());
+            buf[8..HEADER_SIZE].copy_from_slice(&(n as u32).to_be_bytes());
+            let writer = &mut writers[(seq % stripes) as usize];
+            writer.write_all(&buf[..HEADER_SIZE + n]).await?;
+            seq += 1;
+        }
+        for writer in &mut writers {
+            writer.shutdown().await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+
+    let receive = async {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut seq = 0u64;
+        loop {
+            let reader = &mut readers[(seq % stripes) as usize];
+            let mut header = [0; HEADER_SIZE];
+            if reader.read(&mut header[..1]).await? == 0 {
+                break;
+            }
+            reader.read_exact(&mut header[1..]).await?;
+            let chunk_seq = u64::from_be_bytes(header[..8].try_into().unwrap());
+            let len = u32::from_be_bytes(header[8..].try_into().unwrap()) as usize;
+            if chunk_seq != seq || len > CHUNK_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "striped chunk out of order",
+                ));
+            }
+            reader.read_exact(&mut buf[..len]).await?;
+            local_writer.write_all(&buf[..len]).await?;
+            seq += 1;
+        }
+        local_writer.shutdown().await
+    };
+
+    tokio::try_join!(send, receive)?;
+    Ok(())
+}
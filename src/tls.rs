@@ -0,0 +1,233 @@
+//! TLS for connections to the control port.
+//!
+//! Without TLS, frames on the control port are plaintext, so anyone on the
+//! path can read secrets, API keys, and tunnel metadata. When the server has a
+//! certificate, every connection to the control port is wrapped in TLS before
+//! the first frame, including data connections, which are accepted there too.
+//! Servers that require TLS also recognize plaintext clients, and tell them
+//! to enable it in a plaintext frame.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::{bail, Context as _, Result};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerName};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+use crate::broker::MEMORY_ADDR;
+use crate::multiplex::MuxStream;
+use crate::shared::{canonical_addr, NETWORK_TIMEOUT};
+use crate::websocket::WebSocket;
+
+/// Type of the record that every TLS connection starts with.
+const HANDSHAKE_RECORD: u8 = 0x16;
+
+/// Time that a client may take to start the TLS handshake, after which it is
+/// taken for a plaintext client waiting for the server to speak first.
+const HANDSHAKE_GRACE: Duration = Duration::from_secs(1);
+
+/// Connection to the control port, which may be encrypted.
+pub enum ControlStream {
+    /// Plaintext TCP connection.
+    Plain(TcpStream),
+
+    /// TCP connection wrapped in TLS.
+    Tls(Box<TlsStream<TcpStream>>),
+
+    /// Either of the above, carrying a WebSocket.
+    WebSocket(Box<WebSocket>),
+
+    /// Stream multiplexed with others over one of the above.
+    Mux(Box<MuxStream>),
+
+    /// Connection within the process, through a broker.
+    Memory(DuplexStream),
+}
+
+impl ControlStream {
+    /// Address of the other end of the connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ControlStream::Plain(stream) => stream.peer_addr().map(canonical_addr),
+            ControlStream::Tls(stream) => stream.get_ref().0.peer_addr().map(canonical_addr),
+            ControlStream::WebSocket(stream) => stream.get_ref().peer_addr(),
+            ControlStream::Mux(stream) => Ok(stream.peer_addr()),
+            ControlStream::Memory(_) => Ok(MEMORY_ADDR),
+        }
+    }
+}
+
+impl AsyncRead for ControlStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ControlStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ControlStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            ControlStream::WebSocket(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            ControlStream::Mux(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            ControlStream::Memory(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ControlStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ControlStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ControlStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            ControlStream::WebSocket(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            ControlStream::Mux(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            ControlStream::Memory(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ControlStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ControlStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            ControlStream::WebSocket(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            ControlStream::Mux(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            ControlStream::Memory(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ControlStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ControlStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            ControlStream::WebSocket(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            ControlStream::Mux(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            ControlStream::Memory(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Build a TLS acceptor for the server from PEM files.
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Build a TLS connector for the client.
+///
+/// The server's certificate is checked against the given PEM file of CA
+/// certificates if there is one, and against the usual public roots otherwise.
+pub fn connector(ca_path: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca_path {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("invalid CA certificate in {}", path.display()))?;
+            }
+        }
+        None => {
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }));
+        }
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// As the server, complete the TLS handshake on an incoming connection.
+pub async fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> Result<ControlStream> {
+    let stream = timeout(NETWORK_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("timed out waiting for TLS handshake")?
+        .context("TLS handshake failed")?;
+    Ok(ControlStream::Tls(Box::new(stream.into())))
+}
+
+/// As the server, check whether an incoming connection starts with a TLS
+/// handshake, without consuming any of it.
+pub async fn starts_handshake(stream: &TcpStream) -> bool {
+    let mut first = [0];
+    matches!(
+        timeout(HANDSHAKE_GRACE, stream.peek(&mut first)).await,
+        Ok(Ok(1)) if first[0] == HANDSHAKE_RECORD
+    )
+}
+
+/// As the client, complete the TLS handshake with a server at this host.
+pub async fn connect(
+    connector: &TlsConnector,
+    host: &str,
+    stream: TcpStream,
+) -> Result<ControlStream> {
+    let name = ServerName::try_from(host)
+        .with_context(|| format!("{host} is not a valid TLS server name"))?;
+    let stream = timeout(NETWORK_TIMEOUT, connector.connect(name, stream))
+        .await
+        .context("timed out waiting for TLS handshake")?
+        .context("TLS handshake failed")?;
+    Ok(ControlStream::Tls(Box::new(stream.into())))
+}
+
+/// As the client, the first message of a TLS handshake with a server at this
+/// host, for the server to route by its name without finishing the handshake.
+pub(crate) fn client_hello(host: &str) -> Result<Vec<u8>> {
+    let name = ServerName::try_from(host)
+        .with_context(|| format!("{host} is not a valid TLS server name"))?;
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+    let mut conn = rustls::ClientConnection::new(Arc::new(config), name)?;
+    let mut hello = Vec::new();
+    conn.write_tls(&mut hello)?;
+    Ok(hello)
+}
+
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", path.display());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+pub(crate) fn load_key(path: &Path) -> Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    for item in rustls_pemfile::read_all(&mut BufReader::new(file))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    bail!("no private key found in {}", path.display())
+}
@@ -0,0 +1,148 @@
+//! Tamper-evident transcripts of proxied connections, for audit trails.
+//!
+//! Each line of a transcript is a JSON record of one connection: who connected
+//! to which port, when, for how long, and SHA-256 digests of the bytes in each
+//! direction. Payloads are never stored. Every record includes the hash of the
+//! record before it, so editing or removing a line breaks the chain from that
+//! point on, which [`verify`] detects. Records removed from the end of the file
+//! leave a valid chain, so keep a copy of the latest hash elsewhere to detect
+//! truncation.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::integrity::StreamDigest;
+
+/// Previous hash of the first record in a transcript.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Record of one proxied connection in a transcript.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TranscriptEntry {
+    /// Position of the record in the transcript, starting at 0.
+    pub seq: u64,
+
+    /// Unix timestamp in seconds when the connection closed.
+    pub time: u64,
+
+    /// Identifier of the connection, as used in the server's logs.
+    pub id: Uuid,
+
+    /// Public port that the visitor connected to.
+    pub port: u16,
+
+    /// Address of the visitor.
+    pub peer: SocketAddr,
+
+    /// How long the connection was open, in milliseconds.
+    pub duration_ms: u64,
+
+    /// Bytes sent by the visitor to the client.
+    pub inbound: StreamDigest,
+
+    /// Bytes sent by the client to the visitor.
+    pub outbound: StreamDigest,
+
+    /// Hash of the previous record.
+    pub prev: String,
+
+    /// Hash of this record, computed with this field empty.
+    #[serde(default)]
+    pub hash: String,
+}
+
+impl TranscriptEntry {
+    fn compute_hash(&self) -> Result<String> {
+        let unhashed = TranscriptEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        Ok(hex::encode(Sha256::digest(serde_json::to_vec(&unhashed)?)))
+    }
+}
+
+struct Chain {
+    file: File,
+    seq: u64,
+    prev: String,
+}
+
+/// Append-only transcript file that the server records connections into.
+pub struct Transcript {
+    chain: Mutex<Chain>,
+}
+
+impl Transcript {
+    /// Open a transcript, continuing the chain of an existing file.
+    ///
+    /// The existing records are verified first, so that a server never
+    /// extends a transcript that has already been tampered with.
+    pub fn open(path: &Path) -> Result<Self> {
+        let last = if path.exists() { verify(path)? } else { None };
+        let (seq, prev) = match last {
+            Some(entry) => (entry.seq + 1, entry.hash),
+            None => (0, GENESIS.into()),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("could not open transcript {}", path.display()))?;
+        Ok(Self {
+            chain: Mutex::new(Chain { file, seq, prev }),
+        })
+    }
+
+    /// Append a record, filling in its position and hashes.
+    pub fn record(&self, mut entry: TranscriptEntry) -> Result<()> {
+        let mut chain = self.chain.lock().unwrap();
+        entry.seq = chain.seq;
+        entry.prev = chain.prev.clone();
+        entry.hash = entry.compute_hash()?;
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        chain.file.write_all(line.as_bytes())?;
+        chain.file.flush()?;
+        chain.seq += 1;
+        chain.prev = entry.hash;
+        Ok(())
+    }
+}
+
+/// Check the hash chain of a transcript, returning its last record.
+pub fn verify(path: &Path) -> Result<Option<TranscriptEntry>> {
+    let file = File::open(path)
+        .with_context(|| format!("could not open transcript {}", path.display()))?;
+    let mut prev = GENESIS.to_string();
+    let mut last = None;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let number = index + 1;
+        let entry: TranscriptEntry = serde_json::from_str(&line)
+            .with_context(|| format!("line {number} of the transcript is not a record"))?;
+        ensure!(
+            entry.seq == index as u64,
+            "line {number} of the transcript is out of sequence"
+        );
+        ensure!(
+            entry.prev == prev,
+            "line {number} of the transcript does not follow the previous record"
+        );
+        ensure!(
+            entry.hash == entry.compute_hash()?,
+            "line {number} of the transcript has been modified"
+        );
+        prev = entry.hash.clone();
+        last = Some(entry);
+    }
+    Ok(last)
+}
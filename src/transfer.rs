@@ -0,0 +1,124 @@
+//! Bytes transferred by tunnels, for quotas on them.
+//!
+//! The validation backend can cap how much data a user's tunnels move, in
+//! total or per calendar month, as plans with tiers of traffic do. Usage is
+//! counted per user and tunnel name, so that it carries over when a tunnel
+//! reconnects, and is kept in the state file across restarts.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+/// Period over which a transfer quota applies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferPeriod {
+    /// The quota covers all traffic, and never resets.
+    #[default]
+    Total,
+
+    /// The quota resets at the start of each calendar month, in UTC.
+    Monthly,
+}
+
+/// Bytes that a tunnel transferred, since the start of a month.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Bytes in both directions.
+    pub bytes: u64,
+
+    /// Month in which counting started, as counted by [`month_of`].
+    pub month: u32,
+}
+
+/// Usage of the tunnels with transfer quotas, by user and tunnel name.
+///
+/// ```
+/// use bore_cli::transfer::{TransferLedger, TransferPeriod};
+///
+/// let ledger = TransferLedger::default();
+/// let key = TransferLedger::key("acme", Some("web"));
+/// ledger.add(&key, 600, TransferPeriod::Total);
+/// assert_eq!(ledger.add(&key, 600, TransferPeriod::Total), 1200);
+/// assert_eq!(ledger.used(&TransferLedger::key("acme", None), TransferPeriod::Total), 0);
+/// ```
+#[derive(Debug, Default)]
+pub struct TransferLedger {
+    usage: DashMap<String, Usage>,
+}
+
+impl TransferLedger {
+    /// Key that usage of a user's tunnel is counted under. Unnamed tunnels
+    /// of a user share one.
+    pub fn key(user_id: &str, name: Option<&str>) -> String {
+        format!("{user_id}/{}", name.unwrap_or_default())
+    }
+
+    /// Bytes transferred under a key in the current period.
+    pub fn used(&self, key: &str, period: TransferPeriod) -> u64 {
+        self.add(key, 0, period)
+    }
+
+    /// Count bytes transferred under a key, returning the total in the
+    /// current period.
+    pub fn add(&self, key: &str, bytes: u64, period: TransferPeriod) -> u64 {
+        let month = current_month();
+        let mut usage = (self.usage)
+            .entry(key.to_string())
+            .or_insert(Usage { bytes: 0, month });
+        if period == TransferPeriod::Monthly && usage.month != month {
+            *usage = Usage { bytes: 0, month };
+        }
+        usage.bytes = usage.bytes.saturating_add(bytes);
+        usage.bytes
+    }
+
+    /// Usage under every key, to carry it across restarts.
+    pub fn snapshot(&self) -> Vec<(String, Usage)> {
+        (self.usage.iter())
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect()
+    }
+
+    /// Take over usage saved before a restart.
+    pub fn restore(&self, saved: Vec<(String, Usage)>) {
+        for (key, usage) in saved {
+            self.usage.insert(key, usage);
+        }
+    }
+}
+
+/// Month of the current time, as counted by [`month_of`].
+fn current_month() -> u32 {
+    let secs = (SystemTime::now().duration_since(UNIX_EPOCH)).map_or(0, |since| since.as_secs());
+    month_of(secs)
+}
+
+/// Calendar month in UTC of a time in seconds since the Unix epoch, counted
+/// as `year * 12 + month - 1`.
+///
+/// ```
+/// use bore_cli::transfer::month_of;
+///
+/// assert_eq!(month_of(0), 1970 * 12);
+/// assert_eq!(month_of(1_709_251_199), 2024 * 12 + 1); // 2024-02-29 23:59:59
+/// assert_eq!(month_of(1_709_251_200), 2024 * 12 + 2); // 2024-03-01 00:00:00
+/// ```
+pub fn month_of(secs: u64) -> u32 {
+    // Civil calendar from days, after Howard Hinnant's algorithm.
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year * 12 + month - 1) as u32
+}
@@ -0,0 +1,222 @@
+//! Forwarding of UDP datagrams through tunnels.
+//!
+//! UDP tunnels reuse the machinery of TCP tunnels. The server binds a UDP
+//! socket on the public port and keeps a table of peers, much like a NAT. The
+//! first datagram from a new peer opens a session, which is announced to the
+//! client like a new connection. Datagrams of the session are then carried over
+//! its data connection, each prefixed with its length. Sessions end after a
+//! period with no datagrams in either direction.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::interval;
+use tracing::debug;
+
+/// How long a session may go without datagrams before it is closed.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest payload of a UDP datagram.
+const MAX_DATAGRAM: usize = u16::MAX as usize;
+
+/// Number of datagrams from a peer to queue while its session is not ready.
+const QUEUE_LENGTH: usize = 64;
+
+/// Public UDP socket of a tunnel, with the table of sessions by peer.
+pub struct Relay {
+    socket: Arc<UdpSocket>,
+    sessions: Arc<DashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+    buf: Vec<u8>,
+}
+
+impl Relay {
+    /// Relay datagrams arriving on this socket.
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket: Arc::new(socket),
+            sessions: Arc::new(DashMap::new()),
+            buf: vec![0; MAX_DATAGRAM],
+        }
+    }
+
+    /// Local address of the public socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Wait for a datagram from a new peer, opening a session for it.
+    ///
+    /// Datagrams from peers with a session are passed on to that session in
+    /// the meantime, or dropped if it is falling behind. This is cancel safe.
+    pub async fn accept(&mut self) -> io::Result<(Session, SocketAddr)> {
+        loop {
+            let (len, peer) = self.socket.recv_from(&mut self.buf).await?;
+            let datagram = self.buf[..len].to_vec();
+            if let Some(session) = self.sessions.get(&peer) {
+                if session.try_send(datagram).is_err() {
+                    debug!(?peer, "dropped datagram for busy session");
+                }
+                continue;
+            }
+            let (sender, incoming) = mpsc::channel(QUEUE_LENGTH);
+            sender.try_send(datagram).expect("new queue has capacity");
+            self.sessions.insert(peer, sender.clone());
+            let session = Session {
+                peer,
+                socket: Arc::clone(&self.socket),
+                sessions: Arc::clone(&self.sessions),
+                sender,
+                incoming,
+            };
+            return Ok((session, peer));
+        }
+    }
+}
+
+/// Datagrams exchanged with one peer of a UDP tunnel.
+pub struct Session {
+    peer: SocketAddr,
+    socket: Arc<UdpSocket>,
+    sessions: Arc<DashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+    sender: mpsc::Sender<Vec<u8>>,
+    incoming: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Session {
+    /// Relay datagrams between the peer and a data connection, until either
+    /// side closes or the session times out.
+    pub async fn relay<S: AsyncRead + AsyncWrite + Unpin>(mut self, stream: S) -> io::Result<()> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let activity = Activity::new();
+        let outbound = async {
+            let mut buf = vec![0; MAX_DATAGRAM];
+            while let Some(len) = read_datagram(&mut reader, &mut buf).await? {
+                activity.touch();
+                self.socket.send_to(&buf[..len], self.peer).await?;
+            }
+            Ok::<_, io::Error>(())
+        };
+        let inbound = async {
+            while let Some(datagram) = self.incoming.recv().await {
+                activity.touch();
+                write_datagram(&mut writer, &datagram).await?;
+            }
+            Ok(())
+        };
+        tokio::select! {
+            result = outbound => result,
+            result = inbound => result,
+            _ = activity.idle(SESSION_TIMEOUT) => {
+                debug!(peer = ?self.peer, "UDP session timed out");
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // A newer session for the same peer may have replaced this one.
+        self.sessions
+            .remove_if(&self.peer, |_, sender| sender.same_channel(&self.sender));
+    }
+}
+
+/// Relay datagrams between a connected local socket and a data connection,
+/// until the data connection closes.
+pub async fn relay<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &UdpSocket,
+    stream: S,
+) -> io::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let outbound = async {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            match socket.recv(&mut buf).await {
+                Ok(len) => write_datagram(&mut writer, &buf[..len]).await?,
+                // An earlier datagram was refused by the local host, which
+                // should not end the session.
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    };
+    let inbound = async {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        while let Some(len) = read_datagram(&mut reader, &mut buf).await? {
+            if let Err(err) = socket.send(&buf[..len]).await {
+                if err.kind() != io::ErrorKind::ConnectionRefused {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    };
+    tokio::select! {
+        result = outbound => result,
+        result = inbound => result,
+    }
+}
+
+/// Read one length-prefixed datagram, or `None` at the end of the stream.
+async fn read_datagram<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> io::Result<Option<usize>> {
+    let mut header = [0; 2];
+    if reader.read(&mut header[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[1..]).await?;
+    let len = u16::from_be_bytes(header) as usize;
+    reader.read_exact(&mut buf[..len]).await?;
+    Ok(Some(len))
+}
+
+/// Write one datagram, prefixed with its length.
+async fn write_datagram<W: AsyncWrite + Unpin>(writer: &mut W, datagram: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(2 + datagram.len());
+    frame.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+    frame.extend_from_slice(datagram);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Time of the last datagram in a session.
+struct Activity {
+    start: Instant,
+    last_millis: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_millis: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let millis = self.start.elapsed().as_millis() as u64;
+        self.last_millis.store(millis, Ordering::Relaxed);
+    }
+
+    /// Wait until there has been no activity for this long.
+    async fn idle(&self, limit: Duration) {
+        let mut ticker = interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let last = Duration::from_millis(self.last_millis.load(Ordering::Relaxed));
+            if self.start.elapsed().saturating_sub(last) >= limit {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Parsing of human-friendly durations, sizes, and rates for command-line flags.
+//!
+//! All flags that take a timeout or a limit use these parsers, so that values
+//! like `30s`, `5m`, `1GiB`, or `10MBps` are accepted consistently. Output for
+//! people uses the matching formatters.
+
+use std::time::Duration;
+
+/// Split a value like `1.5GiB` into its number and unit suffix.
+fn split_number(input: &str) -> Result<(f64, &str), String> {
+    let input = input.trim();
+    let end = input
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(end);
+    if number.is_empty() {
+        return Err(format!("expected a number, found {input:?}"));
+    }
+    let number = number
+        .parse()
+        .map_err(|_| format!("invalid number {number:?}"))?;
+    Ok((number, unit.trim()))
+}
+
+/// Parse a duration such as `500ms`, `30s`, `5m`, `2h`, `1d`, or `1h30m`.
+///
+/// ```
+/// use std::time::Duration;
+/// use bore_cli::units::parse_duration;
+///
+/// assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+/// assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+/// assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+/// assert!(parse_duration("30").is_err());
+/// assert!(parse_duration("99999999999999999999d").is_err());
+/// assert!(parse_duration(&format!("{}s", "9".repeat(400))).is_err());
+/// assert!(parse_duration("10000000000000000000s 10000000000000000000s").is_err());
+/// ```
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let mut rest = input.trim();
+    if rest.is_empty() {
+        return Err("expected a duration, like 30s or 5m".into());
+    }
+    let mut total = Duration::ZERO;
+    while !rest.is_empty() {
+        let (number, tail) = split_number(rest)?;
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        let seconds = match unit.trim() {
+            "ms" => 0.001,
+            "s" | "sec" | "secs" => 1.0,
+            "m" | "min" | "mins" => 60.0,
+            "h" | "hr" | "hrs" => 3600.0,
+            "d" | "day" | "days" => 86400.0,
+            "" => {
+                return Err(format!(
+                    "missing unit in duration {input:?}, expected one of ms, s, m, h, d"
+                ))
+            }
+            unit => return Err(format!("unknown duration unit {unit:?}")),
+        };
+        let duration = Duration::try_from_secs_f64(number * seconds)
+            .map_err(|_| format!("duration {input:?} is too long"))?;
+        total = total
+            .checked_add(duration)
+            .ok_or_else(|| format!("duration {input:?} is too long"))?;
+        rest = tail.trim_start();
+    }
+    Ok(total)
+}
+
+/// Parse a byte size such as `512`, `64KiB`, `10MB`, or `1GiB`.
+///
+/// Units with an `i` are powers of 1024, and the others are powers of 1000.
+///
+/// ```
+/// use bore_cli::units::parse_size;
+///
+/// assert_eq!(parse_size("512"), Ok(512));
+/// assert_eq!(parse_size("10MB"), Ok(10_000_000));
+/// assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+/// assert!(parse_size("10 parsecs").is_err());
+/// assert!(parse_size("20000000TB").is_err());
+/// ```
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let (number, unit) = split_number(input)?;
+    let multiplier: u64 = match unit {
+        "" | "B" => 1,
+        "k" | "K" | "kB" | "KB" => 1000,
+        "M" | "MB" => 1000_u64.pow(2),
+        "G" | "GB" => 1000_u64.pow(3),
+        "T" | "TB" => 1000_u64.pow(4),
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        unit => return Err(format!("unknown size unit {unit:?}")),
+    };
+    let size = (number * multiplier as f64).round();
+    // `u64::MAX as f64` rounds up to 2^64, which is itself out of range.
+    if size >= u64::MAX as f64 {
+        return Err(format!("size {input:?} is too large"));
+    }
+    Ok(size as u64)
+}
+
+/// Parse a transfer rate in bytes per second, such as `10MBps` or `1MiB/s`.
+/// Rates are limits, so they cannot be zero.
+///
+/// ```
+/// use bore_cli::units::parse_rate;
+///
+/// assert_eq!(parse_rate("10MBps"), Ok(10_000_000));
+/// assert_eq!(parse_rate("1MiB/s"), Ok(1 << 20));
+/// assert!(parse_rate("10MB").is_err());
+/// assert!(parse_rate("0ps").is_err());
+/// assert!(parse_rate("0.0001KBps").is_err());
+/// ```
+pub fn parse_rate(input: &str) -> Result<u64, String> {
+    let trimmed = input.trim();
+    let size = trimmed
+        .strip_suffix("ps")
+        .or_else(|| trimmed.strip_suffix("/s"))
+        .ok_or_else(|| {
+            format!("expected a rate per second, like 10MBps or 1MiB/s, found {input:?}")
+        })?;
+    match parse_size(size)? {
+        0 => Err(format!("rate {input:?} is zero, leave it out for no limit")),
+        rate => Ok(rate),
+    }
+}
+
+/// Format a duration in the largest two units, such as `1h5m` or `42s`.
+///
+/// ```
+/// use std::time::Duration;
+/// use bore_cli::units::format_duration;
+///
+/// assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+/// assert_eq!(format_duration(Duration::from_secs(3900)), "1h5m");
+/// assert_eq!(format_duration(Duration::from_secs(90000)), "1d1h");
+/// ```
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let units = [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+    let Some(first) = units.iter().position(|(_, size)| secs >= *size) else {
+        return "0s".into();
+    };
+    let mut output = String::new();
+    let mut rest = secs;
+    for (unit, size) in &units[first..(first + 2).min(units.len())] {
+        let count = rest / size;
+        rest %= size;
+        if count > 0 || output.is_empty() {
+            output += &format!("{count}{unit}");
+        }
+    }
+    output
+}
+
+/// Format a byte size with a binary unit, such as `512B` or `1.5MiB`.
+///
+/// ```
+/// use bore_cli::units::format_size;
+///
+/// assert_eq!(format_size(512), "512B");
+/// assert_eq!(format_size(1536 * 1024), "1.5MiB");
+/// ```
+pub fn format_size(bytes: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in units {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    match unit {
+        "B" => format!("{bytes}B"),
+        _ => format!("{size:.1}{unit}"),
+    }
+}
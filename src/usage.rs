@@ -0,0 +1,252 @@
+//! Reports of the traffic of each API key, for billing.
+//!
+//! The server adds up the bytes and connections of the tunnels opened with
+//! each API key, and periodically posts them to a billing endpoint. Reports
+//! that cannot be delivered are kept and sent again, oldest first, once the
+//! endpoint is back, and are carried across restarts in the state file. Each
+//! report has a unique ID, so the endpoint can drop one that it receives
+//! twice.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, sleep, MissedTickBehavior};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::stats::TunnelStats;
+
+/// Default time between reports.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Shortest time between reports.
+pub const MIN_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most reports kept while the endpoint is down, beyond which the oldest are
+/// dropped.
+pub const MAX_BUFFERED_REPORTS: usize = 1440;
+
+/// Attempts to deliver a report before waiting for the next interval.
+const ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled after each failure.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Traffic of one API key during a report's period.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyUsage {
+    /// Digest of the API key, as the server logs it.
+    pub api_key_digest: String,
+
+    /// User that the validation backend named for the key, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+
+    /// Bytes sent by visitors to the key's tunnels.
+    pub bytes_in: u64,
+
+    /// Bytes sent to visitors by the key's tunnels.
+    pub bytes_out: u64,
+
+    /// Connections forwarded by the key's tunnels.
+    pub connections: u64,
+}
+
+/// Usage of every API key with traffic during one period.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    /// Unique ID of the report, to drop duplicates.
+    pub id: Uuid,
+
+    /// Unix timestamp in seconds when the period started.
+    pub start: u64,
+
+    /// Unix timestamp in seconds when the period ended.
+    pub end: u64,
+
+    /// Usage by API key.
+    pub usage: Vec<KeyUsage>,
+}
+
+/// Tunnel whose traffic is counted toward an API key.
+struct Tracked {
+    stats: Arc<TunnelStats>,
+    usage: KeyUsage,
+    reported: (u64, u64, u64),
+}
+
+/// Adds up usage by API key and delivers it to a billing endpoint.
+pub struct UsageReporter {
+    url: String,
+    client: reqwest::Client,
+    interval: Duration,
+    tunnels: Mutex<Vec<Tracked>>,
+    buffer: Mutex<VecDeque<UsageReport>>,
+    since: Mutex<u64>,
+}
+
+impl UsageReporter {
+    /// Report usage to an endpoint, which receives each report as JSON in a
+    /// POST request.
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("failed to create HTTP client"),
+            interval: REPORT_INTERVAL,
+            tunnels: Mutex::new(Vec::new()),
+            buffer: Mutex::new(VecDeque::new()),
+            since: Mutex::new(now()),
+        }
+    }
+
+    /// Set the time between reports, which is at least a second.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval.max(MIN_REPORT_INTERVAL);
+    }
+
+    /// Count the traffic of a tunnel toward an API key, until the tunnel and
+    /// all of its connections have closed.
+    pub fn track(&self, api_key_digest: &str, user_id: Option<&str>, stats: Arc<TunnelStats>) {
+        let usage = KeyUsage {
+            api_key_digest: api_key_digest.to_string(),
+            user_id: user_id.map(String::from),
+            ..Default::default()
+        };
+        self.tunnels.lock().unwrap().push(Tracked {
+            stats,
+            usage,
+            reported: (0, 0, 0),
+        });
+    }
+
+    /// Close the current period, adding a report of its usage to those
+    /// waiting to be delivered, unless there was none.
+    pub fn collect(&self) {
+        let mut usage: Vec<KeyUsage> = Vec::new();
+        self.tunnels.lock().unwrap().retain_mut(|tracked| {
+            let stats = &tracked.stats;
+            let counts = (stats.inbound(), stats.outbound(), stats.connections());
+            let (bytes_in, bytes_out, connections) = (
+                counts.0 - tracked.reported.0,
+                counts.1 - tracked.reported.1,
+                counts.2 - tracked.reported.2,
+            );
+            tracked.reported = counts;
+            if bytes_in + bytes_out + connections > 0 {
+                let key = &tracked.usage;
+                let index = match usage.iter().position(|entry| {
+                    entry.api_key_digest == key.api_key_digest && entry.user_id == key.user_id
+                }) {
+                    Some(index) => index,
+                    None => {
+                        usage.push(key.clone());
+                        usage.len() - 1
+                    }
+                };
+                let entry = &mut usage[index];
+                entry.bytes_in += bytes_in;
+                entry.bytes_out += bytes_out;
+                entry.connections += connections;
+            }
+            // Nothing else holds the counters once the tunnel has closed.
+            Arc::strong_count(stats) > 1
+        });
+        let end = now();
+        let start = std::mem::replace(&mut *self.since.lock().unwrap(), end);
+        if !usage.is_empty() {
+            self.buffer(vec![UsageReport {
+                id: Uuid::new_v4(),
+                start,
+                end,
+                usage,
+            }]);
+        }
+    }
+
+    /// Reports waiting to be delivered, oldest first.
+    pub fn pending(&self) -> Vec<UsageReport> {
+        self.buffer.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Add reports to those waiting to be delivered, such as those saved
+    /// before a restart.
+    pub fn buffer(&self, reports: Vec<UsageReport>) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.extend(reports);
+        let excess = buffer.len().saturating_sub(MAX_BUFFERED_REPORTS);
+        if excess > 0 {
+            warn!(
+                dropped = excess,
+                "too many undelivered usage reports, dropping the oldest"
+            );
+            buffer.drain(..excess);
+        }
+    }
+
+    /// Deliver the waiting reports in order, stopping at the first one that
+    /// the endpoint does not accept.
+    pub async fn flush(&self) -> Result<()> {
+        loop {
+            let Some(report) = self.buffer.lock().unwrap().front().cloned() else {
+                return Ok(());
+            };
+            self.deliver(&report).await?;
+            debug!(id = %report.id, keys = report.usage.len(), "delivered usage report");
+            let mut buffer = self.buffer.lock().unwrap();
+            if buffer.front().is_some_and(|front| front.id == report.id) {
+                buffer.pop_front();
+            }
+        }
+    }
+
+    /// Collect and deliver reports every interval, until the task is aborted.
+    pub async fn run(&self) {
+        let mut ticker = interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.collect();
+            if let Err(err) = self.flush().await {
+                let pending = self.buffer.lock().unwrap().len();
+                warn!(%err, pending, "could not deliver usage reports, will retry");
+            }
+        }
+    }
+
+    /// Post a report, retrying a few times.
+    async fn deliver(&self, report: &UsageReport) -> Result<()> {
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            match self.post(report).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt >= ATTEMPTS => return Err(err),
+                Err(err) => debug!(%err, attempt, "usage report failed, retrying"),
+            }
+            sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    async fn post(&self, report: &UsageReport) -> Result<()> {
+        let response = self.client.post(&self.url).json(report).send().await?;
+        let status = response.status();
+        ensure!(status.is_success(), "usage endpoint returned {status}");
+        Ok(())
+    }
+}
+
+/// Current Unix timestamp in seconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
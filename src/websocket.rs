@@ -0,0 +1,216 @@
+//! WebSocket transport for connections to the control port.
+//!
+//! Some networks only let HTTP traffic out, so clients can wrap every
+//! connection to the control port, including data connections, in a WebSocket.
+//! Frames are then carried as binary messages. Servers that allow this tell
+//! the two kinds of connection apart by their first bytes, since a WebSocket
+//! handshake always starts with an HTTP `GET` request.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::time::{timeout, timeout_at, Instant};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::shared::NETWORK_TIMEOUT;
+use crate::tls::ControlStream;
+
+/// How long to wait for the first bytes of a connection when sniffing for a
+/// WebSocket handshake. Clients of the plain protocol may wait for the server
+/// to speak first, so this delays them when WebSocket is enabled.
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Start of every WebSocket handshake.
+const UPGRADE_PREFIX: &[u8] = b"GET ";
+
+/// Connection to the control port that is carried in WebSocket messages.
+pub struct WebSocket {
+    inner: WebSocketStream<Prefixed<ControlStream>>,
+
+    /// Payload of the last message, of which `consumed` bytes have been read.
+    message: Vec<u8>,
+    consumed: usize,
+}
+
+impl WebSocket {
+    fn new(inner: WebSocketStream<Prefixed<ControlStream>>) -> Self {
+        Self {
+            inner,
+            message: Vec::new(),
+            consumed: 0,
+        }
+    }
+
+    /// The connection that carries the WebSocket.
+    pub fn get_ref(&self) -> &ControlStream {
+        &self.inner.get_ref().inner
+    }
+}
+
+/// As the client, open a WebSocket over a connection to the control port.
+pub async fn connect(stream: ControlStream, host: &str, port: u16) -> Result<ControlStream> {
+    let scheme = match stream {
+        ControlStream::Tls(_) => "wss",
+        _ => "ws",
+    };
+    let host = match host.contains(':') {
+        true => format!("[{host}]"),
+        false => host.to_string(),
+    };
+    let url = format!("{scheme}://{host}:{port}/");
+    let handshake = tokio_tungstenite::client_async(url, Prefixed::new(stream, Vec::new()));
+    let (inner, _) = timeout(NETWORK_TIMEOUT, handshake)
+        .await
+        .context("timed out waiting for WebSocket handshake")?
+        .context("WebSocket handshake failed")?;
+    Ok(ControlStream::WebSocket(Box::new(WebSocket::new(inner))))
+}
+
+/// As the server, accept a WebSocket if the client starts a handshake.
+///
+/// Returns the connection, along with any bytes of the plain protocol that
+/// were read while checking.
+pub async fn accept(mut stream: ControlStream) -> Result<(ControlStream, Vec<u8>)> {
+    let mut prefix = Vec::new();
+    let deadline = Instant::now() + SNIFF_TIMEOUT;
+    while prefix.len() < UPGRADE_PREFIX.len() && UPGRADE_PREFIX.starts_with(&prefix) {
+        let mut buf = [0; 4];
+        let wanted = UPGRADE_PREFIX.len() - prefix.len();
+        match timeout_at(deadline, stream.read(&mut buf[..wanted])).await {
+            Ok(Ok(0)) | Err(_) => return Ok((stream, prefix)),
+            Ok(Ok(n)) => prefix.extend_from_slice(&buf[..n]),
+            Ok(Err(err)) => return Err(err.into()),
+        }
+    }
+    if prefix != UPGRADE_PREFIX {
+        return Ok((stream, prefix));
+    }
+    let handshake = tokio_tungstenite::accept_async(Prefixed::new(stream, prefix));
+    let inner = timeout(NETWORK_TIMEOUT, handshake)
+        .await
+        .context("timed out waiting for WebSocket handshake")?
+        .context("WebSocket handshake failed")?;
+    Ok((
+        ControlStream::WebSocket(Box::new(WebSocket::new(inner))),
+        Vec::new(),
+    ))
+}
+
+fn into_io(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+impl AsyncRead for WebSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.consumed == this.message.len() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.message = data;
+                    this.consumed = 0;
+                }
+                // Pings are answered by the WebSocket itself.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Text(_))) => {
+                    let err = io::Error::new(io::ErrorKind::InvalidData, "unexpected text message");
+                    return Poll::Ready(Err(err));
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(tungstenite::Error::ConnectionClosed)) => return Poll::Ready(Ok(())),
+                Some(Err(err)) => return Poll::Ready(Err(into_io(err))),
+            }
+        }
+        let n = (this.message.len() - this.consumed).min(buf.remaining());
+        buf.put_slice(&this.message[this.consumed..this.consumed + n]);
+        this.consumed += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WebSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(into_io)?;
+        Pin::new(&mut this.inner)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(into_io)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(into_io)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.get_mut().inner).poll_close(cx)) {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) => Poll::Ready(Ok(())),
+            Err(err) => Poll::Ready(Err(into_io(err))),
+        }
+    }
+}
+
+/// Stream that replays some bytes that were already read from it.
+pub(crate) struct Prefixed<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub(crate) fn new(inner: S, prefix: Vec<u8>) -> Self {
+        Self { prefix, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.prefix.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = this.prefix.len().min(buf.remaining());
+        buf.put_slice(&this.prefix[..n]);
+        this.prefix.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
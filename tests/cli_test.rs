@@ -0,0 +1,181 @@
+//! Tests of the `bore` command line, run as a separate process.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bore_cli::auth::{AuthProvider, Authenticator, Principal};
+use bore_cli::shared::{AuthError, AuthErrorCode};
+use bore_cli::{credentials::Credentials, exit, server::Server};
+use futures_util::future::{self, BoxFuture, FutureExt};
+use lazy_static::lazy_static;
+use rstest::*;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio::time;
+
+lazy_static! {
+    /// Guard to make sure that tests are run serially, not concurrently.
+    static ref SERIAL_GUARD: Mutex<()> = Mutex::new(());
+}
+
+/// Accepts a secret of `"secret"`, or an API key of `"letmein"`.
+struct SecretOrKey;
+
+impl AuthProvider for SecretOrKey {
+    fn method(&self) -> &str {
+        "secret_or_key"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        challenge: &'a uuid::Uuid,
+        answer: &'a str,
+    ) -> BoxFuture<'a, Result<Principal, AuthError>> {
+        let secret = Authenticator::new("secret");
+        let result = match answer == "letmein" || secret.validate(challenge, answer) {
+            true => Ok(Principal::default()),
+            false => Err(AuthError::new(AuthErrorCode::Failed, "wrong credentials")),
+        };
+        future::ready(result).boxed()
+    }
+}
+
+/// Start a server, and a config directory with `"stored"` as the API key of
+/// `localhost`, as if saved by `bore login`.
+async fn setup() -> Result<PathBuf> {
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_auth_provider(Arc::new(SecretOrKey));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let dir = std::env::temp_dir().join(format!("bore-cli-{}", uuid::Uuid::new_v4()));
+    let mut credentials = Credentials::load(&dir.join("bore").join("credentials"))?;
+    credentials.store("localhost", "stored", false)?;
+    credentials.save()?;
+    Ok(dir)
+}
+
+/// Run `bore local` with these arguments, returning its exit code, or `None`
+/// once it opens the tunnel.
+async fn bore_local(config: &Path, args: &[&str]) -> Result<Option<i32>> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bore"))
+        .args(["local", "8000", "--to", "localhost"])
+        .args(args)
+        .env_clear()
+        .env("XDG_CONFIG_HOME", config)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut lines = BufReader::new(child.stdout.take().context("no stdout")?).lines();
+    let opened = time::timeout(Duration::from_secs(10), async {
+        while let Some(line) = lines.next_line().await? {
+            if line.contains("listening at") {
+                return Ok(true);
+            }
+        }
+        anyhow::Ok(false)
+    })
+    .await
+    .context("timed out waiting for bore")??;
+    if opened {
+        return Ok(None);
+    }
+    let status = child.wait().await?;
+    let mut stderr = String::new();
+    (child.stderr.take().context("no stderr")?)
+        .read_to_string(&mut stderr)
+        .await?;
+    eprintln!("{stderr}");
+    Ok(status.code())
+}
+
+#[rstest]
+#[case("--secret-file", "secret")]
+#[case("--secret-file", "secret\n")]
+#[case("--secret-file", "secret\r\n")]
+#[case("--api-key-file", "letmein\n")]
+#[tokio::test]
+async fn credential_file(#[case] flag: &str, #[case] contents: &str) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let config = setup().await?;
+
+    // The stored API key is wrong, so the tunnel only opens if the file
+    // takes its place, without the trailing newline.
+    let path = config.join("credential");
+    std::fs::write(&path, contents)?;
+    let path = path.display().to_string();
+    assert_eq!(bore_local(&config, &[flag, &path]).await?, None);
+    assert_eq!(
+        bore_local(&config, &[]).await?,
+        Some(exit::AUTH_FAILED.into())
+    );
+    Ok(())
+}
+
+#[rstest]
+#[case("--secret-file", "")]
+#[case("--secret-file", "\n")]
+#[case("--api-key-file", "\r\n")]
+#[tokio::test]
+async fn empty_credential_file(#[case] flag: &str, #[case] contents: &str) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let config = setup().await?;
+
+    let path = config.join("credential");
+    std::fs::write(&path, contents)?;
+    let path = path.display().to_string();
+    assert_eq!(
+        bore_local(&config, &[flag, &path]).await?,
+        Some(exit::USAGE.into())
+    );
+    Ok(())
+}
+
+#[rstest]
+#[case("--max-upload-rate")]
+#[case("--max-download-rate")]
+#[tokio::test]
+async fn zero_rate(#[case] flag: &str) -> Result<()> {
+    let config = std::env::temp_dir().join(format!("bore-cli-{}", uuid::Uuid::new_v4()));
+    assert_eq!(
+        bore_local(&config, &[flag, "0ps"]).await?,
+        Some(exit::USAGE.into())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn json_messages() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let config = setup().await?;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bore"))
+        .args(["local", "8000", "--to", "localhost", "--check-reachability"])
+        .args(["--message-format", "json"])
+        .env_clear()
+        .env("XDG_CONFIG_HOME", &config)
+        .env("BORE_API_KEY", "letmein")
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut lines = BufReader::new(child.stdout.take().context("no stdout")?).lines();
+    let mut ids = Vec::new();
+    time::timeout(Duration::from_secs(10), async {
+        while ids.len() < 2 {
+            let line = lines.next_line().await?.context("bore exited")?;
+            if let Ok(message) = serde_json::from_str::<serde_json::Value>(&line) {
+                ids.push(message["id"].as_str().unwrap_or_default().to_string());
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await
+    .context("timed out waiting for bore")??;
+    assert_eq!(ids, ["listening_at", "endpoint_reachable"]);
+    Ok(())
+}
@@ -1,12 +1,45 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{anyhow, Result};
-use bore_cli::{client::Client, server::Server, shared::CONTROL_PORT};
+use anyhow::{anyhow, Context, Result};
+use bore_cli::auth::{AuthProvider, OutagePolicy, Principal, Quota, ValidationRequestOptions};
+use bore_cli::client::{self, Client, ClientOptions, HostHeader, PortFallback, Session};
+use bore_cli::shared::{
+    AuthError, AuthErrorCode, ClientHello, ClientMessage, Delimited, ErrorCode, LocalTimeout,
+    Observation, ObserveRequest, Scope, ServerMessage, SubKeyRequest, TunnelClosed, TunnelRejected,
+    CONTROL_PORT, NETWORK_TIMEOUT, PROTOCOL_VERSION,
+};
+use bore_cli::{
+    access_log::{AccessEntry, AccessLog, Reason},
+    acl::{self, AccessList},
+    acme::{Acme, AcmeConfig, Challenge},
+    admin::{BulkResult, OpenTunnel, ServerSummary},
+    announce::Announce,
+    broker::Broker,
+    capture::{Capture, CaptureFormat, CaptureOptions, Direction, FlowRecord},
+    config::{ClientConfig, ServerConfig},
+    credentials::Credentials,
+    daemon::{self, Daemon, TunnelState},
+    doctor, exit,
+    handoff::Inherited,
+    identity::ServerIdentity,
+    inspect::{self, Exchange, Inspector},
+    jwt::JwtAuthenticator,
+    policy::Policy,
+    ports::{PortAllocator, PortSet, SequentialPorts},
+    proxy_protocol::ProxyProtocol,
+    server::Server,
+    tls,
+    usage::{UsageReport, UsageReporter},
+};
+use futures_util::future::{self, BoxFuture, FutureExt};
 use lazy_static::lazy_static;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
 use rstest::*;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::sync::Mutex;
 use tokio::time;
 
@@ -17,7 +50,7 @@ lazy_static! {
 
 /// Spawn the server, giving some time for the control port TcpListener to start.
 async fn spawn_server(secret: Option<&str>) {
-    tokio::spawn(Server::new(1024..=65535, secret).listen());
+    tokio::spawn(Server::new(1024..=65535, secret, None).listen());
     time::sleep(Duration::from_millis(50)).await;
 }
 
@@ -25,66 +58,3516 @@ async fn spawn_server(secret: Option<&str>) {
 async fn spawn_client(secret: Option<&str>) -> Result<(TcpListener, SocketAddr)> {
     let listener = TcpListener::bind("localhost:0").await?;
     let local_port = listener.local_addr()?.port();
-    let client = Client::new("localhost", local_port, "localhost", 0, secret).await?;
+    let client = Client::new("localhost", local_port, "localhost", 0, secret, None).await?;
     let remote_addr = ([127, 0, 0, 1], client.remote_port()).into();
     tokio::spawn(client.listen());
     Ok((listener, remote_addr))
 }
 
+/// Serve an API key validation backend that answers every key with this
+/// response, returning its URL and the number of requests it received.
+async fn spawn_validation_backend(
+    response: serde_json::Value,
+) -> Result<(String, Arc<AtomicUsize>)> {
+    use hyper::service::{make_service_fn, service_fn};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let body = response.to_string();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&requests);
+    let make_service = make_service_fn(move |_| {
+        let body = body.clone();
+        let counter = Arc::clone(&counter);
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let body = body.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(hyper::Response::new(hyper::Body::from(body)))
+                }
+            }))
+        }
+    });
+    tokio::spawn(hyper::Server::from_tcp(listener)?.serve(make_service));
+    Ok((format!("http://{addr}/validate"), requests))
+}
+
+#[rstest]
+#[tokio::test]
+async fn basic_proxy(#[values(None, Some(""), Some("abc"))] secret: Option<&str>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(secret).await;
+    let (listener, addr) = spawn_client(secret).await?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = [0u8; 11];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world");
+
+        stream.write_all(b"I can send a message too!").await?;
+        anyhow::Ok(())
+    });
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello world").await?;
+
+    let mut buf = [0u8; 25];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"I can send a message too!");
+
+    // Ensure that the client end of the stream is closed now.
+    assert_eq!(stream.read(&mut buf).await?, 0);
+
+    // Also ensure that additional connections do not produce any data.
+    let mut stream = TcpStream::connect(addr).await?;
+    assert_eq!(stream.read(&mut buf).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn compressed_control() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        compression: true,
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        listener.local_addr()?.port(),
+        "localhost",
+        options,
+    )
+    .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    let (mut cli, (mut srv, _)) = tokio::try_join!(TcpStream::connect(addr), listener.accept())?;
+    cli.write_all(b"compressed").await?;
+    let mut buf = [0u8; 10];
+    srv.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"compressed");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn checksummed_streams() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        checksums: true,
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        listener.local_addr()?.port(),
+        "localhost",
+        options,
+    )
+    .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    let (mut cli, (mut srv, _)) = tokio::try_join!(TcpStream::connect(addr), listener.accept())?;
+    cli.write_all(b"checksummed").await?;
+    let mut buf = [0u8; 11];
+    srv.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"checksummed");
+    srv.write_all(b"reply").await?;
+    let mut buf = [0u8; 5];
+    cli.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"reply");
+
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn websocket_transport(#[values(None, Some("abc"))] secret: Option<&str>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, secret, None);
+    server.enable_websocket();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Clients of either transport can use the same control port.
+    for websocket in [true, false] {
+        let listener = TcpListener::bind("localhost:0").await?;
+        let options = ClientOptions {
+            secret: secret.map(String::from),
+            websocket,
+            ..Default::default()
+        };
+        let client = Client::with_options(
+            "localhost",
+            listener.local_addr()?.port(),
+            "localhost",
+            options,
+        )
+        .await?;
+        let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+        tokio::spawn(client.listen());
+
+        let (mut cli, (mut srv, _)) =
+            tokio::try_join!(TcpStream::connect(addr), listener.accept())?;
+        cli.write_all(b"upgraded").await?;
+        let mut buf = [0u8; 8];
+        srv.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"upgraded");
+        srv.write_all(b"reply").await?;
+        drop(srv);
+        let mut buf = Vec::new();
+        cli.read_to_end(&mut buf).await?;
+        assert_eq!(buf, b"reply");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn named_tunnel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let named = |name: &str| ClientOptions {
+        name: Some(name.into()),
+        ..Default::default()
+    };
+    Client::with_options("localhost", 8000, "localhost", named("staging-api")).await?;
+    let result = Client::with_options("localhost", 8000, "localhost", named("bad name")).await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn udp_forwarding(#[values(false, true)] encryption: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let local = UdpSocket::bind("127.0.0.1:0").await?;
+    let options = ClientOptions {
+        udp: true,
+        encryption,
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "127.0.0.1",
+        local.local_addr()?.port(),
+        "localhost",
+        options,
+    )
+    .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    // Echo each datagram back, reversed.
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        while let Ok((len, peer)) = local.recv_from(&mut buf).await {
+            buf[..len].reverse();
+            local.send_to(&buf[..len], peer).await.ok();
+        }
+    });
+
+    // Each peer gets its own session, and datagrams keep their boundaries.
+    for _ in 0..2 {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        peer.connect(addr).await?;
+        for message in [&b"first"[..], b"second datagram"] {
+            peer.send(message).await?;
+            let mut buf = [0u8; 1024];
+            let len = time::timeout(Duration::from_secs(5), peer.recv(&mut buf)).await??;
+            let mut expected = message.to_vec();
+            expected.reverse();
+            assert_eq!(&buf[..len], expected);
+        }
+    }
+
+    Ok(())
+}
+
+#[rstest]
+#[case(4, false, false)]
+#[case(1, true, false)]
+#[case(3, true, false)]
+#[case(1, false, true)]
+#[case(3, true, true)]
+#[tokio::test]
+async fn large_transfer(
+    #[case] stripes: u8,
+    #[case] encryption: bool,
+    #[case] multiplex: bool,
+) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        stripes,
+        encryption,
+        multiplex,
+        checksums: true,
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        listener.local_addr()?.port(),
+        "localhost",
+        options,
+    )
+    .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    let (cli, (srv, _)) = tokio::try_join!(TcpStream::connect(addr), listener.accept())?;
+    let payload: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    let echo = tokio::spawn(async move {
+        let (mut reader, mut writer) = srv.into_split();
+        tokio::io::copy(&mut reader, &mut writer).await
+    });
+    let (mut reader, mut writer) = cli.into_split();
+    let sent = payload.clone();
+    let send = tokio::spawn(async move {
+        writer.write_all(&sent).await?;
+        writer.shutdown().await
+    });
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received).await?;
+    send.await??;
+    assert_eq!(echo.await??, payload.len() as u64);
+    assert!(received == payload, "stream was corrupted or reordered");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn upload_rate_limit() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        max_upload_rate: Some(8 * 1024),
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        listener.local_addr()?.port(),
+        "localhost",
+        options,
+    )
+    .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    let (mut cli, (mut srv, _)) = tokio::try_join!(TcpStream::connect(addr), listener.accept())?;
+    let start = time::Instant::now();
+    tokio::spawn(async move { srv.write_all(&[7; 24 * 1024]).await });
+    let mut buf = vec![0; 24 * 1024];
+    cli.read_exact(&mut buf).await?;
+
+    // A second's worth passes at once, and the rest at the limited rate.
+    assert!(start.elapsed() >= Duration::from_millis(1500));
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn doctor_checks(#[values(None, Some(1000))] max_segment_size: Option<u16>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let options = ClientOptions {
+        max_segment_size,
+        ..Default::default()
+    };
+    let diagnosis = doctor::diagnose("localhost", options).await;
+    assert!(diagnosis.passed(), "{:?}", diagnosis.checks);
+    assert_eq!(diagnosis.checks.len(), 3);
+    assert_eq!(diagnosis.suggested_segment_size, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiplexed_connections() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(Some("abc")).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        secret: Some("abc".into()),
+        multiplex: true,
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        listener.local_addr()?.port(),
+        "localhost",
+        options,
+    )
+    .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await?;
+                buf.reverse();
+                stream.write_all(&buf).await?;
+                anyhow::Ok(())
+            });
+        }
+    });
+
+    // Hold several connections open at once, so they share the connection.
+    let mut streams = Vec::new();
+    for _ in 0..8 {
+        streams.push(TcpStream::connect(addr).await?);
+    }
+    for (i, stream) in streams.iter_mut().enumerate() {
+        stream.write_all(&(i as u32).to_be_bytes()).await?;
+    }
+    for (i, stream) in streams.iter_mut().enumerate() {
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(buf, (i as u32).to_le_bytes());
+        assert_eq!(stream.read(&mut buf).await?, 0);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reachability_check() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    let options = ClientOptions {
+        check_reachability: true,
+        ..Default::default()
+    };
+    let mut client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    client.check_reachability().await?;
+
+    // The server answers the probe itself, so it is neither forwarded nor
+    // counted as a visitor.
+    let stats = client.stats();
+    tokio::spawn(client.listen());
+    assert!(time::timeout(Duration::from_millis(200), local.accept())
+        .await
+        .is_err());
+    assert_eq!(stats.connections(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reachability_probe_http() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.enable_http(48087, "tunnel.test");
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    let hello = ClientHello {
+        version: PROTOCOL_VERSION,
+        http: true,
+        subdomain: Some("app".into()),
+        ..Default::default()
+    };
+    conn.send(ClientMessage::HelloExt(hello)).await?;
+    let Some(ServerMessage::HelloExt(hello)) = conn.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+    assert!(hello.probes);
+
+    let visit = || async {
+        let mut visitor = TcpStream::connect("127.0.0.1:48087").await?;
+        let request = "GET / HTTP/1.1\r\nHost: app.tunnel.test:48087\r\n\r\n";
+        visitor.write_all(request.as_bytes()).await?;
+        anyhow::Ok(visitor)
+    };
+    let token = uuid::Uuid::new_v4();
+    conn.send(ClientMessage::Probe(token)).await?;
+    loop {
+        match conn.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Probe(confirmed)) => {
+                assert_eq!(confirmed, token);
+                break;
+            }
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+    let mut response = String::new();
+    visit().await?.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(response.contains(&format!("\r\nBore-Probe: {token}\r\n")));
+
+    // Only the one visit is answered, and the next is a visitor again.
+    let _visitor = visit().await?;
+    loop {
+        match conn.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(_)) => break,
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+    Ok(())
+}
+
+#[rstest]
+#[case(None, Some("my secret"))]
+#[case(Some("my secret"), None)]
+#[tokio::test]
+async fn mismatched_secret(
+    #[case] server_secret: Option<&str>,
+    #[case] client_secret: Option<&str>,
+) {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(server_secret).await;
+    assert!(spawn_client(client_secret).await.is_err());
+}
+
+#[rstest]
+#[case(Some("new"), true)]
+#[case(Some("old"), true)]
+#[case(Some("other"), false)]
+#[case(None, false)]
+#[tokio::test]
+async fn rotated_secrets(
+    #[case] client_secret: Option<&str>,
+    #[case] accepted: bool,
+) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let config = ServerConfig::parse("secret = \"new\"\nsecrets = { \"2024\" = \"old\" }")?;
+    tokio::spawn(Server::with_config(&config).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(spawn_client(client_secret).await.is_ok(), accepted);
+    Ok(())
+}
+
+#[rstest]
+#[case(false, AuthErrorCode::InvalidSecret)]
+#[case(true, AuthErrorCode::Failed)]
+#[tokio::test]
+async fn auth_error_reason(#[case] redact: bool, #[case] code: AuthErrorCode) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("server secret"), None);
+    server.set_redact_auth_errors(redact);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let err = match spawn_client(Some("client secret")).await {
+        Ok(_) => return Err(anyhow!("client should fail to authenticate")),
+        Err(err) => err,
+    };
+    let reason = err
+        .downcast_ref::<AuthError>()
+        .ok_or_else(|| anyhow!("expected an auth error, got {err:#}"))?;
+    assert_eq!(reason.code, code);
+
+    // Older clients stop at an error with the same message.
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    let Some(ServerMessage::Challenge(_)) = conn.recv_timeout().await? else {
+        panic!("expected a challenge");
+    };
+    conn.send(ClientMessage::Authenticate("wrong".into()))
+        .await?;
+    let Some(ServerMessage::Error(message)) = conn.recv_timeout().await? else {
+        panic!("expected an error");
+    };
+    assert_eq!(message, reason.to_string());
+    match conn.recv_timeout().await? {
+        Some(ServerMessage::AuthFailed(err)) => assert_eq!(err.code, code),
+        message => panic!("unexpected reply {message:?}"),
+    }
+    Ok(())
+}
+
+#[rstest]
+#[case(None, None)]
+#[case(None, Some("my secret"))]
+#[tokio::test]
+async fn require_auth_rejects_open_server(
+    #[case] server_secret: Option<&str>,
+    #[case] client_secret: Option<&str>,
+) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(server_secret).await;
+    let options = ClientOptions {
+        secret: client_secret.map(String::from),
+        require_auth: true,
+        ..Default::default()
+    };
+    assert!(
+        Client::with_options("localhost", 5000, "localhost", options)
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn pinned_server_identity() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let identity = ServerIdentity::generate()?;
+    let public_key = identity.public_key();
+    let mut server = Server::new(1024..=65535, Some("secret"), None);
+    server.set_identity(identity);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let connect = |server_key: String| {
+        let options = ClientOptions {
+            secret: Some("secret".into()),
+            server_key: Some(server_key),
+            ..Default::default()
+        };
+        Client::with_options("localhost", 5000, "localhost", options)
+    };
+    connect(public_key).await?;
+    let other_key = ServerIdentity::generate()?.public_key();
+    assert!(connect(other_key).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn relayed_identity_hides_credentials() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (url, _) = spawn_validation_backend(serde_json::json!({ "valid": true })).await?;
+    let identity = ServerIdentity::generate()?;
+    let public_key = identity.public_key();
+    let mut server = Server::new(1024..=65535, None, Some(url));
+    server.set_identity(identity);
+    server.set_bind_addr(Ipv4Addr::LOCALHOST.into());
+    tokio::spawn(server.listen());
+
+    // Someone in the middle relays the control connection to the server, and
+    // keeps what the client sends.
+    let relay = TcpListener::bind(("127.0.0.2", CONTROL_PORT)).await?;
+    let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+    tokio::spawn({
+        let sent = Arc::clone(&sent);
+        async move {
+            while let Ok((mut client, _)) = relay.accept().await {
+                let Ok(mut server) = TcpStream::connect(("127.0.0.1", CONTROL_PORT)).await else {
+                    continue;
+                };
+                let sent = Arc::clone(&sent);
+                tokio::spawn(async move {
+                    let (mut client_read, mut client_write) = client.split();
+                    let (mut server_read, mut server_write) = server.split();
+                    let upstream = async {
+                        let mut buf = [0; 4096];
+                        loop {
+                            let n = client_read.read(&mut buf).await?;
+                            if n == 0 {
+                                break;
+                            }
+                            sent.lock().unwrap().extend_from_slice(&buf[..n]);
+                            server_write.write_all(&buf[..n]).await?;
+                        }
+                        anyhow::Ok(())
+                    };
+                    let downstream = tokio::io::copy(&mut server_read, &mut client_write);
+                    let _ = tokio::join!(upstream, downstream);
+                });
+            }
+        }
+    });
+    time::sleep(Duration::from_millis(50)).await;
+
+    let api_key = "relayed-api-key-1234";
+    let connect = |server_key: Option<String>| {
+        let options = ClientOptions {
+            api_key: Some(api_key.into()),
+            server_key,
+            ..Default::default()
+        };
+        Client::with_options("localhost", 5000, "127.0.0.2", options)
+    };
+    let contains_key = |sent: &[u8]| sent.windows(api_key.len()).any(|w| w == api_key.as_bytes());
+
+    // The relay passes the server's proof along unchanged, so the client goes
+    // on, but its key is sealed for the server alone.
+    connect(Some(public_key)).await?;
+    assert!(!contains_key(&sent.lock().unwrap()));
+
+    // Without an identity check, the key is there for the relay to read.
+    connect(None).await?;
+    assert!(contains_key(&sent.lock().unwrap()));
+    Ok(())
+}
+
+/// Write a CA, and a certificate for localhost signed by it, to a new
+/// directory as `ca.pem`, `cert.pem`, and `key.pem`.
+fn write_tls_files() -> Result<std::path::PathBuf> {
+    let mut ca_params = CertificateParams::new(vec![]);
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params)?;
+    let cert = Certificate::from_params(CertificateParams::new(vec!["localhost".into()]))?;
+    let dir = std::env::temp_dir().join(format!("bore-tls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("ca.pem"), ca.serialize_pem()?)?;
+    std::fs::write(dir.join("cert.pem"), cert.serialize_pem_with_signer(&ca)?)?;
+    std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem())?;
+    Ok(dir)
+}
+
+#[tokio::test]
+async fn tls_control_channel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let dir = write_tls_files()?;
+    let mut server = Server::new(1024..=65535, Some("secret"), None);
+    server.set_tls(tls::acceptor(&dir.join("cert.pem"), &dir.join("key.pem"))?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        secret: Some("secret".into()),
+        tls: true,
+        tls_ca: Some(dir.join("ca.pem")),
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        listener.local_addr()?.port(),
+        "localhost",
+        options,
+    )
+    .await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    let (mut cli, (mut srv, _)) = tokio::try_join!(TcpStream::connect(addr), listener.accept())?;
+    cli.write_all(b"encrypted").await?;
+    let mut buf = [0u8; 9];
+    srv.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"encrypted");
+
+    // Clients without TLS, or that do not trust the certificate, cannot connect.
+    let plain = ClientOptions {
+        secret: Some("secret".into()),
+        ..Default::default()
+    };
+    assert!(Client::with_options("localhost", 5000, "localhost", plain)
+        .await
+        .is_err());
+    let untrusted = ClientOptions {
+        secret: Some("secret".into()),
+        tls: true,
+        tls_ca: Some(dir.join("cert.pem")),
+        ..Default::default()
+    };
+    assert!(
+        Client::with_options("localhost", 5000, "localhost", untrusted)
+            .await
+            .is_err()
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn require_tls(#[values(None, Some("secret"))] secret: Option<&str>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Without a certificate, the server refuses to start.
+    let mut server = Server::new(1024..=65535, secret, None);
+    server.set_require_tls(true);
+    assert!(server.listen().await.is_err());
+
+    let dir = write_tls_files()?;
+    let mut server = Server::new(1024..=65535, secret, None);
+    server.set_tls(tls::acceptor(&dir.join("cert.pem"), &dir.join("key.pem"))?);
+    server.set_require_tls(true);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Plaintext clients are told why they cannot connect, whether they speak
+    // first or wait for a challenge.
+    let plain = ClientOptions {
+        secret: secret.map(Into::into),
+        ..Default::default()
+    };
+    let err = match Client::with_options("localhost", 5000, "localhost", plain.clone()).await {
+        Ok(_) => return Err(anyhow!("plaintext client should be refused")),
+        Err(err) => err,
+    };
+    assert!(
+        format!("{err:#}").contains("server requires TLS"),
+        "{err:#}"
+    );
+
+    let options = ClientOptions {
+        tls: true,
+        tls_ca: Some(dir.join("ca.pem")),
+        ..plain
+    };
+    Client::with_options("localhost", 5000, "localhost", options).await?;
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn hardened_server() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let dir = write_tls_files()?;
+    let acceptor = tls::acceptor(&dir.join("cert.pem"), &dir.join("key.pem"))?;
+
+    // Hardened servers refuse to run without authentication.
+    let mut server = Server::new(1..=65535, None, None);
+    server.set_tls(acceptor.clone());
+    server.enable_hardening();
+    assert!(server.listen().await.is_err());
+
+    // Nor do they let clients without credentials in.
+    let mut server = Server::new(1..=65535, Some("secret"), None);
+    server.set_tls(acceptor.clone());
+    server.set_anonymous_min_port(40000);
+    server.enable_hardening();
+    assert!(server.listen().await.is_err());
+
+    let mut server = Server::new(1..=65535, Some("secret"), None);
+    server.set_tls(acceptor);
+    server.enable_hardening();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        secret: Some("secret".into()),
+        tls: true,
+        tls_ca: Some(dir.join("ca.pem")),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", options.clone()).await?;
+    assert!(client.remote_port() >= 1024);
+
+    // Privileged ports are off limits, even within the port range.
+    let privileged = ClientOptions {
+        port: 80,
+        ..options
+    };
+    assert!(
+        Client::with_options("localhost", 5000, "localhost", privileged)
+            .await
+            .is_err()
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_handshake_timeout(Duration::from_millis(200));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A silent connection is closed well before the first message would
+    // otherwise time out.
+    let mut conn = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+    let read = time::timeout(Duration::from_secs(1), conn.read(&mut [0; 1])).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_handshakes() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_max_handshakes(1);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A silent connection takes the only handshake slot.
+    let silent = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+    time::sleep(Duration::from_millis(50)).await;
+    assert!(spawn_client(None).await.is_err());
+
+    // Slots of finished handshakes are free again.
+    drop(silent);
+    time::sleep(Duration::from_millis(50)).await;
+    spawn_client(None).await?;
+    spawn_client(None).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn ban_after_failed_authentications() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"), None);
+    server.set_auto_ban(2, Duration::from_millis(500));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert!(spawn_client(Some("guess1")).await.is_err());
+    spawn_client(Some("secret")).await?;
+    assert!(spawn_client(Some("guess2")).await.is_err());
+
+    // Even the right secret is refused while the address is banned.
+    assert!(spawn_client(Some("secret")).await.is_err());
+    time::sleep(Duration::from_millis(600)).await;
+    spawn_client(Some("secret")).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_rate_per_ip() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_handshake_rate_per_ip(1.0, 2);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    spawn_client(None).await?;
+    spawn_client(None).await?;
+    assert!(spawn_client(None).await.is_err());
+    time::sleep(Duration::from_millis(1100)).await;
+    spawn_client(None).await?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn tunnel_open_hook() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let dir = std::env::temp_dir().join(format!("bore-hook-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let script = dir.join("hook.sh");
+    let output = dir.join("event");
+    std::fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\necho \"$BORE_EVENT $BORE_PORT\" > {}\n",
+            output.display()
+        ),
+    )?;
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_on_tunnel_open(script);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let client = Client::new("localhost", 5000, "localhost", 0, None, None).await?;
+    let expected = format!("tunnel_open {}\n", client.remote_port());
+    for _ in 0..50 {
+        if std::fs::read_to_string(&output).is_ok_and(|event| event == expected) {
+            std::fs::remove_dir_all(&dir)?;
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    Err(anyhow!("hook did not run"))
+}
+
+#[tokio::test]
+async fn delegated_sub_key() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"), None);
+    server.set_identity(ServerIdentity::generate()?);
+    server.enable_sub_keys();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let owner = ClientOptions {
+        secret: Some("secret".into()),
+        ..Default::default()
+    };
+    let request = SubKeyRequest {
+        ttl_secs: 60,
+        min_port: 40000,
+        max_port: 40100,
+        scope: Scope::Tunnel,
+    };
+    let sub_key = client::create_sub_key("localhost", &owner, request).await?;
+
+    let teammate = |port: u16, api_key: String| {
+        let options = ClientOptions {
+            port,
+            api_key: Some(api_key),
+            ..Default::default()
+        };
+        Client::with_options("localhost", 5000, "localhost", options)
+    };
+    let client = teammate(0, sub_key.clone()).await?;
+    assert!((40000..=40100).contains(&client.remote_port()));
+    assert!(teammate(8080, sub_key.clone()).await.is_err());
+    assert!(teammate(0, format!("{sub_key}x")).await.is_err());
+
+    // Sub-keys cannot be used to mint further sub-keys.
+    let sub_options = ClientOptions {
+        api_key: Some(sub_key),
+        ..Default::default()
+    };
+    let request = SubKeyRequest {
+        ttl_secs: 60,
+        min_port: 0,
+        max_port: 65535,
+        scope: Scope::Tunnel,
+    };
+    assert!(client::create_sub_key("localhost", &sub_options, request)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn observer_tunnel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"), None);
+    server.set_identity(ServerIdentity::generate()?);
+    server.enable_sub_keys();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let owner = ClientOptions {
+        secret: Some("secret".into()),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = Client::with_options("localhost", local_port, "localhost", owner.clone()).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let request = SubKeyRequest {
+        ttl_secs: 60,
+        min_port: port,
+        max_port: port,
+        scope: Scope::Observe,
+    };
+    let sub_key = client::create_sub_key("localhost", &owner, request).await?;
+    let watcher = ClientOptions {
+        api_key: Some(sub_key),
+        ..Default::default()
+    };
+    let observe = |port| ObserveRequest {
+        port,
+        samples: false,
+    };
+
+    // The sub-key only allows watching its port, not opening tunnels.
+    assert!(client::observe("localhost", &watcher, observe(port + 1))
+        .await
+        .is_err());
+    assert!(
+        Client::with_options("localhost", local_port, "localhost", watcher.clone())
+            .await
+            .is_err()
+    );
+    let mut observer = client::observe("localhost", &watcher, observe(port)).await?;
+
+    let mut visitor = TcpStream::connect(("127.0.0.1", port)).await?;
+    let (mut local, _) = listener.accept().await?;
+    let Some(Observation::Connection { id, peer }) = observer.next().await? else {
+        panic!("expected a connection event");
+    };
+    assert_eq!(peer, visitor.local_addr()?);
+
+    visitor.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    local.read_exact(&mut buf).await?;
+    drop((visitor, local));
+    match observer.next().await? {
+        Some(Observation::Closed { id: closed, .. }) => assert_eq!(closed, id),
+        event => panic!("expected the connection to close, got {event:?}"),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_rate_limit() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_handshake_rate(10.0, 1);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let _first = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+    let mut second = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    assert!(matches!(
+        second.recv_timeout().await?,
+        Some(ServerMessage::Busy(_))
+    ));
+
+    // Clients wait for a random delay and retry on their own.
+    Client::new("localhost", 5000, "localhost", 0, None, None).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_reconnects() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Stand in for a server that drops every control connection after the
+    // tunnel is opened, then goes away entirely.
+    let server = TcpListener::bind(("localhost", CONTROL_PORT)).await?;
+    let fake = tokio::spawn(async move {
+        let mut ports = Vec::new();
+        for _ in 0..2 {
+            let (stream, _) = server.accept().await?;
+            let mut conn = Delimited::new(stream);
+            match conn.recv_timeout().await? {
+                Some(ClientMessage::Hello(port)) => ports.push(port),
+                message => panic!("unexpected message {message:?}"),
+            }
+            conn.send(ServerMessage::Hello(40000)).await?;
+        }
+        anyhow::Ok(ports)
+    });
+
+    let options = ClientOptions {
+        reconnect: true,
+        max_retries: Some(1),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 5000, "localhost", options).await?;
+    assert_eq!(client.remote_port(), 40000);
+    let result = time::timeout(Duration::from_secs(10), client.listen()).await?;
+    assert!(
+        result.is_err(),
+        "client should give up once the server is gone"
+    );
+
+    // The client asked for the same port again when it reconnected.
+    assert_eq!(fake.await??, [0, 40000]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn heartbeat_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Stand in for a server that opens the tunnel, then goes silent without
+    // closing the connection.
+    let server = TcpListener::bind(("localhost", CONTROL_PORT)).await?;
+    let fake = tokio::spawn(async move {
+        let (stream, _) = server.accept().await?;
+        let mut conn = Delimited::new(stream);
+        conn.recv_timeout::<ClientMessage>().await?;
+        conn.send(ServerMessage::Hello(40000)).await?;
+        time::sleep(Duration::from_secs(10)).await;
+        anyhow::Ok(())
+    });
+
+    let mut client = Client::new("localhost", 5000, "localhost", 0, None, None).await?;
+    client.set_heartbeat_timeout(Duration::from_millis(500));
+    let result = time::timeout(Duration::from_secs(5), client.listen()).await?;
+    assert!(result.is_err(), "client should notice the silent server");
+    fake.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn port_fallback() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let taken = TcpListener::bind("0.0.0.0:0").await?;
+    let port = taken.local_addr()?.port();
+    let open = |port_fallback| {
+        let options = ClientOptions {
+            port,
+            port_fallback,
+            ..Default::default()
+        };
+        Client::with_options("localhost", 8000, "localhost", options)
+    };
+
+    assert!(open(PortFallback::Fail).await.is_err());
+    let client = open(PortFallback::Nearest).await?;
+    assert_ne!(client.remote_port(), port);
+    assert!(client.remote_port().abs_diff(port) <= 75);
+    let client = open(PortFallback::Any).await?;
+    assert_ne!(client.remote_port(), port);
+    let range = port.saturating_sub(50)..=port.saturating_add(50);
+    let client = open(PortFallback::Range(range.clone())).await?;
+    assert_ne!(client.remote_port(), port);
+    assert!(range.contains(&client.remote_port()));
+
+    Ok(())
+}
+
+#[rstest]
+#[case(40805, &[40804, 40806])]
+#[case(40800, &[40801])]
+#[case(40809, &[40808])]
+#[tokio::test]
+async fn nearest_port(#[case] requested: u16, #[case] expected: &[u16]) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    tokio::spawn(Server::new(40800..=40809, None, None).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The tunnel moves next to the taken port, without leaving the range at
+    // its edges.
+    let _taken = TcpListener::bind(("0.0.0.0", requested)).await?;
+    let options = ClientOptions {
+        port: requested,
+        port_fallback: PortFallback::Nearest,
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 8000, "localhost", options).await?;
+    let port = client.remote_port();
+    assert!(
+        expected.contains(&port),
+        "expected one of {expected:?}, got {port}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn port_reservation() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"), None);
+    server.set_heartbeat_interval(Duration::from_millis(100));
+    server.set_port_reservation(Duration::from_secs(60));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let open = |name: &str, port| {
+        let options = ClientOptions {
+            secret: Some("secret".into()),
+            name: Some(name.into()),
+            port,
+            ..Default::default()
+        };
+        Client::with_options("localhost", 8000, "localhost", options)
+    };
+    let port = open("web", 0).await?.remote_port();
+    // Give the server time to notice that the tunnel closed.
+    time::sleep(Duration::from_millis(500)).await;
+
+    // Other tunnels cannot take the port while it is reserved.
+    assert!(open("api", port).await.is_err());
+
+    // The same tunnel gets it back, even without asking for it.
+    assert_eq!(open("web", 0).await?.remote_port(), port);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn anonymous_port_reservation() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_heartbeat_interval(Duration::from_millis(100));
+    server.set_port_reservation(Duration::from_secs(60));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let open = || {
+        let options = ClientOptions {
+            name: Some("web".into()),
+            ..Default::default()
+        };
+        Client::with_options("127.0.0.1", 8000, "127.0.0.1", options)
+    };
+    let port = open().await?.remote_port();
+    time::sleep(Duration::from_millis(500)).await;
+
+    // Without credentials, a client from another address is someone else,
+    // even with the same tunnel name.
+    let stranger = |port| async move {
+        let socket = tokio::net::TcpSocket::new_v4()?;
+        socket.bind(([127, 0, 0, 2], 0).into())?;
+        let stream = socket
+            .connect(([127, 0, 0, 1], CONTROL_PORT).into())
+            .await?;
+        let mut conn = Delimited::new(stream);
+        let hello = ClientHello {
+            port,
+            version: PROTOCOL_VERSION,
+            name: Some("web".into()),
+            ..Default::default()
+        };
+        conn.send(ClientMessage::HelloExt(hello)).await?;
+        anyhow::Ok(conn.recv_timeout().await?)
+    };
+    match stranger(port).await? {
+        Some(ServerMessage::ErrorExt(err)) => assert_eq!(err.code, ErrorCode::PortUnavailable),
+        message => panic!("stranger took the reserved port: {message:?}"),
+    }
+    match stranger(0).await? {
+        Some(ServerMessage::HelloExt(hello)) => assert_ne!(hello.port, port),
+        message => panic!("unexpected reply {message:?}"),
+    }
+
+    assert_eq!(open().await?.remote_port(), port);
+    Ok(())
+}
+
+#[tokio::test]
+async fn throttled_tunnel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_heartbeat_interval(Duration::from_millis(100));
+    server.set_policy(Policy::compile("1500")?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    conn.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = conn.recv_timeout().await? else {
+        panic!("expected a hello");
+    };
+    let _first = TcpStream::connect(("localhost", port)).await?;
+    let _second = TcpStream::connect(("localhost", port)).await?;
+
+    // Heartbeats go on while the second visitor waits out the throttle.
+    let start = time::Instant::now();
+    let mut connections = Vec::new();
+    let mut heartbeats = 0;
+    while connections.len() < 2 {
+        match conn.recv_timeout().await? {
+            Some(ServerMessage::Connection(_)) => connections.push(start.elapsed()),
+            Some(ServerMessage::Heartbeat) if connections.len() == 1 => heartbeats += 1,
+            Some(ServerMessage::Heartbeat) => (),
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+    assert!(connections[1] - connections[0] >= Duration::from_millis(1400));
+    assert!(
+        heartbeats >= 5,
+        "only {heartbeats} heartbeats while throttled"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn inherited_listeners() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let control = std::net::TcpListener::bind(("0.0.0.0", CONTROL_PORT))?;
+    let tunnel = std::net::TcpListener::bind("0.0.0.0:0")?;
+    let port = tunnel.local_addr()?.port();
+    let mut server = Server::new(1024..=65535, None, None);
+    server.adopt_listeners(Inherited {
+        control: Some(control),
+        tunnels: [(port, tunnel)].into(),
+    });
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Visitors wait on the inherited port until its client reconnects.
+    let mut visitor = TcpStream::connect(("localhost", port)).await?;
+    visitor.write_all(b"hello").await?;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        port,
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        local.local_addr()?.port(),
+        "localhost",
+        options,
+    )
+    .await?;
+    assert_eq!(client.remote_port(), port);
+    tokio::spawn(client.listen());
+
+    let (mut stream, _) = local.accept().await?;
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[rstest]
+#[case(1, None)]
+#[case(PROTOCOL_VERSION, Some(ErrorCode::PortUnavailable))]
+#[tokio::test]
+async fn typed_errors(#[case] version: u32, #[case] code: Option<ErrorCode>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let taken = TcpListener::bind("0.0.0.0:0").await?;
+    let port = taken.local_addr()?.port();
+
+    // Older clients only get a message, newer ones also get a code.
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    let hello = ClientHello {
+        port,
+        version,
+        ..Default::default()
+    };
+    conn.send(ClientMessage::HelloExt(hello)).await?;
+    match (conn.recv_timeout().await?, code) {
+        (Some(ServerMessage::Error(message)), None) => assert_eq!(message, "port already in use"),
+        (Some(ServerMessage::ErrorExt(err)), Some(code)) => {
+            assert_eq!(err.code, code);
+            assert_eq!(err.message, "port already in use");
+        }
+        (message, _) => panic!("unexpected reply {message:?}"),
+    }
+
+    let options = ClientOptions {
+        port,
+        ..Default::default()
+    };
+    let err = Client::with_options("localhost", 8000, "localhost", options)
+        .await
+        .err()
+        .context("tunnel on a taken port should be rejected")?;
+    let rejected = err
+        .downcast_ref::<TunnelRejected>()
+        .context("not rejected")?;
+    assert_eq!(rejected.0.code, ErrorCode::PortUnavailable);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn shared_session() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(Some("secret")).await;
+    let options = ClientOptions {
+        secret: Some("secret".into()),
+        ..Default::default()
+    };
+    let session = Session::connect("localhost", &options).await?;
+    let options = ClientOptions {
+        session: Some(session),
+        ..options
+    };
+
+    let mut tunnels = Vec::new();
+    for _ in 0..3 {
+        let listener = TcpListener::bind("localhost:0").await?;
+        let local_port = listener.local_addr()?.port();
+        let client =
+            Client::with_options("localhost", local_port, "localhost", options.clone()).await?;
+        tunnels.push((listener, client.remote_port()));
+        tokio::spawn(client.listen());
+    }
+    for (listener, remote_port) in tunnels {
+        let mut cli = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+        let (mut srv, _) = listener.accept().await?;
+        cli.write_all(&remote_port.to_be_bytes()).await?;
+        let mut buf = [0u8; 2];
+        srv.read_exact(&mut buf).await?;
+        assert_eq!(u16::from_be_bytes(buf), remote_port);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn pending_queue() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_max_pending(1);
+    server.set_pending_timeout(Duration::from_millis(300));
+    let metrics = server.metrics();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A client that never accepts its connections.
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    conn.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = conn.recv_timeout().await? else {
+        panic!("expected a hello");
+    };
+
+    let mut waiting = TcpStream::connect(("127.0.0.1", port)).await?;
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(metrics.pending(), 1);
+
+    // The queue is full, so the next visitor is turned away right away.
+    let mut turned_away = TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut buf = [0u8; 1];
+    let read = time::timeout(Duration::from_millis(200), turned_away.read(&mut buf)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    // The first visitor is dropped once it has waited too long.
+    let read = time::timeout(Duration::from_secs(1), waiting.read(&mut buf)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    let rendered = metrics.render();
+    assert_eq!(metrics.pending(), 0);
+    assert!(rendered.contains("bore_pending_connections_total{outcome=\"queue_full\"} 1"));
+    assert!(rendered.contains("bore_pending_connections_total{outcome=\"accept_timeout\"} 1"));
+    Ok(())
+}
+
+/// Next visitor that the server asks a raw client to accept.
+async fn next_connection(conn: &mut Delimited<TcpStream>) -> Result<uuid::Uuid> {
+    loop {
+        match conn.recv_timeout().await? {
+            Some(ServerMessage::Connection(id)) => return Ok(id),
+            Some(ServerMessage::Heartbeat) => continue,
+            message => panic!("expected a connection, got {message:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn unmatched_accepts() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_pending_timeout(Duration::from_secs(1));
+    server.set_heartbeat_interval(Duration::from_millis(100));
+    let metrics = server.metrics();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    conn.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = conn.recv_timeout().await? else {
+        panic!("expected a hello");
+    };
+    let accept = |id| async move {
+        let mut data = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+        data.send(ClientMessage::Accept(id)).await?;
+        time::sleep(Duration::from_millis(50)).await;
+        Ok::<_, anyhow::Error>(data)
+    };
+
+    // A client that accepts too late finds that the visitor timed out.
+    let _late = TcpStream::connect(("127.0.0.1", port)).await?;
+    let id = next_connection(&mut conn).await?;
+    time::sleep(Duration::from_millis(1100)).await;
+    accept(id).await?;
+
+    // Accepting a visitor twice matches nothing the second time.
+    let _visitor = TcpStream::connect(("127.0.0.1", port)).await?;
+    let id = next_connection(&mut conn).await?;
+    let _data = accept(id).await?;
+    accept(id).await?;
+    accept(uuid::Uuid::new_v4()).await?;
+
+    // Visitors still waiting are dropped as soon as the tunnel closes.
+    let _abandoned = TcpStream::connect(("127.0.0.1", port)).await?;
+    next_connection(&mut conn).await?;
+    drop(conn);
+    time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(metrics.pending(), 0);
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("bore_pending_connections_total{outcome=\"accepted\"} 1"));
+    assert!(rendered.contains("bore_pending_connections_total{outcome=\"accept_timeout\"} 1"));
+    assert!(rendered.contains("bore_pending_connections_total{outcome=\"tunnel_closed\"} 1"));
+    assert!(rendered.contains("bore_unmatched_accepts_total{reason=\"expired\"} 1"));
+    assert!(rendered.contains("bore_unmatched_accepts_total{reason=\"duplicate\"} 1"));
+    assert!(rendered.contains("bore_unmatched_accepts_total{reason=\"unknown\"} 1"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn tunnel_metrics() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = Server::new(1024..=65535, None, None);
+    let metrics = server.metrics();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    for _ in 0..2 {
+        let (mut cli, (mut srv, _)) =
+            tokio::try_join!(TcpStream::connect(addr), listener.accept())?;
+        cli.write_all(&[0; 1000]).await?;
+        srv.read_exact(&mut [0; 1000]).await?;
+    }
+    time::sleep(Duration::from_millis(100)).await;
+
+    let port = addr.port();
+    let rendered = metrics.render();
+    let throughput = rendered
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix(&format!(
+                "bore_tunnel_throughput_bytes_per_second{{port=\"{port}\",window=\"1m\"}} "
+            ))
+        })
+        .ok_or_else(|| anyhow!("missing throughput in {rendered}"))?;
+    assert!(throughput.parse::<f64>()? > 0.0);
+    assert!(rendered.contains(&format!(
+        "bore_tunnel_connection_duration_seconds{{port=\"{port}\",quantile=\"0.95\"}}"
+    )));
+    Ok(())
+}
+
+#[tokio::test]
+async fn user_metrics() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (url, _) =
+        spawn_validation_backend(serde_json::json!({ "valid": true, "user_id": "acme" })).await?;
+    let server = Server::new(1024..=65535, None, Some(url));
+    let metrics = server.metrics();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let client = connect_to_local(local_port, "key").await?;
+    let port = client.remote_port();
+    let task = tokio::spawn(client.listen());
+    let (mut cli, (mut srv, _)) =
+        tokio::try_join!(TcpStream::connect(("127.0.0.1", port)), listener.accept())?;
+    cli.write_all(&[0; 1000]).await?;
+    srv.read_exact(&mut [0; 1000]).await?;
+    time::sleep(Duration::from_millis(100)).await;
+
+    let rendered = metrics.render();
+    assert!(rendered.contains(&format!(
+        "bore_tunnel_throughput_bytes_per_second{{port=\"{port}\",user=\"acme\",window=\"1m\"}}"
+    )));
+    assert!(rendered.contains("bore_user_bytes_total{user=\"acme\",direction=\"in\"} 1000"));
+
+    // Traffic of closed tunnels still counts toward the user.
+    task.abort();
+    drop((cli, srv));
+    let closed = || !metrics.render().contains(&format!("port=\"{port}\""));
+    for _ in 0..40 {
+        if closed() {
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(closed());
+    let rendered = metrics.render();
+    assert!(rendered.contains("bore_user_bytes_total{user=\"acme\",direction=\"in\"} 1000"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_api() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let admin_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_admin(admin_addr, "hunter2".into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    let (mut cli, (mut srv, _)) = tokio::try_join!(TcpStream::connect(addr), listener.accept())?;
+    cli.write_all(b"admin").await?;
+    let mut buf = [0u8; 5];
+    srv.read_exact(&mut buf).await?;
+
+    let http = reqwest::Client::new();
+    let url = |path: &str| format!("http://{admin_addr}{path}");
+    let response = http.get(url("/tunnels")).send().await?;
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let tunnels: Vec<OpenTunnel> = http
+        .get(url("/tunnels"))
+        .bearer_auth("hunter2")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0].port, addr.port());
+    assert_eq!(tunnels[0].connections, 1);
+    assert_eq!(tunnels[0].bytes_in, 5);
+    assert!(tunnels[0].throughput_1m > 0.0);
+    assert_eq!(tunnels[0].p95_duration_ms, None); // The connection is still open.
+
+    let summary: ServerSummary = http
+        .get(url("/config"))
+        .bearer_auth("hunter2")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!((summary.min_port, summary.max_port), (1024, 65535));
+    assert_eq!(summary.auth, "none");
+
+    let response = http
+        .delete(url(&format!("/tunnels/{}", addr.port())))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(TcpStream::connect(addr).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_sampling() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let admin_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let mut server = Server::new(1024..=65535, Some("secret"), None);
+    server.set_admin(admin_addr, "hunter2".into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(Some("secret")).await?;
+    let http = reqwest::Client::new();
+    let url = format!("http://{admin_addr}/samples/{}", addr.port());
+    let sample = |body: serde_json::Value| http.put(&url).bearer_auth("hunter2").json(&body).send();
+    let response = sample(serde_json::json!({ "bytes": 1 << 20 })).await?;
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    let response = sample(serde_json::json!({ "every": 1, "bytes": 4 })).await?;
+    assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+    let rules: Vec<serde_json::Value> = (http.get(format!("http://{admin_addr}/samples")))
+        .bearer_auth("hunter2")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(
+        rules,
+        [serde_json::json!({ "port": addr.port(), "every": 1, "bytes": 4 })]
+    );
+
+    let request = ObserveRequest {
+        port: addr.port(),
+        samples: true,
+    };
+    let options = ClientOptions {
+        secret: Some("secret".into()),
+        ..Default::default()
+    };
+    let mut observer = client::observe("localhost", &options, request).await?;
+    // Exchange a message each way over a new connection, returning the
+    // events that the observer sees for it.
+    async fn exchange(
+        listener: &TcpListener,
+        addr: SocketAddr,
+        observer: &mut client::Observer,
+    ) -> Result<Vec<Observation>> {
+        let mut visitor = TcpStream::connect(addr).await?;
+        let (mut local, _) = listener.accept().await?;
+        visitor.write_all(b"hello").await?;
+        let mut buf = [0; 5];
+        local.read_exact(&mut buf).await?;
+        local.write_all(b"hi").await?;
+        visitor.read_exact(&mut buf[..2]).await?;
+        drop((visitor, local));
+        let mut events = Vec::new();
+        loop {
+            let event = time::timeout(Duration::from_secs(3), observer.next())
+                .await??
+                .context("observer closed")?;
+            let closed = matches!(event, Observation::Closed { .. });
+            events.push(event);
+            if closed {
+                return Ok(events);
+            }
+        }
+    }
+
+    // Samples keep the first bytes in each direction, and no more.
+    let events = exchange(&listener, addr, &mut observer).await?;
+    let sample = events
+        .iter()
+        .find_map(|event| match event {
+            Observation::Sample {
+                inbound, outbound, ..
+            } => Some((inbound.clone(), outbound.clone())),
+            _ => None,
+        })
+        .context("connection was not sampled")?;
+    assert_eq!(sample, (hex::encode("hell"), hex::encode("hi")));
+
+    let response = http.delete(&url).bearer_auth("hunter2").send().await?;
+    assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+    let events = exchange(&listener, addr, &mut observer).await?;
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, Observation::Sample { .. })));
+    let response = http.delete(&url).bearer_auth("hunter2").send().await?;
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_bulk_actions() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let admin_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_admin(admin_addr, "hunter2".into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    let (_other_listener, other_addr) = spawn_client(None).await?;
+
+    let http = reqwest::Client::new();
+    let act = |body: serde_json::Value| {
+        http.post(format!("http://{admin_addr}/actions"))
+            .bearer_auth("hunter2")
+            .json(&body)
+            .send()
+    };
+    let response = act(serde_json::json!({ "select": {}, "action": "close" })).await?;
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    let body = serde_json::json!({
+        "select": { "ports": [addr.port()] },
+        "action": "set_group",
+        "group": "incident",
+    });
+    let result: BulkResult = act(body).await?.json().await?;
+    assert_eq!(result.ports, [addr.port()]);
+
+    // Visitors of a paused tunnel wait until it is resumed.
+    let body = serde_json::json!({ "select": { "group": "incident" }, "action": "pause" });
+    act(body).await?;
+    time::sleep(Duration::from_millis(600)).await;
+    let _visitor = TcpStream::connect(addr).await?;
+    let accepted = time::timeout(Duration::from_millis(700), listener.accept()).await;
+    assert!(accepted.is_err());
+    let body = serde_json::json!({ "select": { "group": "incident" }, "action": "resume" });
+    act(body).await?;
+    time::timeout(Duration::from_secs(2), listener.accept()).await??;
+
+    let body = serde_json::json!({
+        "select": { "group": "incident" },
+        "action": "rate_limit",
+        "bytes_per_second": 4096,
+    });
+    act(body).await?;
+    let tunnels: Vec<OpenTunnel> = http
+        .get(format!("http://{admin_addr}/tunnels"))
+        .bearer_auth("hunter2")
+        .send()
+        .await?
+        .json()
+        .await?;
+    let limited = tunnels.iter().find(|tunnel| tunnel.port == addr.port());
+    assert_eq!(limited.unwrap().bytes_per_second, Some(4096));
+
+    // Closing the group leaves other tunnels open.
+    let body = serde_json::json!({ "select": { "group": "incident" }, "action": "close" });
+    act(body).await?;
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(TcpStream::connect(addr).await.is_err());
+    assert!(TcpStream::connect(other_addr).await.is_ok());
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_key_quota() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (url, _) = spawn_validation_backend(serde_json::json!({
+        "valid": true,
+        "user_id": "acme",
+        "limits": { "max_tunnels": 1, "min_port": 40000, "max_port": 40100 },
+    }))
+    .await?;
+    tokio::spawn(Server::new(1024..=65535, None, Some(url)).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        api_key: Some("key".into()),
+        ..Default::default()
+    };
+    let open = || Client::with_options("localhost", 8000, "localhost", options.clone());
+    let first = open().await?;
+    assert!((40000..=40100).contains(&first.remote_port()));
+
+    let err = open().await.err().expect("quota allows one tunnel");
+    assert!(err.to_string().contains("tunnel quota exceeded"), "{err:#}");
+
+    // The slot is given back once the first tunnel closes.
+    drop(first);
+    let mut reopened = false;
+    for _ in 0..10 {
+        time::sleep(Duration::from_millis(300)).await;
+        if open().await.is_ok() {
+            reopened = true;
+            break;
+        }
+    }
+    assert!(reopened);
+    Ok(())
+}
+
+#[tokio::test]
+async fn credential_port_ranges() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (url, _) = spawn_validation_backend(serde_json::json!({
+        "valid": true,
+        "user_id": "acme",
+        "tier": "free",
+    }))
+    .await?;
+    let mut server = Server::new(1024..=65535, None, Some(url));
+    server.set_credential_port_range("tier:free", 40200..=40300);
+    server.set_credential_port_range("tier:pro", 1024..=65535);
+    server.set_credential_port_range("user:acme", 40250..=40400);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let open = |port| {
+        let options = ClientOptions {
+            api_key: Some("key".into()),
+            port,
+            ..Default::default()
+        };
+        Client::with_options("localhost", 8000, "localhost", options)
+    };
+    let client = open(0).await?;
+    assert!((40250..=40300).contains(&client.remote_port()));
+
+    let err = open(40200).await.err().context("port outside the tier")?;
+    let rejected = err
+        .downcast_ref::<TunnelRejected>()
+        .context("not rejected")?;
+    assert_eq!(rejected.0.code, ErrorCode::PortOutOfRange);
+    Ok(())
+}
+
+#[tokio::test]
+async fn disjoint_port_ranges() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_ports(PortSet::new(
+        vec![40500..=40509, 40600..=40609],
+        vec![40505..=40509, 40600..=40600],
+    ));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let open = |port| {
+        let options = ClientOptions {
+            port,
+            ..Default::default()
+        };
+        Client::with_options("localhost", 8000, "localhost", options)
+    };
+    let mut clients = Vec::new();
+    for _ in 0..8 {
+        let client = open(0).await?;
+        let port = client.remote_port();
+        assert!(matches!(port, 40500..=40504 | 40601..=40609), "{port}");
+        clients.push(client);
+    }
+    let taken: Vec<_> = clients.iter().map(Client::remote_port).collect();
+    let free = (40601..=40609).find(|port| !taken.contains(port)).unwrap();
+    assert_eq!(open(free).await?.remote_port(), free);
+
+    for port in [40505, 40600, 40550] {
+        let err = open(port).await.err().context("port is not allowed")?;
+        let rejected = err
+            .downcast_ref::<TunnelRejected>()
+            .context("not rejected")?;
+        assert_eq!(rejected.0.code, ErrorCode::PortOutOfRange);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn listener_classes() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let loopback = |last| IpAddr::from([127, 0, 0, last]);
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_tunnel_addrs(vec![loopback(1), loopback(3)]);
+    server.set_listener_class("internal", vec![loopback(2)]);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    let open = |listener_class: Option<&str>| {
+        let options = ClientOptions {
+            listener_class: listener_class.map(String::from),
+            ..Default::default()
+        };
+        Client::with_options("localhost", local_port, "localhost", options)
+    };
+    let reaches = |addr: IpAddr, port: u16| {
+        let local = &local;
+        async move {
+            let Ok(_stream) = TcpStream::connect((addr, port)).await else {
+                return false;
+            };
+            time::timeout(Duration::from_secs(1), local.accept())
+                .await
+                .is_ok()
+        }
+    };
+
+    let public = open(None).await?;
+    let port = public.remote_port();
+    tokio::spawn(public.listen());
+    assert!(reaches(loopback(1), port).await);
+    assert!(reaches(loopback(3), port).await);
+    assert!(!reaches(loopback(2), port).await);
+
+    let internal = open(Some("internal")).await?;
+    let port = internal.remote_port();
+    tokio::spawn(internal.listen());
+    assert!(reaches(loopback(2), port).await);
+    assert!(!reaches(loopback(1), port).await);
+
+    let err = open(Some("dmz")).await.err().context("unknown class")?;
+    let rejected = err
+        .downcast_ref::<TunnelRejected>()
+        .context("not rejected")?;
+    assert_eq!(rejected.0.code, ErrorCode::InvalidRequest);
+    Ok(())
+}
+
+#[tokio::test]
+async fn dual_stack() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_bind_addr(IpAddr::from(Ipv6Addr::UNSPECIFIED));
+    server.set_tunnel_addrs(vec![
+        Ipv4Addr::UNSPECIFIED.into(),
+        Ipv6Addr::UNSPECIFIED.into(),
+    ]);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The control port takes both, and hosts may be bracketed IPv6 literals.
+    let local = TcpListener::bind("[::1]:0").await?;
+    let local_port = local.local_addr()?.port();
+    let client = Client::new("[::1]", local_port, "127.0.0.1", 0, None, None).await?;
+    let port = client.remote_port();
+    tokio::spawn(client.listen());
+    let client = Client::new("[::1]", local_port, "[::1]", 0, None, None).await?;
+    tokio::spawn(client.listen());
+
+    for visitor in [
+        IpAddr::from(Ipv4Addr::LOCALHOST),
+        Ipv6Addr::LOCALHOST.into(),
+    ] {
+        let mut stream = TcpStream::connect((visitor, port)).await?;
+        let (mut conn, _) = time::timeout(Duration::from_secs(1), local.accept()).await??;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0; 5];
+        conn.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+    }
+    Ok(())
+}
+
+/// Sequential allocator that records the ports it is told were released.
+#[derive(Default)]
+struct RecordingAllocator {
+    inner: SequentialPorts,
+    released: std::sync::Mutex<Vec<u16>>,
+}
+
+impl PortAllocator for RecordingAllocator {
+    fn candidates(&self, ports: &PortSet, client: &str, count: usize) -> Vec<u16> {
+        self.inner.candidates(ports, client, count)
+    }
+
+    fn allocated(&self, port: u16) {
+        self.inner.allocated(port);
+    }
+
+    fn released(&self, port: u16) {
+        self.released.lock().unwrap().push(port);
+    }
+}
+
+#[tokio::test]
+async fn port_allocator() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let allocator = Arc::new(RecordingAllocator::default());
+    let mut server = Server::new(40700..=40709, None, None);
+    server.set_port_allocator(allocator.clone());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let first = Client::new("localhost", 8000, "localhost", 0, None, None).await?;
+    let second = Client::new("localhost", 8000, "localhost", 0, None, None).await?;
+    assert_eq!((first.remote_port(), second.remote_port()), (40700, 40701));
+
+    drop(first);
+    for _ in 0..40 {
+        if !allocator.released.lock().unwrap().is_empty() {
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(*allocator.released.lock().unwrap(), [40700]);
+
+    let third = Client::new("localhost", 8000, "localhost", 0, None, None).await?;
+    assert_eq!(third.remote_port(), 40702);
+    Ok(())
+}
+
+#[tokio::test]
+async fn transfer_quota() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (url, _) = spawn_validation_backend(serde_json::json!({
+        "valid": true,
+        "user_id": "acme",
+        "limits": { "max_transfer_bytes": 1000 },
+    }))
+    .await?;
+    tokio::spawn(Server::new(1024..=65535, None, Some(url)).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        api_key: Some("key".into()),
+        ..Default::default()
+    };
+    let local_port = local.local_addr()?.port();
+    let open = || Client::with_options("localhost", local_port, "localhost", options.clone());
+    let client = open().await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    let tunnel = tokio::spawn(client.listen());
+    tokio::spawn(async move {
+        let (mut stream, _) = local.accept().await?;
+        tokio::io::copy(&mut tokio::io::repeat(b'x'), &mut stream).await?;
+        anyhow::Ok(())
+    });
+
+    // The tunnel and its connection are cut off once the quota is used up.
+    let (mut stream, mut sink) = (TcpStream::connect(addr).await?, tokio::io::sink());
+    let copy = tokio::io::copy(&mut stream, &mut sink);
+    let received = time::timeout(Duration::from_secs(5), copy).await??;
+    assert!(received >= 1000);
+    let err = time::timeout(Duration::from_secs(5), tunnel)
+        .await??
+        .unwrap_err();
+    let closed = err
+        .downcast_ref::<TunnelClosed>()
+        .expect("tunnel closed for good");
+    assert_eq!(closed.0.code, ErrorCode::QuotaExceeded);
+
+    let err = open().await.err().expect("quota is used up");
+    assert!(
+        err.to_string().contains("transfer quota exceeded"),
+        "{err:#}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn usage_reports() -> Result<()> {
+    use hyper::service::{make_service_fn, service_fn};
+    use sha2::{Digest, Sha256};
+
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // The billing endpoint is down until told otherwise.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let usage_url = format!("http://{}/usage", listener.local_addr()?);
+    let down = Arc::new(AtomicBool::new(true));
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let (state, received) = (Arc::clone(&down), Arc::clone(&reports));
+    let make_service = make_service_fn(move |_| {
+        let (down, received) = (Arc::clone(&state), Arc::clone(&received));
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(move |request: hyper::Request<_>| {
+                let (down, received) = (Arc::clone(&down), Arc::clone(&received));
+                async move {
+                    let body = hyper::body::to_bytes(request.into_body()).await?;
+                    let mut response = hyper::Response::new(hyper::Body::empty());
+                    if down.load(Ordering::Relaxed) {
+                        *response.status_mut() = hyper::StatusCode::SERVICE_UNAVAILABLE;
+                    } else {
+                        let report: UsageReport = serde_json::from_slice(&body).unwrap();
+                        received.lock().await.push(report);
+                    }
+                    Ok::<_, hyper::Error>(response)
+                }
+            }))
+        }
+    });
+    tokio::spawn(hyper::Server::from_tcp(listener)?.serve(make_service));
+
+    let (url, _) = spawn_validation_backend(serde_json::json!({
+        "valid": true,
+        "user_id": "acme",
+    }))
+    .await?;
+    let mut server = Server::new(1024..=65535, None, Some(url));
+    let mut reporter = UsageReporter::new(usage_url);
+    reporter.set_interval(Duration::from_secs(1));
+    server.set_usage_reporter(reporter);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        api_key: Some("key".into()),
+        ..Default::default()
+    };
+    let local_port = local.local_addr()?.port();
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+    tokio::spawn(async move {
+        let (mut stream, _) = local.accept().await?;
+        let mut buf = [0u8; 11];
+        stream.read_exact(&mut buf).await?;
+        stream.write_all(b"I can send a message too!").await?;
+        anyhow::Ok(())
+    });
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello world").await?;
+    let mut buf = [0u8; 25];
+    stream.read_exact(&mut buf).await?;
+    drop(stream);
+
+    // Reports are kept while the endpoint is down, then delivered.
+    time::sleep(Duration::from_secs(3)).await;
+    assert!(reports.lock().await.is_empty());
+    down.store(false, Ordering::Relaxed);
+    for _ in 0..50 {
+        if !reports.lock().await.is_empty() {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    let reports = reports.lock().await;
+    let usage: Vec<_> = reports.iter().flat_map(|report| &report.usage).collect();
+    assert_eq!(usage.len(), 1, "{reports:?}");
+    assert_eq!(usage[0].api_key_digest, hex::encode(Sha256::digest("key")));
+    assert_eq!(usage[0].user_id.as_deref(), Some("acme"));
+    assert_eq!((usage[0].bytes_in, usage[0].bytes_out), (11, 25));
+    assert_eq!(usage[0].connections, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn idle_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_idle_timeout(Duration::from_secs(2));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let _ = stream.write_all(b"hi").await;
+        }
+    });
+    let options = ClientOptions {
+        reconnect: true,
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 0, "localhost", options).await?;
+    let idle_addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    let idle = tokio::spawn(client.listen());
+
+    // Visitors keep the first tunnel open while the second one sits idle.
+    for _ in 0..6 {
+        time::sleep(Duration::from_millis(500)).await;
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await?;
+    }
+    let err = time::timeout(Duration::from_secs(2), idle)
+        .await??
+        .unwrap_err();
+    let closed = err
+        .downcast_ref::<TunnelClosed>()
+        .expect("tunnel closed for good");
+    assert_eq!(closed.0.code, ErrorCode::IdleTimeout);
+    assert_eq!(exit::for_error(&err), exit::TUNNEL_CLOSED);
+    assert!(TcpStream::connect(idle_addr).await.is_err());
+    assert!(TcpStream::connect(addr).await.is_ok());
+    Ok(())
+}
+
+#[tokio::test]
+async fn anonymous_tunnel_limits() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_anonymous_tunnel_ttl(Duration::from_secs(1));
+    server.set_max_anonymous_tunnels(1);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        reconnect: true,
+        ..Default::default()
+    };
+    let open = || Client::with_options("localhost", 0, "localhost", options.clone());
+    let first = tokio::spawn(open().await?.listen());
+    let err = open()
+        .await
+        .err()
+        .expect("one anonymous tunnel per address");
+    assert!(
+        err.to_string().contains("too many anonymous tunnels"),
+        "{err:#}"
+    );
+
+    let err = time::timeout(Duration::from_secs(3), first)
+        .await??
+        .unwrap_err();
+    let closed = err
+        .downcast_ref::<TunnelClosed>()
+        .expect("tunnel closed for good");
+    assert_eq!(closed.0.code, ErrorCode::Expired);
+
+    // The address may open another tunnel once the first one has expired.
+    time::sleep(Duration::from_millis(100)).await;
+    open().await?;
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn semi_public_server(#[values(None, Some("web"))] name: Option<&str>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("s3cret"), None);
+    server.set_anonymous_min_port(50000);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Clients without credentials get a random high port, and can use it.
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        name: name.map(String::from),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    let port = client.remote_port();
+    assert!(port >= 50000);
+    tokio::spawn(client.listen());
+    let (mut cli, (mut srv, _)) =
+        tokio::try_join!(TcpStream::connect(("127.0.0.1", port)), listener.accept())?;
+    cli.write_all(b"hello").await?;
+    let mut buf = [0; 5];
+    srv.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    // Specific ports require credentials.
+    let options = ClientOptions {
+        port: 40500,
+        ..Default::default()
+    };
+    let err = Client::with_options("localhost", 8000, "localhost", options)
+        .await
+        .err()
+        .context("specific port without credentials")?;
+    assert!(err.to_string().contains("without credentials"), "{err:#}");
+    let client = Client::new("localhost", 8000, "localhost", 40500, Some("s3cret"), None).await?;
+    assert_eq!(client.remote_port(), 40500);
+
+    // Wrong credentials are still turned away.
+    assert!(
+        Client::new("localhost", 8000, "localhost", 0, Some("wrong"), None)
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[cfg(feature = "conformance")]
+#[rstest]
+#[tokio::test]
+async fn conformance_suite(#[values(None, Some("abc"))] secret: Option<&str>) -> Result<()> {
+    use bore_cli::conformance::{Outcome, Target};
+
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(secret).await;
+    let target = match secret {
+        Some(secret) => Target::new("localhost").with_secret(secret),
+        None => Target::new("localhost"),
+    };
+    let report = target.run().await;
+    assert!(report.passed(), "{report}");
+    let skipped = report
+        .checks
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, Outcome::Skipped(_)))
+        .count();
+    assert_eq!(skipped, if secret.is_some() { 0 } else { 1 });
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn cached_api_key_validation(#[values(true, false)] valid: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (url, requests) = spawn_validation_backend(serde_json::json!({ "valid": valid })).await?;
+    tokio::spawn(Server::new(1024..=65535, None, Some(url)).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        api_key: Some("key".into()),
+        ..Default::default()
+    };
+    for _ in 0..3 {
+        let result = Client::with_options("localhost", 8000, "localhost", options.clone()).await;
+        assert_eq!(result.is_ok(), valid);
+    }
+    assert_eq!(requests.load(Ordering::Relaxed), 1);
+    Ok(())
+}
+
+#[rstest]
+#[case(None, true)]
+#[case(Some(1024), false)]
+#[tokio::test]
+async fn max_frame_length(#[case] max_length: Option<usize>, #[case] accepted: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (url, _) = spawn_validation_backend(serde_json::json!({ "valid": true })).await?;
+    let mut server = Server::new(1024..=65535, None, Some(url));
+    if let Some(max_length) = max_length {
+        server.set_max_frame_length(max_length);
+    }
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Long tokens, such as JWTs with many claims, fit within the default.
+    let options = ClientOptions {
+        api_key: Some("k".repeat(2000)),
+        ..Default::default()
+    };
+    let result = Client::with_options("localhost", 8000, "localhost", options).await;
+    assert_eq!(result.is_ok(), accepted);
+    Ok(())
+}
+
+#[rstest]
+#[case(None)]
+#[case(Some(1024))]
+#[tokio::test]
+async fn longest_hello_fits(#[case] max_length: Option<usize>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    if let Some(max_length) = max_length {
+        server.set_max_frame_length(max_length);
+    }
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // As many address rules as a tunnel may have, at their longest.
+    let rule = "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ff00/120";
+    let options = ClientOptions {
+        allow_ips: vec![rule.parse().map_err(|err: String| anyhow!(err))?; acl::MAX_RULES],
+        ..Default::default()
+    };
+    let result = Client::with_options("localhost", 8000, "localhost", options).await;
+    match max_length {
+        None => assert!(result.is_ok()),
+        // A server with a lower limit says why it closes the connection.
+        Some(max_length) => {
+            let err = result.err().context("frame was accepted")?;
+            let expected = format!("frame exceeds the maximum length of {max_length} bytes");
+            assert!(format!("{err:#}").contains(&expected), "{err:#}");
+        }
+    }
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn login_stores_api_key(#[values(true, false)] valid: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (url, _) = spawn_validation_backend(serde_json::json!({ "valid": valid })).await?;
+    tokio::spawn(Server::new(1024..=65535, None, Some(url)).listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        api_key: Some("key".into()),
+        ..Default::default()
+    };
+    let result = client::verify_credentials("localhost", &options).await;
+    assert_eq!(result.is_ok(), valid);
+
+    let dir = std::env::temp_dir().join(format!("bore-login-{}", uuid::Uuid::new_v4()));
+    let path = dir.join("credentials");
+    let mut stored = Credentials::load(&path)?;
+    assert_eq!(stored.api_key("localhost")?, None);
+    stored.store("localhost", "key", false)?;
+    stored.save()?;
+
+    let stored = Credentials::load(&path)?;
+    assert_eq!(stored.api_key("localhost")?.as_deref(), Some("key"));
+    assert_eq!(stored.api_key("bore.pub")?, None);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn session_token_accepts(#[values(false, true)] session_tokens: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (url, requests) = spawn_validation_backend(serde_json::json!({ "valid": true })).await?;
+    let mut server = Server::new(1024..=65535, None, Some(url));
+    server.set_validation_cache_ttl(Duration::ZERO, Duration::ZERO);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        api_key: Some("key".into()),
+        session_tokens,
+        ..Default::default()
+    };
+    let local_port = listener.local_addr()?.port();
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    for _ in 0..3 {
+        let (mut cli, (mut srv, _)) =
+            tokio::try_join!(TcpStream::connect(addr), listener.accept())?;
+        cli.write_all(b"token").await?;
+        let mut buf = [0; 5];
+        srv.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"token");
+    }
+    // Only the control connection is checked with the backend.
+    let expected = if session_tokens { 1 } else { 4 };
+    assert_eq!(requests.load(Ordering::Relaxed), expected);
+    Ok(())
+}
+
+/// Start a validation backend that accepts every key while it is up, and
+/// fails with 503 otherwise.
+fn spawn_flaky_backend(up: Arc<AtomicBool>) -> Result<String> {
+    use hyper::service::{make_service_fn, service_fn};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let make_service = make_service_fn(move |_| {
+        let up = Arc::clone(&up);
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(move |_| {
+                let response = match up.load(Ordering::Relaxed) {
+                    true => hyper::Response::new(hyper::Body::from(r#"{"valid":true}"#)),
+                    false => hyper::Response::builder()
+                        .status(503)
+                        .body(hyper::Body::empty())
+                        .unwrap(),
+                };
+                async move { Ok::<_, std::convert::Infallible>(response) }
+            }))
+        }
+    });
+    tokio::spawn(hyper::Server::from_tcp(listener)?.serve(make_service));
+    Ok(format!("http://{addr}/validate"))
+}
+
+/// Open a tunnel authenticated with an API key.
+async fn connect_with_key(key: &str) -> Result<Client> {
+    connect_to_local(8000, key).await
+}
+
+/// Open a tunnel to a local port, authenticated with an API key.
+async fn connect_to_local(local_port: u16, key: &str) -> Result<Client> {
+    let options = ClientOptions {
+        api_key: Some(key.into()),
+        ..Default::default()
+    };
+    Client::with_options("localhost", local_port, "localhost", options).await
+}
+
+#[tokio::test]
+async fn validation_backend_outage() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let up = Arc::new(AtomicBool::new(true));
+    let url = spawn_flaky_backend(Arc::clone(&up))?;
+    let mut server = Server::new(1024..=65535, None, Some(url.clone()));
+    server.set_validation_cache_ttl(Duration::from_millis(100), Duration::ZERO);
+    server.set_validation_outage_policy(OutagePolicy {
+        grace: Duration::from_secs(30),
+        failure_threshold: 2,
+        max_backoff: Duration::from_millis(100),
+    });
+    let metrics = server.metrics();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    connect_with_key("known").await?;
+    up.store(false, Ordering::Relaxed);
+    time::sleep(Duration::from_millis(150)).await;
+
+    // A key accepted before the outage is still let in, but a new one is not.
+    connect_with_key("known").await?;
+    assert!(connect_with_key("unknown").await.is_err());
+    let rendered = metrics.render();
+    assert!(rendered.contains(&format!(
+        "bore_validation_backend_degraded{{url=\"{url}\"}} 1"
+    )));
+    assert!(rendered.contains("bore_validation_backend_failures_total 2"));
+    assert!(rendered.contains("bore_validation_grace_admissions_total 1"));
+
+    // Once the backoff has passed, the backend is asked again.
+    up.store(true, Ordering::Relaxed);
+    time::sleep(Duration::from_millis(150)).await;
+    connect_with_key("unknown").await?;
+    assert!(metrics.render().contains(&format!(
+        "bore_validation_backend_degraded{{url=\"{url}\"}} 0"
+    )));
+    Ok(())
+}
+
+#[tokio::test]
+async fn validation_failover() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let primary = spawn_flaky_backend(Arc::new(AtomicBool::new(false)))?;
+    let (fallback, requests) =
+        spawn_validation_backend(serde_json::json!({ "valid": true })).await?;
+    let config = ServerConfig {
+        api_validation_url: Some(primary.clone()),
+        api_validation_fallback_urls: vec![fallback],
+        ..Default::default()
+    };
+    let mut server = Server::with_config(&config);
+    server.set_validation_cache_ttl(Duration::ZERO, Duration::ZERO);
+    let metrics = server.metrics();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Once the primary has failed a few times, it is skipped.
+    for _ in 0..5 {
+        connect_with_key("key").await?;
+    }
+    assert_eq!(requests.load(Ordering::Relaxed), 5);
+    let rendered = metrics.render();
+    assert!(rendered.contains(&format!(
+        "bore_validation_backend_degraded{{url=\"{primary}\"}} 1"
+    )));
+    assert!(rendered.contains("bore_validation_backend_failures_total 3"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn validation_request_headers() -> Result<()> {
+    use hyper::service::{make_service_fn, service_fn};
+
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Validation backend that only answers servers with the service token.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, std::convert::Infallible>(service_fn(
+            |req: hyper::Request<hyper::Body>| async move {
+                let authorized = req
+                    .headers()
+                    .get("x-service-auth")
+                    .is_some_and(|v| v == "s3cret");
+                let response = match authorized {
+                    true => hyper::Response::new(hyper::Body::from(r#"{"valid":true}"#)),
+                    false => hyper::Response::builder()
+                        .status(500)
+                        .body(hyper::Body::empty())
+                        .unwrap(),
+                };
+                Ok::<_, std::convert::Infallible>(response)
+            },
+        ))
+    });
+    tokio::spawn(hyper::Server::from_tcp(listener)?.serve(make_service));
+
+    let mut server = Server::new(1024..=65535, None, Some(format!("http://{addr}/validate")));
+    let options = ValidationRequestOptions {
+        headers: vec![("X-Service-Auth".into(), "s3cret".into())],
+        ..Default::default()
+    };
+    server.set_validation_client(options.client()?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    connect_with_key("key").await?;
+
+    let missing = ValidationRequestOptions {
+        client_cert: Some("/nonexistent/client.pem".into()),
+        ..Default::default()
+    };
+    assert!(missing.client().is_err());
+    Ok(())
+}
+
+/// Accepts a single API key, and keeps its tunnels on one port.
+struct SingleKey;
+
+impl AuthProvider for SingleKey {
+    fn method(&self) -> &str {
+        "single_key"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        _challenge: &'a uuid::Uuid,
+        answer: &'a str,
+    ) -> BoxFuture<'a, Result<Principal, AuthError>> {
+        let result = match answer {
+            "letmein" => Ok(Principal {
+                user_id: Some("alice".into()),
+                quota: Quota {
+                    min_port: Some(41000),
+                    max_port: Some(41000),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            _ => Err(AuthError::new(AuthErrorCode::InvalidApiKey, "unknown key")),
+        };
+        future::ready(result).boxed()
+    }
+}
+
+#[tokio::test]
+async fn jwt_authentication() -> Result<()> {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let key = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+    let point = &key.public_key_raw()[1..]; // Uncompressed, after the 0x04 tag.
+    let jwks = serde_json::json!({ "keys": [{
+        "kty": "EC",
+        "crv": "P-256",
+        "kid": "test",
+        "alg": "ES256",
+        "x": URL_SAFE_NO_PAD.encode(&point[..32]),
+        "y": URL_SAFE_NO_PAD.encode(&point[32..]),
+    }]});
+    let (url, requests) = spawn_validation_backend(jwks).await?;
+    let mut auth = JwtAuthenticator::new(url);
+    auth.set_issuer("https://id.example".into());
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_auth_provider(Arc::new(auth));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let now = jsonwebtoken::get_current_timestamp();
+    let sign = |claims: serde_json::Value| {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("test".into());
+        jsonwebtoken::encode(
+            &header,
+            &claims,
+            &EncodingKey::from_ec_der(&key.serialize_der()),
+        )
+    };
+    let connect = |token: String| {
+        let options = ClientOptions {
+            api_key: Some(token),
+            ..Default::default()
+        };
+        Client::with_options("localhost", 8000, "localhost", options)
+    };
+
+    // The port claims limit where tunnels may listen, and the keys are
+    // fetched only once.
+    let token = sign(serde_json::json!({
+        "sub": "alice",
+        "iss": "https://id.example",
+        "exp": now + 60,
+        "min_port": 42000,
+        "max_port": 42001,
+    }))?;
+    let first = connect(token.clone()).await?;
+    let second = connect(token).await?;
+    for client in [&first, &second] {
+        assert!((42000..=42001).contains(&client.remote_port()));
+    }
+    assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+    for claims in [
+        serde_json::json!({ "iss": "https://id.example", "exp": now - 120 }),
+        serde_json::json!({ "iss": "https://other.example", "exp": now + 60 }),
+        serde_json::json!({ "exp": now + 60 }),
+    ] {
+        let err = match connect(sign(claims)?).await {
+            Ok(_) => return Err(anyhow!("token should be rejected")),
+            Err(err) => err.downcast::<AuthError>()?,
+        };
+        assert_eq!(err.code, AuthErrorCode::InvalidApiKey);
+    }
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn custom_auth_provider(#[values("letmein", "wrong")] api_key: &str) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_auth_provider(Arc::new(SingleKey));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let options = ClientOptions {
+        api_key: Some(api_key.into()),
+        ..Default::default()
+    };
+    match Client::with_options("localhost", 8000, "localhost", options).await {
+        Ok(client) => {
+            assert_eq!(api_key, "letmein");
+            assert_eq!(client.remote_port(), 41000);
+        }
+        Err(err) => {
+            assert_eq!(api_key, "wrong");
+            let err = err.downcast::<AuthError>()?;
+            assert_eq!(err.code, AuthErrorCode::InvalidApiKey);
+        }
+    }
+
+    // Clients without credentials are told what the server expects.
+    let result = Client::new("localhost", 8000, "localhost", 0, None, None).await;
+    assert!(result.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn in_memory_broker() -> Result<()> {
+    // No lock is needed, since the broker does not use the control port.
+    let broker = Broker::new();
+    let mut server = Server::new(1024..=65535, Some("secret"), None);
+    server.set_broker(broker.clone());
+    tokio::spawn(server.listen());
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let options = ClientOptions {
+        port: 8080,
+        secret: Some("secret".into()),
+        broker: Some(broker.clone()),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", local_port, "memory", options.clone()).await?;
+    assert_eq!(client.remote_port(), 8080);
+    tokio::spawn(client.listen());
+
+    // Tunnels on the broker take ports like they would on the network.
+    assert!(
+        Client::with_options("localhost", local_port, "memory", options)
+            .await
+            .is_err()
+    );
+
+    let mut visitor = broker.visit(8080)?;
+    let (mut local, _) = listener.accept().await?;
+    visitor.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    local.write_all(b"world").await?;
+    visitor.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"world");
+    Ok(())
+}
+
+#[tokio::test]
+async fn adaptive_heartbeat() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    let hello = ClientHello {
+        version: PROTOCOL_VERSION,
+        adaptive_heartbeat: true,
+        ..Default::default()
+    };
+    conn.send(ClientMessage::HelloExt(hello)).await?;
+    match conn.recv_timeout().await? {
+        Some(ServerMessage::HelloExt(hello)) => assert!(hello.adaptive_heartbeat),
+        message => panic!("unexpected reply {message:?}"),
+    }
+    match conn.recv_timeout().await? {
+        Some(ServerMessage::Ping(seq)) => conn.send(ClientMessage::Pong(seq)).await?,
+        message => panic!("expected a ping, got {message:?}"),
+    }
+
+    // A client that stops answering is disconnected after a few lost pings.
+    let closed = time::timeout(Duration::from_secs(15), async {
+        while conn.recv::<ServerMessage>().await?.is_some() {}
+        anyhow::Ok(())
+    });
+    closed.await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn negotiated_heartbeat_interval() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_heartbeat_interval(Duration::from_secs(60));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The server beats more often for a client with a short liveness threshold.
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    let hello = ClientHello {
+        version: PROTOCOL_VERSION,
+        heartbeat_timeout_ms: Some(900),
+        ..Default::default()
+    };
+    conn.send(ClientMessage::HelloExt(hello)).await?;
+    match conn.recv_timeout().await? {
+        Some(ServerMessage::HelloExt(hello)) => assert_eq!(hello.heartbeat_interval_ms, Some(300)),
+        message => panic!("unexpected reply {message:?}"),
+    }
+    for _ in 0..3 {
+        let message = time::timeout(Duration::from_millis(900), conn.recv()).await??;
+        assert!(matches!(message, Some(ServerMessage::Heartbeat)));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn announced_connections() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let announcements = TcpListener::bind("localhost:0").await?;
+    for announce in [
+        Announce::Inline,
+        Announce::Port(announcements.local_addr()?.port()),
+    ] {
+        let options = ClientOptions {
+            announce: Some(announce),
+            name: Some("web".into()),
+            ..Default::default()
+        };
+        let client = Client::with_options(
+            "localhost",
+            listener.local_addr()?.port(),
+            "localhost",
+            options,
+        )
+        .await?;
+        let remote_port = client.remote_port();
+        tokio::spawn(client.listen());
+
+        let mut cli = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+        cli.write_all(b"hello").await?;
+        let (srv, _) = listener.accept().await?;
+        let mut srv = BufReader::new(srv);
+        let mut line = String::new();
+        match announce {
+            Announce::Inline => srv.read_line(&mut line).await?,
+            Announce::Port(_) => {
+                let (conn, _) = announcements.accept().await?;
+                BufReader::new(conn).read_line(&mut line).await?
+            }
+        };
+        let announcement: serde_json::Value = serde_json::from_str(&line)?;
+        assert_eq!(announcement["peer"], cli.local_addr()?.to_string());
+        assert_eq!(announcement["remote_port"], remote_port);
+        assert_eq!(announcement["tunnel"], "web");
+
+        // The proxied data follows, untouched.
+        let mut buf = [0u8; 5];
+        srv.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+    }
+    Ok(())
+}
+
+#[rstest]
+#[case(None, None, true)]
+#[case(Some("127.0.0.0/8"), None, false)]
+#[case(None, Some("10.0.0.0/8"), false)]
+#[case(None, Some("127.0.0.1"), true)]
+#[tokio::test]
+async fn visitor_access_list(
+    #[case] server_deny: Option<&str>,
+    #[case] tunnel_allow: Option<&str>,
+    #[case] forwarded: bool,
+) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    let deny = server_deny.into_iter().map(|net| net.parse().unwrap());
+    server.set_access_list(AccessList::new(vec![], deny.collect()));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        allow_ips: tunnel_allow
+            .into_iter()
+            .map(|net| net.parse().unwrap())
+            .collect(),
+        ..Default::default()
+    };
+    let local_port = listener.local_addr()?.port();
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut visitor = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+    visitor.write_all(b"hello").await?;
+    let accepted = time::timeout(Duration::from_millis(500), listener.accept()).await;
+    assert_eq!(accepted.is_ok(), forwarded);
+    if !forwarded {
+        // Disallowed visitors are disconnected without reaching the client.
+        let mut buf = [0u8; 1];
+        assert_eq!(visitor.read(&mut buf).await.unwrap_or(0), 0);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_log() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let path = std::env::temp_dir().join(format!("bore-access-{}.log", uuid::Uuid::new_v4()));
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_access_log(AccessLog::open(&path)?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let open = |name: &str, deny_ips| {
+        let options = ClientOptions {
+            name: Some(name.into()),
+            deny_ips,
+            ..Default::default()
+        };
+        Client::with_options("localhost", local_port, "localhost", options)
+    };
+    let open_client = open("web", vec![]).await?;
+    let open_port = open_client.remote_port();
+    tokio::spawn(open_client.listen());
+    let closed_client = open("private", vec!["127.0.0.0/8".parse().unwrap()]).await?;
+    let closed_port = closed_client.remote_port();
+    tokio::spawn(closed_client.listen());
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = [0u8; 11];
+        stream.read_exact(&mut buf).await?;
+        stream.write_all(b"I can send a message too!").await?;
+        anyhow::Ok(())
+    });
+    let mut visitor = TcpStream::connect(("127.0.0.1", open_port)).await?;
+    visitor.write_all(b"hello world").await?;
+    let mut buf = [0u8; 25];
+    visitor.read_exact(&mut buf).await?;
+    let peer = visitor.local_addr()?;
+    drop(visitor);
+    let denied = TcpStream::connect(("127.0.0.1", closed_port)).await?;
+    let denied_peer = denied.local_addr()?;
+    time::sleep(Duration::from_millis(200)).await;
+
+    let contents = std::fs::read_to_string(&path)?;
+    std::fs::remove_file(&path)?;
+    let mut entries = contents
+        .lines()
+        .map(serde_json::from_str::<AccessEntry>)
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.port != open_port);
+    let [forwarded, rejected] = &entries[..] else {
+        panic!("expected two records, got {contents}");
+    };
+    assert_eq!((forwarded.peer, forwarded.port), (peer, open_port));
+    assert_eq!(forwarded.tunnel.as_deref(), Some("web"));
+    assert_eq!((forwarded.bytes_in, forwarded.bytes_out), (11, 25));
+    assert_eq!(forwarded.reason, Reason::Closed);
+    assert!(forwarded.id.is_some());
+    assert_eq!((rejected.peer, rejected.port), (denied_peer, closed_port));
+    assert_eq!(rejected.tunnel.as_deref(), Some("private"));
+    assert_eq!(rejected.reason, Reason::Denied);
+    Ok(())
+}
+
+/// MaxMind database that places 127.0.0.1 in Germany and 127.0.0.2 in the
+/// United States, and knows no other addresses.
+#[cfg(feature = "geoip")]
+fn write_country_db(path: &std::path::Path) -> Result<()> {
+    let string = |s: &str| [&[0x40 | s.len() as u8], s.as_bytes()].concat();
+    let uint16 = |n: u16| [&[0xa2], &n.to_be_bytes()[..]].concat();
+    let country = |code: &str| {
+        [
+            &[0xe1][..],
+            &string("country"),
+            &[0xe1],
+            &string("iso_code"),
+            &string(code),
+        ]
+        .concat()
+    };
+    let data = [country("DE"), country("US")];
+
+    // Node `i` of the search tree follows bit `i` of 127.0.0.0/30, and the
+    // last two tell the addresses apart.
+    let prefix = u32::from(Ipv4Addr::new(127, 0, 0, 0));
+    let nodes = 32u32;
+    let empty = nodes;
+    let record =
+        |index: usize| nodes + 16 + data[..index].iter().map(Vec::len).sum::<usize>() as u32;
+    let mut tree = Vec::new();
+    for i in 0..nodes {
+        let (left, right) = match i {
+            30 => (i + 1, record(1)),
+            31 => (empty, record(0)),
+            _ if prefix >> (31 - i) & 1 == 1 => (empty, i + 1),
+            _ => (i + 1, empty),
+        };
+        tree.extend_from_slice(&left.to_be_bytes()[1..]);
+        tree.extend_from_slice(&right.to_be_bytes()[1..]);
+    }
+
+    let metadata = [
+        &[0xe9][..],
+        &string("node_count"),
+        &[0xc1, nodes as u8],
+        &string("record_size"),
+        &uint16(24),
+        &string("ip_version"),
+        &uint16(4),
+        &string("database_type"),
+        &string("GeoLite2-Country"),
+        &string("languages"),
+        &[0x00, 0x04],
+        &string("binary_format_major_version"),
+        &uint16(2),
+        &string("binary_format_minor_version"),
+        &uint16(0),
+        &string("build_epoch"),
+        &[0x00, 0x02],
+        &string("description"),
+        &[0xe0],
+    ]
+    .concat();
+    let contents = [
+        tree,
+        vec![0; 16],
+        data.concat(),
+        b"\xab\xcd\xefMaxMind.com".to_vec(),
+        metadata,
+    ]
+    .concat();
+    Ok(std::fs::write(path, contents)?)
+}
+
+#[cfg(feature = "geoip")]
+#[rstest]
+#[case(vec!["DE"], vec![])]
+#[case(vec![], vec!["us"])]
+#[tokio::test]
+async fn country_rules(#[case] allow: Vec<&str>, #[case] deny: Vec<&str>) -> Result<()> {
+    use bore_cli::geoip::{CountryRules, GeoIp};
+
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let dir = std::env::temp_dir().join(format!("bore-geoip-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let db = dir.join("countries.mmdb");
+    write_country_db(&db)?;
+    let log = dir.join("access.log");
+    let codes = |codes: Vec<&str>| codes.into_iter().map(String::from).collect();
+    let mut server = Server::new(1024..=65535, None, None);
+    let rules = CountryRules::new(codes(allow), codes(deny))?;
+    server.set_country_rules(GeoIp::open(&db)?, rules);
+    server.set_access_log(AccessLog::open(&log)?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(b"hello from germany").await?;
+        anyhow::Ok(())
+    });
+
+    // Visitors from the United States are turned away, and those from
+    // Germany get through.
+    let socket = tokio::net::TcpSocket::new_v4()?;
+    socket.bind("127.0.0.2:0".parse()?)?;
+    let mut denied = socket.connect(addr).await?;
+    let mut buf = Vec::new();
+    denied.read_to_end(&mut buf).await?;
+    assert!(buf.is_empty());
+    let mut admitted = TcpStream::connect(addr).await?;
+    let mut buf = [0u8; 18];
+    admitted.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello from germany");
+    drop(admitted);
+    time::sleep(Duration::from_millis(200)).await;
+
+    let contents = std::fs::read_to_string(&log)?;
+    std::fs::remove_dir_all(&dir)?;
+    let entries = contents
+        .lines()
+        .map(serde_json::from_str::<AccessEntry>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let [rejected, forwarded] = &entries[..] else {
+        panic!("expected two records, got {contents}");
+    };
+    assert_eq!(rejected.country.as_deref(), Some("US"));
+    assert_eq!(rejected.reason, Reason::Denied);
+    assert_eq!(forwarded.country.as_deref(), Some("DE"));
+    assert_eq!(forwarded.reason, Reason::Closed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn visitor_info() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let mut conn = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    let hello = ClientHello {
+        version: PROTOCOL_VERSION,
+        connection_info: true,
+        ..Default::default()
+    };
+    conn.send(ClientMessage::HelloExt(hello)).await?;
+    let Some(ServerMessage::HelloExt(hello)) = conn.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+
+    // The server names the visitor and the port that it connected to.
+    let visitor = TcpStream::connect(("127.0.0.1", hello.port)).await?;
+    loop {
+        match conn.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::ConnectionExt(info)) => {
+                assert_eq!(info.peer, visitor.local_addr()?);
+                assert_eq!(info.port, Some(hello.port));
+                break;
+            }
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+
+    // Clients that ask for visitors keep the latest in their statistics.
+    let listener = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        visitor_info: true,
+        ..Default::default()
+    };
+    let local_port = listener.local_addr()?.port();
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    let (remote_port, stats) = (client.remote_port(), client.stats());
+    tokio::spawn(client.listen());
+    let visitor = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+    listener.accept().await?;
+    assert_eq!(stats.last_visitor(), Some(visitor.local_addr()?));
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn local_connect_timeout() -> Result<()> {
+    use socket2::{Domain, Socket, Type};
+
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    // A local service that never accepts: once its backlog is full, Linux
+    // drops new handshakes and leaves them waiting.
+    let blackhole = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+    blackhole.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())?;
+    blackhole.listen(0)?;
+    let addr = blackhole.local_addr()?.as_socket().context("no address")?;
+    let mut queued = Vec::new();
+    while let Ok(conn) = time::timeout(Duration::from_millis(200), TcpStream::connect(addr)).await {
+        queued.push(conn?);
+        assert!(queued.len() < 16, "backlog never filled");
+    }
+
+    let deadline = Duration::from_millis(300);
+    let mut client =
+        Client::with_options("127.0.0.1", addr.port(), "localhost", Default::default()).await?;
+    client.set_local_connect_timeout(deadline);
+    let (remote_port, stats) = (client.remote_port(), client.stats());
+    tokio::spawn(client.listen());
+
+    let start = time::Instant::now();
+    let _visitor = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+    let err = time::timeout(NETWORK_TIMEOUT, async {
+        loop {
+            match stats.last_error() {
+                Some(err) => return err,
+                None => time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    })
+    .await
+    .context("local connect deadline did not fire before the network timeout")?;
+    assert_eq!(err, LocalTimeout(deadline).to_string());
+    assert!(start.elapsed() >= deadline);
+    Ok(())
+}
+
 #[rstest]
+#[case(ProxyProtocol::V1)]
+#[case(ProxyProtocol::V2)]
 #[tokio::test]
-async fn basic_proxy(#[values(None, Some(""), Some("abc"))] secret: Option<&str>) -> Result<()> {
+async fn proxy_protocol(#[case] version: ProxyProtocol) -> Result<()> {
     let _guard = SERIAL_GUARD.lock().await;
 
-    spawn_server(secret).await;
-    let (listener, addr) = spawn_client(secret).await?;
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        proxy_protocol: Some(version),
+        ..Default::default()
+    };
+    let client = Client::with_options(
+        "localhost",
+        listener.local_addr()?.port(),
+        "localhost",
+        options,
+    )
+    .await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
 
-    tokio::spawn(async move {
-        let (mut stream, _) = listener.accept().await?;
-        let mut buf = [0u8; 11];
-        stream.read_exact(&mut buf).await?;
-        assert_eq!(&buf, b"hello world");
+    let mut cli = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+    cli.write_all(b"hello").await?;
+    let (mut srv, _) = listener.accept().await?;
 
-        stream.write_all(b"I can send a message too!").await?;
-        anyhow::Ok(())
-    });
+    // The header carries the visitor's address, and the data follows.
+    let expected = version.header(Some(cli.local_addr()?), remote_port);
+    let mut header = vec![0u8; expected.len()];
+    srv.read_exact(&mut header).await?;
+    assert_eq!(header, expected);
+    if version == ProxyProtocol::V1 {
+        let line = String::from_utf8(header)?;
+        assert!(line.starts_with("PROXY TCP4 127.0.0.1 "), "{line}");
+    }
+    let mut buf = [0u8; 5];
+    srv.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
 
-    let mut stream = TcpStream::connect(addr).await?;
-    stream.write_all(b"hello world").await?;
+#[tokio::test]
+async fn daemon_exposes_ports() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
 
-    let mut buf = [0u8; 25];
-    stream.read_exact(&mut buf).await?;
-    assert_eq!(&buf, b"I can send a message too!");
+    spawn_server(None).await;
+    let daemon = Daemon::new("localhost", ClientOptions::default());
+    let listener = TcpListener::bind("localhost:0").await?;
+    let info = daemon
+        .expose("localhost", listener.local_addr()?.port(), 0)
+        .await?;
+    assert_eq!(daemon.tunnels().len(), 1);
 
-    // Ensure that the client end of the stream is closed now.
-    assert_eq!(stream.read(&mut buf).await?, 0);
+    let addr: SocketAddr = ([127, 0, 0, 1], info.remote_port).into();
+    let (mut cli, (mut srv, _)) = tokio::try_join!(TcpStream::connect(addr), listener.accept())?;
+    cli.write_all(b"daemon").await?;
+    let mut buf = [0u8; 6];
+    srv.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"daemon");
 
-    // Also ensure that additional connections do not produce any data.
-    let mut stream = TcpStream::connect(addr).await?;
-    assert_eq!(stream.read(&mut buf).await?, 0);
+    let tunnels = daemon.tunnels();
+    assert_eq!(tunnels[0].state, TunnelState::Open);
+    assert_eq!(tunnels[0].connections, 1);
+    assert_eq!(tunnels[0].bytes_in, 6);
 
+    assert!(daemon.close(&info.id));
+    assert!(daemon.tunnels().is_empty());
     Ok(())
 }
 
-#[rstest]
-#[case(None, Some("my secret"))]
-#[case(Some("my secret"), None)]
 #[tokio::test]
-async fn mismatched_secret(
-    #[case] server_secret: Option<&str>,
-    #[case] client_secret: Option<&str>,
-) {
+async fn daemon_api_requires_token() -> Result<()> {
+    let daemon = Daemon::new("localhost", ClientOptions::default());
+    let token = daemon.token().to_string();
+    let api_addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    tokio::spawn(daemon.listen(api_addr));
+    time::sleep(Duration::from_millis(50)).await;
+
+    let url = format!("http://{api_addr}/tunnels");
+    let http = reqwest::Client::new();
+    let status = |request: reqwest::RequestBuilder| async move {
+        anyhow::Ok(request.send().await?.status().as_u16())
+    };
+    assert_eq!(status(http.get(&url)).await?, 401);
+    assert_eq!(status(http.get(&url).bearer_auth("wrong")).await?, 401);
+    assert_eq!(status(http.get(&url).bearer_auth(&token)).await?, 200);
+
+    // Pages that rebind their own domain to a loopback address are refused.
+    let rebound = http
+        .get(&url)
+        .bearer_auth(&token)
+        .header("Host", "evil.example:7836");
+    assert_eq!(status(rebound).await?, 403);
+    let named = http
+        .get(&url)
+        .bearer_auth(&token)
+        .header("Host", "localhost:7836");
+    assert_eq!(status(named).await?, 200);
+
+    // Simple requests that pages can send without a preflight are refused.
+    let form = (http.post(&url).bearer_auth(&token))
+        .header("Content-Type", "text/plain")
+        .body(r#"{"local_port": 22}"#);
+    assert_eq!(status(form).await?, 415);
+
+    daemon::shutdown(api_addr, &token).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn config_tunnels() -> Result<()> {
     let _guard = SERIAL_GUARD.lock().await;
 
-    spawn_server(server_secret).await;
-    assert!(spawn_client(client_secret).await.is_err());
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let config = ClientConfig::parse(&format!(
+        r#"
+        server = "localhost"
+
+        [[tunnels]]
+        name = "web"
+        local_port = {}
+
+        [[tunnels]]
+        name = "dns"
+        local_port = 5353
+        protocol = "udp"
+        "#,
+        listener.local_addr()?.port(),
+    ))?;
+    let options = config.options();
+    let daemon = Daemon::new(&config.server, options.clone());
+    for tunnel in &config.tunnels {
+        let options = tunnel.options(&options);
+        daemon
+            .expose_with_options(&tunnel.local_host, tunnel.local_port, options)
+            .await?;
+    }
+    let mut names: Vec<_> = daemon
+        .tunnels()
+        .into_iter()
+        .filter_map(|t| t.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["dns", "web"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn config_up_and_down() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(Some("abc")).await;
+    let config = ClientConfig::parse(
+        r#"
+        server = "localhost"
+        secret = "abc"
+
+        [[tunnels]]
+        name = "web"
+        local_port = 3000
+        labels = { team = "frontend" }
+
+        [[tunnels]]
+        name = "db"
+        local_port = 5432
+        secret = "wrong"
+        "#,
+    )?;
+    let options = config.options();
+    let daemon = Daemon::new(&config.server, options.clone());
+    daemon.expose_tunnel(&config.tunnels[0], &options).await?;
+    assert!(daemon
+        .expose_tunnel(&config.tunnels[1], &options)
+        .await
+        .is_err());
+
+    let api_addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let token = daemon.token().to_string();
+    let listening = tokio::spawn(daemon.listen(api_addr));
+    time::sleep(Duration::from_millis(50)).await;
+    let tunnels = daemon::status(api_addr, &token).await?;
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0].labels["team"], "frontend");
+
+    daemon::shutdown(api_addr, &token).await?;
+    time::timeout(Duration::from_secs(1), listening).await???;
+    Ok(())
 }
 
 #[tokio::test]
 async fn invalid_address() -> Result<()> {
     // We don't need the serial guard for this test because it doesn't create a server.
     async fn check_address(to: &str, use_secret: bool) -> Result<()> {
-        match Client::new("localhost", 5000, to, 0, use_secret.then_some("a secret")).await {
+        let secret = use_secret.then_some("a secret");
+        match Client::new("localhost", 5000, to, 0, secret, None).await {
             Ok(_) => Err(anyhow!("expected error for {to}, use_secret={use_secret}")),
             Err(_) => Ok(()),
         }
@@ -123,7 +3606,7 @@ async fn very_long_frame() -> Result<()> {
 fn empty_port_range() {
     let min_port = 5000;
     let max_port = 3000;
-    let _ = Server::new(min_port..=max_port, None);
+    let _ = Server::new(min_port..=max_port, None, None);
 }
 
 #[tokio::test]
@@ -162,3 +3645,979 @@ async fn half_closed_tcp_stream() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(unix)]
+#[tokio::test]
+async fn reload_server_config() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let path = std::env::temp_dir().join(format!("bore-server-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(&path, "secret = \"old\"")?;
+    let mut server = Server::with_config(&ServerConfig::load(&path)?);
+    server.set_config_file(path.clone());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(Some("old")).await?;
+
+    std::fs::write(&path, "secret = \"new\"")?;
+    let status = std::process::Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()?;
+    assert!(status.success());
+    time::sleep(Duration::from_millis(100)).await;
+
+    // New handshakes use the new secret.
+    assert!(spawn_client(Some("old")).await.is_err());
+    spawn_client(Some("new")).await?;
+
+    // The tunnel opened with the old secret is still forwarding.
+    let mut visitor = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+    visitor.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    // A broken file is ignored, keeping the current settings.
+    std::fs::write(&path, "secret = ")?;
+    std::process::Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()?;
+    time::sleep(Duration::from_millis(100)).await;
+    spawn_client(Some("new")).await?;
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_routing() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.enable_http(48080, "tunnel.test");
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    let open = |subdomain: &str| {
+        let options = ClientOptions {
+            http: true,
+            subdomain: Some(subdomain.into()),
+            ..Default::default()
+        };
+        Client::with_options("localhost", local_port, "localhost", options)
+    };
+    let request = |host: &'static str| async move {
+        let mut stream = TcpStream::connect("127.0.0.1:48080").await?;
+        let head = format!("GET /hello HTTP/1.1\r\nHost: {host}\r\n\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        anyhow::Ok(stream)
+    };
+
+    let client = open("MyApp").await?;
+    assert_eq!(client.hostname(), Some("myapp.tunnel.test"));
+    tokio::spawn(client.listen());
+
+    let mut visitor = request("myapp.tunnel.test:48080").await?;
+    let (mut stream, _) = time::timeout(Duration::from_secs(3), local.accept()).await??;
+    let mut buf = [0; 512];
+    let n = stream.read(&mut buf).await?;
+    let head = String::from_utf8_lossy(&buf[..n]);
+    assert!(head.starts_with("GET /hello HTTP/1.1\r\nHost: myapp.tunnel.test"));
+    assert!(head.contains("\r\nX-Forwarded-For: 127.0.0.1\r\n"));
+    assert!(head.contains("\r\nX-Forwarded-Proto: http\r\n"));
+    assert!(head
+        .contains("\r\nForwarded: for=127.0.0.1;proto=http;host=\"myapp.tunnel.test:48080\"\r\n"));
+    stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+    let n = visitor.read(&mut buf).await?;
+    assert_eq!(&buf[..n], b"HTTP/1.1 204 No Content\r\n\r\n");
+
+    // Later requests on the connection get the headers too, even if the
+    // visitor sent its own.
+    visitor
+        .write_all(
+            b"POST /form HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\nContent-Length: 4\r\n\r\nbody",
+        )
+        .await?;
+    let mut received = Vec::new();
+    while !received.ends_with(b"body") {
+        let n = stream.read(&mut buf).await?;
+        anyhow::ensure!(n > 0, "connection closed");
+        received.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&received);
+    assert!(head.contains("\r\nX-Forwarded-For: 10.0.0.1, 127.0.0.1\r\n"));
+
+    let mut visitor = request("other.tunnel.test").await?;
+    let mut response = String::new();
+    visitor.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+    let err = open("myapp").await.err().context("duplicate subdomain")?;
+    let rejected = err
+        .downcast_ref::<TunnelRejected>()
+        .context("not rejected")?;
+    assert_eq!(rejected.0.code, ErrorCode::HostnameTaken);
+    Ok(())
+}
+
+#[tokio::test]
+async fn https_routing() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // A cached wildcard certificate is used as is, without ordering one.
+    let mut ca_params = CertificateParams::new(vec![]);
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params)?;
+    let cert = Certificate::from_params(CertificateParams::new(vec!["*.tunnel.test".into()]))?;
+    let dir = std::env::temp_dir().join(format!("bore-acme-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("ca.pem"), ca.serialize_pem()?)?;
+    std::fs::write(
+        dir.join("_.tunnel.test.crt"),
+        cert.serialize_pem_with_signer(&ca)?,
+    )?;
+    std::fs::write(
+        dir.join("_.tunnel.test.key"),
+        cert.serialize_private_key_pem(),
+    )?;
+
+    let config = AcmeConfig {
+        challenge: Challenge::Dns01,
+        ..AcmeConfig::new(&dir)
+    };
+    let mut server = Server::new(1024..=65535, None, None);
+    server.enable_http(48081, "tunnel.test");
+    server.enable_https(48443, Acme::new(config, "tunnel.test")?);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        http: true,
+        subdomain: Some("secure".into()),
+        ..Default::default()
+    };
+    let local_port = local.local_addr()?.port();
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    assert_eq!(
+        client.urls(),
+        [
+            "http://secure.tunnel.test:48081",
+            "https://secure.tunnel.test:48443"
+        ]
+    );
+    tokio::spawn(client.listen());
+
+    let connector = tls::connector(Some(&dir.join("ca.pem")))?;
+    let stream = TcpStream::connect("127.0.0.1:48443").await?;
+    let mut visitor = tls::connect(&connector, "secure.tunnel.test", stream).await?;
+    visitor
+        .write_all(b"GET / HTTP/1.1\r\nHost: secure.tunnel.test\r\n\r\n")
+        .await?;
+    let (mut stream, _) = time::timeout(Duration::from_secs(3), local.accept()).await??;
+    let mut buf = [0; 64];
+    let n = stream.read(&mut buf).await?;
+    assert!(buf[..n].starts_with(b"GET / HTTP/1.1\r\n"));
+    stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+    let n = visitor.read(&mut buf).await?;
+    assert_eq!(&buf[..n], b"HTTP/1.1 204 No Content\r\n\r\n");
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn tls_passthrough() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // The local service terminates TLS with its own certificate.
+    let mut ca_params = CertificateParams::new(vec![]);
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params)?;
+    let cert = Certificate::from_params(CertificateParams::new(vec!["raw.tunnel.test".into()]))?;
+    let dir = std::env::temp_dir().join(format!("bore-sni-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("ca.pem"), ca.serialize_pem()?)?;
+    std::fs::write(dir.join("cert.pem"), cert.serialize_pem_with_signer(&ca)?)?;
+    std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem())?;
+    let acceptor = tls::acceptor(&dir.join("cert.pem"), &dir.join("key.pem"))?;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.enable_http(48082, "tunnel.test");
+    server.enable_tls_passthrough(48445);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let options = ClientOptions {
+        tls_passthrough: true,
+        subdomain: Some("raw".into()),
+        ..Default::default()
+    };
+    let local_port = local.local_addr()?.port();
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    assert_eq!(client.hostname(), Some("raw.tunnel.test"));
+    assert!(client.urls().is_empty());
+    tokio::spawn(client.listen());
+
+    let connector = tls::connector(Some(&dir.join("ca.pem")))?;
+    let visit = tokio::spawn(async move {
+        let stream = TcpStream::connect("127.0.0.1:48445").await?;
+        let mut visitor = tls::connect(&connector, "raw.tunnel.test", stream).await?;
+        visitor.write_all(b"ping").await?;
+        let mut buf = [0; 4];
+        visitor.read_exact(&mut buf).await?;
+        anyhow::Ok(buf)
+    });
+    let (stream, _) = time::timeout(Duration::from_secs(3), local.accept()).await??;
+    let mut stream = tls::accept(&acceptor, stream).await?;
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+    stream.write_all(b"pong").await?;
+    assert_eq!(&visit.await??, b"pong");
+
+    // Server names without a TLS tunnel are not routed, and HTTP requests
+    // for a TLS tunnel's hostname find no tunnel.
+    let connector = tls::connector(Some(&dir.join("ca.pem")))?;
+    let stream = TcpStream::connect("127.0.0.1:48445").await?;
+    assert!(tls::connect(&connector, "other.tunnel.test", stream)
+        .await
+        .is_err());
+    let mut visitor = TcpStream::connect("127.0.0.1:48082").await?;
+    visitor
+        .write_all(b"GET / HTTP/1.1\r\nHost: raw.tunnel.test\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    visitor.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[rstest]
+#[case("Content-Length: 4\r\nContent-Length: 4\r\n\r\nbody")]
+#[case("Content-Length: 4, 40\r\n\r\nbody")]
+#[case("Content-Length: +4\r\n\r\nbody")]
+#[case("Transfer-Encoding: gzip\r\n\r\nbody")]
+#[case("Transfer-Encoding: chunked, gzip\r\n\r\nbody")]
+#[case("Transfer-Encoding: chunked\r\nContent-Length: 9\r\n\r\n4\r\nbody\r\n0\r\n\r\n")]
+#[tokio::test]
+async fn http_ambiguous_request(#[case] rest: &str) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.enable_http(48087, "tunnel.test");
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    let options = ClientOptions {
+        http: true,
+        subdomain: Some("app".into()),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    tokio::spawn(client.listen());
+    let ambiguous = format!("POST / HTTP/1.1\r\nHost: app.tunnel.test\r\n{rest}");
+
+    // Whether first on the connection or after another request, the request
+    // is answered with an error and never reaches the local service.
+    let mut visitor = TcpStream::connect("127.0.0.1:48087").await?;
+    visitor.write_all(ambiguous.as_bytes()).await?;
+    let mut response = String::new();
+    time::timeout(
+        Duration::from_secs(3),
+        visitor.read_to_string(&mut response),
+    )
+    .await??;
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{response}"
+    );
+
+    let mut visitor = TcpStream::connect("127.0.0.1:48087").await?;
+    let first = "GET / HTTP/1.1\r\nHost: app.tunnel.test\r\n\r\n";
+    visitor.write_all(first.as_bytes()).await?;
+    let (mut stream, _) = time::timeout(Duration::from_secs(3), local.accept()).await??;
+    let mut buf = [0; 512];
+    let n = stream.read(&mut buf).await?;
+    assert!(buf[..n].starts_with(b"GET / HTTP/1.1\r\n"));
+    stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+    visitor.write_all(ambiguous.as_bytes()).await?;
+    let mut received = Vec::new();
+    time::timeout(Duration::from_secs(3), stream.read_to_end(&mut received)).await??;
+    let received = String::from_utf8_lossy(&received);
+    assert!(!received.contains("POST"), "{received}");
+    drop(stream);
+    let mut response = String::new();
+    time::timeout(
+        Duration::from_secs(3),
+        visitor.read_to_string(&mut response),
+    )
+    .await??;
+    assert_eq!(
+        response,
+        "HTTP/1.1 204 No Content\r\n\r\nHTTP/1.1 400 Bad Request\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Length: 18\r\n\
+         Connection: close\r\n\r\nmalformed request\n"
+    );
+    Ok(())
+}
+
+#[rstest]
+#[case("+4")]
+#[case("-4")]
+#[case("0x4")]
+#[case("")]
+#[tokio::test]
+async fn malformed_chunk(#[case] size: &str) -> Result<()> {
+    use bore_cli::rewrite::Rewritten;
+
+    // Nothing is read past the head of the request with the bad chunk.
+    let requests = format!(
+        "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{size}\r\nbody\r\n0\r\n\r\n\
+         GET /hidden HTTP/1.1\r\n\r\n"
+    );
+    let mut stream = Rewritten::new(requests.as_bytes(), Box::new(|_| ()));
+    let mut read = String::new();
+    stream.read_to_string(&mut read).await?;
+    assert_eq!(
+        read,
+        "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn host_header_rewrite() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.enable_http(48083, "tunnel.test");
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    let options = ClientOptions {
+        http: true,
+        subdomain: Some("app".into()),
+        host_header: HostHeader::Rewrite,
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    tokio::spawn(client.listen());
+
+    let mut visitor = TcpStream::connect("127.0.0.1:48083").await?;
+    visitor
+        .write_all(b"GET / HTTP/1.1\r\nHost: app.tunnel.test\r\n\r\n")
+        .await?;
+    let (mut stream, _) = time::timeout(Duration::from_secs(3), local.accept()).await??;
+    let mut buf = [0; 512];
+    let n = stream.read(&mut buf).await?;
+    let head = String::from_utf8_lossy(&buf[..n]);
+    assert!(head.contains(&format!("\r\nHost: localhost:{local_port}\r\n")));
+    assert!(head.contains("\r\nX-Forwarded-Host: app.tunnel.test\r\n"));
+    assert!(!head.contains("\r\nHost: app.tunnel.test"));
+    stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+    let n = visitor.read(&mut buf).await?;
+    assert_eq!(&buf[..n], b"HTTP/1.1 204 No Content\r\n\r\n");
+
+    // Only HTTP tunnels can change the Host header.
+    let options = ClientOptions {
+        host_header: HostHeader::Custom("app.local".into()),
+        ..Default::default()
+    };
+    assert!(
+        Client::with_options("localhost", local_port, "localhost", options)
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn basic_auth() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.enable_http(48084, "tunnel.test");
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    let options = ClientOptions {
+        http: true,
+        subdomain: Some("private".into()),
+        basic_auth: Some("demo:s3cret".into()),
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    tokio::spawn(client.listen());
+
+    // Visitors without the right credentials are challenged.
+    for authorization in ["", "Authorization: Basic ZGVtbzp3cm9uZw==\r\n"] {
+        let mut visitor = TcpStream::connect("127.0.0.1:48084").await?;
+        let request = format!("GET / HTTP/1.1\r\nHost: private.tunnel.test\r\n{authorization}\r\n");
+        visitor.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        visitor.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(response.contains("\r\nWWW-Authenticate: Basic realm=\"bore\""));
+    }
+
+    let mut visitor = TcpStream::connect("127.0.0.1:48084").await?;
+    visitor
+        .write_all(
+            b"GET / HTTP/1.1\r\nHost: private.tunnel.test\r\n\
+              Authorization: Basic ZGVtbzpzM2NyZXQ=\r\n\r\n",
+        )
+        .await?;
+    let (mut stream, _) = time::timeout(Duration::from_secs(3), local.accept()).await??;
+    let mut buf = [0; 512];
+    let n = stream.read(&mut buf).await?;
+    let head = String::from_utf8_lossy(&buf[..n]);
+    assert!(head.starts_with("GET / HTTP/1.1\r\n"));
+    assert!(!head.to_ascii_lowercase().contains("authorization"));
+    stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+    let n = visitor.read(&mut buf).await?;
+    assert_eq!(&buf[..n], b"HTTP/1.1 204 No Content\r\n\r\n");
+
+    // Only HTTP tunnels can require basic authentication.
+    let options = ClientOptions {
+        basic_auth: Some("demo:s3cret".into()),
+        ..Default::default()
+    };
+    assert!(
+        Client::with_options("localhost", local_port, "localhost", options)
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+/// Serve a minimal OpenID Connect provider that issues ID tokens for codes
+/// like `{user}.{nonce}`, for `{user}@example.com`, or `{user}` if it has an
+/// @, returning its issuer. Emails of users named `unverified` are not
+/// verified.
+async fn spawn_oidc_provider() -> Result<String> {
+    use hyper::service::{make_service_fn, service_fn};
+    use jsonwebtoken::{EncodingKey, Header};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let issuer = format!("http://{}", listener.local_addr()?);
+    let provider = issuer.clone();
+    let make_service = make_service_fn(move |_| {
+        let issuer = provider.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request: hyper::Request<hyper::Body>| {
+                let issuer = issuer.clone();
+                async move {
+                    let body = match request.uri().path() {
+                        "/.well-known/openid-configuration" => serde_json::json!({
+                            "authorization_endpoint": format!("{issuer}/authorize"),
+                            "token_endpoint": format!("{issuer}/token"),
+                        }),
+                        _ => {
+                            let form = hyper::body::to_bytes(request.into_body()).await?;
+                            let form = String::from_utf8_lossy(&form).to_string();
+                            let code = (form.split('&'))
+                                .find_map(|pair| pair.strip_prefix("code="))
+                                .unwrap_or_default()
+                                .replace("%40", "@");
+                            let (user, nonce) = code.rsplit_once('.').unwrap_or_default();
+                            let email = match user.contains('@') {
+                                true => user.to_string(),
+                                false => format!("{user}@example.com"),
+                            };
+                            let claims = serde_json::json!({
+                                "iss": issuer,
+                                "aud": "bore",
+                                "exp": jsonwebtoken::get_current_timestamp() + 600,
+                                "email": email,
+                                "email_verified": user != "unverified",
+                                "nonce": nonce,
+                            });
+                            let key = EncodingKey::from_secret(b"provider");
+                            let token = jsonwebtoken::encode(&Header::default(), &claims, &key)
+                                .expect("valid claims");
+                            serde_json::json!({ "id_token": token })
+                        }
+                    };
+                    Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::from(body.to_string())))
+                }
+            }))
+        }
+    });
+    tokio::spawn(hyper::Server::from_tcp(listener)?.serve(make_service));
+    Ok(issuer)
+}
+
+#[tokio::test]
+async fn oidc_login() -> Result<()> {
+    use bore_cli::oidc::OidcConfig;
+
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let issuer = spawn_oidc_provider().await?;
+    let mut server = Server::new(1024..=65535, None, None);
+    server.enable_http(48085, "tunnel.test");
+    server.enable_oidc(OidcConfig {
+        issuer: issuer.clone(),
+        client_id: "bore".into(),
+        client_secret: Some("secret".into()),
+    });
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let local_port = local.local_addr()?.port();
+    let options = ClientOptions {
+        http: true,
+        subdomain: Some("preview".into()),
+        oidc_allow: vec!["@example.com".into()],
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", local_port, "localhost", options).await?;
+    tokio::spawn(client.listen());
+
+    async fn get(host: &str, path: &str, cookie: &str) -> Result<String> {
+        let mut visitor = TcpStream::connect("127.0.0.1:48085").await?;
+        let request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\n{cookie}\r\n");
+        visitor.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        visitor.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+    fn header<'a>(response: &'a str, name: &str) -> &'a str {
+        (response.lines())
+            .find_map(|line| line.strip_prefix(&format!("{name}: ")))
+            .unwrap_or_default()
+    }
+    fn set_cookie(response: &str) -> String {
+        let cookie = header(response, "Set-Cookie");
+        cookie.split(';').next().unwrap_or_default().to_string()
+    }
+    // Start a login on the tunnel, returning the URL of the provider that it
+    // leads to, and the login cookie.
+    let start = || async move {
+        let response = get("preview.tunnel.test:48085", "/page?q=1", "").await?;
+        assert!(response.starts_with("HTTP/1.1 302 Found\r\n"));
+        let tunnel_cookie = set_cookie(&response);
+        assert!(tunnel_cookie.starts_with("bore_oidc_login="));
+        let path = header(&response, "Location")
+            .strip_prefix("http://tunnel.test:48085")
+            .context("login is not on the server's domain")?;
+        let response = get("tunnel.test:48085", path, "").await?;
+        assert!(response.starts_with("HTTP/1.1 302 Found\r\n"));
+        assert_eq!(set_cookie(&response), tunnel_cookie);
+        let url = reqwest::Url::parse(header(&response, "Location"))?;
+        assert_eq!(url.path(), "/authorize");
+        Ok::<_, anyhow::Error>((url, tunnel_cookie))
+    };
+    fn param(url: &reqwest::Url, name: &str) -> String {
+        (url.query_pairs())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_default()
+    }
+    // Log in through the provider as a user, returning the session cookie.
+    let login = |user: &'static str| async move {
+        let (url, login_cookie) = start().await?;
+        assert_eq!(
+            param(&url, "redirect_uri"),
+            "http://tunnel.test:48085/_bore/oidc/callback"
+        );
+        let code = format!("{user}.{}", param(&url, "nonce"));
+        let callback = format!(
+            "/_bore/oidc/callback?code={code}&state={}",
+            param(&url, "state")
+        );
+        let login_cookie = format!("Cookie: {login_cookie}\r\n");
+        let response = get("tunnel.test:48085", &callback, &login_cookie).await?;
+        let grant = header(&response, "Location");
+        let path = grant
+            .strip_prefix("http://preview.tunnel.test:48085")
+            .context("grant is not for the tunnel")?;
+        let response = get("preview.tunnel.test:48085", path, &login_cookie).await?;
+        assert_eq!(header(&response, "Location"), "/page?q=1");
+        Ok::<_, anyhow::Error>(set_cookie(&response))
+    };
+
+    let cookie = login("alice").await?;
+    let mut visitor = TcpStream::connect("127.0.0.1:48085").await?;
+    let request = format!(
+        "GET /page HTTP/1.1\r\nHost: preview.tunnel.test:48085\r\nCookie: theme=dark; {cookie}\r\n\r\n"
+    );
+    visitor.write_all(request.as_bytes()).await?;
+    let (mut stream, _) = time::timeout(Duration::from_secs(3), local.accept()).await??;
+    let mut buf = [0; 512];
+    let n = stream.read(&mut buf).await?;
+    let head = String::from_utf8_lossy(&buf[..n]);
+    assert!(head.contains("\r\nCookie: theme=dark\r\n"));
+    assert!(!head.contains("bore_oidc"));
+    stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+    let n = visitor.read(&mut buf).await?;
+    assert_eq!(&buf[..n], b"HTTP/1.1 204 No Content\r\n\r\n");
+
+    // Visitors with emails that the tunnel does not allow are turned away.
+    let cookie = login("mallory%40other.test").await?;
+    let response = get(
+        "preview.tunnel.test:48085",
+        "/",
+        &format!("Cookie: {cookie}\r\n"),
+    )
+    .await?;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert!(response.ends_with("mallory@other.test may not visit this tunnel\n"));
+
+    // Logins only finish in the browser that started them, with an ID token
+    // for that login and a verified email.
+    let (url, login_cookie) = start().await?;
+    let callback = |user: &str| {
+        let code = format!("{user}.{}", param(&url, "nonce"));
+        format!(
+            "/_bore/oidc/callback?code={code}&state={}",
+            param(&url, "state")
+        )
+    };
+    let response = get("tunnel.test:48085", &callback("alice"), "").await?;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.ends_with("login was started in another browser\n"));
+    let login_cookie = format!("Cookie: {login_cookie}\r\n");
+    let replayed = format!(
+        "/_bore/oidc/callback?code=alice.{}&state={}",
+        "0".repeat(64),
+        param(&url, "state")
+    );
+    let response = get("tunnel.test:48085", &replayed, &login_cookie).await?;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    let response = get("tunnel.test:48085", &callback("unverified"), &login_cookie).await?;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    let response = get("tunnel.test:48085", &callback("alice"), &login_cookie).await?;
+    let grant = header(&response, "Location");
+    let path = grant
+        .strip_prefix("http://preview.tunnel.test:48085")
+        .context("grant is not for the tunnel")?;
+    let response = get("preview.tunnel.test:48085", path, "").await?;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    // A forged session is no session at all.
+    let response = get(
+        "preview.tunnel.test:48085",
+        "/",
+        "Cookie: bore_oidc=e30.AAAA\r\n",
+    )
+    .await?;
+    assert!(response.starts_with("HTTP/1.1 302 Found\r\n"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_error_page() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.enable_http(48086, "tunnel.test");
+    server.set_http_error_page("<h1>{{status}}</h1><p>{{name}}: {{message}}</p>".into());
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let get = |host: &'static str| async move {
+        let mut visitor = TcpStream::connect("127.0.0.1:48086").await?;
+        let request = format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n");
+        visitor.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        visitor.read_to_string(&mut response).await?;
+        Ok::<_, anyhow::Error>(response)
+    };
+
+    let response = get("nothing.tunnel.test").await?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(response.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+    assert!(response.ends_with(
+        "<h1>404</h1><p>nothing.tunnel.test: no tunnel is open at nothing.tunnel.test</p>"
+    ));
+
+    let options = ClientOptions {
+        http: true,
+        name: Some("docs".into()),
+        // The server reads pongs, so it sees at once that the client left.
+        adaptive_heartbeat: true,
+        ..Default::default()
+    };
+    let client = Client::with_options("localhost", 1, "localhost", options).await?;
+    let task = tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Once the client is gone, visitors learn that the tunnel is offline.
+    task.abort();
+    time::sleep(Duration::from_millis(200)).await;
+    let response = get("docs.tunnel.test").await?;
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+    assert!(
+        response.ends_with("<h1>502</h1><p>docs: the tunnel at docs.tunnel.test is offline</p>")
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_grace() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None, None);
+    server.set_reconnect_grace(Duration::from_millis(500));
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let open = |local_port, port| {
+        let options = ClientOptions {
+            port,
+            adaptive_heartbeat: true,
+            ..Default::default()
+        };
+        Client::with_options("localhost", local_port, "localhost", options)
+    };
+    let client = open(1, 0).await?;
+    let port = client.remote_port();
+    let task = tokio::spawn(client.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    task.abort();
+    time::sleep(Duration::from_millis(100)).await;
+
+    // Visitors wait on the port while the client reconnects.
+    let mut visitor = TcpStream::connect(("localhost", port)).await?;
+    visitor.write_all(b"hello").await?;
+
+    let local = TcpListener::bind("localhost:0").await?;
+    let client = open(local.local_addr()?.port(), port).await?;
+    assert_eq!(client.remote_port(), port);
+    let task = tokio::spawn(client.listen());
+
+    let (mut stream, _) = local.accept().await?;
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    // Once no client comes back within the grace, the port closes.
+    task.abort();
+    time::sleep(Duration::from_millis(800)).await;
+    assert!(TcpStream::connect(("localhost", port)).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn inspect_traffic() -> Result<()> {
+    use hyper::service::{make_service_fn, service_fn};
+
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    // A local service that answers each request with its body in reverse.
+    let hits = Arc::new(AtomicUsize::new(0));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let local_port = listener.local_addr()?.port();
+    let counter = Arc::clone(&hits);
+    let make_service = make_service_fn(move |_| {
+        let counter = Arc::clone(&counter);
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request: hyper::Request<hyper::Body>| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    let body = hyper::body::to_bytes(request.into_body()).await?;
+                    let reversed: Vec<u8> = body.iter().rev().copied().collect();
+                    Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::from(reversed)))
+                }
+            }))
+        }
+    });
+    tokio::spawn(hyper::Server::from_tcp(listener)?.serve(make_service));
+
+    let inspector = Arc::new(Inspector::new());
+    tokio::spawn(inspect::serve(
+        ([127, 0, 0, 1], 48087).into(),
+        Arc::clone(&inspector),
+    ));
+    let options = ClientOptions {
+        inspector: Some(Arc::clone(&inspector)),
+        ..Default::default()
+    };
+    let client = Client::with_options("127.0.0.1", local_port, "localhost", options).await?;
+    let remote_port = client.remote_port();
+    tokio::spawn(client.listen());
+
+    let mut visitor = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+    visitor
+        .write_all(b"POST /hook HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n")
+        .await?;
+    let mut response = vec![0; 1024];
+    let n = visitor.read(&mut response).await?;
+    assert!(String::from_utf8_lossy(&response[..n]).ends_with("cba"));
+    drop(visitor);
+    time::sleep(Duration::from_millis(50)).await;
+
+    let api = "http://127.0.0.1:48087/api/requests";
+    let exchanges: Vec<Exchange> = reqwest::get(api).await?.json().await?;
+    assert_eq!(exchanges.len(), 1);
+    let exchange = &exchanges[0];
+    assert_eq!(exchange.request.method, "POST");
+    assert_eq!(exchange.request.path, "/hook");
+    assert_eq!(exchange.request.body, b"abc");
+    let response = exchange.response.as_ref().context("no response")?;
+    assert_eq!(
+        (response.status, response.body.as_slice()),
+        (200, &b"cba"[..])
+    );
+
+    // A replay reaches the local service again, and is recorded too.
+    let url = format!("{api}/{}/replay", exchange.id);
+    let replayed: Exchange = reqwest::Client::new()
+        .post(&url)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(replayed.replay_of, Some(exchange.id));
+    assert_eq!(replayed.request.body, b"abc");
+    assert_eq!(replayed.response.context("no response")?.body, b"cba");
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+    assert_eq!(inspector.exchanges().len(), 2);
+
+    let missing = format!("{api}/{}/replay", uuid::Uuid::new_v4());
+    let status = (reqwest::Client::new().post(&missing))
+        .header("Content-Type", "application/json")
+        .send()
+        .await?
+        .status();
+    assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+
+    // Pages on other sites can neither rebind their domain to the inspector
+    // nor post forms to it.
+    let rebound = (reqwest::Client::new().get(api))
+        .header("Host", "attacker.example:48087")
+        .send()
+        .await?
+        .status();
+    assert_eq!(rebound, reqwest::StatusCode::FORBIDDEN);
+    let form = (reqwest::Client::new().post(&url))
+        .header("Content-Type", "text/plain")
+        .send()
+        .await?
+        .status();
+    assert_eq!(form, reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    // `bore replay` replays the most recent request, or one by its ID.
+    let addr = ([127, 0, 0, 1], 48087).into();
+    let latest = inspect::replay(addr, None).await?;
+    assert_eq!(latest.replay_of, Some(replayed.id));
+    let again = inspect::replay(addr, Some(exchange.id)).await?;
+    assert_eq!(again.replay_of, Some(exchange.id));
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+    let err = inspect::replay(addr, Some(uuid::Uuid::new_v4())).await;
+    assert_eq!(err.unwrap_err().to_string(), "no such request");
+    Ok(())
+}
+
+#[tokio::test]
+async fn capture_traffic() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let request = b"POST /hook HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\nping";
+    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\npong";
+    for (extension, headers_only) in [("pcapng", false), ("jsonl", true)] {
+        let name = format!("bore-capture-{}.{extension}", uuid::Uuid::new_v4());
+        let path = std::env::temp_dir().join(name);
+        let capture_options = CaptureOptions {
+            format: CaptureFormat::for_path(&path),
+            headers_only,
+            max_bytes: None,
+        };
+        let capture = Arc::new(Capture::create(&path, capture_options)?);
+        let options = ClientOptions {
+            capture: Some(Arc::clone(&capture)),
+            ..Default::default()
+        };
+        let local = TcpListener::bind("127.0.0.1:0").await?;
+        let local_port = local.local_addr()?.port();
+        let client = Client::with_options("127.0.0.1", local_port, "localhost", options).await?;
+        let remote_port = client.remote_port();
+        tokio::spawn(client.listen());
+        tokio::spawn(async move {
+            let (mut stream, _) = local.accept().await?;
+            let mut buf = vec![0; request.len()];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(response).await?;
+            anyhow::Ok(())
+        });
+
+        let mut visitor = TcpStream::connect(("127.0.0.1", remote_port)).await?;
+        visitor.write_all(request).await?;
+        let mut answer = Vec::new();
+        visitor.read_to_end(&mut answer).await?;
+        assert_eq!(answer, response);
+        drop(visitor);
+        time::sleep(Duration::from_millis(100)).await;
+        capture.flush().await;
+
+        let captured = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        let contains = |needle: &[u8]| captured.windows(needle.len()).any(|w| w == needle);
+        if extension == "pcapng" {
+            assert_eq!(captured[..4], [0x0A, 0x0D, 0x0D, 0x0A]);
+            assert!(contains(request) && contains(response));
+            // A handshake, the request and response, and a close.
+            let mut packets = 0;
+            let mut offset = 0;
+            while offset < captured.len() {
+                let kind = u32::from_le_bytes(captured[offset..offset + 4].try_into()?);
+                let length = u32::from_le_bytes(captured[offset + 4..offset + 8].try_into()?);
+                packets += (kind == 6) as usize;
+                offset += length as usize;
+            }
+            assert_eq!(offset, captured.len());
+            assert!(packets >= 8);
+        } else {
+            let records = String::from_utf8(captured)?
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<FlowRecord>, _>>()?;
+            assert!(matches!(records[0], FlowRecord::Open { .. }));
+            let mut kept = (Vec::new(), Vec::new());
+            for record in &records {
+                if let FlowRecord::Data {
+                    direction, data, ..
+                } = record
+                {
+                    match direction {
+                        Direction::Inbound => kept.0.extend_from_slice(data),
+                        Direction::Outbound => kept.1.extend_from_slice(data),
+                    }
+                }
+            }
+            assert_eq!(kept.0, &request[..request.len() - 4]);
+            assert_eq!(kept.1, &response[..response.len() - 4]);
+            let Some(FlowRecord::Close {
+                inbound_bytes,
+                outbound_bytes,
+                ..
+            }) = records.last()
+            else {
+                panic!("no close record");
+            };
+            assert_eq!(*inbound_bytes, request.len() as u64);
+            assert_eq!(*outbound_bytes, response.len() as u64);
+        }
+    }
+    Ok(())
+}
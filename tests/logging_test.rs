@@ -0,0 +1,67 @@
+use std::fs;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_cli::logging::RotatingFile;
+use uuid::Uuid;
+
+#[test]
+fn rotates_by_size() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("bore-log-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("bore.log");
+
+    let mut file = RotatingFile::open(&path, 16, 2)?;
+    for i in 0..4 {
+        writeln!(file, "line number {i}")?;
+    }
+    file.flush()?;
+
+    assert_eq!(fs::read_to_string(&path)?, "line number 3\n");
+    assert_eq!(
+        fs::read_to_string(dir.join("bore.log.1"))?,
+        "line number 2\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("bore.log.2"))?,
+        "line number 1\n"
+    );
+    assert!(!dir.join("bore.log.3").exists());
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn rotates_by_age() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("bore-log-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("bore.log");
+    fs::write(&path, "from an earlier run\n")?;
+    thread::sleep(Duration::from_millis(300));
+
+    // The age counts from when the file was created, not when it was opened.
+    let mut file = RotatingFile::open(&path, 1 << 20, 2)?;
+    file.set_max_age(Duration::from_millis(200))?;
+    writeln!(file, "first")?;
+    writeln!(file, "second")?;
+    thread::sleep(Duration::from_millis(300));
+    writeln!(file, "third")?;
+    file.flush()?;
+
+    assert_eq!(fs::read_to_string(&path)?, "third\n");
+    assert_eq!(
+        fs::read_to_string(dir.join("bore.log.1"))?,
+        "first\nsecond\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("bore.log.2"))?,
+        "from an earlier run\n"
+    );
+    assert!(file.set_max_age(Duration::ZERO).is_err());
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_cli::policy::{Admission, Decision, Policy};
+
+fn admission(peer_ip: &str, connections: usize) -> Admission {
+    Admission {
+        peer_ip: peer_ip.parse().unwrap(),
+        port: 8080,
+        connections,
+    }
+}
+
+#[test]
+fn policy_decisions() -> Result<()> {
+    let policy = Policy::compile(
+        r#"
+        if peer_ip.starts_with("10.") { "accept" }
+        else if connections > 100 { 250 }
+        else if port == 8080 { false }
+        else { "throttle" }
+        "#,
+    )?;
+    assert_eq!(
+        policy.evaluate(&admission("10.0.0.1", 0))?,
+        Decision::Accept
+    );
+    assert_eq!(
+        policy.evaluate(&admission("1.2.3.4", 500))?,
+        Decision::Throttle(Duration::from_millis(250))
+    );
+    assert_eq!(policy.evaluate(&admission("1.2.3.4", 0))?, Decision::Reject);
+    Ok(())
+}
+
+#[test]
+fn policy_errors() -> Result<()> {
+    assert!(Policy::compile("if {").is_err());
+    assert!(Policy::compile(r#""maybe""#)?
+        .evaluate(&admission("1.2.3.4", 0))
+        .is_err());
+    assert!(Policy::compile("loop {}")?
+        .evaluate(&admission("1.2.3.4", 0))
+        .is_err());
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_cli::process::{detect_port, is_running, listening_ports, spawn_shell, wait_for_exit};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn watch_process_exit() -> Result<()> {
+    assert!(is_running(std::process::id()));
+
+    let mut child = spawn_shell("exit 0", &[])?;
+    let pid = child.id().expect("child has a process id");
+    child.wait().await?;
+    timeout(Duration::from_secs(5), wait_for_exit(pid)).await?;
+    assert!(!is_running(pid));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn detect_listening_port() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let pid = std::process::id();
+    assert!(listening_ports(pid)?.contains(&port));
+
+    // Ports opened by descendants count too.
+    let parent = std::os::unix::process::parent_id();
+    assert!(listening_ports(parent)?.contains(&port));
+    let detected = timeout(Duration::from_secs(5), detect_port(pid)).await??;
+    assert!(detected <= port);
+
+    let mut child = spawn_shell("sleep 30", &[])?;
+    let child_pid = child.id().expect("child has a process id");
+    assert!(listening_ports(child_pid)?.is_empty());
+    child.kill().await?;
+    drop(listener);
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use anyhow::Result;
+use bore_cli::shared::{ClientMessage, Delimited, ErrorCode, ServerMessage, MAX_STRING_LENGTH};
+use tokio::io::{self, AsyncWriteExt};
+
+#[tokio::test]
+async fn compressed_frames() -> Result<()> {
+    let (client, server) = io::duplex(64);
+    let mut client = Delimited::new(client);
+    let mut server = Delimited::new(server);
+    client.set_compression(true);
+    server.set_compression(true);
+
+    // This message is longer than the maximum frame length, but compresses well.
+    let message = "a".repeat(2000);
+    let (_, received) = tokio::try_join!(
+        server.send(ServerMessage::Error(message.clone())),
+        client.recv::<ServerMessage>(),
+    )?;
+    match received {
+        Some(ServerMessage::Error(received)) => assert_eq!(received, message),
+        other => panic!("unexpected message: {other:?}"),
+    }
+
+    // Small messages are still sent without compression.
+    let (_, received) = tokio::try_join!(
+        server.send(ServerMessage::Heartbeat),
+        client.recv::<ServerMessage>(),
+    )?;
+    assert!(matches!(received, Some(ServerMessage::Heartbeat)));
+
+    Ok(())
+}
+
+#[test]
+fn error_codes() -> Result<()> {
+    let err = r#"{"ErrorExt":{"code":"PortUnavailable","message":"port already in use"}}"#;
+    match serde_json::from_str(err)? {
+        ServerMessage::ErrorExt(err) => assert_eq!(err.code, ErrorCode::PortUnavailable),
+        other => panic!("unexpected message: {other:?}"),
+    }
+
+    // Codes from newer servers are still understood as errors.
+    let err = r#"{"ErrorExt":{"code":"SolarFlare","message":"try again tomorrow"}}"#;
+    match serde_json::from_str(err)? {
+        ServerMessage::ErrorExt(err) => assert_eq!(err.code, ErrorCode::Other),
+        other => panic!("unexpected message: {other:?}"),
+    }
+
+    Ok(())
+}
+
+/// Feed a raw frame to a delimited stream and parse it as a client message.
+async fn parse(frame: &[u8]) -> Result<Option<ClientMessage>> {
+    let (mut raw, stream) = io::duplex(4096);
+    let mut stream = Delimited::new(stream);
+    raw.write_all(frame).await?;
+    raw.write_all(&[0]).await?;
+    drop(raw);
+    stream.recv().await
+}
+
+#[tokio::test]
+async fn message_limits() -> Result<()> {
+    let request = r#"{"Delegate":{"ttl_secs":60,"min_port":1,"max_port":2}}"#;
+    assert!(matches!(
+        parse(request.as_bytes()).await?,
+        Some(ClientMessage::Delegate(_))
+    ));
+
+    // Fixed-shape requests reject fields they do not know about.
+    let request = r#"{"Delegate":{"ttl_secs":60,"min_port":1,"max_port":2,"admin":true}}"#;
+    assert!(parse(request.as_bytes()).await.is_err());
+
+    // Extensible hellos accept them, for newer peers.
+    let hello = r#"{"HelloExt":{"port":0,"version":9,"future":[1,2,3]}}"#;
+    assert!(matches!(
+        parse(hello.as_bytes()).await?,
+        Some(ClientMessage::HelloExt(_))
+    ));
+
+    // Long strings are rejected even when they fit in a compressed frame.
+    let (client, server) = io::duplex(64);
+    let mut client = Delimited::new(client);
+    let mut server = Delimited::new(server);
+    client.set_compression(true);
+    server.set_compression(true);
+    let message = "a".repeat(MAX_STRING_LENGTH + 1);
+    let (_, received) = tokio::join!(
+        server.send(ServerMessage::Error(message)),
+        client.recv::<ServerMessage>(),
+    );
+    assert!(received.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn malformed_frames() -> Result<()> {
+    let seeds: &[&[u8]] = &[
+        br#"{"Hello":8080}"#,
+        br#"{"HelloExt":{"port":0,"version":1,"compression":true}}"#,
+        br#"{"Authenticate":"0123456789abcdef"}"#,
+        br#"{"Accept":"67e55044-10b1-426f-9247-bb680e5fe0c8"}"#,
+        br#"{"Delegate":{"ttl_secs":60,"min_port":1,"max_port":2}}"#,
+        br#"[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[["#,
+    ];
+    let rng = fastrand::Rng::with_seed(499);
+    for _ in 0..2000 {
+        let mut frame = seeds[rng.usize(..seeds.len())].to_vec();
+        for _ in 0..rng.usize(1..8) {
+            match rng.u8(..3) {
+                0 if !frame.is_empty() => {
+                    let i = rng.usize(..frame.len());
+                    frame[i] = rng.u8(1..);
+                }
+                1 => frame.insert(rng.usize(..=frame.len()), rng.u8(1..)),
+                _ if !frame.is_empty() => {
+                    frame.remove(rng.usize(..frame.len()));
+                }
+                _ => {}
+            }
+        }
+        // Any outcome is fine, as long as parsing neither panics nor hangs.
+        let _ = parse(&frame).await;
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+#![cfg(unix)]
+
+use std::path::PathBuf;
+
+use bore_cli::service::ServiceDefinition;
+
+fn definition(args: &[&str]) -> ServiceDefinition {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    ServiceDefinition::new(
+        "web",
+        PathBuf::from("/opt/bore & co/bore"),
+        PathBuf::from("/var/log/bore"),
+        PathBuf::from("/etc/bore"),
+        &args,
+    )
+}
+
+#[test]
+fn service_arguments() {
+    let service = definition(&["local", "8000", "--to", "example.com"]);
+    assert_eq!(service.program, PathBuf::from("/opt/bore & co/bore"));
+    assert_eq!(
+        service.arguments,
+        [
+            "--log-file",
+            "/var/log/bore/web.log",
+            "service",
+            "run",
+            "--name",
+            "web",
+            "--",
+            "local",
+            "8000",
+            "--to",
+            "example.com",
+        ]
+    );
+    assert_eq!(
+        service.stderr_path(),
+        PathBuf::from("/var/log/bore/web.stderr.log")
+    );
+}
+
+#[test]
+fn service_credentials() {
+    let service = definition(&[
+        "server",
+        "--secret",
+        "old",
+        "-s",
+        "new",
+        "--secret=newest",
+        "--api-key-file",
+        "/keys/web",
+    ]);
+    assert_eq!(
+        service.arguments[7..],
+        [
+            "server",
+            "--secret-file",
+            "/etc/bore/web.secret",
+            "--secret-file",
+            "/etc/bore/web.secret.2",
+            "--secret-file",
+            "/etc/bore/web.secret.3",
+            "--api-key-file",
+            "/keys/web",
+        ]
+    );
+    assert_eq!(
+        service.credentials,
+        [
+            (PathBuf::from("/etc/bore/web.secret"), "old".to_string()),
+            (PathBuf::from("/etc/bore/web.secret.2"), "new".to_string()),
+            (
+                PathBuf::from("/etc/bore/web.secret.3"),
+                "newest".to_string()
+            ),
+        ]
+    );
+
+    // Nothing secret ends up in the agent's definition.
+    let plist = definition(&["local", "8000", "--api-key", "k3y"]).plist();
+    assert!(!plist.contains("k3y"));
+    assert!(plist
+        .contains("<string>--api-key-file</string>\n    <string>/etc/bore/web.api-key</string>"));
+}
+
+#[test]
+fn launchd_plist() {
+    let plist = definition(&["local", "8000", "--to", "<example.com>"]).plist();
+    assert!(plist.contains("<key>Label</key>\n  <string>dev.bore.web</string>"));
+
+    // The program comes first, and every argument is escaped.
+    let arguments = concat!(
+        "  <key>ProgramArguments</key>\n",
+        "  <array>\n",
+        "    <string>/opt/bore &amp; co/bore</string>\n",
+        "    <string>--log-file</string>\n",
+        "    <string>/var/log/bore/web.log</string>\n",
+        "    <string>service</string>\n",
+        "    <string>run</string>\n",
+        "    <string>--name</string>\n",
+        "    <string>web</string>\n",
+        "    <string>--</string>\n",
+        "    <string>local</string>\n",
+        "    <string>8000</string>\n",
+        "    <string>--to</string>\n",
+        "    <string>&lt;example.com&gt;</string>\n",
+        "  </array>\n",
+    );
+    assert!(plist.contains(arguments), "{plist}");
+
+    // Restarted after failures only, and not in a tight loop.
+    assert!(plist.contains("<key>RunAtLoad</key>\n  <true/>"));
+    assert!(plist.contains("<key>SuccessfulExit</key>\n    <false/>"));
+    assert!(plist.contains("<key>ThrottleInterval</key>\n  <integer>10</integer>"));
+    assert!(plist
+        .contains("<key>StandardErrorPath</key>\n  <string>/var/log/bore/web.stderr.log</string>"));
+}
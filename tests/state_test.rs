@@ -0,0 +1,77 @@
+use std::fs;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_cli::reservation::Reservations;
+use bore_cli::state::{self, SavedBan, SavedReservation, SavedTransfer, ServerState, StateFile};
+use uuid::Uuid;
+
+#[test]
+fn save_and_load() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("bore-state-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("state.json");
+    let file = StateFile::new(&path);
+
+    // A missing file is an empty state.
+    assert!(file.load()?.handshake_tokens.is_none());
+
+    let ban = SavedBan {
+        addr: "203.0.113.7".parse()?,
+        until: state::deadline(Duration::from_secs(60)),
+    };
+    let reservation = SavedReservation {
+        port: 8080,
+        owner: "ab".repeat(32),
+        until: state::deadline(Duration::from_secs(300)),
+    };
+    let transfer = SavedTransfer {
+        key: "acme/web".into(),
+        bytes: 1 << 30,
+        month: 24290,
+    };
+    let state = ServerState {
+        handshake_tokens: Some(12.5),
+        bans: vec![ban.clone()],
+        reservation_key: Some("cd".repeat(32)),
+        reservations: vec![reservation.clone()],
+        transfers: vec![transfer.clone()],
+        ..Default::default()
+    };
+    file.save(state)?;
+    let loaded = file.load()?;
+    assert_eq!(loaded.handshake_tokens, Some(12.5));
+    assert_eq!(loaded.bans, [ban]);
+    assert_eq!(loaded.reservations, [reservation]);
+    assert_eq!(loaded.transfers, [transfer]);
+    assert!(loaded.saved_at > 0);
+    assert!(loaded.age().as_secs() < 60);
+
+    fs::write(&path, "not json")?;
+    assert!(file.load().is_err());
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn reservations_survive_restart() {
+    let before = Reservations::new(Duration::from_secs(60));
+    let web = before.owner("secret:abcd", Some("web"));
+    let _open = before.hold(9000, web);
+    drop(before.hold(9001, before.owner("secret:abcd", Some("api"))));
+
+    // Both the open tunnel and the closed one keep their ports.
+    let after = Reservations::new(Duration::from_secs(60));
+    after.restore(before.key(), before.snapshot());
+    assert_eq!(
+        after.reserved_for(&after.owner("secret:abcd", Some("web"))),
+        Some(9000)
+    );
+    assert_eq!(
+        after.reserved_for(&after.owner("secret:abcd", Some("api"))),
+        Some(9001)
+    );
+    assert!(after.is_held_for_other(9000, &after.owner("secret:ef01", Some("web"))));
+}
@@ -0,0 +1,52 @@
+use std::fs;
+
+use anyhow::Result;
+use bore_cli::integrity::StreamDigest;
+use bore_cli::transcript::{self, Transcript, TranscriptEntry};
+use uuid::Uuid;
+
+fn entry(port: u16) -> TranscriptEntry {
+    let digest = |bytes| StreamDigest {
+        bytes,
+        sha256: "ab".repeat(32),
+    };
+    TranscriptEntry {
+        seq: 0,
+        time: 1_700_000_000,
+        id: Uuid::new_v4(),
+        port,
+        peer: "203.0.113.7:51234".parse().unwrap(),
+        duration_ms: 250,
+        inbound: digest(10),
+        outbound: digest(20),
+        prev: String::new(),
+        hash: String::new(),
+    }
+}
+
+#[test]
+fn hash_chain() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("bore-transcript-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("transcript.jsonl");
+
+    let transcript = Transcript::open(&path)?;
+    transcript.record(entry(8000))?;
+    transcript.record(entry(8001))?;
+    drop(transcript);
+
+    // Reopening continues the chain where it left off.
+    Transcript::open(&path)?.record(entry(8002))?;
+    let last = transcript::verify(&path)?.expect("transcript has records");
+    assert_eq!(last.seq, 2);
+    assert_eq!(last.port, 8002);
+
+    // Any edit to an earlier record is detected, and blocks further recording.
+    let tampered = fs::read_to_string(&path)?.replacen("8001", "9001", 1);
+    fs::write(&path, tampered)?;
+    assert!(transcript::verify(&path).is_err());
+    assert!(Transcript::open(&path).is_err());
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}